@@ -0,0 +1,277 @@
+//! Load generator for a running gateway.
+//!
+//! Hammers the service mappings endpoint and/or the allocation endpoints with
+//! concurrent workers for a fixed duration, then reports throughput, latency
+//! percentiles and status codes per endpoint. The database can be seeded with
+//! synthetic users first so `/service/mappings` runs against a realistic size.
+//!
+//! ```bash
+//! # Gateway started with --bypass-jwt against a throwaway database
+//! cargo run --release --example loadtest -- \
+//!     --database-url postgresql://localhost/peerlab_gateway --seed-users 5000 \
+//!     --scenario mixed --concurrency 64 --duration 30
+//! ```
+
+use clap::{Parser, ValueEnum};
+use ipnet::Ipv6Net;
+use std::{
+    collections::BTreeMap,
+    net::Ipv6Addr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+use peerlab_gateway::{
+    database::{Database, DatabaseConfig},
+    hash_user_identifier,
+    types::{Asn, UserHash},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Scenario {
+    /// GET /service/mappings only
+    Mappings,
+    /// POST /api/user/asn and /api/user/prefix only
+    Allocation,
+    /// Both, alternating per request
+    Mixed,
+}
+
+#[derive(Parser, Debug)]
+#[command(about = "Load test a running peerlab-gateway")]
+struct Args {
+    /// Gateway base URL
+    #[arg(long, default_value = "http://127.0.0.1:8080")]
+    url: String,
+
+    /// Agent key for the service API
+    #[arg(long, default_value = "agent-key")]
+    agent_key: String,
+
+    /// Bearer token for the client API (not needed when the gateway runs with --bypass-jwt)
+    #[arg(long)]
+    token: Option<String>,
+
+    /// Endpoints to exercise
+    #[arg(long, value_enum, default_value = "mixed")]
+    scenario: Scenario,
+
+    /// Number of concurrent workers
+    #[arg(long, default_value = "32")]
+    concurrency: usize,
+
+    /// Test duration in seconds
+    #[arg(long, default_value = "30")]
+    duration: u64,
+
+    /// Database to seed before the run (seeding is skipped when unset)
+    #[arg(long)]
+    database_url: Option<String>,
+
+    /// Number of synthetic users to seed, each with an ASN and one prefix lease
+    #[arg(long, default_value = "0")]
+    seed_users: u32,
+
+    /// First ASN given to seeded users (outside the gateway pool so allocations aren't affected)
+    #[arg(long, default_value = "100000")]
+    seed_asn_start: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Endpoint {
+    Mappings,
+    RequestAsn,
+    RequestPrefix,
+}
+
+impl Endpoint {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Mappings => "GET /service/mappings",
+            Self::RequestAsn => "POST /api/user/asn",
+            Self::RequestPrefix => "POST /api/user/prefix",
+        }
+    }
+}
+
+#[derive(Default)]
+struct EndpointStats {
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<String, u64>,
+}
+
+type Stats = Arc<Mutex<BTreeMap<Endpoint, EndpointStats>>>;
+
+/// Insert synthetic users with an ASN and a 24h lease in 2001:db8::/32
+async fn seed(args: &Args, database_url: &str) -> anyhow::Result<()> {
+    if args.seed_users > u16::MAX as u32 + 1 {
+        anyhow::bail!("At most 65536 users can be seeded (one /48 each in 2001:db8::/32)");
+    }
+
+    let database = Database::new(&DatabaseConfig::new(database_url.to_string())).await?;
+    database.initialize().await?;
+
+    let start = Instant::now();
+    for i in 0..args.seed_users {
+        let user_hash: UserHash = hash_user_identifier(&format!("loadtest-user-{}", i))
+            .parse()
+            .map_err(anyhow::Error::msg)?;
+        let asn = Asn::try_from(args.seed_asn_start + i as i64).map_err(anyhow::Error::msg)?;
+        database.assign_user_asn(&user_hash, None, asn, 1).await?;
+
+        // One /48 per user: 2001:db8:XXXX:: with XXXX = i
+        let address = Ipv6Addr::new(0x2001, 0x0db8, i as u16, 0, 0, 0, 0, 0);
+        database
+            .create_prefix_lease(
+                &user_hash,
+                &Ipv6Net::new(address, 48)?.into(),
+                chrono::Duration::hours(24),
+                None,
+            )
+            .await?;
+    }
+
+    println!(
+        "Seeded {} users in {:.1}s",
+        args.seed_users,
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+/// Send one request and return its status (or the transport error)
+async fn send(client: &reqwest::Client, args: &Args, endpoint: Endpoint) -> String {
+    let request = match endpoint {
+        Endpoint::Mappings => client
+            .get(format!("{}/service/mappings", args.url))
+            .bearer_auth(&args.agent_key),
+        Endpoint::RequestAsn => client.post(format!("{}/api/user/asn", args.url)),
+        Endpoint::RequestPrefix => client
+            .post(format!("{}/api/user/prefix", args.url))
+            .json(&serde_json::json!({ "duration_hours": 1 })),
+    };
+    let request = match (&args.token, endpoint) {
+        (Some(token), Endpoint::RequestAsn | Endpoint::RequestPrefix) => request.bearer_auth(token),
+        _ => request,
+    };
+
+    match request.send().await {
+        Ok(response) => {
+            let status = response.status().as_u16().to_string();
+            // Read the body so the measured latency includes the full response
+            let _ = response.bytes().await;
+            status
+        }
+        Err(err) if err.is_timeout() => "timeout".to_string(),
+        Err(_) => "error".to_string(),
+    }
+}
+
+async fn worker(id: usize, args: Arc<Args>, client: reqwest::Client, stats: Stats, until: Instant) {
+    let endpoints: &[Endpoint] = match args.scenario {
+        Scenario::Mappings => &[Endpoint::Mappings],
+        Scenario::Allocation => &[Endpoint::RequestAsn, Endpoint::RequestPrefix],
+        Scenario::Mixed => &[
+            Endpoint::Mappings,
+            Endpoint::RequestAsn,
+            Endpoint::Mappings,
+            Endpoint::RequestPrefix,
+        ],
+    };
+
+    let mut i = id;
+    while Instant::now() < until {
+        let endpoint = endpoints[i % endpoints.len()];
+        i += 1;
+
+        let start = Instant::now();
+        let status = send(&client, &args, endpoint).await;
+        let latency = start.elapsed();
+
+        let mut stats = stats.lock().await;
+        let entry = stats.entry(endpoint).or_default();
+        entry.latencies.push(latency);
+        *entry.statuses.entry(status).or_default() += 1;
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> f64 {
+    let index = ((sorted.len() as f64 - 1.0) * p).round() as usize;
+    sorted[index].as_secs_f64() * 1000.0
+}
+
+fn report(stats: BTreeMap<Endpoint, EndpointStats>, elapsed: Duration) {
+    println!(
+        "\n{:<24} {:>8} {:>9} {:>9} {:>9} {:>9} {:>9}  statuses",
+        "endpoint", "requests", "req/s", "p50 ms", "p90 ms", "p99 ms", "max ms"
+    );
+    for (endpoint, mut endpoint_stats) in stats {
+        let latencies = &mut endpoint_stats.latencies;
+        if latencies.is_empty() {
+            continue;
+        }
+        latencies.sort();
+
+        let statuses: Vec<String> = endpoint_stats
+            .statuses
+            .iter()
+            .map(|(status, count)| format!("{}={}", status, count))
+            .collect();
+
+        println!(
+            "{:<24} {:>8} {:>9.1} {:>9.2} {:>9.2} {:>9.2} {:>9.2}  {}",
+            endpoint.label(),
+            latencies.len(),
+            latencies.len() as f64 / elapsed.as_secs_f64(),
+            percentile(latencies, 0.50),
+            percentile(latencies, 0.90),
+            percentile(latencies, 0.99),
+            percentile(latencies, 1.0),
+            statuses.join(" ")
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Arc::new(Args::parse());
+
+    if let Some(ref database_url) = args.database_url
+        && args.seed_users > 0
+    {
+        seed(&args, database_url).await?;
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .pool_max_idle_per_host(args.concurrency)
+        .build()?;
+    let stats: Stats = Arc::default();
+
+    println!(
+        "Running {:?} scenario against {} with {} workers for {}s",
+        args.scenario, args.url, args.concurrency, args.duration
+    );
+
+    let start = Instant::now();
+    let until = start + Duration::from_secs(args.duration);
+    let workers: Vec<_> = (0..args.concurrency)
+        .map(|id| {
+            tokio::spawn(worker(
+                id,
+                args.clone(),
+                client.clone(),
+                stats.clone(),
+                until,
+            ))
+        })
+        .collect();
+    for worker in workers {
+        worker.await?;
+    }
+
+    let stats = std::mem::take(&mut *stats.lock().await);
+    report(stats, start.elapsed());
+    Ok(())
+}
@@ -0,0 +1,16 @@
+-- Migration to create webhooks table
+-- This table stores user-registered webhook endpoints and their event subscriptions
+
+CREATE TABLE IF NOT EXISTS webhooks (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_hash VARCHAR(64) NOT NULL,
+    url TEXT NOT NULL,
+    event_types TEXT[] NOT NULL DEFAULT '{}',
+    secret VARCHAR(128) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Create index on user_hash for efficient lookups
+CREATE INDEX IF NOT EXISTS idx_webhooks_user_hash
+ON webhooks (user_hash);
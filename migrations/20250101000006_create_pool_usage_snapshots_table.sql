@@ -0,0 +1,13 @@
+-- Migration to create pool usage snapshots table
+-- This table records pool utilization over time for exhaustion forecasting
+
+CREATE TABLE IF NOT EXISTS pool_usage_snapshots (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    asns_assigned INTEGER NOT NULL,
+    prefixes_leased INTEGER NOT NULL,
+    recorded_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Create index on recorded_at for efficient window queries
+CREATE INDEX IF NOT EXISTS idx_pool_usage_snapshots_recorded_at
+ON pool_usage_snapshots (recorded_at);
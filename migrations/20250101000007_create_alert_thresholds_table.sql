@@ -0,0 +1,14 @@
+-- Migration to create alert thresholds table
+-- This table stores admin-configured pool utilization alerts
+
+CREATE TABLE IF NOT EXISTS alert_thresholds (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    pool VARCHAR(16) NOT NULL CHECK (pool IN ('asn', 'prefix')),
+    threshold DOUBLE PRECISION NOT NULL CHECK (threshold > 0 AND threshold <= 1),
+    webhook_url TEXT,
+    email VARCHAR(255),
+    triggered_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    CHECK (webhook_url IS NOT NULL OR email IS NOT NULL)
+);
@@ -0,0 +1,5 @@
+-- Migration to add sites column to prefix_leases table
+-- This stores the sites (POPs) where a lease may be announced; NULL means every site
+
+ALTER TABLE prefix_leases
+ADD COLUMN IF NOT EXISTS sites TEXT[];
@@ -0,0 +1,8 @@
+-- Migration to add indexes backing sorted mapping listings
+-- These let ORDER BY created_at/updated_at on user_asn_mappings use an index scan
+
+CREATE INDEX IF NOT EXISTS idx_user_asn_mappings_created_at
+ON user_asn_mappings (created_at);
+
+CREATE INDEX IF NOT EXISTS idx_user_asn_mappings_updated_at
+ON user_asn_mappings (updated_at);
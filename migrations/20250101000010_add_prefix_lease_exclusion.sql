@@ -0,0 +1,18 @@
+-- Migration to prevent overlapping leases of the same prefix
+-- Two leases of a prefix may never cover the same instant, so two active leases
+-- can't reference the same prefix even if concurrent requests race
+
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM pg_constraint WHERE conname = 'prefix_leases_no_overlap'
+    ) THEN
+        ALTER TABLE prefix_leases
+        ADD CONSTRAINT prefix_leases_no_overlap
+        EXCLUDE USING gist (
+            (prefix::inet) inet_ops WITH =,
+            tstzrange(start_time, end_time) WITH &&
+        );
+    END IF;
+END
+$$;
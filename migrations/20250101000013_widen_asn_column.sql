@@ -0,0 +1,4 @@
+-- Migration to widen the ASN column
+-- 32-bit ASNs (e.g. 4200000000-4294967294) don't fit in INTEGER
+
+ALTER TABLE user_asn_mappings ALTER COLUMN asn TYPE BIGINT;
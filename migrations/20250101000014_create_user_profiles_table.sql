@@ -0,0 +1,10 @@
+-- Migration to create user profiles table
+-- Caches IdP profile data (emails) per IdP user id until revalidated or pruned
+
+CREATE TABLE IF NOT EXISTS user_profiles (
+    user_id TEXT PRIMARY KEY,
+    email TEXT,
+    validated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_profiles_validated_at ON user_profiles(validated_at);
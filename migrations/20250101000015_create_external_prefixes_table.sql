@@ -0,0 +1,33 @@
+-- Migration to create external prefixes table
+-- Address space users bring themselves, included in exports once they prove control of it
+
+CREATE TABLE IF NOT EXISTS external_prefixes (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_hash VARCHAR(64) NOT NULL,
+    prefix VARCHAR(43) NOT NULL,
+    -- Challenge the user publishes to prove control of the prefix
+    token VARCHAR(64) NOT NULL,
+    -- One of: pending, verified
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    -- How control was proven (dns)
+    verification_method VARCHAR(16),
+    verified_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_external_prefixes_user_hash
+ON external_prefixes (user_hash);
+
+-- Registrations may not overlap, whoever registered them
+DO $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM pg_constraint WHERE conname = 'external_prefixes_no_overlap'
+    ) THEN
+        ALTER TABLE external_prefixes
+        ADD CONSTRAINT external_prefixes_no_overlap
+        EXCLUDE USING gist ((prefix::inet) inet_ops WITH &&);
+    END IF;
+END
+$$;
@@ -0,0 +1,6 @@
+-- Migration to add RPKI state to external prefixes
+-- Route origin validation of each prefix against the lab's origin ASN, refreshed by the scheduler
+
+-- One of: valid, invalid, not-found (NULL until first checked)
+ALTER TABLE external_prefixes ADD COLUMN IF NOT EXISTS rpki_state VARCHAR(16);
+ALTER TABLE external_prefixes ADD COLUMN IF NOT EXISTS rpki_checked_at TIMESTAMP WITH TIME ZONE;
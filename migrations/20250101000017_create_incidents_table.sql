@@ -0,0 +1,22 @@
+-- Migration to create incidents table
+-- Abuse reports and other incidents, attached to the user holding the space involved
+
+CREATE TABLE IF NOT EXISTS incidents (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    -- Holder of the space when the incident was opened (NULL when nobody held it)
+    user_hash VARCHAR(64),
+    lease_id UUID,
+    prefix VARCHAR(43),
+    -- Where the incident came from (abuse_report)
+    source VARCHAR(32) NOT NULL,
+    description TEXT NOT NULL,
+    -- How to reach whoever reported it
+    reporter_contact TEXT,
+    -- One of: open, closed
+    status VARCHAR(16) NOT NULL DEFAULT 'open',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_incidents_user_hash
+ON incidents (user_hash);
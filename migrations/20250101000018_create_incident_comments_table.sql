@@ -0,0 +1,18 @@
+-- Migration to create incident_comments table
+-- Incidents can be attached to an ASN and closed with a resolution; comments record their handling
+
+ALTER TABLE incidents ADD COLUMN IF NOT EXISTS asn BIGINT;
+ALTER TABLE incidents ADD COLUMN IF NOT EXISTS resolution TEXT;
+ALTER TABLE incidents ADD COLUMN IF NOT EXISTS closed_at TIMESTAMP WITH TIME ZONE;
+
+CREATE TABLE IF NOT EXISTS incident_comments (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    incident_id UUID NOT NULL REFERENCES incidents (id) ON DELETE CASCADE,
+    -- Who wrote the comment, as given by the admin
+    author TEXT,
+    body TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_incident_comments_incident_id
+ON incident_comments (incident_id);
@@ -0,0 +1,18 @@
+-- Migration to create api_usage table
+-- API calls per user or agent and route, rolled up hourly
+
+CREATE TABLE IF NOT EXISTS api_usage (
+    -- Start of the hour the calls were made in
+    hour TIMESTAMP WITH TIME ZONE NOT NULL,
+    -- One of: user, agent
+    subject_kind VARCHAR(8) NOT NULL,
+    -- User hash or agent id
+    subject VARCHAR(128) NOT NULL,
+    -- Method and route template, e.g. POST /api/user/prefix
+    route VARCHAR(255) NOT NULL,
+    calls BIGINT NOT NULL,
+    PRIMARY KEY (hour, subject_kind, subject, route)
+);
+
+CREATE INDEX IF NOT EXISTS idx_api_usage_subject
+ON api_usage (subject_kind, subject, hour);
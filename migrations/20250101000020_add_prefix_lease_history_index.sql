@@ -0,0 +1,5 @@
+-- Migration to index the lease history of users
+-- Serves pages of a user's leases, newest first
+
+CREATE INDEX IF NOT EXISTS idx_prefix_leases_user_history
+ON prefix_leases (user_hash, start_time DESC, id DESC);
@@ -0,0 +1,8 @@
+-- Migration to add auto-renewal to prefix_leases table
+-- Leases with renew_hours set are extended by that many hours shortly before they end
+
+ALTER TABLE prefix_leases
+ADD COLUMN IF NOT EXISTS renew_hours INTEGER;
+
+CREATE INDEX IF NOT EXISTS idx_prefix_leases_auto_renew
+ON prefix_leases (end_time) WHERE renew_hours IS NOT NULL;
@@ -0,0 +1,8 @@
+-- Migration to count auto-renewal extensions in minutes
+-- Leases may now last less than an hour, so renew_hours becomes renew_minutes
+
+ALTER TABLE prefix_leases
+RENAME COLUMN renew_hours TO renew_minutes;
+
+UPDATE prefix_leases SET renew_minutes = renew_minutes * 60
+WHERE renew_minutes IS NOT NULL;
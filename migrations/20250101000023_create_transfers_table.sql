@@ -0,0 +1,17 @@
+-- Migration to create transfers table
+-- Each row records a lease or ASN moved from one user to another by an admin
+
+CREATE TABLE IF NOT EXISTS transfers (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    resource VARCHAR(16) NOT NULL,
+    lease_id UUID,
+    prefix CIDR,
+    asn BIGINT,
+    from_user_hash VARCHAR(64) NOT NULL,
+    to_user_hash VARCHAR(64) NOT NULL,
+    reason TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_transfers_from_user_hash ON transfers(from_user_hash);
+CREATE INDEX IF NOT EXISTS idx_transfers_to_user_hash ON transfers(to_user_hash);
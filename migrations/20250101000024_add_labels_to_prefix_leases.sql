@@ -0,0 +1,6 @@
+-- Migration to add user-set labels to prefix_leases table
+-- A short label and a longer purpose tell which experiment a lease backs
+
+ALTER TABLE prefix_leases
+ADD COLUMN IF NOT EXISTS label TEXT,
+ADD COLUMN IF NOT EXISTS purpose TEXT;
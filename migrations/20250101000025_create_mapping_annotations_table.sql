@@ -0,0 +1,11 @@
+-- Migration to create mapping annotations table
+-- Each row is a key-value pair an integrator attached to a user's ASN mapping
+
+CREATE TABLE IF NOT EXISTS mapping_annotations (
+    mapping_id UUID NOT NULL REFERENCES user_asn_mappings(id) ON DELETE CASCADE,
+    key VARCHAR(64) NOT NULL,
+    value TEXT NOT NULL,
+    updated_by TEXT NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (mapping_id, key)
+);
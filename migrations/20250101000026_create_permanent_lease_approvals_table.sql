@@ -0,0 +1,8 @@
+-- Migration to create permanent lease approvals table
+-- Users listed here may lease prefixes without an end (end_time 9999-12-31)
+
+CREATE TABLE IF NOT EXISTS permanent_lease_approvals (
+    user_hash VARCHAR(64) PRIMARY KEY,
+    reason TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
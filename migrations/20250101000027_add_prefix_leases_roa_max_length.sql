@@ -0,0 +1,5 @@
+-- Migration to add a ROA maxLength to prefix_leases table
+-- Lets more-specific announcements of a lease validate in the ROA export
+
+ALTER TABLE prefix_leases
+ADD COLUMN IF NOT EXISTS roa_max_length SMALLINT CHECK (roa_max_length BETWEEN 0 AND 128);
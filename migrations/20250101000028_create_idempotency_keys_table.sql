@@ -0,0 +1,16 @@
+-- Migration to create idempotency keys table
+-- Responses of allocation requests, replayed when a client retries with the same Idempotency-Key
+
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    user_hash VARCHAR(64) NOT NULL,
+    idempotency_key VARCHAR(255) NOT NULL,
+    -- SHA-256 of the method, path, query and body of the request
+    request_hash VARCHAR(64) NOT NULL,
+    -- Unset while the request is being processed
+    status SMALLINT,
+    response TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_hash, idempotency_key)
+);
+
+CREATE INDEX IF NOT EXISTS idx_idempotency_keys_created_at ON idempotency_keys(created_at);
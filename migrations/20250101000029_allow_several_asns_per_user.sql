@@ -0,0 +1,4 @@
+-- Migration to allow several ASNs per user
+-- Experiments with two origin ASNs (e.g. route leaks) need more than one mapping per user
+
+ALTER TABLE user_asn_mappings DROP CONSTRAINT IF EXISTS user_asn_mappings_user_hash_key;
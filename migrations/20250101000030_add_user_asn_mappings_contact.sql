@@ -0,0 +1,6 @@
+-- Migration to add a description and an abuse contact to user_asn_mappings table
+-- Lets registry/IRR tooling describe the ASN and who to reach about it
+
+ALTER TABLE user_asn_mappings
+ADD COLUMN IF NOT EXISTS description TEXT,
+ADD COLUMN IF NOT EXISTS abuse_contact TEXT;
@@ -0,0 +1,17 @@
+-- Migration to create invite_codes table
+-- Each row is a workshop slot: an ASN and leases set aside ahead of a course,
+-- held by a placeholder user until a student redeems the code
+
+CREATE TABLE IF NOT EXISTS invite_codes (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    code_hash VARCHAR(64) NOT NULL UNIQUE,
+    workshop VARCHAR(64) NOT NULL,
+    slot_user_hash VARCHAR(64) NOT NULL UNIQUE,
+    asn BIGINT NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    redeemed_by VARCHAR(64),
+    redeemed_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_invite_codes_workshop ON invite_codes(workshop);
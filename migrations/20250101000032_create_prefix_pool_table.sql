@@ -0,0 +1,13 @@
+-- Migration to create prefix_pool table
+-- Each row adds a prefix to the pool file or overrides one of its prefixes,
+-- so operators can grow or shrink the pool without a restart
+
+CREATE TABLE IF NOT EXISTS prefix_pool (
+    prefix CIDR PRIMARY KEY,
+    class VARCHAR(16),
+    status VARCHAR(16) NOT NULL DEFAULT 'active'
+        CHECK (status IN ('active', 'disabled', 'retired')),
+    note TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,96 @@
+-- Migration to create announcement_permissions table
+-- Read model of who may announce what: one row per prefix a user holds and
+-- ASN of theirs allowed to originate it, from leases and verified external
+-- prefixes. Triggers rebuild a user's rows whenever their leases, ASNs or
+-- external prefixes change, so exports read this table instead of joining.
+
+CREATE TABLE IF NOT EXISTS announcement_permissions (
+    prefix CIDR NOT NULL,
+    max_length SMALLINT NOT NULL,
+    -- NULL while the holder has no ASN: the prefix is held but can't be originated
+    origin_asn BIGINT,
+    -- Orders the holder's ASNs, first assigned first
+    asn_assigned_at TIMESTAMP WITH TIME ZONE,
+    user_hash VARCHAR(64) NOT NULL,
+    -- One of: lease, external
+    source VARCHAR(16) NOT NULL,
+    -- Lease or external prefix granting the permission
+    source_id UUID NOT NULL,
+    -- Sites where the prefix may be announced (NULL for every site)
+    sites TEXT[],
+    -- Route origin validation state of external prefixes
+    rpki_state VARCHAR(16),
+    valid_from TIMESTAMP WITH TIME ZONE NOT NULL,
+    -- NULL for permissions without an end (external prefixes)
+    valid_to TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX IF NOT EXISTS idx_announcement_permissions_user_hash
+ON announcement_permissions (user_hash);
+
+CREATE INDEX IF NOT EXISTS idx_announcement_permissions_validity
+ON announcement_permissions (valid_from, valid_to);
+
+-- Rebuild the permissions of a user from their leases, ASNs and external prefixes
+CREATE OR REPLACE FUNCTION refresh_announcement_permissions(holder VARCHAR) RETURNS VOID AS $$
+BEGIN
+    -- Serialize rebuilds of the same user across transactions
+    PERFORM pg_advisory_xact_lock(hashtext('announcement_permissions:' || holder));
+
+    DELETE FROM announcement_permissions WHERE user_hash = holder;
+
+    INSERT INTO announcement_permissions
+        (prefix, max_length, origin_asn, asn_assigned_at, user_hash, source, source_id,
+         sites, rpki_state, valid_from, valid_to)
+    SELECT l.prefix, COALESCE(l.roa_max_length, masklen(l.prefix)), m.asn, m.created_at,
+           l.user_hash, 'lease', l.id, l.sites, NULL, l.start_time, l.end_time
+    FROM prefix_leases l
+    LEFT JOIN user_asn_mappings m ON m.user_hash = l.user_hash
+    WHERE l.user_hash = holder AND l.end_time > NOW()
+    UNION ALL
+    SELECT e.prefix::cidr, masklen(e.prefix::cidr), m.asn, m.created_at,
+           e.user_hash, 'external', e.id, NULL, e.rpki_state,
+           COALESCE(e.verified_at, e.created_at), NULL
+    FROM external_prefixes e
+    LEFT JOIN user_asn_mappings m ON m.user_hash = e.user_hash
+    WHERE e.user_hash = holder AND e.status = 'verified';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION announcement_permissions_changed() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        PERFORM refresh_announcement_permissions(OLD.user_hash);
+    END IF;
+    IF TG_OP = 'INSERT' OR (TG_OP = 'UPDATE' AND NEW.user_hash IS DISTINCT FROM OLD.user_hash) THEN
+        PERFORM refresh_announcement_permissions(NEW.user_hash);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS prefix_leases_announcement_permissions ON prefix_leases;
+CREATE TRIGGER prefix_leases_announcement_permissions
+AFTER INSERT OR DELETE OR UPDATE OF user_hash, prefix, start_time, end_time, sites, roa_max_length
+ON prefix_leases
+FOR EACH ROW EXECUTE FUNCTION announcement_permissions_changed();
+
+DROP TRIGGER IF EXISTS user_asn_mappings_announcement_permissions ON user_asn_mappings;
+CREATE TRIGGER user_asn_mappings_announcement_permissions
+AFTER INSERT OR DELETE OR UPDATE OF user_hash, asn, created_at
+ON user_asn_mappings
+FOR EACH ROW EXECUTE FUNCTION announcement_permissions_changed();
+
+DROP TRIGGER IF EXISTS external_prefixes_announcement_permissions ON external_prefixes;
+CREATE TRIGGER external_prefixes_announcement_permissions
+AFTER INSERT OR DELETE OR UPDATE OF user_hash, prefix, status, verified_at, rpki_state
+ON external_prefixes
+FOR EACH ROW EXECUTE FUNCTION announcement_permissions_changed();
+
+-- Build the permissions of the existing leases and external prefixes
+SELECT refresh_announcement_permissions(holders.user_hash)
+FROM (
+    SELECT user_hash FROM prefix_leases WHERE end_time > NOW()
+    UNION
+    SELECT user_hash FROM external_prefixes WHERE status = 'verified'
+) holders;
@@ -0,0 +1,12 @@
+-- Migration to prevent overlapping leases of nested prefixes
+-- Leases may be /56 or /64 slices of a pool /48: a slice can't be leased
+-- while the /48 or a slice overlapping it is, and the other way around
+
+ALTER TABLE prefix_leases DROP CONSTRAINT IF EXISTS prefix_leases_no_overlap;
+
+ALTER TABLE prefix_leases
+ADD CONSTRAINT prefix_leases_no_overlap
+EXCLUDE USING gist (
+    (prefix::inet) inet_ops WITH &&,
+    tstzrange(start_time, end_time) WITH &&
+);
@@ -0,0 +1,7 @@
+-- Migration to track which channels of an alert threshold were notified
+-- A failed channel is retried alone on the next run, so the channels that
+-- already got the event don't receive it twice
+
+ALTER TABLE alert_thresholds
+ADD COLUMN IF NOT EXISTS notified_event VARCHAR(32),
+ADD COLUMN IF NOT EXISTS notified_channels TEXT[] NOT NULL DEFAULT '{}';
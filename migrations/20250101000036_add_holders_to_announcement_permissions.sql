@@ -0,0 +1,77 @@
+-- Migration to add the holders' details to announcement_permissions table
+-- The service mappings read the read model too: every ASN a user holds gets
+-- an `asn` row (without a prefix), and rows carry the details the mappings
+-- show (IdP user ID, ASN description and contact, lease labels)
+
+ALTER TABLE announcement_permissions
+ALTER COLUMN prefix DROP NOT NULL,
+ALTER COLUMN max_length DROP NOT NULL,
+-- IdP user ID of the holder, on `asn` rows
+ADD COLUMN IF NOT EXISTS holder_id VARCHAR(255),
+-- Description and abuse contact of the ASN, on `asn` rows
+ADD COLUMN IF NOT EXISTS description TEXT,
+ADD COLUMN IF NOT EXISTS abuse_contact TEXT,
+-- Label and purpose of the lease, on `lease` rows
+ADD COLUMN IF NOT EXISTS label TEXT,
+ADD COLUMN IF NOT EXISTS purpose TEXT,
+-- Last change to the ASN mapping, lease or external prefix of the row
+ADD COLUMN IF NOT EXISTS updated_at TIMESTAMP WITH TIME ZONE;
+
+CREATE OR REPLACE FUNCTION refresh_announcement_permissions(holder VARCHAR) RETURNS VOID AS $$
+BEGIN
+    -- Serialize rebuilds of the same user across transactions
+    PERFORM pg_advisory_xact_lock(hashtext('announcement_permissions:' || holder));
+
+    DELETE FROM announcement_permissions WHERE user_hash = holder;
+
+    INSERT INTO announcement_permissions
+        (prefix, max_length, origin_asn, asn_assigned_at, user_hash, source, source_id,
+         sites, rpki_state, valid_from, valid_to, holder_id, description, abuse_contact,
+         label, purpose, updated_at)
+    SELECT l.prefix, COALESCE(l.roa_max_length, masklen(l.prefix)), m.asn, m.created_at,
+           l.user_hash, 'lease', l.id, l.sites, NULL, l.start_time, l.end_time,
+           NULL, NULL, NULL, l.label, l.purpose, l.updated_at
+    FROM prefix_leases l
+    LEFT JOIN user_asn_mappings m ON m.user_hash = l.user_hash
+    WHERE l.user_hash = holder AND l.end_time > NOW()
+    UNION ALL
+    SELECT e.prefix::cidr, masklen(e.prefix::cidr), m.asn, m.created_at,
+           e.user_hash, 'external', e.id, NULL, e.rpki_state,
+           COALESCE(e.verified_at, e.created_at), NULL,
+           NULL, NULL, NULL, NULL, NULL, e.updated_at
+    FROM external_prefixes e
+    LEFT JOIN user_asn_mappings m ON m.user_hash = e.user_hash
+    WHERE e.user_hash = holder AND e.status = 'verified'
+    UNION ALL
+    SELECT NULL, NULL, m.asn, m.created_at,
+           m.user_hash, 'asn', m.id, NULL, NULL, m.created_at, NULL,
+           m.user_id, m.description, m.abuse_contact, NULL, NULL, m.updated_at
+    FROM user_asn_mappings m
+    WHERE m.user_hash = holder;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Rebuild on changes to the details shown too
+DROP TRIGGER IF EXISTS prefix_leases_announcement_permissions ON prefix_leases;
+CREATE TRIGGER prefix_leases_announcement_permissions
+AFTER INSERT OR DELETE OR UPDATE OF user_hash, prefix, start_time, end_time, sites, roa_max_length,
+    label, purpose, updated_at
+ON prefix_leases
+FOR EACH ROW EXECUTE FUNCTION announcement_permissions_changed();
+
+DROP TRIGGER IF EXISTS user_asn_mappings_announcement_permissions ON user_asn_mappings;
+CREATE TRIGGER user_asn_mappings_announcement_permissions
+AFTER INSERT OR DELETE OR UPDATE OF user_hash, asn, created_at, user_id, description,
+    abuse_contact, updated_at
+ON user_asn_mappings
+FOR EACH ROW EXECUTE FUNCTION announcement_permissions_changed();
+
+-- Rebuild every holder, adding the rows of users holding only ASNs
+SELECT refresh_announcement_permissions(holders.user_hash)
+FROM (
+    SELECT user_hash FROM prefix_leases WHERE end_time > NOW()
+    UNION
+    SELECT user_hash FROM external_prefixes WHERE status = 'verified'
+    UNION
+    SELECT user_hash FROM user_asn_mappings
+) holders;
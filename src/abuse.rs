@@ -0,0 +1,447 @@
+//! Abuse report intake.
+//!
+//! Gives external parties a standard contact path for announcements made from
+//! the lab: a report names a prefix, is attached to whoever holds it as an
+//! incident, and admins are notified. The endpoint is public, so it is rate
+//! limited per client address and can require a captcha.
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Extension, State},
+    http::{HeaderMap, StatusCode},
+};
+use ipnet::Ipv6Net;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::database::NewIncident;
+use crate::pool_prefixes::POOL_PREFIX_LENGTH;
+use crate::rate_limit::{self, RateLimiter};
+use crate::types::{Prefix, UserHash};
+use crate::{AppState, retry};
+
+/// Source of incidents opened from abuse reports
+pub const SOURCE_ABUSE_REPORT: &str = "abuse_report";
+
+/// Default number of reports accepted per client address and hour
+pub const DEFAULT_REPORTS_PER_HOUR: u32 = 5;
+
+/// Longest accepted description, in characters
+pub const MAX_DESCRIPTION_LENGTH: usize = 5000;
+
+/// Longest accepted reporter contact, in characters
+pub const MAX_CONTACT_LENGTH: usize = 320;
+
+/// Most incidents a report opens, one per lease or external prefix; a
+/// report overlapping more is recorded as a single unattached incident
+pub const MAX_INCIDENTS_PER_REPORT: usize = 10;
+
+/// Captcha provider verifying report tokens (hCaptcha, Turnstile and
+/// reCAPTCHA share the same `siteverify` API)
+#[derive(Clone)]
+pub struct CaptchaConfig {
+    pub verify_url: String,
+    secret: String,
+}
+
+impl std::fmt::Debug for CaptchaConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CaptchaConfig")
+            .field("verify_url", &self.verify_url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CaptchaConfig {
+    pub fn new(verify_url: impl Into<String>, secret: impl Into<String>) -> Self {
+        Self {
+            verify_url: verify_url.into(),
+            secret: secret.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CaptchaResponse {
+    success: bool,
+}
+
+/// Configuration of the abuse report endpoint
+#[derive(Debug, Clone)]
+pub struct AbuseReports {
+    limiter: RateLimiter,
+    /// Key the rate limit by the last `X-Forwarded-For` address, set by a
+    /// trusted reverse proxy, instead of the peer address
+    pub trust_forwarded_for: bool,
+    pub captcha: Option<CaptchaConfig>,
+    /// Webhook receiving a JSON notification of every report
+    pub notify_webhook: Option<String>,
+    /// Address emailed about every report (needs `--smtp-url`)
+    pub notify_email: Option<String>,
+}
+
+impl Default for AbuseReports {
+    fn default() -> Self {
+        Self::new(DEFAULT_REPORTS_PER_HOUR)
+    }
+}
+
+impl AbuseReports {
+    /// Accept `reports_per_hour` reports per client address
+    pub fn new(reports_per_hour: u32) -> Self {
+        Self {
+            limiter: RateLimiter::new(reports_per_hour, Duration::from_secs(3600)),
+            trust_forwarded_for: false,
+            captcha: None,
+            notify_webhook: None,
+            notify_email: None,
+        }
+    }
+
+    /// Address reports are rate limited by
+    fn client_ip(&self, peer: Option<SocketAddr>, headers: &HeaderMap) -> IpAddr {
+        rate_limit::client_ip(self.trust_forwarded_for, peer, headers)
+    }
+}
+
+// Request/Response types
+
+#[derive(Deserialize)]
+pub struct AbuseReportRequest {
+    /// Reported prefix or address
+    prefix: String,
+    description: String,
+    /// How to reach the reporter
+    contact: Option<String>,
+    /// Token of the captcha widget, when one is configured
+    captcha_token: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AbuseReportResponse {
+    /// Reference of the report, to quote in follow-ups
+    reference: Uuid,
+    message: &'static str,
+}
+
+/// Notification sent to admins about a report
+#[derive(Serialize)]
+struct AbuseNotification<'a> {
+    event: &'static str,
+    prefix: &'a str,
+    description: &'a str,
+    contact: Option<&'a str>,
+    /// Incidents opened, one per holder of the reported space
+    incidents: Vec<Value>,
+    /// Leases and external prefixes overlapping the reported space
+    holders: usize,
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.into()
+        })),
+    )
+}
+
+/// Parse a reported prefix, accepting a bare address as a /128. Ranges
+/// shorter than `min_length` (the shortest pool prefixes) would match every
+/// lease of the pool and are refused.
+fn parse_prefix(prefix: &str, min_length: u8) -> Result<Ipv6Net, String> {
+    let prefix = prefix.trim();
+    let net = prefix
+        .parse::<Ipv6Net>()
+        .map(|net| net.trunc())
+        .or_else(|_| prefix.parse::<Ipv6Addr>().map(Ipv6Net::from))
+        .map_err(|_| format!("Invalid IPv6 prefix or address '{}'", prefix))?;
+    if net.prefix_len() < min_length {
+        return Err(format!(
+            "Prefix {} is too broad, report a /{} or longer",
+            net, min_length
+        ));
+    }
+    Ok(net)
+}
+
+/// Check a captcha token with the provider
+async fn verify_captcha(
+    config: &CaptchaConfig,
+    token: &str,
+    ip: IpAddr,
+) -> Result<bool, reqwest::Error> {
+    let response = reqwest::Client::new()
+        .post(&config.verify_url)
+        .timeout(Duration::from_secs(10))
+        .form(&[
+            ("secret", config.secret.as_str()),
+            ("response", token),
+            ("remoteip", &ip.to_string()),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<CaptchaResponse>()
+        .await?;
+    Ok(response.success)
+}
+
+/// Send a notification through every configured channel
+async fn notify(state: &AppState, notification: &AbuseNotification<'_>) {
+    let config = &state.abuse_reports;
+    if let Some(url) = config.notify_webhook.as_deref() {
+        let result = reqwest::Client::new()
+            .post(url)
+            .timeout(Duration::from_secs(10))
+            .json(notification)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        if let Err(err) = result {
+            error!("Failed to notify {} about an abuse report: {}", url, err);
+        }
+    }
+
+    #[cfg(feature = "alerts")]
+    if let (Some(email), Some(mailer)) = (config.notify_email.as_deref(), &state.alert_mailer) {
+        let body = format!(
+            "Abuse report about {}\n\nContact: {}\nIncidents: {}\n\n{}\n",
+            notification.prefix,
+            notification.contact.unwrap_or("none given"),
+            serde_json::to_string(&notification.incidents).unwrap_or_default(),
+            notification.description
+        );
+        let subject = format!(
+            "[peerlab-gateway] Abuse report about {}",
+            notification.prefix
+        );
+        if let Err(err) = mailer.send(email, &subject, body).await {
+            error!("Failed to email {} about an abuse report: {}", email, err);
+        }
+    }
+}
+
+// Handler implementations
+
+/// Accept an abuse report, opening an incident for each holder of the space
+#[instrument(name = "handler", skip_all, fields(operation = "submit_abuse_report", prefix = %request.prefix))]
+pub async fn submit_report(
+    State(state): State<AppState>,
+    peer: Option<Extension<ConnectInfo<SocketAddr>>>,
+    headers: HeaderMap,
+    Json(request): Json<AbuseReportRequest>,
+) -> Result<(StatusCode, Json<AbuseReportResponse>), ApiError> {
+    let config = &state.abuse_reports;
+    let ip = config.client_ip(peer.map(|Extension(ConnectInfo(addr))| addr), &headers);
+    if !config.limiter.check(ip) {
+        warn!("Rate limited abuse reports from {}", ip);
+        let (status, Json(mut body)) = api_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many reports, try again later",
+        );
+        body[retry::RETRY_AFTER_FIELD] = json!(config.limiter.retry_after(ip).as_secs().max(1));
+        return Err((status, Json(body)));
+    }
+
+    let min_length = state
+        .prefix_pool
+        .load()
+        .lengths()
+        .iter()
+        .copied()
+        .min()
+        .unwrap_or(POOL_PREFIX_LENGTH);
+    let prefix = parse_prefix(&request.prefix, min_length)
+        .map(Prefix::from)
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+    let description = request.description.trim();
+    if description.is_empty() || description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Description must be between 1 and {} characters",
+                MAX_DESCRIPTION_LENGTH
+            ),
+        ));
+    }
+    let contact = request
+        .contact
+        .as_deref()
+        .map(str::trim)
+        .filter(|c| !c.is_empty());
+    if contact.is_some_and(|c| c.chars().count() > MAX_CONTACT_LENGTH) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Contact must be at most {} characters", MAX_CONTACT_LENGTH),
+        ));
+    }
+
+    if let Some(captcha) = &config.captcha {
+        let Some(token) = request.captcha_token.as_deref() else {
+            return Err(api_error(StatusCode::BAD_REQUEST, "A captcha is required"));
+        };
+        match verify_captcha(captcha, token, ip).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(api_error(
+                    StatusCode::FORBIDDEN,
+                    "Captcha verification failed",
+                ));
+            }
+            Err(err) => {
+                error!("Failed to verify captcha: {}", err);
+                return Err(api_error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Captcha verification is unavailable",
+                ));
+            }
+        }
+    }
+
+    // Resolve who currently holds the reported space
+    let internal_error = |err: sqlx::Error| {
+        error!("Failed to record abuse report: {}", err);
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to record abuse report",
+        )
+    };
+    let (leases, external) = tokio::try_join!(
+        state.database.get_active_leases_overlapping(&prefix),
+        state
+            .database
+            .get_verified_external_prefixes_overlapping(&prefix),
+    )
+    .map_err(internal_error)?;
+    let mut holders: Vec<(Option<&UserHash>, Option<Uuid>, String)> = leases
+        .iter()
+        .map(|l| (Some(&l.user_hash), Some(l.id), l.prefix.clone()))
+        .chain(
+            external
+                .iter()
+                .map(|e| (Some(&e.user_hash), None, e.prefix.clone())),
+        )
+        .collect();
+    let held = holders.len();
+    if held > MAX_INCIDENTS_PER_REPORT {
+        warn!(
+            "Abuse report about {} overlaps {} leases and external prefixes, opening a single incident",
+            prefix, held
+        );
+        holders.clear();
+    }
+    if holders.is_empty() {
+        holders.push((None, None, prefix.to_string()));
+    }
+
+    let mut incidents = Vec::with_capacity(holders.len());
+    for (user_hash, lease_id, held_prefix) in holders {
+        let incident = state
+            .database
+            .create_incident(&NewIncident {
+                user_hash,
+                asn: None,
+                lease_id,
+                prefix: Some(&held_prefix),
+                source: SOURCE_ABUSE_REPORT,
+                description,
+                reporter_contact: contact,
+            })
+            .await
+            .map_err(internal_error)?;
+        incidents.push(incident);
+    }
+    info!(
+        "Abuse report about {} opened {} incident(s)",
+        prefix,
+        incidents.len()
+    );
+
+    let reference = incidents[0].id;
+    let prefix = prefix.to_string();
+    let description = description.to_string();
+    let contact = contact.map(str::to_string);
+    tokio::spawn(async move {
+        let notification = AbuseNotification {
+            event: "abuse.reported",
+            prefix: &prefix,
+            description: &description,
+            contact: contact.as_deref(),
+            incidents: incidents
+                .iter()
+                .map(|i| {
+                    json!({
+                        "id": i.id,
+                        "user_hash": i.user_hash,
+                        "lease_id": i.lease_id,
+                        "prefix": i.prefix,
+                    })
+                })
+                .collect(),
+            holders: held,
+        };
+        notify(&state, &notification).await;
+    });
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(AbuseReportResponse {
+            reference,
+            message: "Report received",
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_prefix() {
+        assert_eq!(
+            parse_prefix("2001:db8:1::1/48", 48).unwrap(),
+            "2001:db8:1::/48".parse::<Ipv6Net>().unwrap()
+        );
+        assert_eq!(
+            parse_prefix(" 2001:db8:1::1 ", 48).unwrap(),
+            "2001:db8:1::1/128".parse::<Ipv6Net>().unwrap()
+        );
+        assert!(parse_prefix("192.0.2.0/24", 48).is_err());
+        // Covering prefixes would match every lease
+        assert!(parse_prefix("::/0", 48).unwrap_err().contains("too broad"));
+        assert!(parse_prefix("2001:db8::/32", 48).is_err());
+        assert!(parse_prefix("2001:db8::/44", 44).is_ok());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "2001:db8::1, 2001:db8::2".parse().unwrap(),
+        );
+        let peer: SocketAddr = "[2001:db8::ff]:443".parse().unwrap();
+
+        let mut config = AbuseReports::default();
+        assert_eq!(config.client_ip(Some(peer), &headers), peer.ip());
+        config.trust_forwarded_for = true;
+        assert_eq!(
+            config.client_ip(Some(peer), &headers),
+            "2001:db8::2".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            config.client_ip(None, &HeaderMap::new()),
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        );
+    }
+}
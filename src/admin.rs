@@ -0,0 +1,734 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+    routing::{delete, get, post, put},
+};
+use chrono::{DateTime, Utc};
+use ipnet::Ipv6Net;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tower_http::trace::TraceLayer;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::database::{LeaseFilter, PermanentLeaseApproval};
+use crate::events::{AgentEvent, EVENT_INVALIDATE, EVENT_LEASE_UPDATED, EventPriority};
+use crate::pool_prefixes::PrefixClass;
+use crate::types::UserHash;
+use crate::{
+    AppState, export, impersonation, incidents, jwt, pool_entries, stats, telemetry, transfers,
+    usage, workshops,
+};
+
+/// Admin API (requires the admin key; disabled when no key is configured)
+pub fn create_admin_app(state: AppState) -> Router {
+    let router = Router::new()
+        .route("/config", get(crate::config::get_config))
+        .route("/stats/forecast", get(get_forecast))
+        .route("/stats/asn-pool", get(get_asn_pool))
+        .route("/pools/preview", get(preview_pools))
+        .route("/pools/prefixes", get(pool_entries::list_prefixes))
+        .route(
+            "/pools/prefixes/{prefix}",
+            put(pool_entries::set_prefix).delete(pool_entries::delete_prefix),
+        )
+        .route("/simulate", post(crate::simulate::simulate))
+        .route("/users/{user_hash}", get(get_user))
+        .route("/users/{user_hash}/asn", delete(revoke_asn))
+        .route(
+            "/users/{user_hash}/permanent-leases",
+            put(approve_permanent_leases).delete(revoke_permanent_leases),
+        )
+        .route("/permanent-leases", get(list_permanent_lease_approvals))
+        .route(
+            "/users/{user_hash}/asn/transfer",
+            post(transfers::transfer_asn),
+        )
+        .route("/leases/revoke", post(revoke_leases))
+        .route("/leases/{id}", delete(revoke_lease).patch(update_lease))
+        .route("/leases/{id}/transfer", post(transfers::transfer_lease))
+        .route("/leases/{id}/roa", put(set_lease_roa))
+        .route(
+            "/exports/safety",
+            get(crate::export_safety::get_export_safety),
+        )
+        .route("/transfers", get(transfers::list_transfers))
+        .route("/workshops", post(workshops::create_workshop))
+        .route(
+            "/workshops/{name}",
+            get(workshops::get_workshop).delete(workshops::delete_workshop),
+        )
+        .route(
+            "/incidents",
+            get(incidents::list_incidents).post(incidents::open_incident),
+        )
+        .route("/incidents/{id}", get(incidents::get_incident))
+        .route("/incidents/{id}/comments", post(incidents::add_comment))
+        .route("/incidents/{id}/close", post(incidents::close_incident))
+        .route("/usage", get(usage::get_top_usage))
+        .route("/usage/{kind}/{subject}", get(usage::get_subject_usage))
+        .route(
+            "/impersonations",
+            get(impersonation::list_impersonations).post(impersonation::create_impersonation),
+        )
+        .route(
+            "/impersonations/{id}",
+            delete(impersonation::revoke_impersonation),
+        );
+
+    #[cfg(feature = "alerts")]
+    let router = router
+        .route(
+            "/alerts",
+            get(crate::alerts::list_alerts).post(crate::alerts::create_alert),
+        )
+        .route("/alerts/{id}", delete(crate::alerts::delete_alert));
+
+    #[cfg(feature = "chaos")]
+    let router = router
+        .route(
+            "/chaos",
+            get(crate::chaos::list_faults).delete(crate::chaos::clear_faults),
+        )
+        .route(
+            "/chaos/{fault}",
+            axum::routing::put(crate::chaos::inject_fault).delete(crate::chaos::clear_fault),
+        );
+
+    router
+        .with_state(state.clone())
+        .layer(axum::middleware::from_fn_with_state(
+            state,
+            validate_admin_key,
+        ))
+        .layer(TraceLayer::new_for_http().make_span_with(telemetry::http_span))
+}
+
+// Admin key validation middleware
+async fn validate_admin_key(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, jwt::AuthorizationError> {
+    let Some(admin_key) = state.admin_key.as_deref() else {
+        return Err(jwt::AuthorizationError::new(
+            jwt::AuthErrorReason::Forbidden,
+            "Admin API is disabled (no admin key configured)",
+        ));
+    };
+
+    let auth_header = request
+        .headers()
+        .get("authorization")
+        .and_then(|h| h.to_str().ok());
+
+    match jwt::extract_bearer_token(auth_header) {
+        Ok(key) if key == admin_key => Ok(next.run(request).await),
+        Ok(_) => {
+            warn!("Unauthorized access attempt to admin API");
+            Err(jwt::AuthorizationError::new(
+                jwt::AuthErrorReason::InvalidToken,
+                "Invalid admin key",
+            ))
+        }
+        Err(err) => {
+            warn!("Unauthorized access attempt to admin API");
+            Err(err)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ForecastQuery {
+    window_days: Option<i64>,
+}
+
+/// Get pool utilization and exhaustion forecasts
+async fn get_forecast(
+    State(state): State<AppState>,
+    Query(query): Query<ForecastQuery>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let window_days = query
+        .window_days
+        .unwrap_or(stats::DEFAULT_FORECAST_WINDOW_DAYS)
+        .clamp(1, 365);
+
+    match stats::pool_forecasts(&state, window_days).await {
+        Ok(pools) => Ok(Json(json!({
+            "window_days": window_days,
+            "pools": pools,
+        }))),
+        Err(err) => {
+            error!("Failed to compute pool forecast: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": 500,
+                    "message": "Failed to compute pool forecast"
+                })),
+            ))
+        }
+    }
+}
+
+/// Classify the ASN pool into public, private, documentation and reserved space
+async fn get_asn_pool(State(state): State<AppState>) -> Json<Value> {
+    let pool = state.asn_pool.load();
+    Json(json!({
+        "size": pool.size(),
+        "classes": pool.class_sizes(),
+        "ranges": pool
+            .classify()
+            .into_iter()
+            .map(|(start, end, class)| json!({
+                "start": start,
+                "end": end,
+                "size": end - start + 1,
+                "class": class,
+            }))
+            .collect::<Vec<_>>(),
+        "warnings": pool.policy_warnings(),
+    }))
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+/// Show the ASN and the prefix of each class the allocators would pick next
+async fn preview_pools(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+    let (asn_pool, prefix_pool) = (state.asn_pool.load(), state.prefix_pool.load());
+    let (next_asn, mappings, leases) = tokio::try_join!(
+        asn_pool.find_available_asn(&state.database),
+        state.database.get_all_asn_mappings(),
+        state.database.get_all_active_leases(),
+    )
+    .map_err(|err| {
+        error!("Failed to preview pools: {}", err);
+        api_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to preview pools")
+    })?;
+
+    let assigned = mappings.iter().filter(|m| asn_pool.contains(m.asn)).count() as i64;
+    let leased: Vec<Ipv6Net> = leases
+        .iter()
+        .filter_map(|lease| lease.prefix.parse().ok())
+        .collect();
+    let preview = |class: Option<PrefixClass>| {
+        json!({
+            "next": prefix_pool
+                .find_available_prefix(&leased, class, None)
+                .map(|p| p.to_string()),
+            "available": prefix_pool.count_available(&leased, class),
+        })
+    };
+
+    Ok(Json(json!({
+        "asn": {
+            "next": next_asn,
+            "available": asn_pool.size() - assigned,
+        },
+        "prefix": {
+            "any": preview(None),
+            "classes": PrefixClass::ALL
+                .iter()
+                .map(|class| (class.name().to_string(), preview(Some(*class))))
+                .collect::<serde_json::Map<_, _>>(),
+        },
+    })))
+}
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.into()
+        })),
+    )
+}
+
+/// Get a user's ASN and active leases, including lease ids
+async fn get_user(
+    State(state): State<AppState>,
+    Path(user_hash): Path<UserHash>,
+) -> Result<Json<Value>, ApiError> {
+    let (open_incidents, total_incidents) = state
+        .database
+        .count_user_incidents(&user_hash)
+        .await
+        .map_err(|err| {
+        error!("Failed to count incidents of user {}: {}", user_hash, err);
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to retrieve user information",
+        )
+    })?;
+    match state.database.get_user_info(&user_hash).await {
+        Ok(Some((mappings, leases))) => Ok(Json(json!({
+            "user_hash": user_hash,
+            "asn": mappings.first().map(|m| m.asn),
+            "asns": mappings.iter().map(|m| m.asn).collect::<Vec<_>>(),
+            "active_leases": leases
+                .into_iter()
+                .map(|lease| json!({
+                    "id": lease.id,
+                    "prefix": lease.prefix,
+                    "start_time": lease.start_time.to_rfc3339(),
+                    "end_time": lease.end_time.to_rfc3339(),
+                    "sites": lease.sites,
+                    "permanent": lease.is_permanent(),
+                    "roa_max_length": lease.roa_max_length,
+                }))
+                .collect::<Vec<_>>(),
+            "incidents": {"open": open_incidents, "total": total_incidents},
+        }))),
+        Ok(None) => Err(api_error(StatusCode::NOT_FOUND, "User not found")),
+        Err(err) => {
+            error!("Failed to get user {}: {}", user_hash, err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to retrieve user information",
+            ))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RevokeQuery {
+    reason: Option<String>,
+}
+
+/// Force-revoke an active lease so its prefix can be reassigned.
+///
+/// Agents get a high-priority invalidation before any later lease of the
+/// prefix, so they tear down the old filters first.
+#[instrument(name = "handler", skip_all, fields(operation = "revoke_lease", lease_id = %id, prefix = tracing::field::Empty))]
+async fn revoke_lease(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<RevokeQuery>,
+) -> Result<StatusCode, ApiError> {
+    let lease = match state.database.revoke_prefix_lease(id).await {
+        Ok(Some(lease)) => lease,
+        Ok(None) => {
+            return Err(api_error(
+                StatusCode::NOT_FOUND,
+                "Lease not found or already ended",
+            ));
+        }
+        Err(err) => {
+            error!("Failed to revoke lease {}: {}", id, err);
+            return Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to revoke lease",
+            ));
+        }
+    };
+
+    tracing::Span::current().record("prefix", lease.prefix.as_str());
+    info!(
+        "Revoked lease {} of {} for user {} ({})",
+        lease.id,
+        lease.prefix,
+        lease.user_hash,
+        query.reason.as_deref().unwrap_or("no reason given")
+    );
+    state.agent_events.publish(
+        AgentEvent::new(
+            EVENT_INVALIDATE,
+            EventPriority::High,
+            json!({
+                "resource": "prefix",
+                "id": lease.id,
+                "user_hash": lease.user_hash,
+                "prefix": lease.prefix,
+                "reason": query.reason,
+            }),
+        )
+        .at_sites(lease.sites),
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct RevokeLeasesRequest {
+    user_hash: Option<UserHash>,
+    /// Range the leased prefixes fall in
+    prefix: Option<String>,
+    site: Option<String>,
+    created_before: Option<DateTime<Utc>>,
+    reason: Option<String>,
+    /// List the matching leases without revoking them
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Force-revoke every active lease matching a filter, e.g. when
+/// decommissioning a site or responding to abuse
+#[instrument(name = "handler", skip_all, fields(operation = "revoke_leases", count = tracing::field::Empty))]
+async fn revoke_leases(
+    State(state): State<AppState>,
+    Json(request): Json<RevokeLeasesRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let prefix = request
+        .prefix
+        .as_deref()
+        .map(|p| p.parse::<Ipv6Net>().map(|net| net.trunc()))
+        .transpose()
+        .map_err(|_| api_error(StatusCode::BAD_REQUEST, "Invalid IPv6 prefix"))?;
+    let filter = LeaseFilter {
+        user_hash: request.user_hash,
+        prefix,
+        site: request.site,
+        created_before: request.created_before,
+    };
+    if filter.is_empty() {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "At least one of user_hash, prefix, site or created_before is required",
+        ));
+    }
+
+    let result = if request.dry_run {
+        state.database.find_active_leases(&filter).await
+    } else {
+        state.database.revoke_prefix_leases(&filter).await
+    };
+    let leases = result.map_err(|err| {
+        error!("Failed to revoke leases: {}", err);
+        api_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to revoke leases")
+    })?;
+    tracing::Span::current().record("count", leases.len());
+
+    if !request.dry_run {
+        info!(
+            "Revoked {} leases matching {:?} ({})",
+            leases.len(),
+            filter,
+            request.reason.as_deref().unwrap_or("no reason given")
+        );
+        for lease in &leases {
+            state.agent_events.publish(
+                AgentEvent::new(
+                    EVENT_INVALIDATE,
+                    EventPriority::High,
+                    json!({
+                        "resource": "prefix",
+                        "id": lease.id,
+                        "user_hash": lease.user_hash,
+                        "prefix": lease.prefix,
+                        "reason": request.reason,
+                    }),
+                )
+                .at_sites(lease.sites.clone()),
+            );
+        }
+    }
+
+    Ok(Json(json!({
+        "dry_run": request.dry_run,
+        "count": leases.len(),
+        "leases": leases
+            .iter()
+            .map(|lease| json!({
+                "id": lease.id,
+                "user_hash": lease.user_hash,
+                "prefix": lease.prefix,
+                "start_time": lease.start_time.to_rfc3339(),
+                "end_time": lease.end_time.to_rfc3339(),
+                "sites": lease.sites,
+            }))
+            .collect::<Vec<_>>(),
+    })))
+}
+
+#[derive(Deserialize)]
+struct UpdateLeaseRequest {
+    end_time: DateTime<Utc>,
+    /// Why the lease is changed, logged and sent to agents
+    reason: String,
+}
+
+/// Shorten or extend an active lease, e.g. to wind an experiment down early
+#[instrument(name = "handler", skip_all, fields(operation = "update_lease", lease_id = %id, prefix = tracing::field::Empty))]
+async fn update_lease(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(request): Json<UpdateLeaseRequest>,
+) -> Result<Json<Value>, ApiError> {
+    if request.reason.trim().is_empty() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "A reason is required"));
+    }
+    if request.end_time <= Utc::now() {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "end_time must be in the future (use DELETE to end a lease now)",
+        ));
+    }
+
+    let lease = match state
+        .database
+        .set_prefix_lease_end_time(id, request.end_time)
+        .await
+    {
+        Ok(Some(lease)) => lease,
+        Ok(None) => {
+            return Err(api_error(
+                StatusCode::NOT_FOUND,
+                "Lease not found or already ended",
+            ));
+        }
+        Err(err) => {
+            error!("Failed to update lease {}: {}", id, err);
+            return Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to update lease",
+            ));
+        }
+    };
+
+    tracing::Span::current().record("prefix", lease.prefix.as_str());
+    info!(
+        "Moved the end of lease {} of {} for user {} to {} ({})",
+        lease.id,
+        lease.prefix,
+        lease.user_hash,
+        lease.end_time.to_rfc3339(),
+        request.reason.trim()
+    );
+    state.agent_events.publish(
+        AgentEvent::new(
+            EVENT_LEASE_UPDATED,
+            EventPriority::Normal,
+            json!({
+                "id": lease.id,
+                "user_hash": lease.user_hash,
+                "prefix": lease.prefix,
+                "start_time": lease.start_time.to_rfc3339(),
+                "end_time": lease.end_time.to_rfc3339(),
+                "reason": request.reason.trim(),
+            }),
+        )
+        .at_sites(lease.sites.clone()),
+    );
+    Ok(Json(json!({
+        "id": lease.id,
+        "prefix": lease.prefix,
+        "start_time": lease.start_time.to_rfc3339(),
+        "end_time": lease.end_time.to_rfc3339(),
+        "sites": lease.sites,
+    })))
+}
+
+#[derive(Deserialize)]
+struct ApprovePermanentLeasesRequest {
+    /// Why the user may hold prefixes indefinitely, logged and recorded
+    reason: String,
+}
+
+fn approval_json(approval: &PermanentLeaseApproval) -> Value {
+    json!({
+        "user_hash": approval.user_hash,
+        "reason": approval.reason,
+        "created_at": approval.created_at.to_rfc3339(),
+    })
+}
+
+/// List the users allowed to take permanent leases
+async fn list_permanent_lease_approvals(
+    State(state): State<AppState>,
+) -> Result<Json<Value>, ApiError> {
+    match state.database.get_permanent_lease_approvals().await {
+        Ok(approvals) => Ok(Json(json!({
+            "approvals": approvals.iter().map(approval_json).collect::<Vec<_>>(),
+        }))),
+        Err(err) => {
+            error!("Failed to list permanent lease approvals: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to list permanent lease approvals",
+            ))
+        }
+    }
+}
+
+/// Allow a user to take permanent leases, e.g. for a long-running measurement
+/// platform
+#[instrument(name = "handler", skip_all, fields(operation = "approve_permanent_leases", user_hash = %user_hash))]
+async fn approve_permanent_leases(
+    State(state): State<AppState>,
+    Path(user_hash): Path<UserHash>,
+    Json(request): Json<ApprovePermanentLeasesRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let reason = request.reason.trim();
+    if reason.is_empty() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "A reason is required"));
+    }
+    let approval = state
+        .database
+        .approve_permanent_leases(&user_hash, reason)
+        .await
+        .map_err(|err| {
+            error!(
+                "Failed to approve permanent leases for user {}: {}",
+                user_hash, err
+            );
+            api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to approve permanent leases",
+            )
+        })?;
+
+    info!(
+        "Allowed user {} to take permanent leases ({})",
+        user_hash, reason
+    );
+    state.audit.record(
+        "permanent_leases.approved",
+        json!({ "user_hash": user_hash, "reason": reason }),
+    );
+    Ok(Json(approval_json(&approval)))
+}
+
+/// Withdraw a user's approval for permanent leases. The permanent leases they
+/// hold are kept, end them with `DELETE /leases/{id}`.
+#[instrument(name = "handler", skip_all, fields(operation = "revoke_permanent_leases", user_hash = %user_hash))]
+async fn revoke_permanent_leases(
+    State(state): State<AppState>,
+    Path(user_hash): Path<UserHash>,
+) -> Result<StatusCode, ApiError> {
+    match state
+        .database
+        .revoke_permanent_lease_approval(&user_hash)
+        .await
+    {
+        Ok(true) => {
+            info!("Withdrew permanent lease approval of user {}", user_hash);
+            state.audit.record(
+                "permanent_leases.revoked",
+                json!({ "user_hash": user_hash }),
+            );
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err(api_error(
+            StatusCode::NOT_FOUND,
+            "User is not approved for permanent leases",
+        )),
+        Err(err) => {
+            error!(
+                "Failed to withdraw permanent lease approval of user {}: {}",
+                user_hash, err
+            );
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to withdraw permanent lease approval",
+            ))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SetLeaseRoaRequest {
+    /// Longest more-specific the ROA authorizes (`null` for the prefix length)
+    max_length: Option<u8>,
+    /// Why the ROA is changed, logged
+    reason: String,
+}
+
+/// Set the ROA maxLength of a current or upcoming lease, without the limit
+/// applied to users
+#[instrument(name = "handler", skip_all, fields(operation = "set_lease_roa", lease_id = %id))]
+async fn set_lease_roa(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(request): Json<SetLeaseRoaRequest>,
+) -> Result<Json<Value>, ApiError> {
+    if request.reason.trim().is_empty() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "A reason is required"));
+    }
+    let not_found = || api_error(StatusCode::NOT_FOUND, "Lease not found or already ended");
+    let internal_error = |err: sqlx::Error| {
+        error!("Failed to set ROA maxLength of lease {}: {}", id, err);
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to set ROA maxLength",
+        )
+    };
+    let lease = state
+        .database
+        .get_prefix_lease(id)
+        .await
+        .map_err(internal_error)?
+        .filter(|lease| lease.end_time > Utc::now())
+        .ok_or_else(not_found)?;
+    let prefix: Ipv6Net = lease.prefix.parse().map_err(|_| not_found())?;
+    let max_length = export::validate_roa_max_length(&prefix, request.max_length, 128)
+        .map_err(|message| api_error(StatusCode::BAD_REQUEST, message))?;
+    let lease = state
+        .database
+        .set_lease_roa_max_length(id, max_length)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+
+    info!(
+        "Set ROA maxLength of lease {} of {} for user {} to {} ({})",
+        lease.id,
+        lease.prefix,
+        lease.user_hash,
+        lease.roa_max_length(&prefix),
+        request.reason.trim()
+    );
+    crate::publish_roa_update(&state, &lease);
+    Ok(Json(json!({
+        "id": lease.id,
+        "prefix": lease.prefix,
+        "start_time": lease.start_time.to_rfc3339(),
+        "end_time": lease.end_time.to_rfc3339(),
+        "roa_max_length": lease.roa_max_length(&prefix),
+    })))
+}
+
+/// Force-revoke a user's ASNs, returning them to the pool for reassignment
+#[instrument(name = "handler", skip_all, fields(operation = "revoke_asn", user_hash = %user_hash))]
+async fn revoke_asn(
+    State(state): State<AppState>,
+    Path(user_hash): Path<UserHash>,
+    Query(query): Query<RevokeQuery>,
+) -> Result<StatusCode, ApiError> {
+    // The user can't be assigned a new ASN while the old one is revoked
+    let _guard = state.user_locks.lock(&user_hash).await;
+
+    let mappings = match state.database.delete_user_asns(&user_hash).await {
+        Ok(mappings) if !mappings.is_empty() => mappings,
+        Ok(_) => return Err(api_error(StatusCode::NOT_FOUND, "User has no ASN")),
+        Err(err) => {
+            error!("Failed to revoke ASN of user {}: {}", user_hash, err);
+            return Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to revoke ASN",
+            ));
+        }
+    };
+
+    for mapping in &mappings {
+        info!(
+            "Revoked ASN {} of user {} ({})",
+            mapping.asn,
+            user_hash,
+            query.reason.as_deref().unwrap_or("no reason given")
+        );
+        state.agent_events.publish(AgentEvent::new(
+            EVENT_INVALIDATE,
+            EventPriority::High,
+            json!({
+                "resource": "asn",
+                "user_hash": user_hash,
+                "asn": mapping.asn,
+                "reason": query.reason,
+            }),
+        ));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,8 +1,124 @@
+use anyhow::{Result, bail};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::Path,
+    sync::Arc,
+};
 use tokio::sync::RwLock;
 
+/// Scope granting access to every service endpoint
+pub const ALL_SCOPES: &str = "*";
+
+/// Identity of the agent behind a service API request, injected by the agent
+/// key middleware
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AgentInfo {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Site (POP) the agent serves
+    #[serde(default)]
+    pub site: Option<String>,
+    /// Service endpoints the agent may call (`mappings`, `prefixes`, `filters`,
+    /// `policies`, or `*` for all); writes need the `:write` scope of the
+    /// endpoint, e.g. `mappings:write`
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec![ALL_SCOPES.to_string()]
+}
+
+impl AgentInfo {
+    /// Identity of agents authenticating with the shared agent key
+    pub fn shared() -> Self {
+        Self {
+            id: "shared".to_string(),
+            name: None,
+            site: None,
+            scopes: default_scopes(),
+        }
+    }
+
+    /// Whether the agent may use the given scope
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == ALL_SCOPES || s == scope)
+    }
+}
+
+#[derive(Deserialize)]
+struct AgentKeyEntry {
+    key: String,
+    #[serde(flatten)]
+    info: AgentInfo,
+}
+
+/// Per-agent service API keys
+#[derive(Debug, Clone, Default)]
+pub struct AgentKeys {
+    keys: Arc<HashMap<String, AgentInfo>>,
+}
+
+impl AgentKeys {
+    pub fn new(keys: impl IntoIterator<Item = (String, AgentInfo)>) -> Self {
+        Self {
+            keys: Arc::new(keys.into_iter().collect()),
+        }
+    }
+
+    /// Load keys from a JSON file: an array of `{"key", "id", "name", "site", "scopes"}`
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path.as_ref())?;
+        let entries: Vec<AgentKeyEntry> = serde_json::from_str(&content)?;
+
+        let mut keys = HashMap::new();
+        for entry in entries {
+            if entry.key.is_empty() {
+                bail!("Agent {} has an empty key", entry.info.id);
+            }
+            if keys
+                .values()
+                .any(|info: &AgentInfo| info.id == entry.info.id)
+            {
+                bail!("Duplicate agent id {}", entry.info.id);
+            }
+            if keys.contains_key(&entry.key) {
+                bail!("Agent {} reuses another agent's key", entry.info.id);
+            }
+            keys.insert(entry.key, entry.info);
+        }
+
+        Ok(Self::new(keys))
+    }
+
+    /// Find the agent owning a key
+    pub fn get(&self, key: &str) -> Option<&AgentInfo> {
+        self.keys.get(key)
+    }
+
+    /// Keys with the agent owning them
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &AgentInfo)> {
+        self.keys.iter().map(|(key, info)| (key.as_str(), info))
+    }
+
+    /// Sites served by the configured agents
+    pub fn sites(&self) -> BTreeSet<String> {
+        self.keys.values().filter_map(|a| a.site.clone()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
 pub struct Agent {
     pub id: String,
@@ -89,16 +205,14 @@ pub struct HealthStatus {
     pub message: Option<String>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct AgentStore {
     agents: Arc<RwLock<HashMap<String, Agent>>>,
 }
 
 impl AgentStore {
     pub fn new() -> Self {
-        Self {
-            agents: Arc::new(RwLock::new(HashMap::new())),
-        }
+        Self::default()
     }
 
     pub async fn add_agent(&self, id: String, secret: String) -> Result<(), String> {
@@ -206,6 +320,53 @@ impl AgentStore {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_agent_keys_from_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"[
+                {{"key": "k1", "id": "rs-ams", "site": "ams", "scopes": ["mappings"]}},
+                {{"key": "k2", "id": "monitor", "name": "Monitoring"}}
+            ]"#
+        )
+        .unwrap();
+
+        let keys = AgentKeys::from_file(file.path()).unwrap();
+        assert_eq!(keys.len(), 2);
+
+        let ams = keys.get("k1").unwrap();
+        assert_eq!(ams.site.as_deref(), Some("ams"));
+        assert!(ams.has_scope("mappings"));
+        assert!(!ams.has_scope("filters"));
+
+        assert_eq!(keys.sites().into_iter().collect::<Vec<_>>(), vec!["ams"]);
+
+        let monitor = keys.get("k2").unwrap();
+        assert!(monitor.has_scope("filters"));
+        assert!(keys.get("k3").is_none());
+    }
+
+    #[test]
+    fn test_agent_keys_validation() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"[{{"key": "k1", "id": "a"}}, {{"key": "k1", "id": "b"}}]"#
+        )
+        .unwrap();
+        assert!(AgentKeys::from_file(file.path()).is_err());
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"[{{"key": "k1", "id": "a"}}, {{"key": "k2", "id": "a"}}]"#
+        )
+        .unwrap();
+        assert!(AgentKeys::from_file(file.path()).is_err());
+    }
 
     #[tokio::test]
     async fn test_agent_store_add_get() {
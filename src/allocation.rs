@@ -0,0 +1,306 @@
+//! Allocation decisions.
+//!
+//! A decision explains a prefix allocation: the pool prefixes the allocator
+//! looked at, in order, what it picked from them and why it passed over the
+//! others (disabled, of another class, too small, leased, reserved, held in
+//! a grace period, full). The decision of every allocation is written to the
+//! audit stream, and dry runs return it, so "why did I get this prefix?" has
+//! an answer.
+
+use ipnet::Ipv6Net;
+use serde_json::{Value, json};
+
+use crate::pool_prefixes::{Candidate, PrefixClass, Skip};
+
+/// Most candidates listed in a decision, the allocator may look at every
+/// prefix of a large pool
+pub const MAX_CANDIDATES: usize = 64;
+
+/// What holds a taken prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Holder {
+    /// A lease active during the requested window
+    Lease,
+    /// A lease starting later, reserved ahead
+    Reservation,
+    /// A lease whose grace period overlaps the requested window
+    GracePeriod,
+    /// Another prefix selected by the same request
+    Request,
+}
+
+impl Holder {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Lease => "leased",
+            Self::Reservation => "reserved",
+            Self::GracePeriod => "grace_period",
+            Self::Request => "selected",
+        }
+    }
+}
+
+/// A pool prefix looked at, over every pick of the request
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    prefix: Ipv6Net,
+    selected: Vec<Ipv6Net>,
+    preferred: bool,
+    skip: Option<Skip>,
+}
+
+/// How the prefixes of a request were picked
+#[derive(Debug, Clone, Default)]
+pub struct Decision {
+    class: Option<PrefixClass>,
+    prefix_len: Option<u8>,
+    preferred: Option<Ipv6Net>,
+    requested: Option<Ipv6Net>,
+    entries: Vec<Entry>,
+}
+
+impl Decision {
+    /// Decision for prefixes picked by the allocator
+    pub fn new(
+        class: Option<PrefixClass>,
+        prefix_len: Option<u8>,
+        preferred: Option<Ipv6Net>,
+    ) -> Self {
+        Self {
+            class,
+            prefix_len,
+            preferred,
+            ..Default::default()
+        }
+    }
+
+    /// Decision for a prefix the user asked for by address
+    pub fn requested(prefix: Ipv6Net, class: Option<PrefixClass>) -> Self {
+        Self {
+            class,
+            prefix_len: Some(prefix.prefix_len()),
+            requested: Some(prefix),
+            ..Default::default()
+        }
+    }
+
+    /// Add the candidates of one pick. A prefix looked at again keeps the
+    /// reason it was first passed over, unless it is picked from this time.
+    pub fn add(&mut self, candidates: Vec<Candidate>) {
+        for candidate in candidates {
+            let index = match self
+                .entries
+                .iter()
+                .position(|e| e.prefix == candidate.prefix)
+            {
+                Some(index) => index,
+                None => {
+                    self.entries.push(Entry {
+                        prefix: candidate.prefix,
+                        selected: Vec::new(),
+                        preferred: false,
+                        skip: None,
+                    });
+                    self.entries.len() - 1
+                }
+            };
+            let entry = &mut self.entries[index];
+            match candidate.outcome {
+                Ok(picked) => {
+                    entry.selected.push(picked);
+                    entry.preferred |= candidate.preferred;
+                    entry.skip = None;
+                }
+                Err(skip) if entry.selected.is_empty() && entry.skip.is_none() => {
+                    entry.skip = Some(skip);
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Prefixes picked, in order
+    pub fn selected(&self) -> Vec<Ipv6Net> {
+        match self.requested {
+            Some(prefix) => vec![prefix],
+            None => self
+                .entries
+                .iter()
+                .flat_map(|entry| entry.selected.iter().copied())
+                .collect(),
+        }
+    }
+
+    /// The decision with its `outcome` (e.g. `leased` or `exhausted`), `holder`
+    /// telling what holds each taken prefix
+    pub fn to_json(&self, outcome: &str, holder: impl Fn(&Ipv6Net) -> Holder) -> Value {
+        let candidates: Vec<Value> = self
+            .entries
+            .iter()
+            .take(MAX_CANDIDATES)
+            .map(|entry| {
+                let mut candidate = json!({ "prefix": entry.prefix.to_string() });
+                if !entry.selected.is_empty() {
+                    candidate["selected"] = json!(
+                        entry
+                            .selected
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()
+                    );
+                    if entry.preferred {
+                        candidate["preferred"] = json!(true);
+                    }
+                }
+                match entry.skip {
+                    Some(Skip::Disabled) => candidate["skipped"] = json!("disabled"),
+                    Some(Skip::Class(class)) => {
+                        candidate["skipped"] = json!("class");
+                        candidate["class"] = json!(class);
+                    }
+                    Some(Skip::TooSmall) => candidate["skipped"] = json!("too_small"),
+                    Some(Skip::Taken(by)) => {
+                        candidate["skipped"] = json!(holder(&by).name());
+                        candidate["by"] = json!(by.to_string());
+                    }
+                    Some(Skip::Full) => candidate["skipped"] = json!("full"),
+                    None => {}
+                }
+                candidate
+            })
+            .collect();
+
+        let mut decision = json!({
+            "outcome": outcome,
+            "class": self.class,
+            "prefix_len": self.prefix_len,
+            "requested": self.requested.map(|p| p.to_string()),
+            "preferred": self.preferred.map(|p| p.to_string()),
+            "selected": self.selected().iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "candidates": candidates,
+        });
+        let omitted = self.entries.len().saturating_sub(MAX_CANDIDATES);
+        if omitted > 0 {
+            decision["candidates_omitted"] = json!(omitted);
+        }
+        decision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool_prefixes::PrefixPool;
+
+    fn net(prefix: &str) -> Ipv6Net {
+        prefix.parse().unwrap()
+    }
+
+    #[test]
+    fn test_decision_of_bulk_request() {
+        let pool = PrefixPool::new(vec![
+            net("2001:db8:1::/48"),
+            net("fd00:1::/48"),
+            net("2001:db8:2::/48"),
+            net("2001:db8:3::/48"),
+        ])
+        .with_entries(&[crate::pool_prefixes::PoolEntry {
+            prefix: net("2001:db8:3::/48"),
+            class: None,
+            status: crate::pool_prefixes::PoolStatus::Disabled,
+        }]);
+        let class = Some(PrefixClass::Documentation);
+        let mut taken = vec![net("2001:db8:1::/56")];
+        let mut decision = Decision::new(class, Some(56), None);
+        for _ in 0..2 {
+            let (picked, candidates) = pool.explain_available_slice(&taken, class, None, Some(56));
+            decision.add(candidates);
+            taken.push(picked.unwrap());
+        }
+        assert_eq!(
+            decision.selected(),
+            vec![net("2001:db8:1:100::/56"), net("2001:db8:1:200::/56")]
+        );
+
+        let json = decision.to_json("leased", |prefix| {
+            if *prefix == net("2001:db8:1::/56") {
+                Holder::Reservation
+            } else {
+                Holder::Request
+            }
+        });
+        assert_eq!(json["outcome"], "leased");
+        assert_eq!(json["prefix_len"], 56);
+        assert_eq!(
+            json["candidates"],
+            json!([
+                {"prefix": "fd00:1::/48", "skipped": "class", "class": "ula"},
+                {"prefix": "2001:db8:3::/48", "skipped": "disabled"},
+                {"prefix": "2001:db8:1::/48", "selected": ["2001:db8:1:100::/56", "2001:db8:1:200::/56"]},
+            ])
+        );
+        assert!(json.get("candidates_omitted").is_none());
+    }
+
+    #[test]
+    fn test_decision_of_whole_prefixes() {
+        let pool = PrefixPool::new(vec![net("2001:db8:1::/48"), net("2001:db8:2::/48")]);
+        let leased = [net("2001:db8:1::/48")];
+
+        // The preferred prefix is picked when free, and passed over otherwise
+        let mut decision = Decision::new(None, None, Some(net("2001:db8:2::/48")));
+        let (picked, candidates) =
+            pool.explain_available_slice(&leased, None, Some(&net("2001:db8:2::/48")), None);
+        assert_eq!(picked, Some(net("2001:db8:2::/48")));
+        decision.add(candidates);
+        let json = decision.to_json("dry_run", |_| Holder::Lease);
+        assert_eq!(
+            json["candidates"],
+            json!([{"prefix": "2001:db8:2::/48", "selected": ["2001:db8:2::/48"], "preferred": true}])
+        );
+
+        let mut decision = Decision::new(None, None, None);
+        let taken = [net("2001:db8:1::/48"), net("2001:db8:2::/48")];
+        let (picked, candidates) = pool.explain_available_slice(&taken, None, None, None);
+        assert_eq!(picked, None);
+        decision.add(candidates);
+        let json = decision.to_json("exhausted", |prefix| {
+            if *prefix == net("2001:db8:1::/48") {
+                Holder::Lease
+            } else {
+                Holder::GracePeriod
+            }
+        });
+        assert_eq!(
+            json["candidates"],
+            json!([
+                {"prefix": "2001:db8:1::/48", "skipped": "leased", "by": "2001:db8:1::/48"},
+                {"prefix": "2001:db8:2::/48", "skipped": "grace_period", "by": "2001:db8:2::/48"},
+            ])
+        );
+        assert_eq!(json["selected"], json!([]));
+
+        let requested = Decision::requested(net("2001:db8:2:100::/56"), None);
+        assert_eq!(requested.selected(), vec![net("2001:db8:2:100::/56")]);
+        assert_eq!(
+            requested.to_json("leased", |_| Holder::Lease)["requested"],
+            "2001:db8:2:100::/56"
+        );
+    }
+
+    #[test]
+    fn test_candidates_are_capped() {
+        let prefixes: Vec<Ipv6Net> = (0..MAX_CANDIDATES as u16 + 2)
+            .map(|i| net(&format!("2001:db8:{:x}::/48", i)))
+            .collect();
+        let pool = PrefixPool::new(prefixes.clone());
+        let mut decision = Decision::new(None, Some(64), None);
+        decision.add(
+            pool.explain_available_slice(&prefixes, None, None, Some(64))
+                .1,
+        );
+        let json = decision.to_json("exhausted", |_| Holder::Lease);
+        assert_eq!(json["candidates"].as_array().unwrap().len(), MAX_CANDIDATES);
+        assert_eq!(json["candidates_omitted"], 2);
+    }
+}
@@ -0,0 +1,254 @@
+//! Key-value annotations on user mappings.
+//!
+//! Integrators with the `mappings:write` scope attach small bits of state to
+//! a user's ASN mapping (e.g. `rs_session_state=established` or a portal id)
+//! and read them back with the mappings, so they don't need a database of
+//! their own. Annotations are removed with the mapping, and cleared when its
+//! ASN is transferred to another user.
+
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap};
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::agent::AgentInfo;
+use crate::database::MappingAnnotation;
+use crate::types::UserHash;
+
+/// Most annotations on a mapping
+const MAX_ANNOTATIONS: i64 = 32;
+
+/// Longest annotation key and value, in characters
+const MAX_KEY_LENGTH: usize = 64;
+const MAX_VALUE_LENGTH: usize = 1024;
+
+// Request/Response types
+
+#[derive(Deserialize)]
+pub struct SetAnnotationRequest {
+    value: String,
+}
+
+#[derive(Serialize)]
+pub struct AnnotationResponse {
+    key: String,
+    value: String,
+    updated_by: String,
+    updated_at: String,
+}
+
+impl From<MappingAnnotation> for AnnotationResponse {
+    fn from(annotation: MappingAnnotation) -> Self {
+        Self {
+            key: annotation.key,
+            value: annotation.value,
+            updated_by: annotation.updated_by,
+            updated_at: annotation.updated_at.to_rfc3339(),
+        }
+    }
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.into()
+        })),
+    )
+}
+
+fn validate_key(key: &str) -> Result<(), String> {
+    let valid = !key.is_empty()
+        && key.len() <= MAX_KEY_LENGTH
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        return Err(format!(
+            "Invalid annotation key '{}' (at most {} letters, digits, '_', '-' and '.')",
+            key, MAX_KEY_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+fn validate_value(value: &str) -> Result<(), String> {
+    if value.chars().count() > MAX_VALUE_LENGTH {
+        return Err(format!(
+            "Annotation values must be at most {} characters",
+            MAX_VALUE_LENGTH
+        ));
+    }
+    if value.chars().any(char::is_control) {
+        return Err("Annotation values must not contain control characters".to_string());
+    }
+    Ok(())
+}
+
+/// Load the annotations of mappings, as key-value maps by mapping id
+pub async fn by_mapping(
+    state: &AppState,
+    mapping_ids: &[Uuid],
+) -> Result<HashMap<Uuid, BTreeMap<String, String>>, sqlx::Error> {
+    let mut annotations: HashMap<Uuid, BTreeMap<String, String>> = HashMap::new();
+    for annotation in state.store.get_mapping_annotations(mapping_ids).await? {
+        annotations
+            .entry(annotation.mapping_id)
+            .or_default()
+            .insert(annotation.key, annotation.value);
+    }
+    Ok(annotations)
+}
+
+// Handlers
+
+/// List the annotations of a user's mapping, with who last set them
+pub async fn list_annotations(
+    State(state): State<AppState>,
+    Path(user_hash): Path<UserHash>,
+) -> Result<Json<Value>, ApiError> {
+    let internal_error = |err: sqlx::Error| {
+        error!("Failed to list annotations of user {}: {}", user_hash, err);
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to list annotations",
+        )
+    };
+    let Some(mapping) = state
+        .database
+        .get_user_asn(&user_hash)
+        .await
+        .map_err(internal_error)?
+    else {
+        return Err(api_error(StatusCode::NOT_FOUND, "User has no ASN assigned"));
+    };
+    let annotations = state
+        .database
+        .get_mapping_annotations(&[mapping.id])
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(json!({
+        "annotations": annotations
+            .into_iter()
+            .map(AnnotationResponse::from)
+            .collect::<Vec<_>>(),
+    })))
+}
+
+/// Set an annotation of a user's mapping
+#[instrument(name = "handler", skip_all, fields(operation = "set_annotation", user_hash = %user_hash, key = %key))]
+pub async fn set_annotation(
+    State(state): State<AppState>,
+    Extension(agent): Extension<AgentInfo>,
+    Path((user_hash, key)): Path<(UserHash, String)>,
+    Json(request): Json<SetAnnotationRequest>,
+) -> Result<Json<AnnotationResponse>, ApiError> {
+    validate_key(&key)
+        .and_then(|_| validate_value(&request.value))
+        .map_err(|message| api_error(StatusCode::BAD_REQUEST, message))?;
+
+    let internal_error = |err: sqlx::Error| {
+        error!("Failed to annotate mapping of user {}: {}", user_hash, err);
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to set annotation",
+        )
+    };
+    let annotation = state
+        .database
+        .set_mapping_annotation(&user_hash, &key, &request.value, &agent.id, MAX_ANNOTATIONS)
+        .await
+        .map_err(internal_error)?;
+    let Some(annotation) = annotation else {
+        // Either the user has no mapping or it has no room left
+        return match state.database.get_user_asn(&user_hash).await {
+            Ok(Some(_)) => Err(api_error(
+                StatusCode::CONFLICT,
+                format!("At most {} annotations per mapping", MAX_ANNOTATIONS),
+            )),
+            Ok(None) => Err(api_error(StatusCode::NOT_FOUND, "User has no ASN assigned")),
+            Err(err) => Err(internal_error(err)),
+        };
+    };
+
+    info!(
+        "Agent {} set annotation {} of user {}",
+        agent.id, key, user_hash
+    );
+    state.audit.record(
+        "mapping.annotated",
+        json!({
+            "user_hash": user_hash,
+            "key": annotation.key,
+            "value": annotation.value,
+            "agent_id": agent.id,
+        }),
+    );
+    Ok(Json(AnnotationResponse::from(annotation)))
+}
+
+/// Remove an annotation of a user's mapping
+#[instrument(name = "handler", skip_all, fields(operation = "delete_annotation", user_hash = %user_hash, key = %key))]
+pub async fn delete_annotation(
+    State(state): State<AppState>,
+    Extension(agent): Extension<AgentInfo>,
+    Path((user_hash, key)): Path<(UserHash, String)>,
+) -> Result<StatusCode, ApiError> {
+    match state
+        .database
+        .delete_mapping_annotation(&user_hash, &key)
+        .await
+    {
+        Ok(true) => {
+            info!(
+                "Agent {} removed annotation {} of user {}",
+                agent.id, key, user_hash
+            );
+            state.audit.record(
+                "mapping.annotation_removed",
+                json!({ "user_hash": user_hash, "key": key, "agent_id": agent.id }),
+            );
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err(api_error(StatusCode::NOT_FOUND, "Annotation not found")),
+        Err(err) => {
+            error!(
+                "Failed to remove annotation {} of user {}: {}",
+                key, user_hash, err
+            );
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to remove annotation",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validation() {
+        for key in ["rs_session_state", "portal-id", "ixp.port", "A1"] {
+            assert!(validate_key(key).is_ok(), "{}", key);
+        }
+        for key in ["", "with space", "slash/key", "é", &"k".repeat(65)] {
+            assert!(validate_key(key).is_err(), "{}", key);
+        }
+        assert!(validate_value("").is_ok());
+        assert!(validate_value("established").is_ok());
+        assert!(validate_value("line\nbreak").is_err());
+        assert!(validate_value(&"v".repeat(1025)).is_err());
+    }
+}
@@ -0,0 +1,285 @@
+//! Audit stream of resource changes.
+//!
+//! Every event published to agents (leases created, updated, transferred or
+//! revoked, ASNs assigned or revoked) and every use of impersonation is
+//! written to an append-only log outside the database: a JSON lines file,
+//! rotated by size, and/or the local syslog. Records are written in order by
+//! a dedicated thread so requests never wait on the disk.
+
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use serde_json::{Value, json};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::events::AgentEvent;
+
+/// Default size of the audit file before it is rotated
+pub const DEFAULT_MAX_FILE_MB: u64 = 100;
+
+/// Default number of rotated audit files kept
+pub const DEFAULT_KEEP_FILES: usize = 5;
+
+/// Syslog facility `local0`, severity `info`
+const SYSLOG_PRIORITY: u8 = 16 * 8 + 6;
+
+/// Where audit records are written
+#[derive(Debug, Clone, Default)]
+pub struct AuditConfig {
+    /// JSON lines file, rotated to `<file>.1`, `<file>.2`, ... when full
+    pub file: Option<PathBuf>,
+    pub max_file_bytes: u64,
+    pub keep_files: usize,
+    /// Send records to the local syslog daemon
+    pub syslog: bool,
+}
+
+/// Handle to the audit stream, a no-op when no destination is configured
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    sender: Option<mpsc::UnboundedSender<String>>,
+}
+
+impl AuditLog {
+    /// Open the configured destinations and start the writer thread
+    pub fn open(config: AuditConfig) -> Result<Self> {
+        let mut file = match config.file {
+            Some(ref path) => Some(FileSink::open(
+                path.clone(),
+                config.max_file_bytes,
+                config.keep_files,
+            )?),
+            None => None,
+        };
+        let mut syslog = match config.syslog {
+            true => Some(SyslogSink::connect()?),
+            false => None,
+        };
+        if file.is_none() && syslog.is_none() {
+            return Ok(Self::default());
+        }
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+        std::thread::Builder::new()
+            .name("audit".to_string())
+            .spawn(move || {
+                while let Some(line) = receiver.blocking_recv() {
+                    if let Some(ref mut file) = file
+                        && let Err(err) = file.write(&line)
+                    {
+                        error!(
+                            "Failed to write audit record to {}: {}",
+                            file.path.display(),
+                            err
+                        );
+                    }
+                    if let Some(ref mut syslog) = syslog
+                        && let Err(err) = syslog.send(&line)
+                    {
+                        error!("Failed to send audit record to syslog: {}", err);
+                    }
+                }
+            })
+            .context("Failed to start the audit writer")?;
+        Ok(Self {
+            sender: Some(sender),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// Append a record to the stream
+    pub fn record(&self, event_type: &str, data: Value) {
+        self.send(json!({
+            "id": Uuid::new_v4(),
+            "type": event_type,
+            "created_at": Utc::now().to_rfc3339(),
+            "data": data,
+        }));
+    }
+
+    fn send(&self, record: Value) {
+        if let Some(ref sender) = self.sender
+            && sender.send(record.to_string()).is_err()
+        {
+            error!("Audit writer stopped, dropping record");
+        }
+    }
+}
+
+/// Forward the events published to agents to the audit stream
+pub fn spawn(state: AppState) {
+    if !state.audit.is_enabled() {
+        return;
+    }
+    let mut receiver = state.agent_events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => state.audit.send(audit_record(&event)),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    error!("Audit stream missed {} events", missed);
+                    state.audit.record("audit.gap", json!({ "missed": missed }));
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+fn audit_record(event: &AgentEvent) -> Value {
+    json!({
+        "id": event.id,
+        "type": event.event_type,
+        "created_at": event.created_at,
+        "data": event.data,
+        "sites": event.sites,
+    })
+}
+
+/// Append-only JSON lines file, rotated when it grows past `max_bytes`
+struct FileSink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl FileSink {
+    fn open(path: PathBuf, max_bytes: u64, keep: usize) -> Result<Self> {
+        if max_bytes == 0 || keep == 0 {
+            bail!("The audit file needs a size limit and at least one rotated file");
+        }
+        let file = Self::append(&path)?;
+        let size = file.metadata()?.len();
+        info!("Writing audit records to {}", path.display());
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_bytes,
+            keep,
+        })
+    }
+
+    fn append(path: &Path) -> Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit file {}", path.display()))
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    /// Shift `<file>.N` to `<file>.N+1`, dropping the oldest, and start a new file
+    fn rotate(&mut self) -> Result<()> {
+        for index in (1..self.keep).rev() {
+            match fs::rename(self.rotated(index), self.rotated(index + 1)) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        self.file = Self::append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) -> Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.file.sync_data()?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+/// Local syslog daemon, reached through its Unix socket
+struct SyslogSink {
+    #[cfg(unix)]
+    socket: std::os::unix::net::UnixDatagram,
+}
+
+impl SyslogSink {
+    #[cfg(unix)]
+    fn connect() -> Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        for path in ["/dev/log", "/var/run/syslog"] {
+            if socket.connect(path).is_ok() {
+                info!("Sending audit records to syslog at {}", path);
+                return Ok(Self { socket });
+            }
+        }
+        bail!("No syslog socket found at /dev/log or /var/run/syslog")
+    }
+
+    #[cfg(not(unix))]
+    fn connect() -> Result<Self> {
+        bail!("Syslog is only supported on Unix")
+    }
+
+    #[cfg(unix)]
+    fn send(&mut self, line: &str) -> Result<()> {
+        let message = format!(
+            "<{}>{} peerlab-gateway[{}]: {}",
+            SYSLOG_PRIORITY,
+            Utc::now().format("%b %e %H:%M:%S"),
+            std::process::id(),
+            line
+        );
+        if let Err(err) = self.socket.send(message.as_bytes()) {
+            warn!("Syslog rejected an audit record, reconnecting: {}", err);
+            *self = Self::connect()?;
+            self.socket.send(message.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn send(&mut self, _line: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let mut sink = FileSink::open(path.clone(), 32, 2).unwrap();
+        for i in 0..8 {
+            sink.write(&format!("{{\"record\": {}}}", i)).unwrap();
+        }
+
+        // Each file holds two 15-byte records, the oldest ones are dropped
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "{\"record\": 6}\n{\"record\": 7}\n");
+        assert_eq!(read(sink.rotated(1)), "{\"record\": 4}\n{\"record\": 5}\n");
+        assert_eq!(read(sink.rotated(2)), "{\"record\": 2}\n{\"record\": 3}\n");
+        assert!(!sink.rotated(3).exists());
+
+        // Reopening appends to the current file
+        let mut sink = FileSink::open(path.clone(), 64, 2).unwrap();
+        sink.write("{\"record\": 8}").unwrap();
+        assert!(read(path).ends_with("{\"record\": 7}\n{\"record\": 8}\n"));
+    }
+}
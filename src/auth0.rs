@@ -1,5 +1,6 @@
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, error};
+use tracing::{debug, error, instrument};
 
 #[derive(Debug, Serialize)]
 #[allow(dead_code)]
@@ -23,6 +24,79 @@ struct Auth0User {
     #[allow(dead_code)]
     pub user_id: String,
     pub email: Option<String>,
+    #[serde(default)]
+    pub blocked: bool,
+}
+
+/// User as known by the IdP
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdpUser {
+    pub email: Option<String>,
+    /// Suspended by an administrator
+    pub blocked: bool,
+}
+
+/// Management API client holding one M2M token, for looking up several users
+pub struct ManagementClient {
+    client: reqwest::Client,
+    management_api_url: String,
+    token: String,
+}
+
+impl ManagementClient {
+    /// Obtain an M2M token for the Management API
+    pub async fn connect(
+        management_api_url: &str,
+        app_id: &str,
+        app_secret: &str,
+    ) -> Result<Self, String> {
+        let token = get_m2m_token(management_api_url, app_id, app_secret).await?;
+        Ok(Self {
+            client: reqwest::Client::new(),
+            management_api_url: management_api_url.to_string(),
+            token,
+        })
+    }
+
+    /// Fetch a user, or `None` when the IdP doesn't know it (anymore)
+    #[instrument(name = "idp", skip_all, fields(operation = "get_user", user_id = %user_id))]
+    pub async fn get_user(&self, user_id: &str) -> Result<Option<IdpUser>, String> {
+        let user_url = format!("{}/api/users/{}", self.management_api_url, user_id);
+
+        debug!("Fetching user details from Auth0: {}", user_url);
+
+        let response = self
+            .client
+            .get(&user_url)
+            .header("Authorization", format!("Bearer {}", self.token))
+            .send()
+            .await
+            .map_err(|e| format!("Failed to fetch user from Auth0: {}", e))?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            debug!("User {} not found in Auth0", user_id);
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            error!("Auth0 API returned error {}: {}", status, error_text);
+            return Err(format!("Auth0 API error: {} - {}", status, error_text));
+        }
+
+        let user: Auth0User = response
+            .json()
+            .await
+            .map_err(|e| format!("Failed to parse Auth0 user response: {}", e))?;
+
+        Ok(Some(IdpUser {
+            email: user.email,
+            blocked: user.blocked,
+        }))
+    }
 }
 
 /// Fetch user email from Auth0 Management API
@@ -32,41 +106,23 @@ pub async fn get_user_email(
     app_id: &str,
     app_secret: &str,
 ) -> Result<Option<String>, String> {
-    // Get M2M access token
-    let token = get_m2m_token(management_api_url, app_id, app_secret).await?;
-
-    // Fetch user details
-    let client = reqwest::Client::new();
-    let user_url = format!("{}/api/users/{}", management_api_url, user_id);
-
-    debug!("Fetching user details from Auth0: {}", user_url);
-
-    let response = client
-        .get(&user_url)
-        .header("Authorization", format!("Bearer {}", token))
-        .send()
-        .await
-        .map_err(|e| format!("Failed to fetch user from Auth0: {}", e))?;
-
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        error!("Auth0 API returned error {}: {}", status, error_text);
-        return Err(format!("Auth0 API error: {} - {}", status, error_text));
-    }
+    let client = ManagementClient::connect(management_api_url, app_id, app_secret).await?;
+    Ok(client.get_user(user_id).await?.and_then(|user| user.email))
+}
 
-    let user: Auth0User = response
-        .json()
+/// Check the M2M credentials by obtaining a Management API token
+pub async fn check_m2m_credentials(
+    management_api_url: &str,
+    app_id: &str,
+    app_secret: &str,
+) -> Result<(), String> {
+    get_m2m_token(management_api_url, app_id, app_secret)
         .await
-        .map_err(|e| format!("Failed to parse Auth0 user response: {}", e))?;
-
-    Ok(user.email)
+        .map(|_| ())
 }
 
 /// Get M2M access token for Auth0 Management API
+#[instrument(name = "idp", skip_all, fields(operation = "m2m_token"))]
 async fn get_m2m_token(
     management_api_url: &str,
     app_id: &str,
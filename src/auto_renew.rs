@@ -0,0 +1,87 @@
+//! Automatic renewal of prefix leases.
+//!
+//! Leases requested with `auto_renew` are extended by their duration shortly
+//! before they end, until they reach the maximum lifetime, so long-running
+//! measurement campaigns keep their prefix.
+
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::{AppState, events};
+
+/// How often leases about to end are checked
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Leases ending within this window are renewed, leaving a few checks to
+/// succeed before they lapse
+const RENEW_BEFORE: chrono::Duration = chrono::Duration::minutes(30);
+
+/// Spawn the task renewing flagged leases
+pub fn spawn(state: AppState) {
+    info!(
+        "Renewing flagged leases up to {} hours after their start",
+        state.auto_renew_max_lifetime.num_hours()
+    );
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            renew_expiring(&state).await;
+        }
+    });
+}
+
+/// Renew the flagged leases ending soon, notifying agents and webhooks
+pub async fn renew_expiring(state: &AppState) {
+    let leases = match state
+        .database
+        .renew_expiring_leases(
+            chrono::Utc::now() + RENEW_BEFORE,
+            state
+                .auto_renew_max_lifetime
+                .num_hours()
+                .min(i32::MAX as i64) as i32,
+        )
+        .await
+    {
+        Ok(leases) => leases,
+        Err(err) => {
+            error!("Failed to renew expiring leases: {}", err);
+            return;
+        }
+    };
+
+    for lease in leases {
+        info!(
+            "Renewed lease of {} for user {} until {}",
+            lease.prefix, lease.user_hash, lease.end_time
+        );
+        state.agent_events.publish(
+            events::AgentEvent::new(
+                events::EVENT_LEASE_UPDATED,
+                events::EventPriority::Normal,
+                serde_json::json!({
+                    "id": lease.id,
+                    "user_hash": lease.user_hash,
+                    "prefix": lease.prefix,
+                    "start_time": lease.start_time.to_rfc3339(),
+                    "end_time": lease.end_time.to_rfc3339(),
+                }),
+            )
+            .at_sites(lease.sites.clone()),
+        );
+        #[cfg(feature = "webhooks")]
+        crate::webhooks::dispatch(
+            state,
+            &lease.user_hash,
+            "prefix.renewed",
+            serde_json::json!({
+                "prefix": lease.prefix,
+                "start_time": lease.start_time.to_rfc3339(),
+                "end_time": lease.end_time.to_rfc3339(),
+                "sites": lease.sites,
+                "auto_renew": true,
+            }),
+        );
+    }
+}
@@ -0,0 +1,795 @@
+use anyhow::{Result, bail};
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::AppState;
+use crate::agent::{AgentKeys, AgentStore};
+use crate::audit::AuditLog;
+use crate::database::Database;
+use crate::events::AgentEvents;
+use crate::identity::IdentityMapping;
+use crate::jwt::TokenValidator;
+use crate::messages::Catalog;
+use crate::pool_asns::AsnPool;
+use crate::pool_prefixes::PrefixPool;
+use crate::pool_store::PoolStore;
+use crate::problem::ErrorFormat;
+use crate::public_stats::PublicStats;
+use crate::reload::Reloadable;
+use crate::response_cache::ResponseCache;
+use crate::store::Store;
+use crate::token_cache::TokenCache;
+use crate::usage::UsageMeter;
+use crate::user_locks::UserLocks;
+
+/// Default number of validated tokens kept in the token cache
+pub const DEFAULT_TOKEN_CACHE_SIZE: usize = 1024;
+
+/// Default number of webhook delivery attempts before dead-lettering
+pub const DEFAULT_WEBHOOK_MAX_ATTEMPTS: u32 = 5;
+
+/// Default number of hours cached IdP profiles are served before revalidation
+pub const DEFAULT_PROFILE_CACHE_TTL_HOURS: i64 = 24;
+
+/// Default bounds of a lease duration
+pub const DEFAULT_LEASE_MIN_MINUTES: i64 = 15;
+pub const DEFAULT_LEASE_MAX_HOURS: i64 = 24;
+
+/// Longest grace period of an ended lease, well within the 7 days ended
+/// leases are kept
+pub const MAX_LEASE_GRACE_HOURS: i64 = 24;
+
+/// Default number of hours auto-renewed leases are extended to, from their start
+pub const DEFAULT_AUTO_RENEW_MAX_HOURS: i64 = 7 * 24;
+
+/// Builder for [`AppState`], for embedding the gateway in other binaries and tests.
+///
+/// Only the database is required. Everything else defaults to the same values
+/// as the command line: the 65000-65999 ASN pool, an empty prefix pool, the
+/// `sub` identity claim and a 1024-token cache.
+#[derive(Clone)]
+pub struct AppStateBuilder {
+    agent_store: AgentStore,
+    agent_key: String,
+    agent_keys: AgentKeys,
+    admin_key: Option<String>,
+    database: Option<Database>,
+    store: Option<Arc<dyn Store>>,
+    token_validator: Option<Arc<dyn TokenValidator>>,
+    asn_pool: AsnPool,
+    prefix_pool: PrefixPool,
+    pool_store: Option<Arc<dyn PoolStore>>,
+    jwks_uri: Option<String>,
+    jwks_file: Option<String>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    token_endpoint: Option<String>,
+    auth0_management_api: Option<String>,
+    auth0_m2m_app_id: Option<String>,
+    auth0_m2m_app_secret: Option<String>,
+    profile_cache_ttl: chrono::Duration,
+    auto_renew_max_lifetime: chrono::Duration,
+    lease_min_duration: chrono::Duration,
+    lease_max_duration: chrono::Duration,
+    lease_grace_period: chrono::Duration,
+    permanent_lease_scope: Option<String>,
+    roa_max_length_limit: Option<u8>,
+    verify_idp_users: bool,
+    bypass_jwt_validation: bool,
+    identity: IdentityMapping,
+    token_cache: Option<TokenCache>,
+    revoked_tokens_file: Option<String>,
+    webhook_max_attempts: u32,
+    max_space_per_user: Option<u32>,
+    max_leases_per_user: Option<u32>,
+    max_asns_per_user: u32,
+    idempotency_ttl: chrono::Duration,
+    idempotency_claim_timeout: chrono::Duration,
+    client_concurrency_limit: Option<usize>,
+    service_concurrency_limit: Option<usize>,
+    mappings_cache: Option<ResponseCache>,
+    error_format: ErrorFormat,
+    config: Option<serde_json::Value>,
+    messages: Option<Catalog>,
+    audit: AuditLog,
+    public_stats: PublicStats,
+    #[cfg(feature = "alerts")]
+    alert_mailer: Option<crate::alerts::AlertMailer>,
+    #[cfg(feature = "sessions")]
+    sessions: Option<crate::sessions::SessionConfig>,
+    #[cfg(feature = "snapshots")]
+    snapshot_dir: Option<std::path::PathBuf>,
+    #[cfg(feature = "byoip")]
+    dns_resolver: String,
+    #[cfg(feature = "byoip")]
+    rpki_validator: Option<crate::rpki::RpkiValidator>,
+    #[cfg(feature = "abuse")]
+    abuse_reports: crate::abuse::AbuseReports,
+}
+
+impl Default for AppStateBuilder {
+    fn default() -> Self {
+        Self {
+            agent_store: AgentStore::new(),
+            agent_key: "agent-key".to_string(),
+            agent_keys: AgentKeys::default(),
+            admin_key: None,
+            database: None,
+            store: None,
+            token_validator: None,
+            asn_pool: AsnPool::new(65000, 65999),
+            prefix_pool: PrefixPool::new(Vec::new()),
+            pool_store: None,
+            jwks_uri: None,
+            jwks_file: None,
+            issuer: None,
+            audience: None,
+            token_endpoint: None,
+            auth0_management_api: None,
+            auth0_m2m_app_id: None,
+            auth0_m2m_app_secret: None,
+            profile_cache_ttl: chrono::Duration::hours(DEFAULT_PROFILE_CACHE_TTL_HOURS),
+            auto_renew_max_lifetime: chrono::Duration::hours(DEFAULT_AUTO_RENEW_MAX_HOURS),
+            lease_min_duration: chrono::Duration::minutes(DEFAULT_LEASE_MIN_MINUTES),
+            lease_max_duration: chrono::Duration::hours(DEFAULT_LEASE_MAX_HOURS),
+            lease_grace_period: chrono::Duration::zero(),
+            permanent_lease_scope: None,
+            roa_max_length_limit: None,
+            verify_idp_users: false,
+            bypass_jwt_validation: false,
+            identity: IdentityMapping::default(),
+            token_cache: None,
+            revoked_tokens_file: None,
+            webhook_max_attempts: DEFAULT_WEBHOOK_MAX_ATTEMPTS,
+            max_space_per_user: None,
+            max_leases_per_user: None,
+            max_asns_per_user: 1,
+            idempotency_ttl: chrono::Duration::hours(crate::idempotency::DEFAULT_TTL_HOURS),
+            idempotency_claim_timeout: chrono::Duration::seconds(
+                crate::idempotency::DEFAULT_CLAIM_TIMEOUT_SECS,
+            ),
+            client_concurrency_limit: None,
+            service_concurrency_limit: None,
+            mappings_cache: None,
+            error_format: ErrorFormat::default(),
+            config: None,
+            messages: None,
+            audit: AuditLog::default(),
+            public_stats: PublicStats::default(),
+            #[cfg(feature = "alerts")]
+            alert_mailer: None,
+            #[cfg(feature = "sessions")]
+            sessions: None,
+            #[cfg(feature = "snapshots")]
+            snapshot_dir: None,
+            #[cfg(feature = "byoip")]
+            dns_resolver: crate::external_prefixes::DEFAULT_DNS_RESOLVER.to_string(),
+            #[cfg(feature = "byoip")]
+            rpki_validator: None,
+            #[cfg(feature = "abuse")]
+            abuse_reports: Default::default(),
+        }
+    }
+}
+
+impl AppState {
+    /// Start building an app state
+    pub fn builder() -> AppStateBuilder {
+        AppStateBuilder::default()
+    }
+}
+
+impl AppStateBuilder {
+    /// Set the database (required)
+    pub fn database(mut self, database: Database) -> Self {
+        self.database = Some(database);
+        self
+    }
+
+    /// Serve the user and service mapping endpoints from this store instead
+    /// of the database, e.g. a [`MemoryStore`](crate::store::MemoryStore) in tests
+    pub fn store(mut self, store: Arc<dyn Store>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Validate bearer tokens with this validator instead of the JWKS, e.g.
+    /// a mock IdP in tests
+    pub fn token_validator(mut self, validator: Arc<dyn TokenValidator>) -> Self {
+        self.token_validator = Some(validator);
+        self
+    }
+
+    /// Use an existing agent store, e.g. one pre-populated with test agents
+    pub fn agent_store(mut self, agent_store: AgentStore) -> Self {
+        self.agent_store = agent_store;
+        self
+    }
+
+    /// Set the key agents use to authenticate to the service API
+    pub fn agent_key(mut self, agent_key: impl Into<String>) -> Self {
+        self.agent_key = agent_key.into();
+        self
+    }
+
+    /// Set per-agent keys, accepted alongside the shared agent key
+    pub fn agent_keys(mut self, agent_keys: AgentKeys) -> Self {
+        self.agent_keys = agent_keys;
+        self
+    }
+
+    /// Enable the admin API with this key
+    pub fn admin_key(mut self, admin_key: impl Into<String>) -> Self {
+        self.admin_key = Some(admin_key.into());
+        self
+    }
+
+    /// Set the ASN pool
+    pub fn asn_pool(mut self, asn_pool: AsnPool) -> Self {
+        self.asn_pool = asn_pool;
+        self
+    }
+
+    /// Set the prefix pool
+    pub fn prefix_pool(mut self, prefix_pool: PrefixPool) -> Self {
+        self.prefix_pool = prefix_pool;
+        self
+    }
+
+    /// Keep the prefix pool entries in this store instead of the database
+    pub fn pool_store(mut self, store: Arc<dyn PoolStore>) -> Self {
+        self.pool_store = Some(store);
+        self
+    }
+
+    /// Fetch signing keys from a remote JWKS URI
+    pub fn jwks_uri(mut self, jwks_uri: impl Into<String>) -> Self {
+        self.jwks_uri = Some(jwks_uri.into());
+        self
+    }
+
+    /// Load signing keys from a local JWKS file (e.g. a mock IdP's keys in tests)
+    pub fn jwks_file(mut self, jwks_file: impl Into<String>) -> Self {
+        self.jwks_file = Some(jwks_file.into());
+        self
+    }
+
+    /// Set the expected token issuer
+    pub fn issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    /// Set the expected token audience
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Set the IdP token endpoint clients refresh expired tokens at
+    pub fn token_endpoint(mut self, token_endpoint: impl Into<String>) -> Self {
+        self.token_endpoint = Some(token_endpoint.into());
+        self
+    }
+
+    /// Enable email enrichment through the Auth0 Management API
+    pub fn auth0_management(
+        mut self,
+        api_url: impl Into<String>,
+        app_id: impl Into<String>,
+        app_secret: impl Into<String>,
+    ) -> Self {
+        self.auth0_management_api = Some(api_url.into());
+        self.auth0_m2m_app_id = Some(app_id.into());
+        self.auth0_m2m_app_secret = Some(app_secret.into());
+        self
+    }
+
+    /// Set how long cached IdP profiles are served before revalidation
+    pub fn profile_cache_ttl(mut self, ttl: chrono::Duration) -> Self {
+        self.profile_cache_ttl = ttl;
+        self
+    }
+
+    /// Set the longest an auto-renewed lease is extended to, from its start
+    pub fn auto_renew_max_lifetime(mut self, lifetime: chrono::Duration) -> Self {
+        self.auto_renew_max_lifetime = lifetime;
+        self
+    }
+
+    /// Set the shortest and longest lease duration clients may request
+    pub fn lease_durations(mut self, min: chrono::Duration, max: chrono::Duration) -> Self {
+        self.lease_min_duration = min;
+        self.lease_max_duration = max;
+        self
+    }
+
+    /// Keep the prefix of an ended lease from other users for this long
+    pub fn lease_grace_period(mut self, period: chrono::Duration) -> Self {
+        self.lease_grace_period = period;
+        self
+    }
+
+    /// Let users set the ROA maxLength of their leases, up to this length
+    pub fn roa_max_length_limit(mut self, limit: u8) -> Self {
+        self.roa_max_length_limit = Some(limit);
+        self
+    }
+
+    /// Let users whose token carries this scope take permanent leases
+    /// without an admin's approval
+    pub fn permanent_lease_scope(mut self, scope: impl Into<String>) -> Self {
+        self.permanent_lease_scope = Some(scope.into());
+        self
+    }
+
+    /// Check with the Auth0 Management API that an account still exists and
+    /// isn't blocked before assigning it an ASN or a prefix
+    pub fn verify_idp_users(mut self, verify: bool) -> Self {
+        self.verify_idp_users = verify;
+        self
+    }
+
+    /// Skip JWT validation and authenticate every client request as a test user
+    pub fn bypass_jwt_validation(mut self, bypass: bool) -> Self {
+        self.bypass_jwt_validation = bypass;
+        self
+    }
+
+    /// Set how users are identified from their token claims
+    pub fn identity(mut self, identity: IdentityMapping) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Use an existing token cache (a capacity of 0 disables caching)
+    pub fn token_cache(mut self, token_cache: TokenCache) -> Self {
+        self.token_cache = Some(token_cache);
+        self
+    }
+
+    /// Reload revoked tokens from this file
+    pub fn revoked_tokens_file(mut self, path: impl Into<String>) -> Self {
+        self.revoked_tokens_file = Some(path.into());
+        self
+    }
+
+    /// Set the number of webhook delivery attempts before dead-lettering
+    pub fn webhook_max_attempts(mut self, attempts: u32) -> Self {
+        self.webhook_max_attempts = attempts;
+        self
+    }
+
+    /// Cap the address space a user may lease at once, in /48 equivalents
+    pub fn max_space_per_user(mut self, max_space: u32) -> Self {
+        self.max_space_per_user = Some(max_space);
+        self
+    }
+
+    /// Cap the number of leases a user may hold at once
+    pub fn max_leases_per_user(mut self, max_leases: u32) -> Self {
+        self.max_leases_per_user = Some(max_leases);
+        self
+    }
+
+    /// Let users hold up to this many ASNs at once (one by default)
+    pub fn max_asns_per_user(mut self, max_asns: u32) -> Self {
+        self.max_asns_per_user = max_asns;
+        self
+    }
+
+    /// Replay responses to requests with an `Idempotency-Key` for this long
+    pub fn idempotency_ttl(mut self, ttl: chrono::Duration) -> Self {
+        self.idempotency_ttl = ttl;
+        self
+    }
+
+    /// Let a retry take over the `Idempotency-Key` of a request still
+    /// processed after this long, e.g. one cut short by a restart
+    pub fn idempotency_claim_timeout(mut self, timeout: chrono::Duration) -> Self {
+        self.idempotency_claim_timeout = timeout;
+        self
+    }
+
+    /// Shed client API requests beyond this many in flight
+    pub fn client_concurrency_limit(mut self, limit: usize) -> Self {
+        self.client_concurrency_limit = Some(limit);
+        self
+    }
+
+    /// Shed service API requests beyond this many in flight
+    pub fn service_concurrency_limit(mut self, limit: usize) -> Self {
+        self.service_concurrency_limit = Some(limit);
+        self
+    }
+
+    /// Cache `/service/mappings` responses for `ttl`, then serve them up to
+    /// `max_stale` longer while refreshing in the background
+    pub fn mappings_cache(
+        mut self,
+        ttl: std::time::Duration,
+        max_stale: std::time::Duration,
+    ) -> Self {
+        self.mappings_cache = Some(ResponseCache::new(ttl, max_stale));
+        self
+    }
+
+    /// Set the format of error response bodies
+    pub fn error_format(mut self, error_format: ErrorFormat) -> Self {
+        self.error_format = error_format;
+        self
+    }
+
+    /// Serve this effective configuration on `/admin/config`
+    pub fn config(mut self, config: serde_json::Value) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Translate client API messages with this catalog
+    pub fn messages(mut self, catalog: Catalog) -> Self {
+        self.messages = Some(catalog);
+        self
+    }
+
+    /// Write audit records to this log
+    pub fn audit_log(mut self, log: AuditLog) -> Self {
+        self.audit = log;
+        self
+    }
+
+    /// Rate limit and cache of the public stats
+    pub fn public_stats(mut self, config: PublicStats) -> Self {
+        self.public_stats = config;
+        self
+    }
+
+    /// Send email alerts through this mailer
+    #[cfg(feature = "alerts")]
+    pub fn alert_mailer(mut self, mailer: crate::alerts::AlertMailer) -> Self {
+        self.alert_mailer = Some(mailer);
+        self
+    }
+
+    /// Accept browser sessions opened through the OIDC code flow
+    #[cfg(feature = "sessions")]
+    pub fn sessions(mut self, config: crate::sessions::SessionConfig) -> Self {
+        self.sessions = Some(config);
+        self
+    }
+
+    /// Write a mappings snapshot to this directory on every scheduler run
+    #[cfg(feature = "snapshots")]
+    pub fn snapshot_dir(mut self, dir: impl Into<std::path::PathBuf>) -> Self {
+        self.snapshot_dir = Some(dir.into());
+        self
+    }
+
+    /// Verify external prefixes through this DNS-over-HTTPS resolver (JSON API)
+    #[cfg(feature = "byoip")]
+    pub fn dns_resolver(mut self, url: impl Into<String>) -> Self {
+        self.dns_resolver = url.into();
+        self
+    }
+
+    /// Rate limit, captcha and notifications of abuse reports
+    #[cfg(feature = "abuse")]
+    pub fn abuse_reports(mut self, config: crate::abuse::AbuseReports) -> Self {
+        self.abuse_reports = config;
+        self
+    }
+
+    /// Only export external prefixes this validator finds RPKI-valid
+    #[cfg(feature = "byoip")]
+    pub fn rpki_validator(mut self, validator: crate::rpki::RpkiValidator) -> Self {
+        self.rpki_validator = Some(validator);
+        self
+    }
+
+    /// Validate the configuration and build the app state
+    pub fn build(self) -> Result<AppState> {
+        let Some(database) = self.database else {
+            bail!("A database is required");
+        };
+        if self.agent_key.is_empty() {
+            bail!("The agent key must not be empty");
+        }
+        if self.admin_key.as_deref() == Some("") {
+            bail!("The admin key must not be empty");
+        }
+        if self.asn_pool.ranges().is_empty() {
+            bail!("The ASN pool is empty");
+        }
+        if self.asn_pool.size() < 1 {
+            bail!(
+                "Invalid ASN pool {}-{}: start is after end",
+                self.asn_pool.start(),
+                self.asn_pool.end()
+            );
+        }
+        if self.webhook_max_attempts == 0 {
+            bail!("Webhooks need at least one delivery attempt");
+        }
+        if self.profile_cache_ttl <= chrono::Duration::zero() {
+            bail!("The profile cache TTL must be positive");
+        }
+        if self.auto_renew_max_lifetime < chrono::Duration::hours(1) {
+            bail!("The auto-renewal lifetime must be at least an hour");
+        }
+        if self.lease_min_duration < chrono::Duration::minutes(1) {
+            bail!("The minimum lease duration must be at least a minute");
+        }
+        if self.lease_min_duration > self.lease_max_duration {
+            bail!(
+                "Invalid lease durations {}-{} minutes: the minimum is above the maximum",
+                self.lease_min_duration.num_minutes(),
+                self.lease_max_duration.num_minutes()
+            );
+        }
+        if self.lease_grace_period < chrono::Duration::zero()
+            || self.lease_grace_period > chrono::Duration::hours(MAX_LEASE_GRACE_HOURS)
+        {
+            bail!(
+                "The lease grace period must be between 0 and {} hours",
+                MAX_LEASE_GRACE_HOURS
+            );
+        }
+        if self.roa_max_length_limit.is_some_and(|limit| limit > 128) {
+            bail!("The ROA maxLength limit must be at most 128");
+        }
+        if self.client_concurrency_limit == Some(0) || self.service_concurrency_limit == Some(0) {
+            bail!("Concurrency limits must allow at least one request");
+        }
+        if self.max_space_per_user == Some(0) {
+            bail!("The per-user address space quota must be at least one /48");
+        }
+        if self.max_leases_per_user == Some(0) {
+            bail!("The per-user lease quota must allow at least one lease");
+        }
+        if self.max_asns_per_user == 0 {
+            bail!("The per-user ASN quota must allow at least one ASN");
+        }
+        if self.idempotency_ttl <= chrono::Duration::zero() {
+            bail!("The Idempotency-Key TTL must be positive");
+        }
+        if self.idempotency_claim_timeout <= chrono::Duration::zero()
+            || self.idempotency_claim_timeout > self.idempotency_ttl
+        {
+            bail!("The Idempotency-Key claim timeout must be positive and within the TTL");
+        }
+        if let Some(url) = &self.token_endpoint
+            && !(url.starts_with("https://") || url.starts_with("http://"))
+        {
+            bail!("The token endpoint must be an http(s) URL");
+        }
+
+        for warning in self.asn_pool.policy_warnings() {
+            warn!("{}", warning);
+        }
+
+        let management = [
+            &self.auth0_management_api,
+            &self.auth0_m2m_app_id,
+            &self.auth0_m2m_app_secret,
+        ];
+        let configured = management.iter().filter(|v| v.is_some()).count();
+        if configured != 0 && configured != management.len() {
+            bail!(
+                "The Auth0 Management API URL, M2M app ID and M2M app secret must be set together"
+            );
+        }
+        if self.verify_idp_users && (configured == 0 || cfg!(not(feature = "auth0"))) {
+            bail!("Verifying IdP users requires the Auth0 Management API");
+        }
+
+        let pool_store = self
+            .pool_store
+            .unwrap_or_else(|| Arc::new(database.clone()));
+        let store = self.store.unwrap_or_else(|| Arc::new(database.clone()));
+
+        Ok(AppState {
+            agent_store: self.agent_store,
+            agent_key: self.agent_key,
+            agent_keys: Reloadable::new(self.agent_keys),
+            admin_key: self.admin_key,
+            database,
+            store,
+            asn_pool: Reloadable::new(self.asn_pool),
+            prefix_pool: Reloadable::new(self.prefix_pool.clone()),
+            prefix_pool_file: Reloadable::new(self.prefix_pool),
+            prefix_pool_entries: Reloadable::default(),
+            pool_store,
+            auth0_jwks_uri: self.jwks_uri,
+            jwks_file: self.jwks_file,
+            token_validator: self.token_validator,
+            auth0_issuer: self.issuer,
+            auth0_audience: self.audience,
+            token_endpoint: self.token_endpoint,
+            auth0_management_api: self.auth0_management_api,
+            auth0_m2m_app_id: self.auth0_m2m_app_id,
+            auth0_m2m_app_secret: self.auth0_m2m_app_secret,
+            profile_cache_ttl: self.profile_cache_ttl,
+            auto_renew_max_lifetime: self.auto_renew_max_lifetime,
+            lease_min_duration: self.lease_min_duration,
+            lease_max_duration: self.lease_max_duration,
+            lease_grace_period: self.lease_grace_period,
+            permanent_lease_scope: self.permanent_lease_scope,
+            roa_max_length_limit: self.roa_max_length_limit,
+            verify_idp_users: self.verify_idp_users,
+            bypass_jwt_validation: self.bypass_jwt_validation,
+            identity: self.identity,
+            token_cache: self
+                .token_cache
+                .unwrap_or_else(|| TokenCache::new(DEFAULT_TOKEN_CACHE_SIZE)),
+            user_locks: UserLocks::new(),
+            agent_events: AgentEvents::default(),
+            usage: UsageMeter::new(),
+            revoked_tokens_file: self.revoked_tokens_file,
+            webhook_max_attempts: self.webhook_max_attempts,
+            max_space_per_user: self.max_space_per_user,
+            max_leases_per_user: self.max_leases_per_user,
+            max_asns_per_user: self.max_asns_per_user,
+            idempotency_ttl: self.idempotency_ttl,
+            idempotency_claim_timeout: self.idempotency_claim_timeout,
+            client_concurrency_limit: self.client_concurrency_limit,
+            service_concurrency_limit: self.service_concurrency_limit,
+            mappings_cache: self.mappings_cache,
+            mappings_flights: Default::default(),
+            error_format: self.error_format,
+            config: self.config.map(Arc::new),
+            messages: Reloadable::new(self.messages.unwrap_or_default()),
+            audit: self.audit,
+            public_stats: self.public_stats,
+            export_safety: Default::default(),
+            #[cfg(feature = "alerts")]
+            alert_mailer: self.alert_mailer,
+            #[cfg(feature = "sessions")]
+            sessions: self.sessions,
+            #[cfg(feature = "snapshots")]
+            snapshot_dir: self.snapshot_dir,
+            #[cfg(feature = "byoip")]
+            dns_resolver: self.dns_resolver,
+            #[cfg(feature = "byoip")]
+            rpki_validator: self.rpki_validator,
+            #[cfg(feature = "abuse")]
+            abuse_reports: self.abuse_reports,
+            #[cfg(feature = "chaos")]
+            chaos: Default::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::DatabaseConfig;
+
+    fn database() -> Database {
+        Database::connect_lazy(&DatabaseConfig::new("postgresql://localhost/test".into())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_build_with_defaults() {
+        let state = AppState::builder().database(database()).build().unwrap();
+
+        assert_eq!(state.agent_key, "agent-key");
+        assert_eq!(state.asn_pool.load().size(), 1000);
+        assert!(state.prefix_pool.load().is_empty());
+        assert!(state.admin_key.is_none());
+        assert!(!state.bypass_jwt_validation);
+        assert_eq!(state.webhook_max_attempts, DEFAULT_WEBHOOK_MAX_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn test_build_validation() {
+        assert!(AppState::builder().build().is_err());
+        assert!(
+            AppState::builder()
+                .database(database())
+                .asn_pool(AsnPool::new(65010, 65000))
+                .build()
+                .is_err()
+        );
+        assert!(
+            AppState::builder()
+                .database(database())
+                .asn_pool(AsnPool::from_ranges(Vec::new()))
+                .build()
+                .is_err()
+        );
+        assert!(
+            AppState::builder()
+                .database(database())
+                .agent_key("")
+                .build()
+                .is_err()
+        );
+        assert!(
+            AppState::builder()
+                .database(database())
+                .webhook_max_attempts(0)
+                .build()
+                .is_err()
+        );
+        for (min, max) in [(0, 24 * 60), (12 * 60, 6 * 60)] {
+            assert!(
+                AppState::builder()
+                    .database(database())
+                    .lease_durations(
+                        chrono::Duration::minutes(min),
+                        chrono::Duration::minutes(max)
+                    )
+                    .build()
+                    .is_err()
+            );
+        }
+        for minutes in [-1, 25 * 60] {
+            assert!(
+                AppState::builder()
+                    .database(database())
+                    .lease_grace_period(chrono::Duration::minutes(minutes))
+                    .build()
+                    .is_err()
+            );
+        }
+        assert!(
+            AppState::builder()
+                .database(database())
+                .max_leases_per_user(0)
+                .build()
+                .is_err()
+        );
+        assert!(
+            AppState::builder()
+                .database(database())
+                .max_asns_per_user(0)
+                .build()
+                .is_err()
+        );
+        assert!(
+            AppState::builder()
+                .database(database())
+                .idempotency_ttl(chrono::Duration::zero())
+                .build()
+                .is_err()
+        );
+        assert!(
+            AppState::builder()
+                .database(database())
+                .idempotency_claim_timeout(chrono::Duration::hours(48))
+                .build()
+                .is_err()
+        );
+        assert!(
+            AppState::builder()
+                .database(database())
+                .token_endpoint("not a url")
+                .build()
+                .is_err()
+        );
+        assert!(
+            AppState::builder()
+                .database(database())
+                .roa_max_length_limit(129)
+                .build()
+                .is_err()
+        );
+
+        let mut partial = AppState::builder().database(database()).auth0_management(
+            "https://example.auth0.com",
+            "app-id",
+            "secret",
+        );
+        assert!(partial.clone().build().is_ok());
+        partial.auth0_m2m_app_secret = None;
+        assert!(partial.build().is_err());
+
+        assert!(
+            AppState::builder()
+                .database(database())
+                .verify_idp_users(true)
+                .build()
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use axum::{
+    Json,
+    extract::{Path, Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{AppState, jwt, messages};
+
+/// Header set on responses produced by an injected fault
+pub const FAULT_HEADER: &str = "x-peerlab-fault";
+
+/// Default delay before an injected database timeout fails
+const DEFAULT_DB_TIMEOUT_MS: u64 = 5000;
+
+/// Failure modes that can be injected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// Requests hang, then fail with a database error
+    DbTimeout,
+    /// Authenticated client requests fail as if the identity provider were down
+    IdpError,
+    /// ASN and prefix requests fail as if the pools were exhausted
+    PoolExhausted,
+}
+
+impl Fault {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::DbTimeout => "db_timeout",
+            Self::IdpError => "idp_error",
+            Self::PoolExhausted => "pool_exhausted",
+        }
+    }
+}
+
+/// Settings of an injected fault
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FaultConfig {
+    /// Fraction of matching requests that fail
+    #[serde(default = "default_probability")]
+    pub probability: f64,
+    /// Delay before failing (only used by `db_timeout`)
+    #[serde(default = "default_delay_ms")]
+    pub delay_ms: u64,
+}
+
+fn default_probability() -> f64 {
+    1.0
+}
+
+fn default_delay_ms() -> u64 {
+    DEFAULT_DB_TIMEOUT_MS
+}
+
+/// Currently injected faults, shared across the app
+#[derive(Debug, Clone, Default)]
+pub struct ChaosState {
+    faults: Arc<RwLock<BTreeMap<Fault, FaultConfig>>>,
+}
+
+impl ChaosState {
+    /// Inject a fault (replacing its previous settings)
+    pub fn inject(&self, fault: Fault, config: FaultConfig) {
+        self.faults.write().unwrap().insert(fault, config);
+    }
+
+    /// Stop injecting a fault, returning whether it was active
+    pub fn clear(&self, fault: Fault) -> bool {
+        self.faults.write().unwrap().remove(&fault).is_some()
+    }
+
+    /// Stop injecting every fault
+    pub fn clear_all(&self) {
+        self.faults.write().unwrap().clear();
+    }
+
+    /// Get the active faults
+    pub fn faults(&self) -> BTreeMap<Fault, FaultConfig> {
+        self.faults.read().unwrap().clone()
+    }
+
+    /// Pick the fault to apply to a request, if any
+    fn select(&self, method: &Method, path: &str, jwt_route: bool) -> Option<(Fault, FaultConfig)> {
+        let faults = self.faults.read().unwrap();
+        faults
+            .iter()
+            .filter(|(fault, _)| applies_to(**fault, method, path, jwt_route))
+            .find(|(_, config)| roll(config.probability))
+            .map(|(fault, config)| (*fault, *config))
+    }
+}
+
+/// Whether a fault applies to a request
+fn applies_to(fault: Fault, method: &Method, path: &str, jwt_route: bool) -> bool {
+    match fault {
+        Fault::DbTimeout => true,
+        Fault::IdpError => jwt_route,
+        Fault::PoolExhausted => {
+            method == Method::POST
+                && (path.ends_with("/user/asn") || path.ends_with("/user/prefix"))
+        }
+    }
+}
+
+/// Return true with the given probability
+fn roll(probability: f64) -> bool {
+    if probability >= 1.0 {
+        return true;
+    }
+    let sample = (Uuid::new_v4().as_u128() >> 64) as f64 / u64::MAX as f64;
+    sample < probability
+}
+
+/// Build the response a real failure of this kind would produce
+async fn fault_response(fault: Fault, config: FaultConfig, path: &str) -> Response {
+    let mut response = match fault {
+        Fault::DbTimeout => {
+            tokio::time::sleep(Duration::from_millis(config.delay_ms)).await;
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": 500,
+                    "message": "Database operation timed out"
+                })),
+            )
+                .into_response()
+        }
+        Fault::IdpError => jwt::AuthorizationError::new(
+            jwt::AuthErrorReason::IdpUnavailable,
+            "Injected identity provider failure",
+        )
+        .into_response(),
+        Fault::PoolExhausted => {
+            let message = if path.ends_with("/user/asn") {
+                messages::ASN_POOL_EXHAUSTED
+            } else {
+                messages::PREFIX_POOL_EXHAUSTED
+            };
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({
+                    "error": 503,
+                    "message": message.text(&[])
+                })),
+            )
+                .into_response()
+        }
+    };
+
+    if let Ok(value) = fault.name().parse() {
+        response.headers_mut().insert(FAULT_HEADER, value);
+    }
+    response
+}
+
+/// Fail a request according to the injected faults
+async fn inject(state: AppState, request: Request, next: Next, jwt_route: bool) -> Response {
+    let path = request.uri().path().to_string();
+    match state.chaos.select(request.method(), &path, jwt_route) {
+        Some((fault, config)) => {
+            warn!(
+                "Injecting {} fault into {} {}",
+                fault.name(),
+                request.method(),
+                path
+            );
+            fault_response(fault, config, &path).await
+        }
+        None => next.run(request).await,
+    }
+}
+
+/// Fault injection middleware for the client API
+pub async fn inject_client_faults(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    inject(state, request, next, true).await
+}
+
+/// Fault injection middleware for the service API
+pub async fn inject_service_faults(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    inject(state, request, next, false).await
+}
+
+// Admin handlers
+
+#[derive(Serialize)]
+pub struct FaultResponse {
+    fault: Fault,
+    #[serde(flatten)]
+    config: FaultConfig,
+}
+
+#[derive(Serialize)]
+pub struct FaultListResponse {
+    faults: Vec<FaultResponse>,
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn list(state: &AppState) -> Json<FaultListResponse> {
+    Json(FaultListResponse {
+        faults: state
+            .chaos
+            .faults()
+            .into_iter()
+            .map(|(fault, config)| FaultResponse { fault, config })
+            .collect(),
+    })
+}
+
+/// List injected faults
+pub async fn list_faults(State(state): State<AppState>) -> Json<FaultListResponse> {
+    list(&state)
+}
+
+/// Inject a fault
+pub async fn inject_fault(
+    State(state): State<AppState>,
+    Path(fault): Path<Fault>,
+    body: Option<Json<FaultConfig>>,
+) -> Result<Json<FaultListResponse>, ApiError> {
+    let config = body.map(|Json(c)| c).unwrap_or(FaultConfig {
+        probability: default_probability(),
+        delay_ms: default_delay_ms(),
+    });
+
+    if !(config.probability > 0.0 && config.probability <= 1.0) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": 400,
+                "message": "Probability must be in (0, 1]"
+            })),
+        ));
+    }
+
+    warn!("Chaos: injecting {} ({:?})", fault.name(), config);
+    state.chaos.inject(fault, config);
+    Ok(list(&state))
+}
+
+/// Stop injecting a fault
+pub async fn clear_fault(
+    State(state): State<AppState>,
+    Path(fault): Path<Fault>,
+) -> Json<FaultListResponse> {
+    state.chaos.clear(fault);
+    list(&state)
+}
+
+/// Stop injecting every fault
+pub async fn clear_faults(State(state): State<AppState>) -> Json<FaultListResponse> {
+    state.chaos.clear_all();
+    list(&state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(probability: f64) -> FaultConfig {
+        FaultConfig {
+            probability,
+            delay_ms: 0,
+        }
+    }
+
+    #[test]
+    fn test_fault_selection() {
+        let chaos = ChaosState::default();
+        assert!(chaos.select(&Method::GET, "/user/info", true).is_none());
+
+        chaos.inject(Fault::PoolExhausted, config(1.0));
+        assert!(chaos.select(&Method::GET, "/user/info", true).is_none());
+        assert_eq!(
+            chaos
+                .select(&Method::POST, "/user/prefix", true)
+                .map(|(f, _)| f),
+            Some(Fault::PoolExhausted)
+        );
+
+        chaos.inject(Fault::IdpError, config(1.0));
+        assert!(chaos.select(&Method::GET, "/mappings", false).is_none());
+        assert_eq!(
+            chaos
+                .select(&Method::GET, "/user/info", true)
+                .map(|(f, _)| f),
+            Some(Fault::IdpError)
+        );
+
+        assert!(chaos.clear(Fault::IdpError));
+        assert!(!chaos.clear(Fault::IdpError));
+        chaos.clear_all();
+        assert!(chaos.faults().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fault_responses() {
+        let response = fault_response(Fault::PoolExhausted, config(1.0), "/user/asn").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[FAULT_HEADER], "pool_exhausted");
+
+        let response = fault_response(Fault::IdpError, config(1.0), "/user/info").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[FAULT_HEADER], "idp_error");
+
+        let response = fault_response(Fault::DbTimeout, config(1.0), "/mappings").await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_fault_names() {
+        let fault: Fault = serde_json::from_value(json!("db_timeout")).unwrap();
+        assert_eq!(fault, Fault::DbTimeout);
+        assert_eq!(Fault::PoolExhausted.name(), "pool_exhausted");
+    }
+}
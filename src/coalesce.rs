@@ -0,0 +1,134 @@
+//! Coalescing of identical concurrent reads.
+//!
+//! After a change notification every agent refetches its mappings at about
+//! the same instant. Requests for the same key while a load is in flight
+//! wait for that load and share its result instead of each querying the
+//! database (the singleflight pattern). Nothing is kept once the load
+//! completes: the next request loads fresh data.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::OnceCell;
+use tracing::debug;
+
+/// Loads in flight, keyed by request
+#[derive(Debug)]
+pub struct Coalescer<T> {
+    flights: Arc<Mutex<HashMap<String, Arc<OnceCell<T>>>>>,
+}
+
+impl<T> Clone for Coalescer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            flights: self.flights.clone(),
+        }
+    }
+}
+
+impl<T> Default for Coalescer<T> {
+    fn default() -> Self {
+        Self {
+            flights: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<T: Clone> Coalescer<T> {
+    /// Run `load`, or wait for the load of the same key already in flight.
+    /// Returns the result and whether it was shared with an earlier request.
+    ///
+    /// If the request running the load is dropped, one of the waiting
+    /// requests takes over with its own `load`.
+    pub async fn run<F, Fut>(&self, key: String, load: F) -> (T, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let flight = self
+            .flights
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+
+        let mut loaded = false;
+        let value = flight
+            .get_or_init(|| {
+                loaded = true;
+                load()
+            })
+            .await
+            .clone();
+
+        let mut flights = self.flights.lock().unwrap();
+        if flights
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, &flight))
+        {
+            flights.remove(&key);
+        }
+        if !loaded {
+            debug!("Shared the in-flight load of {}", key);
+        }
+        (value, !loaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_concurrent_loads_are_coalesced() {
+        let coalescer = Coalescer::default();
+        let loads = Arc::new(AtomicUsize::new(0));
+        let load = |key: &str| {
+            let (coalescer, loads) = (coalescer.clone(), loads.clone());
+            let key = key.to_string();
+            async move {
+                coalescer
+                    .run(key, || async move {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        loads.fetch_add(1, Ordering::SeqCst) + 1
+                    })
+                    .await
+            }
+        };
+
+        let results = futures_util::future::join_all((0..10).map(|_| load("mappings"))).await;
+        assert!(results.iter().all(|(value, _)| *value == 1));
+        assert_eq!(results.iter().filter(|(_, shared)| !shared).count(), 1);
+
+        // Other keys load separately, and a completed load isn't reused
+        assert_eq!(load("other").await, (2, false));
+        assert_eq!(load("mappings").await, (3, false));
+        assert!(coalescer.flights.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_waiter_takes_over_dropped_load() {
+        let coalescer = Coalescer::default();
+        let first = {
+            let coalescer = coalescer.clone();
+            tokio::spawn(async move {
+                coalescer
+                    .run("key".to_string(), std::future::pending::<u32>)
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let second = {
+            let coalescer = coalescer.clone();
+            tokio::spawn(async move { coalescer.run("key".to_string(), || async { 2 }).await })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        first.abort();
+        assert_eq!(second.await.unwrap(), (2, false));
+    }
+}
@@ -1,50 +1,764 @@
 use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
 use ipnet::Ipv6Net;
-use sqlx::PgPool;
-use tracing::debug;
+use sqlx::{PgPool, Postgres, Transaction, postgres::PgPoolOptions};
+use std::future::Future;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+use std::{str::FromStr, time::Duration};
+use tracing::{debug, info, instrument, warn};
 use uuid::Uuid;
 
+use crate::types::{Asn, Prefix, UserHash};
+
+/// SQLSTATE of an exclusion constraint violation
+const EXCLUSION_VIOLATION: &str = "23P01";
+
+/// SQLSTATE of a unique constraint violation
+pub(crate) const UNIQUE_VIOLATION: &str = "23505";
+
+/// Constraint keeping an ASN from being assigned twice
+pub(crate) const ASN_UNIQUE_CONSTRAINT: &str = "user_asn_mappings_asn_key";
+
+/// End time of permanent leases (9999-12-31T00:00:00Z), far enough that every
+/// query treating leases as active until they end keeps working
+const PERMANENT_END_TIMESTAMP: i64 = 253_402_214_400;
+
+/// End time given to permanent leases
+pub fn permanent_end_time() -> DateTime<Utc> {
+    DateTime::from_timestamp(PERMANENT_END_TIMESTAMP, 0).unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+/// Whether an error means the database couldn't be reached (connection lost,
+/// pool exhausted, server shutting down or failing over), rather than the
+/// query being wrong
+pub fn is_connection_error(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Io(_)
+        | sqlx::Error::Tls(_)
+        | sqlx::Error::Protocol(_)
+        | sqlx::Error::PoolTimedOut
+        | sqlx::Error::PoolClosed
+        | sqlx::Error::WorkerCrashed => true,
+        // Connection exceptions, and admin shutdown or recovery in progress
+        sqlx::Error::Database(db_err) => db_err
+            .code()
+            .is_some_and(|code| code.starts_with("08") || code.starts_with("57P")),
+        _ => false,
+    }
+}
+
+/// Whether an error comes from inserting a lease overlapping an existing lease
+/// of the same prefix
+pub fn is_lease_conflict(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(db_err) => {
+            db_err.code().as_deref() == Some(EXCLUSION_VIOLATION)
+                && db_err.constraint() == Some("prefix_leases_no_overlap")
+        }
+        _ => false,
+    }
+}
+
+/// Whether an error comes from assigning an ASN already assigned to another user
+pub fn is_asn_conflict(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(db_err) => {
+            db_err.code().as_deref() == Some(UNIQUE_VIOLATION)
+                && db_err.constraint() == Some(ASN_UNIQUE_CONSTRAINT)
+        }
+        _ => false,
+    }
+}
+
+/// Whether an error comes from registering an external prefix overlapping
+/// an existing registration
+pub fn is_external_prefix_conflict(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(db_err) => {
+            db_err.code().as_deref() == Some(EXCLUSION_VIOLATION)
+                && db_err.constraint() == Some("external_prefixes_no_overlap")
+        }
+        _ => false,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DatabaseConfig {
     pub database_url: String,
+    pub acquire_timeout: Duration,
 }
 
 impl DatabaseConfig {
     pub fn new(database_url: String) -> Self {
-        Self { database_url }
+        Self {
+            database_url,
+            acquire_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Set how long to wait for a connection before failing a query
+    pub fn with_acquire_timeout(mut self, acquire_timeout: Duration) -> Self {
+        self.acquire_timeout = acquire_timeout;
+        self
+    }
+
+    fn pool_options(&self) -> PgPoolOptions {
+        PgPoolOptions::new().acquire_timeout(self.acquire_timeout)
+    }
+}
+
+/// Column a mapping listing is sorted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MappingSort {
+    #[default]
+    CreatedAt,
+    UpdatedAt,
+    Asn,
+}
+
+impl MappingSort {
+    /// Column of [`Database::get_mapping_permissions`] rows sorted on
+    fn column(&self) -> &'static str {
+        match self {
+            Self::CreatedAt => "asn_assigned_at",
+            Self::UpdatedAt => "updated_at",
+            Self::Asn => "origin_asn",
+        }
+    }
+}
+
+impl FromStr for MappingSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "created_at" => Ok(Self::CreatedAt),
+            "updated_at" => Ok(Self::UpdatedAt),
+            "asn" => Ok(Self::Asn),
+            other => Err(format!(
+                "Unknown sort field '{}' (expected 'created_at', 'updated_at' or 'asn')",
+                other
+            )),
+        }
+    }
+}
+
+/// Direction of a sorted listing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl SortOrder {
+    fn keyword(&self) -> &'static str {
+        match self {
+            Self::Asc => "ASC",
+            Self::Desc => "DESC",
+        }
+    }
+
+    /// Aggregate picking the value of a user's ASNs that sorts first
+    fn leading(&self) -> &'static str {
+        match self {
+            Self::Asc => "MIN",
+            Self::Desc => "MAX",
+        }
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asc" => Ok(Self::Asc),
+            "desc" => Ok(Self::Desc),
+            other => Err(format!(
+                "Unknown sort order '{}' (expected 'asc' or 'desc')",
+                other
+            )),
+        }
+    }
+}
+
+/// Filters and ordering for mapping listings
+#[derive(Debug, Clone, Default)]
+pub struct MappingFilter {
+    pub sort: MappingSort,
+    pub order: SortOrder,
+    /// Only users holding at least one active lease
+    pub active_only: bool,
+    pub asn: Option<i64>,
+}
+
+impl MappingFilter {
+    /// Fold the rows of [`Database::get_mapping_permissions`] per user, in
+    /// their order, keeping the users holding an ASN
+    pub fn group(rows: Vec<MappingPermission>) -> Vec<UserMappings> {
+        let mut users: Vec<UserMappings> = Vec::new();
+        for row in rows {
+            if users.last().and_then(UserMappings::user_hash) != Some(&row.user_hash) {
+                users.push(UserMappings::default());
+            }
+            let user = users.last_mut().expect("pushed above");
+            if row.is_asn() {
+                user.asns.push(row);
+            } else if !user.leases.iter().any(|l| l.source_id == row.source_id) {
+                // Lease rows repeat for every ASN of the holder
+                user.leases.push(row);
+            }
+        }
+        users.retain(|user| !user.asns.is_empty());
+        users
+    }
+}
+
+/// ASNs (first assigned first) and active leases (latest ending first) of a
+/// user, as rows of the read model
+#[derive(Debug, Clone, Default)]
+pub struct UserMappings {
+    pub asns: Vec<MappingPermission>,
+    pub leases: Vec<MappingPermission>,
+}
+
+impl UserMappings {
+    fn user_hash(&self) -> Option<&UserHash> {
+        self.asns
+            .first()
+            .or(self.leases.first())
+            .map(|row| &row.user_hash)
+    }
+}
+
+/// Active leases matched by a bulk revocation; every criterion set must match
+#[derive(Debug, Clone, Default)]
+pub struct LeaseFilter {
+    pub user_hash: Option<UserHash>,
+    /// Leases inside this range
+    pub prefix: Option<Ipv6Net>,
+    /// Leases pinned to this site
+    pub site: Option<String>,
+    pub created_before: Option<DateTime<Utc>>,
+}
+
+impl LeaseFilter {
+    /// Whether no criterion is set, matching every active lease
+    pub fn is_empty(&self) -> bool {
+        self.user_hash.is_none()
+            && self.prefix.is_none()
+            && self.site.is_none()
+            && self.created_before.is_none()
     }
 }
 
+/// Conditions of `LeaseFilter`, bound as $1 to $4
+const LEASE_FILTER_CONDITIONS: &str = "end_time > NOW()
+    AND ($1::text IS NULL OR user_hash = $1)
+    AND ($2::cidr IS NULL OR prefix <<= $2::cidr)
+    AND ($3::text IS NULL OR $3 = ANY(sites))
+    AND ($4::timestamptz IS NULL OR created_at < $4)";
+
 #[derive(Debug, Clone, sqlx::FromRow)]
 pub struct UserAsnMapping {
     pub id: Uuid,
-    pub user_hash: String,
+    pub user_hash: UserHash,
     pub user_id: Option<String>,
-    pub asn: i32,
+    pub asn: i64,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// What the ASN is used for, for registry/IRR tooling
+    pub description: Option<String>,
+    /// Email address to reach about announcements from the ASN
+    pub abuse_contact: Option<String>,
 }
 
 #[derive(Debug, Clone, sqlx::FromRow)]
 pub struct PrefixLease {
     pub id: Uuid,
-    pub user_hash: String,
+    pub user_hash: UserHash,
     pub prefix: String,
     pub start_time: DateTime<Utc>,
     pub end_time: DateTime<Utc>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// Sites where the lease may be announced (`None` for every site)
+    pub sites: Option<Vec<String>>,
+    /// Minutes the lease is extended by shortly before it ends (`None` when
+    /// not renewed automatically)
+    pub renew_minutes: Option<i32>,
+    /// Short name the user gave the lease
+    pub label: Option<String>,
+    /// What the lease is used for, as described by the user
+    pub purpose: Option<String>,
+    /// maxLength of the lease's ROA (`None` for the prefix length)
+    pub roa_max_length: Option<i16>,
+}
+
+/// Where new leases may be announced, whether they are renewed and how the
+/// user labelled them
+#[derive(Debug, Clone, Default)]
+pub struct LeaseOptions {
+    /// Sites where the leases may be announced (`None` for every site)
+    pub sites: Option<Vec<String>>,
+    pub auto_renew: bool,
+    pub label: Option<String>,
+    pub purpose: Option<String>,
+}
+
+impl PrefixLease {
+    /// Whether the lease may be announced at a site
+    pub fn allowed_at(&self, site: &str) -> bool {
+        match &self.sites {
+            Some(sites) => sites.iter().any(|s| s == site),
+            None => true,
+        }
+    }
+
+    /// Whether the lease never ends
+    pub fn is_permanent(&self) -> bool {
+        self.end_time >= permanent_end_time()
+    }
+
+    /// maxLength of the lease's ROA, the prefix length unless set
+    pub fn roa_max_length(&self, prefix: &Ipv6Net) -> u8 {
+        self.roa_max_length
+            .and_then(|length| u8::try_from(length).ok())
+            .unwrap_or(prefix.prefix_len())
+    }
+}
+
+/// Permission of an ASN to originate a prefix, from the
+/// `announcement_permissions` read model kept up to date by triggers
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct AnnouncementPermission {
+    pub prefix: String,
+    pub max_length: i16,
+    /// `None` while the holder has no ASN
+    pub origin_asn: Option<i64>,
+    /// When the origin ASN was assigned, ordering the holder's ASNs
+    pub asn_assigned_at: Option<DateTime<Utc>>,
+    pub user_hash: UserHash,
+    /// `lease` or `external`
+    pub source: String,
+    /// Lease or external prefix granting the permission
+    pub source_id: Uuid,
+    /// Sites where the prefix may be announced (`None` for every site)
+    pub sites: Option<Vec<String>>,
+    /// Route origin validation state of an external prefix
+    pub rpki_state: Option<String>,
+    pub valid_from: DateTime<Utc>,
+    /// `None` for permissions without an end
+    pub valid_to: Option<DateTime<Utc>>,
+}
+
+/// Permissions granted by a lease
+pub const PERMISSION_SOURCE_LEASE: &str = "lease";
+
+/// Rows listing an ASN the user holds, without a prefix
+pub const PERMISSION_SOURCE_ASN: &str = "asn";
+
+/// Row of the `announcement_permissions` read model as the service mappings
+/// read it: an ASN a user holds, or one of their leases (once per ASN)
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct MappingPermission {
+    pub user_hash: UserHash,
+    /// `asn` or `lease`
+    pub source: String,
+    /// ASN mapping or lease of the row
+    pub source_id: Uuid,
+    /// Leased prefix, on `lease` rows
+    pub prefix: Option<String>,
+    /// ASN of an `asn` row, or ASN allowed to originate the lease (`None`
+    /// while the holder has no ASN)
+    pub origin_asn: Option<i64>,
+    pub asn_assigned_at: Option<DateTime<Utc>>,
+    /// IdP user ID of the holder, on `asn` rows
+    pub holder_id: Option<String>,
+    /// Description and abuse contact of the ASN, on `asn` rows
+    pub description: Option<String>,
+    pub abuse_contact: Option<String>,
+    /// Label and purpose of the lease, on `lease` rows
+    pub label: Option<String>,
+    pub purpose: Option<String>,
+    /// Sites where the lease may be announced (`None` for every site)
+    pub sites: Option<Vec<String>>,
+    pub valid_to: Option<DateTime<Utc>>,
+    /// Last change to the ASN mapping or the lease
+    pub updated_at: DateTime<Utc>,
+}
+
+impl MappingPermission {
+    /// Whether the row lists an ASN rather than a lease
+    pub fn is_asn(&self) -> bool {
+        self.source == PERMISSION_SOURCE_ASN
+    }
+
+    /// Whether the lease may be announced at a site
+    pub fn allowed_at(&self, site: &str) -> bool {
+        match &self.sites {
+            Some(sites) => sites.iter().any(|s| s == site),
+            None => true,
+        }
+    }
+}
+
+impl AnnouncementPermission {
+    /// Whether the permission comes from a lease rather than an external prefix
+    pub fn is_lease(&self) -> bool {
+        self.source == PERMISSION_SOURCE_LEASE
+    }
+
+    /// Whether the prefix may be announced at a site
+    pub fn allowed_at(&self, site: &str) -> bool {
+        match &self.sites {
+            Some(sites) => sites.iter().any(|s| s == site),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub user_hash: UserHash,
+    pub url: String,
+    pub event_types: Vec<String>,
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub webhook_id: Uuid,
+    pub event_id: Uuid,
+    pub event_type: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_status_code: Option<i32>,
+    pub last_latency_ms: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PoolUsageSnapshot {
+    pub id: Uuid,
+    pub asns_assigned: i32,
+    pub prefixes_leased: i32,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// Prefix of the pool managed through the admin API
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PrefixPoolEntry {
+    pub prefix: String,
+    /// Class overriding the one of the pool file or of the address
+    pub class: Option<String>,
+    /// `active`, `disabled` or `retired`
+    pub status: String,
+    pub note: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct AlertThreshold {
+    pub id: Uuid,
+    pub pool: String,
+    pub threshold: f64,
+    pub webhook_url: Option<String>,
+    pub email: Option<String>,
+    pub triggered_at: Option<DateTime<Utc>>,
+    /// Event of the state change being notified, while some of its channels
+    /// are still to be notified
+    pub notified_event: Option<String>,
+    /// Channels already notified of `notified_event`
+    pub notified_channels: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Lab-wide totals shown on the public stats
+#[derive(Debug, Clone, Copy, sqlx::FromRow)]
+pub struct LabTotals {
+    /// Users holding an ASN
+    pub participants: i64,
+    /// ASNs originating at least one active lease
+    pub asns_in_use: i64,
+    /// Active leases, leaving out reservations
+    pub prefixes_announced: i64,
+}
+
+/// A user an admin allowed to take permanent leases
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PermanentLeaseApproval {
+    pub user_hash: UserHash,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A request made with an `Idempotency-Key`, and its response once processed
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct IdempotentRequest {
+    pub request_hash: String,
+    pub status: Option<i16>,
+    pub response: Option<String>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Impersonation {
+    pub id: Uuid,
+    pub token_hash: String,
+    pub identity: String,
+    pub user_hash: UserHash,
+    pub reason: String,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub use_count: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An invite code of a workshop slot, held by a placeholder user until it is
+/// redeemed
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct InviteCode {
+    pub id: Uuid,
+    pub code_hash: String,
+    pub workshop: String,
+    pub slot_user_hash: UserHash,
+    pub asn: i64,
+    pub expires_at: DateTime<Utc>,
+    pub redeemed_by: Option<UserHash>,
+    pub redeemed_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A workshop slot to provision: an ASN and prefixes for a placeholder user
+#[derive(Debug, Clone)]
+pub struct WorkshopSlot {
+    pub user_hash: UserHash,
+    pub code_hash: String,
+    pub asn: Asn,
+    pub prefixes: Vec<Prefix>,
+}
+
+/// A lease or ASN moved from one user to another
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Transfer {
+    pub id: Uuid,
+    /// `lease` or `asn`
+    pub resource: String,
+    pub lease_id: Option<Uuid>,
+    pub prefix: Option<String>,
+    pub asn: Option<i64>,
+    pub from_user_hash: UserHash,
+    pub to_user_hash: UserHash,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Key-value pair attached to a user's ASN mapping through the service API
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct MappingAnnotation {
+    pub mapping_id: Uuid,
+    pub key: String,
+    pub value: String,
+    /// Agent that last set the value
+    pub updated_by: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A user's ASN mappings, first assigned first, with their active leases
+pub type UserInfo = (Vec<UserAsnMapping>, Vec<PrefixLease>);
+
+/// IdP profile data cached for a user
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UserProfile {
+    pub user_id: String,
+    pub email: Option<String>,
+    /// When the IdP last confirmed the profile
+    pub validated_at: DateTime<Utc>,
+}
+
+/// Address space a user brought to the lab
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ExternalPrefix {
+    pub id: Uuid,
+    pub user_hash: UserHash,
+    pub prefix: String,
+    pub token: String,
+    pub status: String,
+    pub verification_method: Option<String>,
+    pub verified_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Route origin validation state against the lab's origin ASN
+    pub rpki_state: Option<String>,
+    pub rpki_checked_at: Option<DateTime<Utc>>,
+}
+
+/// An abuse report or other incident, attached to a user when known
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Incident {
+    pub id: Uuid,
+    pub user_hash: Option<UserHash>,
+    pub lease_id: Option<Uuid>,
+    pub prefix: Option<String>,
+    pub source: String,
+    pub description: String,
+    pub reporter_contact: Option<String>,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub asn: Option<i64>,
+    pub resolution: Option<String>,
+    pub closed_at: Option<DateTime<Utc>>,
+}
+
+/// A note on the handling of an incident
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct IncidentComment {
+    pub id: Uuid,
+    pub incident_id: Uuid,
+    pub author: Option<String>,
+    pub body: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Fields of an incident being opened
+#[derive(Debug, Clone, Default)]
+pub struct NewIncident<'a> {
+    pub user_hash: Option<&'a UserHash>,
+    pub asn: Option<Asn>,
+    pub lease_id: Option<Uuid>,
+    pub prefix: Option<&'a str>,
+    pub source: &'a str,
+    pub description: &'a str,
+    pub reporter_contact: Option<&'a str>,
+}
+
+/// API calls of a user or agent on a route during an hour
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct ApiUsage {
+    pub hour: DateTime<Utc>,
+    pub subject_kind: String,
+    pub subject: String,
+    pub route: String,
+    pub calls: i64,
+}
+
+/// API calls of a user or agent over a period
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ApiUsageTotal {
+    pub subject_kind: String,
+    pub subject: String,
+    pub calls: i64,
 }
 
 #[derive(Debug, Clone)]
 pub struct Database {
     pool: PgPool,
+    /// Cleared on connection errors, set again once a query succeeds
+    available: Arc<AtomicBool>,
 }
 
 impl Database {
     pub async fn new(config: &DatabaseConfig) -> Result<Self, sqlx::Error> {
-        let pool = PgPool::connect(&config.database_url).await?;
-        Ok(Self { pool })
+        let pool = config.pool_options().connect(&config.database_url).await?;
+        Ok(Self::from_pool(pool))
+    }
+
+    /// Create a database handle that only connects when first used
+    pub fn connect_lazy(config: &DatabaseConfig) -> Result<Self, sqlx::Error> {
+        let pool = config.pool_options().connect_lazy(&config.database_url)?;
+        Ok(Self::from_pool(pool))
+    }
+
+    fn from_pool(pool: PgPool) -> Self {
+        Self {
+            pool,
+            available: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Whether the last queries reached the database
+    pub fn is_available(&self) -> bool {
+        self.available.load(Ordering::Relaxed)
+    }
+
+    /// Track availability from the outcome of a query
+    fn observe<T>(&self, result: &Result<T, sqlx::Error>) {
+        match result {
+            Err(err) if is_connection_error(err) => {
+                if self.available.swap(false, Ordering::Relaxed) {
+                    warn!("Database is unavailable: {}", err);
+                }
+            }
+            Err(_) => {}
+            Ok(_) => {
+                if !self.available.swap(true, Ordering::Relaxed) {
+                    info!("Database is available again");
+                }
+            }
+        }
+    }
+
+    /// Check the database answers
+    #[instrument(name = "db", skip_all, fields(operation = "ping"))]
+    pub async fn ping(&self) -> Result<(), sqlx::Error> {
+        let result = sqlx::query("SELECT 1")
+            .execute(&self.pool)
+            .await
+            .map(|_| ());
+        self.observe(&result);
+        result
+    }
+
+    /// Run statements in a transaction, committed if `f` succeeds and rolled
+    /// back if it fails, so multi-step changes are never left half done.
+    ///
+    /// The future returned by `f` may only borrow the transaction: move owned
+    /// copies of any other data into it.
+    pub async fn transaction<T, F>(&self, f: F) -> Result<T, sqlx::Error>
+    where
+        T: Send,
+        F: for<'c> FnOnce(
+            &'c mut Transaction<'static, Postgres>,
+        ) -> BoxFuture<'c, Result<T, sqlx::Error>>,
+    {
+        let mut tx = self.pool.begin().await?;
+        // Dropping the transaction on error rolls it back
+        let value = f(&mut tx).await?;
+        tx.commit().await?;
+        Ok(value)
+    }
+
+    /// Run an idempotent read, retrying it once on a connection error so a
+    /// failover only costs a reconnect
+    async fn retry_read<T, F, Fut>(&self, read: F) -> Result<T, sqlx::Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, sqlx::Error>>,
+    {
+        let result = match read().await {
+            Err(err) if is_connection_error(&err) => {
+                warn!("Retrying read after connection error: {}", err);
+                read().await
+            }
+            result => result,
+        };
+        self.observe(&result);
+        result
     }
 
     /// Initialize the database by running migrations
@@ -53,58 +767,172 @@ impl Database {
         Ok(())
     }
 
-    /// Get or create ASN for a user
-    pub async fn get_or_create_user_asn(
+    /// Assign an ASN to a user, unless the user already holds `max` ASNs
+    #[instrument(name = "db", skip_all, fields(operation = "assign_user_asn", user_hash = %user_hash, asn = %asn))]
+    pub async fn assign_user_asn(
         &self,
-        user_hash: &str,
+        user_hash: &UserHash,
         user_id: Option<&str>,
-        asn: i32,
-    ) -> Result<UserAsnMapping, sqlx::Error> {
-        // First try to get existing mapping
-        let existing = sqlx::query_as::<_, UserAsnMapping>(
-            "SELECT * FROM user_asn_mappings WHERE user_hash = $1",
-        )
-        .bind(user_hash)
-        .fetch_optional(&self.pool)
-        .await?;
+        asn: Asn,
+        max: i64,
+    ) -> Result<Option<UserAsnMapping>, sqlx::Error> {
+        let owner = user_hash.clone();
+        let user_id = user_id.map(str::to_string);
+        let mapping = self
+            .transaction(|tx| {
+                Box::pin(async move {
+                    // Concurrent requests of a user would both see room under the quota
+                    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+                        .bind(&owner)
+                        .execute(&mut **tx)
+                        .await?;
+                    sqlx::query_as::<_, UserAsnMapping>(
+                        "INSERT INTO user_asn_mappings (user_hash, user_id, asn)
+                         SELECT $1, $2, $3
+                         WHERE (SELECT COUNT(*) FROM user_asn_mappings WHERE user_hash = $1) < $4
+                         RETURNING *",
+                    )
+                    .bind(&owner)
+                    .bind(user_id)
+                    .bind(asn)
+                    .bind(max)
+                    .fetch_optional(&mut **tx)
+                    .await
+                })
+            })
+            .await?;
 
-        if let Some(mapping) = existing {
-            return Ok(mapping);
+        if mapping.is_some() {
+            debug!("Created ASN mapping for user {}: ASN {}", user_hash, asn);
         }
+        Ok(mapping)
+    }
+
+    /// Get the first ASN mapping of a user
+    #[instrument(name = "db", skip_all, fields(operation = "get_user_asn", user_hash = %user_hash))]
+    pub async fn get_user_asn(
+        &self,
+        user_hash: &UserHash,
+    ) -> Result<Option<UserAsnMapping>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, UserAsnMapping>(
+                "SELECT * FROM user_asn_mappings WHERE user_hash = $1
+                 ORDER BY created_at, id LIMIT 1",
+            )
+            .bind(user_hash)
+            .fetch_optional(&self.pool)
+        })
+        .await
+    }
+
+    /// Get all ASN mappings of a user, first assigned first
+    #[instrument(name = "db", skip_all, fields(operation = "get_user_asns", user_hash = %user_hash))]
+    pub async fn get_user_asns(
+        &self,
+        user_hash: &UserHash,
+    ) -> Result<Vec<UserAsnMapping>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, UserAsnMapping>(
+                "SELECT * FROM user_asn_mappings WHERE user_hash = $1
+                 ORDER BY created_at, id",
+            )
+            .bind(user_hash)
+            .fetch_all(&self.pool)
+        })
+        .await
+    }
 
-        // Create new mapping
-        let mapping = sqlx::query_as::<_, UserAsnMapping>(
-            "INSERT INTO user_asn_mappings (user_hash, user_id, asn) VALUES ($1, $2, $3)
-             ON CONFLICT (user_hash) DO UPDATE SET updated_at = NOW(), user_id = EXCLUDED.user_id
+    /// Set the description and abuse contact of one ASN of a user, returning
+    /// `None` if the user doesn't hold the ASN
+    #[instrument(name = "db", skip_all, fields(operation = "set_user_asn_contact", user_hash = %user_hash, asn = %asn))]
+    pub async fn set_user_asn_contact(
+        &self,
+        user_hash: &UserHash,
+        asn: Asn,
+        description: Option<&str>,
+        abuse_contact: Option<&str>,
+    ) -> Result<Option<UserAsnMapping>, sqlx::Error> {
+        sqlx::query_as::<_, UserAsnMapping>(
+            "UPDATE user_asn_mappings SET description = $3, abuse_contact = $4, updated_at = NOW()
+             WHERE user_hash = $1 AND asn = $2
              RETURNING *",
         )
         .bind(user_hash)
-        .bind(user_id)
         .bind(asn)
-        .fetch_one(&self.pool)
-        .await?;
-
-        debug!("Created ASN mapping for user {}: ASN {}", user_hash, asn);
-        Ok(mapping)
+        .bind(description)
+        .bind(abuse_contact)
+        .fetch_optional(&self.pool)
+        .await
     }
 
-    /// Get user ASN mapping
-    pub async fn get_user_asn(
+    /// Release one ASN of a user back to the pool, returning the removed mapping
+    #[instrument(name = "db", skip_all, fields(operation = "delete_user_asn", user_hash = %user_hash, asn = %asn))]
+    pub async fn delete_user_asn(
         &self,
-        user_hash: &str,
+        user_hash: &UserHash,
+        asn: Asn,
     ) -> Result<Option<UserAsnMapping>, sqlx::Error> {
-        let mapping = sqlx::query_as::<_, UserAsnMapping>(
-            "SELECT * FROM user_asn_mappings WHERE user_hash = $1",
+        let user_hash = user_hash.clone();
+        self.transaction(|tx| {
+            Box::pin(async move {
+                // Annotations live on the user's first mapping, keep them when
+                // it goes and another ASN is left
+                sqlx::query(
+                    "UPDATE mapping_annotations SET mapping_id = next.id
+                     FROM (SELECT id FROM user_asn_mappings
+                           WHERE user_hash = $1 AND asn <> $2
+                           ORDER BY created_at, id LIMIT 1) AS next
+                     WHERE mapping_id = (SELECT id FROM user_asn_mappings
+                                         WHERE user_hash = $1 AND asn = $2)",
+                )
+                .bind(&user_hash)
+                .bind(asn)
+                .execute(&mut **tx)
+                .await?;
+                sqlx::query_as::<_, UserAsnMapping>(
+                    "DELETE FROM user_asn_mappings WHERE user_hash = $1 AND asn = $2 RETURNING *",
+                )
+                .bind(&user_hash)
+                .bind(asn)
+                .fetch_optional(&mut **tx)
+                .await
+            })
+        })
+        .await
+    }
+
+    /// Release a user's ASNs back to the pool, returning the removed mappings
+    #[instrument(name = "db", skip_all, fields(operation = "delete_user_asns", user_hash = %user_hash))]
+    pub async fn delete_user_asns(
+        &self,
+        user_hash: &UserHash,
+    ) -> Result<Vec<UserAsnMapping>, sqlx::Error> {
+        let mut mappings = sqlx::query_as::<_, UserAsnMapping>(
+            "DELETE FROM user_asn_mappings WHERE user_hash = $1 RETURNING *",
         )
         .bind(user_hash)
-        .fetch_optional(&self.pool)
+        .fetch_all(&self.pool)
         .await?;
 
-        Ok(mapping)
+        mappings.sort_by_key(|mapping| (mapping.created_at, mapping.id));
+        Ok(mappings)
+    }
+
+    /// Get all ASN mappings
+    #[instrument(name = "db", skip_all, fields(operation = "get_all_asn_mappings"))]
+    pub async fn get_all_asn_mappings(&self) -> Result<Vec<UserAsnMapping>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, UserAsnMapping>(
+                "SELECT * FROM user_asn_mappings ORDER BY created_at DESC",
+            )
+            .fetch_all(&self.pool)
+        })
+        .await
     }
 
     /// Check if an ASN is already assigned
-    pub async fn is_asn_assigned(&self, asn: i32) -> Result<bool, sqlx::Error> {
+    #[instrument(name = "db", skip_all, fields(operation = "is_asn_assigned", asn = %asn))]
+    pub async fn is_asn_assigned(&self, asn: Asn) -> Result<bool, sqlx::Error> {
         let count: i64 =
             sqlx::query_scalar("SELECT COUNT(*) FROM user_asn_mappings WHERE asn = $1")
                 .bind(asn)
@@ -114,25 +942,28 @@ impl Database {
         Ok(count > 0)
     }
 
-    /// Create a new prefix lease
+    /// Create a new prefix lease, optionally pinned to sites
+    #[instrument(name = "db", skip_all, fields(operation = "create_prefix_lease", user_hash = %user_hash, prefix = %prefix))]
     pub async fn create_prefix_lease(
         &self,
-        user_hash: &str,
-        prefix: &Ipv6Net,
-        duration_hours: i32,
+        user_hash: &UserHash,
+        prefix: &Prefix,
+        duration: chrono::Duration,
+        sites: Option<&[String]>,
     ) -> Result<PrefixLease, sqlx::Error> {
         let start_time = Utc::now();
-        let end_time = start_time + chrono::Duration::hours(duration_hours as i64);
+        let end_time = start_time + duration;
 
         let lease = sqlx::query_as::<_, PrefixLease>(
-            "INSERT INTO prefix_leases (user_hash, prefix, start_time, end_time)
-             VALUES ($1, $2::cidr, $3, $4)
-             RETURNING id, user_hash, prefix::text, start_time, end_time, created_at, updated_at",
+            "INSERT INTO prefix_leases (user_hash, prefix, start_time, end_time, sites)
+             VALUES ($1, $2::cidr, $3, $4, $5)
+             RETURNING id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length",
         )
         .bind(user_hash)
         .bind(prefix.to_string())
         .bind(start_time)
         .bind(end_time)
+        .bind(sites)
         .fetch_one(&self.pool)
         .await?;
 
@@ -143,52 +974,917 @@ impl Database {
         Ok(lease)
     }
 
-    /// Get active prefix leases for a user
-    pub async fn get_active_user_leases(
+    /// Create leases of several prefixes at once, all or none of them,
+    /// starting at `start_time` (which may be in the future) and labelled
+    /// alike. Auto-renewed leases are extended by `duration` each time.
+    #[instrument(name = "db", skip_all, fields(operation = "create_prefix_leases", user_hash = %user_hash, count = prefixes.len()))]
+    pub async fn create_prefix_leases(
         &self,
-        user_hash: &str,
+        user_hash: &UserHash,
+        prefixes: &[Prefix],
+        start_time: DateTime<Utc>,
+        duration: chrono::Duration,
+        options: &LeaseOptions,
     ) -> Result<Vec<PrefixLease>, sqlx::Error> {
-        let leases = sqlx::query_as::<_, PrefixLease>(
-            "SELECT id, user_hash, prefix::text, start_time, end_time, created_at, updated_at
-             FROM prefix_leases
-             WHERE user_hash = $1 AND end_time > NOW()
-             ORDER BY end_time DESC",
+        let renew_minutes = options.auto_renew.then_some(duration.num_minutes() as i32);
+        let end_time = start_time + duration;
+
+        let (user_hash, prefixes, options) =
+            (user_hash.clone(), prefixes.to_vec(), options.clone());
+        let leases = self
+            .transaction(|tx| {
+                Box::pin(async move {
+                    let mut leases = Vec::with_capacity(prefixes.len());
+                    for prefix in &prefixes {
+                        let lease = sqlx::query_as::<_, PrefixLease>(
+                            "INSERT INTO prefix_leases (user_hash, prefix, start_time, end_time, sites, renew_minutes, label, purpose)
+                             VALUES ($1, $2::cidr, $3, $4, $5, $6, $7, $8)
+                             RETURNING id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length",
+                        )
+                        .bind(&user_hash)
+                        .bind(prefix.to_string())
+                        .bind(start_time)
+                        .bind(end_time)
+                        .bind(&options.sites)
+                        .bind(renew_minutes)
+                        .bind(&options.label)
+                        .bind(&options.purpose)
+                        .fetch_one(&mut **tx)
+                        .await?;
+                        leases.push(lease);
+                    }
+                    Ok(leases)
+                })
+            })
+            .await?;
+
+        debug!("Created {} prefix leases until {}", leases.len(), end_time);
+        Ok(leases)
+    }
+
+    /// Renew a user's active lease of a prefix, ending it `duration` from now,
+    /// and turn its auto-renewal on or off when `auto_renew` is set. Permanent
+    /// leases are left as they are.
+    #[instrument(name = "db", skip_all, fields(operation = "extend_prefix_lease", prefix = %prefix))]
+    pub async fn extend_prefix_lease(
+        &self,
+        user_hash: &UserHash,
+        prefix: &Prefix,
+        duration: chrono::Duration,
+        auto_renew: Option<bool>,
+    ) -> Result<Option<PrefixLease>, sqlx::Error> {
+        let end_time = Utc::now() + duration;
+
+        let lease = sqlx::query_as::<_, PrefixLease>(
+            "UPDATE prefix_leases SET end_time = $3, updated_at = NOW(),
+                renew_minutes = CASE WHEN $4::boolean IS NULL THEN renew_minutes
+                                   WHEN $4 THEN $5 END
+             WHERE user_hash = $1 AND prefix = $2::cidr AND start_time <= NOW() AND end_time > NOW()
+               AND end_time < $6
+             RETURNING id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length",
         )
         .bind(user_hash)
-        .fetch_all(&self.pool)
+        .bind(prefix.to_string())
+        .bind(end_time)
+        .bind(auto_renew)
+        .bind(duration.num_minutes() as i32)
+        .bind(permanent_end_time())
+        .fetch_optional(&self.pool)
         .await?;
 
-        Ok(leases)
+        Ok(lease)
     }
 
-    /// Get all active leases (for downstream services)
-    pub async fn get_all_active_leases(&self) -> Result<Vec<PrefixLease>, sqlx::Error> {
-        let leases = sqlx::query_as::<_, PrefixLease>(
-            "SELECT id, user_hash, prefix::text, start_time, end_time, created_at, updated_at
-             FROM prefix_leases
-             WHERE end_time > NOW()
-             ORDER BY end_time DESC",
+    /// Extend the auto-renewed leases ending before `before` by their
+    /// `renew_minutes`, up to `max_hours` after their start. Leases that would
+    /// run into a later lease of their prefix, a slice of it or the prefix
+    /// containing it are left to end.
+    #[instrument(name = "db", skip_all, fields(operation = "renew_expiring_leases"))]
+    pub async fn renew_expiring_leases(
+        &self,
+        before: DateTime<Utc>,
+        max_hours: i32,
+    ) -> Result<Vec<PrefixLease>, sqlx::Error> {
+        sqlx::query_as::<_, PrefixLease>(
+            "WITH renewals AS (
+                SELECT l.id, LEAST(
+                    l.end_time + make_interval(mins => l.renew_minutes),
+                    l.start_time + make_interval(hours => $2)
+                ) AS end_time
+                FROM prefix_leases l
+                WHERE l.renew_minutes IS NOT NULL
+                  AND l.start_time <= NOW() AND l.end_time > NOW() AND l.end_time <= $1
+                  AND l.end_time < l.start_time + make_interval(hours => $2)
+            )
+            UPDATE prefix_leases l SET end_time = r.end_time, updated_at = NOW()
+            FROM renewals r
+            WHERE l.id = r.id
+              AND NOT EXISTS (
+                SELECT 1 FROM prefix_leases later
+                WHERE later.prefix && l.prefix AND later.id <> l.id
+                  AND later.start_time < r.end_time AND later.end_time > l.end_time
+              )
+            RETURNING l.id, l.user_hash, l.prefix::text, l.start_time, l.end_time, l.created_at, l.updated_at, l.sites, l.renew_minutes, l.label, l.purpose, l.roa_max_length",
         )
+        .bind(before)
+        .bind(max_hours)
         .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Get a page of a user's leases, active or not, newest first. `after` is
+    /// the start time and ID of the last lease of the previous page.
+    #[instrument(name = "db", skip_all, fields(operation = "get_user_lease_history", user_hash = %user_hash))]
+    pub async fn get_user_lease_history(
+        &self,
+        user_hash: &UserHash,
+        after: Option<(DateTime<Utc>, Uuid)>,
+        limit: i64,
+    ) -> Result<Vec<PrefixLease>, sqlx::Error> {
+        let (after_time, after_id) = after.unzip();
+        self.retry_read(|| {
+            sqlx::query_as::<_, PrefixLease>(
+                "SELECT id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length
+                 FROM prefix_leases
+                 WHERE user_hash = $1
+                   AND ($2::timestamptz IS NULL OR (start_time, id) < ($2, $3))
+                 ORDER BY start_time DESC, id DESC
+                 LIMIT $4",
+            )
+            .bind(user_hash)
+            .bind(after_time)
+            .bind(after_id)
+            .bind(limit)
+            .fetch_all(&self.pool)
+        })
+        .await
+    }
+
+    /// Get the prefix of the user's most recent lease, ended or not
+    #[instrument(name = "db", skip_all, fields(operation = "get_last_prefix_for_user", user_hash = %user_hash))]
+    pub async fn get_last_prefix_for_user(
+        &self,
+        user_hash: &UserHash,
+    ) -> Result<Option<String>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_scalar::<_, String>(
+                "SELECT prefix::text
+                 FROM prefix_leases
+                 WHERE user_hash = $1
+                 ORDER BY start_time DESC, id DESC
+                 LIMIT 1",
+            )
+            .bind(user_hash)
+            .fetch_optional(&self.pool)
+        })
+        .await
+    }
+
+    /// Get active prefix leases for a user, leaving out those starting later
+    #[instrument(name = "db", skip_all, fields(operation = "get_active_user_leases", user_hash = %user_hash))]
+    pub async fn get_active_user_leases(
+        &self,
+        user_hash: &UserHash,
+    ) -> Result<Vec<PrefixLease>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, PrefixLease>(
+                "SELECT id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length
+                 FROM prefix_leases
+                 WHERE user_hash = $1 AND start_time <= NOW() AND end_time > NOW()
+                 ORDER BY end_time DESC",
+            )
+            .bind(user_hash)
+            .fetch_all(&self.pool)
+        })
+        .await
+    }
+
+    /// Get the leases of a user starting later, soonest first
+    #[instrument(name = "db", skip_all, fields(operation = "get_upcoming_user_leases", user_hash = %user_hash))]
+    pub async fn get_upcoming_user_leases(
+        &self,
+        user_hash: &UserHash,
+    ) -> Result<Vec<PrefixLease>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, PrefixLease>(
+                "SELECT id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length
+                 FROM prefix_leases
+                 WHERE user_hash = $1 AND start_time > NOW()
+                 ORDER BY start_time",
+            )
+            .bind(user_hash)
+            .fetch_all(&self.pool)
+        })
+        .await
+    }
+
+    /// Get a user's leases that ended after `since`, latest first
+    #[instrument(name = "db", skip_all, fields(operation = "get_user_leases_ended_since", user_hash = %user_hash))]
+    pub async fn get_user_leases_ended_since(
+        &self,
+        user_hash: &UserHash,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<PrefixLease>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, PrefixLease>(
+                "SELECT id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length
+                 FROM prefix_leases
+                 WHERE user_hash = $1 AND end_time > $2 AND end_time <= NOW()
+                 ORDER BY end_time DESC",
+            )
+            .bind(user_hash)
+            .bind(since)
+            .fetch_all(&self.pool)
+        })
+        .await
+    }
+
+    /// Get a user's leases covering some of `[start, until)`
+    #[instrument(name = "db", skip_all, fields(operation = "get_user_leases_during", user_hash = %user_hash))]
+    pub async fn get_user_leases_during(
+        &self,
+        user_hash: &UserHash,
+        start: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<Vec<PrefixLease>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, PrefixLease>(
+                "SELECT id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length
+                 FROM prefix_leases
+                 WHERE user_hash = $1 AND end_time > $2 AND start_time < $3
+                 ORDER BY end_time DESC",
+            )
+            .bind(user_hash)
+            .bind(start)
+            .bind(until)
+            .fetch_all(&self.pool)
+        })
+        .await
+    }
+
+    /// Get all active leases (for downstream services), leaving out those
+    /// starting later
+    #[instrument(name = "db", skip_all, fields(operation = "get_all_active_leases"))]
+    pub async fn get_all_active_leases(&self) -> Result<Vec<PrefixLease>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, PrefixLease>(
+                "SELECT id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length
+                 FROM prefix_leases
+                 WHERE start_time <= NOW() AND end_time > NOW()
+                 ORDER BY end_time DESC",
+            )
+            .fetch_all(&self.pool)
+        })
+        .await
+    }
+
+    /// Get the announcement permissions of prefixes valid now, ordered by
+    /// user, prefix and ASN (first assigned first)
+    #[instrument(
+        name = "db",
+        skip_all,
+        fields(operation = "get_announcement_permissions")
+    )]
+    pub async fn get_announcement_permissions(
+        &self,
+    ) -> Result<Vec<AnnouncementPermission>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, AnnouncementPermission>(
+                "SELECT prefix::text, max_length, origin_asn, asn_assigned_at, user_hash, source,
+                        source_id, sites, rpki_state, valid_from, valid_to
+                 FROM announcement_permissions
+                 WHERE prefix IS NOT NULL
+                   AND valid_from <= NOW() AND (valid_to IS NULL OR valid_to > NOW())
+                 ORDER BY user_hash, prefix, asn_assigned_at, origin_asn",
+            )
+            .fetch_all(&self.pool)
+        })
+        .await
+    }
+
+    /// Get the rows of the read model the service mappings are built from:
+    /// the ASNs and active leases of the users matching `filter`, or of
+    /// `user_hash` only. Users are in the filter's order, by the ASN of theirs
+    /// that sorts first, and their rows by ASN (first assigned first), then
+    /// lease (latest ending first).
+    #[instrument(name = "db", skip_all, fields(operation = "get_mapping_permissions"))]
+    pub async fn get_mapping_permissions(
+        &self,
+        user_hash: Option<&UserHash>,
+        filter: &MappingFilter,
+    ) -> Result<Vec<MappingPermission>, sqlx::Error> {
+        // Sort column and order come from fixed enums, never from user input
+        let mut query = String::from(
+            "WITH permissions AS (
+                 SELECT user_hash, source, source_id, prefix::text, origin_asn, asn_assigned_at,
+                        holder_id, description, abuse_contact, label, purpose, sites, valid_to,
+                        COALESCE(updated_at, valid_from) AS updated_at
+                 FROM announcement_permissions
+                 WHERE source IN ('asn', 'lease')
+                   AND ($1::varchar IS NULL OR user_hash = $1)
+                   AND ($2::bigint IS NULL OR user_hash IN (
+                       SELECT user_hash FROM announcement_permissions
+                       WHERE source = 'asn' AND origin_asn = $2
+                   ))",
+        );
+        if filter.active_only {
+            query.push_str(
+                "
+                   AND user_hash IN (
+                       SELECT user_hash FROM announcement_permissions
+                       WHERE source = 'lease' AND valid_from <= NOW() AND valid_to > NOW()
+                   )",
+            );
+        }
+        query.push_str(&format!(
+            "
+                   AND valid_from <= NOW() AND (valid_to IS NULL OR valid_to > NOW())
+             ),
+             holders AS (
+                 SELECT user_hash, {leading}({column}) AS leading
+                 FROM permissions WHERE source = 'asn'
+                 GROUP BY user_hash
+             )
+             SELECT permissions.* FROM permissions LEFT JOIN holders USING (user_hash)
+             ORDER BY holders.leading {keyword} NULLS LAST, user_hash, asn_assigned_at,
+                      origin_asn, valid_to DESC NULLS FIRST, source_id",
+            leading = filter.order.leading(),
+            column = filter.sort.column(),
+            keyword = filter.order.keyword(),
+        ));
+
+        self.retry_read(|| {
+            sqlx::query_as::<_, MappingPermission>(&query)
+                .bind(user_hash)
+                .bind(filter.asn)
+                .fetch_all(&self.pool)
+        })
+        .await
+    }
+
+    /// Count participants, ASNs in use and announced prefixes
+    #[instrument(name = "db", skip_all, fields(operation = "get_lab_totals"))]
+    pub async fn get_lab_totals(&self) -> Result<LabTotals, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, LabTotals>(
+                "WITH active AS (
+                     SELECT user_hash FROM prefix_leases
+                     WHERE start_time <= NOW() AND end_time > NOW()
+                 )
+                 SELECT
+                     (SELECT COUNT(DISTINCT user_hash) FROM user_asn_mappings) AS participants,
+                     (SELECT COUNT(DISTINCT m.asn) FROM user_asn_mappings m
+                      WHERE m.user_hash IN (SELECT user_hash FROM active)) AS asns_in_use,
+                     (SELECT COUNT(*) FROM active) AS prefixes_announced",
+            )
+            .fetch_one(&self.pool)
+        })
+        .await
+    }
+
+    /// Get the leases covering some of `[start, until)`
+    #[instrument(name = "db", skip_all, fields(operation = "get_leases_during"))]
+    pub async fn get_leases_during(
+        &self,
+        start: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<Vec<PrefixLease>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, PrefixLease>(
+                "SELECT id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length
+                 FROM prefix_leases
+                 WHERE end_time > $1 AND start_time < $2
+                 ORDER BY end_time DESC",
+            )
+            .bind(start)
+            .bind(until)
+            .fetch_all(&self.pool)
+        })
+        .await
+    }
+
+    /// End an active lease now, returning it if it was active. A lease that
+    /// hasn't started is cancelled (ended at its start).
+    #[instrument(name = "db", skip_all, fields(operation = "revoke_prefix_lease"))]
+    pub async fn revoke_prefix_lease(&self, id: Uuid) -> Result<Option<PrefixLease>, sqlx::Error> {
+        let lease = sqlx::query_as::<_, PrefixLease>(
+            "UPDATE prefix_leases SET end_time = GREATEST(start_time, NOW()), updated_at = NOW()
+             WHERE id = $1 AND end_time > NOW()
+             RETURNING id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
         .await?;
 
-        Ok(leases)
+        Ok(lease)
     }
 
-    /// Check if a prefix is currently leased
-    pub async fn is_prefix_leased(&self, prefix: &Ipv6Net) -> Result<bool, sqlx::Error> {
-        let count: i64 = sqlx::query_scalar(
-            "SELECT COUNT(*) FROM prefix_leases
-             WHERE prefix = $1::cidr AND end_time > NOW()",
+    /// Get a lease by ID, whether or not it is still active
+    #[instrument(name = "db", skip_all, fields(operation = "get_prefix_lease"))]
+    pub async fn get_prefix_lease(&self, id: Uuid) -> Result<Option<PrefixLease>, sqlx::Error> {
+        sqlx::query_as::<_, PrefixLease>(
+            "SELECT id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length
+             FROM prefix_leases WHERE id = $1",
         )
-        .bind(prefix.to_string())
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Get the mapping of an ASN
+    #[instrument(name = "db", skip_all, fields(operation = "get_asn_mapping", asn = %asn))]
+    pub async fn get_asn_mapping(&self, asn: Asn) -> Result<Option<UserAsnMapping>, sqlx::Error> {
+        sqlx::query_as::<_, UserAsnMapping>("SELECT * FROM user_asn_mappings WHERE asn = $1")
+            .bind(asn)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Get the active leases matching a filter
+    #[instrument(name = "db", skip_all, fields(operation = "find_active_leases"))]
+    pub async fn find_active_leases(
+        &self,
+        filter: &LeaseFilter,
+    ) -> Result<Vec<PrefixLease>, sqlx::Error> {
+        sqlx::query_as::<_, PrefixLease>(&format!(
+            "SELECT id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length
+             FROM prefix_leases
+             WHERE {}
+             ORDER BY prefix",
+            LEASE_FILTER_CONDITIONS
+        ))
+        .bind(filter.user_hash.as_deref())
+        .bind(filter.prefix.map(|p| p.to_string()))
+        .bind(filter.site.as_deref())
+        .bind(filter.created_before)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// End every active lease matching a filter now, in a single statement
+    #[instrument(name = "db", skip_all, fields(operation = "revoke_prefix_leases"))]
+    pub async fn revoke_prefix_leases(
+        &self,
+        filter: &LeaseFilter,
+    ) -> Result<Vec<PrefixLease>, sqlx::Error> {
+        sqlx::query_as::<_, PrefixLease>(&format!(
+            "UPDATE prefix_leases SET end_time = GREATEST(start_time, NOW()), updated_at = NOW()
+             WHERE {}
+             RETURNING id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length",
+            LEASE_FILTER_CONDITIONS
+        ))
+        .bind(filter.user_hash.as_deref())
+        .bind(filter.prefix.map(|p| p.to_string()))
+        .bind(filter.site.as_deref())
+        .bind(filter.created_before)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Allow a user to take permanent leases, replacing the reason of an
+    /// existing approval
+    #[instrument(name = "db", skip_all, fields(operation = "approve_permanent_leases", user_hash = %user_hash))]
+    pub async fn approve_permanent_leases(
+        &self,
+        user_hash: &UserHash,
+        reason: &str,
+    ) -> Result<PermanentLeaseApproval, sqlx::Error> {
+        sqlx::query_as::<_, PermanentLeaseApproval>(
+            "INSERT INTO permanent_lease_approvals (user_hash, reason) VALUES ($1, $2)
+             ON CONFLICT (user_hash) DO UPDATE SET reason = EXCLUDED.reason
+             RETURNING *",
+        )
+        .bind(user_hash)
+        .bind(reason)
         .fetch_one(&self.pool)
-        .await?;
+        .await
+    }
 
-        Ok(count > 0)
+    /// Withdraw a user's approval for permanent leases, returning whether they
+    /// had one. Their permanent leases are kept.
+    #[instrument(name = "db", skip_all, fields(operation = "revoke_permanent_lease_approval", user_hash = %user_hash))]
+    pub async fn revoke_permanent_lease_approval(
+        &self,
+        user_hash: &UserHash,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM permanent_lease_approvals WHERE user_hash = $1")
+            .bind(user_hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Get a user's approval for permanent leases
+    #[instrument(name = "db", skip_all, fields(operation = "get_permanent_lease_approval", user_hash = %user_hash))]
+    pub async fn get_permanent_lease_approval(
+        &self,
+        user_hash: &UserHash,
+    ) -> Result<Option<PermanentLeaseApproval>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, PermanentLeaseApproval>(
+                "SELECT * FROM permanent_lease_approvals WHERE user_hash = $1",
+            )
+            .bind(user_hash)
+            .fetch_optional(&self.pool)
+        })
+        .await
+    }
+
+    /// Get every approval for permanent leases, newest first
+    #[instrument(
+        name = "db",
+        skip_all,
+        fields(operation = "get_permanent_lease_approvals")
+    )]
+    pub async fn get_permanent_lease_approvals(
+        &self,
+    ) -> Result<Vec<PermanentLeaseApproval>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, PermanentLeaseApproval>(
+                "SELECT * FROM permanent_lease_approvals ORDER BY created_at DESC",
+            )
+            .fetch_all(&self.pool)
+        })
+        .await
+    }
+
+    /// Move the end of an active lease, returning it if it was active
+    #[instrument(name = "db", skip_all, fields(operation = "set_prefix_lease_end_time"))]
+    pub async fn set_prefix_lease_end_time(
+        &self,
+        id: Uuid,
+        end_time: DateTime<Utc>,
+    ) -> Result<Option<PrefixLease>, sqlx::Error> {
+        sqlx::query_as::<_, PrefixLease>(
+            "UPDATE prefix_leases SET end_time = GREATEST(start_time, $2), updated_at = NOW()
+             WHERE id = $1 AND end_time > NOW()
+             RETURNING id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length",
+        )
+        .bind(id)
+        .bind(end_time)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Set the ROA maxLength of a current or upcoming lease (`None` for the
+    /// prefix length), returning it if it hasn't ended
+    #[instrument(name = "db", skip_all, fields(operation = "set_lease_roa_max_length", lease_id = %id))]
+    pub async fn set_lease_roa_max_length(
+        &self,
+        id: Uuid,
+        max_length: Option<i16>,
+    ) -> Result<Option<PrefixLease>, sqlx::Error> {
+        sqlx::query_as::<_, PrefixLease>(
+            "UPDATE prefix_leases SET roa_max_length = $2, updated_at = NOW()
+             WHERE id = $1 AND end_time > NOW()
+             RETURNING id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length",
+        )
+        .bind(id)
+        .bind(max_length)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Move a current or upcoming lease of `from` to `to`, recording the
+    /// transfer. Returns `None` if `from` doesn't hold the lease anymore.
+    #[instrument(name = "db", skip_all, fields(operation = "transfer_prefix_lease", lease_id = %id))]
+    pub async fn transfer_prefix_lease(
+        &self,
+        id: Uuid,
+        from: &UserHash,
+        to: &UserHash,
+        reason: &str,
+    ) -> Result<Option<PrefixLease>, sqlx::Error> {
+        let (from, to, reason) = (from.clone(), to.clone(), reason.to_string());
+        self.transaction(|tx| {
+            Box::pin(async move {
+                let lease = sqlx::query_as::<_, PrefixLease>(
+                    "UPDATE prefix_leases SET user_hash = $3, updated_at = NOW()
+                     WHERE id = $1 AND user_hash = $2 AND end_time > NOW()
+                     RETURNING id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length",
+                )
+                .bind(id)
+                .bind(&from)
+                .bind(&to)
+                .fetch_optional(&mut **tx)
+                .await?;
+                if let Some(ref lease) = lease {
+                    sqlx::query(
+                        "INSERT INTO transfers (resource, lease_id, prefix, from_user_hash, to_user_hash, reason)
+                         VALUES ('lease', $1, $2::cidr, $3, $4, $5)",
+                    )
+                    .bind(lease.id)
+                    .bind(&lease.prefix)
+                    .bind(&from)
+                    .bind(&to)
+                    .bind(&reason)
+                    .execute(&mut **tx)
+                    .await?;
+                }
+                Ok(lease)
+            })
+        })
+        .await
+    }
+
+    /// Move the ASNs of `from` to `to`, with their current and upcoming leases
+    /// when `with_leases` is set, recording each transfer. The IdP user ID,
+    /// description and abuse contact of the mappings belonged to `from` and
+    /// are cleared. Returns `None` if `from` has no ASN.
+    #[instrument(name = "db", skip_all, fields(operation = "transfer_user_asn", user_hash = %from))]
+    pub async fn transfer_user_asn(
+        &self,
+        from: &UserHash,
+        to: &UserHash,
+        with_leases: bool,
+        reason: &str,
+    ) -> Result<Option<UserInfo>, sqlx::Error> {
+        let (from, to, reason) = (from.clone(), to.clone(), reason.to_string());
+        self.transaction(|tx| {
+            Box::pin(async move {
+                let mut mappings = sqlx::query_as::<_, UserAsnMapping>(
+                    "UPDATE user_asn_mappings
+                     SET user_hash = $2, user_id = NULL, description = NULL, abuse_contact = NULL,
+                         updated_at = NOW()
+                     WHERE user_hash = $1
+                     RETURNING *",
+                )
+                .bind(&from)
+                .bind(&to)
+                .fetch_all(&mut **tx)
+                .await?;
+                if mappings.is_empty() {
+                    return Ok(None);
+                }
+                mappings.sort_by_key(|mapping| (mapping.created_at, mapping.id));
+                sqlx::query(
+                    "INSERT INTO transfers (resource, asn, from_user_hash, to_user_hash, reason)
+                     SELECT 'asn', asn, $2, $3, $4 FROM UNNEST($1::bigint[]) AS moved(asn)",
+                )
+                .bind(mappings.iter().map(|mapping| mapping.asn).collect::<Vec<_>>())
+                .bind(&from)
+                .bind(&to)
+                .bind(&reason)
+                .execute(&mut **tx)
+                .await?;
+
+                // Annotations describe the previous holder's use of the ASNs
+                sqlx::query("DELETE FROM mapping_annotations WHERE mapping_id = ANY($1)")
+                    .bind(mappings.iter().map(|mapping| mapping.id).collect::<Vec<_>>())
+                    .execute(&mut **tx)
+                    .await?;
+
+                if !with_leases {
+                    return Ok(Some((mappings, Vec::new())));
+                }
+                let leases = sqlx::query_as::<_, PrefixLease>(
+                    "UPDATE prefix_leases SET user_hash = $2, updated_at = NOW()
+                     WHERE user_hash = $1 AND end_time > NOW()
+                     RETURNING id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length",
+                )
+                .bind(&from)
+                .bind(&to)
+                .fetch_all(&mut **tx)
+                .await?;
+                sqlx::query(
+                    "INSERT INTO transfers (resource, lease_id, prefix, from_user_hash, to_user_hash, reason)
+                     SELECT 'lease', id, prefix, $2, $3, $4 FROM UNNEST($1::uuid[]) AS moved(id)
+                     JOIN prefix_leases USING (id)",
+                )
+                .bind(leases.iter().map(|lease| lease.id).collect::<Vec<_>>())
+                .bind(&from)
+                .bind(&to)
+                .bind(&reason)
+                .execute(&mut **tx)
+                .await?;
+                Ok(Some((mappings, leases)))
+            })
+        })
+        .await
+    }
+
+    /// Provision workshop slots at once, all or none of them: the ASN, leases
+    /// and invite code of each slot. The leases start at `start_time` and are
+    /// labelled alike.
+    #[instrument(name = "db", skip_all, fields(operation = "create_workshop_slots", workshop = %workshop, count = slots.len()))]
+    pub async fn create_workshop_slots(
+        &self,
+        workshop: &str,
+        slots: &[WorkshopSlot],
+        start_time: DateTime<Utc>,
+        duration: chrono::Duration,
+        options: &LeaseOptions,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Vec<(InviteCode, Vec<PrefixLease>)>, sqlx::Error> {
+        let end_time = start_time + duration;
+        let (workshop, slots, options) = (workshop.to_string(), slots.to_vec(), options.clone());
+        self.transaction(|tx| {
+            Box::pin(async move {
+                let mut created = Vec::with_capacity(slots.len());
+                for slot in &slots {
+                    sqlx::query("INSERT INTO user_asn_mappings (user_hash, asn) VALUES ($1, $2)")
+                        .bind(&slot.user_hash)
+                        .bind(slot.asn)
+                        .execute(&mut **tx)
+                        .await?;
+                    let mut leases = Vec::with_capacity(slot.prefixes.len());
+                    for prefix in &slot.prefixes {
+                        let lease = sqlx::query_as::<_, PrefixLease>(
+                            "INSERT INTO prefix_leases (user_hash, prefix, start_time, end_time, sites, label, purpose)
+                             VALUES ($1, $2::cidr, $3, $4, $5, $6, $7)
+                             RETURNING id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length",
+                        )
+                        .bind(&slot.user_hash)
+                        .bind(prefix.to_string())
+                        .bind(start_time)
+                        .bind(end_time)
+                        .bind(&options.sites)
+                        .bind(&options.label)
+                        .bind(&options.purpose)
+                        .fetch_one(&mut **tx)
+                        .await?;
+                        leases.push(lease);
+                    }
+                    let code = sqlx::query_as::<_, InviteCode>(
+                        "INSERT INTO invite_codes (code_hash, workshop, slot_user_hash, asn, expires_at)
+                         VALUES ($1, $2, $3, $4, $5)
+                         RETURNING *",
+                    )
+                    .bind(&slot.code_hash)
+                    .bind(&workshop)
+                    .bind(&slot.user_hash)
+                    .bind(slot.asn)
+                    .bind(expires_at)
+                    .fetch_one(&mut **tx)
+                    .await?;
+                    created.push((code, leases));
+                }
+                Ok(created)
+            })
+        })
+        .await
+    }
+
+    /// Get the invite codes of a workshop, in the order they were provisioned
+    #[instrument(name = "db", skip_all, fields(operation = "get_workshop_invite_codes", workshop = %workshop))]
+    pub async fn get_workshop_invite_codes(
+        &self,
+        workshop: &str,
+    ) -> Result<Vec<InviteCode>, sqlx::Error> {
+        sqlx::query_as::<_, InviteCode>(
+            "SELECT * FROM invite_codes WHERE workshop = $1 ORDER BY created_at, id",
+        )
+        .bind(workshop)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Get an invite code by the hash of the code
+    #[instrument(name = "db", skip_all, fields(operation = "get_invite_code"))]
+    pub async fn get_invite_code(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<InviteCode>, sqlx::Error> {
+        sqlx::query_as::<_, InviteCode>("SELECT * FROM invite_codes WHERE code_hash = $1")
+            .bind(code_hash)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Redeem an unexpired invite code once: the ASN of its slot and the
+    /// current and upcoming leases move to the user, recording each transfer.
+    /// Returns `None` if the code was redeemed or expired meanwhile.
+    #[instrument(name = "db", skip_all, fields(operation = "redeem_invite_code", user_hash = %user_hash))]
+    pub async fn redeem_invite_code(
+        &self,
+        id: Uuid,
+        user_hash: &UserHash,
+        user_id: Option<&str>,
+    ) -> Result<Option<(InviteCode, UserAsnMapping, Vec<PrefixLease>)>, sqlx::Error> {
+        let (user_hash, user_id) = (user_hash.clone(), user_id.map(str::to_string));
+        self.transaction(|tx| {
+            Box::pin(async move {
+                let Some(code) = sqlx::query_as::<_, InviteCode>(
+                    "UPDATE invite_codes SET redeemed_by = $2, redeemed_at = NOW()
+                     WHERE id = $1 AND redeemed_at IS NULL AND expires_at > NOW()
+                     RETURNING *",
+                )
+                .bind(id)
+                .bind(&user_hash)
+                .fetch_optional(&mut **tx)
+                .await?
+                else {
+                    return Ok(None);
+                };
+                let reason = format!("invite code of workshop {} redeemed", code.workshop);
+
+                let mapping = sqlx::query_as::<_, UserAsnMapping>(
+                    "UPDATE user_asn_mappings SET user_hash = $2, user_id = $3, updated_at = NOW()
+                     WHERE user_hash = $1
+                     RETURNING *",
+                )
+                .bind(&code.slot_user_hash)
+                .bind(&user_hash)
+                .bind(&user_id)
+                .fetch_one(&mut **tx)
+                .await?;
+                sqlx::query(
+                    "INSERT INTO transfers (resource, asn, from_user_hash, to_user_hash, reason)
+                     VALUES ('asn', $1, $2, $3, $4)",
+                )
+                .bind(mapping.asn)
+                .bind(&code.slot_user_hash)
+                .bind(&user_hash)
+                .bind(&reason)
+                .execute(&mut **tx)
+                .await?;
+
+                let leases = sqlx::query_as::<_, PrefixLease>(
+                    "UPDATE prefix_leases SET user_hash = $2, updated_at = NOW()
+                     WHERE user_hash = $1 AND end_time > NOW()
+                     RETURNING id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length",
+                )
+                .bind(&code.slot_user_hash)
+                .bind(&user_hash)
+                .fetch_all(&mut **tx)
+                .await?;
+                sqlx::query(
+                    "INSERT INTO transfers (resource, lease_id, prefix, from_user_hash, to_user_hash, reason)
+                     SELECT 'lease', id, prefix, $2, $3, $4 FROM UNNEST($1::uuid[]) AS moved(id)
+                     JOIN prefix_leases USING (id)",
+                )
+                .bind(leases.iter().map(|lease| lease.id).collect::<Vec<_>>())
+                .bind(&code.slot_user_hash)
+                .bind(&user_hash)
+                .bind(&reason)
+                .execute(&mut **tx)
+                .await?;
+                Ok(Some((code, mapping, leases)))
+            })
+        })
+        .await
+    }
+
+    /// Give the unredeemed slots of a workshop back to the pools: their ASNs
+    /// are released, their leases ended and their codes removed. Returns the
+    /// removed codes with the ended leases.
+    #[instrument(name = "db", skip_all, fields(operation = "delete_workshop_slots", workshop = %workshop))]
+    pub async fn delete_workshop_slots(
+        &self,
+        workshop: &str,
+    ) -> Result<(Vec<InviteCode>, Vec<PrefixLease>), sqlx::Error> {
+        let workshop = workshop.to_string();
+        self.transaction(|tx| {
+            Box::pin(async move {
+                let codes = sqlx::query_as::<_, InviteCode>(
+                    "DELETE FROM invite_codes WHERE workshop = $1 AND redeemed_at IS NULL
+                     RETURNING *",
+                )
+                .bind(&workshop)
+                .fetch_all(&mut **tx)
+                .await?;
+                let slots: Vec<&str> = codes.iter().map(|code| code.slot_user_hash.as_str()).collect();
+                sqlx::query("DELETE FROM user_asn_mappings WHERE user_hash = ANY($1)")
+                    .bind(&slots)
+                    .execute(&mut **tx)
+                    .await?;
+                let leases = sqlx::query_as::<_, PrefixLease>(
+                    "UPDATE prefix_leases SET end_time = GREATEST(start_time, NOW()), updated_at = NOW()
+                     WHERE user_hash = ANY($1) AND end_time > NOW()
+                     RETURNING id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length",
+                )
+                .bind(&slots)
+                .fetch_all(&mut **tx)
+                .await?;
+                Ok((codes, leases))
+            })
+        })
+        .await
+    }
+
+    /// Get the transfers from or to a user (every transfer when `None`),
+    /// newest first
+    #[instrument(name = "db", skip_all, fields(operation = "get_transfers"))]
+    pub async fn get_transfers(
+        &self,
+        user_hash: Option<&UserHash>,
+    ) -> Result<Vec<Transfer>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, Transfer>(
+                "SELECT id, resource, lease_id, prefix::text, asn, from_user_hash, to_user_hash, reason, created_at
+                 FROM transfers
+                 WHERE $1::varchar IS NULL OR from_user_hash = $1 OR to_user_hash = $1
+                 ORDER BY created_at DESC",
+            )
+            .bind(user_hash)
+            .fetch_all(&self.pool)
+        })
+        .await
     }
 
     /// Clean up expired leases (optional maintenance task)
+    #[instrument(name = "db", skip_all, fields(operation = "cleanup_expired_leases"))]
     pub async fn cleanup_expired_leases(&self) -> Result<u64, sqlx::Error> {
         let result =
             sqlx::query("DELETE FROM prefix_leases WHERE end_time < NOW() - INTERVAL '7 days'")
@@ -198,40 +1894,1253 @@ impl Database {
         Ok(result.rows_affected())
     }
 
-    /// Get user information with ASN and active leases
-    pub async fn get_user_info(
+    /// Count assigned ASNs and currently leased prefixes
+    #[instrument(name = "db", skip_all, fields(operation = "get_pool_usage"))]
+    pub async fn get_pool_usage(&self) -> Result<(i64, i64), sqlx::Error> {
+        let usage: (i64, i64) = sqlx::query_as(
+            "SELECT
+                (SELECT COUNT(*) FROM user_asn_mappings),
+                (SELECT COUNT(*) FROM prefix_leases WHERE start_time <= NOW() AND end_time > NOW())",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(usage)
+    }
+
+    /// Record a snapshot of the current pool usage
+    #[instrument(name = "db", skip_all, fields(operation = "record_pool_usage"))]
+    pub async fn record_pool_usage(&self) -> Result<PoolUsageSnapshot, sqlx::Error> {
+        let snapshot = sqlx::query_as::<_, PoolUsageSnapshot>(
+            "INSERT INTO pool_usage_snapshots (asns_assigned, prefixes_leased)
+             SELECT
+                (SELECT COUNT(*) FROM user_asn_mappings),
+                (SELECT COUNT(*) FROM prefix_leases WHERE start_time <= NOW() AND end_time > NOW())
+             RETURNING *",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        debug!(
+            "Recorded pool usage: {} ASNs, {} prefixes",
+            snapshot.asns_assigned, snapshot.prefixes_leased
+        );
+        Ok(snapshot)
+    }
+
+    /// Get the prefixes of the pool managed through the admin API
+    #[instrument(name = "db", skip_all, fields(operation = "get_prefix_pool_entries"))]
+    pub async fn get_prefix_pool_entries(&self) -> Result<Vec<PrefixPoolEntry>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, PrefixPoolEntry>(
+                "SELECT prefix::text, class, status, note, created_at, updated_at
+                 FROM prefix_pool ORDER BY prefix",
+            )
+            .fetch_all(&self.pool)
+        })
+        .await
+    }
+
+    /// Add a prefix to the pool or update its class, status and note
+    #[instrument(name = "db", skip_all, fields(operation = "set_prefix_pool_entry", prefix = %prefix, status = %status))]
+    pub async fn set_prefix_pool_entry(
         &self,
-        user_hash: &str,
-    ) -> Result<Option<(Option<UserAsnMapping>, Vec<PrefixLease>)>, sqlx::Error> {
-        let asn_mapping = self.get_user_asn(user_hash).await?;
-        let leases = self.get_active_user_leases(user_hash).await?;
+        prefix: &Prefix,
+        class: Option<&str>,
+        status: &str,
+        note: Option<&str>,
+    ) -> Result<PrefixPoolEntry, sqlx::Error> {
+        sqlx::query_as::<_, PrefixPoolEntry>(
+            "INSERT INTO prefix_pool (prefix, class, status, note)
+             VALUES ($1::cidr, $2, $3, $4)
+             ON CONFLICT (prefix) DO UPDATE
+             SET class = EXCLUDED.class, status = EXCLUDED.status, note = EXCLUDED.note,
+                 updated_at = NOW()
+             RETURNING prefix::text, class, status, note, created_at, updated_at",
+        )
+        .bind(prefix.to_string())
+        .bind(class)
+        .bind(status)
+        .bind(note)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Remove a prefix managed through the admin API, returning whether it existed
+    #[instrument(name = "db", skip_all, fields(operation = "delete_prefix_pool_entry", prefix = %prefix))]
+    pub async fn delete_prefix_pool_entry(&self, prefix: &Prefix) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM prefix_pool WHERE prefix = $1::cidr")
+            .bind(prefix.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Add hourly API call counts to the recorded usage
+    #[instrument(name = "db", skip_all, fields(operation = "record_api_usage", rows = usage.len()))]
+    pub async fn record_api_usage(&self, usage: &[ApiUsage]) -> Result<(), sqlx::Error> {
+        if usage.is_empty() {
+            return Ok(());
+        }
+        sqlx::query(
+            "INSERT INTO api_usage (hour, subject_kind, subject, route, calls)
+             SELECT * FROM UNNEST($1::timestamptz[], $2::text[], $3::text[], $4::text[], $5::bigint[])
+             ON CONFLICT (hour, subject_kind, subject, route)
+             DO UPDATE SET calls = api_usage.calls + EXCLUDED.calls",
+        )
+        .bind(usage.iter().map(|u| u.hour).collect::<Vec<_>>())
+        .bind(usage.iter().map(|u| u.subject_kind.as_str()).collect::<Vec<_>>())
+        .bind(usage.iter().map(|u| u.subject.as_str()).collect::<Vec<_>>())
+        .bind(usage.iter().map(|u| u.route.as_str()).collect::<Vec<_>>())
+        .bind(usage.iter().map(|u| u.calls).collect::<Vec<_>>())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get the users or agents making the most API calls over a period
+    #[instrument(name = "db", skip_all, fields(operation = "get_top_api_usage"))]
+    pub async fn get_top_api_usage(
+        &self,
+        subject_kind: Option<&str>,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<ApiUsageTotal>, sqlx::Error> {
+        sqlx::query_as::<_, ApiUsageTotal>(
+            "SELECT subject_kind, subject, SUM(calls)::bigint AS calls
+             FROM api_usage
+             WHERE ($1::text IS NULL OR subject_kind = $1) AND hour >= $2 AND hour < $3
+             GROUP BY subject_kind, subject
+             ORDER BY calls DESC, subject
+             LIMIT $4",
+        )
+        .bind(subject_kind)
+        .bind(since)
+        .bind(until)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Get the hourly API usage of a user or agent over a period, oldest first
+    #[instrument(name = "db", skip_all, fields(operation = "get_api_usage"))]
+    pub async fn get_api_usage(
+        &self,
+        subject_kind: &str,
+        subject: &str,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<Vec<ApiUsage>, sqlx::Error> {
+        sqlx::query_as::<_, ApiUsage>(
+            "SELECT * FROM api_usage
+             WHERE subject_kind = $1 AND subject = $2 AND hour >= $3 AND hour < $4
+             ORDER BY hour, route",
+        )
+        .bind(subject_kind)
+        .bind(subject)
+        .bind(since)
+        .bind(until)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Delete API usage recorded before a point in time
+    #[instrument(name = "db", skip_all, fields(operation = "delete_api_usage_before"))]
+    pub async fn delete_api_usage_before(&self, before: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM api_usage WHERE hour < $1")
+            .bind(before)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Claim an idempotency key of a user for a request, returning the claim
+    /// time if it was free. Keys created before `expired_before` are claimed
+    /// again, as are claims still in progress since before `stale_before`,
+    /// left behind by a request that never finished.
+    #[instrument(name = "db", skip_all, fields(operation = "claim_idempotency_key", user_hash = %user_hash))]
+    pub async fn claim_idempotency_key(
+        &self,
+        user_hash: &UserHash,
+        key: &str,
+        request_hash: &str,
+        expired_before: DateTime<Utc>,
+        stale_before: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+        sqlx::query_scalar(
+            "INSERT INTO idempotency_keys (user_hash, idempotency_key, request_hash)
+             VALUES ($1, $2, $3)
+             ON CONFLICT (user_hash, idempotency_key) DO UPDATE
+             SET request_hash = EXCLUDED.request_hash, status = NULL, response = NULL, created_at = NOW()
+             WHERE idempotency_keys.created_at < $4
+                OR (idempotency_keys.status IS NULL AND idempotency_keys.created_at < $5)
+             RETURNING created_at",
+        )
+        .bind(user_hash)
+        .bind(key)
+        .bind(request_hash)
+        .bind(expired_before)
+        .bind(stale_before)
+        .fetch_optional(&self.pool)
+        .await
+    }
 
-        Ok(Some((asn_mapping, leases)))
+    /// Get the request made with an idempotency key of a user
+    #[instrument(name = "db", skip_all, fields(operation = "get_idempotent_request", user_hash = %user_hash))]
+    pub async fn get_idempotent_request(
+        &self,
+        user_hash: &UserHash,
+        key: &str,
+    ) -> Result<Option<IdempotentRequest>, sqlx::Error> {
+        self.retry_read(|| {
+            sqlx::query_as::<_, IdempotentRequest>(
+                "SELECT request_hash, status, response
+                 FROM idempotency_keys
+                 WHERE user_hash = $1 AND idempotency_key = $2",
+            )
+            .bind(user_hash)
+            .bind(key)
+            .fetch_optional(&self.pool)
+        })
+        .await
+    }
+
+    /// Store the response to the request that claimed an idempotency key at
+    /// `claimed_at`, returning `false` if another request took the claim over
+    #[instrument(name = "db", skip_all, fields(operation = "complete_idempotent_request", user_hash = %user_hash))]
+    pub async fn complete_idempotent_request(
+        &self,
+        user_hash: &UserHash,
+        key: &str,
+        claimed_at: DateTime<Utc>,
+        status: i16,
+        response: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let completed = sqlx::query(
+            "UPDATE idempotency_keys SET status = $4, response = $5
+             WHERE user_hash = $1 AND idempotency_key = $2 AND created_at = $3 AND status IS NULL",
+        )
+        .bind(user_hash)
+        .bind(key)
+        .bind(claimed_at)
+        .bind(status)
+        .bind(response)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(completed.rows_affected() > 0)
+    }
+
+    /// Free an idempotency key claimed at `claimed_at` whose request failed,
+    /// so it can be retried. Claims taken over by another request are kept.
+    #[instrument(name = "db", skip_all, fields(operation = "release_idempotency_key", user_hash = %user_hash))]
+    pub async fn release_idempotency_key(
+        &self,
+        user_hash: &UserHash,
+        key: &str,
+        claimed_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "DELETE FROM idempotency_keys
+             WHERE user_hash = $1 AND idempotency_key = $2 AND created_at = $3 AND status IS NULL",
+        )
+        .bind(user_hash)
+        .bind(key)
+        .bind(claimed_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
     }
 
-    /// Get all user mappings with their ASN and active leases (for downstream services)
-    pub async fn get_all_user_mappings(
+    /// Delete the idempotency keys created before a point in time
+    #[instrument(
+        name = "db",
+        skip_all,
+        fields(operation = "delete_idempotency_keys_before")
+    )]
+    pub async fn delete_idempotency_keys_before(
         &self,
-    ) -> Result<Vec<(UserAsnMapping, Vec<PrefixLease>)>, sqlx::Error> {
-        // Get all ASN mappings
-        let mappings = sqlx::query_as::<_, UserAsnMapping>(
-            "SELECT * FROM user_asn_mappings ORDER BY created_at DESC",
+        before: DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM idempotency_keys WHERE created_at < $1")
+            .bind(before)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Get pool usage snapshots recorded since a point in time, oldest first
+    #[instrument(name = "db", skip_all, fields(operation = "get_pool_usage_since"))]
+    pub async fn get_pool_usage_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<PoolUsageSnapshot>, sqlx::Error> {
+        let snapshots = sqlx::query_as::<_, PoolUsageSnapshot>(
+            "SELECT * FROM pool_usage_snapshots
+             WHERE recorded_at >= $1
+             ORDER BY recorded_at ASC",
         )
+        .bind(since)
         .fetch_all(&self.pool)
         .await?;
 
-        let mut result = Vec::new();
-        for mapping in mappings {
-            let leases = self.get_active_user_leases(&mapping.user_hash).await?;
-            result.push((mapping, leases));
-        }
+        Ok(snapshots)
+    }
+
+    /// Create a pool utilization alert threshold
+    #[instrument(name = "db", skip_all, fields(operation = "create_alert_threshold"))]
+    pub async fn create_alert_threshold(
+        &self,
+        pool: &str,
+        threshold: f64,
+        webhook_url: Option<&str>,
+        email: Option<&str>,
+    ) -> Result<AlertThreshold, sqlx::Error> {
+        let alert = sqlx::query_as::<_, AlertThreshold>(
+            "INSERT INTO alert_thresholds (pool, threshold, webhook_url, email)
+             VALUES ($1, $2, $3, $4)
+             RETURNING *",
+        )
+        .bind(pool)
+        .bind(threshold)
+        .bind(webhook_url)
+        .bind(email)
+        .fetch_one(&self.pool)
+        .await?;
 
-        Ok(result)
+        debug!(
+            "Created alert threshold {} for {} pool at {}",
+            alert.id, pool, threshold
+        );
+        Ok(alert)
+    }
+
+    /// Get all alert thresholds
+    #[instrument(name = "db", skip_all, fields(operation = "get_alert_thresholds"))]
+    pub async fn get_alert_thresholds(&self) -> Result<Vec<AlertThreshold>, sqlx::Error> {
+        let alerts = sqlx::query_as::<_, AlertThreshold>(
+            "SELECT * FROM alert_thresholds ORDER BY pool, threshold",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(alerts)
+    }
+
+    /// Mark an alert threshold as triggered (or resolved when `triggered` is false)
+    #[instrument(name = "db", skip_all, fields(operation = "set_alert_triggered"))]
+    pub async fn set_alert_triggered(&self, id: Uuid, triggered: bool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE alert_thresholds
+             SET triggered_at = CASE WHEN $2 THEN NOW() ELSE NULL END,
+                 notified_event = NULL, notified_channels = '{}', updated_at = NOW()
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(triggered)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record that a channel of an alert threshold was notified of `event`,
+    /// forgetting channels notified of another event
+    #[instrument(name = "db", skip_all, fields(operation = "record_alert_notified"))]
+    pub async fn record_alert_notified(
+        &self,
+        id: Uuid,
+        event: &str,
+        channel: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE alert_thresholds
+             SET notified_channels = CASE
+                     WHEN notified_event = $2 THEN array_append(notified_channels, $3)
+                     ELSE ARRAY[$3::TEXT]
+                 END,
+                 notified_event = $2, updated_at = NOW()
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(event)
+        .bind(channel)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Delete an alert threshold, returning whether it existed
+    #[instrument(name = "db", skip_all, fields(operation = "delete_alert_threshold"))]
+    pub async fn delete_alert_threshold(&self, id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM alert_thresholds WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Get user information with ASN and active leases
+    #[instrument(name = "db", skip_all, fields(operation = "get_user_info", user_hash = %user_hash))]
+    pub async fn get_user_info(
+        &self,
+        user_hash: &UserHash,
+    ) -> Result<Option<UserInfo>, sqlx::Error> {
+        let asn_mappings = self.get_user_asns(user_hash).await?;
+        let leases = self.get_active_user_leases(user_hash).await?;
+
+        Ok(Some((asn_mappings, leases)))
+    }
+
+    /// Get the annotations of mappings, by key within each mapping
+    #[instrument(name = "db", skip_all, fields(operation = "get_mapping_annotations", count = mapping_ids.len()))]
+    pub async fn get_mapping_annotations(
+        &self,
+        mapping_ids: &[Uuid],
+    ) -> Result<Vec<MappingAnnotation>, sqlx::Error> {
+        if mapping_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.retry_read(|| {
+            sqlx::query_as::<_, MappingAnnotation>(
+                "SELECT * FROM mapping_annotations WHERE mapping_id = ANY($1) ORDER BY mapping_id, key",
+            )
+            .bind(mapping_ids)
+            .fetch_all(&self.pool)
+        })
+        .await
+    }
+
+    /// Set an annotation of a user's mapping (of their first ASN), unless the
+    /// user has no ASN or the mapping already has `max` other annotations
+    #[instrument(name = "db", skip_all, fields(operation = "set_mapping_annotation", user_hash = %user_hash, key = %key))]
+    pub async fn set_mapping_annotation(
+        &self,
+        user_hash: &UserHash,
+        key: &str,
+        value: &str,
+        updated_by: &str,
+        max: i64,
+    ) -> Result<Option<MappingAnnotation>, sqlx::Error> {
+        sqlx::query_as::<_, MappingAnnotation>(
+            "INSERT INTO mapping_annotations (mapping_id, key, value, updated_by)
+             SELECT m.id, $2, $3, $4 FROM user_asn_mappings m
+             WHERE m.id = (SELECT id FROM user_asn_mappings WHERE user_hash = $1
+                           ORDER BY created_at, id LIMIT 1)
+               AND (EXISTS (SELECT 1 FROM mapping_annotations a WHERE a.mapping_id = m.id AND a.key = $2)
+                    OR (SELECT COUNT(*) FROM mapping_annotations a WHERE a.mapping_id = m.id) < $5)
+             ON CONFLICT (mapping_id, key) DO UPDATE
+             SET value = EXCLUDED.value, updated_by = EXCLUDED.updated_by, updated_at = NOW()
+             RETURNING *",
+        )
+        .bind(user_hash)
+        .bind(key)
+        .bind(value)
+        .bind(updated_by)
+        .bind(max)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Remove an annotation of a user's mapping, returning whether it existed
+    #[instrument(name = "db", skip_all, fields(operation = "delete_mapping_annotation", user_hash = %user_hash, key = %key))]
+    pub async fn delete_mapping_annotation(
+        &self,
+        user_hash: &UserHash,
+        key: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "DELETE FROM mapping_annotations a USING user_asn_mappings m
+             WHERE a.mapping_id = m.id AND m.user_hash = $1 AND a.key = $2",
+        )
+        .bind(user_hash)
+        .bind(key)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Register a webhook for a user
+    #[instrument(name = "db", skip_all, fields(operation = "create_webhook", user_hash = %user_hash))]
+    pub async fn create_webhook(
+        &self,
+        user_hash: &UserHash,
+        url: &str,
+        event_types: &[String],
+        secret: &str,
+    ) -> Result<Webhook, sqlx::Error> {
+        let webhook = sqlx::query_as::<_, Webhook>(
+            "INSERT INTO webhooks (user_hash, url, event_types, secret)
+             VALUES ($1, $2, $3, $4)
+             RETURNING *",
+        )
+        .bind(user_hash)
+        .bind(url)
+        .bind(event_types)
+        .bind(secret)
+        .fetch_one(&self.pool)
+        .await?;
+
+        debug!("Created webhook {} for user {}", webhook.id, user_hash);
+        Ok(webhook)
+    }
+
+    /// Get all webhooks registered by a user
+    #[instrument(name = "db", skip_all, fields(operation = "get_user_webhooks", user_hash = %user_hash))]
+    pub async fn get_user_webhooks(
+        &self,
+        user_hash: &UserHash,
+    ) -> Result<Vec<Webhook>, sqlx::Error> {
+        let webhooks = sqlx::query_as::<_, Webhook>(
+            "SELECT * FROM webhooks WHERE user_hash = $1 ORDER BY created_at",
+        )
+        .bind(user_hash)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(webhooks)
+    }
+
+    /// Get a single webhook owned by a user
+    #[instrument(name = "db", skip_all, fields(operation = "get_user_webhook", user_hash = %user_hash))]
+    pub async fn get_user_webhook(
+        &self,
+        user_hash: &UserHash,
+        id: Uuid,
+    ) -> Result<Option<Webhook>, sqlx::Error> {
+        let webhook =
+            sqlx::query_as::<_, Webhook>("SELECT * FROM webhooks WHERE user_hash = $1 AND id = $2")
+                .bind(user_hash)
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(webhook)
+    }
+
+    /// Get the user's webhooks subscribed to an event type (an empty subscription list means all events)
+    #[instrument(name = "db", skip_all, fields(operation = "get_webhooks_for_event", user_hash = %user_hash))]
+    pub async fn get_webhooks_for_event(
+        &self,
+        user_hash: &UserHash,
+        event_type: &str,
+    ) -> Result<Vec<Webhook>, sqlx::Error> {
+        let webhooks = sqlx::query_as::<_, Webhook>(
+            "SELECT * FROM webhooks
+             WHERE user_hash = $1 AND (cardinality(event_types) = 0 OR $2 = ANY(event_types))",
+        )
+        .bind(user_hash)
+        .bind(event_type)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(webhooks)
+    }
+
+    /// Replace the signing secret of a user's webhook
+    #[instrument(name = "db", skip_all, fields(operation = "rotate_webhook_secret", user_hash = %user_hash))]
+    pub async fn rotate_webhook_secret(
+        &self,
+        user_hash: &UserHash,
+        id: Uuid,
+        secret: &str,
+    ) -> Result<Option<Webhook>, sqlx::Error> {
+        let webhook = sqlx::query_as::<_, Webhook>(
+            "UPDATE webhooks SET secret = $3, updated_at = NOW()
+             WHERE user_hash = $1 AND id = $2
+             RETURNING *",
+        )
+        .bind(user_hash)
+        .bind(id)
+        .bind(secret)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(webhook)
+    }
+
+    /// Delete a user's webhook, returning whether it existed
+    #[instrument(name = "db", skip_all, fields(operation = "delete_webhook", user_hash = %user_hash))]
+    pub async fn delete_webhook(
+        &self,
+        user_hash: &UserHash,
+        id: Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM webhooks WHERE user_hash = $1 AND id = $2")
+            .bind(user_hash)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Log a new webhook delivery in the pending state
+    #[instrument(name = "db", skip_all, fields(operation = "create_webhook_delivery"))]
+    pub async fn create_webhook_delivery(
+        &self,
+        webhook_id: Uuid,
+        event_id: Uuid,
+        event_type: &str,
+        payload: &str,
+    ) -> Result<WebhookDelivery, sqlx::Error> {
+        let delivery = sqlx::query_as::<_, WebhookDelivery>(
+            "INSERT INTO webhook_deliveries (webhook_id, event_id, event_type, payload)
+             VALUES ($1, $2, $3, $4)
+             RETURNING *",
+        )
+        .bind(webhook_id)
+        .bind(event_id)
+        .bind(event_type)
+        .bind(payload)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(delivery)
+    }
+
+    /// Record the outcome of a delivery attempt
+    #[instrument(name = "db", skip_all, fields(operation = "record_webhook_attempt"))]
+    pub async fn record_webhook_attempt(
+        &self,
+        id: Uuid,
+        status: &str,
+        status_code: Option<i32>,
+        latency_ms: i32,
+        error: Option<&str>,
+    ) -> Result<WebhookDelivery, sqlx::Error> {
+        let delivery = sqlx::query_as::<_, WebhookDelivery>(
+            "UPDATE webhook_deliveries
+             SET status = $2, attempts = attempts + 1, last_status_code = $3,
+                 last_latency_ms = $4, last_error = $5, updated_at = NOW()
+             WHERE id = $1
+             RETURNING *",
+        )
+        .bind(id)
+        .bind(status)
+        .bind(status_code)
+        .bind(latency_ms)
+        .bind(error)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(delivery)
+    }
+
+    /// Get the most recent deliveries of a webhook
+    #[instrument(name = "db", skip_all, fields(operation = "get_webhook_deliveries"))]
+    pub async fn get_webhook_deliveries(
+        &self,
+        webhook_id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+        let deliveries = sqlx::query_as::<_, WebhookDelivery>(
+            "SELECT * FROM webhook_deliveries
+             WHERE webhook_id = $1
+             ORDER BY created_at DESC
+             LIMIT $2",
+        )
+        .bind(webhook_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(deliveries)
+    }
+
+    /// Get a single delivery of a webhook
+    #[instrument(name = "db", skip_all, fields(operation = "get_webhook_delivery"))]
+    pub async fn get_webhook_delivery(
+        &self,
+        webhook_id: Uuid,
+        id: Uuid,
+    ) -> Result<Option<WebhookDelivery>, sqlx::Error> {
+        let delivery = sqlx::query_as::<_, WebhookDelivery>(
+            "SELECT * FROM webhook_deliveries WHERE webhook_id = $1 AND id = $2",
+        )
+        .bind(webhook_id)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(delivery)
+    }
+
+    /// Record an impersonation grant
+    #[instrument(name = "db", skip_all, fields(operation = "create_impersonation", user_hash = %user_hash))]
+    pub async fn create_impersonation(
+        &self,
+        token_hash: &str,
+        identity: &str,
+        user_hash: &UserHash,
+        reason: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Impersonation, sqlx::Error> {
+        let impersonation = sqlx::query_as::<_, Impersonation>(
+            "INSERT INTO impersonations (token_hash, identity, user_hash, reason, expires_at)
+             VALUES ($1, $2, $3, $4, $5)
+             RETURNING *",
+        )
+        .bind(token_hash)
+        .bind(identity)
+        .bind(user_hash)
+        .bind(reason)
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(impersonation)
+    }
+
+    /// Get all impersonation grants, newest first
+    #[instrument(name = "db", skip_all, fields(operation = "get_impersonations"))]
+    pub async fn get_impersonations(&self) -> Result<Vec<Impersonation>, sqlx::Error> {
+        let impersonations = sqlx::query_as::<_, Impersonation>(
+            "SELECT * FROM impersonations ORDER BY created_at DESC",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(impersonations)
+    }
+
+    /// Look up a live impersonation grant, recording its use
+    #[instrument(name = "db", skip_all, fields(operation = "use_impersonation"))]
+    pub async fn use_impersonation(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<Impersonation>, sqlx::Error> {
+        let impersonation = sqlx::query_as::<_, Impersonation>(
+            "UPDATE impersonations
+             SET last_used_at = NOW(), use_count = use_count + 1
+             WHERE token_hash = $1 AND expires_at > NOW() AND revoked_at IS NULL
+             RETURNING *",
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(impersonation)
+    }
+
+    /// Revoke an impersonation grant before it expires
+    #[instrument(name = "db", skip_all, fields(operation = "revoke_impersonation"))]
+    pub async fn revoke_impersonation(&self, id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE impersonations SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Store a browser session under the hash of its id
+    #[instrument(name = "db", skip_all, fields(operation = "create_session"))]
+    pub async fn create_session(
+        &self,
+        id_hash: &str,
+        auth_info: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO sessions (id, auth_info, expires_at) VALUES ($1, $2, $3)")
+            .bind(id_hash)
+            .bind(auth_info)
+            .bind(expires_at)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Get the serialized identity of an unexpired session
+    #[instrument(name = "db", skip_all, fields(operation = "get_session"))]
+    pub async fn get_session(&self, id_hash: &str) -> Result<Option<String>, sqlx::Error> {
+        let auth_info: Option<String> = sqlx::query_scalar(
+            "SELECT auth_info FROM sessions WHERE id = $1 AND expires_at > NOW()",
+        )
+        .bind(id_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(auth_info)
+    }
+
+    #[instrument(name = "db", skip_all, fields(operation = "delete_session"))]
+    pub async fn delete_session(&self, id_hash: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM sessions WHERE id = $1")
+            .bind(id_hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Remove sessions past their expiry
+    #[instrument(name = "db", skip_all, fields(operation = "delete_expired_sessions"))]
+    pub async fn delete_expired_sessions(&self) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM sessions WHERE expires_at <= NOW()")
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    #[instrument(name = "db", skip_all, fields(operation = "get_user_profile"))]
+    pub async fn get_user_profile(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<UserProfile>, sqlx::Error> {
+        sqlx::query_as::<_, UserProfile>(
+            "SELECT user_id, email, validated_at FROM user_profiles WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Store a profile freshly validated against the IdP
+    #[instrument(name = "db", skip_all, fields(operation = "upsert_user_profile"))]
+    pub async fn upsert_user_profile(
+        &self,
+        user_id: &str,
+        email: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO user_profiles (user_id, email, validated_at) VALUES ($1, $2, NOW())
+             ON CONFLICT (user_id) DO UPDATE SET email = EXCLUDED.email, validated_at = NOW()",
+        )
+        .bind(user_id)
+        .bind(email)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[instrument(name = "db", skip_all, fields(operation = "delete_user_profile"))]
+    pub async fn delete_user_profile(&self, user_id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM user_profiles WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Remove profiles of users that no longer have an ASN mapping
+    #[instrument(
+        name = "db",
+        skip_all,
+        fields(operation = "delete_orphaned_user_profiles")
+    )]
+    pub async fn delete_orphaned_user_profiles(&self) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            "DELETE FROM user_profiles p
+             WHERE NOT EXISTS (SELECT 1 FROM user_asn_mappings m WHERE m.user_id = p.user_id)",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Register an external prefix pending verification
+    #[instrument(name = "db", skip_all, fields(operation = "create_external_prefix", user_hash = %user_hash, prefix = %prefix))]
+    pub async fn create_external_prefix(
+        &self,
+        user_hash: &UserHash,
+        prefix: &Prefix,
+        token: &str,
+    ) -> Result<ExternalPrefix, sqlx::Error> {
+        sqlx::query_as::<_, ExternalPrefix>(
+            "INSERT INTO external_prefixes (user_hash, prefix, token)
+             VALUES ($1, $2, $3)
+             RETURNING *",
+        )
+        .bind(user_hash)
+        .bind(prefix.to_string())
+        .bind(token)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Get all external prefixes registered by a user
+    #[instrument(name = "db", skip_all, fields(operation = "get_user_external_prefixes", user_hash = %user_hash))]
+    pub async fn get_user_external_prefixes(
+        &self,
+        user_hash: &UserHash,
+    ) -> Result<Vec<ExternalPrefix>, sqlx::Error> {
+        sqlx::query_as::<_, ExternalPrefix>(
+            "SELECT * FROM external_prefixes WHERE user_hash = $1 ORDER BY created_at",
+        )
+        .bind(user_hash)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Get a single external prefix registered by a user
+    #[instrument(name = "db", skip_all, fields(operation = "get_user_external_prefix", user_hash = %user_hash))]
+    pub async fn get_user_external_prefix(
+        &self,
+        user_hash: &UserHash,
+        id: Uuid,
+    ) -> Result<Option<ExternalPrefix>, sqlx::Error> {
+        sqlx::query_as::<_, ExternalPrefix>(
+            "SELECT * FROM external_prefixes WHERE user_hash = $1 AND id = $2",
+        )
+        .bind(user_hash)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Mark an external prefix as verified through `method`
+    #[instrument(name = "db", skip_all, fields(operation = "verify_external_prefix"))]
+    pub async fn verify_external_prefix(
+        &self,
+        id: Uuid,
+        method: &str,
+    ) -> Result<ExternalPrefix, sqlx::Error> {
+        sqlx::query_as::<_, ExternalPrefix>(
+            "UPDATE external_prefixes
+             SET status = 'verified', verification_method = $2, verified_at = NOW(), updated_at = NOW()
+             WHERE id = $1
+             RETURNING *",
+        )
+        .bind(id)
+        .bind(method)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Record the route origin validation state of an external prefix
+    #[instrument(
+        name = "db",
+        skip_all,
+        fields(operation = "set_external_prefix_rpki_state")
+    )]
+    pub async fn set_external_prefix_rpki_state(
+        &self,
+        id: Uuid,
+        rpki_state: &str,
+    ) -> Result<ExternalPrefix, sqlx::Error> {
+        sqlx::query_as::<_, ExternalPrefix>(
+            "UPDATE external_prefixes
+             SET rpki_state = $2, rpki_checked_at = NOW(), updated_at = NOW()
+             WHERE id = $1
+             RETURNING *",
+        )
+        .bind(id)
+        .bind(rpki_state)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Delete a user's external prefix, returning it if it existed
+    #[instrument(name = "db", skip_all, fields(operation = "delete_external_prefix", user_hash = %user_hash))]
+    pub async fn delete_external_prefix(
+        &self,
+        user_hash: &UserHash,
+        id: Uuid,
+    ) -> Result<Option<ExternalPrefix>, sqlx::Error> {
+        sqlx::query_as::<_, ExternalPrefix>(
+            "DELETE FROM external_prefixes WHERE user_hash = $1 AND id = $2 RETURNING *",
+        )
+        .bind(user_hash)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Get every verified external prefix, for exports
+    #[instrument(
+        name = "db",
+        skip_all,
+        fields(operation = "get_verified_external_prefixes")
+    )]
+    pub async fn get_verified_external_prefixes(&self) -> Result<Vec<ExternalPrefix>, sqlx::Error> {
+        sqlx::query_as::<_, ExternalPrefix>(
+            "SELECT * FROM external_prefixes WHERE status = 'verified' ORDER BY prefix",
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Get the profiles validated longest ago, up to `limit`, validated before `before`
+    #[instrument(name = "db", skip_all, fields(operation = "get_stale_user_profiles"))]
+    pub async fn get_stale_user_profiles(
+        &self,
+        before: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<UserProfile>, sqlx::Error> {
+        sqlx::query_as::<_, UserProfile>(
+            "SELECT user_id, email, validated_at FROM user_profiles
+             WHERE validated_at < $1 ORDER BY validated_at LIMIT $2",
+        )
+        .bind(before)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Get the active leases overlapping a prefix
+    #[instrument(
+        name = "db",
+        skip_all,
+        fields(operation = "get_active_leases_overlapping")
+    )]
+    pub async fn get_active_leases_overlapping(
+        &self,
+        prefix: &Prefix,
+    ) -> Result<Vec<PrefixLease>, sqlx::Error> {
+        sqlx::query_as::<_, PrefixLease>(
+            "SELECT id, user_hash, prefix::text, start_time, end_time, created_at, updated_at, sites, renew_minutes, label, purpose, roa_max_length
+             FROM prefix_leases
+             WHERE start_time <= NOW() AND end_time > NOW() AND prefix && $1::cidr
+             ORDER BY prefix",
+        )
+        .bind(prefix.to_string())
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Get the verified external prefixes overlapping a prefix
+    #[instrument(
+        name = "db",
+        skip_all,
+        fields(operation = "get_verified_external_prefixes_overlapping")
+    )]
+    pub async fn get_verified_external_prefixes_overlapping(
+        &self,
+        prefix: &Prefix,
+    ) -> Result<Vec<ExternalPrefix>, sqlx::Error> {
+        sqlx::query_as::<_, ExternalPrefix>(
+            "SELECT * FROM external_prefixes
+             WHERE status = 'verified' AND prefix::inet && $1::inet
+             ORDER BY prefix",
+        )
+        .bind(prefix.to_string())
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Open an incident
+    #[instrument(name = "db", skip_all, fields(operation = "create_incident", source = %incident.source))]
+    pub async fn create_incident(
+        &self,
+        incident: &NewIncident<'_>,
+    ) -> Result<Incident, sqlx::Error> {
+        sqlx::query_as::<_, Incident>(
+            "INSERT INTO incidents (user_hash, asn, lease_id, prefix, source, description, reporter_contact)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             RETURNING *",
+        )
+        .bind(incident.user_hash)
+        .bind(incident.asn)
+        .bind(incident.lease_id)
+        .bind(incident.prefix)
+        .bind(incident.source)
+        .bind(incident.description)
+        .bind(incident.reporter_contact)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Get incidents, newest first, optionally of a user and with a status
+    #[instrument(name = "db", skip_all, fields(operation = "get_incidents"))]
+    pub async fn get_incidents(
+        &self,
+        user_hash: Option<&str>,
+        status: Option<&str>,
+    ) -> Result<Vec<Incident>, sqlx::Error> {
+        sqlx::query_as::<_, Incident>(
+            "SELECT * FROM incidents
+             WHERE ($1::text IS NULL OR user_hash = $1)
+               AND ($2::text IS NULL OR status = $2)
+             ORDER BY created_at DESC",
+        )
+        .bind(user_hash)
+        .bind(status)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Get an incident by ID
+    #[instrument(name = "db", skip_all, fields(operation = "get_incident"))]
+    pub async fn get_incident(&self, id: Uuid) -> Result<Option<Incident>, sqlx::Error> {
+        sqlx::query_as::<_, Incident>("SELECT * FROM incidents WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Count the open and total incidents of a user
+    #[instrument(name = "db", skip_all, fields(operation = "count_user_incidents"))]
+    pub async fn count_user_incidents(
+        &self,
+        user_hash: &UserHash,
+    ) -> Result<(i64, i64), sqlx::Error> {
+        sqlx::query_as::<_, (i64, i64)>(
+            "SELECT COUNT(*) FILTER (WHERE status = 'open'), COUNT(*)
+             FROM incidents WHERE user_hash = $1",
+        )
+        .bind(user_hash)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Comment on an incident, returning `None` if it doesn't exist
+    #[instrument(name = "db", skip_all, fields(operation = "add_incident_comment"))]
+    pub async fn add_incident_comment(
+        &self,
+        incident_id: Uuid,
+        author: Option<&str>,
+        body: &str,
+    ) -> Result<Option<IncidentComment>, sqlx::Error> {
+        // The statements can't borrow from the caller
+        let (author, body) = (author.map(str::to_string), body.to_string());
+        self.transaction(|tx| {
+            Box::pin(async move {
+                let updated = sqlx::query("UPDATE incidents SET updated_at = NOW() WHERE id = $1")
+                    .bind(incident_id)
+                    .execute(&mut **tx)
+                    .await?;
+                if updated.rows_affected() == 0 {
+                    return Ok(None);
+                }
+                let comment = sqlx::query_as::<_, IncidentComment>(
+                    "INSERT INTO incident_comments (incident_id, author, body)
+                     VALUES ($1, $2, $3)
+                     RETURNING *",
+                )
+                .bind(incident_id)
+                .bind(author)
+                .bind(body)
+                .fetch_one(&mut **tx)
+                .await?;
+                Ok(Some(comment))
+            })
+        })
+        .await
+    }
+
+    /// Get the comments of an incident, oldest first
+    #[instrument(name = "db", skip_all, fields(operation = "get_incident_comments"))]
+    pub async fn get_incident_comments(
+        &self,
+        incident_id: Uuid,
+    ) -> Result<Vec<IncidentComment>, sqlx::Error> {
+        sqlx::query_as::<_, IncidentComment>(
+            "SELECT * FROM incident_comments WHERE incident_id = $1 ORDER BY created_at",
+        )
+        .bind(incident_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Close an open incident, returning `None` if it isn't open
+    #[instrument(name = "db", skip_all, fields(operation = "close_incident"))]
+    pub async fn close_incident(
+        &self,
+        id: Uuid,
+        resolution: Option<&str>,
+    ) -> Result<Option<Incident>, sqlx::Error> {
+        sqlx::query_as::<_, Incident>(
+            "UPDATE incidents
+             SET status = 'closed', resolution = $2, closed_at = NOW(), updated_at = NOW()
+             WHERE id = $1 AND status = 'open'
+             RETURNING *",
+        )
+        .bind(id)
+        .bind(resolution)
+        .fetch_optional(&self.pool)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapping_sort_parsing() {
+        assert_eq!("updated_at".parse(), Ok(MappingSort::UpdatedAt));
+        assert_eq!("asc".parse(), Ok(SortOrder::Asc));
+        assert!("user_hash".parse::<MappingSort>().is_err());
+        assert!("up".parse::<SortOrder>().is_err());
+    }
+
+    fn row(user: char, source: &str, id: u128, asn: Option<i64>, minute: u32) -> MappingPermission {
+        let at = DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+            + chrono::Duration::minutes(minute.into());
+        MappingPermission {
+            user_hash: user.to_string().repeat(64).parse().unwrap(),
+            source: source.to_string(),
+            source_id: Uuid::from_u128(id),
+            prefix: (source == PERMISSION_SOURCE_LEASE).then(|| format!("2001:db8:{}::/48", id)),
+            origin_asn: asn,
+            asn_assigned_at: Some(at),
+            holder_id: None,
+            description: None,
+            abuse_contact: None,
+            label: None,
+            purpose: None,
+            sites: None,
+            valid_to: Some(at),
+            updated_at: at,
+        }
+    }
+
+    #[test]
+    fn test_mapping_grouping() {
+        let rows = vec![
+            row('a', PERMISSION_SOURCE_ASN, 1, Some(65002), 10),
+            row('a', PERMISSION_SOURCE_LEASE, 2, Some(65002), 10),
+            row('a', PERMISSION_SOURCE_ASN, 3, Some(65000), 20),
+            row('a', PERMISSION_SOURCE_LEASE, 2, Some(65000), 20),
+            row('b', PERMISSION_SOURCE_ASN, 4, Some(65001), 5),
+            // Leases of users without an ASN are not listed
+            row('c', PERMISSION_SOURCE_LEASE, 5, None, 0),
+        ];
+
+        let users = MappingFilter::group(rows);
+        assert_eq!(users.len(), 2);
+        // Users in the order of their rows, with every ASN and each lease once
+        assert_eq!(users[0].asns[0].origin_asn, Some(65002));
+        assert_eq!(users[0].asns.len(), 2);
+        assert_eq!(users[0].leases.len(), 1);
+        assert_eq!(users[1].asns[0].origin_asn, Some(65001));
+        assert!(users[1].leases.is_empty());
+    }
+
+    #[test]
+    fn test_lease_conflict_detection() {
+        assert!(!is_lease_conflict(&sqlx::Error::RowNotFound));
+        assert!(!is_lease_conflict(&sqlx::Error::PoolTimedOut));
+    }
+
+    #[test]
+    fn test_connection_error_detection() {
+        assert!(is_connection_error(&sqlx::Error::PoolTimedOut));
+        assert!(is_connection_error(&sqlx::Error::Io(std::io::Error::from(
+            std::io::ErrorKind::ConnectionReset
+        ))));
+        assert!(!is_connection_error(&sqlx::Error::RowNotFound));
     }
-}
 
-#[cfg(test)]
-mod tests {
     #[tokio::test]
     async fn test_database_operations() {
         // This is a placeholder for integration tests
@@ -0,0 +1,246 @@
+//! Advance warning of breaking API changes.
+//!
+//! Endpoints on their way out are listed in [`REGISTRY`]. Responses of a
+//! listed endpoint carry a `Deprecation` header (RFC 9745) with the date it
+//! was deprecated, a `Sunset` header (RFC 8594) with the date it is removed
+//! when one is planned, and `Link` headers to its successor and to
+//! `/api/deprecations`, which lists the registry so agent operators can
+//! check for upcoming changes before they break.
+
+use axum::{
+    Json,
+    extract::{Request, State},
+    http::{HeaderValue, header},
+    middleware::Next,
+    response::Response,
+};
+use chrono::NaiveDate;
+use serde_json::{Value, json};
+use tracing::warn;
+
+/// Path of the listing, linked from every deprecated response
+pub const LISTING_PATH: &str = "/api/deprecations";
+
+/// Deprecated endpoints, oldest first. Add an entry when an endpoint gets a
+/// replacement, and remove it once the endpoint is gone.
+pub const REGISTRY: &[Deprecation] = &[];
+
+/// An endpoint on its way out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deprecation {
+    /// Method of the endpoint, or every method when unset
+    pub method: Option<&'static str>,
+    /// Path of the endpoint, with `{name}` matching any segment
+    /// (e.g. `/service/mappings/{user_hash}`)
+    pub path: &'static str,
+    /// When the endpoint was deprecated
+    pub deprecated: NaiveDate,
+    /// When the endpoint is removed, if planned
+    pub sunset: Option<NaiveDate>,
+    /// Path of the endpoint replacing it
+    pub successor: Option<&'static str>,
+    /// What changes, for operators
+    pub note: &'static str,
+}
+
+/// Date of a registry entry, checked at compile time
+pub const fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+    match NaiveDate::from_ymd_opt(year, month, day) {
+        Some(date) => date,
+        None => panic!("invalid date"),
+    }
+}
+
+impl Deprecation {
+    /// Whether a request is one of the endpoint
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        if self.method.is_some_and(|m| !m.eq_ignore_ascii_case(method)) {
+            return false;
+        }
+        let mut pattern = self.path.split('/');
+        let mut segments = path.trim_end_matches('/').split('/');
+        loop {
+            match (pattern.next(), segments.next()) {
+                (None, None) => return true,
+                (Some(p), Some(s)) if p.starts_with('{') && p.ends_with('}') && !s.is_empty() => {}
+                (Some(p), Some(s)) if p == s => {}
+                _ => return false,
+            }
+        }
+    }
+
+    /// `Deprecation` header value: the date as a Unix timestamp
+    fn deprecation_header(&self) -> String {
+        format!(
+            "@{}",
+            self.deprecated
+                .and_time(Default::default())
+                .and_utc()
+                .timestamp()
+        )
+    }
+
+    /// `Sunset` header value: the date as an HTTP date
+    fn sunset_header(&self) -> Option<String> {
+        self.sunset.map(|sunset| {
+            sunset
+                .and_time(Default::default())
+                .and_utc()
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string()
+        })
+    }
+
+    fn to_json(self) -> Value {
+        json!({
+            "method": self.method,
+            "path": self.path,
+            "deprecated": self.deprecated,
+            "sunset": self.sunset,
+            "successor": self.successor,
+            "note": self.note,
+        })
+    }
+}
+
+/// Add the deprecation headers to responses of the endpoints of `registry`
+pub async fn add_headers(
+    State(registry): State<&'static [Deprecation]>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().as_str().to_string();
+    let path = request.uri().path().to_string();
+    let mut response = next.run(request).await;
+    let Some(deprecation) = registry.iter().find(|d| d.matches(&method, &path)) else {
+        return response;
+    };
+
+    let mut links = vec![format!("<{}>; rel=\"deprecation\"", LISTING_PATH)];
+    links.extend(
+        deprecation
+            .successor
+            .map(|successor| format!("<{}>; rel=\"successor-version\"", successor)),
+    );
+    let headers = response.headers_mut();
+    let values = [
+        (
+            header::HeaderName::from_static("deprecation"),
+            Some(deprecation.deprecation_header()),
+        ),
+        (
+            header::HeaderName::from_static("sunset"),
+            deprecation.sunset_header(),
+        ),
+        (header::LINK, Some(links.join(", "))),
+    ];
+    for (name, value) in values {
+        let Some(value) = value else { continue };
+        match HeaderValue::from_str(&value) {
+            Ok(value) => {
+                headers.append(name, value);
+            }
+            Err(err) => warn!("Invalid {} header for {}: {}", name, deprecation.path, err),
+        }
+    }
+    response
+}
+
+/// List the deprecated endpoints, soonest removed first
+pub async fn list_deprecations() -> Json<Value> {
+    Json(listing(REGISTRY))
+}
+
+fn listing(registry: &[Deprecation]) -> Value {
+    let mut deprecations = registry.to_vec();
+    deprecations.sort_by_key(|d| (d.sunset.is_none(), d.sunset, d.deprecated));
+    json!({
+        "deprecations": deprecations.into_iter().map(Deprecation::to_json).collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, body::Body, routing::get};
+    use tower::ServiceExt;
+
+    const TEST_REGISTRY: &[Deprecation] = &[
+        Deprecation {
+            method: Some("GET"),
+            path: "/service/mappings/{user_hash}",
+            deprecated: date(2026, 1, 1),
+            sunset: Some(date(2026, 7, 1)),
+            successor: Some("/service/v2/mappings/{user_hash}"),
+            note: "Mappings move to the v2 API",
+        },
+        Deprecation {
+            method: None,
+            path: "/api/user/asn",
+            deprecated: date(2026, 2, 1),
+            sunset: None,
+            successor: None,
+            note: "Use /api/user/asns",
+        },
+    ];
+
+    #[test]
+    fn test_matches() {
+        let [mappings, asn] = TEST_REGISTRY else {
+            unreachable!()
+        };
+        assert!(mappings.matches("GET", "/service/mappings/abc"));
+        assert!(mappings.matches("get", "/service/mappings/abc/"));
+        assert!(!mappings.matches("PUT", "/service/mappings/abc"));
+        assert!(!mappings.matches("GET", "/service/mappings"));
+        assert!(!mappings.matches("GET", "/service/mappings//"));
+        assert!(!mappings.matches("GET", "/service/mappings/abc/annotations"));
+        assert!(asn.matches("DELETE", "/api/user/asn"));
+        assert!(!asn.matches("GET", "/api/user/asns"));
+    }
+
+    #[tokio::test]
+    async fn test_deprecated_responses_get_headers() {
+        let router = Router::new()
+            .route("/service/mappings/{user_hash}", get(|| async { "mapping" }))
+            .route("/service/mappings", get(|| async { "mappings" }))
+            .layer(axum::middleware::from_fn_with_state(
+                TEST_REGISTRY,
+                add_headers,
+            ));
+        let call = |path: &'static str| {
+            let router = router.clone();
+            async move {
+                router
+                    .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let response = call("/service/mappings/abc").await;
+        let headers = response.headers();
+        assert_eq!(headers["deprecation"], "@1767225600");
+        assert_eq!(headers["sunset"], "Wed, 01 Jul 2026 00:00:00 GMT");
+        assert_eq!(
+            headers[header::LINK],
+            "</api/deprecations>; rel=\"deprecation\", \
+             </service/v2/mappings/{user_hash}>; rel=\"successor-version\""
+        );
+
+        let response = call("/service/mappings").await;
+        assert!(response.headers().get("deprecation").is_none());
+    }
+
+    #[test]
+    fn test_listing() {
+        let listing = listing(TEST_REGISTRY);
+        let deprecations = listing["deprecations"].as_array().unwrap();
+        assert_eq!(deprecations.len(), 2);
+        assert_eq!(deprecations[0]["path"], "/service/mappings/{user_hash}");
+        assert_eq!(deprecations[0]["deprecated"], "2026-01-01");
+        assert_eq!(deprecations[0]["sunset"], "2026-07-01");
+        assert_eq!(deprecations[1]["method"], Value::Null);
+        assert_eq!(deprecations[1]["sunset"], Value::Null);
+    }
+}
@@ -0,0 +1,96 @@
+//! Lease durations.
+//!
+//! Clients give the duration of a lease in whole hours, in minutes, or as an
+//! ISO 8601 duration such as `PT30M` or `P1DT12H`. Leases are kept to the
+//! minute, so durations are whole numbers of minutes.
+
+use chrono::Duration;
+
+use crate::messages;
+
+/// Parse an ISO 8601 duration of weeks, days, hours, minutes and seconds.
+/// Years and months are refused since their length varies.
+pub fn parse_iso8601(s: &str) -> Result<Duration, String> {
+    let invalid = || messages::LEASE_INVALID_DURATION.text(&[&s]);
+    let rest = s.strip_prefix('P').ok_or_else(invalid)?;
+    if rest.is_empty() || rest == "T" || rest.ends_with('T') {
+        return Err(invalid());
+    }
+
+    let mut total = Duration::zero();
+    let mut in_time = false;
+    let mut number = String::new();
+    // Designators must come in order, each at most once
+    let mut last = 0;
+    for c in rest.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        if c == 'T' && !in_time && number.is_empty() {
+            in_time = true;
+            continue;
+        }
+        let (rank, unit) = match (in_time, c) {
+            (false, 'W') => (1, Duration::weeks(1)),
+            (false, 'D') => (2, Duration::days(1)),
+            (true, 'H') => (3, Duration::hours(1)),
+            (true, 'M') => (4, Duration::minutes(1)),
+            (true, 'S') => (5, Duration::seconds(1)),
+            _ => return Err(invalid()),
+        };
+        if rank <= last || number.is_empty() {
+            return Err(invalid());
+        }
+        last = rank;
+        let value: i32 = number.parse().map_err(|_| invalid())?;
+        total = unit
+            .checked_mul(value)
+            .and_then(|value| total.checked_add(&value))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+    Ok(total)
+}
+
+/// Format a duration for messages, e.g. `15 minutes` or `24 hours`
+pub fn describe(duration: Duration) -> String {
+    let minutes = duration.num_minutes();
+    match (minutes / 60, minutes % 60) {
+        (1, 0) => "1 hour".to_string(),
+        (hours, 0) => format!("{} hours", hours),
+        _ if minutes == 1 => "1 minute".to_string(),
+        _ => format!("{} minutes", minutes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_iso8601() {
+        assert_eq!(parse_iso8601("PT15M"), Ok(Duration::minutes(15)));
+        assert_eq!(parse_iso8601("PT1H30M"), Ok(Duration::minutes(90)));
+        assert_eq!(parse_iso8601("P1DT12H"), Ok(Duration::hours(36)));
+        assert_eq!(parse_iso8601("P1W"), Ok(Duration::weeks(1)));
+        assert_eq!(parse_iso8601("PT90S"), Ok(Duration::seconds(90)));
+        for invalid in [
+            "", "P", "PT", "P1DT", "PT15", "P1M", "P1Y", "PT1M1H", "PTM", "15M",
+        ] {
+            assert!(parse_iso8601(invalid).is_err(), "{}", invalid);
+        }
+        assert!(parse_iso8601("P99999999999W").is_err());
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(describe(Duration::minutes(15)), "15 minutes");
+        assert_eq!(describe(Duration::minutes(90)), "90 minutes");
+        assert_eq!(describe(Duration::hours(1)), "1 hour");
+        assert_eq!(describe(Duration::hours(24)), "24 hours");
+    }
+}
@@ -0,0 +1,279 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, header},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+/// Media type of protobuf-encoded service payloads
+pub const PROTOBUF: &str = "application/x-protobuf";
+
+/// Encoding of a service API payload, negotiated from the `Accept` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    /// Messages of `proto/service.proto`
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+}
+
+impl Encoding {
+    /// Protobuf when the client accepts it, JSON otherwise
+    pub fn negotiate(headers: &HeaderMap) -> Self {
+        #[cfg(feature = "protobuf")]
+        if headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|media_type| media_type.split(';').next().unwrap_or("").trim() == PROTOBUF)
+        {
+            return Self::Protobuf;
+        }
+        #[cfg(not(feature = "protobuf"))]
+        let _ = headers;
+        Self::Json
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            #[cfg(feature = "protobuf")]
+            Self::Protobuf => PROTOBUF,
+        }
+    }
+
+    /// Encode a payload
+    pub fn encode<T: Payload>(&self, payload: &T) -> Bytes {
+        match self {
+            Self::Json => Bytes::from(serde_json::to_vec(payload).unwrap_or_default()),
+            #[cfg(feature = "protobuf")]
+            Self::Protobuf => Bytes::from(prost::Message::encode_to_vec(&payload.to_message())),
+        }
+    }
+
+    /// Encode a payload into a response
+    pub fn respond<T: Payload>(&self, payload: &T) -> Response {
+        (
+            [(header::CONTENT_TYPE, self.content_type())],
+            self.encode(payload),
+        )
+            .into_response()
+    }
+}
+
+/// A service API payload, encodable as JSON or protobuf
+pub trait Payload: Serialize {
+    #[cfg(feature = "protobuf")]
+    type Message: prost::Message;
+
+    #[cfg(feature = "protobuf")]
+    fn to_message(&self) -> Self::Message;
+}
+
+/// Messages of `proto/service.proto`
+#[cfg(feature = "protobuf")]
+pub mod proto {
+    use std::collections::BTreeMap;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct UserMapping {
+        #[prost(string, tag = "1")]
+        pub user_hash: String,
+        #[prost(string, tag = "2")]
+        pub user_id: String,
+        #[prost(string, optional, tag = "3")]
+        pub email: Option<String>,
+        #[prost(int64, tag = "4")]
+        pub asn: i64,
+        #[prost(string, repeated, tag = "5")]
+        pub prefixes: Vec<String>,
+        #[prost(string, tag = "6")]
+        pub created_at: String,
+        #[prost(string, tag = "7")]
+        pub updated_at: String,
+        #[prost(int64, tag = "8")]
+        pub age_seconds: i64,
+        #[prost(string, tag = "9")]
+        pub last_changed_at: String,
+        #[prost(btree_map = "string, message", tag = "10")]
+        pub labels: BTreeMap<String, LeaseLabels>,
+        #[prost(btree_map = "string, string", tag = "11")]
+        pub annotations: BTreeMap<String, String>,
+        #[prost(int64, repeated, tag = "12")]
+        pub asns: Vec<i64>,
+        #[prost(btree_map = "string, message", tag = "13")]
+        pub asn_contacts: BTreeMap<String, AsnContact>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AsnContact {
+        #[prost(string, optional, tag = "1")]
+        pub description: Option<String>,
+        #[prost(string, optional, tag = "2")]
+        pub abuse_contact: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LeaseLabels {
+        #[prost(string, optional, tag = "1")]
+        pub label: Option<String>,
+        #[prost(string, optional, tag = "2")]
+        pub purpose: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Mappings {
+        #[prost(string, optional, tag = "1")]
+        pub site: Option<String>,
+        #[prost(message, repeated, tag = "2")]
+        pub mappings: Vec<UserMapping>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PrefixOwner {
+        #[prost(int64, tag = "1")]
+        pub asn: i64,
+        #[prost(string, tag = "2")]
+        pub user_hash: String,
+        #[prost(int64, repeated, tag = "3")]
+        pub asns: Vec<i64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PrefixIndex {
+        #[prost(btree_map = "string, message", tag = "1")]
+        pub prefixes: BTreeMap<String, PrefixOwner>,
+    }
+}
+
+impl Payload for crate::UserMappingResponse {
+    #[cfg(feature = "protobuf")]
+    type Message = proto::UserMapping;
+
+    #[cfg(feature = "protobuf")]
+    fn to_message(&self) -> Self::Message {
+        proto::UserMapping {
+            user_hash: self.user_hash.clone(),
+            user_id: self.user_id.clone(),
+            email: self.email.clone(),
+            asn: self.asn,
+            asns: self.asns.clone(),
+            prefixes: self.prefixes.clone(),
+            created_at: self.created_at.clone(),
+            updated_at: self.updated_at.clone(),
+            age_seconds: self.age_seconds,
+            last_changed_at: self.last_changed_at.clone(),
+            labels: self
+                .labels
+                .iter()
+                .map(|(prefix, labels)| {
+                    (
+                        prefix.clone(),
+                        proto::LeaseLabels {
+                            label: labels.label.clone(),
+                            purpose: labels.purpose.clone(),
+                        },
+                    )
+                })
+                .collect(),
+            annotations: self.annotations.clone(),
+            asn_contacts: self
+                .asn_contacts
+                .iter()
+                .map(|(asn, contact)| {
+                    (
+                        asn.clone(),
+                        proto::AsnContact {
+                            description: contact.description.clone(),
+                            abuse_contact: contact.abuse_contact.clone(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Payload for crate::AllMappingsResponse {
+    #[cfg(feature = "protobuf")]
+    type Message = proto::Mappings;
+
+    #[cfg(feature = "protobuf")]
+    fn to_message(&self) -> Self::Message {
+        proto::Mappings {
+            site: self.site.clone(),
+            mappings: self.mappings.iter().map(Payload::to_message).collect(),
+        }
+    }
+}
+
+impl Payload for crate::export::PrefixIndex {
+    #[cfg(feature = "protobuf")]
+    type Message = proto::PrefixIndex;
+
+    #[cfg(feature = "protobuf")]
+    fn to_message(&self) -> Self::Message {
+        proto::PrefixIndex {
+            prefixes: self
+                .iter()
+                .map(|(prefix, owner)| {
+                    (
+                        prefix.clone(),
+                        proto::PrefixOwner {
+                            asn: owner.asn,
+                            user_hash: owner.user_hash.clone(),
+                            asns: owner.asns.clone(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_negotiate_defaults_to_json() {
+        assert_eq!(Encoding::negotiate(&HeaderMap::new()), Encoding::Json);
+        assert_eq!(
+            Encoding::negotiate(&accept("application/json")),
+            Encoding::Json
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "protobuf")]
+    fn test_protobuf_round_trip() {
+        assert_eq!(
+            Encoding::negotiate(&accept(
+                "application/x-protobuf;q=1, application/json;q=0.5"
+            )),
+            Encoding::Protobuf
+        );
+
+        let mut index = crate::export::PrefixIndex::new();
+        index.insert(
+            "2001:db8:1::/48".to_string(),
+            crate::export::PrefixOwner {
+                asn: 65001,
+                asns: vec![65001],
+                user_hash: "alice".to_string(),
+            },
+        );
+        let body = Encoding::Protobuf.encode(&index);
+        assert!(body.len() < Encoding::Json.encode(&index).len());
+        let decoded = <proto::PrefixIndex as prost::Message>::decode(body).unwrap();
+        assert_eq!(decoded.prefixes["2001:db8:1::/48"].asn, 65001);
+    }
+}
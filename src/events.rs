@@ -0,0 +1,219 @@
+use axum::{
+    extract::{Extension, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use chrono::Utc;
+use futures_util::{Stream, stream};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::convert::Infallible;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::agent::AgentInfo;
+
+/// Events buffered for slow agents before they have to resync
+pub const EVENT_BUFFER: usize = 1024;
+
+/// A prefix lease was created
+pub const EVENT_LEASE_CREATED: &str = "lease.created";
+/// An admin moved the end of an active lease
+pub const EVENT_LEASE_UPDATED: &str = "lease.updated";
+/// An ASN was assigned
+pub const EVENT_ASN_ASSIGNED: &str = "asn.assigned";
+/// An external prefix was verified and joins the exports
+pub const EVENT_EXTERNAL_PREFIX_VERIFIED: &str = "external_prefix.verified";
+/// A resource was force-revoked and may be reassigned: tear down its filters
+pub const EVENT_INVALIDATE: &str = "resource.invalidate";
+/// Events were dropped for this agent: refetch the full mappings
+pub const EVENT_RESYNC: &str = "resync";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventPriority {
+    Normal,
+    /// Must be applied before any later event (e.g. teardown before reassignment)
+    High,
+}
+
+/// Change pushed to agents on `/service/events`
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentEvent {
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub priority: EventPriority,
+    pub created_at: String,
+    pub data: Value,
+    /// Sites the event concerns (`None` for every site)
+    #[serde(skip)]
+    pub sites: Option<Vec<String>>,
+}
+
+impl AgentEvent {
+    pub fn new(event_type: &str, priority: EventPriority, data: Value) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            event_type: event_type.to_string(),
+            priority,
+            created_at: Utc::now().to_rfc3339(),
+            data,
+            sites: None,
+        }
+    }
+
+    /// Only deliver the event to agents at these sites
+    pub fn at_sites(mut self, sites: Option<Vec<String>>) -> Self {
+        self.sites = sites;
+        self
+    }
+
+    /// Whether an agent should receive the event
+    pub fn visible_to(&self, agent: &AgentInfo) -> bool {
+        match (&self.sites, &agent.site) {
+            (Some(sites), Some(site)) => sites.contains(site),
+            _ => true,
+        }
+    }
+}
+
+/// In-process fan-out of agent events.
+///
+/// Events are delivered in publish order, so an invalidation published when a
+/// resource is revoked always reaches an agent before the event of the
+/// resource being reassigned.
+#[derive(Debug, Clone)]
+pub struct AgentEvents {
+    sender: broadcast::Sender<AgentEvent>,
+}
+
+impl Default for AgentEvents {
+    fn default() -> Self {
+        Self::new(EVENT_BUFFER)
+    }
+}
+
+impl AgentEvents {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    /// Publish an event, returning how many agents are listening
+    pub fn publish(&self, event: AgentEvent) -> usize {
+        debug!("Publishing {} event {}", event.event_type, event.id);
+        self.sender.send(event).unwrap_or(0)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AgentEvent> {
+        self.sender.subscribe()
+    }
+}
+
+fn sse_event(event: &AgentEvent) -> Event {
+    Event::default()
+        .event(event.event_type.as_str())
+        .id(event.id.to_string())
+        .data(json!(event).to_string())
+}
+
+/// Stream agent events as server-sent events
+pub async fn stream_events(
+    Extension(agent): Extension<AgentInfo>,
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    debug!("Agent {} subscribed to events", agent.id);
+    let receiver = state.agent_events.subscribe();
+
+    let events = stream::unfold(receiver, move |mut receiver| {
+        let agent = agent.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if event.visible_to(&agent) => {
+                        return Some((Ok(sse_event(&event)), receiver));
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Agent {} missed {} events", agent.id, skipped);
+                        let event = AgentEvent::new(
+                            EVENT_RESYNC,
+                            EventPriority::High,
+                            json!({ "skipped": skipped }),
+                        );
+                        return Some((Ok(sse_event(&event)), receiver));
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(site: Option<&str>) -> AgentInfo {
+        let mut agent = AgentInfo::shared();
+        agent.site = site.map(str::to_string);
+        agent
+    }
+
+    #[test]
+    fn test_site_visibility() {
+        let event = AgentEvent::new(EVENT_INVALIDATE, EventPriority::High, json!({}))
+            .at_sites(Some(vec!["ams".to_string()]));
+        assert!(event.visible_to(&agent(Some("ams"))));
+        assert!(!event.visible_to(&agent(Some("fra"))));
+        assert!(event.visible_to(&agent(None)));
+
+        let event = AgentEvent::new(EVENT_ASN_ASSIGNED, EventPriority::Normal, json!({}));
+        assert!(event.visible_to(&agent(Some("fra"))));
+    }
+
+    #[tokio::test]
+    async fn test_events_are_delivered_in_order() {
+        let events = AgentEvents::new(8);
+        assert_eq!(
+            events.publish(AgentEvent::new(
+                EVENT_LEASE_CREATED,
+                EventPriority::Normal,
+                json!({})
+            )),
+            0
+        );
+
+        let mut receiver = events.subscribe();
+        events.publish(AgentEvent::new(
+            EVENT_INVALIDATE,
+            EventPriority::High,
+            json!({}),
+        ));
+        events.publish(AgentEvent::new(
+            EVENT_LEASE_CREATED,
+            EventPriority::Normal,
+            json!({}),
+        ));
+
+        assert_eq!(receiver.recv().await.unwrap().event_type, EVENT_INVALIDATE);
+        assert_eq!(
+            receiver.recv().await.unwrap().event_type,
+            EVENT_LEASE_CREATED
+        );
+    }
+
+    #[test]
+    fn test_event_serialization() {
+        let event = AgentEvent::new(EVENT_INVALIDATE, EventPriority::High, json!({"asn": 65001}))
+            .at_sites(Some(vec!["ams".to_string()]));
+        let value = json!(event);
+        assert_eq!(value["type"], EVENT_INVALIDATE);
+        assert_eq!(value["priority"], "high");
+        assert!(value.get("sites").is_none());
+    }
+}
@@ -0,0 +1,773 @@
+use ipnet::Ipv6Net;
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::database::{AnnouncementPermission, PrefixLease, UserAsnMapping};
+use crate::messages;
+
+/// Active prefixes held by a single user, with the user's ASNs (first
+/// assigned first, empty if none)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixGroup {
+    pub user_hash: String,
+    pub asns: Vec<i64>,
+    pub prefixes: Vec<Ipv6Net>,
+}
+
+impl PrefixGroup {
+    /// Minimal set of prefixes exactly covering this group's leased space
+    pub fn aggregated(&self) -> Vec<Ipv6Net> {
+        aggregate(&self.prefixes)
+    }
+}
+
+/// Owner of a leased prefix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PrefixOwner {
+    /// First ASN assigned to the owner
+    pub asn: i64,
+    /// Every ASN of the owner, all allowed to originate the prefix
+    pub asns: Vec<i64>,
+    pub user_hash: String,
+}
+
+/// Owner of each leased prefix, keyed by prefix
+pub type PrefixIndex = BTreeMap<String, PrefixOwner>;
+
+/// Index leased prefixes by prefix, for agents looking up the owner of a
+/// route or packet without re-indexing per-user mappings.
+///
+/// Users without an ASN are left out, like in [`prefixes_by_asn`].
+pub fn index_by_prefix(groups: &[PrefixGroup]) -> PrefixIndex {
+    let mut index = BTreeMap::new();
+    for group in groups {
+        let Some(&asn) = group.asns.first() else {
+            continue;
+        };
+        for prefix in &group.prefixes {
+            index.insert(
+                prefix.to_string(),
+                PrefixOwner {
+                    asn,
+                    asns: group.asns.clone(),
+                    user_hash: group.user_hash.clone(),
+                },
+            );
+        }
+    }
+    index
+}
+
+/// Merge prefixes into the minimal set of aggregates covering the same space
+pub fn aggregate(prefixes: &[Ipv6Net]) -> Vec<Ipv6Net> {
+    Ipv6Net::aggregate(&prefixes.to_vec())
+}
+
+/// ASNs of each user, first assigned first
+fn asns_by_user(mappings: &[UserAsnMapping]) -> BTreeMap<&str, Vec<i64>> {
+    let mut sorted: Vec<&UserAsnMapping> = mappings.iter().collect();
+    sorted.sort_by_key(|m| (m.created_at, m.asn));
+    let mut asns: BTreeMap<&str, Vec<i64>> = BTreeMap::new();
+    for mapping in sorted {
+        asns.entry(mapping.user_hash.as_str())
+            .or_default()
+            .push(mapping.asn);
+    }
+    asns
+}
+
+/// Group active leases per user, attaching each user's ASNs.
+///
+/// Groups are ordered by user hash so exports are stable between calls.
+pub fn group_leases_by_user(
+    mappings: &[UserAsnMapping],
+    leases: &[PrefixLease],
+) -> Vec<PrefixGroup> {
+    group_prefixes_by_user(
+        mappings,
+        leases
+            .iter()
+            .map(|l| (l.user_hash.as_str(), l.prefix.as_str())),
+    )
+}
+
+/// Group `(user_hash, prefix)` pairs per user, attaching each user's ASNs.
+///
+/// Groups are ordered by user hash so exports are stable between calls.
+pub fn group_prefixes_by_user<'a>(
+    mappings: &[UserAsnMapping],
+    prefixes: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Vec<PrefixGroup> {
+    let asns = asns_by_user(mappings);
+
+    let mut groups: BTreeMap<&str, Vec<Ipv6Net>> = BTreeMap::new();
+    for (user_hash, prefix) in prefixes {
+        if let Ok(prefix) = Ipv6Net::from_str(prefix) {
+            groups.entry(user_hash).or_default().push(prefix);
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(user_hash, mut prefixes)| {
+            prefixes.sort();
+            prefixes.dedup();
+            PrefixGroup {
+                user_hash: user_hash.to_string(),
+                asns: asns.get(user_hash).cloned().unwrap_or_default(),
+                prefixes,
+            }
+        })
+        .collect()
+}
+
+/// Group announcement permissions per user, with each user's ASNs (first
+/// assigned first, empty if none).
+///
+/// Groups are ordered by user hash so exports are stable between calls.
+pub fn group_permissions_by_user(permissions: &[AnnouncementPermission]) -> Vec<PrefixGroup> {
+    type Asns<'a> = Vec<(Option<&'a chrono::DateTime<chrono::Utc>>, i64)>;
+    let mut groups: BTreeMap<&str, (Asns, Vec<Ipv6Net>)> = BTreeMap::new();
+    for permission in permissions {
+        let Ok(prefix) = Ipv6Net::from_str(&permission.prefix) else {
+            continue;
+        };
+        let (asns, prefixes) = groups.entry(permission.user_hash.as_str()).or_default();
+        if let Some(asn) = permission.origin_asn {
+            asns.push((permission.asn_assigned_at.as_ref(), asn));
+        }
+        prefixes.push(prefix);
+    }
+
+    groups
+        .into_iter()
+        .map(|(user_hash, (mut asns, mut prefixes))| {
+            asns.sort();
+            let mut ordered: Vec<i64> = Vec::new();
+            for (_, asn) in asns {
+                if !ordered.contains(&asn) {
+                    ordered.push(asn);
+                }
+            }
+            prefixes.sort();
+            prefixes.dedup();
+            PrefixGroup {
+                user_hash: user_hash.to_string(),
+                asns: ordered,
+                prefixes,
+            }
+        })
+        .collect()
+}
+
+/// Collect the prefixes each ASN may announce, ordered by ASN.
+///
+/// Every ASN of a user may announce all of the user's prefixes. Users without
+/// an ASN can't originate routes and are left out.
+pub fn prefixes_by_asn(groups: &[PrefixGroup]) -> BTreeMap<i64, Vec<Ipv6Net>> {
+    let mut by_asn: BTreeMap<i64, Vec<Ipv6Net>> = BTreeMap::new();
+    for group in groups {
+        for &asn in &group.asns {
+            by_asn
+                .entry(asn)
+                .or_default()
+                .extend(group.prefixes.iter().copied());
+        }
+    }
+    for prefixes in by_asn.values_mut() {
+        prefixes.sort();
+        prefixes.dedup();
+    }
+    by_asn
+}
+
+/// Router configuration syntax for generated prefix filters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterFormat {
+    Bird,
+    Frr,
+    Junos,
+    IosXr,
+}
+
+impl FilterFormat {
+    pub const ALL: [FilterFormat; 4] = [Self::Bird, Self::Frr, Self::Junos, Self::IosXr];
+
+    /// Name used in the `/service/filters/{format}` path
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Bird => "bird",
+            Self::Frr => "frr",
+            Self::Junos => "junos",
+            Self::IosXr => "iosxr",
+        }
+    }
+}
+
+impl FromStr for FilterFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|f| f.name() == s.to_ascii_lowercase())
+            .ok_or_else(|| format!("Unsupported filter format '{}'", s))
+    }
+}
+
+/// Render per-ASN prefix filters in the given router syntax
+pub fn render_filters(format: FilterFormat, by_asn: &BTreeMap<i64, Vec<Ipv6Net>>) -> String {
+    let mut out = String::new();
+    let header = match format {
+        FilterFormat::Bird => "# Generated by peerlab-gateway",
+        FilterFormat::Frr | FilterFormat::IosXr => "! Generated by peerlab-gateway",
+        FilterFormat::Junos => "/* Generated by peerlab-gateway */",
+    };
+    let _ = writeln!(out, "{}", header);
+
+    if format == FilterFormat::Junos {
+        let _ = writeln!(out, "policy-options {{");
+    }
+
+    for (asn, prefixes) in by_asn {
+        let name = format!("AS{}", asn);
+        match format {
+            FilterFormat::Bird => {
+                let _ = writeln!(out, "define {}_PREFIXES = [", name);
+                let entries: Vec<String> = prefixes.iter().map(|p| format!("    {}", p)).collect();
+                let _ = writeln!(out, "{}", entries.join(",\n"));
+                let _ = writeln!(out, "];");
+            }
+            FilterFormat::Frr => {
+                for (i, prefix) in prefixes.iter().enumerate() {
+                    let _ = writeln!(
+                        out,
+                        "ipv6 prefix-list {} seq {} permit {}",
+                        name,
+                        (i + 1) * 5,
+                        prefix
+                    );
+                }
+            }
+            FilterFormat::Junos => {
+                let _ = writeln!(out, "    route-filter-list {} {{", name);
+                for prefix in prefixes {
+                    let _ = writeln!(out, "        {} exact;", prefix);
+                }
+                let _ = writeln!(out, "    }}");
+            }
+            FilterFormat::IosXr => {
+                let _ = writeln!(out, "prefix-set {}", name);
+                let entries: Vec<String> = prefixes.iter().map(|p| format!("  {}", p)).collect();
+                let _ = writeln!(out, "{}", entries.join(",\n"));
+                let _ = writeln!(out, "end-set");
+            }
+        }
+    }
+
+    if format == FilterFormat::Junos {
+        let _ = writeln!(out, "}}");
+    }
+
+    out
+}
+
+/// JSON policy syntax for software routers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyFormat {
+    GoBgp,
+    ExaBgp,
+}
+
+impl PolicyFormat {
+    pub const ALL: [PolicyFormat; 2] = [Self::GoBgp, Self::ExaBgp];
+
+    /// Name used in the `/service/policies/{format}` path
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::GoBgp => "gobgp",
+            Self::ExaBgp => "exabgp",
+        }
+    }
+}
+
+impl FromStr for PolicyFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|f| f.name() == s.to_ascii_lowercase())
+            .ok_or_else(|| format!("Unsupported policy format '{}'", s))
+    }
+}
+
+/// Render announcement permissions as JSON for GoBGP or ExaBGP
+pub fn render_policy(format: PolicyFormat, by_asn: &BTreeMap<i64, Vec<Ipv6Net>>) -> Value {
+    match format {
+        // Mirrors GoBGP's `defined-sets` / `policy-definitions` configuration schema:
+        // one prefix set and origin AS-path set per ASN, and a statement accepting
+        // routes only when both match.
+        PolicyFormat::GoBgp => {
+            let prefix_sets: Vec<Value> = by_asn
+                .iter()
+                .map(|(asn, prefixes)| {
+                    json!({
+                        "prefix-set-name": format!("AS{}-PREFIXES", asn),
+                        "prefix-list": prefixes
+                            .iter()
+                            .map(|p| json!({
+                                "ip-prefix": p.to_string(),
+                                "masklength-range": format!("{}..{}", p.prefix_len(), p.prefix_len()),
+                            }))
+                            .collect::<Vec<_>>(),
+                    })
+                })
+                .collect();
+
+            let as_path_sets: Vec<Value> = by_asn
+                .keys()
+                .map(|asn| {
+                    json!({
+                        "as-path-set-name": format!("AS{}-ORIGIN", asn),
+                        "as-path-list": [format!("_{}$", asn)],
+                    })
+                })
+                .collect();
+
+            let statements: Vec<Value> = by_asn
+                .keys()
+                .map(|asn| {
+                    json!({
+                        "name": format!("AS{}", asn),
+                        "conditions": {
+                            "match-prefix-set": {
+                                "prefix-set": format!("AS{}-PREFIXES", asn),
+                                "match-set-options": "any",
+                            },
+                            "bgp-conditions": {
+                                "match-as-path-set": {
+                                    "as-path-set": format!("AS{}-ORIGIN", asn),
+                                    "match-set-options": "any",
+                                },
+                            },
+                        },
+                        "actions": { "route-disposition": "accept-route" },
+                    })
+                })
+                .collect();
+
+            json!({
+                "defined-sets": {
+                    "prefix-sets": prefix_sets,
+                    "bgp-defined-sets": { "as-path-sets": as_path_sets },
+                },
+                "policy-definitions": [{
+                    "name": "peerlab-announcements",
+                    "statements": statements,
+                }],
+            })
+        }
+        // ExaBGP has no policy engine, so emit the permitted routes per origin
+        // ASN along with the API command a helper process would send.
+        PolicyFormat::ExaBgp => {
+            let permissions: Vec<Value> = by_asn
+                .iter()
+                .map(|(asn, prefixes)| {
+                    json!({
+                        "asn": asn,
+                        "routes": prefixes
+                            .iter()
+                            .map(|p| json!({
+                                "prefix": p.to_string(),
+                                "command": format!("announce route {} next-hop self as-path [ {} ]", p, asn),
+                            }))
+                            .collect::<Vec<_>>(),
+                    })
+                })
+                .collect();
+
+            json!({ "permissions": permissions })
+        }
+    }
+}
+
+/// Authorization for an ASN to originate a prefix, and its more-specifics up
+/// to `max_length`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Roa {
+    pub prefix: Ipv6Net,
+    pub max_length: u8,
+    pub asn: i64,
+}
+
+/// Collect the ROAs of the permissions granted by leases to an ASN,
+/// ordered by prefix.
+///
+/// Users without an ASN can't originate routes and are left out.
+pub fn roas(permissions: &[AnnouncementPermission]) -> Vec<Roa> {
+    let mut roas: Vec<Roa> = permissions
+        .iter()
+        .filter(|permission| permission.is_lease())
+        .filter_map(|permission| {
+            Some(Roa {
+                prefix: Ipv6Net::from_str(&permission.prefix).ok()?,
+                max_length: u8::try_from(permission.max_length).ok()?,
+                asn: permission.origin_asn?,
+            })
+        })
+        .collect();
+    roas.sort_by_key(|roa| (roa.prefix, roa.asn));
+    roas.dedup();
+    roas
+}
+
+/// Check a ROA maxLength lies between the prefix length and `limit`,
+/// returning it as stored (`None` for the prefix length)
+pub fn validate_roa_max_length(
+    prefix: &Ipv6Net,
+    max_length: Option<u8>,
+    limit: u8,
+) -> Result<Option<i16>, String> {
+    let Some(max_length) = max_length else {
+        return Ok(None);
+    };
+    let (min, max) = (prefix.prefix_len(), limit.clamp(prefix.prefix_len(), 128));
+    if !(min..=max).contains(&max_length) {
+        return Err(messages::LEASE_ROA_MAX_LENGTH_OUT_OF_RANGE.text(&[&min, &max]));
+    }
+    Ok((max_length > min).then_some(max_length.into()))
+}
+
+/// Render ROAs in the JSON format of rpki-client, read by RTR servers such as
+/// StayRTR or GoRTR
+pub fn render_roas(roas: &[Roa], generated: i64) -> Value {
+    json!({
+        "metadata": {
+            "generated": generated,
+            "counts": roas.len(),
+        },
+        "roas": roas
+            .iter()
+            .map(|roa| json!({
+                "prefix": roa.prefix.to_string(),
+                "maxLength": roa.max_length,
+                "asn": roa.asn,
+                "ta": "peerlab",
+            }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::types::UserHash;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    pub(crate) fn mapping(user_hash: &str, asn: i64) -> UserAsnMapping {
+        UserAsnMapping {
+            id: Uuid::new_v4(),
+            user_hash: UserHash::from_digest(user_hash.to_string()),
+            user_id: None,
+            asn,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            description: None,
+            abuse_contact: None,
+        }
+    }
+
+    pub(crate) fn lease(user_hash: &str, prefix: &str) -> PrefixLease {
+        PrefixLease {
+            id: Uuid::new_v4(),
+            user_hash: UserHash::from_digest(user_hash.to_string()),
+            prefix: prefix.to_string(),
+            start_time: Utc::now(),
+            end_time: Utc::now() + chrono::Duration::hours(1),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            sites: None,
+            renew_minutes: None,
+            label: None,
+            purpose: None,
+            roa_max_length: None,
+        }
+    }
+
+    pub(crate) fn permission(
+        user_hash: &str,
+        prefix: &str,
+        asn: Option<i64>,
+    ) -> AnnouncementPermission {
+        let prefix: Ipv6Net = prefix.parse().unwrap();
+        AnnouncementPermission {
+            prefix: prefix.to_string(),
+            max_length: prefix.prefix_len().into(),
+            origin_asn: asn,
+            asn_assigned_at: asn.map(|_| Utc::now()),
+            user_hash: UserHash::from_digest(user_hash.to_string()),
+            source: crate::database::PERMISSION_SOURCE_LEASE.to_string(),
+            source_id: Uuid::new_v4(),
+            sites: None,
+            rpki_state: None,
+            valid_from: Utc::now(),
+            valid_to: Some(Utc::now() + chrono::Duration::hours(1)),
+        }
+    }
+
+    #[test]
+    fn test_aggregate_adjacent_prefixes() {
+        let prefixes = vec![
+            Ipv6Net::from_str("2001:db8:2::/48").unwrap(),
+            Ipv6Net::from_str("2001:db8:3::/48").unwrap(),
+            Ipv6Net::from_str("2001:db8:5::/48").unwrap(),
+        ];
+
+        assert_eq!(
+            aggregate(&prefixes),
+            vec![
+                Ipv6Net::from_str("2001:db8:2::/47").unwrap(),
+                Ipv6Net::from_str("2001:db8:5::/48").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_group_leases_by_user() {
+        let mappings = vec![mapping("alice", 65001)];
+        let leases = vec![
+            lease("bob", "2001:db8:9::/48"),
+            lease("alice", "2001:db8:1::/48"),
+            lease("alice", "2001:db8:0::/48"),
+        ];
+
+        let groups = group_leases_by_user(&mappings, &leases);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].user_hash, "alice");
+        assert_eq!(groups[0].asns, vec![65001]);
+        assert_eq!(
+            groups[0].aggregated(),
+            vec![Ipv6Net::from_str("2001:db8::/47").unwrap()]
+        );
+        assert_eq!(groups[1].user_hash, "bob");
+        assert!(groups[1].asns.is_empty());
+    }
+
+    fn sample_by_asn() -> BTreeMap<i64, Vec<Ipv6Net>> {
+        let mappings = vec![mapping("alice", 65001), mapping("bob", 65002)];
+        let leases = vec![
+            lease("alice", "2001:db8:1::/48"),
+            lease("alice", "2001:db8:2::/48"),
+            lease("bob", "2001:db8:3::/48"),
+            lease("carol", "2001:db8:4::/48"),
+        ];
+        prefixes_by_asn(&group_leases_by_user(&mappings, &leases))
+    }
+
+    #[test]
+    fn test_index_by_prefix() {
+        let mappings = vec![mapping("alice", 65001), mapping("bob", 65002)];
+        let leases = vec![
+            lease("alice", "2001:db8:1::/48"),
+            lease("bob", "2001:db8:3::/48"),
+            lease("carol", "2001:db8:4::/48"),
+        ];
+
+        let index = index_by_prefix(&group_leases_by_user(&mappings, &leases));
+        assert_eq!(index.len(), 2);
+        assert_eq!(
+            index["2001:db8:3::/48"],
+            PrefixOwner {
+                asn: 65002,
+                asns: vec![65002],
+                user_hash: "bob".to_string()
+            }
+        );
+        assert!(!index.contains_key("2001:db8:4::/48"));
+    }
+
+    #[test]
+    fn test_prefixes_by_asn_skips_users_without_asn() {
+        let by_asn = sample_by_asn();
+        assert_eq!(
+            by_asn.keys().copied().collect::<Vec<_>>(),
+            vec![65001, 65002]
+        );
+        assert_eq!(by_asn[&65001].len(), 2);
+    }
+
+    #[test]
+    fn test_parse_filter_format() {
+        assert_eq!("BIRD".parse::<FilterFormat>(), Ok(FilterFormat::Bird));
+        assert_eq!("iosxr".parse::<FilterFormat>(), Ok(FilterFormat::IosXr));
+        assert!("cisco".parse::<FilterFormat>().is_err());
+    }
+
+    #[test]
+    fn test_render_bird_filters() {
+        let out = render_filters(FilterFormat::Bird, &sample_by_asn());
+        assert_eq!(
+            out,
+            "# Generated by peerlab-gateway\n\
+             define AS65001_PREFIXES = [\n    2001:db8:1::/48,\n    2001:db8:2::/48\n];\n\
+             define AS65002_PREFIXES = [\n    2001:db8:3::/48\n];\n"
+        );
+    }
+
+    #[test]
+    fn test_render_frr_filters() {
+        let out = render_filters(FilterFormat::Frr, &sample_by_asn());
+        assert!(out.contains("ipv6 prefix-list AS65001 seq 5 permit 2001:db8:1::/48\n"));
+        assert!(out.contains("ipv6 prefix-list AS65001 seq 10 permit 2001:db8:2::/48\n"));
+        assert!(out.contains("ipv6 prefix-list AS65002 seq 5 permit 2001:db8:3::/48\n"));
+    }
+
+    #[test]
+    fn test_render_iosxr_filters() {
+        let out = render_filters(FilterFormat::IosXr, &sample_by_asn());
+        assert!(out.starts_with("! Generated by peerlab-gateway\n"));
+        assert!(
+            out.contains("prefix-set AS65001\n  2001:db8:1::/48,\n  2001:db8:2::/48\nend-set\n")
+        );
+    }
+
+    #[test]
+    fn test_render_junos_filters() {
+        let out = render_filters(FilterFormat::Junos, &sample_by_asn());
+        assert!(out.starts_with("/* Generated by peerlab-gateway */\npolicy-options {\n"));
+        assert!(
+            out.contains(
+                "    route-filter-list AS65002 {\n        2001:db8:3::/48 exact;\n    }\n"
+            )
+        );
+        assert!(out.ends_with("}\n"));
+    }
+
+    #[test]
+    fn test_render_gobgp_policy() {
+        let policy = render_policy(PolicyFormat::GoBgp, &sample_by_asn());
+        let prefix_sets = policy["defined-sets"]["prefix-sets"].as_array().unwrap();
+        assert_eq!(prefix_sets.len(), 2);
+        assert_eq!(prefix_sets[0]["prefix-set-name"], "AS65001-PREFIXES");
+        assert_eq!(
+            prefix_sets[0]["prefix-list"][1],
+            json!({ "ip-prefix": "2001:db8:2::/48", "masklength-range": "48..48" })
+        );
+        assert_eq!(
+            policy["defined-sets"]["bgp-defined-sets"]["as-path-sets"][1]["as-path-list"][0],
+            "_65002$"
+        );
+        let statement = &policy["policy-definitions"][0]["statements"][0];
+        assert_eq!(statement["actions"]["route-disposition"], "accept-route");
+    }
+
+    #[test]
+    fn test_render_exabgp_policy() {
+        let policy = render_policy(PolicyFormat::ExaBgp, &sample_by_asn());
+        assert_eq!(policy["permissions"][1]["asn"], 65002);
+        assert_eq!(
+            policy["permissions"][1]["routes"][0]["command"],
+            "announce route 2001:db8:3::/48 next-hop self as-path [ 65002 ]"
+        );
+    }
+
+    #[test]
+    fn test_validate_roa_max_length() {
+        let prefix = Ipv6Net::from_str("2001:db8:1::/48").unwrap();
+        assert_eq!(validate_roa_max_length(&prefix, None, 64), Ok(None));
+        assert_eq!(validate_roa_max_length(&prefix, Some(48), 64), Ok(None));
+        assert_eq!(validate_roa_max_length(&prefix, Some(56), 64), Ok(Some(56)));
+        assert!(validate_roa_max_length(&prefix, Some(47), 64).is_err());
+        assert!(validate_roa_max_length(&prefix, Some(65), 64).is_err());
+        // A limit below the prefix length only allows the prefix length
+        assert_eq!(validate_roa_max_length(&prefix, Some(48), 32), Ok(None));
+        assert!(validate_roa_max_length(&prefix, Some(129), 128).is_err());
+    }
+
+    #[test]
+    fn test_roas() {
+        let mut more_specifics = permission("alice", "2001:db8:2::/48", Some(65001));
+        more_specifics.max_length = 56;
+        let mut external = permission("alice", "2001:db8:4::/48", Some(65001));
+        external.source = "external".to_string();
+        let permissions = vec![
+            more_specifics,
+            permission("alice", "2001:db8:1::/48", Some(65001)),
+            permission("bob", "2001:db8:3::/48", None),
+            external,
+        ];
+
+        let roas = roas(&permissions);
+        assert_eq!(
+            roas.iter()
+                .map(|roa| (roa.prefix.to_string(), roa.max_length))
+                .collect::<Vec<_>>(),
+            vec![
+                ("2001:db8:1::/48".to_string(), 48),
+                ("2001:db8:2::/48".to_string(), 56)
+            ]
+        );
+        let rendered = render_roas(&roas, 0);
+        assert_eq!(rendered["metadata"]["counts"], 2);
+        assert_eq!(
+            rendered["roas"][1],
+            json!({ "prefix": "2001:db8:2::/48", "maxLength": 56, "asn": 65001, "ta": "peerlab" })
+        );
+    }
+
+    #[test]
+    fn test_group_permissions_by_user() {
+        let permissions = vec![
+            permission("bob", "2001:db8:9::/48", None),
+            permission("alice", "2001:db8:1::/48", Some(65001)),
+            permission("alice", "2001:db8:0::/48", Some(65001)),
+        ];
+
+        let groups = group_permissions_by_user(&permissions);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].user_hash, "alice");
+        assert_eq!(groups[0].asns, vec![65001]);
+        assert_eq!(
+            groups[0].aggregated(),
+            vec![Ipv6Net::from_str("2001:db8::/47").unwrap()]
+        );
+        assert_eq!(groups[1].user_hash, "bob");
+        assert!(groups[1].asns.is_empty());
+    }
+
+    #[test]
+    fn test_several_asns_per_user() {
+        let first = mapping("alice", 65002);
+        let mut second = mapping("alice", 65001);
+        second.created_at = first.created_at + chrono::Duration::seconds(1);
+        // Mappings come newest first from the database
+        let mappings = vec![second, first];
+        let leases = vec![lease("alice", "2001:db8:1::/48")];
+
+        let groups = group_leases_by_user(&mappings, &leases);
+        assert_eq!(groups[0].asns, vec![65002, 65001]);
+        assert_eq!(index_by_prefix(&groups)["2001:db8:1::/48"].asn, 65002);
+        assert_eq!(prefixes_by_asn(&groups).len(), 2);
+
+        // The same from the read model, one permission per ASN
+        let first = permission("alice", "2001:db8:1::/48", Some(65002));
+        let mut second = permission("alice", "2001:db8:1::/48", Some(65001));
+        second.asn_assigned_at = first
+            .asn_assigned_at
+            .map(|t| t + chrono::Duration::seconds(1));
+        let permissions = vec![second, first];
+        assert_eq!(group_permissions_by_user(&permissions), groups);
+        assert_eq!(
+            roas(&permissions)
+                .iter()
+                .map(|roa| roa.asn)
+                .collect::<Vec<_>>(),
+            vec![65001, 65002]
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Safety checks of exported filters, policies and ROAs.
+//!
+//! Before an export is generated, the announcement permissions it is built
+//! from are checked: every leased prefix must be in the prefix pool (whole or
+//! a slice of a pool prefix), every origin ASN must be in the ASN pool, and
+//! no two active leases may overlap. While a check fails the export is withheld (routers and the RTR
+//! server keep the last one they fetched, the bucket keeps the last upload)
+//! and admins are alerted through the logs, the audit stream and `/health`.
+
+use axum::{Json, extract::State, http::StatusCode};
+use chrono::{DateTime, Utc};
+use ipnet::Ipv6Net;
+use serde_json::{Value, json};
+use std::sync::{Arc, Mutex};
+use tracing::{error, info};
+
+use crate::AppState;
+use crate::database::{AnnouncementPermission, PERMISSION_SOURCE_LEASE};
+use crate::pool_asns::AsnPool;
+use crate::pool_prefixes::PrefixPool;
+
+/// Failing safety checks, and since when they fail
+#[derive(Debug, Clone)]
+pub struct SafetyFailure {
+    pub violations: Vec<String>,
+    pub since: DateTime<Utc>,
+}
+
+/// State of the export safety checks, shared across requests
+#[derive(Debug, Clone, Default)]
+pub struct ExportSafety {
+    failure: Arc<Mutex<Option<SafetyFailure>>>,
+}
+
+impl ExportSafety {
+    /// The failing checks, if the last export was withheld
+    pub fn failure(&self) -> Option<SafetyFailure> {
+        self.failure.lock().unwrap().clone()
+    }
+
+    /// Record the outcome of a check, alerting admins when it changes
+    fn record(&self, state: &AppState, violations: &[String]) {
+        let mut failure = self.failure.lock().unwrap();
+        match (failure.as_mut(), violations.is_empty()) {
+            (None, true) => {}
+            (Some(_), true) => {
+                info!("Export safety checks pass again, exports resumed");
+                state.audit.record("export.resumed", json!({}));
+                *failure = None;
+            }
+            (Some(current), false) if current.violations == violations => {}
+            (current, false) => {
+                error!(
+                    "Export withheld, {} safety check(s) failed: {}",
+                    violations.len(),
+                    violations.join("; ")
+                );
+                state
+                    .audit
+                    .record("export.withheld", json!({ "violations": violations }));
+                let since = current.map_or_else(Utc::now, |current| current.since);
+                *failure = Some(SafetyFailure {
+                    violations: violations.to_vec(),
+                    since,
+                });
+            }
+        }
+    }
+}
+
+/// Why an export could not be generated
+#[derive(Debug)]
+pub enum ExportError {
+    Database(sqlx::Error),
+    /// Safety checks failed, with the violations found
+    Unsafe(Vec<String>),
+}
+
+impl From<sqlx::Error> for ExportError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+impl ExportError {
+    /// Response to an export request that failed, `what` naming the export
+    pub fn response(self, what: &str) -> (StatusCode, Json<Value>) {
+        match self {
+            Self::Database(err) => {
+                error!("Failed to get leases for {} generation: {}", what, err);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({
+                        "error": 500,
+                        "message": format!("Failed to generate {}", what)
+                    })),
+                )
+            }
+            Self::Unsafe(violations) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({
+                    "error": 503,
+                    "message": "Export withheld, safety checks failed",
+                    "violations": violations
+                })),
+            ),
+        }
+    }
+}
+
+/// Find, among the permissions an export is built from, the leases outside
+/// the prefix pool, the origin ASNs outside the ASN pool and the leases
+/// overlapping each other (slices of a pool prefix are in the pool)
+pub fn violations(
+    prefix_pool: &PrefixPool,
+    asn_pool: &AsnPool,
+    permissions: &[AnnouncementPermission],
+) -> Vec<String> {
+    let mut violations = Vec::new();
+
+    // A lease has a permission per ASN of its holder
+    let mut leases: Vec<&AnnouncementPermission> = permissions
+        .iter()
+        .filter(|p| p.source == PERMISSION_SOURCE_LEASE)
+        .collect();
+    leases.sort_by_key(|p| p.source_id);
+    leases.dedup_by_key(|p| p.source_id);
+
+    let mut prefixes: Vec<(Ipv6Net, &AnnouncementPermission)> = Vec::with_capacity(leases.len());
+    for lease in leases {
+        match lease.prefix.parse::<Ipv6Net>() {
+            Ok(prefix) if prefix_pool.covering(&prefix).is_some() => prefixes.push((prefix, lease)),
+            _ => violations.push(format!(
+                "Lease {} of {} is outside the prefix pool",
+                lease.source_id, lease.prefix
+            )),
+        }
+    }
+
+    let mut asns: Vec<(&str, i64)> = permissions
+        .iter()
+        .filter_map(|p| Some((p.user_hash.as_str(), p.origin_asn?)))
+        .collect();
+    asns.sort();
+    asns.dedup();
+    for (user_hash, asn) in asns {
+        if !asn_pool.contains(asn) {
+            violations.push(format!(
+                "ASN {} of user {} is outside the ASN pool",
+                asn, user_hash
+            ));
+        }
+    }
+
+    prefixes.sort_by_key(|(prefix, _)| *prefix);
+    for (i, (prefix, lease)) in prefixes.iter().enumerate() {
+        for (other, other_lease) in &prefixes[i + 1..] {
+            if prefix.contains(other) || other.contains(prefix) {
+                violations.push(format!(
+                    "Leases {} of {} and {} of {} overlap",
+                    lease.source_id, prefix, other_lease.source_id, other
+                ));
+            }
+        }
+    }
+
+    violations
+}
+
+/// Check the permissions an export is built from against the pools
+pub fn check(state: &AppState, permissions: &[AnnouncementPermission]) -> Result<(), ExportError> {
+    let violations = violations(
+        &state.prefix_pool.load(),
+        &state.asn_pool.load(),
+        permissions,
+    );
+    state.export_safety.record(state, &violations);
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(ExportError::Unsafe(violations))
+    }
+}
+
+/// Run the export safety checks and report whether exports are withheld
+/// (admin API)
+pub async fn get_export_safety(
+    State(state): State<AppState>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let permissions = state
+        .database
+        .get_announcement_permissions()
+        .await
+        .map_err(|err| ExportError::from(err).response("safety checks"))?;
+    // The outcome is recorded, so exports resume or stop as they are requested
+    let _ = check(&state, &permissions);
+    Ok(Json(match state.export_safety.failure() {
+        Some(failure) => json!({
+            "withheld": true,
+            "since": failure.since.to_rfc3339(),
+            "violations": failure.violations,
+        }),
+        None => json!({ "withheld": false, "violations": [] }),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn permission(source: &str, id: u128, prefix: &str, asn: i64) -> AnnouncementPermission {
+        AnnouncementPermission {
+            prefix: prefix.to_string(),
+            max_length: 64,
+            origin_asn: Some(asn),
+            asn_assigned_at: Some(Utc::now()),
+            user_hash: "a".repeat(64).parse().unwrap(),
+            source: source.to_string(),
+            source_id: Uuid::from_u128(id),
+            sites: None,
+            rpki_state: None,
+            valid_from: Utc::now(),
+            valid_to: None,
+        }
+    }
+
+    fn lease(id: u128, prefix: &str, asn: i64) -> AnnouncementPermission {
+        permission(PERMISSION_SOURCE_LEASE, id, prefix, asn)
+    }
+
+    #[test]
+    fn test_violations() {
+        let prefix_pool = PrefixPool::new(vec![
+            "2001:db8:1::/48".parse().unwrap(),
+            "2001:db8:2::/48".parse().unwrap(),
+            "2001:db8:2::/56".parse().unwrap(),
+        ]);
+        let asn_pool = AsnPool::new(65000, 65009);
+
+        // Slices of a pool prefix are in the pool, a lease is checked once
+        // whatever the number of ASNs of its holder, and external prefixes
+        // are not leased from the pool
+        let safe = [
+            lease(1, "2001:db8:1::/48", 65000),
+            lease(1, "2001:db8:1::/48", 65001),
+            lease(2, "2001:db8:2:100::/56", 65000),
+            lease(3, "2001:db8:2:200::/64", 65000),
+            permission("external", 4, "2a00::/48", 65000),
+        ];
+        assert!(violations(&prefix_pool, &asn_pool, &safe).is_empty());
+
+        let unsafe_permissions = [
+            lease(1, "2001:db8:1::/48", 65000),
+            lease(2, "2001:db8:9::/48", 65000),
+            lease(3, "2001:db8:2::/48", 65010),
+            lease(4, "2001:db8:2::/56", 65010),
+        ];
+        let found = violations(&prefix_pool, &asn_pool, &unsafe_permissions);
+        assert_eq!(found.len(), 3);
+        assert!(found[0].contains("2001:db8:9::/48 is outside the prefix pool"));
+        assert!(found[1].contains("ASN 65010"));
+        assert!(found[2].contains("2001:db8:2::/48 and"));
+        assert!(found[2].ends_with("2001:db8:2::/56 overlap"));
+    }
+}
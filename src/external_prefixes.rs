@@ -0,0 +1,555 @@
+use axum::{
+    Json,
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+};
+use ipnet::Ipv6Net;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::database::{self, AnnouncementPermission, ExternalPrefix};
+use crate::events::{self, AgentEvent, EventPriority};
+use crate::pool_prefixes::PrefixClass;
+use crate::rpki;
+use crate::{AppState, jwt};
+
+/// Maximum number of external prefixes a single user can register
+pub const MAX_EXTERNAL_PREFIXES_PER_USER: usize = 10;
+
+/// Widest external prefix accepted
+pub const MIN_PREFIX_LEN: u8 = 32;
+/// Narrowest external prefix accepted, the longest announced in the global table
+pub const MAX_PREFIX_LEN: u8 = 48;
+
+/// Registered, waiting for the user to prove control
+pub const STATUS_PENDING: &str = "pending";
+/// Control proven, included in exports
+pub const STATUS_VERIFIED: &str = "verified";
+
+/// Verified through a TXT record in the prefix's reverse DNS zone
+pub const METHOD_DNS: &str = "dns";
+/// Verified through a ROA authorizing the lab's origin ASN
+pub const METHOD_RPKI: &str = "rpki";
+
+/// Label under the reverse zone holding the challenge TXT record
+pub const CHALLENGE_LABEL: &str = "_peerlab-challenge";
+
+/// Default DNS-over-HTTPS resolver (JSON API) used to look up challenges
+pub const DEFAULT_DNS_RESOLVER: &str = "https://cloudflare-dns.com/dns-query";
+
+/// Reverse DNS zone of a prefix (`ip6.arpa`), rounded to the nibble covering
+/// its first address. Only the holder of the space can delegate it.
+pub fn reverse_zone(prefix: &Ipv6Net) -> String {
+    let nibbles = prefix.prefix_len().div_ceil(4) as usize;
+    let hex = format!("{:032x}", u128::from(prefix.network()));
+    let labels: Vec<String> = hex.chars().take(nibbles).map(String::from).collect();
+    let reversed: Vec<&str> = labels.iter().rev().map(String::as_str).collect();
+    format!("{}.ip6.arpa", reversed.join("."))
+}
+
+/// Name of the TXT record proving control of a prefix
+pub fn challenge_name(prefix: &Ipv6Net) -> String {
+    format!("{}.{}", CHALLENGE_LABEL, reverse_zone(prefix))
+}
+
+/// Content of the TXT record proving control of a prefix
+pub fn challenge_value(token: &str) -> String {
+    format!("peerlab-verification={}", token)
+}
+
+/// Whether a prefix is included in exports: verified, and RPKI-valid when a
+/// validator is configured
+pub fn is_exported(state: &AppState, external: &ExternalPrefix) -> bool {
+    external.status == STATUS_VERIFIED
+        && (state.rpki_validator.is_none()
+            || external.rpki_state.as_deref() == Some(rpki::STATE_VALID))
+}
+
+/// Whether an announcement permission of an external prefix is exported, like
+/// [`is_exported`] (the read model only holds verified prefixes)
+pub fn is_permission_exported(state: &AppState, permission: &AnnouncementPermission) -> bool {
+    state.rpki_validator.is_none() || permission.rpki_state.as_deref() == Some(rpki::STATE_VALID)
+}
+
+/// Check that a prefix can be brought to the lab
+fn validate_prefix(state: &AppState, prefix: &str) -> Result<Ipv6Net, String> {
+    let net: Ipv6Net = prefix
+        .parse()
+        .map_err(|_| format!("Invalid IPv6 prefix '{}'", prefix))?;
+    if net.trunc() != net {
+        return Err(format!(
+            "Prefix {} has host bits set (did you mean {}?)",
+            net,
+            net.trunc()
+        ));
+    }
+    if !(MIN_PREFIX_LEN..=MAX_PREFIX_LEN).contains(&net.prefix_len()) {
+        return Err(format!(
+            "Prefix length must be between /{} and /{}",
+            MIN_PREFIX_LEN, MAX_PREFIX_LEN
+        ));
+    }
+    if PrefixClass::of(&net) != PrefixClass::Global {
+        return Err(format!("Prefix {} is not globally routable", net));
+    }
+    if let Some(pool_prefix) = state
+        .prefix_pool
+        .load()
+        .get_all_prefixes()
+        .iter()
+        .find(|p| p.contains(&net) || net.contains(*p))
+    {
+        return Err(format!(
+            "Prefix {} overlaps the lab's pool prefix {}",
+            net, pool_prefix
+        ));
+    }
+    Ok(net)
+}
+
+#[derive(Deserialize)]
+struct DnsResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DnsAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DnsAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+/// DNS type of TXT records
+const TXT: u16 = 16;
+/// DNS response code of a name that doesn't exist
+const NXDOMAIN: u32 = 3;
+
+/// Join the character strings of a TXT record (`"a" "b"` is `ab`)
+fn txt_content(data: &str) -> String {
+    if !data.contains('"') {
+        return data.to_string();
+    }
+    data.split('"').skip(1).step_by(2).collect()
+}
+
+/// Look up the TXT records of a name through a DNS-over-HTTPS resolver
+#[instrument(name = "dns", skip_all, fields(operation = "lookup_txt", name = %name))]
+async fn lookup_txt(resolver: &str, name: &str) -> Result<Vec<String>, String> {
+    let response = reqwest::Client::new()
+        .get(resolver)
+        .query(&[("name", name), ("type", "TXT")])
+        .header(reqwest::header::ACCEPT, "application/dns-json")
+        .send()
+        .await
+        .map_err(|e| format!("DNS resolver is unreachable: {}", e))?
+        .error_for_status()
+        .map_err(|e| format!("DNS resolver failed: {}", e))?
+        .json::<DnsResponse>()
+        .await
+        .map_err(|e| format!("Invalid DNS resolver response: {}", e))?;
+
+    match response.status {
+        0 => Ok(response
+            .answer
+            .iter()
+            .filter(|a| a.record_type == TXT)
+            .map(|a| txt_content(&a.data))
+            .collect()),
+        NXDOMAIN => Ok(Vec::new()),
+        status => Err(format!(
+            "DNS lookup of {} failed with rcode {}",
+            name, status
+        )),
+    }
+}
+
+// Request/Response types
+
+#[derive(Deserialize)]
+pub struct RegisterExternalPrefixRequest {
+    prefix: String,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyQuery {
+    /// `dns` (default) or `rpki`
+    method: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Challenge {
+    method: &'static str,
+    name: String,
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    value: String,
+}
+
+#[derive(Serialize)]
+pub struct ExternalPrefixResponse {
+    id: Uuid,
+    prefix: String,
+    status: String,
+    verification_method: Option<String>,
+    verified_at: Option<String>,
+    /// Route origin validation state, when a validator is configured
+    rpki_state: Option<String>,
+    created_at: String,
+    /// How to prove control, until verified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    challenge: Option<Challenge>,
+}
+
+impl From<ExternalPrefix> for ExternalPrefixResponse {
+    fn from(external: ExternalPrefix) -> Self {
+        let challenge = match external.prefix.parse::<Ipv6Net>() {
+            Ok(net) if external.status == STATUS_PENDING => Some(Challenge {
+                method: METHOD_DNS,
+                name: challenge_name(&net),
+                record_type: "TXT",
+                value: challenge_value(&external.token),
+            }),
+            _ => None,
+        };
+        Self {
+            id: external.id,
+            prefix: external.prefix,
+            status: external.status,
+            verification_method: external.verification_method,
+            verified_at: external.verified_at.map(|t| t.to_rfc3339()),
+            rpki_state: external.rpki_state,
+            created_at: external.created_at.to_rfc3339(),
+            challenge,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ExternalPrefixListResponse {
+    external_prefixes: Vec<ExternalPrefixResponse>,
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.into()
+        })),
+    )
+}
+
+fn not_found() -> ApiError {
+    api_error(StatusCode::NOT_FOUND, "External prefix not found")
+}
+
+// Handler implementations
+
+/// List the caller's external prefixes
+pub async fn list_external_prefixes(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+) -> Result<Json<ExternalPrefixListResponse>, ApiError> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+
+    match state.database.get_user_external_prefixes(&user_hash).await {
+        Ok(external) => Ok(Json(ExternalPrefixListResponse {
+            external_prefixes: external.into_iter().map(Into::into).collect(),
+        })),
+        Err(err) => {
+            error!("Failed to list external prefixes: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to list external prefixes",
+            ))
+        }
+    }
+}
+
+/// Register an external prefix for the caller, pending verification
+#[instrument(name = "handler", skip_all, fields(operation = "register_external_prefix", prefix = %request.prefix))]
+pub async fn register_external_prefix(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Json(request): Json<RegisterExternalPrefixRequest>,
+) -> Result<(StatusCode, Json<ExternalPrefixResponse>), ApiError> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    let net = validate_prefix(&state, &request.prefix)
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+
+    // Hold the user's lock so concurrent requests can't exceed the limit
+    let _guard = state.user_locks.lock(&user_hash).await;
+    let existing = state
+        .database
+        .get_user_external_prefixes(&user_hash)
+        .await
+        .map_err(|err| {
+            error!("Failed to count external prefixes: {}", err);
+            api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to register external prefix",
+            )
+        })?;
+    if existing.len() >= MAX_EXTERNAL_PREFIXES_PER_USER {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!(
+                "External prefix limit reached ({} per user)",
+                MAX_EXTERNAL_PREFIXES_PER_USER
+            ),
+        ));
+    }
+
+    let token = Uuid::new_v4().simple().to_string();
+    match state
+        .database
+        .create_external_prefix(&user_hash, &net.into(), &token)
+        .await
+    {
+        Ok(external) => Ok((StatusCode::CREATED, Json(external.into()))),
+        Err(err) if database::is_external_prefix_conflict(&err) => Err(api_error(
+            StatusCode::CONFLICT,
+            format!("Prefix {} overlaps an existing registration", net),
+        )),
+        Err(err) => {
+            error!("Failed to register external prefix: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to register external prefix",
+            ))
+        }
+    }
+}
+
+/// Check that the TXT challenge of a prefix is published
+async fn check_dns_challenge(state: &AppState, external: &ExternalPrefix) -> Result<(), ApiError> {
+    let net: Ipv6Net = external.prefix.parse().map_err(|_| {
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Stored external prefix is invalid",
+        )
+    })?;
+    let name = challenge_name(&net);
+    let records = lookup_txt(&state.dns_resolver, &name)
+        .await
+        .map_err(|err| {
+            error!(
+                "Failed to verify external prefix {}: {}",
+                external.prefix, err
+            );
+            api_error(StatusCode::SERVICE_UNAVAILABLE, err)
+        })?;
+    if !records.contains(&challenge_value(&external.token)) {
+        return Err(api_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("TXT record {} doesn't hold the challenge yet", name),
+        ));
+    }
+    Ok(())
+}
+
+/// Check that a ROA authorizes the lab's origin ASN for a prefix
+async fn check_roa(
+    validator: &rpki::RpkiValidator,
+    external: &ExternalPrefix,
+) -> Result<String, ApiError> {
+    let rpki_state = validator.validate(&external.prefix).await.map_err(|err| {
+        error!(
+            "Failed to validate external prefix {}: {}",
+            external.prefix, err
+        );
+        api_error(StatusCode::SERVICE_UNAVAILABLE, err)
+    })?;
+    if rpki_state != rpki::STATE_VALID {
+        return Err(api_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "No ROA authorizes AS{} to originate {} (RPKI state: {})",
+                validator.origin_asn(),
+                external.prefix,
+                rpki_state
+            ),
+        ));
+    }
+    Ok(rpki_state)
+}
+
+/// Check the challenge of one of the caller's external prefixes
+#[instrument(name = "handler", skip_all, fields(operation = "verify_external_prefix", prefix = tracing::field::Empty))]
+pub async fn verify_external_prefix(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<VerifyQuery>,
+) -> Result<Json<ExternalPrefixResponse>, ApiError> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    let method = query.method.as_deref().unwrap_or(METHOD_DNS);
+    if method != METHOD_DNS && method != METHOD_RPKI {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Unknown verification method '{}'", method),
+        ));
+    }
+
+    let external = match state
+        .database
+        .get_user_external_prefix(&user_hash, id)
+        .await
+    {
+        Ok(Some(external)) => external,
+        Ok(None) => return Err(not_found()),
+        Err(err) => {
+            error!("Failed to get external prefix: {}", err);
+            return Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to retrieve external prefix",
+            ));
+        }
+    };
+    tracing::Span::current().record("prefix", external.prefix.as_str());
+    if external.status == STATUS_VERIFIED {
+        return Ok(Json(external.into()));
+    }
+
+    let rpki_state = if method == METHOD_RPKI {
+        let validator = state.rpki_validator.as_ref().ok_or_else(|| {
+            api_error(StatusCode::BAD_REQUEST, "RPKI validation is not configured")
+        })?;
+        Some(check_roa(validator, &external).await?)
+    } else {
+        check_dns_challenge(&state, &external).await?;
+        // Exports also need a ROA; the scheduler retries if the validator fails
+        match &state.rpki_validator {
+            Some(validator) => validator
+                .validate(&external.prefix)
+                .await
+                .inspect_err(|err| warn!("Failed to validate {}: {}", external.prefix, err))
+                .ok(),
+            None => None,
+        }
+    };
+
+    let failed = |err: sqlx::Error| {
+        error!("Failed to mark external prefix as verified: {}", err);
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to verify external prefix",
+        )
+    };
+    let mut external = state
+        .database
+        .verify_external_prefix(external.id, method)
+        .await
+        .map_err(failed)?;
+    if let Some(rpki_state) = rpki_state {
+        external = state
+            .database
+            .set_external_prefix_rpki_state(external.id, &rpki_state)
+            .await
+            .map_err(failed)?;
+    }
+    info!(
+        "Verified external prefix {} of user {} through {}",
+        external.prefix, user_hash, method
+    );
+    if is_exported(&state, &external) {
+        state.agent_events.publish(AgentEvent::new(
+            events::EVENT_EXTERNAL_PREFIX_VERIFIED,
+            EventPriority::Normal,
+            json!({ "user_hash": user_hash, "prefix": external.prefix }),
+        ));
+    }
+    Ok(Json(external.into()))
+}
+
+/// Delete one of the caller's external prefixes
+pub async fn delete_external_prefix(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+
+    match state.database.delete_external_prefix(&user_hash, id).await {
+        Ok(Some(external)) => {
+            // Agents must stop accepting announcements of the prefix
+            if is_exported(&state, &external) {
+                state.agent_events.publish(AgentEvent::new(
+                    events::EVENT_INVALIDATE,
+                    EventPriority::High,
+                    json!({
+                        "resource": "external_prefix",
+                        "id": external.id,
+                        "user_hash": user_hash,
+                        "prefix": external.prefix,
+                    }),
+                ));
+            }
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(None) => Err(not_found()),
+        Err(err) => {
+            error!("Failed to delete external prefix: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to delete external prefix",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge_name() {
+        let net: Ipv6Net = "2001:db8:1234::/48".parse().unwrap();
+        assert_eq!(
+            challenge_name(&net),
+            "_peerlab-challenge.4.3.2.1.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+        // Rounded to the nibble covering the first address
+        let net: Ipv6Net = "2a0e:97c0:3e0::/45".parse().unwrap();
+        assert_eq!(reverse_zone(&net), "0.e.3.0.0.c.7.9.e.0.a.2.ip6.arpa");
+    }
+
+    #[tokio::test]
+    async fn test_validate_prefix() {
+        let config = crate::database::DatabaseConfig::new("postgresql://localhost/test".into());
+        let state = AppState::builder()
+            .database(crate::database::Database::connect_lazy(&config).unwrap())
+            .prefix_pool(crate::pool_prefixes::PrefixPool::new(vec![
+                "2a0e:97c0:3e0::/48".parse().unwrap(),
+            ]))
+            .build()
+            .unwrap();
+
+        assert!(validate_prefix(&state, "2a0e:97c0:3e1::/48").is_ok());
+        assert!(validate_prefix(&state, "2a0e:97c0:3e1::1/48").is_err());
+        assert!(validate_prefix(&state, "2a0e::/16").is_err());
+        assert!(validate_prefix(&state, "2001:db8::/32").is_err());
+        assert!(validate_prefix(&state, "fd00::/48").is_err());
+        assert!(validate_prefix(&state, "2a0e:97c0::/32").is_err());
+    }
+
+    #[test]
+    fn test_txt_content() {
+        assert_eq!(
+            txt_content("\"peerlab-verification=abc\""),
+            "peerlab-verification=abc"
+        );
+        assert_eq!(
+            txt_content("\"peerlab-\" \"verification=abc\""),
+            "peerlab-verification=abc"
+        );
+        assert_eq!(txt_content("plain"), "plain");
+    }
+}
@@ -0,0 +1,129 @@
+//! Postgres failover handling.
+//!
+//! Reads are retried once by the database layer when the connection drops.
+//! While the database stays unreachable, failed requests get a `503`
+//! "Database unavailable" instead of a generic `500`, and a background task
+//! pings the database until it answers again.
+
+use axum::{
+    Json,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::AppState;
+use crate::retry::RETRY_AFTER_FIELD;
+
+/// How often an unavailable database is checked
+pub const MONITOR_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a failed request waits on a ping before giving up on the database
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn unavailable() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!({
+            "error": 503,
+            "message": "Database unavailable",
+            RETRY_AFTER_FIELD: MONITOR_INTERVAL.as_secs()
+        })),
+    )
+        .into_response()
+}
+
+/// Whether the database answers a ping in time
+async fn reachable(state: &AppState) -> bool {
+    matches!(
+        tokio::time::timeout(PING_TIMEOUT, state.database.ping()).await,
+        Ok(Ok(()))
+    )
+}
+
+/// Turn internal errors caused by an unreachable database into `503`
+pub async fn report_unavailable(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    if response.status() != StatusCode::INTERNAL_SERVER_ERROR {
+        return response;
+    }
+    if !state.database.is_available() || !reachable(&state).await {
+        return unavailable();
+    }
+    response
+}
+
+/// Spawn the task checking an unavailable database until it recovers
+pub fn spawn(state: AppState) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(MONITOR_INTERVAL);
+        let mut down = false;
+        loop {
+            ticker.tick().await;
+            if state.database.is_available() {
+                down = false;
+                continue;
+            }
+            if !down {
+                warn!(
+                    "Database unavailable, checking every {:?}",
+                    MONITOR_INTERVAL
+                );
+                down = true;
+            }
+            // A successful ping marks the database available again
+            if reachable(&state).await {
+                down = false;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Database, DatabaseConfig};
+    use axum::{Router, body::Body, routing::get};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_errors_without_database_become_unavailable() {
+        let config = DatabaseConfig::new("postgresql://127.0.0.1:1/none".into())
+            .with_acquire_timeout(Duration::from_millis(100));
+        let state = AppState::builder()
+            .database(Database::connect_lazy(&config).unwrap())
+            .build()
+            .unwrap();
+        let router = Router::new()
+            .route("/fail", get(|| async { StatusCode::INTERNAL_SERVER_ERROR }))
+            .route("/missing", get(|| async { StatusCode::NOT_FOUND }))
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                report_unavailable,
+            ));
+        let call = |uri: &'static str| {
+            router
+                .clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        assert!(state.database.is_available());
+        assert_eq!(
+            call("/fail").await.unwrap().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert!(!state.database.is_available());
+        assert_eq!(
+            call("/missing").await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
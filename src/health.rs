@@ -0,0 +1,36 @@
+//! Health of the gateway and its dependencies.
+//!
+//! `GET /health` is unauthenticated so load balancers and status pages can
+//! poll it. The gateway keeps serving while the IdP is down by validating
+//! tokens against the keys it already has; that state is reported as
+//! `degraded` rather than failing the check, and so are an unreachable
+//! database, which requests already answer with `503` until it recovers,
+//! and exports withheld by their safety checks.
+
+use axum::{Json, extract::State};
+use serde_json::{Value, json};
+
+use crate::{AppState, jwt};
+
+/// Report the database, authentication and export status
+pub async fn get_health(State(state): State<AppState>) -> Json<Value> {
+    let database = state.database.is_available();
+    let degraded_since = jwt::auth_degraded_since().await;
+    let withheld_since = state.export_safety.failure().map(|failure| failure.since);
+    let status = match (database, degraded_since, withheld_since) {
+        (true, None, None) => "ok",
+        _ => "degraded",
+    };
+    Json(json!({
+        "status": status,
+        "database": if database { "up" } else { "down" },
+        "auth": {
+            "degraded": degraded_since.is_some(),
+            "degraded_since": degraded_since.map(|since| since.to_rfc3339()),
+        },
+        "exports": {
+            "withheld": withheld_since.is_some(),
+            "withheld_since": withheld_since.map(|since| since.to_rfc3339()),
+        },
+    }))
+}
@@ -0,0 +1,284 @@
+//! `Idempotency-Key` support on the allocation endpoints.
+//!
+//! A client retrying `POST /api/user/asn` or `POST /api/user/prefix` (after a
+//! timeout, or through a proxy replaying requests) sends the same
+//! `Idempotency-Key` header and gets the original response back instead of a
+//! second allocation. Successful responses are stored per user and key for
+//! the configured TTL; failed requests free their key so they can be retried.
+//! A key stays claimed while its request is processed, for at most the claim
+//! timeout: past it, a retry takes the claim over, so a request that never
+//! finished (the gateway restarting mid-request) doesn't hold its key until
+//! the TTL.
+
+use axum::{
+    Json,
+    body::{Body, to_bytes},
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::{debug, error, warn};
+
+use crate::jwt::AuthInfo;
+use crate::messages::{self, Message};
+use crate::retry::RETRY_AFTER_FIELD;
+use crate::{AppState, types::UserHash};
+
+/// Header carrying the client's key
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Header set on replayed responses
+pub const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+/// Default time a response is replayed for
+pub const DEFAULT_TTL_HOURS: i64 = 24;
+
+/// Default time a request may hold its key before a retry takes it over
+pub const DEFAULT_CLAIM_TIMEOUT_SECS: i64 = 60;
+
+/// Longest key accepted
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Largest request body handled
+const MAX_REQUEST_BODY: usize = 64 * 1024;
+
+/// Largest response stored, enough for the biggest bulk lease
+const MAX_RESPONSE_BODY: usize = 1024 * 1024;
+
+fn error_response(status: StatusCode, message: Message) -> Response {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.text(&[])
+        })),
+    )
+        .into_response()
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LENGTH && key.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Hash identifying a request, so a key reused for another request is caught
+fn request_hash(method: &str, uri: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_bytes());
+    hasher.update(b" ");
+    hasher.update(uri.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+fn replay(status: i16, body: String) -> Response {
+    let status = u16::try_from(status)
+        .ok()
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(StatusCode::OK);
+    let mut response = (status, body).into_response();
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    headers.insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+    response
+}
+
+fn in_progress() -> Response {
+    (
+        StatusCode::CONFLICT,
+        Json(json!({
+            "error": 409,
+            "message": messages::IDEMPOTENCY_IN_PROGRESS.text(&[]),
+            RETRY_AFTER_FIELD: 1
+        })),
+    )
+        .into_response()
+}
+
+/// Replay the stored response of a request made with the same
+/// `Idempotency-Key`, or process the request and store its response.
+///
+/// Layered inside the authentication middleware, which provides the user.
+pub async fn replay_responses(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = request.headers().get(&IDEMPOTENCY_KEY) else {
+        return next.run(request).await;
+    };
+    let Some(key) = key.to_str().ok().filter(|key| valid_key(key)) else {
+        return error_response(StatusCode::BAD_REQUEST, messages::IDEMPOTENCY_INVALID_KEY);
+    };
+    let key = key.to_string();
+    let Some(auth_info) = request.extensions().get::<AuthInfo>() else {
+        return next.run(request).await;
+    };
+    let user_hash: UserHash = state.identity.user_hash(&auth_info.identity);
+
+    let (parts, body) = request.into_parts();
+    let Ok(body) = to_bytes(body, MAX_REQUEST_BODY).await else {
+        return error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            messages::IDEMPOTENCY_BODY_TOO_LARGE,
+        );
+    };
+    let hash = request_hash(parts.method.as_str(), &parts.uri.to_string(), &body);
+    let request = Request::from_parts(parts, Body::from(body));
+
+    let now = Utc::now();
+    let claimed = state
+        .database
+        .claim_idempotency_key(
+            &user_hash,
+            &key,
+            &hash,
+            now - state.idempotency_ttl,
+            now - state.idempotency_claim_timeout,
+        )
+        .await;
+    let claimed_at = match claimed {
+        Ok(Some(claimed_at)) => claimed_at,
+        Ok(None) => {
+            return match state
+                .database
+                .get_idempotent_request(&user_hash, &key)
+                .await
+            {
+                Ok(Some(stored)) if stored.request_hash != hash => error_response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    messages::IDEMPOTENCY_KEY_REUSED,
+                ),
+                Ok(Some(stored)) => match (stored.status, stored.response) {
+                    (Some(status), Some(body)) => {
+                        debug!("Replaying response of key {} for user {}", key, user_hash);
+                        replay(status, body)
+                    }
+                    _ => in_progress(),
+                },
+                // Released by a request that just failed
+                Ok(None) => in_progress(),
+                Err(err) => {
+                    error!(
+                        "Failed to get idempotent request of user {}: {}",
+                        user_hash, err
+                    );
+                    error_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        messages::IDEMPOTENCY_CHECK_FAILED,
+                    )
+                }
+            };
+        }
+        Err(err) => {
+            error!(
+                "Failed to claim idempotency key of user {}: {}",
+                user_hash, err
+            );
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                messages::IDEMPOTENCY_CHECK_FAILED,
+            );
+        }
+    };
+
+    let release = || async {
+        if let Err(err) = state
+            .database
+            .release_idempotency_key(&user_hash, &key, claimed_at)
+            .await
+        {
+            warn!(
+                "Failed to release idempotency key of user {}: {}",
+                user_hash, err
+            );
+        }
+    };
+
+    let response = next.run(request).await;
+    if !response.status().is_success() {
+        // Nothing was allocated, the request may be retried with the same key
+        release().await;
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, MAX_RESPONSE_BODY).await {
+        Ok(body) => body,
+        Err(err) => {
+            error!("Failed to read response to store for key {}: {}", key, err);
+            release().await;
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let stored = match std::str::from_utf8(&body) {
+        Ok(text) => state
+            .database
+            .complete_idempotent_request(
+                &user_hash,
+                &key,
+                claimed_at,
+                parts.status.as_u16() as i16,
+                text,
+            )
+            .await
+            .map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    match stored {
+        Ok(true) => {}
+        // Past the claim timeout a retry took the key over, its response is kept
+        Ok(false) => warn!(
+            "Claim of key {} for user {} was taken over, response not stored",
+            key, user_hash
+        ),
+        Err(err) => {
+            // Better a possible duplicate on retry than a key stuck in progress
+            warn!(
+                "Failed to store response of key {} for user {}: {}",
+                key, user_hash, err
+            );
+            release().await;
+        }
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_and_hashes() {
+        assert!(valid_key("4b1d2c3e-retry-1"));
+        assert!(!valid_key(""));
+        assert!(!valid_key("with space"));
+        assert!(!valid_key(&"k".repeat(256)));
+
+        let hash = request_hash("POST", "/api/user/prefix", br#"{"duration_hours":1}"#);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(
+            hash,
+            request_hash("POST", "/api/user/prefix", br#"{"duration_hours":1}"#)
+        );
+        assert_ne!(
+            hash,
+            request_hash("POST", "/api/user/prefix", br#"{"duration_hours":2}"#)
+        );
+        assert_ne!(
+            hash,
+            request_hash(
+                "POST",
+                "/api/user/prefix?dry_run=true",
+                br#"{"duration_hours":1}"#
+            )
+        );
+    }
+}
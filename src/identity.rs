@@ -0,0 +1,264 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::jwt::Claims;
+use crate::types::UserHash;
+
+/// Normalization applied to the identity claim before hashing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityNormalization {
+    /// Use the claim value as-is (surrounding whitespace is still trimmed)
+    #[default]
+    None,
+    /// Lowercase the claim value (useful for email-based identities)
+    Lowercase,
+}
+
+impl FromStr for IdentityNormalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "lowercase" => Ok(Self::Lowercase),
+            other => Err(format!(
+                "Unknown identity normalization '{}' (expected 'none' or 'lowercase')",
+                other
+            )),
+        }
+    }
+}
+
+/// Algorithm deriving user hashes from normalized identities.
+///
+/// Changing it changes every user hash, orphaning existing mappings and
+/// leases, so it is picked once per deployment.
+#[derive(Clone, PartialEq, Eq, Default)]
+pub enum IdentityHashing {
+    /// Plain SHA-256 of the identity
+    #[default]
+    Sha256,
+    /// HMAC-SHA256 keyed with a secret, so hashes can't be matched against
+    /// identities known to whoever reads them
+    HmacSha256(Vec<u8>),
+}
+
+impl IdentityHashing {
+    /// Build a strategy from its name (`sha256` or `hmac-sha256`) and key
+    pub fn new(algorithm: &str, key: Option<&str>) -> Result<Self, String> {
+        match (algorithm.to_ascii_lowercase().as_str(), key) {
+            ("sha256", None) => Ok(Self::Sha256),
+            ("sha256", Some(_)) => Err("sha256 identity hashing doesn't take a key".to_string()),
+            ("hmac-sha256", Some(key)) if !key.is_empty() => {
+                Ok(Self::HmacSha256(key.as_bytes().to_vec()))
+            }
+            ("hmac-sha256", _) => Err("hmac-sha256 identity hashing needs a key".to_string()),
+            (other, _) => Err(format!(
+                "Unknown identity hashing '{}' (expected 'sha256' or 'hmac-sha256')",
+                other
+            )),
+        }
+    }
+
+    /// Hex hash of a normalized identity
+    pub fn hash(&self, identity: &str) -> String {
+        match self {
+            Self::Sha256 => crate::hash_user_identifier(identity),
+            Self::HmacSha256(key) => {
+                let mut mac =
+                    Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+                mac.update(identity.as_bytes());
+                hex::encode(mac.finalize().into_bytes())
+            }
+        }
+    }
+}
+
+// Keys stay out of logs
+impl fmt::Debug for IdentityHashing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sha256 => f.write_str("Sha256"),
+            Self::HmacSha256(_) => f.write_str("HmacSha256(..)"),
+        }
+    }
+}
+
+/// Describes which JWT claim identifies a user, how it is normalized and how
+/// it is hashed into the user hash keying all of the user's resources.
+///
+/// Claims are tried in order and the first non-empty string value wins, so a
+/// mapping like `["email", "sub"]` prefers the email but still works for
+/// tokens that don't carry one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentityMapping {
+    claims: Vec<String>,
+    normalization: IdentityNormalization,
+    hashing: IdentityHashing,
+}
+
+impl Default for IdentityMapping {
+    fn default() -> Self {
+        Self {
+            claims: vec!["sub".to_string()],
+            normalization: IdentityNormalization::None,
+            hashing: IdentityHashing::Sha256,
+        }
+    }
+}
+
+impl IdentityMapping {
+    /// Create a new identity mapping from an ordered list of claim names
+    pub fn new(claims: Vec<String>, normalization: IdentityNormalization) -> Self {
+        let claims: Vec<String> = claims
+            .into_iter()
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty())
+            .collect();
+
+        if claims.is_empty() {
+            return Self {
+                normalization,
+                ..Self::default()
+            };
+        }
+
+        Self {
+            claims,
+            normalization,
+            hashing: IdentityHashing::default(),
+        }
+    }
+
+    /// Hash identities with `hashing` instead of plain SHA-256
+    pub fn with_hashing(mut self, hashing: IdentityHashing) -> Self {
+        self.hashing = hashing;
+        self
+    }
+
+    /// Get the ordered list of claims used to identify a user
+    pub fn claims(&self) -> &[String] {
+        &self.claims
+    }
+
+    /// Get the normalization applied to the identity
+    pub fn normalization(&self) -> IdentityNormalization {
+        self.normalization
+    }
+
+    /// Get the algorithm deriving user hashes
+    pub fn hashing(&self) -> &IdentityHashing {
+        &self.hashing
+    }
+
+    /// Hash a normalized identity into its user hash
+    pub fn user_hash(&self, identity: &str) -> UserHash {
+        UserHash::from_digest(self.hashing.hash(identity))
+    }
+
+    /// Extract the normalized identity from a set of JWT claims
+    pub fn extract(&self, claims: &Claims) -> Option<String> {
+        self.claims.iter().find_map(|name| {
+            claims
+                .get_str(name)
+                .map(|v| self.normalize(v))
+                .filter(|v| !v.is_empty())
+        })
+    }
+
+    /// Apply the configured normalization to a raw identity value
+    pub fn normalize(&self, value: &str) -> String {
+        let value = value.trim();
+        match self.normalization {
+            IdentityNormalization::None => value.to_string(),
+            IdentityNormalization::Lowercase => value.to_lowercase(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+
+    fn claims(value: Value) -> Claims {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_default_mapping_uses_sub() {
+        let mapping = IdentityMapping::default();
+        let claims = claims(json!({ "sub": "auth0|123", "email": "User@Example.com" }));
+        assert_eq!(mapping.extract(&claims), Some("auth0|123".to_string()));
+    }
+
+    #[test]
+    fn test_email_mapping_is_lowercased() {
+        let mapping =
+            IdentityMapping::new(vec!["email".to_string()], IdentityNormalization::Lowercase);
+        let claims = claims(json!({ "sub": "auth0|123", "email": " User@Example.com " }));
+        assert_eq!(
+            mapping.extract(&claims),
+            Some("user@example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_mapping_falls_back_to_next_claim() {
+        let mapping = IdentityMapping::new(
+            vec!["https://example.com/uid".to_string(), "sub".to_string()],
+            IdentityNormalization::None,
+        );
+        let claims = claims(json!({ "sub": "auth0|123", "https://example.com/uid": "" }));
+        assert_eq!(mapping.extract(&claims), Some("auth0|123".to_string()));
+    }
+
+    #[test]
+    fn test_mapping_missing_claim() {
+        let mapping = IdentityMapping::new(vec!["email".to_string()], IdentityNormalization::None);
+        let claims = claims(json!({ "sub": "auth0|123" }));
+        assert_eq!(mapping.extract(&claims), None);
+    }
+
+    #[test]
+    fn test_parse_normalization() {
+        assert_eq!(
+            "lowercase".parse::<IdentityNormalization>(),
+            Ok(IdentityNormalization::Lowercase)
+        );
+        assert_eq!(
+            "None".parse::<IdentityNormalization>(),
+            Ok(IdentityNormalization::None)
+        );
+        assert!("upper".parse::<IdentityNormalization>().is_err());
+    }
+
+    #[test]
+    fn test_identity_hashing() {
+        let mapping = IdentityMapping::default();
+        assert_eq!(
+            mapping.user_hash("auth0|123"),
+            crate::hash_user_identifier("auth0|123")
+        );
+
+        let keyed = IdentityHashing::new("hmac-sha256", Some("secret")).unwrap();
+        let mapping = IdentityMapping::default().with_hashing(keyed.clone());
+        let hash = mapping.user_hash("auth0|123");
+        assert_eq!(hash.len(), 64);
+        assert_ne!(hash, crate::hash_user_identifier("auth0|123"));
+        assert_ne!(
+            hash,
+            IdentityHashing::new("hmac-sha256", Some("other"))
+                .unwrap()
+                .hash("auth0|123")
+        );
+        assert_eq!(format!("{:?}", keyed), "HmacSha256(..)");
+
+        assert!(IdentityHashing::new("hmac-sha256", None).is_err());
+        assert!(IdentityHashing::new("sha256", Some("secret")).is_err());
+        assert!(IdentityHashing::new("md5", None).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::{HeaderMap, Method, StatusCode},
+};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::database::Impersonation;
+use crate::jwt::{AuthErrorReason, AuthInfo, AuthorizationError};
+use crate::types::UserHash;
+
+/// Header carrying an impersonation token on client API requests
+pub const IMPERSONATION_HEADER: &str = "x-impersonation-token";
+
+/// Default lifetime of an impersonation grant
+pub const DEFAULT_DURATION_MINUTES: i64 = 15;
+/// Longest lifetime of an impersonation grant
+pub const MAX_DURATION_MINUTES: i64 = 60;
+
+/// Generate a new impersonation token
+pub fn generate_token() -> String {
+    format!("imp_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Tokens are stored hashed; only the admin who created a grant sees the token
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Resolve the impersonated user of a request carrying an impersonation token.
+///
+/// Impersonation is read-only: anything but `GET` and `HEAD` is rejected so
+/// support staff can't change a user's resources. Every use is logged and
+/// counted on the grant.
+pub async fn authenticate(
+    state: &AppState,
+    method: &Method,
+    path: &str,
+    headers: &HeaderMap,
+) -> Result<Option<AuthInfo>, AuthorizationError> {
+    let Some(token) = headers.get(IMPERSONATION_HEADER) else {
+        return Ok(None);
+    };
+    let token = token.to_str().map_err(|_| {
+        AuthorizationError::new(
+            AuthErrorReason::MalformedToken,
+            "Impersonation token is not valid UTF-8",
+        )
+    })?;
+
+    if !matches!(*method, Method::GET | Method::HEAD) {
+        return Err(AuthorizationError::new(
+            AuthErrorReason::Forbidden,
+            "Impersonation is read-only",
+        ));
+    }
+
+    let impersonation = match state.database.use_impersonation(&hash_token(token)).await {
+        Ok(Some(impersonation)) => impersonation,
+        Ok(None) => {
+            warn!("Rejected unknown, expired or revoked impersonation token");
+            return Err(AuthorizationError::new(
+                AuthErrorReason::InvalidToken,
+                "Impersonation token is invalid, expired or revoked",
+            ));
+        }
+        Err(err) => {
+            error!("Failed to load impersonation: {}", err);
+            return Err(AuthorizationError::new(
+                AuthErrorReason::IdpUnavailable,
+                "Failed to load impersonation",
+            ));
+        }
+    };
+
+    info!(
+        "Impersonation {} of user {} ({}): {} {}",
+        impersonation.id, impersonation.user_hash, impersonation.reason, method, path
+    );
+    state.audit.record(
+        "impersonation.used",
+        json!({
+            "id": impersonation.id,
+            "user_hash": impersonation.user_hash,
+            "method": method.as_str(),
+            "path": path,
+        }),
+    );
+
+    let mut auth_info = AuthInfo::new(
+        format!("impersonation:{}", impersonation.id),
+        impersonation.identity,
+        None,
+        None,
+        None,
+        Vec::new(),
+        Vec::new(),
+    );
+    auth_info.expires_at = Some(impersonation.expires_at.timestamp());
+    auth_info.token_id = Some(impersonation.id.to_string());
+    Ok(Some(auth_info))
+}
+
+// Request/Response types
+
+#[derive(Deserialize)]
+pub struct CreateImpersonationRequest {
+    /// Identity claim value of the user, as it appears in their tokens
+    identity: String,
+    /// Why the user is impersonated (e.g. a support ticket), kept for auditing
+    reason: String,
+    duration_minutes: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ImpersonationResponse {
+    id: Uuid,
+    user_hash: UserHash,
+    reason: String,
+    expires_at: String,
+    revoked_at: Option<String>,
+    last_used_at: Option<String>,
+    use_count: i32,
+    created_at: String,
+    /// Only returned when the grant is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+impl ImpersonationResponse {
+    fn new(impersonation: Impersonation, token: Option<String>) -> Self {
+        Self {
+            id: impersonation.id,
+            user_hash: impersonation.user_hash,
+            reason: impersonation.reason,
+            expires_at: impersonation.expires_at.to_rfc3339(),
+            revoked_at: impersonation.revoked_at.map(|t| t.to_rfc3339()),
+            last_used_at: impersonation.last_used_at.map(|t| t.to_rfc3339()),
+            use_count: impersonation.use_count,
+            created_at: impersonation.created_at.to_rfc3339(),
+            token,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ImpersonationListResponse {
+    impersonations: Vec<ImpersonationResponse>,
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.into()
+        })),
+    )
+}
+
+/// Validate an impersonation request, returning the grant duration
+fn validate_request(request: &CreateImpersonationRequest) -> Result<Duration, String> {
+    if request.identity.trim().is_empty() {
+        return Err("Identity must not be empty".to_string());
+    }
+    if request.reason.trim().is_empty() {
+        return Err("A reason is required".to_string());
+    }
+    let minutes = request.duration_minutes.unwrap_or(DEFAULT_DURATION_MINUTES);
+    if !(1..=MAX_DURATION_MINUTES).contains(&minutes) {
+        return Err(format!(
+            "Duration must be between 1 and {} minutes",
+            MAX_DURATION_MINUTES
+        ));
+    }
+    Ok(Duration::minutes(minutes))
+}
+
+// Handlers
+
+/// List impersonation grants (the audit trail)
+pub async fn list_impersonations(
+    State(state): State<AppState>,
+) -> Result<Json<ImpersonationListResponse>, ApiError> {
+    match state.database.get_impersonations().await {
+        Ok(impersonations) => Ok(Json(ImpersonationListResponse {
+            impersonations: impersonations
+                .into_iter()
+                .map(|i| ImpersonationResponse::new(i, None))
+                .collect(),
+        })),
+        Err(err) => {
+            error!("Failed to list impersonations: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to list impersonations",
+            ))
+        }
+    }
+}
+
+/// Grant a time-limited impersonation token
+pub async fn create_impersonation(
+    State(state): State<AppState>,
+    Json(request): Json<CreateImpersonationRequest>,
+) -> Result<(StatusCode, Json<ImpersonationResponse>), ApiError> {
+    let duration = validate_request(&request).map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+
+    let identity = state.identity.normalize(request.identity.trim());
+    let user_hash = state.identity.user_hash(&identity);
+    let token = generate_token();
+
+    match state
+        .database
+        .create_impersonation(
+            &hash_token(&token),
+            &identity,
+            &user_hash,
+            request.reason.trim(),
+            Utc::now() + duration,
+        )
+        .await
+    {
+        Ok(impersonation) => {
+            info!(
+                "Granted impersonation {} of user {} until {} ({})",
+                impersonation.id, user_hash, impersonation.expires_at, impersonation.reason
+            );
+            state.audit.record(
+                "impersonation.granted",
+                json!({
+                    "id": impersonation.id,
+                    "user_hash": user_hash,
+                    "expires_at": impersonation.expires_at.to_rfc3339(),
+                    "reason": impersonation.reason,
+                }),
+            );
+            Ok((
+                StatusCode::CREATED,
+                Json(ImpersonationResponse::new(impersonation, Some(token))),
+            ))
+        }
+        Err(err) => {
+            error!("Failed to create impersonation: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to create impersonation",
+            ))
+        }
+    }
+}
+
+/// Revoke an impersonation grant
+pub async fn revoke_impersonation(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    match state.database.revoke_impersonation(id).await {
+        Ok(true) => {
+            info!("Revoked impersonation {}", id);
+            state
+                .audit
+                .record("impersonation.revoked", json!({ "id": id }));
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err(api_error(
+            StatusCode::NOT_FOUND,
+            "Impersonation not found or already revoked",
+        )),
+        Err(err) => {
+            error!("Failed to revoke impersonation: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to revoke impersonation",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(identity: &str, reason: &str, minutes: Option<i64>) -> CreateImpersonationRequest {
+        CreateImpersonationRequest {
+            identity: identity.to_string(),
+            reason: reason.to_string(),
+            duration_minutes: minutes,
+        }
+    }
+
+    #[test]
+    fn test_validate_request() {
+        assert_eq!(
+            validate_request(&request("auth0|alice", "ticket 42", None)),
+            Ok(Duration::minutes(DEFAULT_DURATION_MINUTES))
+        );
+        assert!(validate_request(&request("auth0|alice", "ticket 42", Some(60))).is_ok());
+        assert!(validate_request(&request("auth0|alice", "ticket 42", Some(61))).is_err());
+        assert!(validate_request(&request("auth0|alice", "ticket 42", Some(0))).is_err());
+        assert!(validate_request(&request(" ", "ticket 42", None)).is_err());
+        assert!(validate_request(&request("auth0|alice", "", None)).is_err());
+    }
+
+    #[test]
+    fn test_tokens_are_hashed() {
+        let token = generate_token();
+        assert!(token.starts_with("imp_"));
+        assert_ne!(token, generate_token());
+        assert_eq!(hash_token(&token).len(), 64);
+        assert_ne!(hash_token(&token), token);
+    }
+}
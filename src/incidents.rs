@@ -0,0 +1,341 @@
+//! Incident records of users.
+//!
+//! Incidents come from abuse reports or are opened by admins against a user,
+//! an ASN or a lease. They stay attached to the user holding the resource at
+//! the time, so their history is at hand when deciding on a suspension.
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::database::{Incident, IncidentComment, NewIncident};
+use crate::types::{Asn, UserHash};
+
+/// Source of incidents opened by admins
+pub const SOURCE_ADMIN: &str = "admin";
+
+pub const STATUS_OPEN: &str = "open";
+pub const STATUS_CLOSED: &str = "closed";
+
+// Request/Response types
+
+#[derive(Deserialize)]
+pub struct IncidentQuery {
+    user_hash: Option<String>,
+    status: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct OpenIncidentRequest {
+    user_hash: Option<String>,
+    asn: Option<Asn>,
+    lease_id: Option<Uuid>,
+    description: String,
+}
+
+#[derive(Deserialize)]
+pub struct CommentRequest {
+    author: Option<String>,
+    body: String,
+}
+
+#[derive(Deserialize)]
+pub struct CloseIncidentRequest {
+    resolution: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct IncidentResponse {
+    id: Uuid,
+    user_hash: Option<UserHash>,
+    asn: Option<i64>,
+    lease_id: Option<Uuid>,
+    prefix: Option<String>,
+    source: String,
+    description: String,
+    reporter_contact: Option<String>,
+    status: String,
+    resolution: Option<String>,
+    created_at: String,
+    updated_at: String,
+    closed_at: Option<String>,
+    /// Only returned when a single incident is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comments: Option<Vec<CommentResponse>>,
+}
+
+impl IncidentResponse {
+    fn new(incident: Incident, comments: Option<Vec<IncidentComment>>) -> Self {
+        Self {
+            id: incident.id,
+            user_hash: incident.user_hash,
+            asn: incident.asn,
+            lease_id: incident.lease_id,
+            prefix: incident.prefix,
+            source: incident.source,
+            description: incident.description,
+            reporter_contact: incident.reporter_contact,
+            status: incident.status,
+            resolution: incident.resolution,
+            created_at: incident.created_at.to_rfc3339(),
+            updated_at: incident.updated_at.to_rfc3339(),
+            closed_at: incident.closed_at.map(|t| t.to_rfc3339()),
+            comments: comments.map(|c| c.into_iter().map(CommentResponse::from).collect()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CommentResponse {
+    id: Uuid,
+    author: Option<String>,
+    body: String,
+    created_at: String,
+}
+
+impl From<IncidentComment> for CommentResponse {
+    fn from(comment: IncidentComment) -> Self {
+        Self {
+            id: comment.id,
+            author: comment.author,
+            body: comment.body,
+            created_at: comment.created_at.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct IncidentListResponse {
+    incidents: Vec<IncidentResponse>,
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.into()
+        })),
+    )
+}
+
+fn internal_error(err: sqlx::Error) -> ApiError {
+    error!("Failed to access incidents: {}", err);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to access incidents",
+    )
+}
+
+/// Trim an optional text field, treating blank as absent
+fn non_blank(value: Option<&str>) -> Option<&str> {
+    value.map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// Find the user an incident is attached to, from the user given and the
+/// holders of the lease and ASN given
+fn resolve_user(
+    user_hash: Option<&str>,
+    lease_holder: Option<&str>,
+    asn_holder: Option<&str>,
+) -> Result<UserHash, String> {
+    let mut holders = [user_hash, lease_holder, asn_holder].into_iter().flatten();
+    let Some(user) = holders.next() else {
+        return Err("A user_hash, asn or lease_id is required".to_string());
+    };
+    if holders.any(|other| other != user) {
+        return Err("The user, ASN and lease given don't belong to the same user".to_string());
+    }
+    user.parse()
+}
+
+// Handlers
+
+/// List incidents, newest first
+pub async fn list_incidents(
+    State(state): State<AppState>,
+    Query(query): Query<IncidentQuery>,
+) -> Result<Json<IncidentListResponse>, ApiError> {
+    let status = non_blank(query.status.as_deref());
+    if let Some(status) = status
+        && status != STATUS_OPEN
+        && status != STATUS_CLOSED
+    {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Unknown status '{}' (expected open or closed)", status),
+        ));
+    }
+    let incidents = state
+        .database
+        .get_incidents(non_blank(query.user_hash.as_deref()), status)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(IncidentListResponse {
+        incidents: incidents
+            .into_iter()
+            .map(|i| IncidentResponse::new(i, None))
+            .collect(),
+    }))
+}
+
+/// Open an incident against a user, an ASN or a lease
+pub async fn open_incident(
+    State(state): State<AppState>,
+    Json(request): Json<OpenIncidentRequest>,
+) -> Result<(StatusCode, Json<IncidentResponse>), ApiError> {
+    let description = request.description.trim();
+    if description.is_empty() {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "A description is required",
+        ));
+    }
+
+    let lease = match request.lease_id {
+        Some(id) => Some(
+            state
+                .database
+                .get_prefix_lease(id)
+                .await
+                .map_err(internal_error)?
+                .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Lease not found"))?,
+        ),
+        None => None,
+    };
+    let mapping = match request.asn {
+        Some(asn) => Some(
+            state
+                .database
+                .get_asn_mapping(asn)
+                .await
+                .map_err(internal_error)?
+                .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "ASN is not assigned"))?,
+        ),
+        None => None,
+    };
+    let user_hash = resolve_user(
+        non_blank(request.user_hash.as_deref()),
+        lease.as_ref().map(|l| l.user_hash.as_str()),
+        mapping.as_ref().map(|m| m.user_hash.as_str()),
+    )
+    .map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+
+    let incident = state
+        .database
+        .create_incident(&NewIncident {
+            user_hash: Some(&user_hash),
+            asn: request.asn,
+            lease_id: request.lease_id,
+            prefix: lease.as_ref().map(|l| l.prefix.as_str()),
+            source: SOURCE_ADMIN,
+            description,
+            reporter_contact: None,
+        })
+        .await
+        .map_err(internal_error)?;
+    info!("Opened incident {} for user {}", incident.id, user_hash);
+    Ok((
+        StatusCode::CREATED,
+        Json(IncidentResponse::new(incident, None)),
+    ))
+}
+
+/// Get an incident with its comments
+pub async fn get_incident(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<IncidentResponse>, ApiError> {
+    let incident = state
+        .database
+        .get_incident(id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Incident not found"))?;
+    let comments = state
+        .database
+        .get_incident_comments(id)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(IncidentResponse::new(incident, Some(comments))))
+}
+
+/// Comment on an incident
+pub async fn add_comment(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(request): Json<CommentRequest>,
+) -> Result<(StatusCode, Json<CommentResponse>), ApiError> {
+    let body = request.body.trim();
+    if body.is_empty() {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "The comment must not be empty",
+        ));
+    }
+    match state
+        .database
+        .add_incident_comment(id, non_blank(request.author.as_deref()), body)
+        .await
+        .map_err(internal_error)?
+    {
+        Some(comment) => Ok((StatusCode::CREATED, Json(comment.into()))),
+        None => Err(api_error(StatusCode::NOT_FOUND, "Incident not found")),
+    }
+}
+
+/// Close an open incident
+pub async fn close_incident(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(request): Json<CloseIncidentRequest>,
+) -> Result<Json<IncidentResponse>, ApiError> {
+    match state
+        .database
+        .close_incident(id, non_blank(request.resolution.as_deref()))
+        .await
+        .map_err(internal_error)?
+    {
+        Some(incident) => {
+            info!("Closed incident {}", id);
+            Ok(Json(IncidentResponse::new(incident, None)))
+        }
+        None => Err(api_error(
+            StatusCode::NOT_FOUND,
+            "Incident not found or already closed",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_user() {
+        let (a, b) = ("a".repeat(64), "b".repeat(64));
+        assert_eq!(resolve_user(Some(&a), None, None), a.parse());
+        assert_eq!(resolve_user(None, Some(&a), Some(&a)), a.parse());
+        assert!(resolve_user(None, None, None).is_err());
+        assert!(resolve_user(Some(&a), None, Some(&b)).is_err());
+        assert!(resolve_user(None, Some(&a), Some(&b)).is_err());
+        // Users given are validated
+        assert!(resolve_user(Some("abc"), None, None).is_err());
+    }
+
+    #[test]
+    fn test_non_blank() {
+        assert_eq!(non_blank(Some(" spam ")), Some("spam"));
+        assert_eq!(non_blank(Some("  ")), None);
+        assert_eq!(non_blank(None), None);
+    }
+}
@@ -1,36 +1,49 @@
 use axum::{
     Json,
     extract::{Request, State},
-    http::StatusCode,
+    http::{HeaderValue, StatusCode, header},
     middleware::Next,
     response::{IntoResponse, Response},
 };
-use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header, errors::ErrorKind};
 use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::{Value, json};
-use std::{collections::HashMap, fmt, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use tokio::sync::RwLock;
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 
 use crate::AppState;
+use crate::identity::IdentityMapping;
 
 // JWT configuration functions to get values from AppState
 pub fn jwks_uri(state: &AppState) -> Result<String, AuthorizationError> {
-    state
-        .auth0_jwks_uri
-        .clone()
-        .ok_or_else(|| AuthorizationError::with_status("AUTH0_JWKS_URI is not configured", 500))
+    state.auth0_jwks_uri.clone().ok_or_else(|| {
+        AuthorizationError::new(
+            AuthErrorReason::Misconfigured,
+            "AUTH0_JWKS_URI is not configured",
+        )
+    })
 }
 
 pub fn issuer(state: &AppState) -> Result<String, AuthorizationError> {
-    state
-        .auth0_issuer
-        .clone()
-        .ok_or_else(|| AuthorizationError::with_status("AUTH0_ISSUER is not configured", 500))
+    state.auth0_issuer.clone().ok_or_else(|| {
+        AuthorizationError::new(
+            AuthErrorReason::Misconfigured,
+            "AUTH0_ISSUER is not configured",
+        )
+    })
 }
 
 // For configuring HTTP client with reasonable timeouts
+#[cfg(feature = "auth0")]
 fn create_http_client() -> reqwest::Client {
     reqwest::Client::builder()
         .timeout(Duration::from_secs(10))
@@ -53,21 +66,68 @@ const JWKS_CACHE_DURATION: Duration = Duration::from_secs(12 * 60 * 60);
 static LAST_JWKS_REFRESH: Lazy<Arc<RwLock<Option<std::time::Instant>>>> =
     Lazy::new(|| Arc::new(RwLock::new(None)));
 
-// No longer needed - we get the bypass flag directly from AppState
+// How long to wait before retrying a failed refresh while serving cached keys
+const JWKS_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// Refreshes failing while cached keys are still served
+#[derive(Clone, Copy)]
+struct JwksDegraded {
+    since: DateTime<Utc>,
+    last_attempt: std::time::Instant,
+}
+
+static JWKS_DEGRADED: Lazy<Arc<RwLock<Option<JwksDegraded>>>> =
+    Lazy::new(|| Arc::new(RwLock::new(None)));
+
+/// Since when tokens are validated against cached keys because the JWKS
+/// can't be refreshed, if they are
+pub async fn auth_degraded_since() -> Option<DateTime<Utc>> {
+    JWKS_DEGRADED.read().await.map(|degraded| degraded.since)
+}
+
+// Modification time of the local JWKS file when it was last loaded
+static JWKS_FILE_MODIFIED: Lazy<Arc<RwLock<Option<SystemTime>>>> =
+    Lazy::new(|| Arc::new(RwLock::new(None)));
+
+// Get the modification time of the local JWKS file, if configured and readable
+async fn jwks_file_modified(state: &AppState) -> Option<SystemTime> {
+    let path = state.jwks_file.as_ref()?;
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+/// Validates bearer tokens in place of the JWKS, for embedders with their
+/// own IdP integration and for tests
+pub trait TokenValidator: Send + Sync {
+    /// Authenticate a token, or tell why it is refused
+    fn validate<'a>(
+        &'a self,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<AuthInfo, AuthorizationError>>;
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthInfo {
     pub sub: String,
+    /// Normalized identity used to derive the user hash (see `IdentityMapping`)
+    pub identity: String,
     pub email: Option<String>,
     pub client_id: Option<String>,
     pub organization_id: Option<String>,
     pub scopes: Vec<String>,
     pub audience: Vec<String>,
+    /// Token expiry (`exp` claim, seconds since epoch)
+    pub expires_at: Option<i64>,
+    /// Token identifier (`jti` claim)
+    pub token_id: Option<String>,
 }
 
 impl AuthInfo {
     pub fn new(
         sub: String,
+        identity: String,
         email: Option<String>,
         client_id: Option<String>,
         organization_id: Option<String>,
@@ -76,34 +136,198 @@ impl AuthInfo {
     ) -> Self {
         Self {
             sub,
+            identity,
             email,
             client_id,
             organization_id,
             scopes,
             audience,
+            expires_at: None,
+            token_id: None,
+        }
+    }
+}
+
+/// JWT claims as issued by the IdP.
+///
+/// Deserialization is deliberately tolerant: IdPs disagree on whether `aud`
+/// and `scope` are strings or arrays, and several omit `iat` or send numeric
+/// dates as floats. Unknown claims are kept in `extra` so they can still be
+/// used as identity claims.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Claims {
+    #[serde(default)]
+    pub sub: Option<String>,
+    #[serde(default, deserialize_with = "string_or_seq")]
+    pub aud: Vec<String>,
+    #[serde(default)]
+    pub iss: Option<String>,
+    #[serde(default, deserialize_with = "numeric_date")]
+    pub iat: Option<i64>,
+    #[serde(default, deserialize_with = "numeric_date")]
+    pub exp: Option<i64>,
+    #[serde(default)]
+    pub jti: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default, alias = "azp")]
+    pub client_id: Option<String>,
+    #[serde(default, alias = "org_id")]
+    pub organization_id: Option<String>,
+    #[serde(default, alias = "scp", deserialize_with = "string_or_seq")]
+    pub scope: Vec<String>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+impl Claims {
+    /// Look up a string claim by name, whether it is a well-known or custom claim
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        match name {
+            "sub" => self.sub.as_deref(),
+            "iss" => self.iss.as_deref(),
+            "jti" => self.jti.as_deref(),
+            "email" => self.email.as_deref(),
+            "client_id" | "azp" => self.client_id.as_deref(),
+            "organization_id" | "org_id" => self.organization_id.as_deref(),
+            _ => self.extra.get(name).and_then(|v| v.as_str()),
+        }
+    }
+}
+
+/// Accept either a single string or an array of strings. A single string is
+/// split on whitespace, which matches how OAuth encodes `scope`.
+fn string_or_seq<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<Value>::deserialize(deserializer)?;
+    Ok(match value {
+        Some(Value::String(s)) => s.split_whitespace().map(|s| s.to_string()).collect(),
+        Some(Value::Array(arr)) => arr
+            .into_iter()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .collect(),
+        _ => Vec::new(),
+    })
+}
+
+/// Accept numeric dates as integers, floats, or numeric strings
+fn numeric_date<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<Value>::deserialize(deserializer)?;
+    Ok(match value {
+        Some(Value::Number(n)) => n.as_i64().or_else(|| n.as_f64().map(|f| f as i64)),
+        Some(Value::String(s)) => s.trim().parse::<f64>().ok().map(|f| f as i64),
+        _ => None,
+    })
+}
+
+/// Machine-readable reason attached to authentication/authorization failures
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthErrorReason {
+    MissingToken,
+    MalformedToken,
+    InvalidToken,
+    Expired,
+    BadIssuer,
+    BadAudience,
+    Revoked,
+    Forbidden,
+    IdpUnavailable,
+    Misconfigured,
+}
+
+impl AuthErrorReason {
+    /// HTTP status code returned for this reason
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::Forbidden => 403,
+            Self::IdpUnavailable => 503,
+            Self::Misconfigured => 500,
+            _ => 401,
+        }
+    }
+
+    /// Client-facing description, which never includes token or key material
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::MissingToken => "Authorization header is missing",
+            Self::MalformedToken => "Authorization header must contain a Bearer token",
+            Self::InvalidToken => "Token is invalid",
+            Self::Expired => "Token has expired",
+            Self::BadIssuer => "Token was issued by an untrusted issuer",
+            Self::BadAudience => "Token is not intended for this API",
+            Self::Revoked => "Token has been revoked",
+            Self::Forbidden => "Insufficient permissions",
+            Self::IdpUnavailable => "Identity provider is unavailable",
+            Self::Misconfigured => "Authentication is not configured",
+        }
+    }
+
+    /// RFC 6750 error code for the `WWW-Authenticate` header
+    fn bearer_error(&self) -> Option<&'static str> {
+        match self {
+            Self::MissingToken => None,
+            Self::MalformedToken => Some("invalid_request"),
+            Self::Forbidden => Some("insufficient_scope"),
+            Self::IdpUnavailable | Self::Misconfigured => None,
+            _ => Some("invalid_token"),
         }
     }
 }
 
 #[derive(Debug)]
 pub struct AuthorizationError {
+    pub reason: AuthErrorReason,
+    /// Internal detail, logged but never returned to the client
     pub message: String,
     pub status_code: u16,
+    /// `exp` of an expired token, and where to refresh it
+    pub refresh: Option<RefreshHint>,
+}
+
+/// What a client needs to refresh an expired token on its own
+#[derive(Debug, Clone)]
+pub struct RefreshHint {
+    /// `exp` claim of the expired token, seconds since epoch
+    pub exp: Option<i64>,
+    /// IdP token endpoint, when configured
+    pub token_endpoint: Option<String>,
 }
 
 impl AuthorizationError {
-    pub fn new(message: impl Into<String>) -> Self {
+    pub fn new(reason: AuthErrorReason, message: impl Into<String>) -> Self {
         Self {
+            reason,
             message: message.into(),
-            status_code: 403,
+            status_code: reason.status_code(),
+            refresh: None,
         }
     }
 
-    pub fn with_status(message: impl Into<String>, status_code: u16) -> Self {
-        Self {
-            message: message.into(),
-            status_code,
+    /// Attach a refresh hint to an expired token error
+    pub fn with_refresh(mut self, refresh: RefreshHint) -> Self {
+        self.refresh = Some(refresh);
+        self
+    }
+
+    /// Build the `WWW-Authenticate` challenge for this error, if any
+    pub fn www_authenticate(&self) -> Option<String> {
+        if self.status_code != 401 && self.status_code != 403 {
+            return None;
         }
+        Some(match self.reason.bearer_error() {
+            Some(error) => format!(
+                "Bearer realm=\"peerlab-gateway\", error=\"{}\", error_description=\"{}\"",
+                error,
+                self.reason.description()
+            ),
+            None => "Bearer realm=\"peerlab-gateway\"".to_string(),
+        })
     }
 }
 
@@ -117,19 +341,51 @@ impl std::error::Error for AuthorizationError {}
 
 impl IntoResponse for AuthorizationError {
     fn into_response(self) -> Response {
+        debug!("Authorization failed ({:?}): {}", self.reason, self.message);
+
         let status = StatusCode::from_u16(self.status_code).unwrap_or(StatusCode::FORBIDDEN);
-        (status, Json(json!({ "error": self.message }))).into_response()
+        let mut body = json!({
+            "error": self.status_code,
+            "reason": self.reason,
+            "message": self.reason.description(),
+        });
+        if let Some(refresh) = &self.refresh {
+            if let Some(exp) = refresh.exp {
+                body["exp"] = json!(exp);
+                if let Some(expired_at) = DateTime::from_timestamp(exp, 0) {
+                    body["expired_at"] = json!(expired_at.to_rfc3339());
+                }
+            }
+            if let Some(token_endpoint) = &refresh.token_endpoint {
+                body["token_endpoint"] = json!(token_endpoint);
+            }
+        }
+        let mut response = (status, Json(body)).into_response();
+
+        if let Some(challenge) = self.www_authenticate()
+            && let Ok(value) = HeaderValue::from_str(&challenge)
+        {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, value);
+        }
+
+        response
     }
 }
 
 pub fn extract_bearer_token(authorization: Option<&str>) -> Result<&str, AuthorizationError> {
-    let auth_header = authorization
-        .ok_or_else(|| AuthorizationError::with_status("Authorization header is missing", 401))?;
+    let auth_header = authorization.ok_or_else(|| {
+        AuthorizationError::new(
+            AuthErrorReason::MissingToken,
+            "Authorization header is missing",
+        )
+    })?;
 
     if !auth_header.starts_with("Bearer ") {
-        return Err(AuthorizationError::with_status(
+        return Err(AuthorizationError::new(
+            AuthErrorReason::MalformedToken,
             "Authorization header must start with \"Bearer \"",
-            401,
         ));
     }
 
@@ -157,18 +413,58 @@ impl JwtValidator {
             }
         };
 
-        if should_refresh {
+        // Reload as soon as the local JWKS file changes on disk
+        let file_modified = jwks_file_modified(state).await;
+        let file_changed =
+            file_modified.is_some() && *JWKS_FILE_MODIFIED.read().await != file_modified;
+        if file_changed && !should_refresh {
+            debug!("JWKS file changed on disk, reloading keys");
+        }
+        let should_refresh = should_refresh || file_changed;
+
+        // While degraded, retry at most once per interval
+        let retry_due = match *JWKS_DEGRADED.read().await {
+            Some(degraded) => degraded.last_attempt.elapsed() > JWKS_RETRY_INTERVAL,
+            None => true,
+        };
+
+        if should_refresh && retry_due {
             // Need to refresh the JWKS
             debug!("JWKS cache expired or not initialized, fetching new keys");
-            let new_validator = Self::new(state).await?;
+            let new_validator = match Self::new(state).await {
+                Ok(validator) => validator,
+                Err(err) => {
+                    // Keep serving the cached keys through IdP outages
+                    let Some(cached) = JWKS_CACHE.read().await.clone() else {
+                        return Err(err);
+                    };
+                    let mut degraded = JWKS_DEGRADED.write().await;
+                    let since = degraded.map_or_else(Utc::now, |degraded| degraded.since);
+                    warn!(
+                        "Failed to refresh JWKS ({}), serving cached keys (degraded since {})",
+                        err.message, since
+                    );
+                    *degraded = Some(JwksDegraded {
+                        since,
+                        last_attempt: std::time::Instant::now(),
+                    });
+                    return Ok(cached);
+                }
+            };
 
             // Update the cache
             {
+                if JWKS_DEGRADED.write().await.take().is_some() {
+                    info!("JWKS refreshed, no longer serving cached keys");
+                }
                 let mut cache = JWKS_CACHE.write().await;
                 *cache = Some(new_validator.clone());
 
                 let mut last_refresh = LAST_JWKS_REFRESH.write().await;
                 *last_refresh = Some(std::time::Instant::now());
+
+                let mut last_modified = JWKS_FILE_MODIFIED.write().await;
+                *last_modified = file_modified;
             }
 
             Ok(new_validator)
@@ -188,6 +484,62 @@ impl JwtValidator {
 
     async fn fetch_jwks(
         state: &AppState,
+    ) -> Result<HashMap<String, DecodingKey>, AuthorizationError> {
+        match (&state.auth0_jwks_uri, &state.jwks_file) {
+            (None, Some(path)) => Self::load_jwks_file(path).await,
+            (Some(_), Some(path)) => match Self::fetch_remote_jwks(state).await {
+                Ok(keys) => Ok(keys),
+                Err(err) => {
+                    warn!(
+                        "Remote JWKS unavailable ({}), falling back to {}",
+                        err, path
+                    );
+                    Self::load_jwks_file(path).await
+                }
+            },
+            _ => Self::fetch_remote_jwks(state).await,
+        }
+    }
+
+    async fn load_jwks_file(
+        path: &str,
+    ) -> Result<HashMap<String, DecodingKey>, AuthorizationError> {
+        debug!("Loading JWKS from file {}", path);
+
+        let content = tokio::fs::read_to_string(path).await.map_err(|e| {
+            warn!("Failed to read JWKS file {}: {}", path, e);
+            AuthorizationError::new(
+                AuthErrorReason::Misconfigured,
+                format!("Failed to read JWKS file {}: {}", path, e),
+            )
+        })?;
+
+        let jwks = serde_json::from_str::<Value>(&content).map_err(|e| {
+            warn!("Failed to parse JWKS file {}: {}", path, e);
+            AuthorizationError::new(
+                AuthErrorReason::Misconfigured,
+                format!("Failed to parse JWKS file: {}", e),
+            )
+        })?;
+
+        debug!("Successfully loaded JWKS from file");
+        Self::parse_jwks(jwks)
+    }
+
+    #[cfg(not(feature = "auth0"))]
+    async fn fetch_remote_jwks(
+        _state: &AppState,
+    ) -> Result<HashMap<String, DecodingKey>, AuthorizationError> {
+        Err(AuthorizationError::new(
+            AuthErrorReason::Misconfigured,
+            "Fetching a remote JWKS requires the auth0 feature, use --jwks-file instead",
+        ))
+    }
+
+    #[cfg(feature = "auth0")]
+    #[tracing::instrument(name = "idp", skip_all, fields(operation = "fetch_jwks"))]
+    async fn fetch_remote_jwks(
+        state: &AppState,
     ) -> Result<HashMap<String, DecodingKey>, AuthorizationError> {
         let jwks_uri = jwks_uri(state)?;
         let client = create_http_client();
@@ -197,27 +549,30 @@ impl JwtValidator {
         // Simple fetch with basic error handling
         let response = client.get(&jwks_uri).send().await.map_err(|e| {
             warn!("JWKS fetch error: {}", e);
-            AuthorizationError::with_status(
+            AuthorizationError::new(
+                AuthErrorReason::IdpUnavailable,
                 format!("Failed to fetch JWKS from {}: {}", jwks_uri, e),
-                401,
             )
         })?;
 
         if !response.status().is_success() {
             warn!("JWKS request failed with status {}", response.status());
-            return Err(AuthorizationError::with_status(
+            return Err(AuthorizationError::new(
+                AuthErrorReason::IdpUnavailable,
                 format!("JWKS request failed with status: {}", response.status()),
-                401,
             ));
         }
 
         let jwks = response.json::<Value>().await.map_err(|e| {
             warn!("Failed to parse JWKS: {}", e);
-            AuthorizationError::with_status(format!("Failed to parse JWKS: {}", e), 401)
+            AuthorizationError::new(
+                AuthErrorReason::IdpUnavailable,
+                format!("Failed to parse JWKS: {}", e),
+            )
         })?;
 
         debug!("Successfully fetched JWKS");
-        Ok(Self::parse_jwks(jwks)?)
+        Self::parse_jwks(jwks)
     }
 
     fn parse_jwks(jwks: Value) -> Result<HashMap<String, DecodingKey>, AuthorizationError> {
@@ -238,10 +593,10 @@ impl JwtValidator {
                 match kty {
                     // Handle RSA keys
                     "RSA" => {
-                        if let (Some(n), Some(e)) = (key["n"].as_str(), key["e"].as_str()) {
-                            if let Ok(decoding_key) = DecodingKey::from_rsa_components(n, e) {
-                                keys.insert(kid.to_string(), decoding_key);
-                            }
+                        if let (Some(n), Some(e)) = (key["n"].as_str(), key["e"].as_str())
+                            && let Ok(decoding_key) = DecodingKey::from_rsa_components(n, e)
+                        {
+                            keys.insert(kid.to_string(), decoding_key);
                         }
                     }
                     // Handle EC (Elliptic Curve) keys
@@ -262,9 +617,9 @@ impl JwtValidator {
         }
 
         if keys.is_empty() {
-            return Err(AuthorizationError::with_status(
+            return Err(AuthorizationError::new(
+                AuthErrorReason::IdpUnavailable,
                 "No valid keys found in JWKS",
-                401,
             ));
         }
 
@@ -277,17 +632,19 @@ impl JwtValidator {
         token: &str,
     ) -> Result<AuthInfo, AuthorizationError> {
         let header = decode_header(token).map_err(|e| {
-            AuthorizationError::with_status(format!("Invalid token header: {}", e), 401)
+            AuthorizationError::new(
+                AuthErrorReason::MalformedToken,
+                format!("Invalid token header: {}", e),
+            )
         })?;
 
-        let kid = header
-            .kid
-            .ok_or_else(|| AuthorizationError::with_status("Token missing kid claim", 401))?;
+        let kid = header.kid.ok_or_else(|| {
+            AuthorizationError::new(AuthErrorReason::InvalidToken, "Token missing kid claim")
+        })?;
 
-        let key = self
-            .jwks
-            .get(&kid)
-            .ok_or_else(|| AuthorizationError::with_status("Unknown key ID", 401))?;
+        let key = self.jwks.get(&kid).ok_or_else(|| {
+            AuthorizationError::new(AuthErrorReason::InvalidToken, "Unknown key ID")
+        })?;
 
         // Determine the correct algorithm based on the token header
         let algorithm = match header.alg {
@@ -302,52 +659,81 @@ impl JwtValidator {
 
             // Default to RS256 for other algorithms
             _ => {
-                return Err(AuthorizationError::with_status(
+                return Err(AuthorizationError::new(
+                    AuthErrorReason::InvalidToken,
                     format!("Unsupported algorithm: {:?}", header.alg),
-                    401,
                 ));
             }
         };
 
         let mut validation = Validation::new(algorithm);
         validation.set_issuer(&[&issuer(state)?]);
-        validation.validate_aud = false; // We'll verify audience manually
+        match state.auth0_audience {
+            Some(ref audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
 
-        let token_data = decode::<Value>(token, key, &validation)
-            .map_err(|e| AuthorizationError::with_status(format!("Invalid token: {}", e), 401))?;
+        let token_data = decode::<Claims>(token, key, &validation).map_err(|e| {
+            let reason = match e.kind() {
+                ErrorKind::ExpiredSignature => AuthErrorReason::Expired,
+                ErrorKind::InvalidIssuer => AuthErrorReason::BadIssuer,
+                ErrorKind::InvalidAudience => AuthErrorReason::BadAudience,
+                _ => AuthErrorReason::InvalidToken,
+            };
+            let err = AuthorizationError::new(reason, format!("Invalid token: {}", e));
+            if reason != AuthErrorReason::Expired {
+                return err;
+            }
+            // The signature checked out, only the expiry failed: read `exp`
+            // back so the client knows to refresh rather than log in again
+            let mut expired = validation.clone();
+            expired.validate_exp = false;
+            let exp = decode::<Claims>(token, key, &expired)
+                .ok()
+                .and_then(|data| data.claims.exp);
+            err.with_refresh(RefreshHint {
+                exp,
+                token_endpoint: state.token_endpoint.clone(),
+            })
+        })?;
 
         let claims = token_data.claims;
 
         // Here we can verify specific claims like audience, scopes, etc.
         // For simplicity, we'll do minimal validation
 
-        Ok(self.create_auth_info(claims))
+        auth_info_from_claims(claims, &state.identity)
     }
+}
 
-    fn create_auth_info(&self, claims: Value) -> AuthInfo {
-        let scopes = claims["scope"]
-            .as_str()
-            .map(|s| s.split(' ').map(|s| s.to_string()).collect())
-            .unwrap_or_default();
-
-        let audience = match &claims["aud"] {
-            Value::Array(arr) => arr
-                .iter()
-                .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                .collect(),
-            Value::String(s) => vec![s.clone()],
-            _ => vec![],
-        };
-
-        AuthInfo::new(
-            claims["sub"].as_str().unwrap_or_default().to_string(),
-            claims["email"].as_str().map(|s| s.to_string()),
-            claims["client_id"].as_str().map(|s| s.to_string()),
-            claims["organization_id"].as_str().map(|s| s.to_string()),
-            scopes,
-            audience,
+/// Build the request `AuthInfo` from decoded token claims
+pub fn auth_info_from_claims(
+    claims: Claims,
+    identity: &IdentityMapping,
+) -> Result<AuthInfo, AuthorizationError> {
+    let identity = identity.extract(&claims).ok_or_else(|| {
+        AuthorizationError::new(
+            AuthErrorReason::InvalidToken,
+            format!(
+                "Token missing identity claim (expected one of: {})",
+                identity.claims().join(", ")
+            ),
         )
-    }
+    })?;
+
+    Ok(AuthInfo {
+        expires_at: claims.exp,
+        token_id: claims.jti,
+        ..AuthInfo::new(
+            claims.sub.unwrap_or_default(),
+            identity,
+            claims.email,
+            claims.client_id,
+            claims.organization_id,
+            claims.scope,
+            claims.aud,
+        )
+    })
 }
 
 // JWT middleware for validating tokens
@@ -356,42 +742,241 @@ pub async fn jwt_middleware(
     mut request: Request,
     next: Next,
 ) -> Result<Response, AuthorizationError> {
+    // Admins viewing the API as a user present an explicit impersonation token
+    if let Some(auth_info) = crate::impersonation::authenticate(
+        &state,
+        request.method(),
+        request.uri().path(),
+        request.headers(),
+    )
+    .await?
+    {
+        record_user(&state, &auth_info);
+        request.extensions_mut().insert(auth_info);
+        return Ok(next.run(request).await);
+    }
+
     // Check if we should bypass JWT validation (for development/testing)
     if state.bypass_jwt_validation {
         // Create dummy auth info for development/testing
         // Using test user ID for email retrieval testing
-        let dummy_auth = AuthInfo::new(
-            "test-user-id".to_string(), // Test user ID
-            Some("test@example.com".to_string()),
-            Some("test-client".to_string()),
-            None,
-            vec!["api:read".to_string(), "api:write".to_string()],
-            vec!["https://api.example.com".to_string()],
-        );
+        let dummy_claims = Claims {
+            sub: Some("test-user-id".to_string()),
+            email: Some("test@example.com".to_string()),
+            client_id: Some("test-client".to_string()),
+            scope: vec!["api:read".to_string(), "api:write".to_string()],
+            aud: vec!["https://api.example.com".to_string()],
+            ..Claims::default()
+        };
+        let dummy_auth = auth_info_from_claims(dummy_claims, &state.identity)?;
 
         // Log that we're bypassing JWT validation
         warn!("⚠️ BYPASSING JWT VALIDATION - For development/testing only!");
 
         // Store dummy auth info in request extensions
+        record_user(&state, &dummy_auth);
         request.extensions_mut().insert(dummy_auth);
 
         return Ok(next.run(request).await);
     }
 
-    // Normal JWT validation path using the cached validator
-    debug!("Validating JWT token");
-    let validator = JwtValidator::get_or_create(&state).await?;
-
     let auth_header = request
         .headers()
         .get("authorization")
         .and_then(|h| h.to_str().ok());
 
-    let token = extract_bearer_token(auth_header)?;
-    let auth_info = validator.validate_jwt(&state, token)?;
+    // Browser sessions authenticate with a cookie instead of a bearer token
+    #[cfg(feature = "sessions")]
+    if auth_header.is_none()
+        && let Some(auth_info) =
+            crate::sessions::authenticate(&state, request.method(), request.headers()).await?
+    {
+        record_user(&state, &auth_info);
+        request.extensions_mut().insert(auth_info);
+        return Ok(next.run(request).await);
+    }
+
+    let token = extract_bearer_token(auth_header)?.to_string();
+    let auth_info = authenticate_token(&state, &token).await?;
 
     // Store auth info in request extensions for handlers to use
+    record_user(&state, &auth_info);
     request.extensions_mut().insert(auth_info);
 
     Ok(next.run(request).await)
 }
+
+/// Tag the request span with the authenticated user
+fn record_user(state: &AppState, auth_info: &AuthInfo) {
+    crate::telemetry::record_user_hash(&state.identity.user_hash(&auth_info.identity));
+}
+
+/// Validate a bearer token, using the token cache and checking revocations
+pub async fn authenticate_token(
+    state: &AppState,
+    token: &str,
+) -> Result<AuthInfo, AuthorizationError> {
+    // Pick up revocation list changes before trusting any cached decision
+    if let Some(ref path) = state.revoked_tokens_file {
+        state.token_cache.reload_revocations(path).await;
+    }
+
+    if let Some(auth_info) = state.token_cache.get(token).await {
+        debug!("Using cached JWT validation result");
+        return Ok(auth_info);
+    }
+
+    let auth_info = match &state.token_validator {
+        Some(validator) => validator.validate(token).await?,
+        None => {
+            // Normal JWT validation path using the cached validator
+            debug!("Validating JWT token");
+            let validator = JwtValidator::get_or_create(state).await?;
+            validator.validate_jwt(state, token)?
+        }
+    };
+
+    if state
+        .token_cache
+        .is_revoked(token, auth_info.token_id.as_deref())
+        .await
+    {
+        return Err(AuthorizationError::new(
+            AuthErrorReason::Revoked,
+            "Token has been revoked",
+        ));
+    }
+
+    state.token_cache.insert(token, auth_info.clone()).await;
+    Ok(auth_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(value: Value) -> Claims {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_auth0_access_token_claims() {
+        let claims = claims(json!({
+            "iss": "https://tenant.eu.auth0.com/",
+            "sub": "google-oauth2|1234567890",
+            "aud": ["https://api.nxthdr.dev", "https://tenant.eu.auth0.com/userinfo"],
+            "iat": 1735689600,
+            "exp": 1735776000,
+            "azp": "abc123",
+            "scope": "openid profile email",
+            "org_id": "org_42"
+        }));
+
+        assert_eq!(claims.sub.as_deref(), Some("google-oauth2|1234567890"));
+        assert_eq!(claims.aud.len(), 2);
+        assert_eq!(claims.iat, Some(1735689600));
+        assert_eq!(claims.client_id.as_deref(), Some("abc123"));
+        assert_eq!(claims.organization_id.as_deref(), Some("org_42"));
+        assert_eq!(claims.scope, vec!["openid", "profile", "email"]);
+    }
+
+    #[test]
+    fn test_logto_token_without_iat() {
+        let claims = claims(json!({
+            "iss": "https://auth.nxthdr.dev/oidc",
+            "sub": "u1x2y3",
+            "aud": "https://api.nxthdr.dev",
+            "exp": 1735776000,
+            "client_id": "logto-app",
+            "scope": ""
+        }));
+
+        assert_eq!(claims.aud, vec!["https://api.nxthdr.dev"]);
+        assert_eq!(claims.iat, None);
+        assert_eq!(claims.exp, Some(1735776000));
+        assert!(claims.scope.is_empty());
+    }
+
+    #[test]
+    fn test_scope_as_array_and_float_dates() {
+        let claims = claims(json!({
+            "sub": "00u1abcd",
+            "scp": ["api:read", "api:write"],
+            "iat": 1735689600.25,
+            "exp": "1735776000"
+        }));
+
+        assert_eq!(claims.scope, vec!["api:read", "api:write"]);
+        assert_eq!(claims.iat, Some(1735689600));
+        assert_eq!(claims.exp, Some(1735776000));
+        assert!(claims.aud.is_empty());
+    }
+
+    #[test]
+    fn test_custom_claims_are_preserved() {
+        let claims = claims(json!({
+            "sub": "auth0|1",
+            "https://nxthdr.dev/uid": "student-7",
+            "aud": null
+        }));
+
+        assert_eq!(claims.get_str("https://nxthdr.dev/uid"), Some("student-7"));
+        assert_eq!(claims.get_str("sub"), Some("auth0|1"));
+        assert!(claims.aud.is_empty());
+    }
+
+    #[test]
+    fn test_auth_info_requires_identity_claim() {
+        let identity = IdentityMapping::default();
+        let err = auth_info_from_claims(Claims::default(), &identity).unwrap_err();
+        assert_eq!(err.status_code, 401);
+        assert_eq!(err.reason, AuthErrorReason::InvalidToken);
+    }
+
+    #[test]
+    fn test_missing_token_challenge_has_no_error() {
+        let err = extract_bearer_token(None).unwrap_err();
+        assert_eq!(err.reason, AuthErrorReason::MissingToken);
+        assert_eq!(
+            err.www_authenticate().as_deref(),
+            Some("Bearer realm=\"peerlab-gateway\"")
+        );
+    }
+
+    #[test]
+    fn test_expired_token_challenge() {
+        let err = AuthorizationError::new(AuthErrorReason::Expired, "ExpiredSignature");
+        let challenge = err.www_authenticate().unwrap();
+        assert!(challenge.contains("error=\"invalid_token\""));
+        assert!(challenge.contains("Token has expired"));
+        assert!(!challenge.contains("ExpiredSignature"));
+    }
+
+    #[tokio::test]
+    async fn test_expired_token_refresh_hint() {
+        let err = AuthorizationError::new(AuthErrorReason::Expired, "ExpiredSignature")
+            .with_refresh(RefreshHint {
+                exp: Some(1735776000),
+                token_endpoint: Some("https://idp.example.com/oauth/token".to_string()),
+            });
+        let response = err.into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["reason"], "expired");
+        assert_eq!(body["exp"], 1735776000);
+        assert_eq!(body["expired_at"], "2025-01-02T00:00:00+00:00");
+        assert_eq!(
+            body["token_endpoint"],
+            "https://idp.example.com/oauth/token"
+        );
+    }
+
+    #[test]
+    fn test_server_errors_have_no_challenge() {
+        let err = AuthorizationError::new(AuthErrorReason::IdpUnavailable, "timeout");
+        assert_eq!(err.status_code, 503);
+        assert!(err.www_authenticate().is_none());
+    }
+}
@@ -1,127 +1,657 @@
+#[cfg(feature = "abuse")]
+pub mod abuse;
+pub mod admin;
 pub mod agent;
+#[cfg(feature = "alerts")]
+pub mod alerts;
+pub mod allocation;
+pub mod annotations;
+pub mod audit;
+#[cfg(feature = "auth0")]
 pub mod auth0;
+pub mod auto_renew;
+pub mod builder;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod coalesce;
+pub mod config;
 pub mod database;
+pub mod deprecations;
+pub mod duration;
+pub mod encoding;
+pub mod events;
+pub mod export;
+pub mod export_safety;
+#[cfg(feature = "byoip")]
+pub mod external_prefixes;
+pub mod failover;
+pub mod health;
+pub mod idempotency;
+pub mod identity;
+pub mod impersonation;
+pub mod incidents;
 pub mod jwt;
+pub mod messages;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(any(feature = "webhooks", feature = "alerts"))]
+pub mod outbound;
+pub mod overload;
 pub mod pool_asns;
+pub mod pool_entries;
 pub mod pool_prefixes;
+pub mod pool_store;
+pub mod prewarm;
+pub mod problem;
+#[cfg(feature = "auth0")]
+pub mod profiles;
+pub mod public_stats;
+#[cfg(feature = "s3")]
+pub mod publisher;
+pub mod rate_limit;
+pub mod reload;
+pub mod response_cache;
+pub mod retry;
+#[cfg(feature = "byoip")]
+pub mod rpki;
+pub mod scheduler;
+#[cfg(feature = "sessions")]
+pub mod sessions;
+pub mod simulate;
+#[cfg(feature = "snapshots")]
+pub mod snapshots;
+pub mod stats;
+pub mod store;
+pub mod telemetry;
+pub mod token_cache;
+pub mod transfers;
+pub mod types;
+pub mod usage;
+pub mod user_locks;
+#[cfg(feature = "webhooks")]
+pub mod webhooks;
+pub mod workshops;
 
 use axum::{
     Router,
-    extract::{Extension, Request, State},
+    extract::{Extension, Query, Request, State},
     http::StatusCode,
     middleware::Next,
     response::Json,
-    response::Response,
-    routing::{get, post},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
 };
-use hex;
+use chrono::{DateTime, SubsecRound, Utc};
 use ipnet::Ipv6Net;
 use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::str::FromStr;
+use std::sync::Arc;
 use tower_http::trace::TraceLayer;
-use tracing::{debug, error, warn};
+use tracing::{Span, debug, error, instrument, warn};
 
-use agent::AgentStore;
+#[cfg(feature = "webhooks")]
+use axum::routing::delete;
+
+use agent::{AgentInfo, AgentKeys, AgentStore};
 use database::Database;
+use encoding::Encoding;
+use identity::IdentityMapping;
 use pool_asns::AsnPool;
-use pool_prefixes::PrefixPool;
+use pool_prefixes::{PrefixClass, PrefixPool};
+use token_cache::TokenCache;
+use types::{Prefix, UserHash};
+use user_locks::UserLocks;
 
 #[derive(Clone)]
 pub struct AppState {
     pub agent_store: AgentStore,
     pub agent_key: String,
+    pub agent_keys: reload::Reloadable<AgentKeys>,
+    pub admin_key: Option<String>,
     pub database: Database,
-    pub asn_pool: AsnPool,
-    pub prefix_pool: PrefixPool,
+    /// Storage of the user and service mapping endpoints (the database unless configured)
+    pub store: Arc<dyn store::Store>,
+    pub asn_pool: reload::Reloadable<AsnPool>,
+    /// Prefix pool allocated from: the pool file with the pool store entries applied
+    pub prefix_pool: reload::Reloadable<PrefixPool>,
+    /// Prefix pool as loaded from the file
+    pub prefix_pool_file: reload::Reloadable<PrefixPool>,
+    /// Prefix pool entries last loaded from the pool store
+    pub prefix_pool_entries: reload::Reloadable<Vec<pool_prefixes::PoolEntry>>,
+    /// Where the prefix pool entries are kept (the database unless configured)
+    pub pool_store: Arc<dyn pool_store::PoolStore>,
     pub auth0_jwks_uri: Option<String>,
+    pub jwks_file: Option<String>,
+    /// Validates bearer tokens instead of the JWKS when set
+    pub token_validator: Option<Arc<dyn jwt::TokenValidator>>,
     pub auth0_issuer: Option<String>,
+    pub auth0_audience: Option<String>,
+    /// IdP token endpoint, given to clients whose token expired
+    pub token_endpoint: Option<String>,
     pub auth0_management_api: Option<String>,
     pub auth0_m2m_app_id: Option<String>,
     pub auth0_m2m_app_secret: Option<String>,
+    /// How long cached IdP profiles (emails) are served before revalidation
+    pub profile_cache_ttl: chrono::Duration,
+    /// Longest an auto-renewed lease is extended to, from its start
+    pub auto_renew_max_lifetime: chrono::Duration,
+    /// Bounds of the lease durations clients may request
+    pub lease_min_duration: chrono::Duration,
+    pub lease_max_duration: chrono::Duration,
+    /// How long an ended lease keeps its prefix from other users
+    pub lease_grace_period: chrono::Duration,
+    /// Token scope allowing permanent leases without an admin's approval
+    pub permanent_lease_scope: Option<String>,
+    /// Longest ROA maxLength users may set on their leases (only admins may
+    /// set it when unset)
+    pub roa_max_length_limit: Option<u8>,
+    /// Check with the IdP that an account is active before allocating to it
+    pub verify_idp_users: bool,
     pub bypass_jwt_validation: bool,
+    pub identity: IdentityMapping,
+    pub token_cache: TokenCache,
+    pub user_locks: UserLocks,
+    pub agent_events: events::AgentEvents,
+    /// API calls not written to the database yet
+    pub usage: usage::UsageMeter,
+    pub revoked_tokens_file: Option<String>,
+    pub webhook_max_attempts: u32,
+    /// Most address space a user may lease at once, in /48 equivalents
+    pub max_space_per_user: Option<u32>,
+    /// Most leases a user may hold at once
+    pub max_leases_per_user: Option<u32>,
+    /// Most ASNs a user may hold at once
+    pub max_asns_per_user: u32,
+    /// How long responses to requests with an `Idempotency-Key` are replayed
+    pub idempotency_ttl: chrono::Duration,
+    /// How long a request holds its `Idempotency-Key` before a retry takes it over
+    pub idempotency_claim_timeout: chrono::Duration,
+    /// Most requests the client API handles at once (unlimited when unset)
+    pub client_concurrency_limit: Option<usize>,
+    /// Most requests the service API handles at once (unlimited when unset)
+    pub service_concurrency_limit: Option<usize>,
+    /// Cache of email-enriched `/service/mappings` responses (disabled when unset)
+    pub mappings_cache: Option<response_cache::ResponseCache>,
+    /// Loads of `/service/mappings` in flight, shared by identical requests
+    pub mappings_flights: coalesce::Coalescer<Result<axum::body::Bytes, String>>,
+    pub error_format: problem::ErrorFormat,
+    /// Effective configuration served on `/admin/config` (not recorded when unset)
+    pub config: Option<Arc<serde_json::Value>>,
+    /// Translations of client API messages (English only when empty)
+    pub messages: reload::Reloadable<messages::Catalog>,
+    /// Audit stream of resource changes (disabled when no destination is set)
+    pub audit: audit::AuditLog,
+    /// Rate limit and cache of the public stats
+    pub public_stats: public_stats::PublicStats,
+    /// Outcome of the safety checks run before exports
+    pub export_safety: export_safety::ExportSafety,
+    #[cfg(feature = "alerts")]
+    pub alert_mailer: Option<alerts::AlertMailer>,
+    #[cfg(feature = "sessions")]
+    pub sessions: Option<sessions::SessionConfig>,
+    /// Directory of the mappings snapshots written by the scheduler (disabled when unset)
+    #[cfg(feature = "snapshots")]
+    pub snapshot_dir: Option<std::path::PathBuf>,
+    /// DNS-over-HTTPS resolver used to verify external prefixes
+    #[cfg(feature = "byoip")]
+    pub dns_resolver: String,
+    /// RPKI validator gating external prefixes on route origin validation (disabled when unset)
+    #[cfg(feature = "byoip")]
+    pub rpki_validator: Option<rpki::RpkiValidator>,
+    /// Rate limit, captcha and notifications of abuse reports
+    #[cfg(feature = "abuse")]
+    pub abuse_reports: abuse::AbuseReports,
+    #[cfg(feature = "chaos")]
+    pub chaos: chaos::ChaosState,
 }
 
 // Client-facing API (requires JWT authentication)
 pub fn create_client_app(state: AppState) -> Router {
+    let idempotency_layer =
+        axum::middleware::from_fn_with_state(state.clone(), idempotency::replay_responses);
     let protected_routes = Router::new()
         .route("/user/info", get(get_user_info))
-        .route("/user/asn", post(request_asn))
-        .route("/user/prefix", post(request_prefix))
+        .route("/user/leases/history", get(get_lease_history))
+        .route(
+            "/user/asn",
+            post(request_asn)
+                .layer(idempotency_layer.clone())
+                .patch(update_asn)
+                .delete(release_asn),
+        )
+        .route("/user/asns", get(get_user_asns))
+        .route("/user/redeem", post(workshops::redeem_invite_code))
+        .route(
+            "/user/prefix",
+            post(request_prefix).layer(idempotency_layer),
+        )
+        .route("/user/prefix/{prefix}/renew", post(renew_prefix))
+        .route("/user/prefix/{prefix}/roa", put(set_prefix_roa));
+
+    #[cfg(feature = "webhooks")]
+    let protected_routes = protected_routes
+        .route(
+            "/user/webhooks",
+            get(webhooks::list_webhooks).post(webhooks::create_webhook),
+        )
+        .route("/user/webhooks/{id}", delete(webhooks::delete_webhook))
+        .route(
+            "/user/webhooks/{id}/rotate-secret",
+            post(webhooks::rotate_webhook_secret),
+        )
+        .route("/user/webhooks/{id}/test", post(webhooks::test_webhook))
+        .route(
+            "/user/webhooks/{id}/deliveries",
+            get(webhooks::list_deliveries),
+        )
+        .route(
+            "/user/webhooks/{id}/deliveries/{delivery_id}/redeliver",
+            post(webhooks::redeliver),
+        );
+
+    #[cfg(feature = "byoip")]
+    let protected_routes = protected_routes
+        .route(
+            "/user/external-prefixes",
+            get(external_prefixes::list_external_prefixes)
+                .post(external_prefixes::register_external_prefix),
+        )
+        .route(
+            "/user/external-prefixes/{id}",
+            delete(external_prefixes::delete_external_prefix),
+        )
+        .route(
+            "/user/external-prefixes/{id}/verify",
+            post(external_prefixes::verify_external_prefix),
+        );
+
+    let protected_routes = protected_routes
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            usage::meter,
+        ))
         .layer(axum::middleware::from_fn_with_state(
             state.clone(),
             jwt::jwt_middleware,
         ));
 
-    Router::new()
+    let router = Router::new()
         .merge(protected_routes)
-        .with_state(state)
-        .layer(TraceLayer::new_for_http())
+        .route("/public/stats", get(public_stats::get_public_stats))
+        .route("/deprecations", get(deprecations::list_deprecations));
+
+    // Public contact path for announcements made from the lab
+    #[cfg(feature = "abuse")]
+    let router = router.route("/abuse-report", post(abuse::submit_report));
+
+    // Browser login, only mounted when sessions are configured
+    #[cfg(feature = "sessions")]
+    let router = if state.sessions.is_some() {
+        router
+            .route("/auth/login", get(sessions::login))
+            .route("/auth/callback", get(sessions::callback))
+            .route("/auth/logout", post(sessions::logout))
+    } else {
+        router
+    };
+
+    #[cfg(feature = "chaos")]
+    let router = router.layer(axum::middleware::from_fn_with_state(
+        state.clone(),
+        chaos::inject_client_faults,
+    ));
+
+    let router = router.layer(axum::middleware::from_fn_with_state(
+        state.clone(),
+        messages::localize,
+    ));
+
+    let limit = state.client_concurrency_limit;
+    overload::limit_concurrency(router.with_state(state), limit)
+        .layer(TraceLayer::new_for_http().make_span_with(telemetry::http_span))
 }
 
 // Service-facing API (for downstream services to query mappings)
 // Requires agent authentication
 pub fn create_service_app(state: AppState) -> Router {
-    Router::new()
+    let router = Router::new()
         .route("/mappings", get(get_all_mappings))
         .route("/mappings/{user_hash}", get(get_user_mapping))
+        .route(
+            "/mappings/{user_hash}/annotations",
+            get(annotations::list_annotations),
+        )
+        .route(
+            "/mappings/{user_hash}/annotations/{key}",
+            put(annotations::set_annotation).delete(annotations::delete_annotation),
+        )
+        .route("/mappings/index/by-prefix", get(get_prefix_index))
+        .route("/prefixes/aggregated", get(get_aggregated_prefixes))
+        .route("/filters/{format}", get(get_filters))
+        .route("/policies/{format}", get(get_policies))
+        .route("/roas", get(get_roas))
+        .route("/events", get(events::stream_events));
+
+    #[cfg(feature = "chaos")]
+    let router = router.layer(axum::middleware::from_fn_with_state(
+        state.clone(),
+        chaos::inject_service_faults,
+    ));
+
+    let limit = state.service_concurrency_limit;
+    let router = router
         .with_state(state.clone())
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            usage::meter,
+        ))
         .layer(axum::middleware::from_fn_with_state(
             state,
             validate_agent_key,
-        ))
-        .layer(TraceLayer::new_for_http())
+        ));
+    overload::limit_concurrency(router, limit)
+        .layer(TraceLayer::new_for_http().make_span_with(telemetry::http_span))
 }
 
 // API key validation middleware
+// Injects the calling agent's `AgentInfo` and checks its scope for the endpoint
 async fn validate_agent_key(
     State(state): State<AppState>,
-    request: Request,
+    mut request: Request,
     next: Next,
-) -> Result<Response, StatusCode> {
+) -> Result<Response, jwt::AuthorizationError> {
     let auth_header = request
         .headers()
         .get("authorization")
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.strip_prefix("Bearer "));
+        .and_then(|h| h.to_str().ok());
 
-    match auth_header {
-        Some(key) if key == state.agent_key => Ok(next.run(request).await),
-        _ => {
+    let agent = match jwt::extract_bearer_token(auth_header) {
+        Ok(key) => match state.agent_keys.load().get(key) {
+            Some(agent) => agent.clone(),
+            None if key == state.agent_key => AgentInfo::shared(),
+            None => {
+                warn!("Unauthorized access attempt to service API");
+                return Err(jwt::AuthorizationError::new(
+                    jwt::AuthErrorReason::InvalidToken,
+                    "Invalid agent key",
+                ));
+            }
+        },
+        Err(err) => {
             warn!("Unauthorized access attempt to service API");
-            Err(StatusCode::UNAUTHORIZED)
+            return Err(err);
+        }
+    };
+
+    // Scopes are named after the first path segment (mappings, filters, ...),
+    // with a `:write` suffix for anything but reads
+    let path = request.uri().path().to_string();
+    let segment = path.trim_start_matches('/').split('/').next().unwrap_or("");
+    let scope = match *request.method() {
+        axum::http::Method::GET | axum::http::Method::HEAD => segment.to_string(),
+        _ => format!("{}:write", segment),
+    };
+    if !agent.has_scope(&scope) {
+        warn!("Agent {} is not allowed to access {}", agent.id, path);
+        return Err(jwt::AuthorizationError::new(
+            jwt::AuthErrorReason::Forbidden,
+            format!("Agent {} lacks the {} scope", agent.id, scope),
+        ));
+    }
+
+    debug!(
+        "Service API request {} {} from agent {}",
+        request.method(),
+        path,
+        agent.id
+    );
+    telemetry::record_agent_id(&agent.id);
+    request.extensions_mut().insert(agent);
+    Ok(next.run(request).await)
+}
+
+/// Which APIs a gateway process serves
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AppMode {
+    /// Public client API only (`/api`)
+    Client,
+    /// Internal service and admin APIs only (`/service`, `/admin`)
+    Service,
+    /// Everything in a single process
+    #[default]
+    Combined,
+}
+
+impl FromStr for AppMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "client" => Ok(Self::Client),
+            "service" => Ok(Self::Service),
+            "combined" => Ok(Self::Combined),
+            other => Err(format!(
+                "Unknown mode '{}' (expected 'client', 'service' or 'combined')",
+                other
+            )),
         }
     }
 }
 
+impl AppMode {
+    /// Whether this process serves the client API
+    pub fn serves_client(&self) -> bool {
+        matches!(self, Self::Client | Self::Combined)
+    }
+
+    /// Whether this process serves the service and admin APIs
+    pub fn serves_service(&self) -> bool {
+        matches!(self, Self::Service | Self::Combined)
+    }
+
+    /// Whether this process runs the background scheduler (never the public
+    /// client process, so split deployments don't run jobs twice)
+    pub fn runs_scheduler(&self) -> bool {
+        self.serves_service()
+    }
+}
+
+/// App serving the APIs of the given mode, under the same paths as the combined app.
+/// Error responses are rendered in the configured error format.
+pub fn create_app_for_mode(state: AppState, mode: AppMode) -> Router {
+    let router = Router::new().route("/health", get(health::get_health));
+
+    #[cfg(feature = "metrics")]
+    let router = router.route("/metrics", get(metrics::get_metrics));
+
+    let mut router = router.with_state(state.clone());
+
+    if mode.serves_client() {
+        router = router.nest("/api", create_client_app(state.clone()));
+    }
+    if mode.serves_service() {
+        router = router
+            .nest("/service", create_service_app(state.clone()))
+            .nest("/admin", admin::create_admin_app(state.clone()));
+    }
+
+    router
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            failover::report_unavailable,
+        ))
+        .layer(axum::middleware::from_fn(retry::add_retry_hints))
+        .layer(axum::middleware::from_fn_with_state(
+            deprecations::REGISTRY,
+            deprecations::add_headers,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state,
+            problem::render_errors,
+        ))
+}
+
 // Combined app with both client and service endpoints
 pub fn create_app(state: AppState) -> Router {
-    let client_router = create_client_app(state.clone());
-    let service_router = create_service_app(state);
-
-    Router::new()
-        .nest("/api", client_router)
-        .nest("/service", service_router)
+    create_app_for_mode(state, AppMode::Combined)
 }
 
-/// Compute a consistent hash for a user identifier
+/// Compute a consistent hash for a user identifier (the default
+/// `IdentityHashing`; handlers hash through `IdentityMapping::user_hash`)
 pub fn hash_user_identifier(user_id: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(user_id.as_bytes());
     hex::encode(hasher.finalize())
 }
 
-// Request/Response types (ASN request no longer needs a body)
+/// Prefix requests repeating a lease created this recently (e.g. a double
+/// click) return that lease instead of leasing a second prefix
+const DUPLICATE_LEASE_WINDOW: chrono::Duration = chrono::Duration::seconds(5);
+
+/// Prefixes tried before giving up when concurrent requests keep leasing them first
+const MAX_LEASE_ATTEMPTS: u32 = 3;
+
+/// Most prefixes leased by a single request
+pub(crate) const MAX_PREFIX_COUNT: usize = 16;
+
+/// Allocations leaving a pool at least this utilized (in percent) carry a warning
+const POOL_WARNING_PERCENT: usize = 90;
+
+/// Allocations leaving at most this share of the user's quota (in percent), or
+/// at most one /48, carry a warning
+const QUOTA_WARNING_PERCENT: u128 = 20;
+
+/// How far ahead a lease may be reserved
+const MAX_RESERVATION_DAYS: i64 = 30;
+
+/// Start times this far in the past (clock skew) start the lease now
+const START_TIME_TOLERANCE: chrono::Duration = chrono::Duration::minutes(1);
+
+/// Longest label and purpose of a lease, in characters
+const MAX_LABEL_LENGTH: usize = 64;
+const MAX_PURPOSE_LENGTH: usize = 256;
+
+/// Longest description and abuse contact of an ASN, in characters
+const MAX_ASN_DESCRIPTION_LENGTH: usize = 256;
+const MAX_ABUSE_CONTACT_LENGTH: usize = 254;
+
+/// Leases per page of the lease history, by default and at most
+const DEFAULT_HISTORY_LIMIT: i64 = 50;
+const MAX_HISTORY_LIMIT: i64 = 200;
+
+// Request/Response types
+
+/// Duration of a lease, given in exactly one of these forms
+#[derive(serde::Deserialize)]
+struct LeaseDuration {
+    duration_hours: Option<i32>,
+    duration_minutes: Option<i64>,
+    /// ISO 8601 duration, e.g. `PT30M`
+    duration: Option<String>,
+}
+
+impl LeaseDuration {
+    fn is_given(&self) -> bool {
+        self.duration_hours.is_some() || self.duration_minutes.is_some() || self.duration.is_some()
+    }
+}
+
+#[derive(serde::Deserialize, Default)]
+struct RequestAsnRequest {
+    /// Specific ASN of the pool to assign (any free ASN when omitted)
+    #[serde(default)]
+    asn: Option<types::Asn>,
+    /// Assign another ASN to a user already holding one, within their quota
+    #[serde(default)]
+    additional: bool,
+}
 
 #[derive(serde::Deserialize)]
 struct RequestPrefixRequest {
-    duration_hours: i32,
+    #[serde(flatten)]
+    duration: LeaseDuration,
+    /// Sites where the prefix may be announced (every site when omitted)
+    #[serde(default)]
+    sites: Option<Vec<String>>,
+    /// Routability class of the prefix (any class when omitted)
+    #[serde(default)]
+    class: Option<PrefixClass>,
+    /// Specific prefix of the pool to lease (any free prefix when omitted)
+    #[serde(default)]
+    prefix: Option<String>,
+    /// Length of the prefix: a whole prefix of the pool of that length, or a
+    /// slice of a shorter one (any whole prefix of the pool when omitted)
+    #[serde(default)]
+    prefix_len: Option<u8>,
+    /// Number of prefixes to lease at once, all or none
+    #[serde(default)]
+    count: Option<usize>,
+    /// When the lease starts, to reserve a prefix ahead (now when omitted)
+    #[serde(default)]
+    start_time: Option<DateTime<Utc>>,
+    /// Extend the lease by its duration shortly before it ends, up to the
+    /// maximum lifetime
+    #[serde(default)]
+    auto_renew: bool,
+    /// Lease the prefix until it is released or revoked, without a duration
+    /// (approved users only)
+    #[serde(default)]
+    permanent: bool,
+    /// Short name of the lease, e.g. the experiment it backs
+    #[serde(default)]
+    label: Option<String>,
+    /// What the lease is used for
+    #[serde(default)]
+    purpose: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct RenewPrefixRequest {
+    #[serde(flatten)]
+    duration: LeaseDuration,
+    /// Turn auto-renewal on or off (unchanged when omitted)
+    #[serde(default)]
+    auto_renew: Option<bool>,
+}
+
+#[derive(serde::Deserialize)]
+struct UpdateAsnRequest {
+    /// ASN to update (the user's first ASN when omitted)
+    #[serde(default)]
+    asn: Option<types::Asn>,
+    /// What the ASN is used for (unchanged when omitted, cleared when blank)
+    #[serde(default)]
+    description: Option<String>,
+    /// Email address to reach about the ASN (unchanged when omitted, cleared
+    /// when blank)
+    #[serde(default)]
+    abuse_contact: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct SetPrefixRoaRequest {
+    /// Longest more-specific the ROA authorizes (`null` for the prefix length)
+    max_length: Option<u8>,
 }
 
 #[derive(serde::Serialize)]
 struct UserInfoResponse {
-    user_hash: String,
-    asn: Option<i32>,
+    user_hash: UserHash,
+    /// First ASN assigned to the user
+    asn: Option<i64>,
+    /// When the first ASN was assigned ("member since")
+    asn_assigned_at: Option<String>,
+    /// Every ASN the user holds, first assigned first
+    asns: Vec<i64>,
     active_leases: Vec<PrefixLeaseResponse>,
+    /// Leases reserved for later, soonest first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    upcoming_leases: Vec<PrefixLeaseResponse>,
+    /// Ended leases whose prefix is still kept from other users, latest first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    grace_leases: Vec<PrefixLeaseResponse>,
 }
 
 #[derive(serde::Serialize)]
@@ -129,12 +659,112 @@ struct PrefixLeaseResponse {
     prefix: String,
     start_time: String,
     end_time: String,
+    created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sites: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    auto_renew: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    permanent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    purpose: Option<String>,
+    /// ROA maxLength, when set longer than the prefix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    roa_max_length: Option<i16>,
+    /// End of the grace period of an ended lease
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grace_until: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct LeaseHistoryQuery {
+    /// `next_cursor` of the previous page
+    cursor: Option<uuid::Uuid>,
+    limit: Option<i64>,
+}
+
+#[derive(serde::Serialize)]
+struct LeaseHistoryEntry {
+    id: uuid::Uuid,
+    prefix: String,
+    start_time: String,
+    end_time: String,
+    created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sites: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    purpose: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    permanent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    roa_max_length: Option<i16>,
+    active: bool,
+    /// End of the grace period, while the ended lease is in it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grace_until: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct LeaseHistoryResponse {
+    leases: Vec<LeaseHistoryEntry>,
+    /// Cursor of the next page, `None` on the last page
+    next_cursor: Option<uuid::Uuid>,
+}
+
+#[derive(serde::Deserialize)]
+struct DryRunQuery {
+    /// Run the selection without persisting anything
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct ReleaseAsnQuery {
+    /// ASN to release (every ASN of the user when omitted)
+    asn: Option<types::Asn>,
+}
+
+#[derive(serde::Serialize)]
+struct UserAsnsResponse {
+    asns: Vec<UserAsnResponse>,
+    /// Most ASNs the user may hold at once
+    max_asns: u32,
+}
+
+#[derive(serde::Serialize)]
+struct UserAsnResponse {
+    asn: i64,
+    assigned_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    abuse_contact: Option<String>,
+}
+
+impl From<database::UserAsnMapping> for UserAsnResponse {
+    fn from(mapping: database::UserAsnMapping) -> Self {
+        Self {
+            asn: mapping.asn,
+            assigned_at: mapping.created_at.to_rfc3339(),
+            description: mapping.description,
+            abuse_contact: mapping.abuse_contact,
+        }
+    }
 }
 
 #[derive(serde::Serialize)]
 struct RequestAsnResponse {
-    asn: i32,
+    asn: i64,
     message: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    dry_run: bool,
+    /// The pool is nearly used up
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
 }
 
 #[derive(serde::Serialize)]
@@ -142,7 +772,63 @@ struct RequestPrefixResponse {
     prefix: String,
     start_time: String,
     end_time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sites: Option<Vec<String>>,
+    class: PrefixClass,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    auto_renew: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    permanent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    purpose: Option<String>,
     message: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    dry_run: bool,
+    /// The user's quota or the pool is nearly used up
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+    /// How the prefix was picked, on dry runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    decision: Option<serde_json::Value>,
+}
+
+/// A lease, or the list of leases when `count` was given
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum PrefixRequestResult {
+    Single(RequestPrefixResponse),
+    Bulk {
+        leases: Vec<RequestPrefixResponse>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        warnings: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        decision: Option<serde_json::Value>,
+    },
+}
+
+impl PrefixRequestResult {
+    fn new(
+        count: Option<usize>,
+        mut leases: Vec<RequestPrefixResponse>,
+        warnings: Vec<String>,
+        decision: Option<serde_json::Value>,
+    ) -> Self {
+        match count {
+            None if leases.len() == 1 => {
+                let mut lease = leases.remove(0);
+                lease.warnings = warnings;
+                lease.decision = decision;
+                Self::Single(lease)
+            }
+            _ => Self::Bulk {
+                leases,
+                warnings,
+                decision,
+            },
+        }
+    }
 }
 
 #[derive(serde::Serialize)]
@@ -150,45 +836,216 @@ struct UserMappingResponse {
     user_hash: String,
     user_id: String,
     email: Option<String>,
-    asn: i32,
+    /// First ASN assigned to the user
+    asn: i64,
+    /// Every ASN the user holds, first assigned first
+    asns: Vec<i64>,
+    /// Description and abuse contact of the described ASNs, by ASN
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    asn_contacts: BTreeMap<String, AsnContactResponse>,
     prefixes: Vec<String>,
+    /// Label and purpose of the labelled leases, by prefix
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, LeaseLabelsResponse>,
+    /// Key-value pairs set by integrators
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
+    created_at: String,
+    updated_at: String,
+    /// Seconds since the first ASN was assigned
+    age_seconds: i64,
+    /// Latest change to the mappings or their leases
+    last_changed_at: String,
+}
+
+impl UserMappingResponse {
+    /// Build the response of a user from their rows of the read model (at
+    /// least one ASN)
+    fn new(
+        user: database::UserMappings,
+        email: Option<String>,
+        annotations: BTreeMap<String, String>,
+    ) -> Self {
+        let database::UserMappings { asns, leases } = user;
+        let mapping = &asns[0];
+        let updated_at = asns
+            .iter()
+            .map(|m| m.updated_at)
+            .max()
+            .unwrap_or(mapping.updated_at);
+        let last_changed_at = leases
+            .iter()
+            .map(|l| l.updated_at)
+            .chain([updated_at])
+            .max()
+            .unwrap_or(updated_at);
+        let created_at = mapping.asn_assigned_at.unwrap_or(mapping.updated_at);
+        let labels = leases
+            .iter()
+            .filter(|l| l.label.is_some() || l.purpose.is_some())
+            .filter_map(|l| {
+                let labels = LeaseLabelsResponse {
+                    label: l.label.clone(),
+                    purpose: l.purpose.clone(),
+                };
+                Some((l.prefix.clone()?, labels))
+            })
+            .collect();
+
+        Self {
+            user_hash: mapping.user_hash.to_string(),
+            user_id: mapping.holder_id.clone().unwrap_or_default(),
+            email,
+            asn: mapping.origin_asn.unwrap_or_default(),
+            asns: asns.iter().filter_map(|m| m.origin_asn).collect(),
+            asn_contacts: asns
+                .iter()
+                .filter(|m| m.description.is_some() || m.abuse_contact.is_some())
+                .filter_map(|m| {
+                    let contact = AsnContactResponse {
+                        description: m.description.clone(),
+                        abuse_contact: m.abuse_contact.clone(),
+                    };
+                    Some((m.origin_asn?.to_string(), contact))
+                })
+                .collect(),
+            prefixes: leases.into_iter().filter_map(|l| l.prefix).collect(),
+            labels,
+            annotations,
+            created_at: created_at.to_rfc3339(),
+            updated_at: updated_at.to_rfc3339(),
+            age_seconds: (chrono::Utc::now() - created_at).num_seconds(),
+            last_changed_at: last_changed_at.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct AsnContactResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    abuse_contact: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct LeaseLabelsResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    purpose: Option<String>,
 }
 
 #[derive(serde::Serialize)]
 struct AllMappingsResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    site: Option<String>,
     mappings: Vec<UserMappingResponse>,
 }
 
+#[derive(serde::Deserialize)]
+struct SiteQuery {
+    site: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct UserMappingQuery {
+    site: Option<String>,
+    #[serde(default)]
+    include_email: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct MappingsQuery {
+    site: Option<String>,
+    #[serde(default)]
+    include_email: bool,
+    sort: Option<String>,
+    order: Option<String>,
+    #[serde(default)]
+    active_only: bool,
+    asn: Option<i64>,
+}
+
+impl MappingsQuery {
+    fn filter(&self) -> Result<database::MappingFilter, String> {
+        Ok(database::MappingFilter {
+            sort: self
+                .sort
+                .as_deref()
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            order: self
+                .order
+                .as_deref()
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            active_only: self.active_only,
+            asn: self.asn,
+        })
+    }
+}
+
+#[derive(serde::Serialize)]
+struct AggregatedPrefixGroup {
+    user_hash: String,
+    asn: Option<i64>,
+    asns: Vec<i64>,
+    lease_count: usize,
+    aggregates: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct AggregatedPrefixesResponse {
+    groups: Vec<AggregatedPrefixGroup>,
+    total: Vec<String>,
+}
+
 // Handler implementations
 
 /// Get user information (ASN and active leases)
+#[instrument(name = "handler", skip_all, fields(operation = "get_user_info"))]
 async fn get_user_info(
     Extension(auth_info): Extension<jwt::AuthInfo>,
     State(state): State<AppState>,
 ) -> Result<Json<UserInfoResponse>, (StatusCode, Json<serde_json::Value>)> {
-    let user_hash = hash_user_identifier(&auth_info.sub);
-
-    match state.database.get_user_info(&user_hash).await {
-        Ok(Some((asn_mapping, leases))) => {
-            let active_leases = leases
-                .into_iter()
-                .map(|lease| PrefixLeaseResponse {
-                    prefix: lease.prefix,
-                    start_time: lease.start_time.to_rfc3339(),
-                    end_time: lease.end_time.to_rfc3339(),
-                })
-                .collect();
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    let now = Utc::now();
+    let to_response = |lease| lease_response(&state, lease, now);
 
-            Ok(Json(UserInfoResponse {
-                user_hash,
-                asn: asn_mapping.map(|m| m.asn),
-                active_leases,
-            }))
-        }
-        Ok(None) => Ok(Json(UserInfoResponse {
+    let info = tokio::try_join!(
+        state.store.get_user_info(&user_hash),
+        state.store.get_upcoming_user_leases(&user_hash),
+        async {
+            if state.lease_grace_period.is_zero() {
+                return Ok(Vec::new());
+            }
+            state
+                .store
+                .get_user_leases_ended_since(&user_hash, now - state.lease_grace_period)
+                .await
+        },
+    );
+    match info {
+        Ok((Some((asn_mappings, leases)), upcoming, grace)) => Ok(Json(UserInfoResponse {
+            user_hash,
+            asn: asn_mappings.first().map(|m| m.asn),
+            asn_assigned_at: asn_mappings.first().map(|m| m.created_at.to_rfc3339()),
+            asns: asn_mappings.iter().map(|m| m.asn).collect(),
+            active_leases: leases.into_iter().map(to_response).collect(),
+            upcoming_leases: upcoming.into_iter().map(to_response).collect(),
+            grace_leases: grace.into_iter().map(to_response).collect(),
+        })),
+        Ok((None, _, _)) => Ok(Json(UserInfoResponse {
             user_hash,
             asn: None,
+            asn_assigned_at: None,
+            asns: Vec::new(),
             active_leases: Vec::new(),
+            upcoming_leases: Vec::new(),
+            grace_leases: Vec::new(),
         })),
         Err(err) => {
             error!("Failed to get user info: {}", err);
@@ -196,44 +1053,170 @@ async fn get_user_info(
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(serde_json::json!({
                     "error": 500,
-                    "message": "Failed to retrieve user information"
+                    "message": messages::USER_INFO_FAILED.text(&[])
                 })),
             ))
         }
     }
 }
 
-/// Request an ASN for the user (auto-assigned from pool)
+/// Page through the user's leases, active and expired, newest first
+async fn get_lease_history(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Query(query): Query<LeaseHistoryQuery>,
+) -> Result<Json<LeaseHistoryResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    let internal_error = |err: sqlx::Error| {
+        error!("Failed to get lease history: {}", err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({
+                "error": 500,
+                "message": messages::HISTORY_FAILED.text(&[])
+            })),
+        )
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+    if !(1..=MAX_HISTORY_LIMIT).contains(&limit) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "error": 400,
+                "message": messages::HISTORY_LIMIT_OUT_OF_RANGE.text(&[&MAX_HISTORY_LIMIT])
+            })),
+        ));
+    }
+
+    // The cursor is the last lease of the previous page, which must be one of
+    // the user's
+    let after = match query.cursor {
+        Some(id) => match state.database.get_prefix_lease(id).await {
+            Ok(Some(lease)) if lease.user_hash == user_hash => Some((lease.start_time, lease.id)),
+            Ok(_) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(serde_json::json!({
+                        "error": 400,
+                        "message": messages::HISTORY_INVALID_CURSOR.text(&[])
+                    })),
+                ));
+            }
+            Err(err) => return Err(internal_error(err)),
+        },
+        None => None,
+    };
+
+    // Fetch one more lease than asked to know whether a next page exists
+    let mut leases = state
+        .database
+        .get_user_lease_history(&user_hash, after, limit + 1)
+        .await
+        .map_err(internal_error)?;
+    let next_cursor = if leases.len() as i64 > limit {
+        leases.truncate(limit as usize);
+        leases.last().map(|lease| lease.id)
+    } else {
+        None
+    };
+
+    let now = Utc::now();
+    Ok(Json(LeaseHistoryResponse {
+        leases: leases
+            .into_iter()
+            .map(|lease| LeaseHistoryEntry {
+                grace_until: grace_until(&state, &lease, now).map(|t| t.to_rfc3339()),
+                id: lease.id,
+                permanent: lease.is_permanent(),
+                active: lease.start_time <= now && now < lease.end_time,
+                prefix: lease.prefix,
+                start_time: lease.start_time.to_rfc3339(),
+                end_time: lease.end_time.to_rfc3339(),
+                created_at: lease.created_at.to_rfc3339(),
+                sites: lease.sites,
+                label: lease.label,
+                purpose: lease.purpose,
+                roa_max_length: lease.roa_max_length,
+            })
+            .collect(),
+        next_cursor,
+    }))
+}
+
+/// Request an ASN for the user, the one asked for or the next free one of the pool
+#[instrument(name = "handler", skip_all, fields(operation = "request_asn", asn = tracing::field::Empty))]
 async fn request_asn(
     Extension(auth_info): Extension<jwt::AuthInfo>,
     State(state): State<AppState>,
+    Query(query): Query<DryRunQuery>,
+    request: Option<Json<RequestAsnRequest>>,
 ) -> Result<Json<RequestAsnResponse>, (StatusCode, Json<serde_json::Value>)> {
-    let user_hash = hash_user_identifier(&auth_info.sub);
-
-    // Check if user already has an ASN
-    match state.database.get_user_asn(&user_hash).await {
-        Ok(Some(existing)) => {
-            debug!("User {} already has ASN {}", user_hash, existing.asn);
-            return Ok(Json(RequestAsnResponse {
-                asn: existing.asn,
-                message: "ASN already assigned".to_string(),
-            }));
-        }
-        Ok(None) => {}
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    let request = request.unwrap_or_default().0;
+    let requested = request.asn;
+    verify_account(&state, &auth_info).await?;
+    if let Some(asn) = requested
+        && !state.asn_pool.load().contains(asn.get())
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": 409,
+                "message": messages::ASN_NOT_IN_POOL.text(&[&asn])
+            })),
+        ));
+    }
+
+    // Serialize with the user's other requests so concurrent calls can't
+    // assign two ASNs
+    let _guard = state.user_locks.lock(&user_hash).await;
+
+    // Check the ASNs the user already holds
+    let held = match state.store.get_user_asns(&user_hash).await {
+        Ok(held) => held,
         Err(err) => {
             error!("Failed to check existing ASN: {}", err);
             return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(serde_json::json!({
                     "error": 500,
-                    "message": "Failed to check ASN assignment"
+                    "message": messages::ASN_CHECK_FAILED.text(&[])
                 })),
             ));
         }
+    };
+    let existing = match requested {
+        Some(asn) => held.iter().find(|mapping| mapping.asn == asn.get()),
+        None if request.additional => None,
+        None => held.first(),
+    };
+    if let Some(existing) = existing {
+        debug!("User {} already has ASN {}", user_hash, existing.asn);
+        return Ok(Json(RequestAsnResponse {
+            asn: existing.asn,
+            message: messages::ASN_ALREADY_ASSIGNED.text(&[]),
+            dry_run: query.dry_run,
+            warnings: Vec::new(),
+        }));
+    }
+    let held: Vec<i64> = held.iter().map(|mapping| mapping.asn).collect();
+    if held.len() >= state.max_asns_per_user as usize {
+        return Err(asn_quota_exceeded(&state, held));
     }
 
     // Find an available ASN from the pool (checks database for assigned ASNs)
-    let available_asn = match state.asn_pool.find_available_asn(&state.database).await {
+    let available = match requested {
+        Some(asn) => check_requested_asn(&state, asn).await?,
+        None => {
+            state
+                .asn_pool
+                .load()
+                .find_available_asn(state.store.as_ref())
+                .await
+        }
+    };
+    let available_asn = match available {
         Ok(Some(asn)) => asn,
         Ok(None) => {
             warn!("No available ASNs in the pool");
@@ -241,7 +1224,7 @@ async fn request_asn(
                 StatusCode::SERVICE_UNAVAILABLE,
                 Json(serde_json::json!({
                     "error": 503,
-                    "message": "No available ASNs at this time"
+                    "message": messages::ASN_POOL_EXHAUSTED.text(&[])
                 })),
             ));
         }
@@ -251,232 +1234,1871 @@ async fn request_asn(
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(serde_json::json!({
                     "error": 500,
-                    "message": "Failed to check ASN availability"
+                    "message": messages::ASN_AVAILABILITY_FAILED.text(&[])
                 })),
             ));
         }
     };
 
+    if query.dry_run {
+        return Ok(Json(RequestAsnResponse {
+            asn: available_asn.get(),
+            message: messages::ASN_WOULD_ASSIGN.text(&[]),
+            dry_run: true,
+            warnings: asn_warnings(&state, 1).await,
+        }));
+    }
+
     // Assign the ASN with user_id
     match state
-        .database
-        .get_or_create_user_asn(&user_hash, Some(&auth_info.sub), available_asn)
+        .store
+        .assign_user_asn(
+            &user_hash,
+            Some(&auth_info.sub),
+            available_asn,
+            state.max_asns_per_user.into(),
+        )
         .await
     {
-        Ok(mapping) => {
+        Ok(None) => Err(asn_quota_exceeded(&state, held)),
+        Ok(Some(mapping)) => {
+            Span::current().record("asn", mapping.asn);
             debug!("Assigned ASN {} to user {}", mapping.asn, user_hash);
+            state.agent_events.publish(events::AgentEvent::new(
+                events::EVENT_ASN_ASSIGNED,
+                events::EventPriority::Normal,
+                serde_json::json!({ "user_hash": user_hash, "asn": mapping.asn }),
+            ));
+            #[cfg(feature = "webhooks")]
+            webhooks::dispatch(
+                &state,
+                &user_hash,
+                "asn.assigned",
+                serde_json::json!({ "asn": mapping.asn }),
+            );
             Ok(Json(RequestAsnResponse {
                 asn: mapping.asn,
-                message: "ASN assigned successfully".to_string(),
+                message: messages::ASN_ASSIGNED.text(&[]),
+                dry_run: false,
+                warnings: asn_warnings(&state, 0).await,
             }))
         }
+        Err(err) if database::is_asn_conflict(&err) => {
+            // Taken by another user between the check and the insert
+            Err((
+                StatusCode::CONFLICT,
+                Json(serde_json::json!({
+                    "error": 409,
+                    "message": messages::ASN_TAKEN.text(&[&available_asn])
+                })),
+            ))
+        }
         Err(err) => {
             error!("Failed to assign ASN: {}", err);
             Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(serde_json::json!({
                     "error": 500,
-                    "message": "Failed to assign ASN"
+                    "message": messages::ASN_ASSIGN_FAILED.text(&[])
                 })),
             ))
         }
     }
 }
 
-/// Request a prefix lease for the user
-async fn request_prefix(
-    Extension(auth_info): Extension<jwt::AuthInfo>,
-    State(state): State<AppState>,
-    Json(request): Json<RequestPrefixRequest>,
-) -> Result<Json<RequestPrefixResponse>, (StatusCode, Json<serde_json::Value>)> {
-    let user_hash = hash_user_identifier(&auth_info.sub);
+/// Error for a user already holding as many ASNs as they may
+fn asn_quota_exceeded(state: &AppState, held: Vec<i64>) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::CONFLICT,
+        Json(serde_json::json!({
+            "error": 409,
+            "message": messages::ASN_QUOTA_EXCEEDED.text(&[&state.max_asns_per_user]),
+            "asns": held,
+            "max_asns": state.max_asns_per_user,
+        })),
+    )
+}
 
-    // Validate duration (e.g., max 24 hours)
-    if request.duration_hours < 1 || request.duration_hours > 24 {
-        return Err((
-            StatusCode::BAD_REQUEST,
+/// Check that a requested ASN is unassigned. A taken ASN is refused with the
+/// age of its assignment, without revealing its holder.
+async fn check_requested_asn(
+    state: &AppState,
+    asn: types::Asn,
+) -> Result<Result<Option<types::Asn>, sqlx::Error>, (StatusCode, Json<serde_json::Value>)> {
+    match state.store.get_asn_mapping(asn).await {
+        Ok(Some(mapping)) => Err((
+            StatusCode::CONFLICT,
             Json(serde_json::json!({
-                "error": 400,
-                "message": "Duration must be between 1 and 24 hours"
+                "error": 409,
+                "message": messages::ASN_TAKEN.text(&[&asn]),
+                "assigned_at": mapping.created_at.to_rfc3339(),
+                "assignment_age_seconds": (Utc::now() - mapping.created_at).num_seconds(),
             })),
-        ));
+        )),
+        Ok(None) => Ok(Ok(Some(asn))),
+        Err(err) => Ok(Err(err)),
     }
+}
 
-    // Get all currently leased prefixes
-    let active_leases = match state.database.get_all_active_leases().await {
-        Ok(leases) => leases,
+/// List the ASNs the user holds, first assigned first
+#[instrument(name = "handler", skip_all, fields(operation = "get_user_asns"))]
+async fn get_user_asns(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+) -> Result<Json<UserAsnsResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    match state.store.get_user_asns(&user_hash).await {
+        Ok(mappings) => Ok(Json(UserAsnsResponse {
+            asns: mappings.into_iter().map(UserAsnResponse::from).collect(),
+            max_asns: state.max_asns_per_user,
+        })),
         Err(err) => {
-            error!("Failed to get active leases: {}", err);
-            return Err((
+            error!("Failed to get ASNs of user {}: {}", user_hash, err);
+            Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(serde_json::json!({
                     "error": 500,
-                    "message": "Failed to check available prefixes"
+                    "message": messages::ASN_LIST_FAILED.text(&[])
                 })),
-            ));
+            ))
         }
+    }
+}
+
+/// Set the description and abuse contact of one of the user's ASNs, for
+/// registry/IRR tooling reading the service mappings
+#[instrument(name = "handler", skip_all, fields(operation = "update_asn", asn = tracing::field::Empty))]
+async fn update_asn(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Json(request): Json<UpdateAsnRequest>,
+) -> Result<Json<UserAsnResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    let error = |status: StatusCode, message: String| {
+        (
+            status,
+            Json(serde_json::json!({
+                "error": status.as_u16(),
+                "message": message
+            })),
+        )
     };
+    let bad_request = |message| error(StatusCode::BAD_REQUEST, message);
 
-    let leased_prefixes: Vec<Ipv6Net> = active_leases
-        .iter()
-        .filter_map(|lease| Ipv6Net::from_str(&lease.prefix).ok())
-        .collect();
+    // Omitted fields keep their value, blank ones are cleared
+    let description = request
+        .description
+        .map(|text| validate_lease_text("description", Some(text), MAX_ASN_DESCRIPTION_LENGTH))
+        .transpose()
+        .map_err(bad_request)?;
+    let abuse_contact = request
+        .abuse_contact
+        .map(|text| validate_abuse_contact(&text))
+        .transpose()
+        .map_err(bad_request)?;
 
-    // Find an available prefix
-    let available_prefix = match state.prefix_pool.find_available_prefix(&leased_prefixes) {
-        Some(prefix) => prefix,
-        None => {
-            warn!("No available prefixes in the pool");
-            return Err((
-                StatusCode::SERVICE_UNAVAILABLE,
-                Json(serde_json::json!({
-                    "error": 503,
-                    "message": "No available prefixes at this time"
-                })),
-            ));
-        }
+    let internal_error = |err: sqlx::Error| {
+        error!("Failed to update ASN of user {}: {}", user_hash, err);
+        error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            messages::ASN_UPDATE_FAILED.text(&[]),
+        )
+    };
+    let _guard = state.user_locks.lock(&user_hash).await;
+    let held = state
+        .database
+        .get_user_asns(&user_hash)
+        .await
+        .map_err(internal_error)?;
+    let (asn, mapping) = match request.asn {
+        Some(asn) => held
+            .into_iter()
+            .find(|mapping| mapping.asn == asn.get())
+            .map(|mapping| (asn, mapping))
+            .ok_or_else(|| error(StatusCode::NOT_FOUND, messages::ASN_NOT_YOURS.text(&[&asn])))?,
+        None => held
+            .into_iter()
+            .find_map(|mapping| Some((types::Asn::try_from(mapping.asn).ok()?, mapping)))
+            .ok_or_else(|| error(StatusCode::NOT_FOUND, messages::ASN_NOT_ASSIGNED.text(&[])))?,
     };
+    Span::current().record("asn", asn.get());
 
-    // Create the lease
-    match state
+    let description = description.unwrap_or(mapping.description);
+    let abuse_contact = abuse_contact.unwrap_or(mapping.abuse_contact);
+    let mapping = state
         .database
-        .create_prefix_lease(&user_hash, &available_prefix, request.duration_hours)
+        .set_user_asn_contact(
+            &user_hash,
+            asn,
+            description.as_deref(),
+            abuse_contact.as_deref(),
+        )
         .await
-    {
-        Ok(lease) => {
-            debug!(
-                "Created prefix lease {} for user {} until {}",
-                lease.prefix, user_hash, lease.end_time
-            );
-            Ok(Json(RequestPrefixResponse {
-                prefix: lease.prefix,
-                start_time: lease.start_time.to_rfc3339(),
-                end_time: lease.end_time.to_rfc3339(),
-                message: "Prefix leased successfully".to_string(),
-            }))
+        .map_err(internal_error)?
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, messages::ASN_NOT_ASSIGNED.text(&[])))?;
+
+    debug!(
+        "User {} updated the contact of ASN {}",
+        user_hash, mapping.asn
+    );
+    Ok(Json(mapping.into()))
+}
+
+/// Check an abuse contact is an email address, `None` when blank
+fn validate_abuse_contact(contact: &str) -> Result<Option<String>, String> {
+    let contact = contact.trim();
+    if contact.is_empty() {
+        return Ok(None);
+    }
+    let valid = contact.chars().count() <= MAX_ABUSE_CONTACT_LENGTH
+        && !contact.chars().any(|c| c.is_whitespace() || c.is_control())
+        && contact.split_once('@').is_some_and(|(local, domain)| {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+        });
+    if !valid {
+        return Err(messages::ASN_INVALID_ABUSE_CONTACT.text(&[]));
+    }
+    Ok(Some(contact.to_string()))
+}
+
+/// Give one or all of the user's ASNs back to the pool. Releasing the last
+/// one needs the user to hold no active lease.
+#[instrument(name = "handler", skip_all, fields(operation = "release_asn", asn = tracing::field::Empty))]
+async fn release_asn(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Query(query): Query<ReleaseAsnQuery>,
+) -> Result<StatusCode, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    let internal_error = |message: messages::Message| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({
+                "error": 500,
+                "message": message.text(&[])
+            })),
+        )
+    };
+
+    // Serialize with the user's prefix requests so no lease is taken out
+    // between the check and the release
+    let _guard = state.user_locks.lock(&user_hash).await;
+
+    let held = state
+        .database
+        .get_user_asns(&user_hash)
+        .await
+        .map_err(|err| {
+            error!("Failed to get ASNs of user {}: {}", user_hash, err);
+            internal_error(messages::ASN_RELEASE_FAILED)
+        })?;
+    if held.is_empty() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({
+                "error": 404,
+                "message": messages::ASN_NOT_ASSIGNED.text(&[])
+            })),
+        ));
+    }
+    if let Some(asn) = query.asn
+        && !held.iter().any(|mapping| mapping.asn == asn.get())
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({
+                "error": 404,
+                "message": messages::ASN_NOT_YOURS.text(&[&asn])
+            })),
+        ));
+    }
+
+    // Leases stay announced while another ASN of the user is left to originate them
+    let releases_all = query.asn.is_none() || held.len() == 1;
+    let leases = if releases_all {
+        tokio::try_join!(
+            state.database.get_active_user_leases(&user_hash),
+            state.database.get_upcoming_user_leases(&user_hash),
+        )
+    } else {
+        Ok((Vec::new(), Vec::new()))
+    };
+    match leases {
+        Ok((active, upcoming)) if active.is_empty() && upcoming.is_empty() => {}
+        Ok((active, upcoming)) => {
+            let leases = active.len() + upcoming.len();
+            return Err((
+                StatusCode::CONFLICT,
+                Json(serde_json::json!({
+                    "error": 409,
+                    "message": messages::ASN_RELEASE_BLOCKED.text(&[&leases])
+                })),
+            ));
         }
         Err(err) => {
-            error!("Failed to create prefix lease: {}", err);
-            Err((
+            error!("Failed to check active leases: {}", err);
+            return Err(internal_error(messages::ASN_RELEASE_CHECK_FAILED));
+        }
+    }
+
+    let released = match query.asn {
+        Some(asn) => state
+            .database
+            .delete_user_asn(&user_hash, asn)
+            .await
+            .map(|mapping| mapping.into_iter().collect()),
+        None => state.database.delete_user_asns(&user_hash).await,
+    };
+    let released = released.map_err(|err| {
+        error!("Failed to release ASN: {}", err);
+        internal_error(messages::ASN_RELEASE_FAILED)
+    })?;
+
+    for mapping in &released {
+        Span::current().record("asn", mapping.asn);
+        debug!("User {} released ASN {}", user_hash, mapping.asn);
+        state.agent_events.publish(events::AgentEvent::new(
+            events::EVENT_INVALIDATE,
+            events::EventPriority::High,
+            serde_json::json!({
+                "resource": "asn",
+                "user_hash": user_hash,
+                "asn": mapping.asn,
+                "reason": "released by user",
+            }),
+        ));
+        #[cfg(feature = "webhooks")]
+        webhooks::dispatch(
+            &state,
+            &user_hash,
+            "asn.released",
+            serde_json::json!({ "asn": mapping.asn }),
+        );
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Request prefix leases for the user, one unless `count` is set
+#[instrument(name = "handler", skip_all, fields(operation = "request_prefix", prefix = tracing::field::Empty))]
+async fn request_prefix(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Query(query): Query<DryRunQuery>,
+    Json(request): Json<RequestPrefixRequest>,
+) -> Result<Json<PrefixRequestResult>, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    let bad_request = |message: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "error": 400,
+                "message": message
+            })),
+        )
+    };
+
+    // Permanent leases take no duration, they end at the permanent end time
+    let duration = if request.permanent {
+        if request.duration.is_given() {
+            return Err(bad_request(
+                messages::LEASE_PERMANENT_WITH_DURATION.text(&[]),
+            ));
+        }
+        if request.auto_renew {
+            return Err(bad_request(messages::LEASE_PERMANENT_NOT_RENEWED.text(&[])));
+        }
+        None
+    } else {
+        Some(lease_duration(&state, &request.duration)?)
+    };
+
+    let now = Utc::now();
+    let start_time = match request.start_time {
+        Some(start) if start <= now - START_TIME_TOLERANCE => {
+            return Err(bad_request(messages::LEASE_START_IN_PAST.text(&[])));
+        }
+        Some(start) if start > now + chrono::Duration::days(MAX_RESERVATION_DAYS) => {
+            return Err(bad_request(
+                messages::LEASE_START_TOO_FAR.text(&[&MAX_RESERVATION_DAYS]),
+            ));
+        }
+        // Postgres keeps microseconds, so compare reservations at that precision
+        Some(start) => start.max(now).trunc_subsecs(6),
+        None => now,
+    };
+    let reserved = start_time > now;
+    let duration = duration.unwrap_or_else(|| database::permanent_end_time() - start_time);
+    let end_time = start_time + duration;
+
+    let count = request.count.unwrap_or(1);
+    if !(1..=MAX_PREFIX_COUNT).contains(&count) {
+        return Err(bad_request(
+            messages::LEASE_COUNT_OUT_OF_RANGE.text(&[&MAX_PREFIX_COUNT]),
+        ));
+    }
+
+    let sites = match request.sites {
+        Some(sites) => Some(validate_sites(&state, sites).map_err(bad_request)?),
+        None => None,
+    };
+    let label =
+        validate_lease_text("label", request.label, MAX_LABEL_LENGTH).map_err(bad_request)?;
+    let purpose =
+        validate_lease_text("purpose", request.purpose, MAX_PURPOSE_LENGTH).map_err(bad_request)?;
+
+    let lease_lengths = state.prefix_pool.load().lease_lengths();
+    let requested = match request.prefix.as_deref().map(Ipv6Net::from_str) {
+        Some(Ok(_)) if count > 1 => {
+            return Err(bad_request(messages::PREFIX_ONLY_ALONE.text(&[])));
+        }
+        Some(Ok(prefix)) if !lease_lengths.contains(&prefix.prefix_len()) => {
+            return Err(bad_request(
+                messages::PREFIX_LENGTH_NOT_LEASED
+                    .text(&[&pool_prefixes::format_lengths(&lease_lengths)]),
+            ));
+        }
+        Some(Ok(prefix)) => Some(validate_requested_prefix(
+            &state,
+            prefix.trunc(),
+            request.class,
+        )?),
+        Some(Err(_)) => return Err(bad_request(messages::PREFIX_INVALID.text(&[]))),
+        None => None,
+    };
+    let prefix_len = match (requested, request.prefix_len) {
+        (Some(prefix), Some(len)) if prefix.prefix_len() != len => {
+            return Err(bad_request(
+                messages::PREFIX_LENGTH_MISMATCH.text(&[&len, &prefix]),
+            ));
+        }
+        (Some(prefix), _) => Some(prefix.prefix_len()),
+        (None, Some(len)) if !lease_lengths.contains(&len) => {
+            return Err(bad_request(
+                messages::PREFIX_INVALID_LENGTH
+                    .text(&[&pool_prefixes::format_lengths(&lease_lengths)]),
+            ));
+        }
+        (None, len) => len,
+    };
+
+    verify_account(&state, &auth_info).await?;
+    if request.permanent {
+        verify_permanent_approval(&state, &auth_info, &user_hash).await?;
+    }
+
+    // Serialize with the user's other requests, then treat a lease created
+    // moments ago with the same start, sites, class and prefix as a duplicate
+    // submission
+    let _guard = state.user_locks.lock(&user_hash).await;
+    let user_leases = state
+        .database
+        .get_user_leases_during(&user_hash, start_time, end_time)
+        .await;
+    let user_prefixes: Vec<Ipv6Net> = match user_leases {
+        Ok(leases) => {
+            let user_prefixes = leases
+                .iter()
+                .filter_map(|lease| Ipv6Net::from_str(&lease.prefix).ok())
+                .collect();
+            let recent = leases.into_iter().find(|lease| {
+                Utc::now() - lease.created_at < DUPLICATE_LEASE_WINDOW
+                    && if reserved {
+                        lease.start_time == start_time
+                    } else {
+                        lease.start_time <= now
+                    }
+                    && lease.sites == sites
+                    && lease.renew_minutes.is_some() == request.auto_renew
+                    && lease.is_permanent() == request.permanent
+                    && lease.label == label
+                    && lease.purpose == purpose
+                    && request
+                        .class
+                        .is_none_or(|class| lease_class(&state, lease) == class)
+                    && requested.is_none_or(|prefix| Ipv6Net::from_str(&lease.prefix) == Ok(prefix))
+                    && Ipv6Net::from_str(&lease.prefix).is_ok_and(|p| match prefix_len {
+                        Some(len) => p.prefix_len() == len,
+                        None => state.prefix_pool.load().contains(&p),
+                    })
+            });
+            // Bulk requests are never treated as duplicates
+            if let Some(lease) = recent.filter(|_| request.count.is_none()) {
+                debug!(
+                    "Returning lease {} created moments ago for user {}",
+                    lease.prefix, user_hash
+                );
+                return Ok(Json(PrefixRequestResult::Single(RequestPrefixResponse {
+                    class: lease_class(&state, &lease),
+                    auto_renew: lease.renew_minutes.is_some(),
+                    permanent: lease.is_permanent(),
+                    prefix: lease.prefix,
+                    start_time: lease.start_time.to_rfc3339(),
+                    end_time: lease.end_time.to_rfc3339(),
+                    sites: lease.sites,
+                    label: lease.label,
+                    purpose: lease.purpose,
+                    message: messages::PREFIX_ALREADY_LEASED.text(&[]),
+                    dry_run: query.dry_run,
+                    warnings: Vec::new(),
+                    decision: None,
+                })));
+            }
+            user_prefixes
+        }
+        Err(err) => {
+            error!("Failed to get user leases: {}", err);
+            return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(serde_json::json!({
                     "error": 500,
-                    "message": "Failed to create prefix lease"
+                    "message": messages::LEASE_CHECK_FAILED.text(&[])
                 })),
-            ))
+            ));
         }
+    };
+
+    // Enforce the quota on the number of leases the user would hold at once
+    if let Some(max_leases) = state.max_leases_per_user
+        && user_prefixes.len() + count > max_leases as usize
+    {
+        debug!(
+            "User {} holds {} leases during the window, {} more would exceed the quota of {}",
+            user_hash,
+            user_prefixes.len(),
+            count,
+            max_leases
+        );
+        return Err((
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "error": 409,
+                "message": messages::LEASE_COUNT_QUOTA_EXCEEDED.text(&[&max_leases]),
+                "active_leases": user_prefixes.len(),
+                "max_leases": max_leases,
+            })),
+        ));
     }
-}
 
-/// Get all user mappings (for downstream services)
-async fn get_all_mappings(
-    State(state): State<AppState>,
-) -> Result<Json<AllMappingsResponse>, (StatusCode, Json<serde_json::Value>)> {
-    match state.database.get_all_user_mappings().await {
-        Ok(mappings) => {
-            let mut response_mappings = Vec::new();
-
-            for (asn_mapping, leases) in mappings {
-                // Fetch email from Auth0 if we have the necessary configuration
-                let email = if let (Some(user_id), Some(api_url), Some(app_id), Some(app_secret)) = (
-                    &asn_mapping.user_id,
-                    &state.auth0_management_api,
-                    &state.auth0_m2m_app_id,
-                    &state.auth0_m2m_app_secret,
-                ) {
-                    match auth0::get_user_email(user_id, api_url, app_id, app_secret).await {
-                        Ok(email) => email,
-                        Err(e) => {
-                            warn!("Failed to fetch email for user {}: {}", user_id, e);
-                            None
-                        }
-                    }
+    // Prefer the prefix the user held last, so repeated experiments keep the
+    // same address space
+    let last_prefix = match requested {
+        Some(_) => None,
+        None => state
+            .database
+            .get_last_prefix_for_user(&user_hash)
+            .await
+            .unwrap_or_else(|err| {
+                warn!(
+                    "Failed to get the last prefix of user {}: {}",
+                    user_hash, err
+                );
+                None
+            })
+            .and_then(|prefix| Ipv6Net::from_str(&prefix).ok()),
+    };
+
+    // Get the prefixes leased at some point of the requested window, or held
+    // by another user's lease whose grace period reaches into it (or whose
+    // window the new lease's grace period would reach into)
+    let grace = state.lease_grace_period;
+    let in_window =
+        |lease: &database::PrefixLease| lease.end_time > start_time && lease.start_time < end_time;
+    let load_active_leases = || async {
+        state
+            .database
+            .get_leases_during(start_time - grace, end_time + grace)
+            .await
+            .map(|leases| {
+                leases
+                    .into_iter()
+                    .filter(|lease| lease.user_hash != user_hash || in_window(lease))
+                    .collect::<Vec<_>>()
+            })
+            .map_err(|err| {
+                error!("Failed to get active leases: {}", err);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(serde_json::json!({
+                        "error": 500,
+                        "message": messages::PREFIX_AVAILABILITY_FAILED.text(&[])
+                    })),
+                )
+            })
+    };
+    let mut active_leases = load_active_leases().await?;
+
+    // Another request may lease a selected prefix between our check and
+    // insert; the database rejects the whole batch and we select again
+    let mut attempt = 1;
+    let created = loop {
+        let leased_prefixes: Vec<Ipv6Net> = active_leases
+            .iter()
+            .filter_map(|lease| Ipv6Net::from_str(&lease.prefix).ok())
+            .collect();
+
+        // What holds a prefix the allocator passed over, for the decision
+        let holder = |taken: &Ipv6Net| {
+            let lease = active_leases
+                .iter()
+                .find(|lease| Ipv6Net::from_str(&lease.prefix) == Ok(*taken));
+            match lease {
+                Some(lease) if !in_window(lease) => allocation::Holder::GracePeriod,
+                Some(lease) if lease.start_time > now => allocation::Holder::Reservation,
+                Some(_) => allocation::Holder::Lease,
+                None => allocation::Holder::Request,
+            }
+        };
+
+        // Find available prefixes
+        let mut decision = match requested {
+            Some(prefix) => allocation::Decision::requested(prefix, request.class),
+            None => allocation::Decision::new(request.class, prefix_len, last_prefix),
+        };
+        let selected = match requested {
+            Some(prefix)
+                if leased_prefixes
+                    .iter()
+                    .any(|leased| pool_prefixes::overlaps(leased, &prefix)) =>
+            {
+                let leased_in_window = active_leases.iter().any(|lease| {
+                    Ipv6Net::from_str(&lease.prefix)
+                        .is_ok_and(|leased| pool_prefixes::overlaps(&leased, &prefix))
+                        && in_window(lease)
+                });
+                let message = if user_prefixes.contains(&prefix) {
+                    messages::PREFIX_HELD_BY_CALLER.text(&[&prefix])
+                } else if leased_in_window {
+                    messages::PREFIX_HELD.text(&[&prefix])
                 } else {
-                    None
+                    messages::PREFIX_HELD_IN_GRACE.text(&[&prefix])
                 };
+                return Err((
+                    StatusCode::CONFLICT,
+                    Json(serde_json::json!({
+                        "error": 409,
+                        "message": message
+                    })),
+                ));
+            }
+            Some(prefix) => vec![prefix],
+            None => {
+                let mut taken = leased_prefixes.clone();
+                let mut selected = Vec::with_capacity(count);
+                while selected.len() < count {
+                    let (picked, candidates) = state.prefix_pool.load().explain_available_slice(
+                        &taken,
+                        request.class,
+                        last_prefix.as_ref(),
+                        prefix_len,
+                    );
+                    decision.add(candidates);
+                    let Some(prefix) = picked else { break };
+                    taken.push(prefix);
+                    selected.push(prefix);
+                }
+                selected
+            }
+        };
 
-                response_mappings.push(UserMappingResponse {
-                    user_hash: asn_mapping.user_hash.clone(),
-                    user_id: asn_mapping.user_id.clone().unwrap_or_default(),
-                    email,
-                    asn: asn_mapping.asn,
-                    prefixes: leases.into_iter().map(|l| l.prefix).collect(),
+        if selected.len() < count {
+            match request.class {
+                Some(class) => warn!(
+                    "{} of {} requested {} prefixes available in the pool",
+                    selected.len(),
+                    count,
+                    class.name()
+                ),
+                None => warn!(
+                    "{} of {} requested prefixes available in the pool",
+                    selected.len(),
+                    count
+                ),
+            }
+            // A prefix frees up when the first lease of the class ends
+            let next_end = active_leases
+                .iter()
+                .filter(|lease| {
+                    !lease.is_permanent()
+                        && request
+                            .class
+                            .is_none_or(|class| lease_class(&state, lease) == class)
+                })
+                .map(|lease| lease.end_time + grace)
+                .min();
+            let message = if selected.is_empty() {
+                messages::PREFIX_POOL_EXHAUSTED.text(&[])
+            } else {
+                messages::PREFIX_PARTIALLY_AVAILABLE.text(&[&selected.len(), &count])
+            };
+            let mut body = serde_json::json!({
+                "error": 503,
+                "message": message
+            });
+            if let Some(end_time) = next_end {
+                body[retry::RETRY_AFTER_FIELD] =
+                    serde_json::json!(retry::seconds_until(end_time, Utc::now()));
+            }
+            if query.dry_run {
+                body["decision"] = decision.to_json("exhausted", holder);
+            }
+            return Err((StatusCode::SERVICE_UNAVAILABLE, Json(body)));
+        }
+
+        // Enforce the quota on the space the user would hold with these prefixes
+        if let Some(max_space) = state.max_space_per_user {
+            let mut held = user_prefixes.clone();
+            held.extend(&selected);
+            let space = pool_prefixes::address_space(&held);
+            if space > max_space as u128 * pool_prefixes::SLASH48_ADDRESSES {
+                debug!(
+                    "User {} would hold {} /48 equivalents, above the quota of {}",
+                    user_hash,
+                    pool_prefixes::slash48_equivalents(space),
+                    max_space
+                );
+                let mut body = serde_json::json!({
+                    "error": 409,
+                    "message": messages::LEASE_QUOTA_EXCEEDED.text(&[&max_space])
                 });
+                if query.dry_run {
+                    body["decision"] = decision.to_json("over_quota", holder);
+                }
+                return Err((StatusCode::CONFLICT, Json(body)));
             }
+        }
 
-            Ok(Json(AllMappingsResponse {
-                mappings: response_mappings,
-            }))
+        let warnings = prefix_warnings(
+            &state,
+            &user_prefixes,
+            &leased_prefixes,
+            &selected,
+            request.class,
+        );
+
+        if query.dry_run {
+            let previews = selected
+                .iter()
+                .map(|prefix| RequestPrefixResponse {
+                    prefix: prefix.to_string(),
+                    start_time: start_time.to_rfc3339(),
+                    end_time: end_time.to_rfc3339(),
+                    sites: sites.clone(),
+                    class: state.prefix_pool.load().class_of(prefix),
+                    auto_renew: request.auto_renew,
+                    permanent: request.permanent,
+                    label: label.clone(),
+                    purpose: purpose.clone(),
+                    message: messages::PREFIX_WOULD_LEASE.text(&[]),
+                    dry_run: true,
+                    warnings: Vec::new(),
+                    decision: None,
+                })
+                .collect();
+            return Ok(Json(PrefixRequestResult::new(
+                request.count,
+                previews,
+                warnings,
+                Some(decision.to_json("dry_run", holder)),
+            )));
+        }
+        let decision = state
+            .audit
+            .is_enabled()
+            .then(|| decision.to_json("leased", holder));
+
+        // Create the leases
+        let prefixes: Vec<Prefix> = selected.into_iter().map(Prefix::from).collect();
+        let result = state
+            .database
+            .create_prefix_leases(
+                &user_hash,
+                &prefixes,
+                start_time,
+                duration,
+                &database::LeaseOptions {
+                    sites: sites.clone(),
+                    auto_renew: request.auto_renew,
+                    label: label.clone(),
+                    purpose: purpose.clone(),
+                },
+            )
+            .await;
+        match result {
+            Err(err) if database::is_lease_conflict(&err) && attempt < MAX_LEASE_ATTEMPTS => {
+                warn!("A selected prefix was leased concurrently, selecting again");
+                active_leases = load_active_leases().await?;
+                attempt += 1;
+            }
+            result => break result.map(|leases| (leases, warnings, decision)),
+        }
+    };
+
+    let (leases, warnings, decision) = match created {
+        Ok(created) => created,
+        Err(err) => {
+            error!("Failed to create prefix lease: {}", err);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": messages::PREFIX_LEASE_FAILED.text(&[])
+                })),
+            ));
+        }
+    };
+
+    if let [lease] = leases.as_slice() {
+        Span::current().record("prefix", lease.prefix.as_str());
+    }
+    if let Some(decision) = decision {
+        state.audit.record(
+            "prefix.allocation",
+            serde_json::json!({
+                "user_hash": user_hash,
+                "leases": leases.iter().map(|lease| lease.id).collect::<Vec<_>>(),
+                "decision": decision,
+            }),
+        );
+    }
+    let mut responses = Vec::with_capacity(leases.len());
+    for lease in leases {
+        debug!(
+            "Created prefix lease {} for user {} until {}",
+            lease.prefix, user_hash, lease.end_time
+        );
+        state.agent_events.publish(
+            events::AgentEvent::new(
+                events::EVENT_LEASE_CREATED,
+                events::EventPriority::Normal,
+                serde_json::json!({
+                    "id": lease.id,
+                    "user_hash": user_hash,
+                    "prefix": lease.prefix,
+                    "start_time": lease.start_time.to_rfc3339(),
+                    "end_time": lease.end_time.to_rfc3339(),
+                }),
+            )
+            .at_sites(lease.sites.clone()),
+        );
+        #[cfg(feature = "webhooks")]
+        webhooks::dispatch(
+            &state,
+            &user_hash,
+            "prefix.leased",
+            serde_json::json!({
+                "prefix": lease.prefix,
+                "start_time": lease.start_time.to_rfc3339(),
+                "end_time": lease.end_time.to_rfc3339(),
+                "sites": lease.sites,
+            }),
+        );
+        responses.push(RequestPrefixResponse {
+            class: lease_class(&state, &lease),
+            auto_renew: lease.renew_minutes.is_some(),
+            permanent: lease.is_permanent(),
+            prefix: lease.prefix,
+            start_time: lease.start_time.to_rfc3339(),
+            end_time: lease.end_time.to_rfc3339(),
+            sites: lease.sites,
+            label: lease.label,
+            purpose: lease.purpose,
+            message: messages::PREFIX_LEASED.text(&[]),
+            dry_run: false,
+            warnings: Vec::new(),
+            decision: None,
+        });
+    }
+    Ok(Json(PrefixRequestResult::new(
+        request.count,
+        responses,
+        warnings,
+        None,
+    )))
+}
+
+/// Renew an active prefix lease of the user, so it ends its duration from now
+#[instrument(name = "handler", skip_all, fields(operation = "renew_prefix", prefix = %prefix))]
+async fn renew_prefix(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    axum::extract::Path(prefix): axum::extract::Path<String>,
+    Json(request): Json<RenewPrefixRequest>,
+) -> Result<Json<RequestPrefixResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+
+    let duration = lease_duration(&state, &request.duration)?;
+    let Ok(prefix) = prefix.parse::<Prefix>() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "error": 400,
+                "message": messages::PREFIX_INVALID_PATH.text(&[])
+            })),
+        ));
+    };
+
+    verify_account(&state, &auth_info).await?;
+
+    let lease = match state
+        .database
+        .extend_prefix_lease(&user_hash, &prefix, duration, request.auto_renew)
+        .await
+    {
+        Ok(Some(lease)) => lease,
+        Ok(None) => {
+            // Permanent leases are left out of renewals
+            let permanent = state
+                .database
+                .get_active_user_leases(&user_hash)
+                .await
+                .unwrap_or_default()
+                .iter()
+                .any(|lease| lease.is_permanent() && lease.prefix.parse() == Ok(prefix));
+            let (status, message) = if permanent {
+                (StatusCode::CONFLICT, messages::LEASE_PERMANENT_NOT_RENEWED)
+            } else {
+                (StatusCode::NOT_FOUND, messages::PREFIX_NOT_LEASED)
+            };
+            return Err((
+                status,
+                Json(serde_json::json!({
+                    "error": status.as_u16(),
+                    "message": message.text(&[])
+                })),
+            ));
+        }
+        Err(err) if database::is_lease_conflict(&err) => {
+            return Err((
+                StatusCode::CONFLICT,
+                Json(serde_json::json!({
+                    "error": 409,
+                    "message": messages::PREFIX_RENEWAL_OVERLAPS.text(&[])
+                })),
+            ));
         }
         Err(err) => {
-            error!("Failed to get all mappings: {}", err);
+            error!("Failed to renew prefix lease: {}", err);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": messages::PREFIX_RENEW_FAILED.text(&[])
+                })),
+            ));
+        }
+    };
+
+    debug!(
+        "Renewed prefix lease {} for user {} until {}",
+        lease.prefix, user_hash, lease.end_time
+    );
+    state.agent_events.publish(
+        events::AgentEvent::new(
+            events::EVENT_LEASE_UPDATED,
+            events::EventPriority::Normal,
+            serde_json::json!({
+                "id": lease.id,
+                "user_hash": user_hash,
+                "prefix": lease.prefix,
+                "start_time": lease.start_time.to_rfc3339(),
+                "end_time": lease.end_time.to_rfc3339(),
+            }),
+        )
+        .at_sites(lease.sites.clone()),
+    );
+    #[cfg(feature = "webhooks")]
+    webhooks::dispatch(
+        &state,
+        &user_hash,
+        "prefix.renewed",
+        serde_json::json!({
+            "prefix": lease.prefix,
+            "start_time": lease.start_time.to_rfc3339(),
+            "end_time": lease.end_time.to_rfc3339(),
+            "sites": lease.sites,
+        }),
+    );
+    Ok(Json(RequestPrefixResponse {
+        class: lease_class(&state, &lease),
+        auto_renew: lease.renew_minutes.is_some(),
+        permanent: lease.is_permanent(),
+        prefix: lease.prefix,
+        start_time: lease.start_time.to_rfc3339(),
+        end_time: lease.end_time.to_rfc3339(),
+        sites: lease.sites,
+        label: lease.label,
+        purpose: lease.purpose,
+        message: messages::PREFIX_RENEWED.text(&[]),
+        dry_run: false,
+        warnings: Vec::new(),
+        decision: None,
+    }))
+}
+
+/// Set the maxLength of the ROA of one of the user's current or upcoming
+/// leases, so announcements of its more-specifics validate
+#[instrument(name = "handler", skip_all, fields(operation = "set_prefix_roa", prefix = %prefix))]
+async fn set_prefix_roa(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    axum::extract::Path(prefix): axum::extract::Path<String>,
+    Json(request): Json<SetPrefixRoaRequest>,
+) -> Result<Json<PrefixLeaseResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    let error = |status: StatusCode, message: String| {
+        (
+            status,
+            Json(serde_json::json!({
+                "error": status.as_u16(),
+                "message": message
+            })),
+        )
+    };
+
+    let Ok(prefix) = prefix.parse::<Prefix>() else {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            messages::PREFIX_INVALID_PATH.text(&[]),
+        ));
+    };
+    let Some(limit) = state.roa_max_length_limit else {
+        return Err(error(
+            StatusCode::FORBIDDEN,
+            messages::LEASE_ROA_SET_BY_ADMINS.text(&[]),
+        ));
+    };
+    let max_length = export::validate_roa_max_length(&prefix.net(), request.max_length, limit)
+        .map_err(|message| error(StatusCode::BAD_REQUEST, message))?;
+
+    let internal_error = |err: sqlx::Error| {
+        error!("Failed to set ROA maxLength of {}: {}", prefix, err);
+        error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            messages::LEASE_ROA_FAILED.text(&[]),
+        )
+    };
+    let _guard = state.user_locks.lock(&user_hash).await;
+    let (active, upcoming) = tokio::try_join!(
+        state.database.get_active_user_leases(&user_hash),
+        state.database.get_upcoming_user_leases(&user_hash),
+    )
+    .map_err(internal_error)?;
+    let not_leased = || error(StatusCode::NOT_FOUND, messages::PREFIX_NOT_LEASED.text(&[]));
+    let Some(lease) = active
+        .into_iter()
+        .chain(upcoming)
+        .find(|lease| lease.prefix.parse() == Ok(prefix))
+    else {
+        return Err(not_leased());
+    };
+    let lease = state
+        .database
+        .set_lease_roa_max_length(lease.id, max_length)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_leased)?;
+
+    debug!(
+        "Set ROA maxLength of {} for user {} to {}",
+        lease.prefix,
+        user_hash,
+        lease.roa_max_length(&prefix.net())
+    );
+    publish_roa_update(&state, &lease);
+    Ok(Json(lease_response(&state, lease, Utc::now())))
+}
+
+/// Tell agents the ROA of a lease changed
+pub(crate) fn publish_roa_update(state: &AppState, lease: &database::PrefixLease) {
+    state.agent_events.publish(
+        events::AgentEvent::new(
+            events::EVENT_LEASE_UPDATED,
+            events::EventPriority::Normal,
+            serde_json::json!({
+                "id": lease.id,
+                "user_hash": lease.user_hash,
+                "prefix": lease.prefix,
+                "start_time": lease.start_time.to_rfc3339(),
+                "end_time": lease.end_time.to_rfc3339(),
+                "roa_max_length": lease.roa_max_length,
+            }),
+        )
+        .at_sites(lease.sites.clone()),
+    );
+}
+
+/// Check a prefix requested by a user can be leased from the pool
+fn validate_requested_prefix(
+    state: &AppState,
+    prefix: Ipv6Net,
+    class: Option<PrefixClass>,
+) -> Result<Ipv6Net, (StatusCode, Json<serde_json::Value>)> {
+    let pool = state.prefix_pool.load();
+    let message = if pool.covering(&prefix).is_none() {
+        messages::PREFIX_NOT_IN_POOL.text(&[&prefix])
+    } else if pool
+        .covering(&prefix)
+        .is_some_and(|parent| pool.is_disabled(parent))
+    {
+        messages::PREFIX_DISABLED.text(&[&prefix])
+    } else if let Some(class) = class
+        && pool.class_of(&prefix) != class
+    {
+        messages::PREFIX_NOT_OF_CLASS.text(&[&prefix, &class.name()])
+    } else {
+        return Ok(prefix);
+    };
+    Err((
+        StatusCode::CONFLICT,
+        Json(serde_json::json!({
+            "error": 409,
+            "message": message
+        })),
+    ))
+}
+
+/// Percentage of a pool in use, when it reaches [`POOL_WARNING_PERCENT`]
+fn pool_utilization_warning(used: usize, size: usize) -> Option<usize> {
+    let percent = (used * 100).checked_div(size)?;
+    (percent >= POOL_WARNING_PERCENT).then_some(percent)
+}
+
+/// Warnings for an ASN assignment leaving the pool nearly used up, counting
+/// `pending` assignments not yet in the database. Best effort: none when the
+/// usage can't be read.
+async fn asn_warnings(state: &AppState, pending: i64) -> Vec<String> {
+    let assigned = match state.store.get_pool_usage().await {
+        Ok((assigned, _)) => assigned + pending,
+        Err(err) => {
+            warn!("Failed to get pool usage for warnings: {}", err);
+            return Vec::new();
+        }
+    };
+    let size = state.asn_pool.load().size();
+    pool_utilization_warning(assigned.min(size) as usize, size as usize)
+        .map(|percent| messages::ASN_POOL_UTILIZED.text(&[&percent]))
+        .into_iter()
+        .collect()
+}
+
+/// Warnings for a prefix allocation leaving the user's quota or the pool
+/// nearly used up. `held` are the user's other leases and `leased` all the
+/// leases of the window, before `selected` are added.
+fn prefix_warnings(
+    state: &AppState,
+    held: &[Ipv6Net],
+    leased: &[Ipv6Net],
+    selected: &[Ipv6Net],
+    class: Option<PrefixClass>,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if let Some(max_space) = state.max_space_per_user {
+        let quota = max_space as u128 * pool_prefixes::SLASH48_ADDRESSES;
+        let held: Vec<Ipv6Net> = held.iter().chain(selected).copied().collect();
+        let remaining = quota.saturating_sub(pool_prefixes::address_space(&held));
+        if remaining <= (quota * QUOTA_WARNING_PERCENT / 100).max(pool_prefixes::SLASH48_ADDRESSES)
+        {
+            warnings.push(
+                messages::QUOTA_REMAINING
+                    .text(&[&pool_prefixes::slash48_equivalents(remaining), &max_space]),
+            );
+        }
+    }
+
+    let pool = state.prefix_pool.load();
+    let taken: Vec<Ipv6Net> = leased.iter().chain(selected).copied().collect();
+    let size = pool.count_available(&[], class);
+    let used = size - pool.count_available(&taken, class);
+    if let Some(percent) = pool_utilization_warning(used, size) {
+        warnings.push(match class {
+            Some(class) => messages::PREFIX_CLASS_POOL_UTILIZED.text(&[&class.name(), &percent]),
+            None => messages::PREFIX_POOL_UTILIZED.text(&[&percent]),
+        });
+    }
+    warnings
+}
+
+/// Read the duration of a lease and check it is within the configured
+/// bounds, which the error carries for clients to adapt
+fn lease_duration(
+    state: &AppState,
+    requested: &LeaseDuration,
+) -> Result<chrono::Duration, (StatusCode, Json<serde_json::Value>)> {
+    let bad_request = |message: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "error": 400,
+                "message": message
+            })),
+        )
+    };
+
+    let parsed = match (
+        requested.duration_hours,
+        requested.duration_minutes,
+        requested.duration.as_deref(),
+    ) {
+        (Some(hours), None, None) => chrono::Duration::try_hours(hours as i64),
+        (None, Some(minutes), None) => chrono::Duration::try_minutes(minutes),
+        (None, None, Some(iso)) => Some(duration::parse_iso8601(iso).map_err(bad_request)?),
+        _ => {
+            return Err(bad_request(messages::LEASE_DURATION_AMBIGUOUS.text(&[])));
+        }
+    };
+    // Durations too large to represent are out of bounds too
+    match parsed {
+        Some(parsed) if parsed.num_seconds() % 60 != 0 => Err(bad_request(
+            messages::LEASE_DURATION_NOT_WHOLE_MINUTES.text(&[]),
+        )),
+        Some(parsed)
+            if parsed >= state.lease_min_duration && parsed <= state.lease_max_duration =>
+        {
+            Ok(parsed)
+        }
+        _ => Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "error": 400,
+                "message": messages::LEASE_DURATION_OUT_OF_RANGE.text(&[&duration::describe(state.lease_min_duration), &duration::describe(state.lease_max_duration)]),
+                "min_minutes": state.lease_min_duration.num_minutes(),
+                "max_minutes": state.lease_max_duration.num_minutes(),
+            })),
+        )),
+    }
+}
+
+/// Reject allocations for accounts the IdP removed or suspended, when enabled.
+/// Fails closed: an account that can't be checked gets nothing.
+#[cfg(feature = "auth0")]
+async fn verify_account(
+    state: &AppState,
+    auth_info: &jwt::AuthInfo,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if !state.verify_idp_users {
+        return Ok(());
+    }
+
+    let rejection = |status: StatusCode, message: messages::Message| {
+        (
+            status,
+            Json(serde_json::json!({
+                "error": status.as_u16(),
+                "message": message.text(&[])
+            })),
+        )
+    };
+    match profiles::check_account(state, &auth_info.sub).await {
+        Ok(profiles::AccountStatus::Active) => Ok(()),
+        Ok(profiles::AccountStatus::Blocked) => {
+            warn!("Refusing allocation to suspended account {}", auth_info.sub);
+            Err(rejection(
+                StatusCode::FORBIDDEN,
+                messages::ACCOUNT_SUSPENDED,
+            ))
+        }
+        Ok(profiles::AccountStatus::Missing) => {
+            warn!("Refusing allocation to removed account {}", auth_info.sub);
+            Err(rejection(StatusCode::FORBIDDEN, messages::ACCOUNT_REMOVED))
+        }
+        Err(err) => {
+            error!("Failed to verify account {}: {}", auth_info.sub, err);
+            Err(rejection(
+                StatusCode::SERVICE_UNAVAILABLE,
+                messages::ACCOUNT_VERIFY_FAILED,
+            ))
+        }
+    }
+}
+
+/// Account verification needs the `auth0` feature, which the builder enforces
+#[cfg(not(feature = "auth0"))]
+async fn verify_account(
+    _state: &AppState,
+    _auth_info: &jwt::AuthInfo,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    Ok(())
+}
+
+/// Routability class of a leased prefix
+fn lease_class(state: &AppState, lease: &database::PrefixLease) -> PrefixClass {
+    match Ipv6Net::from_str(&lease.prefix) {
+        Ok(prefix) => state.prefix_pool.load().class_of(&prefix),
+        Err(_) => PrefixClass::Global,
+    }
+}
+
+fn permission_class(
+    state: &AppState,
+    permission: &database::AnnouncementPermission,
+) -> PrefixClass {
+    match Ipv6Net::from_str(&permission.prefix) {
+        Ok(prefix) => state.prefix_pool.load().class_of(&prefix),
+        Err(_) => PrefixClass::Global,
+    }
+}
+
+/// Check the sites a lease is pinned to, returning them sorted and deduplicated.
+/// When agents are configured with sites, only those sites are accepted.
+fn validate_sites(state: &AppState, sites: Vec<String>) -> Result<Vec<String>, String> {
+    if sites.is_empty() {
+        return Err(messages::LEASE_SITES_REQUIRED.text(&[]));
+    }
+
+    let known = state.agent_keys.load().sites();
+    for site in &sites {
+        let valid = !site.is_empty()
+            && site.len() <= 32
+            && site
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !valid {
+            return Err(messages::LEASE_INVALID_SITE.text(&[&site]));
+        }
+        if !known.is_empty() && !known.contains(site) {
+            return Err(messages::LEASE_UNKNOWN_SITE.text(&[&site]));
+        }
+    }
+
+    let mut sites = sites;
+    sites.sort();
+    sites.dedup();
+    Ok(sites)
+}
+
+/// Check a user may take permanent leases: their token carries the
+/// permanent lease scope, or an admin approved them
+async fn verify_permanent_approval(
+    state: &AppState,
+    auth_info: &jwt::AuthInfo,
+    user_hash: &UserHash,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if let Some(ref scope) = state.permanent_lease_scope
+        && auth_info.scopes.iter().any(|s| s == scope)
+    {
+        return Ok(());
+    }
+    match state.database.get_permanent_lease_approval(user_hash).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err((
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({
+                "error": 403,
+                "message": messages::LEASE_PERMANENT_NOT_APPROVED.text(&[])
+            })),
+        )),
+        Err(err) => {
+            error!("Failed to check permanent lease approval: {}", err);
             Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(serde_json::json!({
                     "error": 500,
-                    "message": "Failed to retrieve mappings"
+                    "message": messages::LEASE_APPROVAL_CHECK_FAILED.text(&[])
                 })),
             ))
         }
     }
 }
 
+/// A lease as listed in `/api/user/info`
+fn lease_response(
+    state: &AppState,
+    lease: database::PrefixLease,
+    now: DateTime<Utc>,
+) -> PrefixLeaseResponse {
+    PrefixLeaseResponse {
+        grace_until: grace_until(state, &lease, now).map(|t| t.to_rfc3339()),
+        permanent: lease.is_permanent(),
+        prefix: lease.prefix,
+        start_time: lease.start_time.to_rfc3339(),
+        end_time: lease.end_time.to_rfc3339(),
+        created_at: lease.created_at.to_rfc3339(),
+        auto_renew: lease.renew_minutes.is_some(),
+        sites: lease.sites,
+        label: lease.label,
+        purpose: lease.purpose,
+        roa_max_length: lease.roa_max_length,
+    }
+}
+
+/// End of the grace period of a lease that has ended, while it lasts
+fn grace_until(
+    state: &AppState,
+    lease: &database::PrefixLease,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let until = lease.end_time + state.lease_grace_period;
+    (lease.end_time <= now && now < until).then_some(until)
+}
+
+/// Trim a label or purpose given to a lease, `None` when blank
+fn validate_lease_text(
+    field: &str,
+    text: Option<String>,
+    max_length: usize,
+) -> Result<Option<String>, String> {
+    let Some(text) = text else {
+        return Ok(None);
+    };
+    let text = text.trim();
+    if text.chars().count() > max_length {
+        return Err(messages::LEASE_TEXT_TOO_LONG.text(&[&field, &max_length]));
+    }
+    if text.chars().any(char::is_control) {
+        return Err(messages::LEASE_TEXT_CONTROL_CHARACTERS.text(&[&field]));
+    }
+    Ok((!text.is_empty()).then(|| text.to_string()))
+}
+
+/// Fetch a user's email through the profile cache if we have the necessary configuration
+#[cfg(feature = "auth0")]
+async fn lookup_email(state: &AppState, user_id: Option<&str>) -> Option<String> {
+    profiles::lookup_email(state, user_id?).await
+}
+
+/// Email enrichment is unavailable without the `auth0` feature
+#[cfg(not(feature = "auth0"))]
+async fn lookup_email(_state: &AppState, _user_id: Option<&str>) -> Option<String> {
+    None
+}
+
+/// Site to scope a service response to: the requested site, or the agent's own.
+/// Agents bound to a site can't query other sites.
+fn resolve_site(
+    agent: &AgentInfo,
+    requested: Option<String>,
+) -> Result<Option<String>, (StatusCode, Json<serde_json::Value>)> {
+    match (requested, &agent.site) {
+        (Some(requested), Some(own)) if &requested != own => {
+            warn!(
+                "Agent {} of site {} requested site {}",
+                agent.id, own, requested
+            );
+            Err((
+                StatusCode::FORBIDDEN,
+                Json(serde_json::json!({
+                    "error": 403,
+                    "message": format!("Agent is restricted to site {}", own)
+                })),
+            ))
+        }
+        (Some(requested), _) => Ok(Some(requested)),
+        (None, own) => Ok(own.clone()),
+    }
+}
+
+/// Keep the leases that may be announced at a site (all of them without a site)
+fn leases_at_site(
+    leases: Vec<database::MappingPermission>,
+    site: Option<&str>,
+) -> Vec<database::MappingPermission> {
+    leases
+        .into_iter()
+        .filter(|l| site.is_none_or(|site| l.allowed_at(site)))
+        .collect()
+}
+
+/// Get all user mappings (for downstream services)
+#[instrument(name = "handler", skip_all, fields(operation = "get_all_mappings"))]
+async fn get_all_mappings(
+    State(state): State<AppState>,
+    Extension(agent): Extension<AgentInfo>,
+    Query(query): Query<MappingsQuery>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    let filter = query.filter().map_err(|message| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "error": 400,
+                "message": message
+            })),
+        )
+    })?;
+    let site = resolve_site(&agent, query.site)?;
+    let include_email = query.include_email;
+    let encoding = Encoding::negotiate(&headers);
+
+    // Identical requests arriving while a load is in flight share its result
+    let key = format!("{:?}|{:?}|{}|{:?}", filter, site, include_email, encoding);
+    let flights = state.mappings_flights.clone();
+    let cache = state.mappings_cache.clone();
+    let load = {
+        let key = key.clone();
+        move || async move {
+            let load = || async move {
+                let response = load_all_mappings(state, filter, site, include_email)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                Ok(encoding.encode(&response))
+            };
+            flights.run(key, load).await.0
+        }
+    };
+
+    let Some(cache) = cache else {
+        return match load().await {
+            Ok(body) => Ok((
+                [(
+                    axum::http::header::CONTENT_TYPE,
+                    encoding.content_type().to_string(),
+                )],
+                body,
+            )
+                .into_response()),
+            Err(err) => Err(mappings_error(err)),
+        };
+    };
+
+    // Email enrichment is slow: serve cached responses, stale while refreshing
+    match cache.get_or_load(key, load).await {
+        Ok((body, age, status)) => Ok((
+            [
+                (
+                    axum::http::header::CONTENT_TYPE,
+                    encoding.content_type().to_string(),
+                ),
+                (axum::http::header::AGE, age.as_secs().to_string()),
+                (
+                    axum::http::HeaderName::from_static("x-cache"),
+                    status.as_str().to_string(),
+                ),
+            ],
+            body,
+        )
+            .into_response()),
+        Err(err) => Err(mappings_error(err)),
+    }
+}
+
+/// Load all mappings matching a filter, enriched with emails when asked
+async fn load_all_mappings(
+    state: AppState,
+    filter: database::MappingFilter,
+    site: Option<String>,
+    include_email: bool,
+) -> Result<AllMappingsResponse, sqlx::Error> {
+    let rows = state.store.get_mapping_permissions(None, &filter).await?;
+    let users = database::MappingFilter::group(rows);
+    // Annotations are set on the first mapping of each user
+    let ids: Vec<uuid::Uuid> = users.iter().map(|user| user.asns[0].source_id).collect();
+    let mut annotations = annotations::by_mapping(&state, &ids).await?;
+    let mut response_mappings = Vec::new();
+
+    for mut user in users {
+        // Skip users whose leases are all pinned to other sites
+        let has_leases = !user.leases.is_empty();
+        user.leases = leases_at_site(user.leases, site.as_deref());
+        if has_leases && user.leases.is_empty() {
+            continue;
+        }
+
+        let first = &user.asns[0];
+        let email = if include_email {
+            lookup_email(&state, first.holder_id.as_deref()).await
+        } else {
+            None
+        };
+
+        let annotations = annotations.remove(&first.source_id).unwrap_or_default();
+        response_mappings.push(UserMappingResponse::new(user, email, annotations));
+    }
+
+    Ok(AllMappingsResponse {
+        site,
+        mappings: response_mappings,
+    })
+}
+
+fn mappings_error(err: String) -> (StatusCode, Json<serde_json::Value>) {
+    error!("Failed to get all mappings: {}", err);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(serde_json::json!({
+            "error": 500,
+            "message": "Failed to retrieve mappings"
+        })),
+    )
+}
+
 /// Get mapping for a specific user (for downstream services)
+#[instrument(name = "handler", skip_all, fields(operation = "get_user_mapping", user_hash = %user_hash))]
 async fn get_user_mapping(
     State(state): State<AppState>,
-    axum::extract::Path(user_hash): axum::extract::Path<String>,
-) -> Result<Json<UserMappingResponse>, (StatusCode, Json<serde_json::Value>)> {
-    match state.database.get_user_info(&user_hash).await {
-        Ok(Some((Some(asn_mapping), leases))) => {
-            // Fetch email from Auth0 if we have the necessary configuration
-            let email = if let (Some(user_id), Some(api_url), Some(app_id), Some(app_secret)) = (
-                &asn_mapping.user_id,
-                &state.auth0_management_api,
-                &state.auth0_m2m_app_id,
-                &state.auth0_m2m_app_secret,
-            ) {
-                match auth0::get_user_email(user_id, api_url, app_id, app_secret).await {
-                    Ok(email) => email,
-                    Err(e) => {
-                        warn!("Failed to fetch email for user {}: {}", user_id, e);
-                        None
-                    }
-                }
-            } else {
-                None
-            };
+    Extension(agent): Extension<AgentInfo>,
+    Query(query): Query<UserMappingQuery>,
+    axum::extract::Path(user_hash): axum::extract::Path<UserHash>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    let site = resolve_site(&agent, query.site)?;
 
-            Ok(Json(UserMappingResponse {
-                user_hash: asn_mapping.user_hash.clone(),
-                user_id: asn_mapping.user_id.clone().unwrap_or_default(),
-                email,
-                asn: asn_mapping.asn,
-                prefixes: leases.into_iter().map(|l| l.prefix).collect(),
-            }))
+    let rows = match state
+        .store
+        .get_mapping_permissions(Some(&user_hash), &database::MappingFilter::default())
+        .await
+    {
+        Ok(rows) => rows,
+        Err(err) => {
+            error!("Failed to get user mapping: {}", err);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to retrieve user mapping"
+                })),
+            ));
         }
-        Ok(Some((None, _))) => Err((
+    };
+    if rows.is_empty() {
+        return Err((
             StatusCode::NOT_FOUND,
             Json(serde_json::json!({
                 "error": 404,
-                "message": "User has no ASN assigned"
+                "message": "User not found"
             })),
-        )),
-        Ok(None) => Err((
+        ));
+    }
+    let Some(mut user) = database::MappingFilter::group(rows).pop() else {
+        return Err((
             StatusCode::NOT_FOUND,
             Json(serde_json::json!({
                 "error": 404,
-                "message": "User not found"
+                "message": "User has no ASN assigned"
             })),
-        )),
+        ));
+    };
+
+    let first = &user.asns[0];
+    let email = if query.include_email {
+        lookup_email(&state, first.holder_id.as_deref()).await
+    } else {
+        None
+    };
+    let annotations = match annotations::by_mapping(&state, &[first.source_id]).await {
+        Ok(mut annotations) => annotations.remove(&first.source_id).unwrap_or_default(),
         Err(err) => {
-            error!("Failed to get user mapping: {}", err);
-            Err((
+            error!("Failed to get annotations of user {}: {}", user_hash, err);
+            return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(serde_json::json!({
                     "error": 500,
                     "message": "Failed to retrieve user mapping"
                 })),
+            ));
+        }
+    };
+
+    user.leases = leases_at_site(user.leases, site.as_deref());
+    Ok(Encoding::negotiate(&headers).respond(&UserMappingResponse::new(user, email, annotations)))
+}
+
+/// Load the announcement permissions grouped per user, with each user's
+/// ASNs, keeping only globally-routable leases and the prefixes that may be
+/// announced at `site` when given
+async fn load_prefix_groups(
+    state: &AppState,
+    site: Option<&str>,
+) -> Result<Vec<export::PrefixGroup>, sqlx::Error> {
+    let permissions = state.database.get_announcement_permissions().await?;
+    Ok(group_exported_prefixes(state, site, permissions))
+}
+
+/// Like [`load_prefix_groups`], for filters and policies: the permissions
+/// are checked first, and the export withheld when they are unsafe
+pub(crate) async fn load_checked_prefix_groups(
+    state: &AppState,
+    site: Option<&str>,
+) -> Result<Vec<export::PrefixGroup>, export_safety::ExportError> {
+    let permissions = state.database.get_announcement_permissions().await?;
+    export_safety::check(state, &permissions)?;
+    Ok(group_exported_prefixes(state, site, permissions))
+}
+
+/// Announcement permissions that are exported, at `site` when given
+fn exported_permissions(
+    state: &AppState,
+    site: Option<&str>,
+    mut permissions: Vec<database::AnnouncementPermission>,
+) -> Vec<database::AnnouncementPermission> {
+    permissions.retain(|p| !p.is_lease() || permission_class(state, p).is_routable());
+    #[cfg(feature = "byoip")]
+    permissions.retain(|p| p.is_lease() || external_prefixes::is_permission_exported(state, p));
+    if let Some(site) = site {
+        permissions.retain(|p| p.allowed_at(site));
+    }
+    permissions
+}
+
+fn group_exported_prefixes(
+    state: &AppState,
+    site: Option<&str>,
+    permissions: Vec<database::AnnouncementPermission>,
+) -> Vec<export::PrefixGroup> {
+    export::group_permissions_by_user(&exported_permissions(state, site, permissions))
+}
+
+/// Get the owner of each leased prefix, keyed by prefix (for downstream services)
+#[instrument(name = "handler", skip_all, fields(operation = "get_prefix_index"))]
+async fn get_prefix_index(
+    State(state): State<AppState>,
+    Extension(agent): Extension<AgentInfo>,
+    Query(query): Query<SiteQuery>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    let site = resolve_site(&agent, query.site)?;
+    match load_prefix_groups(&state, site.as_deref()).await {
+        Ok(groups) => Ok(Encoding::negotiate(&headers).respond(&export::index_by_prefix(&groups))),
+        Err(err) => {
+            error!("Failed to get leases for prefix index: {}", err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to retrieve prefixes"
+                })),
             ))
         }
     }
 }
+
+/// Get leased space merged into minimal aggregates per user/ASN (for downstream services)
+#[instrument(
+    name = "handler",
+    skip_all,
+    fields(operation = "get_aggregated_prefixes")
+)]
+async fn get_aggregated_prefixes(
+    State(state): State<AppState>,
+    Extension(agent): Extension<AgentInfo>,
+    Query(query): Query<SiteQuery>,
+) -> Result<Json<AggregatedPrefixesResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let site = resolve_site(&agent, query.site)?;
+    let groups = match load_prefix_groups(&state, site.as_deref()).await {
+        Ok(groups) => groups,
+        Err(err) => {
+            error!("Failed to get leases for aggregation: {}", err);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": 500,
+                    "message": "Failed to retrieve prefixes"
+                })),
+            ));
+        }
+    };
+
+    let all_prefixes: Vec<Ipv6Net> = groups.iter().flat_map(|g| g.prefixes.clone()).collect();
+
+    Ok(Json(AggregatedPrefixesResponse {
+        groups: groups
+            .iter()
+            .map(|group| AggregatedPrefixGroup {
+                user_hash: group.user_hash.clone(),
+                asn: group.asns.first().copied(),
+                asns: group.asns.clone(),
+                lease_count: group.prefixes.len(),
+                aggregates: group.aggregated().iter().map(|p| p.to_string()).collect(),
+            })
+            .collect(),
+        total: export::aggregate(&all_prefixes)
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
+    }))
+}
+
+/// Get per-ASN prefix filters rendered for a router syntax (for downstream services)
+#[instrument(name = "handler", skip_all, fields(operation = "get_filters", format = %format))]
+async fn get_filters(
+    State(state): State<AppState>,
+    Extension(agent): Extension<AgentInfo>,
+    Query(query): Query<SiteQuery>,
+    axum::extract::Path(format): axum::extract::Path<String>,
+) -> Result<String, (StatusCode, Json<serde_json::Value>)> {
+    let format = match format.parse::<export::FilterFormat>() {
+        Ok(format) => format,
+        Err(err) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({
+                    "error": 404,
+                    "message": err,
+                    "supported_formats": export::FilterFormat::ALL
+                        .iter()
+                        .map(|f| f.name())
+                        .collect::<Vec<_>>()
+                })),
+            ));
+        }
+    };
+
+    let site = resolve_site(&agent, query.site)?;
+    let groups = load_checked_prefix_groups(&state, site.as_deref())
+        .await
+        .map_err(|err| err.response("filters"))?;
+
+    Ok(export::render_filters(
+        format,
+        &export::prefixes_by_asn(&groups),
+    ))
+}
+
+/// Get announcement permissions as GoBGP/ExaBGP JSON (for downstream services)
+#[instrument(name = "handler", skip_all, fields(operation = "get_policies", format = %format))]
+async fn get_policies(
+    State(state): State<AppState>,
+    Extension(agent): Extension<AgentInfo>,
+    Query(query): Query<SiteQuery>,
+    axum::extract::Path(format): axum::extract::Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let format = match format.parse::<export::PolicyFormat>() {
+        Ok(format) => format,
+        Err(err) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({
+                    "error": 404,
+                    "message": err,
+                    "supported_formats": export::PolicyFormat::ALL
+                        .iter()
+                        .map(|f| f.name())
+                        .collect::<Vec<_>>()
+                })),
+            ));
+        }
+    };
+
+    let site = resolve_site(&agent, query.site)?;
+    let groups = load_checked_prefix_groups(&state, site.as_deref())
+        .await
+        .map_err(|err| err.response("policies"))?;
+
+    Ok(Json(export::render_policy(
+        format,
+        &export::prefixes_by_asn(&groups),
+    )))
+}
+
+/// Get the ROAs of the routable leases as rpki-client JSON, for the lab's RTR
+/// server (for downstream services)
+#[instrument(name = "handler", skip_all, fields(operation = "get_roas"))]
+async fn get_roas(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let permissions = state
+        .database
+        .get_announcement_permissions()
+        .await
+        .map_err(|err| export_safety::ExportError::from(err).response("ROAs"))?;
+    export_safety::check(&state, &permissions).map_err(|err| err.response("ROAs"))?;
+    let permissions = exported_permissions(&state, None, permissions);
+
+    Ok(Json(export::render_roas(
+        &export::roas(&permissions),
+        Utc::now().timestamp(),
+    )))
+}
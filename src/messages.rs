@@ -0,0 +1,717 @@
+//! Translations of client API messages.
+//!
+//! Every human-readable `message` and allocation warning of the ASN, prefix,
+//! lease history and invite code endpoints is a [`Message`] of [`MESSAGES`]:
+//! a key and an English template where `{}` stands for a value (a prefix, a
+//! count). Handlers render them with [`Message::text`], and [`localize`]
+//! translates what they rendered by key. Deployments translate them in a
+//! messages file and clients pick a language with `Accept-Language`;
+//! untranslated messages and clients asking for none of the languages get
+//! English.
+//!
+//! The file maps language tags to translations by key:
+//!
+//! ```json
+//! {"fr": {"prefix.leased": "Préfixe loué", "prefix.held": "Le préfixe {} est déjà loué"}}
+//! ```
+
+use anyhow::{Context, Result, bail};
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Request, State},
+    http::{HeaderValue, header},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// A client API message: its key in messages files and its English
+/// template, where `{}` stands for a value (a prefix, a count)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Message {
+    pub key: &'static str,
+    pub template: &'static str,
+}
+
+impl Message {
+    const fn new(key: &'static str, template: &'static str) -> Self {
+        Self { key, template }
+    }
+
+    /// English text of the message, with `values` in its placeholders
+    pub fn text(&self, values: &[&dyn fmt::Display]) -> String {
+        debug_assert_eq!(
+            values.len(),
+            self.template.matches("{}").count(),
+            "values of message {}",
+            self.key
+        );
+        let values: Vec<String> = values.iter().map(ToString::to_string).collect();
+        let text = render(
+            self.template,
+            &values.iter().map(String::as_str).collect::<Vec<_>>(),
+        );
+        // Outside a request being localized there is nothing to record
+        let _ = RENDERED.try_with(|rendered| {
+            rendered.borrow_mut().push(Rendered {
+                message: *self,
+                values,
+                text: text.clone(),
+            })
+        });
+        text
+    }
+}
+
+/// A message rendered while handling a request
+#[derive(Debug)]
+struct Rendered {
+    message: Message,
+    values: Vec<String>,
+    text: String,
+}
+
+tokio::task_local! {
+    /// Messages rendered by the request [`localize`] is handling
+    static RENDERED: RefCell<Vec<Rendered>>;
+}
+
+pub const ACCOUNT_REMOVED: Message = Message::new("account.removed", "Account no longer exists");
+pub const ACCOUNT_SUSPENDED: Message = Message::new("account.suspended", "Account is suspended");
+pub const ACCOUNT_VERIFY_FAILED: Message = Message::new(
+    "account.verify_failed",
+    "Failed to verify account with the identity provider",
+);
+pub const ASN_ALREADY_ASSIGNED: Message =
+    Message::new("asn.already_assigned", "ASN already assigned");
+pub const ASN_ASSIGN_FAILED: Message = Message::new("asn.assign_failed", "Failed to assign ASN");
+pub const ASN_ASSIGNED: Message = Message::new("asn.assigned", "ASN assigned successfully");
+pub const ASN_AVAILABILITY_FAILED: Message = Message::new(
+    "asn.availability_failed",
+    "Failed to check ASN availability",
+);
+pub const ASN_CHECK_FAILED: Message =
+    Message::new("asn.check_failed", "Failed to check ASN assignment");
+pub const ASN_INVALID_ABUSE_CONTACT: Message = Message::new(
+    "asn.invalid_abuse_contact",
+    "Invalid abuse contact email address",
+);
+pub const ASN_LIST_FAILED: Message = Message::new("asn.list_failed", "Failed to retrieve ASNs");
+pub const ASN_NOT_ASSIGNED: Message = Message::new("asn.not_assigned", "No ASN assigned");
+pub const ASN_NOT_IN_POOL: Message = Message::new("asn.not_in_pool", "ASN {} is not in the pool");
+pub const ASN_NOT_YOURS: Message = Message::new("asn.not_yours", "ASN {} is not assigned to you");
+pub const ASN_POOL_EXHAUSTED: Message =
+    Message::new("asn.pool_exhausted", "No available ASNs at this time");
+pub const ASN_POOL_UTILIZED: Message = Message::new("asn.pool_utilized", "ASN pool {}% utilized");
+pub const ASN_QUOTA_EXCEEDED: Message = Message::new(
+    "asn.quota_exceeded",
+    "ASN quota exceeded (at most {} ASN(s) per user), release one first",
+);
+pub const ASN_RELEASE_BLOCKED: Message = Message::new(
+    "asn.release_blocked",
+    "Release or let expire the {} active prefix lease(s) first",
+);
+pub const ASN_RELEASE_CHECK_FAILED: Message =
+    Message::new("asn.release_check_failed", "Failed to check active leases");
+pub const ASN_RELEASE_FAILED: Message = Message::new("asn.release_failed", "Failed to release ASN");
+pub const ASN_TAKEN: Message = Message::new("asn.taken", "ASN {} is already assigned");
+pub const ASN_UPDATE_FAILED: Message = Message::new("asn.update_failed", "Failed to update ASN");
+pub const ASN_WOULD_ASSIGN: Message = Message::new("asn.would_assign", "ASN would be assigned");
+pub const HISTORY_FAILED: Message =
+    Message::new("history.failed", "Failed to retrieve lease history");
+pub const HISTORY_INVALID_CURSOR: Message =
+    Message::new("history.invalid_cursor", "Invalid cursor");
+pub const HISTORY_LIMIT_OUT_OF_RANGE: Message = Message::new(
+    "history.limit_out_of_range",
+    "limit must be between 1 and {}",
+);
+pub const IDEMPOTENCY_BODY_TOO_LARGE: Message =
+    Message::new("idempotency.body_too_large", "Request body too large");
+pub const IDEMPOTENCY_CHECK_FAILED: Message = Message::new(
+    "idempotency.check_failed",
+    "Failed to check Idempotency-Key",
+);
+pub const IDEMPOTENCY_IN_PROGRESS: Message = Message::new(
+    "idempotency.in_progress",
+    "A request with this Idempotency-Key is still being processed",
+);
+pub const IDEMPOTENCY_INVALID_KEY: Message = Message::new(
+    "idempotency.invalid_key",
+    "Invalid Idempotency-Key header (1 to 255 visible ASCII characters)",
+);
+pub const IDEMPOTENCY_KEY_REUSED: Message = Message::new(
+    "idempotency.key_reused",
+    "Idempotency-Key already used for a different request",
+);
+pub const INVITE_ALREADY_REDEEMED: Message =
+    Message::new("invite.already_redeemed", "Invite code already redeemed");
+pub const INVITE_EXPIRED: Message = Message::new("invite.expired", "Invite code expired");
+pub const INVITE_INVALID: Message = Message::new("invite.invalid", "Invalid invite code");
+pub const INVITE_REDEEM_FAILED: Message =
+    Message::new("invite.redeem_failed", "Failed to redeem invite code");
+pub const INVITE_REDEEMED: Message = Message::new("invite.redeemed", "Invite code redeemed");
+pub const LEASE_APPROVAL_CHECK_FAILED: Message = Message::new(
+    "lease.approval_check_failed",
+    "Failed to check permanent lease approval",
+);
+pub const LEASE_CHECK_FAILED: Message =
+    Message::new("lease.check_failed", "Failed to check existing leases");
+pub const LEASE_COUNT_OUT_OF_RANGE: Message =
+    Message::new("lease.count_out_of_range", "count must be between 1 and {}");
+pub const LEASE_COUNT_QUOTA_EXCEEDED: Message = Message::new(
+    "lease.count_quota_exceeded",
+    "Lease quota exceeded (at most {} active lease(s) per user)",
+);
+pub const LEASE_DURATION_AMBIGUOUS: Message = Message::new(
+    "lease.duration_ambiguous",
+    "Give exactly one of duration_hours, duration_minutes and duration",
+);
+pub const LEASE_DURATION_NOT_WHOLE_MINUTES: Message = Message::new(
+    "lease.duration_not_whole_minutes",
+    "Duration must be a whole number of minutes",
+);
+pub const LEASE_DURATION_OUT_OF_RANGE: Message = Message::new(
+    "lease.duration_out_of_range",
+    "Duration must be between {} and {}",
+);
+pub const LEASE_INVALID_DURATION: Message =
+    Message::new("lease.invalid_duration", "Invalid ISO 8601 duration '{}'");
+pub const LEASE_INVALID_SITE: Message =
+    Message::new("lease.invalid_site", "Invalid site name '{}'");
+pub const LEASE_PERMANENT_NOT_APPROVED: Message = Message::new(
+    "lease.permanent_not_approved",
+    "Permanent leases need an admin's approval",
+);
+pub const LEASE_PERMANENT_NOT_RENEWED: Message = Message::new(
+    "lease.permanent_not_renewed",
+    "Permanent leases are not renewed",
+);
+pub const LEASE_PERMANENT_WITH_DURATION: Message = Message::new(
+    "lease.permanent_with_duration",
+    "A permanent lease takes no duration",
+);
+pub const LEASE_QUOTA_EXCEEDED: Message = Message::new(
+    "lease.quota_exceeded",
+    "Address space quota exceeded (at most {} /48 equivalent(s) per user)",
+);
+pub const LEASE_ROA_FAILED: Message =
+    Message::new("lease.roa_failed", "Failed to set ROA maxLength");
+pub const LEASE_ROA_MAX_LENGTH_OUT_OF_RANGE: Message = Message::new(
+    "lease.roa_max_length_out_of_range",
+    "roa_max_length must be between {} and {}",
+);
+pub const LEASE_ROA_SET_BY_ADMINS: Message = Message::new(
+    "lease.roa_set_by_admins",
+    "The ROA maxLength is set by admins",
+);
+pub const LEASE_SITES_REQUIRED: Message = Message::new(
+    "lease.sites_required",
+    "At least one site is required when pinning a lease",
+);
+pub const LEASE_START_IN_PAST: Message =
+    Message::new("lease.start_in_past", "start_time must not be in the past");
+pub const LEASE_START_TOO_FAR: Message =
+    Message::new("lease.start_too_far", "start_time must be within {} days");
+pub const LEASE_TEXT_CONTROL_CHARACTERS: Message = Message::new(
+    "lease.text_control_characters",
+    "{} must not contain control characters",
+);
+pub const LEASE_TEXT_TOO_LONG: Message =
+    Message::new("lease.text_too_long", "{} must be at most {} characters");
+pub const LEASE_UNKNOWN_SITE: Message = Message::new("lease.unknown_site", "Unknown site '{}'");
+pub const PREFIX_ALREADY_LEASED: Message =
+    Message::new("prefix.already_leased", "Prefix already leased");
+pub const PREFIX_AVAILABILITY_FAILED: Message = Message::new(
+    "prefix.availability_failed",
+    "Failed to check available prefixes",
+);
+pub const PREFIX_CLASS_POOL_UTILIZED: Message = Message::new(
+    "prefix.class_pool_utilized",
+    "Pool of {} prefixes {}% utilized",
+);
+pub const PREFIX_DISABLED: Message = Message::new("prefix.disabled", "Prefix {} is disabled");
+pub const PREFIX_HELD: Message = Message::new("prefix.held", "Prefix {} is currently leased");
+pub const PREFIX_HELD_BY_CALLER: Message = Message::new(
+    "prefix.held_by_caller",
+    "You already lease {}, renew it instead",
+);
+pub const PREFIX_HELD_IN_GRACE: Message = Message::new(
+    "prefix.held_in_grace",
+    "Prefix {} is in the grace period of another lease",
+);
+pub const PREFIX_INVALID: Message = Message::new("prefix.invalid", "Invalid IPv6 prefix");
+pub const PREFIX_INVALID_LENGTH: Message =
+    Message::new("prefix.invalid_length", "prefix_len must be {}");
+pub const PREFIX_INVALID_PATH: Message = Message::new(
+    "prefix.invalid_path",
+    "Invalid IPv6 prefix (encode the slash as %2F)",
+);
+pub const PREFIX_LEASE_FAILED: Message =
+    Message::new("prefix.lease_failed", "Failed to create prefix lease");
+pub const PREFIX_LEASED: Message = Message::new("prefix.leased", "Prefix leased successfully");
+pub const PREFIX_LENGTH_MISMATCH: Message = Message::new(
+    "prefix.length_mismatch",
+    "prefix_len {} doesn't match the prefix {}",
+);
+pub const PREFIX_LENGTH_NOT_LEASED: Message =
+    Message::new("prefix.length_not_leased", "The prefix length must be {}");
+pub const PREFIX_NOT_IN_POOL: Message =
+    Message::new("prefix.not_in_pool", "Prefix {} is not in the pool");
+pub const PREFIX_NOT_LEASED: Message =
+    Message::new("prefix.not_leased", "No active lease of this prefix");
+pub const PREFIX_NOT_OF_CLASS: Message =
+    Message::new("prefix.not_of_class", "Prefix {} is not of class {}");
+pub const PREFIX_ONLY_ALONE: Message = Message::new(
+    "prefix.only_alone",
+    "A specific prefix can only be requested alone",
+);
+pub const PREFIX_PARTIALLY_AVAILABLE: Message = Message::new(
+    "prefix.partially_available",
+    "Only {} of the {} prefixes requested are available",
+);
+pub const PREFIX_POOL_EXHAUSTED: Message = Message::new(
+    "prefix.pool_exhausted",
+    "No available prefixes at this time",
+);
+pub const PREFIX_POOL_UTILIZED: Message =
+    Message::new("prefix.pool_utilized", "Prefix pool {}% utilized");
+pub const PREFIX_RENEW_FAILED: Message =
+    Message::new("prefix.renew_failed", "Failed to renew prefix lease");
+pub const PREFIX_RENEWAL_OVERLAPS: Message = Message::new(
+    "prefix.renewal_overlaps",
+    "The renewal overlaps a later lease of this prefix",
+);
+pub const PREFIX_RENEWED: Message = Message::new("prefix.renewed", "Prefix lease renewed");
+pub const PREFIX_WOULD_LEASE: Message =
+    Message::new("prefix.would_lease", "Prefix would be leased");
+pub const QUOTA_REMAINING: Message = Message::new(
+    "quota.remaining",
+    "{} of {} /48 equivalent(s) of your quota remaining",
+);
+pub const USER_INFO_FAILED: Message =
+    Message::new("user.info_failed", "Failed to retrieve user information");
+
+/// Every client API message, by key
+pub const MESSAGES: &[Message] = &[
+    ACCOUNT_REMOVED,
+    ACCOUNT_SUSPENDED,
+    ACCOUNT_VERIFY_FAILED,
+    ASN_ALREADY_ASSIGNED,
+    ASN_ASSIGN_FAILED,
+    ASN_ASSIGNED,
+    ASN_AVAILABILITY_FAILED,
+    ASN_CHECK_FAILED,
+    ASN_INVALID_ABUSE_CONTACT,
+    ASN_LIST_FAILED,
+    ASN_NOT_ASSIGNED,
+    ASN_NOT_IN_POOL,
+    ASN_NOT_YOURS,
+    ASN_POOL_EXHAUSTED,
+    ASN_POOL_UTILIZED,
+    ASN_QUOTA_EXCEEDED,
+    ASN_RELEASE_BLOCKED,
+    ASN_RELEASE_CHECK_FAILED,
+    ASN_RELEASE_FAILED,
+    ASN_TAKEN,
+    ASN_UPDATE_FAILED,
+    ASN_WOULD_ASSIGN,
+    HISTORY_FAILED,
+    HISTORY_INVALID_CURSOR,
+    HISTORY_LIMIT_OUT_OF_RANGE,
+    IDEMPOTENCY_BODY_TOO_LARGE,
+    IDEMPOTENCY_CHECK_FAILED,
+    IDEMPOTENCY_IN_PROGRESS,
+    IDEMPOTENCY_INVALID_KEY,
+    IDEMPOTENCY_KEY_REUSED,
+    INVITE_ALREADY_REDEEMED,
+    INVITE_EXPIRED,
+    INVITE_INVALID,
+    INVITE_REDEEM_FAILED,
+    INVITE_REDEEMED,
+    LEASE_APPROVAL_CHECK_FAILED,
+    LEASE_CHECK_FAILED,
+    LEASE_COUNT_OUT_OF_RANGE,
+    LEASE_COUNT_QUOTA_EXCEEDED,
+    LEASE_DURATION_AMBIGUOUS,
+    LEASE_DURATION_NOT_WHOLE_MINUTES,
+    LEASE_DURATION_OUT_OF_RANGE,
+    LEASE_INVALID_DURATION,
+    LEASE_INVALID_SITE,
+    LEASE_PERMANENT_NOT_APPROVED,
+    LEASE_PERMANENT_NOT_RENEWED,
+    LEASE_PERMANENT_WITH_DURATION,
+    LEASE_QUOTA_EXCEEDED,
+    LEASE_ROA_FAILED,
+    LEASE_ROA_MAX_LENGTH_OUT_OF_RANGE,
+    LEASE_ROA_SET_BY_ADMINS,
+    LEASE_SITES_REQUIRED,
+    LEASE_START_IN_PAST,
+    LEASE_START_TOO_FAR,
+    LEASE_TEXT_CONTROL_CHARACTERS,
+    LEASE_TEXT_TOO_LONG,
+    LEASE_UNKNOWN_SITE,
+    PREFIX_ALREADY_LEASED,
+    PREFIX_AVAILABILITY_FAILED,
+    PREFIX_CLASS_POOL_UTILIZED,
+    PREFIX_DISABLED,
+    PREFIX_HELD,
+    PREFIX_HELD_BY_CALLER,
+    PREFIX_HELD_IN_GRACE,
+    PREFIX_INVALID,
+    PREFIX_INVALID_LENGTH,
+    PREFIX_INVALID_PATH,
+    PREFIX_LEASE_FAILED,
+    PREFIX_LEASED,
+    PREFIX_LENGTH_MISMATCH,
+    PREFIX_LENGTH_NOT_LEASED,
+    PREFIX_NOT_IN_POOL,
+    PREFIX_NOT_LEASED,
+    PREFIX_NOT_OF_CLASS,
+    PREFIX_ONLY_ALONE,
+    PREFIX_PARTIALLY_AVAILABLE,
+    PREFIX_POOL_EXHAUSTED,
+    PREFIX_POOL_UTILIZED,
+    PREFIX_RENEW_FAILED,
+    PREFIX_RENEWAL_OVERLAPS,
+    PREFIX_RENEWED,
+    PREFIX_WOULD_LEASE,
+    QUOTA_REMAINING,
+    USER_INFO_FAILED,
+];
+
+/// Largest response body inspected for messages
+const MAX_BODY: usize = 1024 * 1024;
+
+/// Values of the `{}` placeholders of a template in a message, or `None` if
+/// the message doesn't come from the template
+fn match_template<'a>(template: &str, message: &'a str) -> Option<Vec<&'a str>> {
+    let mut parts = template.split("{}");
+    let mut rest = message.strip_prefix(parts.next()?)?;
+    let mut values = Vec::new();
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        let end = if i == parts.len() - 1 {
+            // The last part anchors the end of the message
+            rest.strip_suffix(part)
+                .filter(|value| !value.is_empty())?
+                .len()
+        } else {
+            rest.find(part).filter(|&end| end > 0)?
+        };
+        values.push(&rest[..end]);
+        rest = &rest[end + part.len()..];
+    }
+    rest.is_empty().then_some(values)
+}
+
+/// Fill the `{}` placeholders of a template
+fn render(template: &str, values: &[&str]) -> String {
+    let mut text = String::new();
+    for (i, part) in template.split("{}").enumerate() {
+        if i > 0 {
+            text.push_str(values.get(i - 1).copied().unwrap_or_default());
+        }
+        text.push_str(part);
+    }
+    text
+}
+
+/// Translations of the messages, by language
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    /// Language tag (lowercase) to translations by key
+    languages: HashMap<String, HashMap<String, String>>,
+}
+
+impl Catalog {
+    /// Load translations from a JSON file, rejecting unknown keys and
+    /// translations that don't keep the placeholders of the template
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read messages file {}", path.display()))?;
+        let catalog = Self::parse(&content)?;
+        info!(
+            "Loaded translations of client messages in {} language(s)",
+            catalog.languages.len()
+        );
+        Ok(catalog)
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let languages: HashMap<String, HashMap<String, String>> =
+            serde_json::from_str(content).context("Invalid messages file")?;
+        for (language, translations) in &languages {
+            for (key, text) in translations {
+                let Some(Message { template, .. }) = MESSAGES.iter().find(|m| m.key == key) else {
+                    bail!("Unknown message key '{}' in language '{}'", key, language);
+                };
+                if text.matches("{}").count() != template.matches("{}").count() {
+                    bail!(
+                        "Translation of '{}' in language '{}' must have {} placeholder(s)",
+                        key,
+                        language,
+                        template.matches("{}").count()
+                    );
+                }
+            }
+        }
+        Ok(Self {
+            languages: languages
+                .into_iter()
+                .map(|(language, translations)| (language.to_ascii_lowercase(), translations))
+                .collect(),
+        })
+    }
+
+    /// Translated languages
+    pub fn languages(&self) -> impl Iterator<Item = &str> {
+        self.languages.keys().map(String::as_str)
+    }
+
+    /// Translations of a language by key
+    pub fn translations(&self, language: &str) -> Option<&HashMap<String, String>> {
+        self.languages.get(language)
+    }
+
+    /// Pick the language of a response from an `Accept-Language` header, if
+    /// one preferred over English is translated
+    pub fn negotiate(&self, accept_language: &str) -> Option<&str> {
+        let mut ranges: Vec<(&str, f32)> = accept_language
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';');
+                let tag = params.next()?.trim();
+                let quality = params
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+                (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+            })
+            .collect();
+        // Stable sort keeps the order of the header among equal qualities
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        for (tag, _) in ranges {
+            let tag = tag.to_ascii_lowercase();
+            let primary = tag.split('-').next().unwrap_or_default();
+            if primary == "en" {
+                return None;
+            }
+            for candidate in [tag.as_str(), primary] {
+                if let Some((language, _)) = self.languages.get_key_value(candidate) {
+                    return Some(language);
+                }
+            }
+        }
+        None
+    }
+
+    /// Translate a message into a language, if it has a translation
+    pub fn translate(&self, language: &str, message: &str) -> Option<String> {
+        let translations = self.languages.get(language)?;
+        MESSAGES.iter().find_map(|Message { key, template }| {
+            let values = match_template(template, message)?;
+            Some(render(translations.get(*key)?, &values))
+        })
+    }
+
+    /// Translate a message by the key and values a handler rendered it
+    /// with, falling back to the templates for text rendered by an earlier
+    /// request (a replayed response)
+    fn translate_rendered(
+        &self,
+        language: &str,
+        rendered: &[Rendered],
+        message: &str,
+    ) -> Option<String> {
+        let Some(rendered) = rendered.iter().find(|rendered| rendered.text == message) else {
+            return self.translate(language, message);
+        };
+        let translation = self.languages.get(language)?.get(rendered.message.key)?;
+        let values: Vec<&str> = rendered.values.iter().map(String::as_str).collect();
+        Some(render(translation, &values))
+    }
+}
+
+/// Translate `message` fields in place, returning whether any was
+fn translate_fields(translate: &impl Fn(&str) -> Option<String>, value: &mut Value) -> bool {
+    match value {
+        Value::Object(fields) => {
+            let mut translated = false;
+            for (key, field) in fields.iter_mut() {
+                if key == "message"
+                    && let Value::String(message) = field
+                    && let Some(text) = translate(message)
+                {
+                    *message = text;
+                    translated = true;
+                } else if key == "warnings"
+                    && let Value::Array(warnings) = field
+                {
+                    for warning in warnings {
+                        if let Value::String(warning) = warning
+                            && let Some(text) = translate(warning)
+                        {
+                            *warning = text;
+                            translated = true;
+                        }
+                    }
+                } else {
+                    translated |= translate_fields(translate, field);
+                }
+            }
+            translated
+        }
+        Value::Array(items) => items.iter_mut().fold(false, |translated, item| {
+            translate_fields(translate, item) | translated
+        }),
+        _ => false,
+    }
+}
+
+/// Translate the messages of JSON responses into the client's language
+pub async fn localize(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let catalog = state.messages.load();
+    let language = request
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| catalog.negotiate(value))
+        .map(str::to_string);
+    let Some(language) = language else {
+        return next.run(request).await;
+    };
+    let (response, rendered) = RENDERED
+        .scope(RefCell::new(Vec::new()), async {
+            let response = next.run(request).await;
+            (response, RENDERED.with(RefCell::take))
+        })
+        .await;
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match to_bytes(body, MAX_BODY).await {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("Failed to read response body to translate: {}", err);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let Ok(mut fields) = serde_json::from_slice::<Value>(&body) else {
+        return Response::from_parts(parts, Body::from(body));
+    };
+    let translate = |message: &str| catalog.translate_rendered(&language, &rendered, message);
+    if !translate_fields(&translate, &mut fields) {
+        return Response::from_parts(parts, Body::from(body));
+    }
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    if let Ok(language) = HeaderValue::from_str(&language) {
+        parts.headers.insert(header::CONTENT_LANGUAGE, language);
+    }
+    Response::from_parts(parts, Body::from(fields.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog() -> Catalog {
+        Catalog::parse(
+            r#"{"fr": {
+                "prefix.leased": "Préfixe loué",
+                "prefix.pool_utilized": "Pool de préfixes utilisé à {}%",
+                "prefix.not_of_class": "Le préfixe {} n'est pas de la classe {}"
+            }}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_templates() {
+        assert_eq!(
+            match_template(
+                "Prefix {} is not of class {}",
+                "Prefix 2001:db8::/48 is not of class ula"
+            ),
+            Some(vec!["2001:db8::/48", "ula"])
+        );
+        assert_eq!(
+            match_template("Invalid cursor", "Invalid cursor"),
+            Some(vec![])
+        );
+        assert_eq!(match_template("Invalid cursor", "Invalid cursor!"), None);
+        assert_eq!(
+            match_template("count must be between 1 and {}", "limit"),
+            None
+        );
+        assert_eq!(render("{} of {}", &["1", "2"]), "1 of 2");
+        for Message { template, .. } in MESSAGES {
+            let values = vec!["x"; template.matches("{}").count()];
+            assert!(match_template(template, &render(template, &values)).is_some());
+        }
+    }
+
+    #[test]
+    fn test_translation() {
+        let catalog = catalog();
+        assert_eq!(catalog.negotiate("fr-CH, fr;q=0.9, en;q=0.8"), Some("fr"));
+        assert_eq!(catalog.negotiate("en-US, fr;q=0.5"), None);
+        assert_eq!(catalog.negotiate("de, fr;q=0"), None);
+        assert_eq!(
+            catalog.translate("fr", "Prefix 2001:db8::/48 is not of class ula"),
+            Some("Le préfixe 2001:db8::/48 n'est pas de la classe ula".to_string())
+        );
+        // Untranslated messages stay in English
+        assert_eq!(catalog.translate("fr", "Prefix lease renewed"), None);
+
+        let mut body = serde_json::json!({"leases": [{"message": "Prefix leased successfully"}]});
+        assert!(translate_fields(
+            &|text| catalog.translate("fr", text),
+            &mut body
+        ));
+        assert_eq!(body["leases"][0]["message"], "Préfixe loué");
+
+        let mut body = serde_json::json!({"warnings": ["Prefix pool 95% utilized"]});
+        assert!(translate_fields(
+            &|text| catalog.translate("fr", text),
+            &mut body
+        ));
+        assert_eq!(body["warnings"][0], "Pool de préfixes utilisé à 95%");
+
+        // Rendered messages are translated by key and values, even when
+        // their values contain the text of the template
+        let (text, rendered) = RENDERED.sync_scope(RefCell::new(Vec::new()), || {
+            let text = PREFIX_NOT_OF_CLASS.text(&[&"x is not of class y", &"ula"]);
+            (text, RENDERED.with(RefCell::take))
+        });
+        assert_eq!(
+            catalog.translate_rendered("fr", &rendered, &text),
+            Some("Le préfixe x is not of class y n'est pas de la classe ula".to_string())
+        );
+        assert_ne!(
+            catalog.translate("fr", &text),
+            catalog.translate_rendered("fr", &rendered, &text)
+        );
+    }
+
+    #[test]
+    fn test_invalid_catalogs() {
+        assert!(Catalog::parse(r#"{"fr": {"prefix.typo": "x"}}"#).is_err());
+        assert!(Catalog::parse(r#"{"fr": {"prefix.held": "Déjà loué"}}"#).is_err());
+    }
+}
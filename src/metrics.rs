@@ -0,0 +1,123 @@
+use axum::{
+    extract::State,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use std::fmt::Write;
+use tracing::error;
+
+use crate::{AppState, stats};
+
+/// A Prometheus gauge with labelled samples
+struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    samples: Vec<(String, f64)>,
+}
+
+impl Gauge {
+    fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            samples: Vec::new(),
+        }
+    }
+
+    fn sample(&mut self, labels: &str, value: f64) {
+        self.samples.push((labels.to_string(), value));
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} gauge", self.name);
+        for (labels, value) in &self.samples {
+            let value = if value.is_infinite() {
+                "+Inf".to_string()
+            } else {
+                value.to_string()
+            };
+            let _ = writeln!(out, "{}{{{}}} {}", self.name, labels, value);
+        }
+    }
+}
+
+/// Render pool forecasts in the Prometheus text exposition format
+pub fn render_pool_metrics(forecasts: &[stats::PoolForecast]) -> String {
+    let mut capacity = Gauge::new("peerlab_pool_capacity", "Total resources in the pool");
+    let mut used = Gauge::new("peerlab_pool_used", "Resources currently allocated");
+    let mut utilization = Gauge::new(
+        "peerlab_pool_utilization_ratio",
+        "Fraction of the pool currently allocated",
+    );
+    let mut exhaustion = Gauge::new(
+        "peerlab_pool_exhaustion_forecast_days",
+        "Days until the pool is exhausted at the current allocation rate (+Inf if not growing)",
+    );
+
+    for forecast in forecasts {
+        let labels = format!("pool=\"{}\"", forecast.pool);
+        capacity.sample(&labels, forecast.capacity as f64);
+        used.sample(&labels, forecast.used as f64);
+        utilization.sample(&labels, forecast.utilization);
+        exhaustion.sample(
+            &labels,
+            forecast.days_until_exhaustion.unwrap_or(f64::INFINITY),
+        );
+    }
+
+    let mut out = String::new();
+    for gauge in [capacity, used, utilization, exhaustion] {
+        gauge.render(&mut out);
+    }
+    out
+}
+
+/// Prometheus scrape endpoint
+pub async fn get_metrics(State(state): State<AppState>) -> Response {
+    match stats::pool_forecasts(&state, stats::DEFAULT_FORECAST_WINDOW_DAYS).await {
+        Ok(forecasts) => (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            render_pool_metrics(&forecasts),
+        )
+            .into_response(),
+        Err(err) => {
+            error!("Failed to compute pool metrics: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_pool_metrics() {
+        let forecasts = vec![
+            stats::PoolForecast {
+                pool: "asn",
+                capacity: 1000,
+                used: 100,
+                utilization: 0.1,
+                rate_per_day: Some(10.0),
+                days_until_exhaustion: Some(90.0),
+            },
+            stats::PoolForecast {
+                pool: "prefix",
+                capacity: 4,
+                used: 1,
+                utilization: 0.25,
+                rate_per_day: None,
+                days_until_exhaustion: None,
+            },
+        ];
+
+        let output = render_pool_metrics(&forecasts);
+        assert!(output.contains("# TYPE peerlab_pool_capacity gauge\n"));
+        assert!(output.contains("peerlab_pool_used{pool=\"asn\"} 100\n"));
+        assert!(output.contains("peerlab_pool_utilization_ratio{pool=\"prefix\"} 0.25\n"));
+        assert!(output.contains("peerlab_pool_exhaustion_forecast_days{pool=\"asn\"} 90\n"));
+        assert!(output.contains("peerlab_pool_exhaustion_forecast_days{pool=\"prefix\"} +Inf\n"));
+    }
+}
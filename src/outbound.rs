@@ -0,0 +1,237 @@
+//! Outgoing requests to user- and admin-supplied URLs.
+//!
+//! Webhook and alert URLs must point at public hosts, so the gateway can't be
+//! used to probe its own network (loopback, RFC 1918, link-local metadata
+//! services, ULAs). URLs are checked when registered and again before every
+//! request; host names are resolved by [`GlobalResolver`], which drops
+//! non-global addresses, and the connection goes to the addresses it
+//! returned, so a DNS answer changing between the check and the connection
+//! can't get around it.
+
+use reqwest::{
+    Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect,
+};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Redirects followed before giving up
+const MAX_REDIRECTS: usize = 5;
+
+/// Whether an address is globally routable unicast space
+pub fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "This network" and reserved space
+                || a == 0
+                || a >= 240
+                // Shared address space (carrier-grade NAT)
+                || (a == 100 && (64..128).contains(&b))
+                // IETF protocol assignments
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking
+                || (a == 198 && (b == 18 || b == 19)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_global(IpAddr::V4(ip)),
+            None => {
+                let [first, second, ..] = ip.segments();
+                // Global unicast space, less documentation, Teredo and 6to4
+                // (which embed IPv4 addresses)
+                first & 0xe000 == 0x2000
+                    && !(first == 0x2001 && (second == 0x0db8 || second == 0))
+                    && first != 0x2002
+            }
+        },
+    }
+}
+
+/// Validate a webhook or alert target URL: HTTP(S) to a public host
+pub fn validate_url(url: &str) -> Result<Url, String> {
+    let parsed = Url::parse(url).map_err(|e| format!("Invalid URL: {}", e))?;
+    match parsed.scheme() {
+        "http" | "https" => {}
+        scheme => return Err(format!("Unsupported URL scheme '{}'", scheme)),
+    }
+    check_host(&parsed)?;
+    Ok(parsed)
+}
+
+/// Reject URLs whose host is a non-global address or a local name
+fn check_host(url: &Url) -> Result<(), String> {
+    // IPv4 hosts are normalized by the parser (e.g. `2130706433` is
+    // `127.0.0.1`), IPv6 hosts are bracketed
+    let host = url.host_str().unwrap_or_default();
+    let allowed = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => is_global(ip),
+        Err(_) => !host.is_empty() && !is_local_name(host),
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(format!("URL host '{}' is not a public address", host))
+    }
+}
+
+fn is_local_name(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    domain == "localhost" || domain.ends_with(".localhost")
+}
+
+/// A host name resolving to no public address
+#[derive(Debug)]
+struct BlockedDestination(String);
+
+impl fmt::Display for BlockedDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} has no public address", self.0)
+    }
+}
+
+impl std::error::Error for BlockedDestination {}
+
+/// Resolver keeping only the global addresses of a host
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlobalResolver;
+
+impl Resolve for GlobalResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            if is_local_name(&host) {
+                return Err(BlockedDestination(host).into());
+            }
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_global(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(BlockedDestination(host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Client for requests to webhook and alert URLs: host names go through
+/// [`GlobalResolver`] and redirects to non-global addresses are refused
+pub fn client(timeout: Duration) -> reqwest::Client {
+    let policy = redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if let Err(err) = check_host(attempt.url()) {
+            attempt.error(err)
+        } else {
+            attempt.follow()
+        }
+    });
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(policy)
+        .dns_resolver(Arc::new(GlobalResolver))
+        .build()
+        .unwrap_or_else(|_| reqwest::Client::new())
+}
+
+/// Error of a request, safe to show to the owner of the URL: the target's
+/// own error messages are only logged
+pub fn describe_error(err: &reqwest::Error) -> &'static str {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(err);
+    while let Some(err) = source {
+        if err.is::<BlockedDestination>() {
+            return "Destination is not a public address";
+        }
+        source = err.source();
+    }
+    if err.is_timeout() {
+        "Request timed out"
+    } else if err.is_redirect() {
+        "Redirect refused"
+    } else if err.is_connect() {
+        "Connection failed"
+    } else {
+        "Request failed"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_global() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fe80::1",
+            "fd00::1",
+            "ff02::1",
+            "2001:db8::1",
+            "::ffff:10.0.0.1",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_global(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["1.1.1.1", "2606:4700::1111", "::ffff:1.1.1.1"] {
+            assert!(is_global(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_validate_url() {
+        assert!(validate_url("https://example.com/hook").is_ok());
+        assert!(validate_url("http://[2606:4700::1111]:8080/hook").is_ok());
+        assert!(validate_url("ftp://example.com/hook").is_err());
+        assert!(validate_url("not a url").is_err());
+        for url in [
+            "http://localhost/hook",
+            "http://api.localhost./hook",
+            "http://127.0.0.1:8080/hook",
+            "http://2130706433/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[::ffff:192.168.0.1]/hook",
+        ] {
+            assert!(
+                validate_url(url)
+                    .unwrap_err()
+                    .contains("is not a public address"),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_refuses_local_names() {
+        // Resolving happens at connection time, past any earlier check
+        let err = client(Duration::from_secs(5))
+            .post("http://localhost:1/hook")
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(describe_error(&err), "Destination is not a public address");
+    }
+}
@@ -0,0 +1,86 @@
+use axum::{
+    BoxError, Json, Router,
+    error_handling::HandleErrorLayer,
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use tower::{ServiceBuilder, limit::GlobalConcurrencyLimitLayer};
+use tracing::warn;
+
+/// Seconds clients are asked to wait before retrying a shed request
+pub const RETRY_AFTER_SECONDS: u64 = 1;
+
+/// Cap the requests a router handles at once, shedding the excess.
+///
+/// Requests over the limit are rejected immediately with `503` and a
+/// `Retry-After` header instead of queueing until the database pool starves.
+/// The limit is shared by all routes (axum layers each route separately).
+pub fn limit_concurrency(router: Router, limit: Option<usize>) -> Router {
+    let Some(limit) = limit else {
+        return router;
+    };
+    router.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(shed))
+            .load_shed()
+            .layer(GlobalConcurrencyLimitLayer::new(limit)),
+    )
+}
+
+async fn shed(err: BoxError) -> Response {
+    warn!("Shedding request: {}", err);
+    let mut response = (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!({
+            "error": 503,
+            "message": "Server is overloaded, retry later"
+        })),
+    )
+        .into_response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECONDS));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request, routing::get};
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_excess_requests_are_shed() {
+        let (entered, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        let (entering, waiter) = (entered.clone(), release.clone());
+        let router = limit_concurrency(
+            Router::new()
+                .route(
+                    "/slow",
+                    get(move || {
+                        let (entering, waiter) = (entering.clone(), waiter.clone());
+                        async move {
+                            entering.notify_one();
+                            waiter.notified().await
+                        }
+                    }),
+                )
+                .route("/fast", get(|| async {})),
+            Some(1),
+        );
+
+        let request = |path| Request::get(path).body(Body::empty()).unwrap();
+        let busy = tokio::spawn(router.clone().oneshot(request("/slow")));
+        entered.notified().await;
+
+        let response = router.clone().oneshot(request("/fast")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        release.notify_one();
+        assert_eq!(busy.await.unwrap().unwrap().status(), StatusCode::OK);
+    }
+}
@@ -1,58 +1,286 @@
-use tracing::{debug, info};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+use tracing::{Span, debug, info, instrument, warn};
 
-use crate::database::Database;
+use crate::store::Store;
+use crate::types::Asn;
+
+/// Largest 32-bit ASN
+pub const MAX_ASN: i64 = u32::MAX as i64;
+
+/// Well-known class of an ASN
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AsnClass {
+    /// Assignable by RIRs, possibly belonging to a real network
+    Public,
+    /// Private use (RFC 6996)
+    Private,
+    /// Documentation and examples (RFC 5398)
+    Documentation,
+    /// Reserved, must not be used (RFC 7300, RFC 6793 `AS_TRANS`, IANA reserved)
+    Reserved,
+}
+
+/// Non-public ASN ranges, sorted; everything else is public
+const SPECIAL_RANGES: [(i64, i64, AsnClass); 9] = [
+    (0, 0, AsnClass::Reserved),
+    (23456, 23456, AsnClass::Reserved),
+    (64496, 64511, AsnClass::Documentation),
+    (64512, 65534, AsnClass::Private),
+    (65535, 65535, AsnClass::Reserved),
+    (65536, 65551, AsnClass::Documentation),
+    (65552, 131071, AsnClass::Reserved),
+    (4200000000, 4294967294, AsnClass::Private),
+    (4294967295, 4294967295, AsnClass::Reserved),
+];
+
+/// Classify a single ASN
+pub fn classify(asn: i64) -> AsnClass {
+    SPECIAL_RANGES
+        .iter()
+        .find(|(start, end, _)| (*start..=*end).contains(&asn))
+        .map_or(AsnClass::Public, |(_, _, class)| *class)
+}
+
+/// Split an inclusive range into segments of a single class
+pub fn classify_range(start: i64, end: i64) -> Vec<(i64, i64, AsnClass)> {
+    let mut segments = Vec::new();
+    let mut next = start;
+    for &(special_start, special_end, class) in &SPECIAL_RANGES {
+        if next > end {
+            break;
+        }
+        if special_end < next || special_start > end {
+            continue;
+        }
+        if special_start > next {
+            segments.push((next, special_start - 1, AsnClass::Public));
+        }
+        let segment_end = special_end.min(end);
+        segments.push((next.max(special_start), segment_end, class));
+        next = segment_end + 1;
+    }
+    if next <= end {
+        segments.push((next, end, AsnClass::Public));
+    }
+    segments
+}
 
 /// ASN pool manager
+///
+/// The pool is a sorted list of disjoint inclusive ranges, either a single
+/// start/end pair or ranges loaded from a pool file.
 #[derive(Debug, Clone)]
 pub struct AsnPool {
-    start: i32,
-    end: i32,
+    ranges: Vec<(i64, i64)>,
 }
 
 impl AsnPool {
     /// Create a new ASN pool with a range
-    pub fn new(start: i32, end: i32) -> Self {
-        info!("Created ASN pool: {} - {} ({} ASNs)", start, end, end - start + 1);
-        Self { start, end }
+    pub fn new(start: i64, end: i64) -> Self {
+        info!(
+            "Created ASN pool: {} - {} ({} ASNs)",
+            start,
+            end,
+            end - start + 1
+        );
+        Self {
+            ranges: vec![(start, end)],
+        }
+    }
+
+    /// Create an ASN pool from several ranges, merging overlapping ones
+    pub fn from_ranges(mut ranges: Vec<(i64, i64)>) -> Self {
+        ranges.sort();
+        let mut merged: Vec<(i64, i64)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => {
+                    if start <= last.1 {
+                        warn!(
+                            "ASN range {}-{} overlaps {}-{}, merging",
+                            start, end, last.0, last.1
+                        );
+                    }
+                    last.1 = last.1.max(end);
+                }
+                _ => merged.push((start, end)),
+            }
+        }
+        Self { ranges: merged }
+    }
+
+    /// Load ASN ranges from a file
+    ///
+    /// Each line is a range (`65000-65099`) or a single ASN (`65100`). Lines
+    /// starting with `#` are comments.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path.as_ref())?;
+        let mut ranges = Vec::new();
+
+        for (line_num, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            // Skip empty lines and comments
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match parse_range(line) {
+                Ok(range) => ranges.push(range),
+                Err(e) => {
+                    warn!(
+                        "Line {}: Failed to parse ASN range '{}': {}",
+                        line_num + 1,
+                        line,
+                        e
+                    );
+                }
+            }
+        }
+
+        let pool = Self::from_ranges(ranges);
+        info!(
+            "Loaded {} ASN ranges ({} ASNs) from file",
+            pool.ranges.len(),
+            pool.size()
+        );
+        Ok(pool)
     }
 
     /// Find an available ASN that is not currently assigned in the database
-    pub async fn find_available_asn(&self, database: &Database) -> Result<Option<i32>, sqlx::Error> {
-        // Get all currently assigned ASNs from database
-        let all_mappings = database.get_all_user_mappings().await?;
-        let assigned_asns: Vec<i32> = all_mappings.iter().map(|(m, _)| m.asn).collect();
+    #[instrument(name = "pool", skip_all, fields(operation = "find_available_asn", asn = tracing::field::Empty))]
+    pub async fn find_available_asn(&self, store: &dyn Store) -> Result<Option<Asn>, sqlx::Error> {
+        // Get all currently assigned ASNs from the store
+        let all_mappings = store.get_all_asn_mappings().await?;
+        let assigned_asns: HashSet<i64> = all_mappings.iter().map(|m| m.asn).collect();
 
         // Find first available ASN in the pool
-        for asn in self.start..=self.end {
-            if !assigned_asns.contains(&asn) {
-                debug!("Found available ASN: {}", asn);
-                return Ok(Some(asn));
+        for &(start, end) in &self.ranges {
+            for asn in start..=end {
+                if !assigned_asns.contains(&asn)
+                    && let Ok(asn) = Asn::try_from(asn)
+                {
+                    Span::current().record("asn", asn.get());
+                    debug!("Found available ASN: {}", asn);
+                    return Ok(Some(asn));
+                }
             }
         }
 
-        debug!("No available ASNs in pool (all {} ASNs assigned)", self.size());
+        debug!(
+            "No available ASNs in pool (all {} ASNs assigned)",
+            self.size()
+        );
         Ok(None)
     }
 
     /// Get the total number of ASNs in the pool
-    pub fn size(&self) -> i32 {
-        self.end - self.start + 1
+    pub fn size(&self) -> i64 {
+        self.ranges
+            .iter()
+            .map(|(start, end)| (end - start + 1).max(0))
+            .sum()
+    }
+
+    /// Get the ranges of the pool
+    pub fn ranges(&self) -> &[(i64, i64)] {
+        &self.ranges
+    }
+
+    /// Check whether an ASN belongs to the pool
+    pub fn contains(&self, asn: i64) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(start, end)| (start..=end).contains(&asn))
+    }
+
+    /// Split the pool into segments of a single class
+    pub fn classify(&self) -> Vec<(i64, i64, AsnClass)> {
+        self.ranges
+            .iter()
+            .flat_map(|&(start, end)| classify_range(start, end))
+            .collect()
+    }
+
+    /// Number of ASNs of each class in the pool
+    pub fn class_sizes(&self) -> BTreeMap<AsnClass, i64> {
+        let mut sizes = BTreeMap::new();
+        for (start, end, class) in self.classify() {
+            *sizes.entry(class).or_insert(0) += end - start + 1;
+        }
+        sizes
+    }
+
+    /// Warnings about pool ranges outside private ASN space
+    pub fn policy_warnings(&self) -> Vec<String> {
+        self.classify()
+            .into_iter()
+            .filter_map(|(start, end, class)| match class {
+                AsnClass::Public => Some(format!(
+                    "ASN pool range {}-{} overlaps public ASN space; these ASNs may belong to real networks",
+                    start, end
+                )),
+                AsnClass::Reserved => Some(format!(
+                    "ASN pool range {}-{} contains reserved ASNs, which must not be used",
+                    start, end
+                )),
+                AsnClass::Documentation => Some(format!(
+                    "ASN pool range {}-{} contains documentation ASNs, which should not appear in real routing",
+                    start, end
+                )),
+                AsnClass::Private => None,
+            })
+            .collect()
     }
 
     /// Get the start of the ASN range
-    pub fn start(&self) -> i32 {
-        self.start
+    pub fn start(&self) -> i64 {
+        self.ranges.first().map_or(0, |r| r.0)
     }
 
     /// Get the end of the ASN range
-    pub fn end(&self) -> i32 {
-        self.end
+    pub fn end(&self) -> i64 {
+        self.ranges.last().map_or(0, |r| r.1)
+    }
+}
+
+/// Parse `start-end` or a single ASN
+fn parse_range(line: &str) -> Result<(i64, i64), String> {
+    let parse = |s: &str| -> Result<i64, String> {
+        let asn: i64 = s
+            .trim()
+            .trim_start_matches("AS")
+            .parse()
+            .map_err(|e| format!("invalid ASN '{}': {}", s.trim(), e))?;
+        if !(1..=MAX_ASN).contains(&asn) {
+            return Err(format!("ASN {} is out of range", asn));
+        }
+        Ok(asn)
+    };
+
+    let (start, end) = match line.split_once('-') {
+        Some((start, end)) => (parse(start)?, parse(end)?),
+        None => {
+            let asn = parse(line)?;
+            (asn, asn)
+        }
+    };
+    if start > end {
+        return Err(format!("start {} is after end {}", start, end));
     }
+    Ok((start, end))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
 
     #[test]
     fn test_asn_pool_size() {
@@ -67,4 +295,82 @@ mod tests {
         assert_eq!(pool.end(), 65099);
         assert_eq!(pool.size(), 100);
     }
+
+    #[test]
+    fn test_load_ranges_from_file() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "# Lab ASNs").unwrap();
+        writeln!(file, "4200000000-4200000999").unwrap();
+        writeln!(file, "65000-65099").unwrap();
+        writeln!(file).unwrap();
+        writeln!(file, "65100").unwrap();
+        writeln!(file, "65300-65200").unwrap();
+        writeln!(file, "4294967296").unwrap();
+
+        let pool = AsnPool::from_file(file.path()).unwrap();
+        assert_eq!(pool.ranges(), &[(65000, 65100), (4200000000, 4200000999)]);
+        assert_eq!(pool.size(), 1101);
+        assert!(pool.contains(4200000500));
+        assert!(!pool.contains(65101));
+        assert_eq!(pool.end(), 4200000999);
+    }
+
+    #[test]
+    fn test_overlapping_ranges_are_merged() {
+        let pool = AsnPool::from_ranges(vec![(65050, 65150), (65000, 65099), (65200, 65200)]);
+        assert_eq!(pool.ranges(), &[(65000, 65150), (65200, 65200)]);
+        assert_eq!(pool.size(), 152);
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(65001), AsnClass::Private);
+        assert_eq!(classify(4200000000), AsnClass::Private);
+        assert_eq!(classify(64500), AsnClass::Documentation);
+        assert_eq!(classify(23456), AsnClass::Reserved);
+        assert_eq!(classify(13335), AsnClass::Public);
+        assert_eq!(classify(400000), AsnClass::Public);
+    }
+
+    #[test]
+    fn test_classify_range() {
+        assert_eq!(
+            classify_range(64000, 65600),
+            vec![
+                (64000, 64495, AsnClass::Public),
+                (64496, 64511, AsnClass::Documentation),
+                (64512, 65534, AsnClass::Private),
+                (65535, 65535, AsnClass::Reserved),
+                (65536, 65551, AsnClass::Documentation),
+                (65552, 65600, AsnClass::Reserved),
+            ]
+        );
+        assert_eq!(
+            classify_range(65000, 65999),
+            vec![
+                (65000, 65534, AsnClass::Private),
+                (65535, 65535, AsnClass::Reserved),
+                (65536, 65551, AsnClass::Documentation),
+                (65552, 65999, AsnClass::Reserved),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_policy_warnings() {
+        let pool = AsnPool::from_ranges(vec![(65000, 65099), (4200000000, 4200000999)]);
+        assert!(pool.policy_warnings().is_empty());
+        assert_eq!(pool.class_sizes()[&AsnClass::Private], 1100);
+
+        let pool = AsnPool::new(64000, 64599);
+        assert_eq!(pool.policy_warnings().len(), 2);
+        assert_eq!(pool.class_sizes()[&AsnClass::Public], 496);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("AS65000 - AS65010"), Ok((65000, 65010)));
+        assert!(parse_range("0").is_err());
+        assert!(parse_range("abc").is_err());
+    }
 }
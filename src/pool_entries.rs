@@ -0,0 +1,367 @@
+//! Prefix pool entries managed at runtime.
+//!
+//! The pool file lists the prefixes the gateway starts with. Operators grow
+//! or shrink the pool through `/admin/pools/prefixes` instead of editing the
+//! file and restarting: entries are kept in the [pool store](crate::pool_store)
+//! and applied on top of the file. An entry adds a prefix, overrides its class,
+//! disables it (kept in the pool but no longer allocated) or retires it
+//! (removed from the pool, even when listed in the file). Every process
+//! loads the entries at startup, after each change it serves and every
+//! [`REFRESH_INTERVAL`], so the processes of a split deployment converge.
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{error, info, instrument, warn};
+
+use crate::AppState;
+use crate::database::PrefixPoolEntry;
+use crate::pool_prefixes::{PoolEntry, PoolStatus, PrefixClass, PrefixPool, format_lengths};
+use crate::reload::diff_prefix_pools;
+use crate::types::Prefix;
+
+/// How often each process loads the entries changed by the others
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest note kept with an entry, in characters
+const MAX_NOTE_LENGTH: usize = 256;
+
+#[derive(Deserialize)]
+pub struct SetPoolPrefixRequest {
+    /// `active` (the default), `disabled` or `retired`
+    #[serde(default = "default_status")]
+    status: PoolStatus,
+    /// Class overriding the one of the pool file or of the address
+    #[serde(default)]
+    class: Option<PrefixClass>,
+    /// Why the prefix was added or taken out, for other operators
+    #[serde(default)]
+    note: Option<String>,
+}
+
+fn default_status() -> PoolStatus {
+    PoolStatus::Active
+}
+
+/// Entry applied to the pool, from a row of the `prefix_pool` table
+fn pool_entry(row: &PrefixPoolEntry) -> Result<PoolEntry, String> {
+    Ok(PoolEntry {
+        prefix: Prefix::from_str(&row.prefix)?.net(),
+        class: row
+            .class
+            .as_deref()
+            .map(PrefixClass::from_str)
+            .transpose()?,
+        status: row.status.parse()?,
+    })
+}
+
+/// Pool with the entries applied, and the changes from the current pool
+fn apply_entries(
+    current: &PrefixPool,
+    file: &PrefixPool,
+    entries: &[PoolEntry],
+) -> (PrefixPool, Vec<String>) {
+    let pool = file.with_entries(entries);
+    let mut changes = diff_prefix_pools(current, &pool);
+    for prefix in pool.get_all_prefixes() {
+        if current.contains(prefix) && current.is_disabled(prefix) != pool.is_disabled(prefix) {
+            let change = if pool.is_disabled(prefix) {
+                "disabled"
+            } else {
+                "enabled"
+            };
+            changes.push(format!("{} {}", prefix, change));
+        }
+    }
+    (pool, changes)
+}
+
+/// Load the entries from the pool store and apply them to the pool file,
+/// returning what changed
+#[instrument(name = "pool", skip_all, fields(operation = "refresh_entries", store = state.pool_store.backend()))]
+pub async fn refresh(state: &AppState) -> anyhow::Result<Vec<String>> {
+    let rows = state.pool_store.entries().await?;
+    let entries: Vec<PoolEntry> = rows
+        .iter()
+        .filter_map(|row| match pool_entry(row) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                warn!("Ignoring prefix pool entry {}: {}", row.prefix, err);
+                None
+            }
+        })
+        .collect();
+
+    let (pool, changes) = apply_entries(
+        &state.prefix_pool.load(),
+        &state.prefix_pool_file.load(),
+        &entries,
+    );
+    state.prefix_pool_entries.replace(entries);
+    state.prefix_pool.replace(pool);
+    for change in &changes {
+        info!("Updated prefix pool: {}", change);
+    }
+    Ok(changes)
+}
+
+/// Spawn the task loading the entries changed by other processes
+pub fn spawn(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(REFRESH_INTERVAL).await;
+            if let Err(err) = refresh(&state).await {
+                error!("Failed to refresh the prefix pool entries: {:#}", err);
+            }
+        }
+    });
+}
+
+fn entry_json(state: &AppState, row: &PrefixPoolEntry) -> Value {
+    let in_file = Prefix::from_str(&row.prefix)
+        .is_ok_and(|prefix| state.prefix_pool_file.load().contains(&prefix.net()));
+    json!({
+        "prefix": row.prefix,
+        "class": row.class,
+        "status": row.status,
+        "note": row.note,
+        "in_file": in_file,
+        "created_at": row.created_at,
+        "updated_at": row.updated_at,
+    })
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.into()
+        })),
+    )
+}
+
+/// Parse a prefix of the path, of one of the lengths accepted for the pool
+fn parse_prefix(prefix: &str, lengths: &[u8]) -> Result<Prefix, ApiError> {
+    let prefix: Prefix = prefix.parse().map_err(|_| {
+        api_error(
+            StatusCode::BAD_REQUEST,
+            "Invalid IPv6 prefix (encode the slash as %2F)",
+        )
+    })?;
+    if !lengths.contains(&prefix.net().prefix_len()) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("The prefix length must be {}", format_lengths(lengths)),
+        ));
+    }
+    Ok(prefix)
+}
+
+/// Pick up a change right away instead of at the next refresh
+async fn refresh_after_change(state: &AppState) {
+    if let Err(err) = refresh(state).await {
+        error!("Failed to refresh the prefix pool entries: {:#}", err);
+    }
+}
+
+/// List the prefixes managed at runtime, with the size of the resulting pool
+#[instrument(name = "handler", skip_all, fields(operation = "list_pool_prefixes"))]
+pub async fn list_prefixes(State(state): State<AppState>) -> Result<Json<Value>, ApiError> {
+    let rows = state.pool_store.entries().await.map_err(|err| {
+        error!("Failed to list prefix pool entries: {:#}", err);
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to list prefix pool entries",
+        )
+    })?;
+
+    let pool = state.prefix_pool.load();
+    let disabled = pool
+        .get_all_prefixes()
+        .iter()
+        .filter(|prefix| pool.is_disabled(prefix))
+        .count();
+    Ok(Json(json!({
+        "prefixes": rows.iter().map(|row| entry_json(&state, row)).collect::<Vec<_>>(),
+        "pool": {
+            "size": pool.len(),
+            "disabled": disabled,
+            "file": state.prefix_pool_file.load().len(),
+        },
+    })))
+}
+
+/// Add a prefix to the pool, or set the class or status of one
+#[instrument(name = "handler", skip_all, fields(operation = "set_pool_prefix", prefix = %prefix))]
+pub async fn set_prefix(
+    State(state): State<AppState>,
+    Path(prefix): Path<String>,
+    Json(request): Json<SetPoolPrefixRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let prefix = parse_prefix(&prefix, state.prefix_pool_file.load().lengths())?;
+    if let Some(other) = state.prefix_pool.load().overlapping(&prefix.net()) {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!("Prefix {} overlaps {} of the pool", prefix, other),
+        ));
+    }
+    let note = request
+        .note
+        .as_deref()
+        .map(str::trim)
+        .filter(|n| !n.is_empty());
+    if note.is_some_and(|note| note.chars().count() > MAX_NOTE_LENGTH) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("The note is longer than {} characters", MAX_NOTE_LENGTH),
+        ));
+    }
+
+    let entry = state
+        .pool_store
+        .set_entry(
+            &prefix,
+            request.class.map(|class| class.name()),
+            request.status.name(),
+            note,
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to set prefix pool entry {}: {:#}", prefix, err);
+            api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to set prefix pool entry",
+            )
+        })?;
+    refresh_after_change(&state).await;
+
+    info!("Set pool prefix {} to {}", prefix, request.status.name());
+    state.audit.record(
+        "pool.prefix_set",
+        json!({
+            "prefix": entry.prefix,
+            "class": entry.class,
+            "status": entry.status,
+            "note": entry.note,
+        }),
+    );
+    Ok(Json(entry_json(&state, &entry)))
+}
+
+/// Remove the entry of a prefix: a prefix of the pool file goes back to how
+/// the file lists it, any other prefix leaves the pool. Active leases are kept.
+#[instrument(name = "handler", skip_all, fields(operation = "delete_pool_prefix", prefix = %prefix))]
+pub async fn delete_prefix(
+    State(state): State<AppState>,
+    Path(prefix): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let prefix = parse_prefix(&prefix, state.prefix_pool_file.load().lengths())?;
+    match state.pool_store.delete_entry(&prefix).await {
+        Ok(true) => {
+            refresh_after_change(&state).await;
+            info!("Removed pool prefix entry {}", prefix);
+            state.audit.record(
+                "pool.prefix_deleted",
+                json!({ "prefix": prefix.to_string() }),
+            );
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err(api_error(
+            StatusCode::NOT_FOUND,
+            "No pool entry for this prefix",
+        )),
+        Err(err) => {
+            error!("Failed to delete prefix pool entry {}: {:#}", prefix, err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to delete prefix pool entry",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn row(prefix: &str, class: Option<&str>, status: &str) -> PrefixPoolEntry {
+        PrefixPoolEntry {
+            prefix: prefix.to_string(),
+            class: class.map(str::to_string),
+            status: status.to_string(),
+            note: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_pool_entry_from_row() {
+        let entry = pool_entry(&row("2001:db8:1::/48", Some("global"), "disabled")).unwrap();
+        assert_eq!(entry.prefix, "2001:db8:1::/48".parse().unwrap());
+        assert_eq!(entry.class, Some(PrefixClass::Global));
+        assert_eq!(entry.status, PoolStatus::Disabled);
+        assert!(pool_entry(&row("2001:db8:1::/48", Some("bogon"), "active")).is_err());
+        assert!(pool_entry(&row("2001:db8:1::/48", None, "gone")).is_err());
+    }
+
+    #[test]
+    fn test_apply_entries_reports_changes() {
+        let file = PrefixPool::new(vec![
+            "2001:db8:1::/48".parse().unwrap(),
+            "2001:db8:2::/48".parse().unwrap(),
+        ]);
+        let entries: Vec<PoolEntry> = [
+            row("2001:db8:1::/48", None, "disabled"),
+            row("2001:db8:2::/48", None, "retired"),
+            row("2001:db8:3::/48", Some("global"), "active"),
+        ]
+        .iter()
+        .map(|row| pool_entry(row).unwrap())
+        .collect();
+
+        let (pool, changes) = apply_entries(&file, &file, &entries);
+        assert_eq!(
+            changes,
+            vec![
+                "added 2001:db8:3::/48 (global)",
+                "removed 2001:db8:2::/48 (active leases are kept)",
+                "2001:db8:1::/48 disabled",
+            ]
+        );
+        assert!(apply_entries(&pool, &file, &entries).1.is_empty());
+        assert_eq!(
+            apply_entries(&pool, &file, &[]).1,
+            vec![
+                "added 2001:db8:2::/48 (documentation)",
+                "removed 2001:db8:3::/48 (active leases are kept)",
+                "2001:db8:1::/48 enabled",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_pool_prefix() {
+        assert!(parse_prefix("2001:db8:1::/48", &[48]).is_ok());
+        assert!(parse_prefix("2001:db8:1::/56", &[48, 56]).is_ok());
+        assert_eq!(
+            parse_prefix("2001:db8:1::/56", &[48]).unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            parse_prefix("2001:db8:1::", &[48]).unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
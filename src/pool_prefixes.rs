@@ -1,21 +1,334 @@
 use anyhow::Result;
 use ipnet::Ipv6Net;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::net::Ipv6Addr;
 use std::path::Path;
 use std::str::FromStr;
-use tracing::{debug, info};
+use tracing::{Span, debug, info, instrument};
+
+/// Number of addresses in a /48, the unit of per-user space quotas
+pub const SLASH48_ADDRESSES: u128 = 1 << 80;
+
+/// Number of addresses in a prefix (saturating for ::/0)
+fn prefix_size(prefix: &Ipv6Net) -> u128 {
+    match prefix.prefix_len() {
+        0 => u128::MAX,
+        len => 1 << (128 - len),
+    }
+}
+
+/// Number of addresses covered by prefixes, counting overlapping space once
+pub fn address_space(prefixes: &[Ipv6Net]) -> u128 {
+    Ipv6Net::aggregate(&prefixes.to_vec())
+        .iter()
+        .map(prefix_size)
+        .fold(0, u128::saturating_add)
+}
+
+/// Length of the prefixes of the pool unless configured otherwise
+pub const POOL_PREFIX_LENGTH: u8 = 48;
+
+/// Lengths of the slices of pool prefixes that may be leased on their own
+pub const SLICE_LENGTHS: [u8; 2] = [56, 64];
+
+/// Shortest and longest lengths accepted for pool prefixes
+pub const POOL_LENGTH_RANGE: std::ops::RangeInclusive<u8> = 16..=64;
+
+/// Whether two prefixes share addresses (one contains the other)
+pub fn overlaps(a: &Ipv6Net, b: &Ipv6Net) -> bool {
+    a.contains(b) || b.contains(a)
+}
+
+/// First /`len` of `parent` overlapping none of `taken`
+fn first_free_slice(parent: &Ipv6Net, len: u8, taken: &[Ipv6Net]) -> Option<Ipv6Net> {
+    let taken: Vec<&Ipv6Net> = taken.iter().filter(|t| overlaps(parent, t)).collect();
+    let size = 1u128 << (128 - len);
+    let last = u128::from(parent.broadcast());
+    let mut start = u128::from(parent.network());
+    while start <= last {
+        let slice = Ipv6Net::new(Ipv6Addr::from(start), len).ok()?;
+        let end_of_taken = taken
+            .iter()
+            .filter(|t| overlaps(&slice, t))
+            .map(|t| u128::from(t.broadcast()))
+            .max();
+        match end_of_taken {
+            None => return Some(slice),
+            // Skip to the first slice past the taken prefix
+            Some(end) => start = (end / size + 1).checked_mul(size)?,
+        }
+    }
+    None
+}
+
+/// Address space expressed in /48 equivalents (e.g. 0.5 for a /49)
+pub fn slash48_equivalents(space: u128) -> f64 {
+    space as f64 / SLASH48_ADDRESSES as f64
+}
+
+/// Routability class of a prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrefixClass {
+    /// Globally-routable unicast space
+    Global,
+    /// Unique local addresses (fc00::/7, RFC 4193)
+    Ula,
+    /// Documentation space (2001:db8::/32 and 3fff::/20, RFC 3849 and RFC 9637)
+    Documentation,
+}
+
+impl PrefixClass {
+    pub const ALL: [PrefixClass; 3] = [Self::Global, Self::Ula, Self::Documentation];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Global => "global",
+            Self::Ula => "ula",
+            Self::Documentation => "documentation",
+        }
+    }
+
+    /// Whether prefixes of this class belong in filters exported to routers
+    pub fn is_routable(&self) -> bool {
+        *self == Self::Global
+    }
+
+    /// Class of a prefix derived from its address
+    pub fn of(prefix: &Ipv6Net) -> Self {
+        let ula: Ipv6Net = "fc00::/7".parse().unwrap();
+        let documentation: [Ipv6Net; 2] = [
+            "2001:db8::/32".parse().unwrap(),
+            "3fff::/20".parse().unwrap(),
+        ];
+
+        if ula.contains(prefix) {
+            Self::Ula
+        } else if documentation.iter().any(|d| d.contains(prefix)) {
+            Self::Documentation
+        } else {
+            Self::Global
+        }
+    }
+}
+
+impl FromStr for PrefixClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|class| class.name() == s.to_ascii_lowercase())
+            .ok_or_else(|| {
+                format!(
+                    "Unknown prefix class '{}' (expected global, ula or documentation)",
+                    s
+                )
+            })
+    }
+}
+
+/// Status of a prefix managed in the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PoolStatus {
+    /// Part of the pool and allocated
+    Active,
+    /// Part of the pool but not allocated to new leases
+    Disabled,
+    /// Removed from the pool, even when listed in the pool file
+    Retired,
+}
+
+impl PoolStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Active => "active",
+            Self::Disabled => "disabled",
+            Self::Retired => "retired",
+        }
+    }
+}
+
+impl FromStr for PoolStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::Active, Self::Disabled, Self::Retired]
+            .into_iter()
+            .find(|status| status.name() == s.to_ascii_lowercase())
+            .ok_or_else(|| {
+                format!(
+                    "Unknown prefix status '{}' (expected active, disabled or retired)",
+                    s
+                )
+            })
+    }
+}
+
+/// Prefix added to, or overriding one of, the pool file at runtime
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolEntry {
+    pub prefix: Ipv6Net,
+    pub class: Option<PrefixClass>,
+    pub status: PoolStatus,
+}
+
+/// Why the allocator passed over a pool prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Skip {
+    /// Disabled through the admin API
+    Disabled,
+    /// Of another class than the one requested (the class of the prefix)
+    Class(PrefixClass),
+    /// Longer than the prefix requested
+    TooSmall,
+    /// Overlapping this leased or already selected prefix
+    Taken(Ipv6Net),
+    /// Every slice of the requested length overlaps a lease
+    Full,
+}
+
+/// A pool prefix the allocator looked at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    /// The pool prefix
+    pub prefix: Ipv6Net,
+    /// The prefix picked from it, or why it was passed over
+    pub outcome: Result<Ipv6Net, Skip>,
+    /// Picked because it was the preferred prefix
+    pub preferred: bool,
+}
 
 /// Prefix pool manager that loads prefixes from a file
 #[derive(Debug, Clone)]
 pub struct PrefixPool {
     prefixes: Vec<Ipv6Net>,
+    /// Classes set explicitly in the pool file, overriding the derived class
+    classes: HashMap<Ipv6Net, PrefixClass>,
+    /// Prefixes kept in the pool but skipped by the allocator
+    disabled: HashSet<Ipv6Net>,
+    /// Lengths accepted for prefixes of the pool, sorted
+    lengths: Vec<u8>,
 }
 
 impl PrefixPool {
-    /// Load prefixes from a file (one prefix per line)
+    /// Create a pool from a list of prefixes
+    pub fn new(prefixes: Vec<Ipv6Net>) -> Self {
+        Self {
+            prefixes,
+            classes: HashMap::new(),
+            disabled: HashSet::new(),
+            lengths: vec![POOL_PREFIX_LENGTH],
+        }
+    }
+
+    /// Lengths accepted for prefixes of the pool, in the file or at runtime
+    pub fn lengths(&self) -> &[u8] {
+        &self.lengths
+    }
+
+    /// Lengths of the prefixes that may be leased: whole pool prefixes, and
+    /// slices longer than the shortest pool prefixes
+    pub fn lease_lengths(&self) -> Vec<u8> {
+        let shortest = self.lengths.first().copied().unwrap_or(POOL_PREFIX_LENGTH);
+        let mut lengths: Vec<u8> = self
+            .lengths
+            .iter()
+            .copied()
+            .chain(SLICE_LENGTHS.into_iter().filter(|len| *len > shortest))
+            .collect();
+        lengths.sort_unstable();
+        lengths.dedup();
+        lengths
+    }
+
+    /// Length leased when none is asked for: a /48, or the shortest longer
+    /// length when the pool can't lease /48s
+    pub fn default_lease_length(&self) -> u8 {
+        let lengths = self.lease_lengths();
+        lengths
+            .iter()
+            .copied()
+            .find(|len| *len >= POOL_PREFIX_LENGTH)
+            .or(lengths.last().copied())
+            .unwrap_or(POOL_PREFIX_LENGTH)
+    }
+
+    /// The pool prefix, other than `prefix` itself, that `prefix` overlaps
+    pub fn overlapping(&self, prefix: &Ipv6Net) -> Option<&Ipv6Net> {
+        self.prefixes
+            .iter()
+            .find(|p| *p != prefix && overlaps(p, prefix))
+    }
+
+    /// Pool with the entries managed in the database applied on top: active
+    /// and disabled entries are added (overriding the class when set) and
+    /// retired ones are removed
+    pub fn with_entries(&self, entries: &[PoolEntry]) -> Self {
+        let mut pool = self.clone();
+        for entry in entries {
+            if entry.status == PoolStatus::Retired {
+                pool.prefixes.retain(|prefix| *prefix != entry.prefix);
+                pool.classes.remove(&entry.prefix);
+                pool.disabled.remove(&entry.prefix);
+                continue;
+            }
+            if !pool.prefixes.contains(&entry.prefix) {
+                pool.prefixes.push(entry.prefix);
+            }
+            if let Some(class) = entry.class {
+                pool.classes.insert(entry.prefix, class);
+            }
+            if entry.status == PoolStatus::Disabled {
+                pool.disabled.insert(entry.prefix);
+            } else {
+                pool.disabled.remove(&entry.prefix);
+            }
+        }
+        pool
+    }
+
+    /// Whether a prefix of the pool is excluded from new allocations
+    pub fn is_disabled(&self, prefix: &Ipv6Net) -> bool {
+        self.disabled.contains(prefix)
+    }
+
+    /// Class of a prefix, or of the pool prefix it is a slice of: its tag in
+    /// the pool file, or the class of its address
+    pub fn class_of(&self, prefix: &Ipv6Net) -> PrefixClass {
+        let prefix = self.covering(prefix).unwrap_or(prefix);
+        self.classes
+            .get(prefix)
+            .copied()
+            .unwrap_or_else(|| PrefixClass::of(prefix))
+    }
+
+    /// Load prefixes from a file (one prefix per line, optionally followed by its class)
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_file_with_lengths(path, &[POOL_PREFIX_LENGTH])
+    }
+
+    /// Load prefixes of any of `lengths` from a file. Other prefixes, and
+    /// those overlapping a prefix listed earlier, are skipped.
+    pub fn from_file_with_lengths<P: AsRef<Path>>(path: P, lengths: &[u8]) -> Result<Self> {
+        let mut lengths = lengths.to_vec();
+        lengths.sort_unstable();
+        lengths.dedup();
+        if let Some(len) = lengths.iter().find(|len| !POOL_LENGTH_RANGE.contains(len)) {
+            anyhow::bail!(
+                "Pool prefix length /{} is outside /{}-/{}",
+                len,
+                POOL_LENGTH_RANGE.start(),
+                POOL_LENGTH_RANGE.end()
+            );
+        }
+
         let content = fs::read_to_string(path.as_ref())?;
-        let mut prefixes = Vec::new();
+        let mut prefixes: Vec<Ipv6Net> = Vec::new();
+        let mut classes = HashMap::new();
 
         for (line_num, line) in content.lines().enumerate() {
             let line = line.trim();
@@ -25,17 +338,38 @@ impl PrefixPool {
                 continue;
             }
 
+            // An optional second column overrides the class of the prefix
+            let mut fields = line.split_whitespace();
+            let (line, class) = (fields.next().unwrap_or_default(), fields.next());
+            let class = match class.map(PrefixClass::from_str).transpose() {
+                Ok(class) => class,
+                Err(e) => {
+                    tracing::warn!("Line {}: {}, skipping", line_num + 1, e);
+                    continue;
+                }
+            };
+
             match Ipv6Net::from_str(line) {
                 Ok(prefix) => {
-                    // Validate that it's a /48 prefix
-                    if prefix.prefix_len() == 48 {
-                        prefixes.push(prefix);
-                    } else {
+                    if !lengths.contains(&prefix.prefix_len()) {
                         tracing::warn!(
-                            "Line {}: Prefix {} is not a /48, skipping",
+                            "Line {}: Prefix {} is not of an allowed length ({}), skipping",
                             line_num + 1,
-                            line
+                            line,
+                            format_lengths(&lengths)
                         );
+                    } else if let Some(other) = prefixes.iter().find(|p| overlaps(p, &prefix)) {
+                        tracing::warn!(
+                            "Line {}: Prefix {} overlaps {}, skipping",
+                            line_num + 1,
+                            line,
+                            other
+                        );
+                    } else {
+                        prefixes.push(prefix);
+                        if let Some(class) = class {
+                            classes.insert(prefix, class);
+                        }
                     }
                 }
                 Err(e) => {
@@ -50,7 +384,12 @@ impl PrefixPool {
         }
 
         info!("Loaded {} prefixes from file", prefixes.len());
-        Ok(Self { prefixes })
+        Ok(Self {
+            prefixes,
+            classes,
+            disabled: HashSet::new(),
+            lengths,
+        })
     }
 
     /// Get all available prefixes
@@ -68,16 +407,220 @@ impl PrefixPool {
         self.prefixes.is_empty()
     }
 
-    /// Find an available prefix that is not currently leased
-    pub fn find_available_prefix(&self, leased_prefixes: &[Ipv6Net]) -> Option<Ipv6Net> {
+    /// Find an available prefix, of a class if given. `leased_prefixes` are
+    /// those leased at some point of the window the prefix is wanted for.
+    /// The `preferred` prefix (e.g. the one a returning user last held) is
+    /// picked when it is available.
+    #[instrument(name = "pool", skip_all, fields(operation = "find_available_prefix", class = ?class, prefix = tracing::field::Empty))]
+    pub fn find_available_prefix(
+        &self,
+        leased_prefixes: &[Ipv6Net],
+        class: Option<PrefixClass>,
+        preferred: Option<&Ipv6Net>,
+    ) -> Option<Ipv6Net> {
+        let prefix = self.select(leased_prefixes, class, preferred, None, None)?;
+        Span::current().record("prefix", prefix.to_string());
+        debug!("Found available prefix: {}", prefix);
+        Some(prefix)
+    }
+
+    /// Find an available prefix of `len`, of a class if given: a whole pool
+    /// prefix of that length or a slice of a shorter one. Without a length,
+    /// any whole pool prefix when they all have the
+    /// [default length](Self::default_lease_length), else a prefix of that
+    /// length, so mixed pools don't hand out /44s or /40s whole. Pool prefixes already partially leased
+    /// are sliced before untouched ones, then the smallest that fit, keeping
+    /// large prefixes whole for longer. The `preferred` prefix is picked when
+    /// it is available.
+    #[instrument(name = "pool", skip_all, fields(operation = "find_available_slice", class = ?class, len = len, prefix = tracing::field::Empty))]
+    pub fn find_available_slice(
+        &self,
+        leased_prefixes: &[Ipv6Net],
+        class: Option<PrefixClass>,
+        preferred: Option<&Ipv6Net>,
+        len: Option<u8>,
+    ) -> Option<Ipv6Net> {
+        let slice = self.select(leased_prefixes, class, preferred, len, None)?;
+        Span::current().record("prefix", slice.to_string());
+        debug!("Found available slice: {}", slice);
+        Some(slice)
+    }
+
+    /// [`find_available_slice`](Self::find_available_slice), also returning
+    /// the pool prefixes looked at, in order, and why those not picked were
+    /// passed over
+    pub fn explain_available_slice(
+        &self,
+        leased_prefixes: &[Ipv6Net],
+        class: Option<PrefixClass>,
+        preferred: Option<&Ipv6Net>,
+        len: Option<u8>,
+    ) -> (Option<Ipv6Net>, Vec<Candidate>) {
+        let mut candidates = Vec::new();
+        let picked = self.select(
+            leased_prefixes,
+            class,
+            preferred,
+            len,
+            Some(&mut candidates),
+        );
+        (picked, candidates)
+    }
+
+    /// Why a pool prefix can't be allocated at all, `None` when it can
+    fn unallocatable(&self, prefix: &Ipv6Net, class: Option<PrefixClass>) -> Option<Skip> {
+        if self.is_disabled(prefix) {
+            return Some(Skip::Disabled);
+        }
+        let actual = self.class_of(prefix);
+        class
+            .is_some_and(|class| class != actual)
+            .then_some(Skip::Class(actual))
+    }
+
+    /// The allocator behind [`find_available_slice`](Self::find_available_slice),
+    /// noting the pool prefixes it looks at in `trace`
+    fn select(
+        &self,
+        leased_prefixes: &[Ipv6Net],
+        class: Option<PrefixClass>,
+        preferred: Option<&Ipv6Net>,
+        len: Option<u8>,
+        mut trace: Option<&mut Vec<Candidate>>,
+    ) -> Option<Ipv6Net> {
+        let default_len = self.default_lease_length();
+        let len = len.or_else(|| {
+            self.prefixes
+                .iter()
+                .any(|prefix| prefix.prefix_len() != default_len)
+                .then_some(default_len)
+        });
+        let mut note = |prefix: &Ipv6Net, outcome: Result<Ipv6Net, Skip>, preferred: bool| {
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.push(Candidate {
+                    prefix: *prefix,
+                    outcome,
+                    preferred,
+                });
+            }
+        };
+        let taken_by = |prefix: &Ipv6Net| {
+            leased_prefixes
+                .iter()
+                .find(|leased| overlaps(leased, prefix))
+                .copied()
+        };
+
+        if let Some(preferred) = preferred.filter(|p| len.is_none_or(|len| p.prefix_len() == len))
+            && let Some(parent) = self.covering(preferred)
+            && (len.is_some() || parent == preferred)
+            && self.unallocatable(parent, class).is_none()
+            && taken_by(preferred).is_none()
+        {
+            note(parent, Ok(*preferred), true);
+            return Some(*preferred);
+        }
+
+        let Some(len) = len else {
+            for prefix in &self.prefixes {
+                let skip = self
+                    .unallocatable(prefix, class)
+                    .or_else(|| taken_by(prefix).map(Skip::Taken));
+                note(prefix, skip.map_or(Ok(*prefix), Err), false);
+                if skip.is_none() {
+                    return Some(*prefix);
+                }
+            }
+            return None;
+        };
+
+        let mut parents: Vec<&Ipv6Net> = Vec::with_capacity(self.prefixes.len());
         for prefix in &self.prefixes {
-            if !leased_prefixes.contains(prefix) {
-                debug!("Found available prefix: {}", prefix);
-                return Some(*prefix);
+            let skip = if prefix.prefix_len() > len {
+                Some(Skip::TooSmall)
+            } else {
+                self.unallocatable(prefix, class)
+            };
+            match skip {
+                Some(skip) => note(prefix, Err(skip), false),
+                None => parents.push(prefix),
+            }
+        }
+        parents.sort_by_key(|parent| {
+            (
+                self.used_space(parent, leased_prefixes) == 0,
+                std::cmp::Reverse(parent.prefix_len()),
+            )
+        });
+        for parent in parents {
+            match first_free_slice(parent, len, leased_prefixes) {
+                Some(slice) => {
+                    note(parent, Ok(slice), false);
+                    return Some(slice);
+                }
+                None if parent.prefix_len() == len => {
+                    let skip = taken_by(parent).map_or(Skip::Full, Skip::Taken);
+                    note(parent, Err(skip), false);
+                }
+                None => note(parent, Err(Skip::Full), false),
             }
         }
         None
     }
+
+    /// Addresses of a pool prefix taken by leases, of the prefix or of slices of it
+    pub fn used_space(&self, prefix: &Ipv6Net, leased_prefixes: &[Ipv6Net]) -> u128 {
+        let inside: Vec<Ipv6Net> = leased_prefixes
+            .iter()
+            .filter(|leased| overlaps(leased, prefix))
+            .map(|leased| {
+                if prefix.contains(leased) {
+                    *leased
+                } else {
+                    *prefix
+                }
+            })
+            .collect();
+        address_space(&inside)
+    }
+
+    /// Whether a prefix is one of the pool
+    pub fn contains(&self, prefix: &Ipv6Net) -> bool {
+        self.prefixes.contains(prefix)
+    }
+
+    /// The pool prefix a prefix is, or is a slice of
+    pub fn covering(&self, prefix: &Ipv6Net) -> Option<&Ipv6Net> {
+        self.prefixes.iter().find(|p| p.contains(prefix))
+    }
+
+    /// Count the prefixes not currently leased, of a class if given
+    pub fn count_available(
+        &self,
+        leased_prefixes: &[Ipv6Net],
+        class: Option<PrefixClass>,
+    ) -> usize {
+        self.prefixes
+            .iter()
+            .filter(|prefix| class.is_none_or(|class| self.class_of(prefix) == class))
+            .filter(|prefix| {
+                !self.is_disabled(prefix)
+                    && !leased_prefixes
+                        .iter()
+                        .any(|leased| overlaps(leased, prefix))
+            })
+            .count()
+    }
+}
+
+/// Prefix lengths for messages (e.g. "48, 56 or 64")
+pub fn format_lengths(lengths: &[u8]) -> String {
+    let lengths: Vec<String> = lengths.iter().map(|len| len.to_string()).collect();
+    match lengths.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => String::new(),
+    }
 }
 
 #[cfg(test)]
@@ -92,13 +635,28 @@ mod tests {
         writeln!(file, "2001:db8:1::/48").unwrap();
         writeln!(file, "2001:db8:2::/48").unwrap();
         writeln!(file, "# This is a comment").unwrap();
-        writeln!(file, "").unwrap();
+        writeln!(file).unwrap();
         writeln!(file, "2001:db8:3::/48").unwrap();
 
         let pool = PrefixPool::from_file(file.path()).unwrap();
         assert_eq!(pool.len(), 3);
     }
 
+    #[test]
+    fn test_address_space() {
+        let prefixes: Vec<Ipv6Net> = ["2001:db8:1::/48", "2001:db8:2::/49", "2001:db8:1:1::/64"]
+            .iter()
+            .map(|p| p.parse().unwrap())
+            .collect();
+
+        // The /64 lies inside the first /48 and isn't counted twice
+        let space = address_space(&prefixes);
+        assert_eq!(space, SLASH48_ADDRESSES + SLASH48_ADDRESSES / 2);
+        assert_eq!(slash48_equivalents(space), 1.5);
+        assert_eq!(address_space(&[]), 0);
+        assert_eq!(address_space(&["::/0".parse().unwrap()]), u128::MAX);
+    }
+
     #[test]
     fn test_find_available_prefix() {
         let mut file = NamedTempFile::new().unwrap();
@@ -109,12 +667,237 @@ mod tests {
         let pool = PrefixPool::from_file(file.path()).unwrap();
 
         let leased = vec![Ipv6Net::from_str("2001:db8:1::/48").unwrap()];
-        let available = pool.find_available_prefix(&leased);
+        let available = pool.find_available_prefix(&leased, None, None);
 
         assert!(available.is_some());
         assert_ne!(
             available.unwrap(),
             Ipv6Net::from_str("2001:db8:1::/48").unwrap()
         );
+        assert!(pool.contains(&leased[0]));
+        assert!(!pool.contains(&Ipv6Net::from_str("2001:db8:1::/64").unwrap()));
+    }
+
+    #[test]
+    fn test_find_preferred_prefix() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "2001:db8:1::/48").unwrap();
+        writeln!(file, "2001:db8:2::/48").unwrap();
+        writeln!(file, "fd00:1::/48").unwrap();
+
+        let pool = PrefixPool::from_file(file.path()).unwrap();
+        let first: Ipv6Net = "2001:db8:1::/48".parse().unwrap();
+        let last: Ipv6Net = "2001:db8:2::/48".parse().unwrap();
+        let ula: Ipv6Net = "fd00:1::/48".parse().unwrap();
+
+        // The preferred prefix is picked when free, of the class and in the pool
+        assert_eq!(
+            pool.find_available_prefix(&[], None, Some(&last)),
+            Some(last)
+        );
+        assert_eq!(
+            pool.find_available_prefix(&[last], None, Some(&last)),
+            Some(first)
+        );
+        assert_eq!(
+            pool.find_available_prefix(&[], Some(PrefixClass::Ula), Some(&last)),
+            Some(ula)
+        );
+        let removed: Ipv6Net = "2001:db8:9::/48".parse().unwrap();
+        assert_eq!(
+            pool.find_available_prefix(&[], None, Some(&removed)),
+            Some(first)
+        );
+    }
+
+    #[test]
+    fn test_prefix_class() {
+        let class = |p: &str| PrefixClass::of(&p.parse().unwrap());
+        assert_eq!(class("2a0e:97c0:8a0::/48"), PrefixClass::Global);
+        assert_eq!(class("fd00:1234:5678::/48"), PrefixClass::Ula);
+        assert_eq!(class("2001:db8:1::/48"), PrefixClass::Documentation);
+        assert_eq!(class("3fff:123::/48"), PrefixClass::Documentation);
+        assert!(PrefixClass::Global.is_routable());
+        assert!(!PrefixClass::Ula.is_routable());
+        assert_eq!("ULA".parse::<PrefixClass>(), Ok(PrefixClass::Ula));
+        assert!("bogon".parse::<PrefixClass>().is_err());
+    }
+
+    #[test]
+    fn test_find_available_prefix_of_class() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "fd00:1::/48").unwrap();
+        writeln!(file, "2001:db8:1::/48 global").unwrap();
+        writeln!(file, "2a0e:97c0:8a0::/48").unwrap();
+        writeln!(file, "2a0e:97c0:8a1::/48 bogon").unwrap();
+
+        let pool = PrefixPool::from_file(file.path()).unwrap();
+        assert_eq!(pool.len(), 3);
+
+        let tagged: Ipv6Net = "2001:db8:1::/48".parse().unwrap();
+        assert_eq!(pool.class_of(&tagged), PrefixClass::Global);
+        assert_eq!(
+            pool.find_available_prefix(&[], Some(PrefixClass::Global), None),
+            Some(tagged)
+        );
+        assert_eq!(
+            pool.find_available_prefix(&[tagged], Some(PrefixClass::Global), None),
+            Some("2a0e:97c0:8a0::/48".parse().unwrap())
+        );
+        assert_eq!(
+            pool.find_available_prefix(&[], Some(PrefixClass::Documentation), None),
+            None
+        );
+        assert_eq!(pool.count_available(&[tagged], None), 2);
+        assert_eq!(
+            pool.count_available(&[tagged], Some(PrefixClass::Global)),
+            1
+        );
+    }
+
+    #[test]
+    fn test_pool_with_entries() {
+        let file: Ipv6Net = "2001:db8:1::/48".parse().unwrap();
+        let retired: Ipv6Net = "2001:db8:2::/48".parse().unwrap();
+        let added: Ipv6Net = "2a0e:97c0:8a0::/48".parse().unwrap();
+        let base = PrefixPool::new(vec![file, retired]);
+        let entry = |prefix, class, status| PoolEntry {
+            prefix,
+            class,
+            status,
+        };
+
+        let pool = base.with_entries(&[
+            entry(file, Some(PrefixClass::Global), PoolStatus::Disabled),
+            entry(retired, None, PoolStatus::Retired),
+            entry(added, None, PoolStatus::Active),
+        ]);
+        assert_eq!(pool.get_all_prefixes(), &[file, added]);
+        assert_eq!(pool.class_of(&file), PrefixClass::Global);
+        assert!(pool.contains(&file) && pool.is_disabled(&file));
+        assert_eq!(
+            pool.find_available_prefix(&[], None, Some(&file)),
+            Some(added)
+        );
+        assert_eq!(pool.count_available(&[], None), 1);
+
+        // Entries apply to the base pool, which is left untouched
+        assert_eq!(base.len(), 2);
+        assert!(!base.is_disabled(&file));
+        assert_eq!("Retired".parse::<PoolStatus>(), Ok(PoolStatus::Retired));
+        assert!("gone".parse::<PoolStatus>().is_err());
+    }
+
+    #[test]
+    fn test_find_available_slice() {
+        let first: Ipv6Net = "2001:db8:1::/48".parse().unwrap();
+        let second: Ipv6Net = "2001:db8:2::/48".parse().unwrap();
+        let pool = PrefixPool::new(vec![first, second]);
+        let net = |p: &str| p.parse::<Ipv6Net>().unwrap();
+
+        // Slices come from the partially leased prefix, skipping taken space
+        let leased = vec![net("2001:db8:2::/56"), net("2001:db8:2:100::/64")];
+        assert_eq!(
+            pool.find_available_slice(&leased, None, None, Some(64)),
+            Some(net("2001:db8:2:101::/64"))
+        );
+        assert_eq!(
+            pool.find_available_slice(&leased, None, None, Some(56)),
+            Some(net("2001:db8:2:200::/56"))
+        );
+        assert_eq!(pool.used_space(&second, &leased), 257 << 64);
+        assert_eq!(pool.used_space(&first, &[first]), SLASH48_ADDRESSES);
+
+        // A /48 with a slice leased is no longer available whole
+        assert_eq!(pool.find_available_prefix(&leased, None, None), Some(first));
+        assert_eq!(pool.count_available(&leased, None), 1);
+        assert_eq!(
+            pool.find_available_slice(&leased, None, None, Some(48)),
+            Some(first)
+        );
+
+        // The preferred slice is picked when free and in the pool
+        let preferred = net("2001:db8:1:ff::/64");
+        assert_eq!(
+            pool.find_available_slice(&leased, None, Some(&preferred), Some(64)),
+            Some(preferred)
+        );
+        assert_eq!(
+            pool.find_available_slice(&[first], None, Some(&preferred), Some(64)),
+            Some(net("2001:db8:2::/64"))
+        );
+
+        // Slices inherit the class of their pool prefix
+        assert_eq!(pool.covering(&preferred), Some(&first));
+        assert_eq!(pool.class_of(&preferred), PrefixClass::Documentation);
+        assert_eq!(
+            pool.find_available_slice(&[first, second], None, None, Some(64)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_mixed_prefix_lengths() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "2001:db8:10::/44").unwrap();
+        writeln!(file, "2001:db8:1::/48").unwrap();
+        writeln!(file, "2001:db8:2::/56").unwrap();
+        writeln!(file, "2001:db8:11::/48").unwrap();
+        writeln!(file, "2001:db8:3::/64").unwrap();
+        let net = |p: &str| p.parse::<Ipv6Net>().unwrap();
+
+        // Only /48s by default; otherwise prefixes overlapping an earlier one are skipped
+        assert_eq!(PrefixPool::from_file(file.path()).unwrap().len(), 2);
+        let pool = PrefixPool::from_file_with_lengths(file.path(), &[56, 44, 48]).unwrap();
+        assert_eq!(
+            pool.get_all_prefixes(),
+            &[
+                net("2001:db8:10::/44"),
+                net("2001:db8:1::/48"),
+                net("2001:db8:2::/56")
+            ]
+        );
+        assert_eq!(pool.lengths(), &[44, 48, 56]);
+        assert_eq!(pool.lease_lengths(), vec![44, 48, 56, 64]);
+        assert_eq!(PrefixPool::new(vec![]).lease_lengths(), vec![48, 56, 64]);
+        assert!(PrefixPool::from_file_with_lengths(file.path(), &[8]).is_err());
+
+        // Without a length a /48 is leased, whole or sliced from a /44
+        assert_eq!(pool.default_lease_length(), 48);
+        assert_eq!(
+            pool.find_available_slice(&[], None, None, None),
+            Some(net("2001:db8:1::/48"))
+        );
+        assert_eq!(
+            pool.find_available_slice(&[net("2001:db8:1::/48")], None, None, None),
+            Some(net("2001:db8:10::/48"))
+        );
+        assert_eq!(
+            pool.find_available_prefix(&[net("2001:db8:1::/48")], None, None),
+            Some(net("2001:db8:10::/48"))
+        );
+        let mut slash56 = NamedTempFile::new().unwrap();
+        writeln!(slash56, "2001:db8:2::/56").unwrap();
+        let slash56 = PrefixPool::from_file_with_lengths(slash56.path(), &[56]).unwrap();
+        assert_eq!(slash56.default_lease_length(), 56);
+        // The smallest prefix that fits is picked, then sliced shorter ones
+        assert_eq!(
+            pool.find_available_slice(&[], None, None, Some(56)),
+            Some(net("2001:db8:2::/56"))
+        );
+        assert_eq!(
+            pool.find_available_slice(&[net("2001:db8:2::/56")], None, None, Some(56)),
+            Some(net("2001:db8:1::/56"))
+        );
+        assert_eq!(
+            pool.find_available_slice(&[net("2001:db8:1::/48")], None, None, Some(48)),
+            Some(net("2001:db8:10::/48"))
+        );
+        assert_eq!(
+            pool.overlapping(&net("2001:db8:1f::/48")),
+            Some(&net("2001:db8:10::/44"))
+        );
+        assert_eq!(pool.overlapping(&net("2001:db8:1::/48")), None);
+        assert_eq!(format_lengths(&[44, 48, 56]), "44, 48 or 56");
     }
 }
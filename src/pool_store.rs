@@ -0,0 +1,469 @@
+//! Storage of the prefix pool entries.
+//!
+//! The entries managed through `/admin/pools/prefixes` live in the
+//! `prefix_pool` table by default. Teams already keeping their address plan
+//! in etcd or Consul can keep the entries there instead (`kv` feature), so
+//! the gateway reads and writes the same keys as their other tooling rather
+//! than owning a second copy: entries written to the store directly are
+//! picked up at the next refresh. Leases stay in the database, whose
+//! exclusion constraint keeps two users from leasing overlapping prefixes.
+
+use futures_util::future::BoxFuture;
+
+use crate::database::{Database, PrefixPoolEntry};
+use crate::types::Prefix;
+
+/// Where the prefix pool entries are kept
+pub trait PoolStore: Send + Sync {
+    /// Name of the backend, for logs
+    fn backend(&self) -> &'static str;
+
+    /// Every entry, ordered by prefix
+    fn entries(&self) -> BoxFuture<'_, anyhow::Result<Vec<PrefixPoolEntry>>>;
+
+    /// Add a prefix to the pool or update its class, status and note
+    fn set_entry<'a>(
+        &'a self,
+        prefix: &'a Prefix,
+        class: Option<&'a str>,
+        status: &'a str,
+        note: Option<&'a str>,
+    ) -> BoxFuture<'a, anyhow::Result<PrefixPoolEntry>>;
+
+    /// Remove the entry of a prefix, returning whether it existed
+    fn delete_entry<'a>(&'a self, prefix: &'a Prefix) -> BoxFuture<'a, anyhow::Result<bool>>;
+}
+
+impl PoolStore for Database {
+    fn backend(&self) -> &'static str {
+        "postgres"
+    }
+
+    fn entries(&self) -> BoxFuture<'_, anyhow::Result<Vec<PrefixPoolEntry>>> {
+        Box::pin(async move { Ok(self.get_prefix_pool_entries().await?) })
+    }
+
+    fn set_entry<'a>(
+        &'a self,
+        prefix: &'a Prefix,
+        class: Option<&'a str>,
+        status: &'a str,
+        note: Option<&'a str>,
+    ) -> BoxFuture<'a, anyhow::Result<PrefixPoolEntry>> {
+        Box::pin(async move {
+            Ok(self
+                .set_prefix_pool_entry(prefix, class, status, note)
+                .await?)
+        })
+    }
+
+    fn delete_entry<'a>(&'a self, prefix: &'a Prefix) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move { Ok(self.delete_prefix_pool_entry(prefix).await?) })
+    }
+}
+
+#[cfg(feature = "kv")]
+pub use kv::{KvBackend, KvStore};
+
+#[cfg(feature = "kv")]
+mod kv {
+    use anyhow::{Context, bail};
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use chrono::{DateTime, Utc};
+    use futures_util::future::BoxFuture;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{Value, json};
+    use std::str::FromStr;
+    use std::time::Duration;
+    use tracing::warn;
+
+    use super::PoolStore;
+    use crate::database::PrefixPoolEntry;
+    use crate::types::Prefix;
+
+    /// Timeout of a request to the store
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Key-value store holding the entries
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum KvBackend {
+        /// etcd v3, through its JSON gateway
+        Etcd,
+        /// Consul KV
+        Consul,
+    }
+
+    /// Value stored under the key of a prefix. Only `status` is needed when
+    /// writing an entry by hand; the gateway fills in the timestamps.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct KvEntry {
+        #[serde(default)]
+        class: Option<String>,
+        #[serde(default = "default_status")]
+        status: String,
+        #[serde(default)]
+        note: Option<String>,
+        #[serde(default)]
+        created_at: Option<DateTime<Utc>>,
+        #[serde(default)]
+        updated_at: Option<DateTime<Utc>>,
+    }
+
+    fn default_status() -> String {
+        "active".to_string()
+    }
+
+    /// Entry stored under `key`, whose last part is the prefix
+    fn parse_entry(key_prefix: &str, key: &str, value: &[u8]) -> anyhow::Result<PrefixPoolEntry> {
+        let prefix = Prefix::from_str(key.strip_prefix(key_prefix).unwrap_or(key))
+            .map_err(anyhow::Error::msg)?;
+        let entry: KvEntry = serde_json::from_slice(value).context("invalid entry")?;
+        Ok(PrefixPoolEntry {
+            prefix: prefix.to_string(),
+            class: entry.class,
+            status: entry.status,
+            note: entry.note,
+            created_at: entry.created_at.unwrap_or_default(),
+            updated_at: entry.updated_at.unwrap_or_default(),
+        })
+    }
+
+    /// First key after every key starting with `prefix`, ending an etcd range
+    fn range_end(prefix: &str) -> Vec<u8> {
+        let mut end = prefix.as_bytes().to_vec();
+        while let Some(last) = end.pop() {
+            if last < u8::MAX {
+                end.push(last + 1);
+                return end;
+            }
+        }
+        // Every key: the prefix was empty or all 0xff
+        vec![0]
+    }
+
+    /// Entries kept in etcd or Consul, one key per prefix
+    pub struct KvStore {
+        backend: KvBackend,
+        client: reqwest::Client,
+        endpoint: reqwest::Url,
+        token: Option<String>,
+        /// Prepended to the prefix to form its key (e.g. `peerlab/pool/`)
+        key_prefix: String,
+    }
+
+    impl std::fmt::Debug for KvStore {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("KvStore")
+                .field("backend", &self.backend)
+                .field("endpoint", &self.endpoint.as_str())
+                .field("key_prefix", &self.key_prefix)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl KvStore {
+        pub fn new(
+            backend: KvBackend,
+            endpoint: &str,
+            key_prefix: String,
+            token: Option<String>,
+        ) -> Result<Self, String> {
+            let endpoint = reqwest::Url::parse(endpoint)
+                .map_err(|e| format!("Invalid pool store URL: {}", e))?;
+            if endpoint.host_str().is_none() {
+                return Err("Invalid pool store URL: no host".to_string());
+            }
+            let client = reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .map_err(|e| format!("Failed to build the pool store client: {}", e))?;
+            Ok(Self {
+                backend,
+                client,
+                endpoint,
+                token,
+                key_prefix,
+            })
+        }
+
+        fn key(&self, prefix: &Prefix) -> String {
+            format!("{}{}", self.key_prefix, prefix)
+        }
+
+        fn url(&self, path: &str) -> anyhow::Result<reqwest::Url> {
+            Ok(self.endpoint.join(path)?)
+        }
+
+        fn request(&self, method: reqwest::Method, url: reqwest::Url) -> reqwest::RequestBuilder {
+            let request = self.client.request(method, url);
+            match (&self.token, self.backend) {
+                (Some(token), KvBackend::Etcd) => request.header("Authorization", token),
+                (Some(token), KvBackend::Consul) => request.header("X-Consul-Token", token),
+                (None, _) => request,
+            }
+        }
+
+        /// Call an etcd v3 JSON gateway method
+        async fn etcd(&self, method: &str, body: Value) -> anyhow::Result<Value> {
+            let response = self
+                .request(
+                    reqwest::Method::POST,
+                    self.url(&format!("v3/kv/{}", method))?,
+                )
+                .json(&body)
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(response.json().await?)
+        }
+
+        /// Consul KV URL of a key, `?recurse` listing the keys under it
+        fn consul_url(&self, key: &str, recurse: bool) -> anyhow::Result<reqwest::Url> {
+            let mut url = self.url(&format!("v1/kv/{}", key))?;
+            if recurse {
+                url.set_query(Some("recurse=true"));
+            }
+            Ok(url)
+        }
+
+        /// Stored `(key, value)` pairs under `key`, or of `key` alone
+        async fn get(&self, key: &str, recurse: bool) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+            match self.backend {
+                KvBackend::Etcd => {
+                    let mut range = json!({ "key": STANDARD.encode(key) });
+                    if recurse {
+                        range["range_end"] = json!(STANDARD.encode(range_end(key)));
+                    }
+                    let response = self.etcd("range", range).await?;
+                    let kvs = response["kvs"].as_array().into_iter().flatten();
+                    decode_pairs(
+                        kvs.map(|kv| (kv["key"].as_str(), kv["value"].as_str())),
+                        true,
+                    )
+                }
+                KvBackend::Consul => {
+                    let response = self
+                        .request(reqwest::Method::GET, self.consul_url(key, recurse)?)
+                        .send()
+                        .await?;
+                    if response.status() == reqwest::StatusCode::NOT_FOUND {
+                        return Ok(Vec::new());
+                    }
+                    let response: Value = response.error_for_status()?.json().await?;
+                    let kvs = response.as_array().into_iter().flatten();
+                    decode_pairs(
+                        kvs.map(|kv| (kv["Key"].as_str(), kv["Value"].as_str())),
+                        false,
+                    )
+                }
+            }
+        }
+
+        async fn put(&self, key: &str, value: Vec<u8>) -> anyhow::Result<()> {
+            match self.backend {
+                KvBackend::Etcd => {
+                    self.etcd(
+                        "put",
+                        json!({ "key": STANDARD.encode(key), "value": STANDARD.encode(value) }),
+                    )
+                    .await?;
+                }
+                KvBackend::Consul => {
+                    let response = self
+                        .request(reqwest::Method::PUT, self.consul_url(key, false)?)
+                        .body(value)
+                        .send()
+                        .await?
+                        .error_for_status()?;
+                    if response.text().await?.trim() != "true" {
+                        bail!("Consul refused to write {}", key);
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        async fn delete(&self, key: &str) -> anyhow::Result<bool> {
+            match self.backend {
+                KvBackend::Etcd => {
+                    let response = self
+                        .etcd("deleterange", json!({ "key": STANDARD.encode(key) }))
+                        .await?;
+                    // int64 fields are strings in the JSON gateway, left out when zero
+                    Ok(response["deleted"].as_str().is_some_and(|n| n != "0"))
+                }
+                KvBackend::Consul => {
+                    if self.get(key, false).await?.is_empty() {
+                        return Ok(false);
+                    }
+                    self.request(reqwest::Method::DELETE, self.consul_url(key, false)?)
+                        .send()
+                        .await?
+                        .error_for_status()?;
+                    Ok(true)
+                }
+            }
+        }
+    }
+
+    /// Decode key-value pairs, keys also base64-encoded with etcd
+    fn decode_pairs<'a>(
+        pairs: impl Iterator<Item = (Option<&'a str>, Option<&'a str>)>,
+        encoded_keys: bool,
+    ) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        pairs
+            .map(|(key, value)| {
+                let key = key.context("key missing from the store response")?;
+                let key = if encoded_keys {
+                    String::from_utf8(STANDARD.decode(key)?)?
+                } else {
+                    key.to_string()
+                };
+                // Consul folders have no value
+                let value = value.map(|v| STANDARD.decode(v)).transpose()?;
+                Ok((key, value.unwrap_or_default()))
+            })
+            .collect()
+    }
+
+    impl PoolStore for KvStore {
+        fn backend(&self) -> &'static str {
+            match self.backend {
+                KvBackend::Etcd => "etcd",
+                KvBackend::Consul => "consul",
+            }
+        }
+
+        fn entries(&self) -> BoxFuture<'_, anyhow::Result<Vec<PrefixPoolEntry>>> {
+            Box::pin(async move {
+                let mut entries: Vec<PrefixPoolEntry> = self
+                    .get(&self.key_prefix, true)
+                    .await?
+                    .into_iter()
+                    .filter(|(_, value)| !value.is_empty())
+                    .filter_map(
+                        |(key, value)| match parse_entry(&self.key_prefix, &key, &value) {
+                            Ok(entry) => Some(entry),
+                            Err(err) => {
+                                warn!("Ignoring pool store key {}: {:#}", key, err);
+                                None
+                            }
+                        },
+                    )
+                    .collect();
+                entries.sort_by_key(|entry| Prefix::from_str(&entry.prefix).map(|p| p.net()).ok());
+                Ok(entries)
+            })
+        }
+
+        fn set_entry<'a>(
+            &'a self,
+            prefix: &'a Prefix,
+            class: Option<&'a str>,
+            status: &'a str,
+            note: Option<&'a str>,
+        ) -> BoxFuture<'a, anyhow::Result<PrefixPoolEntry>> {
+            Box::pin(async move {
+                let key = self.key(prefix);
+                let now = Utc::now();
+                let created_at = self
+                    .get(&key, false)
+                    .await?
+                    .first()
+                    .and_then(|(key, value)| parse_entry(&self.key_prefix, key, value).ok())
+                    .map_or(now, |entry| entry.created_at);
+                let entry = KvEntry {
+                    class: class.map(str::to_string),
+                    status: status.to_string(),
+                    note: note.map(str::to_string),
+                    created_at: Some(created_at),
+                    updated_at: Some(now),
+                };
+                self.put(&key, serde_json::to_vec(&entry)?).await?;
+                Ok(PrefixPoolEntry {
+                    prefix: prefix.to_string(),
+                    class: entry.class,
+                    status: entry.status,
+                    note: entry.note,
+                    created_at,
+                    updated_at: now,
+                })
+            })
+        }
+
+        fn delete_entry<'a>(&'a self, prefix: &'a Prefix) -> BoxFuture<'a, anyhow::Result<bool>> {
+            Box::pin(async move { self.delete(&self.key(prefix)).await })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_parse_entry() {
+            let entry = parse_entry(
+                "peerlab/pool/",
+                "peerlab/pool/2001:db8:1::/48",
+                br#"{"status": "disabled", "note": "hijacked"}"#,
+            )
+            .unwrap();
+            assert_eq!(entry.prefix, "2001:db8:1::/48");
+            assert_eq!(entry.status, "disabled");
+            assert_eq!(entry.class, None);
+            assert_eq!(entry.created_at, DateTime::<Utc>::default());
+
+            // Written by hand with only a class
+            let entry = parse_entry("", "2001:db8:2::/48", br#"{"class": "global"}"#).unwrap();
+            assert_eq!(entry.status, "active");
+            assert!(parse_entry("", "not-a-prefix", b"{}").is_err());
+            assert!(parse_entry("", "2001:db8:2::/48", b"active").is_err());
+        }
+
+        #[test]
+        fn test_range_end() {
+            assert_eq!(range_end("peerlab/pool/"), b"peerlab/pool0");
+            assert_eq!(range_end(""), vec![0]);
+        }
+
+        #[test]
+        fn test_decode_pairs() {
+            let key = STANDARD.encode("pool/2001:db8:1::/48");
+            let value = STANDARD.encode("{}");
+            let pairs = decode_pairs(
+                [(Some(key.as_str()), Some(value.as_str()))].into_iter(),
+                true,
+            )
+            .unwrap();
+            assert_eq!(
+                pairs,
+                vec![("pool/2001:db8:1::/48".to_string(), b"{}".to_vec())]
+            );
+            let folder = decode_pairs([(Some("pool/"), None)].into_iter(), false).unwrap();
+            assert_eq!(folder, vec![("pool/".to_string(), Vec::new())]);
+            assert!(decode_pairs([(None, Some("e30="))].into_iter(), false).is_err());
+        }
+
+        #[test]
+        fn test_store_url() {
+            assert!(KvStore::new(KvBackend::Etcd, "not a url", String::new(), None).is_err());
+            let store = KvStore::new(
+                KvBackend::Consul,
+                "http://consul:8500",
+                "peerlab/pool/".to_string(),
+                None,
+            )
+            .unwrap();
+            let key = store.key(&"2001:db8:1::/48".parse().unwrap());
+            assert_eq!(
+                store.consul_url(&key, false).unwrap().as_str(),
+                "http://consul:8500/v1/kv/peerlab/pool/2001:db8:1::/48"
+            );
+            assert_eq!(
+                store.consul_url("peerlab/pool/", true).unwrap().as_str(),
+                "http://consul:8500/v1/kv/peerlab/pool/?recurse=true"
+            );
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::{Result, anyhow};
+use std::time::Instant;
+use tracing::info;
+
+use crate::{AppState, database::MappingFilter, jwt};
+
+/// Warm caches and check external dependencies before serving traffic.
+///
+/// Loads the full mapping set, fetches the JWKS and obtains an Auth0 M2M token
+/// (each only when configured), failing on the first error so misconfiguration
+/// is found at deploy time rather than by the first user.
+pub async fn run(state: &AppState) -> Result<()> {
+    let start = Instant::now();
+
+    let rows = state
+        .store
+        .get_mapping_permissions(None, &MappingFilter::default())
+        .await
+        .map_err(|err| anyhow!("Failed to load mappings: {}", err))?;
+    let mappings = MappingFilter::group(rows);
+    info!("Pre-warm: loaded {} user mappings", mappings.len());
+
+    if !state.bypass_jwt_validation
+        && state.token_validator.is_none()
+        && (state.auth0_jwks_uri.is_some() || state.jwks_file.is_some())
+    {
+        jwt::JwtValidator::get_or_create(state)
+            .await
+            .map_err(|err| anyhow!("Failed to load JWKS: {}", err.message))?;
+        info!("Pre-warm: loaded JWKS");
+    }
+
+    #[cfg(feature = "auth0")]
+    if let (Some(api_url), Some(app_id), Some(app_secret)) = (
+        &state.auth0_management_api,
+        &state.auth0_m2m_app_id,
+        &state.auth0_m2m_app_secret,
+    ) {
+        crate::auth0::check_m2m_credentials(api_url, app_id, app_secret)
+            .await
+            .map_err(|err| anyhow!("Failed to obtain an Auth0 M2M token: {}", err))?;
+        info!("Pre-warm: obtained Auth0 M2M token");
+    }
+
+    info!("Pre-warm completed in {:?}", start.elapsed());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Database, DatabaseConfig};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_prewarm_fails_without_database() {
+        let config = DatabaseConfig::new("postgresql://127.0.0.1:1/none".into())
+            .with_acquire_timeout(Duration::from_millis(100));
+        let state = AppState::builder()
+            .database(Database::connect_lazy(&config).unwrap())
+            .build()
+            .unwrap();
+
+        let err = run(&state).await.unwrap_err();
+        assert!(err.to_string().starts_with("Failed to load mappings"));
+    }
+}
@@ -0,0 +1,169 @@
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Request, State},
+    http::{HeaderValue, header},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::{Map, Value, json};
+use std::str::FromStr;
+use tracing::warn;
+
+use crate::AppState;
+
+/// Content type of RFC 7807 problem details
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Largest error body rewritten into problem details
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+/// Format of error response bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorFormat {
+    /// RFC 7807 `application/problem+json`
+    #[default]
+    Problem,
+    /// `{"error": <status>, "message": ...}` as returned by earlier versions
+    Legacy,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "problem" => Ok(Self::Problem),
+            "legacy" => Ok(Self::Legacy),
+            other => Err(format!(
+                "Unknown error format '{}' (expected 'problem' or 'legacy')",
+                other
+            )),
+        }
+    }
+}
+
+/// Build problem details from a legacy error body.
+///
+/// `message` becomes `detail`, `error` is dropped in favor of `status`, and any
+/// other member (e.g. `reason`, `supported_formats`) is kept as an extension.
+/// Non-JSON bodies are used as the detail as-is.
+pub fn to_problem(status: u16, title: &str, instance: &str, body: &[u8]) -> Value {
+    let mut problem = Map::new();
+    problem.insert("type".into(), json!("about:blank"));
+    problem.insert("title".into(), json!(title));
+    problem.insert("status".into(), json!(status));
+
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Object(fields)) => {
+            for (key, value) in fields {
+                match key.as_str() {
+                    "error" | "status" | "type" | "title" | "instance" => {}
+                    "message" => {
+                        problem.insert("detail".into(), value);
+                    }
+                    _ => {
+                        problem.insert(key, value);
+                    }
+                }
+            }
+        }
+        _ => {
+            let text = String::from_utf8_lossy(body);
+            let text = text.trim();
+            if !text.is_empty() {
+                problem.insert("detail".into(), json!(text));
+            }
+        }
+    }
+
+    problem.insert("instance".into(), json!(instance));
+    Value::Object(problem)
+}
+
+/// Rewrite error responses into problem details when that format is enabled
+pub async fn render_errors(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let instance = request.uri().path().to_string();
+    let response = next.run(request).await;
+
+    let status = response.status();
+    if state.error_format != ErrorFormat::Problem
+        || !(status.is_client_error() || status.is_server_error())
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match to_bytes(body, MAX_ERROR_BODY).await {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("Failed to read error body for {}: {}", instance, err);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+
+    let problem = to_problem(
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("Error"),
+        &instance,
+        &body,
+    );
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    Response::from_parts(parts, Body::from(problem.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_body_to_problem() {
+        let body = json!({
+            "error": 404,
+            "message": "Unknown format",
+            "supported_formats": ["bird"]
+        });
+        let problem = to_problem(
+            404,
+            "Not Found",
+            "/service/filters/cisco",
+            body.to_string().as_bytes(),
+        );
+
+        assert_eq!(
+            problem,
+            json!({
+                "type": "about:blank",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "Unknown format",
+                "supported_formats": ["bird"],
+                "instance": "/service/filters/cisco"
+            })
+        );
+    }
+
+    #[test]
+    fn test_text_body_to_problem() {
+        let problem = to_problem(400, "Bad Request", "/x", b"Failed to deserialize");
+        assert_eq!(problem["detail"], "Failed to deserialize");
+
+        let problem = to_problem(404, "Not Found", "/x", b"");
+        assert!(problem.get("detail").is_none());
+    }
+
+    #[test]
+    fn test_error_format_parsing() {
+        assert_eq!("legacy".parse(), Ok(ErrorFormat::Legacy));
+        assert_eq!("Problem".parse(), Ok(ErrorFormat::Problem));
+        assert!("xml".parse::<ErrorFormat>().is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use chrono::{DateTime, Duration, Utc};
+use tracing::{debug, info, warn};
+
+use crate::AppState;
+use crate::auth0::ManagementClient;
+
+/// Profiles revalidated against the IdP per scheduler run
+pub const GC_BATCH_SIZE: i64 = 100;
+
+/// Whether a cached profile can be served without asking the IdP
+fn is_fresh(validated_at: DateTime<Utc>, ttl: Duration, now: DateTime<Utc>) -> bool {
+    now - validated_at < ttl
+}
+
+async fn management_client(state: &AppState) -> Option<Result<ManagementClient, String>> {
+    let (Some(api_url), Some(app_id), Some(app_secret)) = (
+        &state.auth0_management_api,
+        &state.auth0_m2m_app_id,
+        &state.auth0_m2m_app_secret,
+    ) else {
+        return None;
+    };
+    Some(ManagementClient::connect(api_url, app_id, app_secret).await)
+}
+
+/// Email of a user, from the profile cache while fresh, else from the IdP.
+///
+/// Users the IdP no longer knows are dropped from the cache. When the IdP is
+/// unreachable, a stale cached email is better than none.
+pub async fn lookup_email(state: &AppState, user_id: &str) -> Option<String> {
+    let cached = match state.database.get_user_profile(user_id).await {
+        Ok(cached) => cached,
+        Err(err) => {
+            warn!("Failed to load cached profile of user {}: {}", user_id, err);
+            None
+        }
+    };
+    if let Some(profile) = &cached
+        && is_fresh(profile.validated_at, state.profile_cache_ttl, Utc::now())
+    {
+        return profile.email.clone();
+    }
+
+    let fetched = match management_client(state).await? {
+        Ok(client) => client.get_user(user_id).await,
+        Err(err) => Err(err),
+    };
+    match fetched {
+        Ok(Some(user)) => {
+            if let Err(err) = state
+                .database
+                .upsert_user_profile(user_id, user.email.as_deref())
+                .await
+            {
+                warn!("Failed to cache profile of user {}: {}", user_id, err);
+            }
+            user.email
+        }
+        Ok(None) => {
+            if cached.is_some() {
+                info!(
+                    "User {} no longer exists in the IdP, pruning profile",
+                    user_id
+                );
+                if let Err(err) = state.database.delete_user_profile(user_id).await {
+                    warn!("Failed to prune profile of user {}: {}", user_id, err);
+                }
+            }
+            None
+        }
+        Err(err) => {
+            warn!("Failed to fetch email for user {}: {}", user_id, err);
+            cached.and_then(|profile| profile.email)
+        }
+    }
+}
+
+/// State of a user's IdP account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountStatus {
+    Active,
+    /// Suspended in the IdP
+    Blocked,
+    /// Removed from the IdP
+    Missing,
+}
+
+/// Check a user's account with the IdP, refreshing its cached profile.
+///
+/// This always asks the IdP: a deactivated account must be caught even while
+/// its profile is cached and its token is still valid.
+pub async fn check_account(state: &AppState, user_id: &str) -> Result<AccountStatus, String> {
+    let client = management_client(state)
+        .await
+        .ok_or_else(|| "the Auth0 Management API is not configured".to_string())??;
+
+    match client.get_user(user_id).await? {
+        Some(user) => {
+            if let Err(err) = state
+                .database
+                .upsert_user_profile(user_id, user.email.as_deref())
+                .await
+            {
+                warn!("Failed to cache profile of user {}: {}", user_id, err);
+            }
+            Ok(if user.blocked {
+                AccountStatus::Blocked
+            } else {
+                AccountStatus::Active
+            })
+        }
+        None => {
+            if let Err(err) = state.database.delete_user_profile(user_id).await {
+                warn!("Failed to prune profile of user {}: {}", user_id, err);
+            }
+            Ok(AccountStatus::Missing)
+        }
+    }
+}
+
+/// Outcome of a profile garbage collection run
+#[derive(Debug, Default, PartialEq)]
+pub struct GcReport {
+    /// Profiles of users without an ASN mapping anymore
+    pub orphaned: u64,
+    /// Profiles confirmed by the IdP
+    pub revalidated: u64,
+    /// Profiles of users the IdP no longer knows
+    pub pruned: u64,
+}
+
+/// Prune orphaned profiles, then revalidate a batch of expired ones against
+/// the IdP, dropping those of removed IdP accounts.
+pub async fn collect_garbage(state: &AppState) -> Result<GcReport, String> {
+    let mut report = GcReport {
+        orphaned: state
+            .database
+            .delete_orphaned_user_profiles()
+            .await
+            .map_err(|e| format!("Failed to delete orphaned profiles: {}", e))?,
+        ..Default::default()
+    };
+
+    let stale = state
+        .database
+        .get_stale_user_profiles(Utc::now() - state.profile_cache_ttl, GC_BATCH_SIZE)
+        .await
+        .map_err(|e| format!("Failed to load stale profiles: {}", e))?;
+    if stale.is_empty() {
+        return Ok(report);
+    }
+
+    // Without Management API access expired profiles can't be revalidated
+    let Some(client) = management_client(state).await else {
+        for profile in &stale {
+            state
+                .database
+                .delete_user_profile(&profile.user_id)
+                .await
+                .map_err(|e| format!("Failed to delete profile: {}", e))?;
+            report.pruned += 1;
+        }
+        return Ok(report);
+    };
+    let client = client?;
+
+    for profile in stale {
+        match client.get_user(&profile.user_id).await? {
+            Some(user) => {
+                state
+                    .database
+                    .upsert_user_profile(&profile.user_id, user.email.as_deref())
+                    .await
+                    .map_err(|e| format!("Failed to update profile: {}", e))?;
+                report.revalidated += 1;
+            }
+            None => {
+                debug!("User {} no longer exists in the IdP", profile.user_id);
+                state
+                    .database
+                    .delete_user_profile(&profile.user_id)
+                    .await
+                    .map_err(|e| format!("Failed to delete profile: {}", e))?;
+                report.pruned += 1;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Database, DatabaseConfig};
+
+    #[test]
+    fn test_is_fresh() {
+        let now = Utc::now();
+        let ttl = Duration::hours(24);
+        assert!(is_fresh(now - Duration::hours(23), ttl, now));
+        assert!(!is_fresh(now - Duration::hours(24), ttl, now));
+    }
+
+    #[tokio::test]
+    async fn test_collect_garbage_fails_without_database() {
+        let config = DatabaseConfig::new("postgresql://127.0.0.1:1/none".into())
+            .with_acquire_timeout(std::time::Duration::from_millis(100));
+        let state = AppState::builder()
+            .database(Database::connect_lazy(&config).unwrap())
+            .build()
+            .unwrap();
+
+        let err = collect_garbage(&state).await.unwrap_err();
+        assert!(err.starts_with("Failed to delete orphaned profiles"));
+    }
+}
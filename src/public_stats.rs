@@ -0,0 +1,133 @@
+//! Public lab statistics.
+//!
+//! `/api/public/stats` serves a few lab-wide totals (participants, ASNs in
+//! use, prefixes announced) to the project website without credentials. Only
+//! totals are exposed, never users, prefixes or ASNs. The numbers are cached
+//! so the endpoint costs one query per refresh however often it is hit, and
+//! requests are rate limited per client address.
+
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{ConnectInfo, Extension, State},
+    http::{HeaderMap, HeaderName, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde_json::{Value, json};
+use std::{net::SocketAddr, time::Duration};
+use tracing::{error, warn};
+
+use crate::rate_limit::{self, RateLimiter};
+use crate::response_cache::ResponseCache;
+use crate::{AppState, retry};
+
+/// Default number of requests accepted per client address and minute
+pub const DEFAULT_REQUESTS_PER_MINUTE: u32 = 30;
+
+/// Default time the totals are served from the cache
+pub const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// Rate limit and cache of the public stats
+#[derive(Debug, Clone)]
+pub struct PublicStats {
+    limiter: RateLimiter,
+    cache: ResponseCache,
+    ttl: Duration,
+    /// Key the rate limit by the last `X-Forwarded-For` address, set by a
+    /// trusted reverse proxy, instead of the peer address
+    pub trust_forwarded_for: bool,
+}
+
+impl Default for PublicStats {
+    fn default() -> Self {
+        Self::new(DEFAULT_REQUESTS_PER_MINUTE, DEFAULT_TTL)
+    }
+}
+
+impl PublicStats {
+    /// Accept `requests_per_minute` requests per client address, and refresh
+    /// the totals every `ttl` (served stale for another `ttl` while refreshing)
+    pub fn new(requests_per_minute: u32, ttl: Duration) -> Self {
+        Self {
+            limiter: RateLimiter::new(requests_per_minute, Duration::from_secs(60)),
+            cache: ResponseCache::new(ttl, ttl),
+            ttl,
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.into()
+        })),
+    )
+}
+
+/// Serve the lab-wide totals, from the cache when fresh enough
+pub async fn get_public_stats(
+    State(state): State<AppState>,
+    peer: Option<Extension<ConnectInfo<SocketAddr>>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let config = &state.public_stats;
+    let ip = rate_limit::client_ip(
+        config.trust_forwarded_for,
+        peer.map(|Extension(ConnectInfo(addr))| addr),
+        &headers,
+    );
+    if !config.limiter.check(ip) {
+        warn!("Rate limited public stats requests from {}", ip);
+        let (status, Json(mut body)) = api_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many requests, try again later",
+        );
+        body[retry::RETRY_AFTER_FIELD] = json!(config.limiter.retry_after(ip).as_secs().max(1));
+        return Err((status, Json(body)));
+    }
+
+    let database = state.database.clone();
+    let load = || async move {
+        let totals = database.get_lab_totals().await?;
+        let body = json!({
+            "participants": totals.participants,
+            "asns_in_use": totals.asns_in_use,
+            "prefixes_announced": totals.prefixes_announced,
+            "updated_at": Utc::now().to_rfc3339(),
+        });
+        Ok::<_, sqlx::Error>(Bytes::from(body.to_string()))
+    };
+    match config.cache.get_or_load("totals".to_string(), load).await {
+        Ok((body, age, status)) => Ok((
+            [
+                (header::CONTENT_TYPE, "application/json".to_string()),
+                (
+                    header::CACHE_CONTROL,
+                    format!("public, max-age={}", config.ttl.as_secs()),
+                ),
+                (header::AGE, age.as_secs().to_string()),
+                // Read by the project website from the browser
+                (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*".to_string()),
+                (
+                    HeaderName::from_static("x-cache"),
+                    status.as_str().to_string(),
+                ),
+            ],
+            body,
+        )
+            .into_response()),
+        Err(err) => {
+            error!("Failed to compute public stats: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to compute stats",
+            ))
+        }
+    }
+}
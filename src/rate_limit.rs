@@ -0,0 +1,192 @@
+//! Rate limiting of the public endpoints.
+//!
+//! Unauthenticated endpoints (abuse reports, public stats) are limited per
+//! client address with a fixed window. Behind a trusted reverse proxy the
+//! client address is taken from the last `X-Forwarded-For` entry. IPv6
+//! clients are limited per /64, the smallest network a host usually gets, so
+//! rotating through addresses of one network doesn't reset the limit.
+
+use axum::http::HeaderMap;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Default length of the IPv6 networks clients are limited by
+pub const DEFAULT_IPV6_PREFIX_LEN: u8 = 64;
+
+/// Default number of clients whose windows are tracked at once
+pub const DEFAULT_MAX_CLIENTS: usize = 100_000;
+
+/// Fixed-window rate limiter keyed by client address
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    ipv6_prefix_len: u8,
+    max_clients: usize,
+    hits: Arc<Mutex<Hits>>,
+}
+
+/// Window start and request count of each client
+#[derive(Debug)]
+struct Hits {
+    clients: HashMap<IpAddr, (Instant, u32)>,
+    /// When expired windows were last dropped
+    swept_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            ipv6_prefix_len: DEFAULT_IPV6_PREFIX_LEN,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            hits: Arc::new(Mutex::new(Hits {
+                clients: HashMap::new(),
+                swept_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// Limit IPv6 clients per network of this length (128 for per address)
+    pub fn with_ipv6_prefix_len(mut self, len: u8) -> Self {
+        self.ipv6_prefix_len = len.min(128);
+        self
+    }
+
+    /// Track at most this many clients; past it the window closest to its
+    /// end is dropped to make room
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = max_clients.max(1);
+        self
+    }
+
+    /// Key of a client: its address, or its network for IPv6
+    fn key(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V6(v6) if v6.to_ipv4_mapped().is_none() => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.ipv6_prefix_len))
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
+            _ => ip.to_canonical(),
+        }
+    }
+
+    /// Count a request from `ip`, returning whether it is within the limit
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let key = self.key(ip);
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+
+        // Drop expired windows once per window rather than on every request
+        if now.duration_since(hits.swept_at) >= self.window {
+            hits.clients
+                .retain(|_, (start, _)| now.duration_since(*start) < self.window);
+            hits.swept_at = now;
+        }
+        if !hits.clients.contains_key(&key) && hits.clients.len() >= self.max_clients {
+            let oldest = hits
+                .clients
+                .iter()
+                .min_by_key(|(_, (start, _))| *start)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                hits.clients.remove(&oldest);
+            }
+        }
+
+        let (start, count) = hits.clients.entry(key).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= self.limit
+    }
+
+    /// Time until `ip` may make requests again
+    pub fn retry_after(&self, ip: IpAddr) -> Duration {
+        let hits = self.hits.lock().unwrap();
+        hits.clients
+            .get(&self.key(ip))
+            .map(|(start, _)| self.window.saturating_sub(start.elapsed()))
+            .unwrap_or_default()
+    }
+}
+
+/// Address a request is rate limited by: the last `X-Forwarded-For` entry
+/// when set by a trusted proxy, else the peer address
+pub fn client_ip(
+    trust_forwarded_for: bool,
+    peer: Option<SocketAddr>,
+    headers: &HeaderMap,
+) -> IpAddr {
+    let forwarded = trust_forwarded_for
+        .then(|| headers.get("x-forwarded-for")?.to_str().ok())
+        .flatten()
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok());
+    forwarded
+        .or(peer.map(|addr| addr.ip()))
+        .unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let alice: IpAddr = "2001:db8::1".parse().unwrap();
+        let bob: IpAddr = "2001:db8:1::2".parse().unwrap();
+        assert!(limiter.check(alice));
+        assert!(limiter.check(alice));
+        assert!(!limiter.check(alice));
+        assert!(limiter.check(bob));
+        assert!(limiter.retry_after(alice) > Duration::from_secs(59));
+        assert_eq!(
+            limiter.retry_after("2001:db8:2::3".parse().unwrap()),
+            Duration::ZERO
+        );
+
+        let limiter = RateLimiter::new(1, Duration::ZERO);
+        assert!(limiter.check(alice));
+        assert!(limiter.check(alice));
+    }
+
+    #[test]
+    fn test_rate_limiter_keys() {
+        // Addresses of the same /64 share a window
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+        assert!(limiter.check("2001:db8:0:1::1".parse().unwrap()));
+        assert!(!limiter.check("2001:db8:0:1:ffff::2".parse().unwrap()));
+        assert!(limiter.check("2001:db8:0:2::1".parse().unwrap()));
+        // IPv4 clients, mapped or not, are limited per address
+        assert!(limiter.check("192.0.2.1".parse().unwrap()));
+        assert!(!limiter.check("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(limiter.check("192.0.2.2".parse().unwrap()));
+
+        let limiter = RateLimiter::new(1, Duration::from_secs(60)).with_ipv6_prefix_len(128);
+        assert!(limiter.check("2001:db8::1".parse().unwrap()));
+        assert!(limiter.check("2001:db8::2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_rate_limiter_bounded() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60)).with_max_clients(2);
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(limiter.check(first));
+        assert!(limiter.check("192.0.2.2".parse().unwrap()));
+        assert!(limiter.check("192.0.2.3".parse().unwrap()));
+        assert_eq!(limiter.hits.lock().unwrap().clients.len(), 2);
+        // The oldest window made room
+        assert_eq!(limiter.retry_after(first), Duration::ZERO);
+    }
+}
@@ -0,0 +1,309 @@
+//! Reloading of file-based configuration on SIGHUP.
+//!
+//! The prefix pool, ASN pool file, agent keys and message translations are
+//! read from files that may be edited while the gateway runs. On SIGHUP each
+//! file is loaded again and what changed is logged. A file that fails to load
+//! or validate is rejected and the configuration it would have replaced stays
+//! active. Prefix pool entries managed in the database stay applied on top of
+//! a reloaded pool file.
+
+use anyhow::{Result, bail};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use tracing::{error, info, warn};
+
+use crate::AppState;
+use crate::agent::{AgentInfo, AgentKeys};
+use crate::messages::Catalog;
+use crate::pool_asns::AsnPool;
+use crate::pool_prefixes::PrefixPool;
+
+/// Shared value that can be replaced while requests hold the previous one
+pub struct Reloadable<T>(Arc<RwLock<Arc<T>>>);
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(value))))
+    }
+
+    /// Current value
+    pub fn load(&self) -> Arc<T> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Replace the value, returning the previous one
+    pub fn replace(&self, value: T) -> Arc<T> {
+        let mut current = self.0.write().unwrap_or_else(|e| e.into_inner());
+        std::mem::replace(&mut *current, Arc::new(value))
+    }
+}
+
+impl<T> Clone for Reloadable<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: Default> Default for Reloadable<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Reloadable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.load().fmt(f)
+    }
+}
+
+/// Files the reloadable configuration is loaded from (not reloaded when unset)
+#[derive(Debug, Clone, Default)]
+pub struct ReloadSources {
+    pub prefix_pool_file: Option<String>,
+    pub asn_pool_file: Option<String>,
+    pub agent_keys_file: Option<String>,
+    pub messages_file: Option<String>,
+}
+
+/// Load a file again and swap it in if it is valid, returning what changed
+fn apply<T>(
+    what: &str,
+    path: Option<&str>,
+    load: impl FnOnce(&str) -> Result<T>,
+    target: &Reloadable<T>,
+    diff: impl FnOnce(&T, &T) -> Vec<String>,
+) -> Vec<String> {
+    let Some(path) = path else {
+        return Vec::new();
+    };
+    let loaded = match load(path) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            error!(
+                "Rejected new {} from {}, keeping the current one: {:#}",
+                what, path, err
+            );
+            return Vec::new();
+        }
+    };
+    let changes = diff(&target.load(), &loaded);
+    if !changes.is_empty() {
+        target.replace(loaded);
+        for change in &changes {
+            info!("Reloaded {}: {}", what, change);
+        }
+    }
+    changes
+}
+
+pub(crate) fn diff_prefix_pools(old: &PrefixPool, new: &PrefixPool) -> Vec<String> {
+    let mut changes = Vec::new();
+    for prefix in new.get_all_prefixes() {
+        if !old.contains(prefix) {
+            changes.push(format!(
+                "added {} ({})",
+                prefix,
+                new.class_of(prefix).name()
+            ));
+        } else if old.class_of(prefix) != new.class_of(prefix) {
+            changes.push(format!(
+                "{} changed from {} to {}",
+                prefix,
+                old.class_of(prefix).name(),
+                new.class_of(prefix).name()
+            ));
+        }
+    }
+    for prefix in old.get_all_prefixes() {
+        if !new.contains(prefix) {
+            changes.push(format!("removed {} (active leases are kept)", prefix));
+        }
+    }
+    changes
+}
+
+fn diff_asn_pools(old: &AsnPool, new: &AsnPool) -> Vec<String> {
+    if old.ranges() == new.ranges() {
+        return Vec::new();
+    }
+    let ranges = |pool: &AsnPool| {
+        pool.ranges()
+            .iter()
+            .map(|(start, end)| format!("{}-{}", start, end))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    for warning in new.policy_warnings() {
+        warn!("{}", warning);
+    }
+    vec![format!(
+        "ranges changed from [{}] to [{}] ({} ASNs)",
+        ranges(old),
+        ranges(new),
+        new.size()
+    )]
+}
+
+fn diff_agent_keys(old: &AgentKeys, new: &AgentKeys) -> Vec<String> {
+    // Keys are compared but never logged
+    let by_id = |keys: &AgentKeys| -> BTreeMap<String, (String, AgentInfo)> {
+        keys.iter()
+            .map(|(key, info)| (info.id.clone(), (key.to_string(), info.clone())))
+            .collect()
+    };
+    let (old, new) = (by_id(old), by_id(new));
+    let mut changes = Vec::new();
+    for (id, (key, info)) in &new {
+        match old.get(id) {
+            None => changes.push(format!("added agent {}", id)),
+            Some((old_key, _)) if old_key != key => {
+                changes.push(format!("rotated the key of agent {}", id))
+            }
+            Some((_, old_info)) if old_info != info => {
+                changes.push(format!("updated agent {}", id))
+            }
+            Some(_) => {}
+        }
+    }
+    for id in old.keys().filter(|id| !new.contains_key(*id)) {
+        changes.push(format!("removed agent {}", id));
+    }
+    changes
+}
+
+fn diff_catalogs(old: &Catalog, new: &Catalog) -> Vec<String> {
+    let mut changes = Vec::new();
+    for language in new.languages() {
+        match old.translations(language) {
+            None => changes.push(format!("added language {}", language)),
+            Some(translations) if Some(translations) != new.translations(language) => {
+                changes.push(format!("updated language {}", language))
+            }
+            Some(_) => {}
+        }
+    }
+    for language in old.languages() {
+        if new.translations(language).is_none() {
+            changes.push(format!("removed language {}", language));
+        }
+    }
+    changes
+}
+
+/// Reload every configured file, returning what changed
+pub fn reload(state: &AppState, sources: &ReloadSources) -> Vec<String> {
+    let mut changes = apply(
+        "prefix pool",
+        sources.prefix_pool_file.as_deref(),
+        |path| {
+            let lengths = state.prefix_pool_file.load().lengths().to_vec();
+            let pool = PrefixPool::from_file_with_lengths(path, &lengths)?;
+            if pool.is_empty() {
+                bail!("no valid prefix in the file");
+            }
+            Ok(pool)
+        },
+        &state.prefix_pool_file,
+        diff_prefix_pools,
+    );
+    if !changes.is_empty() {
+        let entries = state.prefix_pool_entries.load();
+        state
+            .prefix_pool
+            .replace(state.prefix_pool_file.load().with_entries(&entries));
+    }
+    changes.extend(apply(
+        "ASN pool",
+        sources.asn_pool_file.as_deref(),
+        |path| AsnPool::from_file(path),
+        &state.asn_pool,
+        diff_asn_pools,
+    ));
+    changes.extend(apply(
+        "agent keys",
+        sources.agent_keys_file.as_deref(),
+        |path| AgentKeys::from_file(path),
+        &state.agent_keys,
+        diff_agent_keys,
+    ));
+    changes.extend(apply(
+        "messages",
+        sources.messages_file.as_deref(),
+        |path| Catalog::from_file(path),
+        &state.messages,
+        diff_catalogs,
+    ));
+    changes
+}
+
+/// Spawn the task reloading the configuration on SIGHUP
+#[cfg(unix)]
+pub fn spawn(state: AppState, sources: ReloadSources) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    tokio::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => {
+                error!(
+                    "Failed to listen for SIGHUP, reloading is disabled: {}",
+                    err
+                );
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            info!("SIGHUP received, reloading configuration");
+            if reload(&state, &sources).is_empty() {
+                info!("Configuration unchanged");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Database, DatabaseConfig};
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn pool_file(content: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "{}", content).unwrap();
+        file
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_config_on_invalid_file() {
+        let config = DatabaseConfig::new("postgresql://127.0.0.1:1/none".into());
+        let state = AppState::builder()
+            .database(Database::connect_lazy(&config).unwrap())
+            .prefix_pool(PrefixPool::new(vec!["2001:db8:1::/48".parse().unwrap()]))
+            .build()
+            .unwrap();
+
+        let file = pool_file("2001:db8:1::/48\n2001:db8:2::/48\n");
+        let sources = ReloadSources {
+            prefix_pool_file: Some(file.path().display().to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            reload(&state, &sources),
+            vec!["added 2001:db8:2::/48 (documentation)"]
+        );
+        assert_eq!(state.prefix_pool.load().len(), 2);
+        assert!(reload(&state, &sources).is_empty());
+
+        let empty = pool_file("# every prefix commented out\n");
+        for path in [empty.path().display().to_string(), "/nonexistent".into()] {
+            let sources = ReloadSources {
+                prefix_pool_file: Some(path),
+                ..Default::default()
+            };
+            assert!(reload(&state, &sources).is_empty());
+            assert_eq!(state.prefix_pool.load().len(), 2);
+        }
+    }
+}
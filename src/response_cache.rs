@@ -0,0 +1,195 @@
+use axum::body::Bytes;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone)]
+struct Entry {
+    body: Bytes,
+    fetched_at: Instant,
+    refreshing: bool,
+}
+
+/// How a cached response was served
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// Younger than the TTL
+    Hit,
+    /// Past the TTL but within the max staleness; a refresh runs in the background
+    Stale,
+    /// Missing or too stale, loaded before responding
+    Miss,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hit => "hit",
+            Self::Stale => "stale",
+            Self::Miss => "miss",
+        }
+    }
+}
+
+/// Stale-while-revalidate cache of serialized responses, keyed by request.
+///
+/// A response is served from the cache for `ttl`, then served stale for at
+/// most `max_stale` more while a single background task reloads it. Beyond
+/// that it is reloaded before responding.
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    ttl: Duration,
+    max_stale: Duration,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration, max_stale: Duration) -> Self {
+        Self {
+            ttl,
+            max_stale,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Get a response from the cache, loading it with `load` when needed.
+    /// Returns the body, its age and how it was served.
+    pub async fn get_or_load<F, Fut, E>(
+        &self,
+        key: String,
+        load: F,
+    ) -> Result<(Bytes, Duration, CacheStatus), E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Bytes, E>> + Send + 'static,
+        E: std::fmt::Display + Send + 'static,
+    {
+        // (body, age, status, whether this request starts the refresh)
+        let cached = {
+            let mut entries = self.entries.lock().unwrap();
+            entries.get_mut(&key).and_then(|entry| {
+                let age = entry.fetched_at.elapsed();
+                if age < self.ttl {
+                    Some((entry.body.clone(), age, CacheStatus::Hit, false))
+                } else if age < self.ttl + self.max_stale {
+                    // Only the first request past the TTL refreshes
+                    let refresh = !entry.refreshing;
+                    entry.refreshing = true;
+                    Some((entry.body.clone(), age, CacheStatus::Stale, refresh))
+                } else {
+                    None
+                }
+            })
+        };
+
+        match cached {
+            Some((body, age, status, refresh)) => {
+                if refresh {
+                    debug!("Refreshing stale response {} in the background", key);
+                    let future = load();
+                    let cache = self.clone();
+                    tokio::spawn(async move {
+                        match future.await {
+                            Ok(body) => cache.store(key, body),
+                            Err(err) => {
+                                warn!("Failed to refresh cached response {}: {}", key, err);
+                                if let Some(entry) = cache.entries.lock().unwrap().get_mut(&key) {
+                                    entry.refreshing = false;
+                                }
+                            }
+                        }
+                    });
+                }
+                Ok((body, age, status))
+            }
+            None => {
+                let body = load().await?;
+                self.store(key, body.clone());
+                Ok((body, Duration::ZERO, CacheStatus::Miss))
+            }
+        }
+    }
+
+    fn store(&self, key: String, body: Bytes) {
+        let mut entries = self.entries.lock().unwrap();
+        // Drop entries too stale to ever be served again
+        let expiry = self.ttl + self.max_stale;
+        entries.retain(|_, entry| entry.fetched_at.elapsed() < expiry);
+        entries.insert(
+            key,
+            Entry {
+                body,
+                fetched_at: Instant::now(),
+                refreshing: false,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    async fn get(cache: &ResponseCache, loads: &Arc<AtomicUsize>) -> (Bytes, CacheStatus) {
+        let loads = loads.clone();
+        let (body, _, status) = cache
+            .get_or_load("key".to_string(), move || async move {
+                let n = loads.fetch_add(1, Ordering::SeqCst) + 1;
+                Ok::<_, String>(Bytes::from(n.to_string()))
+            })
+            .await
+            .unwrap();
+        (body, status)
+    }
+
+    #[tokio::test]
+    async fn test_fresh_responses_are_cached() {
+        let cache = ResponseCache::new(Duration::from_secs(60), Duration::from_secs(60));
+        let loads = Arc::new(AtomicUsize::new(0));
+
+        assert_eq!(
+            get(&cache, &loads).await,
+            (Bytes::from("1"), CacheStatus::Miss)
+        );
+        assert_eq!(
+            get(&cache, &loads).await,
+            (Bytes::from("1"), CacheStatus::Hit)
+        );
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_responses_are_refreshed_in_the_background() {
+        let cache = ResponseCache::new(Duration::ZERO, Duration::from_secs(60));
+        let loads = Arc::new(AtomicUsize::new(0));
+
+        assert_eq!(get(&cache, &loads).await.1, CacheStatus::Miss);
+        assert_eq!(
+            get(&cache, &loads).await,
+            (Bytes::from("1"), CacheStatus::Stale)
+        );
+        // Let the background refresh complete
+        while loads.load(Ordering::SeqCst) < 2 {
+            tokio::task::yield_now().await;
+        }
+        tokio::task::yield_now().await;
+        assert_eq!(get(&cache, &loads).await.0, Bytes::from("2"));
+    }
+
+    #[tokio::test]
+    async fn test_too_stale_responses_are_reloaded() {
+        let cache = ResponseCache::new(Duration::ZERO, Duration::ZERO);
+        let loads = Arc::new(AtomicUsize::new(0));
+
+        assert_eq!(get(&cache, &loads).await.1, CacheStatus::Miss);
+        assert_eq!(
+            get(&cache, &loads).await,
+            (Bytes::from("2"), CacheStatus::Miss)
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Backoff hints on throttling responses.
+//!
+//! Every `429` and `503` carries a `Retry-After` header and a matching
+//! `retry_after_seconds` body field, so clients and agents back off instead
+//! of retrying in a tight loop. Handlers that know when capacity frees up put
+//! `retry_after_seconds` in their error body; others get the default.
+
+use axum::{
+    body::{Body, to_bytes},
+    extract::Request,
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use tracing::warn;
+
+/// Seconds to wait when the cause doesn't tell when to retry
+pub const DEFAULT_RETRY_AFTER_SECONDS: u64 = 30;
+
+/// Body field carrying the delay
+pub const RETRY_AFTER_FIELD: &str = "retry_after_seconds";
+
+/// Largest error body inspected for a delay
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+/// Seconds until a point in time, at least one
+pub fn seconds_until(time: DateTime<Utc>, now: DateTime<Utc>) -> u64 {
+    (time - now).num_seconds().max(1) as u64
+}
+
+/// Delay of a throttling response: its `Retry-After` header, else its body
+/// field, else the default
+fn retry_after(header: Option<&HeaderValue>, body: Option<&Value>) -> u64 {
+    header
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .or_else(|| body.and_then(|body| body.get(RETRY_AFTER_FIELD)?.as_u64()))
+        .unwrap_or(DEFAULT_RETRY_AFTER_SECONDS)
+}
+
+/// Add backoff hints to `429` and `503` responses
+pub async fn add_retry_hints(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    if !matches!(
+        response.status(),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match to_bytes(body, MAX_ERROR_BODY).await {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("Failed to read throttling response body: {}", err);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let fields = serde_json::from_slice::<Value>(&body)
+        .ok()
+        .filter(Value::is_object);
+
+    let seconds = retry_after(parts.headers.get(header::RETRY_AFTER), fields.as_ref());
+    parts
+        .headers
+        .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    let Some(mut fields) = fields else {
+        // Not ours to rewrite (e.g. a plain text rejection)
+        return Response::from_parts(parts, Body::from(body));
+    };
+    fields[RETRY_AFTER_FIELD] = json!(seconds);
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(fields.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Json, Router, routing::get};
+    use tower::ServiceExt;
+
+    async fn call(router: Router) -> (StatusCode, Option<String>, Value) {
+        let router = router.layer(axum::middleware::from_fn(add_retry_hints));
+        let response = router
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let header = response
+            .headers()
+            .get(header::RETRY_AFTER)
+            .map(|v| v.to_str().unwrap().to_string());
+        let body = to_bytes(response.into_body(), MAX_ERROR_BODY)
+            .await
+            .unwrap();
+        (
+            status,
+            header,
+            serde_json::from_slice(&body).unwrap_or(Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_throttling_responses_get_hints() {
+        let exhausted = Router::new().route(
+            "/",
+            get(|| async {
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Json(json!({"error": 503, "message": "No available prefixes at this time"})),
+                )
+            }),
+        );
+        let (status, header, body) = call(exhausted).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(header.as_deref(), Some("30"));
+        assert_eq!(body[RETRY_AFTER_FIELD], 30);
+        assert_eq!(body["message"], "No available prefixes at this time");
+
+        let limited = Router::new().route(
+            "/",
+            get(|| async {
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Json(json!({"error": 429, "retry_after_seconds": 120})),
+                )
+            }),
+        );
+        let (_, header, body) = call(limited).await;
+        assert_eq!(header.as_deref(), Some("120"));
+        assert_eq!(body[RETRY_AFTER_FIELD], 120);
+
+        let ok = Router::new().route("/", get(|| async { Json(json!({})) }));
+        assert_eq!(call(ok).await.1, None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let body = json!({"retry_after_seconds": 5});
+        assert_eq!(retry_after(Some(&HeaderValue::from(1)), Some(&body)), 1);
+        assert_eq!(retry_after(None, Some(&body)), 5);
+        assert_eq!(retry_after(None, None), DEFAULT_RETRY_AFTER_SECONDS);
+
+        let now = Utc::now();
+        assert_eq!(seconds_until(now + chrono::Duration::seconds(90), now), 90);
+        assert_eq!(seconds_until(now, now), 1);
+    }
+}
@@ -0,0 +1,185 @@
+//! Route origin validation of external prefixes.
+//!
+//! Only the holder of a prefix can publish a ROA authorizing the lab's origin
+//! ASN for it, so a valid state proves control of the prefix and means the
+//! lab's announcements of it won't be dropped by validating networks. States
+//! come from the HTTP API of a Routinator-compatible validator.
+
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, instrument, warn};
+
+use crate::AppState;
+use crate::events::{self, AgentEvent, EventPriority};
+
+/// A ROA authorizes the origin ASN for the prefix
+pub const STATE_VALID: &str = "valid";
+/// ROAs cover the prefix, but none authorizes the origin ASN at this length
+pub const STATE_INVALID: &str = "invalid";
+/// No ROA covers the prefix
+pub const STATE_NOT_FOUND: &str = "not-found";
+
+/// Client of a validator's HTTP API
+#[derive(Debug, Clone)]
+pub struct RpkiValidator {
+    url: String,
+    origin_asn: u32,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct ValidityResponse {
+    validated_route: ValidatedRoute,
+}
+
+#[derive(Deserialize)]
+struct ValidatedRoute {
+    validity: Validity,
+}
+
+#[derive(Deserialize)]
+struct Validity {
+    state: String,
+}
+
+/// Extract the state of a validity response
+fn parse_state(body: &[u8]) -> Result<String, String> {
+    let response: ValidityResponse = serde_json::from_slice(body)
+        .map_err(|e| format!("Invalid RPKI validator response: {}", e))?;
+    let state = response.validated_route.validity.state;
+    match state.as_str() {
+        STATE_VALID | STATE_INVALID | STATE_NOT_FOUND => Ok(state),
+        _ => Err(format!("Unknown RPKI validity state '{}'", state)),
+    }
+}
+
+impl RpkiValidator {
+    /// Validate routes originated by `origin_asn` against the validator at `url`
+    pub fn new(url: impl Into<String>, origin_asn: u32) -> Self {
+        Self {
+            url: url.into().trim_end_matches('/').to_string(),
+            origin_asn,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// ASN the lab announces external prefixes from
+    pub fn origin_asn(&self) -> u32 {
+        self.origin_asn
+    }
+
+    /// Validation state of the lab's origin ASN announcing `prefix`
+    #[instrument(name = "rpki", skip_all, fields(operation = "validate", prefix = %prefix))]
+    pub async fn validate(&self, prefix: &str) -> Result<String, String> {
+        let body = self
+            .client
+            .get(format!(
+                "{}/api/v1/validity/AS{}/{}",
+                self.url, self.origin_asn, prefix
+            ))
+            .send()
+            .await
+            .map_err(|e| format!("RPKI validator is unreachable: {}", e))?
+            .error_for_status()
+            .map_err(|e| format!("RPKI validator failed: {}", e))?
+            .bytes()
+            .await
+            .map_err(|e| format!("RPKI validator failed: {}", e))?;
+        parse_state(&body)
+    }
+}
+
+/// Outcome of a revalidation run
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RevalidationReport {
+    pub checked: usize,
+    pub changed: usize,
+    pub failed: usize,
+}
+
+/// Refresh the state of every verified external prefix, telling agents about
+/// prefixes entering or leaving the exports
+pub async fn revalidate(state: &AppState) -> Result<RevalidationReport, String> {
+    let Some(validator) = &state.rpki_validator else {
+        return Ok(RevalidationReport::default());
+    };
+    let external = state
+        .database
+        .get_verified_external_prefixes()
+        .await
+        .map_err(|e| format!("Failed to load external prefixes: {}", e))?;
+
+    let mut report = RevalidationReport::default();
+    for external in external {
+        report.checked += 1;
+        let rpki_state = match validator.validate(&external.prefix).await {
+            Ok(rpki_state) => rpki_state,
+            Err(err) => {
+                // Keep the last known state until the validator recovers
+                warn!("Failed to validate {}: {}", external.prefix, err);
+                report.failed += 1;
+                continue;
+            }
+        };
+        let was_valid = external.rpki_state.as_deref() == Some(STATE_VALID);
+        let updated = state
+            .database
+            .set_external_prefix_rpki_state(external.id, &rpki_state)
+            .await
+            .map_err(|e| format!("Failed to record RPKI state: {}", e))?;
+        if external.rpki_state.as_deref() == Some(rpki_state.as_str()) {
+            continue;
+        }
+        report.changed += 1;
+        info!(
+            "External prefix {} of user {} is now RPKI {}",
+            updated.prefix, updated.user_hash, rpki_state
+        );
+
+        if rpki_state == STATE_VALID {
+            state.agent_events.publish(AgentEvent::new(
+                events::EVENT_EXTERNAL_PREFIX_VERIFIED,
+                EventPriority::Normal,
+                json!({ "user_hash": updated.user_hash, "prefix": updated.prefix }),
+            ));
+        } else if was_valid {
+            // Agents must stop accepting announcements of the prefix
+            state.agent_events.publish(AgentEvent::new(
+                events::EVENT_INVALIDATE,
+                EventPriority::High,
+                json!({
+                    "resource": "external_prefix",
+                    "id": updated.id,
+                    "user_hash": updated.user_hash,
+                    "prefix": updated.prefix,
+                }),
+            ));
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_state() {
+        let body = br#"{
+            "validated_route": {
+                "route": {"origin_asn": "AS64500", "prefix": "2a0e:97c0:3e1::/48"},
+                "validity": {
+                    "state": "not-found",
+                    "description": "No VRP Covers the Route Prefix",
+                    "VRPs": {"matched": [], "unmatched_as": [], "unmatched_length": []}
+                }
+            },
+            "generatedTime": "2025-01-01T00:00:00Z"
+        }"#;
+        assert_eq!(parse_state(body).unwrap(), STATE_NOT_FOUND);
+        assert!(
+            parse_state(br#"{"validated_route": {"validity": {"state": "unknown"}}}"#).is_err()
+        );
+        assert!(parse_state(b"not json").is_err());
+    }
+}
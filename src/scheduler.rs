@@ -0,0 +1,101 @@
+use chrono::Utc;
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::{AppState, usage};
+
+/// Spawn the background scheduler running periodic maintenance jobs
+pub fn spawn(state: AppState, interval: Duration) {
+    info!("Starting background scheduler (every {:?})", interval);
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            run_jobs(&state).await;
+        }
+    });
+}
+
+/// Run every scheduled job once
+pub async fn run_jobs(state: &AppState) {
+    // Record pool usage for exhaustion forecasting
+    if let Err(err) = state.database.record_pool_usage().await {
+        error!("Failed to record pool usage: {}", err);
+    }
+
+    // Notify admins about pool utilization thresholds
+    #[cfg(feature = "alerts")]
+    if let Err(err) = crate::alerts::check_thresholds(state).await {
+        error!("Failed to check alert thresholds: {}", err);
+    }
+
+    // Revalidate cached IdP profiles and prune those of removed users
+    #[cfg(feature = "auth0")]
+    match crate::profiles::collect_garbage(state).await {
+        Ok(report) if report == Default::default() => {}
+        Ok(report) => info!(
+            "Profile cache: pruned {} orphaned and {} removed users, revalidated {}",
+            report.orphaned, report.pruned, report.revalidated
+        ),
+        Err(err) => error!("Failed to collect profile garbage: {}", err),
+    }
+
+    // Write a mappings snapshot for agents to bootstrap from
+    #[cfg(feature = "snapshots")]
+    if let Some(dir) = &state.snapshot_dir {
+        match crate::snapshots::generate(state).await {
+            Ok(snapshot) => match crate::snapshots::write(&snapshot, dir).await {
+                Ok(()) => info!(
+                    "Wrote snapshot of {} mappings ({} bytes) to {}",
+                    snapshot.manifest.mappings,
+                    snapshot.manifest.size,
+                    dir.display()
+                ),
+                Err(err) => error!("Failed to write snapshot to {}: {}", dir.display(), err),
+            },
+            Err(err) => error!("Failed to generate snapshot: {}", err),
+        }
+    }
+
+    // Refresh route origin validation of external prefixes
+    #[cfg(feature = "byoip")]
+    match crate::rpki::revalidate(state).await {
+        Ok(report) if report.changed == 0 && report.failed == 0 => {}
+        Ok(report) => info!(
+            "RPKI: checked {} external prefixes, {} changed state, {} failed",
+            report.checked, report.changed, report.failed
+        ),
+        Err(err) => error!("Failed to revalidate external prefixes: {}", err),
+    }
+
+    // Drop API usage past its retention
+    match state
+        .database
+        .delete_api_usage_before(Utc::now() - chrono::Duration::days(usage::RETENTION_DAYS))
+        .await
+    {
+        Ok(0) => {}
+        Ok(count) => info!("Deleted {} old API usage rows", count),
+        Err(err) => error!("Failed to delete old API usage: {}", err),
+    }
+
+    // Drop idempotency keys past their TTL
+    match state
+        .database
+        .delete_idempotency_keys_before(Utc::now() - state.idempotency_ttl)
+        .await
+    {
+        Ok(0) => {}
+        Ok(count) => info!("Deleted {} expired idempotency keys", count),
+        Err(err) => error!("Failed to delete expired idempotency keys: {}", err),
+    }
+
+    // Drop expired browser sessions
+    #[cfg(feature = "sessions")]
+    match state.database.delete_expired_sessions().await {
+        Ok(0) => {}
+        Ok(count) => info!("Deleted {} expired sessions", count),
+        Err(err) => error!("Failed to delete expired sessions: {}", err),
+    }
+}
@@ -0,0 +1,571 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::{HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, Key, PrivateCookieJar, SameSite};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256, Sha512};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::jwt::{self, AuthErrorReason, AuthInfo, AuthorizationError};
+
+/// Encrypted cookie holding the session id
+pub const SESSION_COOKIE: &str = "peerlab_session";
+/// Encrypted cookie holding the state and PKCE verifier of a pending login
+const LOGIN_COOKIE: &str = "peerlab_login";
+
+/// Readable cookie holding the CSRF token of the session
+pub const CSRF_COOKIE: &str = "peerlab_csrf";
+/// Header mutating session requests must echo the CSRF token in
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Path the session cookie is sent to
+const SESSION_COOKIE_PATH: &str = "/api";
+/// Path the CSRF cookie is sent to, so the UI can read it from any page
+const CSRF_COOKIE_PATH: &str = "/";
+/// Path the login cookie is sent to
+const LOGIN_COOKIE_PATH: &str = "/api/auth";
+
+/// Shortest accepted session secret
+pub const MIN_SECRET_LENGTH: usize = 32;
+
+/// Default session lifetime
+pub const DEFAULT_SESSION_TTL_HOURS: i64 = 24;
+
+/// Browser session settings and the OIDC client used for the code flow
+#[derive(Clone)]
+pub struct SessionConfig {
+    key: Key,
+    pub client_id: String,
+    client_secret: String,
+    pub authorize_url: String,
+    pub token_url: String,
+    pub redirect_uri: String,
+    /// Where the browser is sent after logging in
+    pub post_login_redirect: String,
+    pub ttl: Duration,
+}
+
+impl std::fmt::Debug for SessionConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionConfig")
+            .field("client_id", &self.client_id)
+            .field("authorize_url", &self.authorize_url)
+            .field("token_url", &self.token_url)
+            .field("redirect_uri", &self.redirect_uri)
+            .field("post_login_redirect", &self.post_login_redirect)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SessionConfig {
+    pub fn new(
+        secret: &str,
+        client_id: String,
+        client_secret: String,
+        authorize_url: String,
+        token_url: String,
+        redirect_uri: String,
+    ) -> Result<Self, String> {
+        if secret.len() < MIN_SECRET_LENGTH {
+            return Err(format!(
+                "Session secret must be at least {} bytes",
+                MIN_SECRET_LENGTH
+            ));
+        }
+        for (name, url) in [
+            ("authorize", &authorize_url),
+            ("token", &token_url),
+            ("redirect", &redirect_uri),
+        ] {
+            reqwest::Url::parse(url).map_err(|e| format!("Invalid OIDC {} URL: {}", name, e))?;
+        }
+
+        Ok(Self {
+            // Cookie keys are 64 bytes, stretched from the secret
+            key: Key::from(&Sha512::digest(secret.as_bytes())),
+            client_id,
+            client_secret,
+            authorize_url,
+            token_url,
+            redirect_uri,
+            post_login_redirect: "/".to_string(),
+            ttl: Duration::hours(DEFAULT_SESSION_TTL_HOURS),
+        })
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn with_post_login_redirect(mut self, url: String) -> Self {
+        self.post_login_redirect = url;
+        self
+    }
+
+    /// Cookies are only marked `Secure` when the gateway is served over HTTPS
+    fn secure(&self) -> bool {
+        self.redirect_uri.starts_with("https://")
+    }
+
+    fn cookie(&self, name: &'static str, value: String, path: &'static str) -> Cookie<'static> {
+        Cookie::build((name, value))
+            .path(path)
+            .http_only(true)
+            .secure(self.secure())
+            .same_site(SameSite::Lax)
+            .build()
+    }
+
+    /// Unencrypted cookie the UI reads to send the CSRF header
+    fn csrf_cookie(&self, token: String) -> Cookie<'static> {
+        Cookie::build((CSRF_COOKIE, token))
+            .path(CSRF_COOKIE_PATH)
+            .secure(self.secure())
+            .same_site(SameSite::Strict)
+            .build()
+    }
+
+    fn removal(&self, name: &'static str, path: &'static str) -> Cookie<'static> {
+        Cookie::build(name).path(path).build()
+    }
+}
+
+/// State and PKCE verifier of a login in progress
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingLogin {
+    state: String,
+    verifier: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.into()
+        })),
+    )
+}
+
+fn config(state: &AppState) -> Result<&SessionConfig, ApiError> {
+    state
+        .sessions
+        .as_ref()
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Sessions are not enabled"))
+}
+
+/// Random URL-safe token used for session ids, login state and PKCE verifiers
+fn random_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// S256 PKCE code challenge of a verifier (RFC 7636)
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// Sessions are stored under a hash so a database leak doesn't expose live ids
+fn hash_session_id(id: &str) -> String {
+    hex::encode(Sha256::digest(id.as_bytes()))
+}
+
+/// CSRF token bound to a session id (signed double-submit).
+///
+/// Only someone holding the session id can compute it, so a cross-site request
+/// carrying the session cookie can't produce a matching header.
+pub fn csrf_token(session_id: &str) -> String {
+    hex::encode(Sha256::digest(format!("peerlab-csrf:{}", session_id)))
+}
+
+/// Methods that can change state and therefore need a CSRF token
+fn is_mutating(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Check the CSRF header of a mutating request against its session
+fn check_csrf(method: &Method, headers: &HeaderMap, session_id: &str) -> bool {
+    if !is_mutating(method) {
+        return true;
+    }
+    headers
+        .get(CSRF_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|token| token == csrf_token(session_id))
+}
+
+/// Resolve the identity of a session cookie, if the request carries a live one.
+///
+/// Mutating requests must also carry the session's CSRF token.
+pub async fn authenticate(
+    state: &AppState,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Option<AuthInfo>, AuthorizationError> {
+    let Some(config) = state.sessions.as_ref() else {
+        return Ok(None);
+    };
+    let jar = PrivateCookieJar::from_headers(headers, config.key.clone());
+    let Some(cookie) = jar.get(SESSION_COOKIE) else {
+        return Ok(None);
+    };
+
+    if !check_csrf(method, headers, cookie.value()) {
+        warn!(
+            "Rejecting {} request with a missing or invalid CSRF token",
+            method
+        );
+        return Err(AuthorizationError::new(
+            AuthErrorReason::Forbidden,
+            "Missing or invalid CSRF token",
+        ));
+    }
+
+    let auth_info = match state
+        .database
+        .get_session(&hash_session_id(cookie.value()))
+        .await
+    {
+        Ok(Some(auth_info)) => auth_info,
+        Ok(None) => {
+            debug!("Session cookie refers to an unknown or expired session");
+            return Ok(None);
+        }
+        Err(err) => {
+            error!("Failed to load session: {}", err);
+            return Err(AuthorizationError::new(
+                AuthErrorReason::IdpUnavailable,
+                "Failed to load session",
+            ));
+        }
+    };
+
+    match serde_json::from_str(&auth_info) {
+        Ok(auth_info) => Ok(Some(auth_info)),
+        Err(err) => {
+            warn!("Discarding unreadable session: {}", err);
+            Ok(None)
+        }
+    }
+}
+
+/// Start the OIDC authorization code flow
+pub async fn login(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let config = match config(&state) {
+        Ok(config) => config,
+        Err(err) => return err.into_response(),
+    };
+
+    let pending = PendingLogin {
+        state: random_token(),
+        verifier: random_token(),
+    };
+
+    let mut params = vec![
+        ("response_type", "code"),
+        ("client_id", config.client_id.as_str()),
+        ("redirect_uri", config.redirect_uri.as_str()),
+        ("scope", "openid profile email"),
+        ("state", pending.state.as_str()),
+        ("code_challenge_method", "S256"),
+    ];
+    let challenge = pkce_challenge(&pending.verifier);
+    params.push(("code_challenge", challenge.as_str()));
+    if let Some(audience) = state.auth0_audience.as_deref() {
+        params.push(("audience", audience));
+    }
+
+    let url = match reqwest::Url::parse_with_params(&config.authorize_url, &params) {
+        Ok(url) => url,
+        Err(err) => {
+            error!("Failed to build authorization URL: {}", err);
+            return api_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to start login")
+                .into_response();
+        }
+    };
+
+    let jar = PrivateCookieJar::from_headers(&headers, config.key.clone()).add(config.cookie(
+        LOGIN_COOKIE,
+        json!(pending).to_string(),
+        LOGIN_COOKIE_PATH,
+    ));
+
+    (jar, Redirect::to(url.as_str())).into_response()
+}
+
+/// Complete the code flow and open a session
+pub async fn callback(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<CallbackQuery>,
+) -> Response {
+    let config = match config(&state) {
+        Ok(config) => config,
+        Err(err) => return err.into_response(),
+    };
+    let jar = PrivateCookieJar::from_headers(&headers, config.key.clone());
+
+    if let Some(error) = query.error {
+        warn!("Identity provider returned an error: {}", error);
+        return api_error(StatusCode::UNAUTHORIZED, "Login was not completed").into_response();
+    }
+
+    let pending: Option<PendingLogin> = jar
+        .get(LOGIN_COOKIE)
+        .and_then(|cookie| serde_json::from_str(cookie.value()).ok());
+    let jar = jar.remove(config.removal(LOGIN_COOKIE, LOGIN_COOKIE_PATH));
+
+    let (Some(pending), Some(code)) = (pending, query.code) else {
+        return (
+            jar,
+            api_error(StatusCode::BAD_REQUEST, "No login in progress"),
+        )
+            .into_response();
+    };
+    if query.state.as_deref() != Some(pending.state.as_str()) {
+        return (
+            jar,
+            api_error(StatusCode::BAD_REQUEST, "Login state mismatch"),
+        )
+            .into_response();
+    }
+
+    let access_token = match exchange_code(config, &code, &pending.verifier).await {
+        Ok(token) => token,
+        Err(err) => {
+            error!("Failed to exchange authorization code: {}", err);
+            return (
+                jar,
+                api_error(StatusCode::BAD_GATEWAY, "Failed to complete login"),
+            )
+                .into_response();
+        }
+    };
+
+    // Sessions carry the same identity a bearer token would
+    let auth_info = match jwt::authenticate_token(&state, &access_token).await {
+        Ok(auth_info) => auth_info,
+        Err(err) => return (jar, err).into_response(),
+    };
+
+    let session_id = random_token();
+    let expires_at = Utc::now() + config.ttl;
+    if let Err(err) = state
+        .database
+        .create_session(
+            &hash_session_id(&session_id),
+            &json!(auth_info).to_string(),
+            expires_at,
+        )
+        .await
+    {
+        error!("Failed to create session: {}", err);
+        return (
+            jar,
+            api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to create session",
+            ),
+        )
+            .into_response();
+    }
+
+    info!("Opened session for {} until {}", auth_info.sub, expires_at);
+    let csrf = CookieJar::from_headers(&headers).add(config.csrf_cookie(csrf_token(&session_id)));
+    let jar = jar.add(config.cookie(SESSION_COOKIE, session_id, SESSION_COOKIE_PATH));
+    (jar, csrf, Redirect::to(&config.post_login_redirect)).into_response()
+}
+
+/// Close the caller's session
+pub async fn logout(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let config = match config(&state) {
+        Ok(config) => config,
+        Err(err) => return err.into_response(),
+    };
+    let jar = PrivateCookieJar::from_headers(&headers, config.key.clone());
+    let session = jar.get(SESSION_COOKIE);
+
+    if let Some(ref cookie) = session
+        && !check_csrf(&Method::POST, &headers, cookie.value())
+    {
+        return api_error(StatusCode::FORBIDDEN, "Missing or invalid CSRF token").into_response();
+    }
+
+    if let Some(cookie) = session
+        && let Err(err) = state
+            .database
+            .delete_session(&hash_session_id(cookie.value()))
+            .await
+    {
+        error!("Failed to delete session: {}", err);
+        return api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to delete session",
+        )
+        .into_response();
+    }
+
+    let jar = jar.remove(config.removal(SESSION_COOKIE, SESSION_COOKIE_PATH));
+    let csrf =
+        CookieJar::from_headers(&headers).remove(config.removal(CSRF_COOKIE, CSRF_COOKIE_PATH));
+    (jar, csrf, StatusCode::NO_CONTENT).into_response()
+}
+
+/// Trade an authorization code for an access token at the token endpoint
+async fn exchange_code(
+    config: &SessionConfig,
+    code: &str,
+    verifier: &str,
+) -> Result<String, String> {
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let response = client
+        .post(&config.token_url)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", config.redirect_uri.as_str()),
+            ("client_id", config.client_id.as_str()),
+            ("client_secret", config.client_secret.as_str()),
+            ("code_verifier", verifier),
+        ])
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !response.status().is_success() {
+        return Err(format!("token endpoint returned {}", response.status()));
+    }
+
+    let token: TokenResponse = response.json().await.map_err(|e| e.to_string())?;
+    Ok(token.access_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> SessionConfig {
+        SessionConfig::new(
+            &"s".repeat(MIN_SECRET_LENGTH),
+            "client".to_string(),
+            "secret".to_string(),
+            "https://idp.example.com/authorize".to_string(),
+            "https://idp.example.com/oauth/token".to_string(),
+            "https://gateway.example.com/api/auth/callback".to_string(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pkce_challenge() {
+        // Example from RFC 7636 appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let config = config();
+        assert!(config.secure());
+        assert_eq!(config.ttl, Duration::hours(DEFAULT_SESSION_TTL_HOURS));
+
+        assert!(
+            SessionConfig::new(
+                "short",
+                "client".to_string(),
+                "secret".to_string(),
+                config.authorize_url.clone(),
+                config.token_url.clone(),
+                config.redirect_uri.clone(),
+            )
+            .is_err()
+        );
+        assert!(
+            SessionConfig::new(
+                &"s".repeat(MIN_SECRET_LENGTH),
+                "client".to_string(),
+                "secret".to_string(),
+                "not a url".to_string(),
+                config.token_url.clone(),
+                config.redirect_uri.clone(),
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_csrf_check() {
+        let mut headers = HeaderMap::new();
+        assert!(check_csrf(&Method::GET, &headers, "session"));
+        assert!(!check_csrf(&Method::POST, &headers, "session"));
+
+        headers.insert(CSRF_HEADER, csrf_token("other").parse().unwrap());
+        assert!(!check_csrf(&Method::DELETE, &headers, "session"));
+
+        headers.insert(CSRF_HEADER, csrf_token("session").parse().unwrap());
+        assert!(check_csrf(&Method::POST, &headers, "session"));
+        assert_ne!(csrf_token("session"), hash_session_id("session"));
+    }
+
+    #[test]
+    fn test_session_cookie_is_encrypted() {
+        let config = config();
+        let jar = PrivateCookieJar::new(config.key.clone()).add(config.cookie(
+            SESSION_COOKIE,
+            "abc".to_string(),
+            SESSION_COOKIE_PATH,
+        ));
+
+        let encrypted = (jar, ())
+            .into_response()
+            .headers()
+            .get("set-cookie")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(!encrypted.contains("=abc;"));
+        assert!(encrypted.contains("HttpOnly"));
+        assert!(encrypted.contains("Secure"));
+
+        let pair = encrypted.split(';').next().unwrap().to_string();
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", pair.parse().unwrap());
+        let jar = PrivateCookieJar::from_headers(&headers, config.key.clone());
+        assert_eq!(jar.get(SESSION_COOKIE).unwrap().value(), "abc");
+
+        // A different key can't read it
+        let other = Key::generate();
+        let jar = PrivateCookieJar::from_headers(&headers, other);
+        assert!(jar.get(SESSION_COOKIE).is_none());
+    }
+}
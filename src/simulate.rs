@@ -0,0 +1,402 @@
+//! Allocation planning by simulation.
+//!
+//! `POST /admin/simulate` plays a hypothetical wave of new users (e.g. the
+//! 200 students of a workshop) against a copy of the current assignments and
+//! leases. Each user gets an ASN and leases prefixes the way the allocators
+//! would pick them, grace period included, and the report tells whether the
+//! pools run out and when. Nothing is written to the database.
+
+use axum::{Json, extract::State, http::StatusCode};
+use chrono::{DateTime, Duration, SubsecRound, Utc};
+use ipnet::Ipv6Net;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::HashSet;
+use tracing::error;
+
+use crate::pool_asns::AsnPool;
+use crate::pool_prefixes::{PrefixClass, PrefixPool};
+use crate::{AppState, MAX_PREFIX_COUNT};
+
+/// Most users a simulation plays
+const MAX_USERS: u32 = 10_000;
+
+/// Longest window users may arrive over
+const MAX_ARRIVAL_MINUTES: i64 = 30 * 24 * 60;
+
+#[derive(Deserialize)]
+pub struct SimulateRequest {
+    /// Number of new users
+    users: u32,
+    /// Lease durations in minutes, taken by the users in turn
+    lease_minutes: Vec<i64>,
+    /// Prefixes each user leases at once
+    #[serde(default = "default_leases_per_user")]
+    leases_per_user: usize,
+    /// Users arrive evenly over this many minutes (all at once by default)
+    #[serde(default)]
+    arrival_minutes: i64,
+    /// When the first user arrives (now when omitted)
+    #[serde(default)]
+    start: Option<DateTime<Utc>>,
+    /// Routability class of the leased prefixes (any class when omitted)
+    #[serde(default)]
+    class: Option<PrefixClass>,
+}
+
+fn default_leases_per_user() -> usize {
+    1
+}
+
+/// A hypothetical wave of new users
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    pub users: u32,
+    pub durations: Vec<Duration>,
+    pub leases_per_user: usize,
+    pub start: DateTime<Utc>,
+    pub arrival: Duration,
+    pub class: Option<PrefixClass>,
+    pub grace: Duration,
+}
+
+impl Scenario {
+    /// When the user of index `user` arrives
+    fn arrival_of(&self, user: u32) -> DateTime<Utc> {
+        self.start + self.arrival * user as i32 / self.users as i32
+    }
+}
+
+/// Allocations from a pool during a simulation
+#[derive(Debug, Default, PartialEq)]
+pub struct PoolOutcome {
+    /// Free ASNs or prefixes when the first user arrives
+    pub available: usize,
+    /// ASNs assigned or prefixes leased to the new users
+    pub allocated: usize,
+    /// Users the pool had nothing left for
+    pub refused_users: u32,
+    /// Arrival of the first user the pool had nothing left for
+    pub exhausted_at: Option<DateTime<Utc>>,
+}
+
+impl PoolOutcome {
+    fn refuse(&mut self, at: DateTime<Utc>) {
+        self.refused_users += 1;
+        self.exhausted_at.get_or_insert(at);
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "available": self.available,
+            "allocated": self.allocated,
+            "refused_users": self.refused_users,
+            "exhausted_at": self.exhausted_at.map(|at| at.to_rfc3339()),
+        })
+    }
+}
+
+/// Outcome of a simulation
+#[derive(Debug, PartialEq)]
+pub struct Outcome {
+    /// Users who got an ASN and all their prefixes
+    pub served_users: u32,
+    pub asn: PoolOutcome,
+    pub prefix: PoolOutcome,
+}
+
+/// A prefix held from `start` to `end`
+#[derive(Debug, Clone)]
+pub struct Holding {
+    pub prefix: Ipv6Net,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// Play a scenario against the assigned ASNs and the leases overlapping it.
+/// Users arrive in turn; a user without an ASN leases nothing, and a user
+/// gets all their prefixes or none, like a bulk lease.
+pub fn run(
+    scenario: &Scenario,
+    asn_pool: &AsnPool,
+    prefix_pool: &PrefixPool,
+    assigned_asns: &HashSet<i64>,
+    mut holdings: Vec<Holding>,
+) -> Outcome {
+    let grace = scenario.grace;
+    let leased_during = |holdings: &[Holding], start, end| -> Vec<Ipv6Net> {
+        holdings
+            .iter()
+            .filter(|holding| holding.end + grace > start && holding.start < end + grace)
+            .map(|holding| holding.prefix)
+            .collect()
+    };
+
+    // ASNs are assigned in pool order, skipping those already assigned
+    let mut free_asns = asn_pool
+        .ranges()
+        .iter()
+        .flat_map(|&(start, end)| start..=end)
+        .filter(|asn| !assigned_asns.contains(asn));
+
+    let mut outcome = Outcome {
+        served_users: 0,
+        asn: PoolOutcome {
+            available: free_asns.clone().count(),
+            ..Default::default()
+        },
+        prefix: PoolOutcome {
+            available: prefix_pool.count_available(
+                &leased_during(&holdings, scenario.start, scenario.start),
+                scenario.class,
+            ),
+            ..Default::default()
+        },
+    };
+
+    for user in 0..scenario.users {
+        let arrival = scenario.arrival_of(user);
+        if free_asns.next().is_none() {
+            outcome.asn.refuse(arrival);
+            continue;
+        }
+        outcome.asn.allocated += 1;
+
+        let duration = scenario.durations[user as usize % scenario.durations.len()];
+        let end = arrival + duration;
+        let mut leased = leased_during(&holdings, arrival, end);
+        let mut selected = Vec::with_capacity(scenario.leases_per_user);
+        for _ in 0..scenario.leases_per_user {
+            match prefix_pool.find_available_prefix(&leased, scenario.class, None) {
+                Some(prefix) => {
+                    leased.push(prefix);
+                    selected.push(prefix);
+                }
+                None => break,
+            }
+        }
+        if selected.len() < scenario.leases_per_user {
+            outcome.prefix.refuse(arrival);
+            continue;
+        }
+        outcome.prefix.allocated += selected.len();
+        outcome.served_users += 1;
+        holdings.extend(selected.into_iter().map(|prefix| Holding {
+            prefix,
+            start: arrival,
+            end,
+        }));
+    }
+    outcome
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn bad_request(message: String) -> ApiError {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "error": 400,
+            "message": message
+        })),
+    )
+}
+
+/// Check a simulation request against the limits clients are held to
+fn scenario(state: &AppState, request: SimulateRequest) -> Result<Scenario, String> {
+    if !(1..=MAX_USERS).contains(&request.users) {
+        return Err(format!("users must be between 1 and {}", MAX_USERS));
+    }
+    if request.lease_minutes.is_empty() {
+        return Err("lease_minutes must list at least one duration".to_string());
+    }
+    let (min, max) = (
+        state.lease_min_duration.num_minutes(),
+        state.lease_max_duration.num_minutes(),
+    );
+    if let Some(minutes) = request
+        .lease_minutes
+        .iter()
+        .find(|minutes| !(min..=max).contains(*minutes))
+    {
+        return Err(format!(
+            "Lease duration of {} minutes is outside the allowed {} to {} minutes",
+            minutes, min, max
+        ));
+    }
+    if !(1..=MAX_PREFIX_COUNT).contains(&request.leases_per_user) {
+        return Err(format!(
+            "leases_per_user must be between 1 and {}",
+            MAX_PREFIX_COUNT
+        ));
+    }
+    if let Some(max_leases) = state.max_leases_per_user
+        && request.leases_per_user > max_leases as usize
+    {
+        return Err(format!(
+            "leases_per_user exceeds the lease quota ({} per user)",
+            max_leases
+        ));
+    }
+    if !(0..=MAX_ARRIVAL_MINUTES).contains(&request.arrival_minutes) {
+        return Err(format!(
+            "arrival_minutes must be between 0 and {}",
+            MAX_ARRIVAL_MINUTES
+        ));
+    }
+    Ok(Scenario {
+        users: request.users,
+        durations: request
+            .lease_minutes
+            .into_iter()
+            .map(Duration::minutes)
+            .collect(),
+        leases_per_user: request.leases_per_user,
+        start: request
+            .start
+            .map_or_else(Utc::now, |start| start.max(Utc::now()))
+            .trunc_subsecs(0),
+        arrival: Duration::minutes(request.arrival_minutes),
+        class: request.class,
+        grace: state.lease_grace_period,
+    })
+}
+
+/// Simulate a wave of new users and report whether and when the pools run
+/// out (admin API)
+pub async fn simulate(
+    State(state): State<AppState>,
+    Json(request): Json<SimulateRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let scenario = scenario(&state, request).map_err(bad_request)?;
+
+    // Only leases overlapping the simulated window (and its grace periods) matter
+    let longest = scenario.durations.iter().max().copied().unwrap_or_default();
+    let until = scenario.start + scenario.arrival + longest + scenario.grace;
+    let (mappings, leases) = tokio::try_join!(
+        state.database.get_all_asn_mappings(),
+        state
+            .database
+            .get_leases_during(scenario.start - scenario.grace, until),
+    )
+    .map_err(|err| {
+        error!("Failed to load allocations to simulate: {}", err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({
+                "error": 500,
+                "message": "Failed to run simulation"
+            })),
+        )
+    })?;
+
+    let assigned: HashSet<i64> = mappings.iter().map(|mapping| mapping.asn).collect();
+    let holdings = leases
+        .iter()
+        .filter_map(|lease| {
+            Some(Holding {
+                prefix: lease.prefix.parse().ok()?,
+                start: lease.start_time,
+                end: lease.end_time,
+            })
+        })
+        .collect();
+    let outcome = run(
+        &scenario,
+        &state.asn_pool.load(),
+        &state.prefix_pool.load(),
+        &assigned,
+        holdings,
+    );
+
+    Ok(Json(json!({
+        "users": scenario.users,
+        "start": scenario.start.to_rfc3339(),
+        "exhausted": outcome.asn.exhausted_at.is_some() || outcome.prefix.exhausted_at.is_some(),
+        "served_users": outcome.served_users,
+        "asn": outcome.asn.to_json(),
+        "prefix": outcome.prefix.to_json(),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scenario(users: u32, durations: &[i64], arrival_minutes: i64) -> Scenario {
+        Scenario {
+            users,
+            durations: durations.iter().copied().map(Duration::minutes).collect(),
+            leases_per_user: 1,
+            start: "2025-06-01T09:00:00Z".parse().unwrap(),
+            arrival: Duration::minutes(arrival_minutes),
+            class: None,
+            grace: Duration::zero(),
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let asn_pool = AsnPool::new(65000, 65009);
+        let prefix_pool = PrefixPool::new(
+            (1..=4)
+                .map(|i| format!("2001:db8:{}::/48", i).parse().unwrap())
+                .collect(),
+        );
+        let assigned = HashSet::from([65000, 65001]);
+        let held = |prefix: &str, start: &str, end: &str| Holding {
+            prefix: prefix.parse().unwrap(),
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+        };
+        let holdings = vec![held(
+            "2001:db8:1::/48",
+            "2025-06-01T08:00:00Z",
+            "2025-06-01T10:00:00Z",
+        )];
+
+        // Everyone at once: 3 free prefixes for 5 users
+        let outcome = run(
+            &scenario(5, &[60], 0),
+            &asn_pool,
+            &prefix_pool,
+            &assigned,
+            holdings.clone(),
+        );
+        assert_eq!(outcome.served_users, 3);
+        assert_eq!(outcome.asn.available, 8);
+        assert_eq!(outcome.asn.allocated, 5);
+        assert_eq!(outcome.prefix.available, 3);
+        assert_eq!(outcome.prefix.refused_users, 2);
+        assert_eq!(
+            outcome.prefix.exhausted_at,
+            Some("2025-06-01T09:00:00Z".parse().unwrap())
+        );
+
+        // Arriving over 4 hours with 1 hour leases, prefixes are freed in time
+        let outcome = run(
+            &scenario(8, &[60], 240),
+            &asn_pool,
+            &prefix_pool,
+            &assigned,
+            holdings.clone(),
+        );
+        assert_eq!(outcome.served_users, 8);
+        assert_eq!(outcome.prefix.exhausted_at, None);
+
+        // The ASN pool runs out before the prefixes do
+        let outcome = run(
+            &scenario(10, &[15, 30], 600),
+            &asn_pool,
+            &prefix_pool,
+            &assigned,
+            holdings,
+        );
+        assert_eq!(outcome.served_users, 8);
+        assert_eq!(outcome.asn.refused_users, 2);
+        assert_eq!(
+            outcome.asn.exhausted_at,
+            Some("2025-06-01T17:00:00Z".parse().unwrap())
+        );
+    }
+}
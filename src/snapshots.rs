@@ -0,0 +1,104 @@
+//! Compressed snapshots of the full mappings.
+//!
+//! New agents bootstrap from the latest snapshot in storage instead of
+//! paging through the API. A snapshot is the `/service/mappings` JSON
+//! (without emails), zstd-compressed, next to a manifest with its checksum.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::Path;
+
+use crate::AppState;
+
+/// Name of the compressed mappings file
+pub const SNAPSHOT_FILE: &str = "mappings.json.zst";
+
+/// Name of the manifest describing the latest snapshot
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Describes a snapshot so agents can verify what they downloaded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub file: String,
+    pub generated_at: DateTime<Utc>,
+    /// Number of user mappings in the snapshot
+    pub mappings: usize,
+    /// Size of the compressed file in bytes
+    pub size: usize,
+    /// Hex SHA-256 of the compressed file
+    pub sha256: String,
+}
+
+/// A compressed snapshot and its manifest
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub data: Vec<u8>,
+    pub manifest: Manifest,
+}
+
+/// Compress serialized mappings into a snapshot
+pub fn compress(json: &[u8], mappings: usize, generated_at: DateTime<Utc>) -> io::Result<Snapshot> {
+    let data = zstd::encode_all(json, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    let manifest = Manifest {
+        file: SNAPSHOT_FILE.to_string(),
+        generated_at,
+        mappings,
+        size: data.len(),
+        sha256: hex::encode(Sha256::digest(&data)),
+    };
+    Ok(Snapshot { data, manifest })
+}
+
+/// Snapshot the current mappings
+pub async fn generate(state: &AppState) -> Result<Snapshot, String> {
+    let mappings = crate::load_all_mappings(state.clone(), Default::default(), None, false)
+        .await
+        .map_err(|e| format!("Failed to load mappings: {}", e))?;
+    let json = serde_json::to_vec(&mappings)
+        .map_err(|e| format!("Failed to serialize mappings: {}", e))?;
+    compress(&json, mappings.mappings.len(), Utc::now())
+        .map_err(|e| format!("Failed to compress snapshot: {}", e))
+}
+
+/// Write a snapshot to a directory, then its manifest.
+///
+/// Files are renamed into place, so readers never see a partial file and a
+/// manifest never describes a snapshot that isn't there yet.
+pub async fn write(snapshot: &Snapshot, dir: &Path) -> io::Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    let manifest = serde_json::to_vec_pretty(&snapshot.manifest)?;
+    for (name, contents) in [
+        (SNAPSHOT_FILE, snapshot.data.as_slice()),
+        (MANIFEST_FILE, manifest.as_slice()),
+    ] {
+        let tmp = dir.join(format!(".{}.tmp", name));
+        tokio::fs::write(&tmp, contents).await?;
+        tokio::fs::rename(&tmp, dir.join(name)).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_snapshot() {
+        let json = br#"{"mappings":[{"user_hash":"abc","asn":65001}]}"#;
+        let snapshot = compress(json, 1, Utc::now()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        write(&snapshot, dir.path()).await.unwrap();
+
+        let manifest: Manifest =
+            serde_json::from_slice(&std::fs::read(dir.path().join(MANIFEST_FILE)).unwrap())
+                .unwrap();
+        assert_eq!(manifest, snapshot.manifest);
+
+        let data = std::fs::read(dir.path().join(SNAPSHOT_FILE)).unwrap();
+        assert_eq!(data.len(), manifest.size);
+        assert_eq!(hex::encode(Sha256::digest(&data)), manifest.sha256);
+        assert_eq!(zstd::decode_all(data.as_slice()).unwrap(), json);
+    }
+}
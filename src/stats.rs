@@ -0,0 +1,172 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::AppState;
+use crate::database::PoolUsageSnapshot;
+
+/// Default window of usage history used for forecasting
+pub const DEFAULT_FORECAST_WINDOW_DAYS: i64 = 30;
+
+/// A pool usage measurement at a point in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UsageSample {
+    pub at: DateTime<Utc>,
+    pub used: i64,
+}
+
+/// Exhaustion forecast for a single pool
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PoolForecast {
+    pub pool: &'static str,
+    pub capacity: i64,
+    pub used: i64,
+    pub utilization: f64,
+    /// Net allocations per day over the forecast window
+    pub rate_per_day: Option<f64>,
+    /// Days until the pool is exhausted at the current rate (None if not growing)
+    pub days_until_exhaustion: Option<f64>,
+}
+
+/// Forecast pool exhaustion from usage samples (oldest first) with a
+/// least-squares fit of usage over time. The last sample is the current usage.
+pub fn forecast(pool: &'static str, capacity: i64, samples: &[UsageSample]) -> PoolForecast {
+    let used = samples.last().map(|s| s.used).unwrap_or(0);
+    let utilization = if capacity > 0 {
+        used as f64 / capacity as f64
+    } else {
+        1.0
+    };
+
+    let rate_per_day = usage_rate_per_day(samples);
+    let days_until_exhaustion = match rate_per_day {
+        _ if used >= capacity => Some(0.0),
+        Some(rate) if rate > 0.0 => Some((capacity - used) as f64 / rate),
+        _ => None,
+    };
+
+    PoolForecast {
+        pool,
+        capacity,
+        used,
+        utilization,
+        rate_per_day,
+        days_until_exhaustion,
+    }
+}
+
+/// Slope of usage over time, in allocations per day
+fn usage_rate_per_day(samples: &[UsageSample]) -> Option<f64> {
+    let first = samples.first()?;
+    let points: Vec<(f64, f64)> = samples
+        .iter()
+        .map(|s| {
+            let days = (s.at - first.at).num_seconds() as f64 / 86_400.0;
+            (days, s.used as f64)
+        })
+        .collect();
+
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    if variance == 0.0 {
+        return None;
+    }
+
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    Some(covariance / variance)
+}
+
+/// Forecast exhaustion of the ASN and prefix pools over the last `window_days`
+pub async fn pool_forecasts(
+    state: &AppState,
+    window_days: i64,
+) -> Result<Vec<PoolForecast>, sqlx::Error> {
+    let now = Utc::now();
+    let snapshots = state
+        .database
+        .get_pool_usage_since(now - Duration::days(window_days))
+        .await?;
+    let (asns_assigned, prefixes_leased) = state.database.get_pool_usage().await?;
+
+    let samples = |current: i64, pick: fn(&PoolUsageSnapshot) -> i32| {
+        let mut samples: Vec<UsageSample> = snapshots
+            .iter()
+            .map(|s| UsageSample {
+                at: s.recorded_at,
+                used: pick(s) as i64,
+            })
+            .collect();
+        samples.push(UsageSample {
+            at: now,
+            used: current,
+        });
+        samples
+    };
+
+    Ok(vec![
+        forecast(
+            "asn",
+            state.asn_pool.load().size(),
+            &samples(asns_assigned, |s| s.asns_assigned),
+        ),
+        forecast(
+            "prefix",
+            state.prefix_pool.load().len() as i64,
+            &samples(prefixes_leased, |s| s.prefixes_leased),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(days_ago: i64, used: i64) -> UsageSample {
+        let now = DateTime::parse_from_rfc3339("2025-06-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        UsageSample {
+            at: now - Duration::days(days_ago),
+            used,
+        }
+    }
+
+    #[test]
+    fn test_forecast_linear_growth() {
+        let samples = vec![sample(10, 0), sample(5, 50), sample(0, 100)];
+        let forecast = forecast("asn", 1000, &samples);
+
+        assert_eq!(forecast.used, 100);
+        assert!((forecast.utilization - 0.1).abs() < 1e-9);
+        assert!((forecast.rate_per_day.unwrap() - 10.0).abs() < 1e-9);
+        assert!((forecast.days_until_exhaustion.unwrap() - 90.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_forecast_without_growth() {
+        let flat = forecast("prefix", 10, &[sample(2, 5), sample(0, 5)]);
+        assert_eq!(flat.rate_per_day, Some(0.0));
+        assert_eq!(flat.days_until_exhaustion, None);
+
+        let shrinking = forecast("prefix", 10, &[sample(2, 8), sample(0, 4)]);
+        assert_eq!(shrinking.days_until_exhaustion, None);
+    }
+
+    #[test]
+    fn test_forecast_single_sample() {
+        let forecast = forecast("asn", 10, &[sample(0, 3)]);
+        assert_eq!(forecast.rate_per_day, None);
+        assert_eq!(forecast.days_until_exhaustion, None);
+    }
+
+    #[test]
+    fn test_forecast_exhausted_pool() {
+        let forecast = forecast("prefix", 4, &[sample(1, 4), sample(0, 4)]);
+        assert_eq!(forecast.days_until_exhaustion, Some(0.0));
+        assert!((forecast.utilization - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,586 @@
+//! Storage behind the user and service mapping endpoints.
+//!
+//! `/api/user/info`, `/api/user/asn(s)` and `/service/mappings` read and
+//! write through [`Store`] rather than the database directly, so embedders
+//! and tests can serve them from [`MemoryStore`] without a Postgres server.
+//! Everything else still goes to [`Database`], which is the default store.
+
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use std::sync::{Arc, Mutex};
+use std::{cmp, fmt};
+use uuid::Uuid;
+
+use crate::database::{
+    Database, MappingAnnotation, MappingFilter, MappingPermission, MappingSort,
+    PERMISSION_SOURCE_ASN, PERMISSION_SOURCE_LEASE, PrefixLease, SortOrder, UserAsnMapping,
+    UserInfo,
+};
+use crate::types::{Asn, UserHash};
+
+/// Storage calls of the user and service mapping endpoints, as on [`Database`]
+pub trait Store: Send + Sync {
+    /// ASNs (first assigned first) and active leases of a user
+    fn get_user_info<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+    ) -> BoxFuture<'a, Result<Option<UserInfo>, sqlx::Error>>;
+
+    /// Leases of a user starting in the future
+    fn get_upcoming_user_leases<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+    ) -> BoxFuture<'a, Result<Vec<PrefixLease>, sqlx::Error>>;
+
+    /// Leases of a user that ended since `since`
+    fn get_user_leases_ended_since<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<PrefixLease>, sqlx::Error>>;
+
+    /// ASN mappings of a user, first assigned first
+    fn get_user_asns<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+    ) -> BoxFuture<'a, Result<Vec<UserAsnMapping>, sqlx::Error>>;
+
+    /// Mapping of an ASN, if assigned
+    fn get_asn_mapping(
+        &self,
+        asn: Asn,
+    ) -> BoxFuture<'_, Result<Option<UserAsnMapping>, sqlx::Error>>;
+
+    /// Every ASN mapping, latest assigned first
+    fn get_all_asn_mappings(&self) -> BoxFuture<'_, Result<Vec<UserAsnMapping>, sqlx::Error>>;
+
+    /// Assign an ASN to a user, unless the user already holds `max` ASNs
+    fn assign_user_asn<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+        user_id: Option<&'a str>,
+        asn: Asn,
+        max: i64,
+    ) -> BoxFuture<'a, Result<Option<UserAsnMapping>, sqlx::Error>>;
+
+    /// Number of assigned ASNs and of active leases
+    fn get_pool_usage(&self) -> BoxFuture<'_, Result<(i64, i64), sqlx::Error>>;
+
+    /// Rows of the read model the service mappings are built from
+    fn get_mapping_permissions<'a>(
+        &'a self,
+        user_hash: Option<&'a UserHash>,
+        filter: &'a MappingFilter,
+    ) -> BoxFuture<'a, Result<Vec<MappingPermission>, sqlx::Error>>;
+
+    /// Annotations of mappings
+    fn get_mapping_annotations<'a>(
+        &'a self,
+        mapping_ids: &'a [Uuid],
+    ) -> BoxFuture<'a, Result<Vec<MappingAnnotation>, sqlx::Error>>;
+}
+
+impl Store for Database {
+    fn get_user_info<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+    ) -> BoxFuture<'a, Result<Option<UserInfo>, sqlx::Error>> {
+        Box::pin(self.get_user_info(user_hash))
+    }
+
+    fn get_upcoming_user_leases<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+    ) -> BoxFuture<'a, Result<Vec<PrefixLease>, sqlx::Error>> {
+        Box::pin(self.get_upcoming_user_leases(user_hash))
+    }
+
+    fn get_user_leases_ended_since<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<PrefixLease>, sqlx::Error>> {
+        Box::pin(self.get_user_leases_ended_since(user_hash, since))
+    }
+
+    fn get_user_asns<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+    ) -> BoxFuture<'a, Result<Vec<UserAsnMapping>, sqlx::Error>> {
+        Box::pin(self.get_user_asns(user_hash))
+    }
+
+    fn get_asn_mapping(
+        &self,
+        asn: Asn,
+    ) -> BoxFuture<'_, Result<Option<UserAsnMapping>, sqlx::Error>> {
+        Box::pin(self.get_asn_mapping(asn))
+    }
+
+    fn get_all_asn_mappings(&self) -> BoxFuture<'_, Result<Vec<UserAsnMapping>, sqlx::Error>> {
+        Box::pin(self.get_all_asn_mappings())
+    }
+
+    fn assign_user_asn<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+        user_id: Option<&'a str>,
+        asn: Asn,
+        max: i64,
+    ) -> BoxFuture<'a, Result<Option<UserAsnMapping>, sqlx::Error>> {
+        Box::pin(self.assign_user_asn(user_hash, user_id, asn, max))
+    }
+
+    fn get_pool_usage(&self) -> BoxFuture<'_, Result<(i64, i64), sqlx::Error>> {
+        Box::pin(self.get_pool_usage())
+    }
+
+    fn get_mapping_permissions<'a>(
+        &'a self,
+        user_hash: Option<&'a UserHash>,
+        filter: &'a MappingFilter,
+    ) -> BoxFuture<'a, Result<Vec<MappingPermission>, sqlx::Error>> {
+        Box::pin(self.get_mapping_permissions(user_hash, filter))
+    }
+
+    fn get_mapping_annotations<'a>(
+        &'a self,
+        mapping_ids: &'a [Uuid],
+    ) -> BoxFuture<'a, Result<Vec<MappingAnnotation>, sqlx::Error>> {
+        Box::pin(self.get_mapping_annotations(mapping_ids))
+    }
+}
+
+/// Store keeping mappings, leases and annotations in memory, for tests and
+/// embedders without a database. Clones share the same data.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    data: Arc<Mutex<MemoryData>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryData {
+    mappings: Vec<UserAsnMapping>,
+    leases: Vec<PrefixLease>,
+    annotations: Vec<MappingAnnotation>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an ASN mapping
+    pub fn with_mapping(self, mapping: UserAsnMapping) -> Self {
+        self.data.lock().unwrap().mappings.push(mapping);
+        self
+    }
+
+    /// Add a lease
+    pub fn with_lease(self, lease: PrefixLease) -> Self {
+        self.data.lock().unwrap().leases.push(lease);
+        self
+    }
+
+    /// Add an annotation of a mapping
+    pub fn with_annotation(self, annotation: MappingAnnotation) -> Self {
+        self.data.lock().unwrap().annotations.push(annotation);
+        self
+    }
+
+    fn user_asns(&self, user_hash: &UserHash) -> Vec<UserAsnMapping> {
+        let mut mappings: Vec<UserAsnMapping> = self
+            .data
+            .lock()
+            .unwrap()
+            .mappings
+            .iter()
+            .filter(|m| &m.user_hash == user_hash)
+            .cloned()
+            .collect();
+        mappings.sort_by_key(|m| (m.created_at, m.id));
+        mappings
+    }
+
+    /// Leases of a user matching `keep`, latest ending first
+    fn user_leases(
+        &self,
+        user_hash: &UserHash,
+        keep: impl Fn(&PrefixLease) -> bool,
+    ) -> Vec<PrefixLease> {
+        let mut leases: Vec<PrefixLease> = self
+            .data
+            .lock()
+            .unwrap()
+            .leases
+            .iter()
+            .filter(|l| &l.user_hash == user_hash && keep(l))
+            .cloned()
+            .collect();
+        leases.sort_by_key(|l| std::cmp::Reverse(l.end_time));
+        leases
+    }
+}
+
+fn is_active(lease: &PrefixLease, now: DateTime<Utc>) -> bool {
+    lease.start_time <= now && now < lease.end_time
+}
+
+/// Rows of a user as `refresh_announcement_permissions` builds them: one per
+/// ASN, and one per active lease and ASN (a single one without an ASN)
+fn permission_rows(
+    mappings: &[&UserAsnMapping],
+    leases: &[&PrefixLease],
+) -> Vec<MappingPermission> {
+    let asn_rows = mappings.iter().map(|m| MappingPermission {
+        user_hash: m.user_hash.clone(),
+        source: PERMISSION_SOURCE_ASN.to_string(),
+        source_id: m.id,
+        prefix: None,
+        origin_asn: Some(m.asn),
+        asn_assigned_at: Some(m.created_at),
+        holder_id: m.user_id.clone(),
+        description: m.description.clone(),
+        abuse_contact: m.abuse_contact.clone(),
+        label: None,
+        purpose: None,
+        sites: None,
+        valid_to: None,
+        updated_at: m.updated_at,
+    });
+    let origins: Vec<Option<&UserAsnMapping>> = if mappings.is_empty() {
+        vec![None]
+    } else {
+        mappings.iter().copied().map(Some).collect()
+    };
+    let lease_rows = leases.iter().flat_map(|l| {
+        origins.iter().map(move |m| MappingPermission {
+            user_hash: l.user_hash.clone(),
+            source: PERMISSION_SOURCE_LEASE.to_string(),
+            source_id: l.id,
+            prefix: Some(l.prefix.clone()),
+            origin_asn: m.map(|m| m.asn),
+            asn_assigned_at: m.map(|m| m.created_at),
+            holder_id: None,
+            description: None,
+            abuse_contact: None,
+            label: l.label.clone(),
+            purpose: l.purpose.clone(),
+            sites: l.sites.clone(),
+            valid_to: Some(l.end_time),
+            updated_at: l.updated_at,
+        })
+    });
+    asn_rows.chain(lease_rows).collect()
+}
+
+impl Store for MemoryStore {
+    fn get_user_info<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+    ) -> BoxFuture<'a, Result<Option<UserInfo>, sqlx::Error>> {
+        let now = Utc::now();
+        let info = (
+            self.user_asns(user_hash),
+            self.user_leases(user_hash, |l| is_active(l, now)),
+        );
+        Box::pin(async move { Ok(Some(info)) })
+    }
+
+    fn get_upcoming_user_leases<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+    ) -> BoxFuture<'a, Result<Vec<PrefixLease>, sqlx::Error>> {
+        let now = Utc::now();
+        let mut leases = self.user_leases(user_hash, |l| l.start_time > now);
+        leases.sort_by_key(|l| l.start_time);
+        Box::pin(async move { Ok(leases) })
+    }
+
+    fn get_user_leases_ended_since<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<PrefixLease>, sqlx::Error>> {
+        let now = Utc::now();
+        let leases = self.user_leases(user_hash, |l| since < l.end_time && l.end_time <= now);
+        Box::pin(async move { Ok(leases) })
+    }
+
+    fn get_user_asns<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+    ) -> BoxFuture<'a, Result<Vec<UserAsnMapping>, sqlx::Error>> {
+        let mappings = self.user_asns(user_hash);
+        Box::pin(async move { Ok(mappings) })
+    }
+
+    fn get_asn_mapping(
+        &self,
+        asn: Asn,
+    ) -> BoxFuture<'_, Result<Option<UserAsnMapping>, sqlx::Error>> {
+        let data = self.data.lock().unwrap();
+        let mapping = data.mappings.iter().find(|m| m.asn == asn.get()).cloned();
+        Box::pin(async move { Ok(mapping) })
+    }
+
+    fn get_all_asn_mappings(&self) -> BoxFuture<'_, Result<Vec<UserAsnMapping>, sqlx::Error>> {
+        let mut mappings = self.data.lock().unwrap().mappings.clone();
+        mappings.sort_by_key(|m| std::cmp::Reverse(m.created_at));
+        Box::pin(async move { Ok(mappings) })
+    }
+
+    fn assign_user_asn<'a>(
+        &'a self,
+        user_hash: &'a UserHash,
+        user_id: Option<&'a str>,
+        asn: Asn,
+        max: i64,
+    ) -> BoxFuture<'a, Result<Option<UserAsnMapping>, sqlx::Error>> {
+        let mut data = self.data.lock().unwrap();
+        let result = if data.mappings.iter().any(|m| m.asn == asn.get()) {
+            Err(sqlx::Error::Database(Box::new(AsnConflict)))
+        } else if data
+            .mappings
+            .iter()
+            .filter(|m| &m.user_hash == user_hash)
+            .count() as i64
+            >= max
+        {
+            Ok(None)
+        } else {
+            let now = Utc::now();
+            let mapping = UserAsnMapping {
+                id: Uuid::new_v4(),
+                user_hash: user_hash.clone(),
+                user_id: user_id.map(str::to_string),
+                asn: asn.get(),
+                created_at: now,
+                updated_at: now,
+                description: None,
+                abuse_contact: None,
+            };
+            data.mappings.push(mapping.clone());
+            Ok(Some(mapping))
+        };
+        Box::pin(async move { result })
+    }
+
+    fn get_pool_usage(&self) -> BoxFuture<'_, Result<(i64, i64), sqlx::Error>> {
+        let now = Utc::now();
+        let data = self.data.lock().unwrap();
+        let usage = (
+            data.mappings.len() as i64,
+            data.leases.iter().filter(|l| is_active(l, now)).count() as i64,
+        );
+        Box::pin(async move { Ok(usage) })
+    }
+
+    fn get_mapping_permissions<'a>(
+        &'a self,
+        user_hash: Option<&'a UserHash>,
+        filter: &'a MappingFilter,
+    ) -> BoxFuture<'a, Result<Vec<MappingPermission>, sqlx::Error>> {
+        let now = Utc::now();
+        let data = self.data.lock().unwrap();
+        let mut holders: Vec<&UserHash> = data
+            .mappings
+            .iter()
+            .map(|m| &m.user_hash)
+            .chain(data.leases.iter().map(|l| &l.user_hash))
+            .filter(|holder| user_hash.is_none_or(|user_hash| *holder == user_hash))
+            .collect();
+        holders.sort();
+        holders.dedup();
+
+        let mut users = Vec::new();
+        for holder in holders {
+            let mut mappings: Vec<&UserAsnMapping> = data
+                .mappings
+                .iter()
+                .filter(|m| &m.user_hash == holder)
+                .collect();
+            mappings.sort_by_key(|m| (m.created_at, m.id));
+            let leases: Vec<&PrefixLease> = data
+                .leases
+                .iter()
+                .filter(|l| &l.user_hash == holder && is_active(l, now))
+                .collect();
+            if filter
+                .asn
+                .is_some_and(|asn| !mappings.iter().any(|m| m.asn == asn))
+                || (filter.active_only && leases.is_empty())
+            {
+                continue;
+            }
+            let mut holder_rows = permission_rows(&mappings, &leases);
+            holder_rows.sort_by_key(|row| {
+                (
+                    row.asn_assigned_at,
+                    row.origin_asn,
+                    row.valid_to.map(cmp::Reverse),
+                    row.source_id,
+                )
+            });
+            let keys = holder_rows
+                .iter()
+                .filter(|row| row.is_asn())
+                .map(|row| sort_key(filter.sort, row));
+            let leading = match filter.order {
+                SortOrder::Asc => keys.min(),
+                SortOrder::Desc => keys.max(),
+            };
+            users.push((leading, holder_rows));
+        }
+        // Users without an ASN last, as with NULLS LAST
+        users.sort_by(|(a, _), (b, _)| match (a, b) {
+            (Some(a), Some(b)) => match filter.order {
+                SortOrder::Asc => a.cmp(b),
+                SortOrder::Desc => b.cmp(a),
+            },
+            _ => b.is_some().cmp(&a.is_some()),
+        });
+        let rows = users.into_iter().flat_map(|(_, rows)| rows).collect();
+        Box::pin(async move { Ok(rows) })
+    }
+
+    fn get_mapping_annotations<'a>(
+        &'a self,
+        mapping_ids: &'a [Uuid],
+    ) -> BoxFuture<'a, Result<Vec<MappingAnnotation>, sqlx::Error>> {
+        let mut annotations: Vec<MappingAnnotation> = self
+            .data
+            .lock()
+            .unwrap()
+            .annotations
+            .iter()
+            .filter(|a| mapping_ids.contains(&a.mapping_id))
+            .cloned()
+            .collect();
+        annotations.sort_by(|a, b| (a.mapping_id, &a.key).cmp(&(b.mapping_id, &b.key)));
+        Box::pin(async move { Ok(annotations) })
+    }
+}
+
+/// Value of an ASN row a mapping listing is sorted on
+fn sort_key(sort: MappingSort, row: &MappingPermission) -> (Option<DateTime<Utc>>, Option<i64>) {
+    match sort {
+        MappingSort::CreatedAt => (row.asn_assigned_at, None),
+        MappingSort::UpdatedAt => (Some(row.updated_at), None),
+        MappingSort::Asn => (None, row.origin_asn),
+    }
+}
+
+/// Error of [`MemoryStore::assign_user_asn`] for an ASN already assigned,
+/// recognized by [`crate::database::is_asn_conflict`] like the database's
+#[derive(Debug)]
+struct AsnConflict;
+
+impl fmt::Display for AsnConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ASN is already assigned")
+    }
+}
+
+impl std::error::Error for AsnConflict {}
+
+impl sqlx::error::DatabaseError for AsnConflict {
+    fn message(&self) -> &str {
+        "ASN is already assigned"
+    }
+
+    fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+        Some(crate::database::UNIQUE_VIOLATION.into())
+    }
+
+    fn constraint(&self) -> Option<&str> {
+        Some(crate::database::ASN_UNIQUE_CONSTRAINT)
+    }
+
+    fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+        self
+    }
+
+    fn kind(&self) -> sqlx::error::ErrorKind {
+        sqlx::error::ErrorKind::UniqueViolation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::is_asn_conflict;
+
+    fn user(c: char) -> UserHash {
+        c.to_string().repeat(64).parse().unwrap()
+    }
+
+    fn lease(user_hash: UserHash, prefix: &str) -> PrefixLease {
+        let now = Utc::now();
+        PrefixLease {
+            id: Uuid::new_v4(),
+            user_hash,
+            prefix: prefix.to_string(),
+            start_time: now - chrono::Duration::hours(1),
+            end_time: now + chrono::Duration::hours(1),
+            created_at: now,
+            updated_at: now,
+            sites: None,
+            renew_minutes: None,
+            label: None,
+            purpose: None,
+            roa_max_length: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        let store = MemoryStore::new().with_lease(lease(user('a'), "2001:db8:1::/48"));
+        let asn = Asn::try_from(65000).unwrap();
+
+        let mapping = store
+            .assign_user_asn(&user('a'), Some("auth0|a"), asn, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(mapping.asn, 65000);
+        // Over the quota, and taken by another user
+        let other = Asn::try_from(65001).unwrap();
+        assert!(
+            store
+                .assign_user_asn(&user('a'), None, other, 1)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        let err = store
+            .assign_user_asn(&user('b'), None, asn, 1)
+            .await
+            .unwrap_err();
+        assert!(is_asn_conflict(&err));
+
+        let (asns, leases) = store.get_user_info(&user('a')).await.unwrap().unwrap();
+        assert_eq!((asns.len(), leases.len()), (1, 1));
+        assert_eq!(store.get_pool_usage().await.unwrap(), (1, 1));
+
+        let rows = store
+            .get_mapping_permissions(None, &MappingFilter::default())
+            .await
+            .unwrap();
+        let users = MappingFilter::group(rows);
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].asns[0].holder_id.as_deref(), Some("auth0|a"));
+        assert_eq!(
+            users[0].leases[0].prefix.as_deref(),
+            Some("2001:db8:1::/48")
+        );
+    }
+}
@@ -0,0 +1,55 @@
+//! Tracing span conventions.
+//!
+//! Every request runs in an `http` span, with a `handler` span per endpoint
+//! and `db`, `idp` and `pool` spans for the work it does. The `operation`
+//! field names what a span does, and resources always use the same field
+//! names so traces can be filtered per user or resource:
+//!
+//! - `user_hash`: hashed user identifier
+//! - `asn`: autonomous system number
+//! - `prefix`: IPv6 prefix
+//! - `agent_id`: service API agent
+
+use axum::http::Request;
+use tracing::{Span, field};
+
+/// Span of an HTTP request, filled in with the caller once authenticated
+pub fn http_span<B>(request: &Request<B>) -> Span {
+    tracing::info_span!(
+        "http",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        user_hash = field::Empty,
+        agent_id = field::Empty,
+    )
+}
+
+/// Record the authenticated user on the current request span
+pub fn record_user_hash(user_hash: &str) {
+    Span::current().record("user_hash", user_hash);
+}
+
+/// Record the calling agent on the current request span
+pub fn record_agent_id(agent_id: &str) {
+    Span::current().record("agent_id", agent_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    #[test]
+    fn test_http_span_fields() {
+        let request = Request::get("/api/user/info").body(Body::empty()).unwrap();
+        let subscriber = tracing_subscriber::fmt().finish();
+        let span = tracing::subscriber::with_default(subscriber, || http_span(&request));
+
+        let metadata = span.metadata().unwrap();
+        assert_eq!(metadata.name(), "http");
+        for name in ["method", "uri", "user_hash", "agent_id"] {
+            assert!(metadata.fields().field(name).is_some());
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+use crate::jwt::AuthInfo;
+
+/// Compute the cache key for a bearer token (raw tokens are never stored)
+pub fn token_hash(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(token.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    auth_info: AuthInfo,
+    expires_at: i64,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    entries: HashMap<String, CacheEntry>,
+    revoked: HashSet<String>,
+    revocations_modified: Option<SystemTime>,
+    clock: u64,
+}
+
+/// LRU cache of validated tokens, keyed by token hash.
+///
+/// Entries are kept until the token's `exp` claim, so a chatty client only pays
+/// for signature verification once per token. The cache also owns the token
+/// revocation list: revoking a token (by `jti` or token hash) evicts it.
+#[derive(Debug, Clone, Default)]
+pub struct TokenCache {
+    capacity: usize,
+    inner: Arc<RwLock<CacheInner>>,
+}
+
+impl TokenCache {
+    /// Create a new cache holding at most `capacity` tokens (0 disables caching)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Arc::new(RwLock::new(CacheInner::default())),
+        }
+    }
+
+    /// Get the cached auth info for a token, if present, unexpired and not revoked
+    pub async fn get(&self, token: &str) -> Option<AuthInfo> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let key = token_hash(token);
+        let mut inner = self.inner.write().await;
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        let entry = inner.entries.get_mut(&key)?;
+        if entry.expires_at <= Utc::now().timestamp() {
+            inner.entries.remove(&key);
+            return None;
+        }
+
+        entry.last_used = clock;
+        Some(entry.auth_info.clone())
+    }
+
+    /// Cache a validated token until its expiry
+    pub async fn insert(&self, token: &str, auth_info: AuthInfo) {
+        // Tokens without an expiry are never cached
+        let Some(expires_at) = auth_info.expires_at else {
+            return;
+        };
+        if self.capacity == 0 || expires_at <= Utc::now().timestamp() {
+            return;
+        }
+
+        let key = token_hash(token);
+        let mut inner = self.inner.write().await;
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        if !inner.entries.contains_key(&key) && inner.entries.len() >= self.capacity {
+            // Evict the least recently used entry
+            if let Some(oldest) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone())
+            {
+                inner.entries.remove(&oldest);
+            }
+        }
+
+        inner.entries.insert(
+            key,
+            CacheEntry {
+                auth_info,
+                expires_at,
+                last_used: clock,
+            },
+        );
+    }
+
+    /// Check whether a token is revoked, either by its hash or by its `jti`
+    pub async fn is_revoked(&self, token: &str, jti: Option<&str>) -> bool {
+        let inner = self.inner.read().await;
+        if inner.revoked.is_empty() {
+            return false;
+        }
+        inner.revoked.contains(&token_hash(token)) || jti.is_some_and(|j| inner.revoked.contains(j))
+    }
+
+    /// Revoke a token by `jti` or token hash, evicting any cached entry
+    pub async fn revoke(&self, id: &str) {
+        let mut inner = self.inner.write().await;
+        inner.revoked.insert(id.to_string());
+        inner
+            .entries
+            .retain(|key, e| key != id && e.auth_info.token_id.as_deref() != Some(id));
+    }
+
+    /// Number of tokens currently cached
+    pub async fn len(&self) -> usize {
+        self.inner.read().await.entries.len()
+    }
+
+    /// Check if the cache is empty
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Reload the revocation list from a file (one `jti` or token hash per line)
+    /// if it changed since the last load. Any change clears the cache so that
+    /// newly revoked tokens can't be served from it.
+    pub async fn reload_revocations(&self, path: &str) {
+        let modified = match tokio::fs::metadata(path).await.and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                warn!("Failed to stat revocation file {}: {}", path, e);
+                return;
+            }
+        };
+
+        if self.inner.read().await.revocations_modified == Some(modified) {
+            return;
+        }
+
+        let content = match tokio::fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(e) => {
+                warn!("Failed to read revocation file {}: {}", path, e);
+                return;
+            }
+        };
+
+        let revoked: HashSet<String> = content
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| l.to_string())
+            .collect();
+
+        let mut inner = self.inner.write().await;
+        debug!("Dropping {} cached tokens", inner.entries.len());
+        info!("Loaded {} revoked tokens from {}", revoked.len(), path);
+        inner.revoked = revoked;
+        inner.revocations_modified = Some(modified);
+        inner.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth_info(sub: &str, expires_at: i64, jti: Option<&str>) -> AuthInfo {
+        AuthInfo {
+            expires_at: Some(expires_at),
+            token_id: jti.map(|j| j.to_string()),
+            ..AuthInfo::new(
+                sub.to_string(),
+                sub.to_string(),
+                None,
+                None,
+                None,
+                vec![],
+                vec![],
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit_until_expiry() {
+        let cache = TokenCache::new(10);
+        let future = Utc::now().timestamp() + 3600;
+        cache.insert("token-a", auth_info("a", future, None)).await;
+        cache
+            .insert("token-b", auth_info("b", Utc::now().timestamp() - 1, None))
+            .await;
+
+        assert_eq!(cache.get("token-a").await.unwrap().sub, "a");
+        assert!(cache.get("token-b").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cache_evicts_least_recently_used() {
+        let cache = TokenCache::new(2);
+        let future = Utc::now().timestamp() + 3600;
+        cache.insert("token-a", auth_info("a", future, None)).await;
+        cache.insert("token-b", auth_info("b", future, None)).await;
+
+        // Touch "a" so that "b" becomes the eviction candidate
+        assert!(cache.get("token-a").await.is_some());
+        cache.insert("token-c", auth_info("c", future, None)).await;
+
+        assert_eq!(cache.len().await, 2);
+        assert!(cache.get("token-a").await.is_some());
+        assert!(cache.get("token-b").await.is_none());
+        assert!(cache.get("token-c").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_evicts_cached_token() {
+        let cache = TokenCache::new(10);
+        let future = Utc::now().timestamp() + 3600;
+        cache
+            .insert("token-a", auth_info("a", future, Some("jti-a")))
+            .await;
+
+        cache.revoke("jti-a").await;
+
+        assert!(cache.get("token-a").await.is_none());
+        assert!(cache.is_revoked("token-a", Some("jti-a")).await);
+        assert!(!cache.is_revoked("token-b", None).await);
+    }
+}
@@ -0,0 +1,356 @@
+//! Transfer of leases and ASNs between users.
+//!
+//! An admin moves a current or upcoming lease, or an ASN with or without its
+//! leases, to another user, e.g. when a student hands a long-running demo
+//! off to a staff member. The resource is kept as is (same prefix, same ASN,
+//! same lease window) and every transfer is recorded.
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::database::{PrefixLease, Transfer};
+use crate::events::{
+    AgentEvent, EVENT_ASN_ASSIGNED, EVENT_INVALIDATE, EVENT_LEASE_CREATED, EventPriority,
+};
+use crate::types::UserHash;
+
+// Request/Response types
+
+#[derive(Deserialize)]
+pub struct TransferRequest {
+    /// User the resource is moved to
+    user_hash: UserHash,
+    /// Why the resource is moved, logged and recorded with the transfer
+    reason: String,
+    /// Move the current and upcoming leases along with an ASN
+    #[serde(default)]
+    with_leases: bool,
+}
+
+#[derive(Deserialize)]
+pub struct TransfersQuery {
+    user_hash: Option<UserHash>,
+}
+
+#[derive(Serialize)]
+pub struct TransferResponse {
+    id: Uuid,
+    resource: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lease_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asn: Option<i64>,
+    from_user_hash: UserHash,
+    to_user_hash: UserHash,
+    reason: String,
+    created_at: String,
+}
+
+impl From<Transfer> for TransferResponse {
+    fn from(transfer: Transfer) -> Self {
+        Self {
+            id: transfer.id,
+            resource: transfer.resource,
+            lease_id: transfer.lease_id,
+            prefix: transfer.prefix,
+            asn: transfer.asn,
+            from_user_hash: transfer.from_user_hash,
+            to_user_hash: transfer.to_user_hash,
+            reason: transfer.reason,
+            created_at: transfer.created_at.to_rfc3339(),
+        }
+    }
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.into()
+        })),
+    )
+}
+
+fn lease_json(lease: &PrefixLease) -> Value {
+    json!({
+        "id": lease.id,
+        "user_hash": lease.user_hash,
+        "prefix": lease.prefix,
+        "start_time": lease.start_time.to_rfc3339(),
+        "end_time": lease.end_time.to_rfc3339(),
+        "sites": lease.sites,
+    })
+}
+
+/// Check a transfer request, returning its trimmed reason
+fn validate_request(request: &TransferRequest, from: &UserHash) -> Result<String, ApiError> {
+    let reason = request.reason.trim();
+    if reason.is_empty() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "A reason is required"));
+    }
+    if request.user_hash == *from {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "The resource already belongs to this user",
+        ));
+    }
+    Ok(reason.to_string())
+}
+
+/// Tell agents the lease moved to another origin ASN (the old announcement is
+/// torn down first) and notify both users
+pub(crate) fn notify_lease_transfer(
+    state: &AppState,
+    lease: &PrefixLease,
+    from: &UserHash,
+    reason: &str,
+) {
+    state.agent_events.publish(
+        AgentEvent::new(
+            EVENT_INVALIDATE,
+            EventPriority::High,
+            json!({
+                "resource": "prefix",
+                "id": lease.id,
+                "user_hash": from,
+                "prefix": lease.prefix,
+                "reason": reason,
+            }),
+        )
+        .at_sites(lease.sites.clone()),
+    );
+    state.agent_events.publish(
+        AgentEvent::new(
+            EVENT_LEASE_CREATED,
+            EventPriority::Normal,
+            json!({
+                "id": lease.id,
+                "user_hash": lease.user_hash,
+                "prefix": lease.prefix,
+                "start_time": lease.start_time.to_rfc3339(),
+                "end_time": lease.end_time.to_rfc3339(),
+            }),
+        )
+        .at_sites(lease.sites.clone()),
+    );
+    #[cfg(feature = "webhooks")]
+    for (user_hash, direction) in [(from, "out"), (&lease.user_hash, "in")] {
+        crate::webhooks::dispatch(
+            state,
+            user_hash,
+            "prefix.transferred",
+            json!({
+                "prefix": lease.prefix,
+                "start_time": lease.start_time.to_rfc3339(),
+                "end_time": lease.end_time.to_rfc3339(),
+                "sites": lease.sites,
+                "direction": direction,
+            }),
+        );
+    }
+}
+
+// Handlers
+
+/// List the transfers from or to a user, or every transfer, newest first
+pub async fn list_transfers(
+    State(state): State<AppState>,
+    Query(query): Query<TransfersQuery>,
+) -> Result<Json<Value>, ApiError> {
+    match state.database.get_transfers(query.user_hash.as_ref()).await {
+        Ok(transfers) => Ok(Json(json!({
+            "transfers": transfers
+                .into_iter()
+                .map(TransferResponse::from)
+                .collect::<Vec<_>>(),
+        }))),
+        Err(err) => {
+            error!("Failed to list transfers: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to list transfers",
+            ))
+        }
+    }
+}
+
+/// Move a current or upcoming lease to another user holding an ASN
+#[instrument(name = "handler", skip_all, fields(operation = "transfer_lease", lease_id = %id))]
+pub async fn transfer_lease(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(request): Json<TransferRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let not_found = || api_error(StatusCode::NOT_FOUND, "Lease not found or already ended");
+    let lease = match state.database.get_prefix_lease(id).await {
+        Ok(Some(lease)) if lease.end_time > chrono::Utc::now() => lease,
+        Ok(_) => return Err(not_found()),
+        Err(err) => {
+            error!("Failed to get lease {}: {}", id, err);
+            return Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to transfer lease",
+            ));
+        }
+    };
+    let from = lease.user_hash;
+    let reason = validate_request(&request, &from)?;
+    let to = request.user_hash;
+
+    let _guards = state.user_locks.lock_pair(&from, &to).await;
+
+    // Leases are announced with the origin ASN of their holder
+    match state.database.get_user_asn(&to).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return Err(api_error(StatusCode::CONFLICT, "The new holder has no ASN"));
+        }
+        Err(err) => {
+            error!("Failed to get ASN of user {}: {}", to, err);
+            return Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to transfer lease",
+            ));
+        }
+    }
+
+    let lease = match state
+        .database
+        .transfer_prefix_lease(id, &from, &to, &reason)
+        .await
+    {
+        Ok(Some(lease)) => lease,
+        Ok(None) => return Err(not_found()),
+        Err(err) => {
+            error!("Failed to transfer lease {}: {}", id, err);
+            return Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to transfer lease",
+            ));
+        }
+    };
+
+    info!(
+        "Transferred lease {} of {} from user {} to user {} ({})",
+        lease.id, lease.prefix, from, to, reason
+    );
+    notify_lease_transfer(&state, &lease, &from, &reason);
+    let mut body = lease_json(&lease);
+    body["previous_user_hash"] = json!(from);
+    Ok(Json(body))
+}
+
+/// Move a user's ASNs to a user without one, with the current and upcoming
+/// leases when `with_leases` is set
+#[instrument(name = "handler", skip_all, fields(operation = "transfer_asn", user_hash = %from))]
+pub async fn transfer_asn(
+    State(state): State<AppState>,
+    Path(from): Path<UserHash>,
+    Json(request): Json<TransferRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let reason = validate_request(&request, &from)?;
+    let to = request.user_hash;
+    let internal_error = || api_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to transfer ASN");
+
+    let _guards = state.user_locks.lock_pair(&from, &to).await;
+
+    let (holder, active, upcoming) = tokio::try_join!(
+        state.database.get_user_asn(&to),
+        state.database.get_active_user_leases(&from),
+        state.database.get_upcoming_user_leases(&from),
+    )
+    .map_err(|err| {
+        error!("Failed to check ASN transfer from user {}: {}", from, err);
+        internal_error()
+    })?;
+    if holder.is_some() {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            "The new holder already has an ASN",
+        ));
+    }
+    // Leases left behind would be announced without an origin ASN
+    let held = active.len() + upcoming.len();
+    if held > 0 && !request.with_leases {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!(
+                "Transfer the {} active prefix lease(s) along (with_leases) or end them first",
+                held
+            ),
+        ));
+    }
+
+    let (mappings, leases) = match state
+        .database
+        .transfer_user_asn(&from, &to, request.with_leases, &reason)
+        .await
+    {
+        Ok(Some(transferred)) => transferred,
+        Ok(None) => return Err(api_error(StatusCode::NOT_FOUND, "User has no ASN")),
+        Err(err) => {
+            error!("Failed to transfer ASN of user {}: {}", from, err);
+            return Err(internal_error());
+        }
+    };
+
+    let asns: Vec<i64> = mappings.iter().map(|mapping| mapping.asn).collect();
+    info!(
+        "Transferred ASN(s) {:?} and {} leases from user {} to user {} ({})",
+        asns,
+        leases.len(),
+        from,
+        to,
+        reason
+    );
+    for &asn in &asns {
+        state.agent_events.publish(AgentEvent::new(
+            EVENT_INVALIDATE,
+            EventPriority::High,
+            json!({
+                "resource": "asn",
+                "user_hash": from,
+                "asn": asn,
+                "reason": reason,
+            }),
+        ));
+        state.agent_events.publish(AgentEvent::new(
+            EVENT_ASN_ASSIGNED,
+            EventPriority::Normal,
+            json!({ "user_hash": to, "asn": asn }),
+        ));
+        #[cfg(feature = "webhooks")]
+        for (user_hash, direction) in [(&from, "out"), (&to, "in")] {
+            crate::webhooks::dispatch(
+                &state,
+                user_hash,
+                "asn.transferred",
+                json!({ "asn": asn, "direction": direction }),
+            );
+        }
+    }
+    for lease in &leases {
+        notify_lease_transfer(&state, lease, &from, &reason);
+    }
+
+    Ok(Json(json!({
+        "asn": asns[0],
+        "asns": asns,
+        "user_hash": to,
+        "previous_user_hash": from,
+        "leases": leases.iter().map(lease_json).collect::<Vec<_>>(),
+    })))
+}
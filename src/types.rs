@@ -0,0 +1,190 @@
+//! Validated identifiers of users and resources.
+//!
+//! Database methods take these instead of raw strings and integers, so an
+//! argument can't be passed in the wrong position and unvalidated input
+//! never reaches a query.
+
+use ipnet::Ipv6Net;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+/// Hex SHA-256 (or HMAC-SHA256) of a normalized user identity
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type,
+)]
+#[serde(into = "String", try_from = "String")]
+#[sqlx(transparent)]
+pub struct UserHash(String);
+
+impl UserHash {
+    /// Length of a hash in hex digits
+    pub const LEN: usize = 64;
+
+    /// Hash of an identity as computed by [`crate::identity`], trusted as is
+    pub(crate) fn from_digest(hex: String) -> Self {
+        Self(hex)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for UserHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != Self::LEN || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return Err(format!(
+                "Invalid user hash (expected {} lowercase hex digits)",
+                Self::LEN
+            ));
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for UserHash {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<UserHash> for String {
+    fn from(hash: UserHash) -> Self {
+        hash.0
+    }
+}
+
+impl Deref for UserHash {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for UserHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl PartialEq<str> for UserHash {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<String> for UserHash {
+    fn eq(&self, other: &String) -> bool {
+        &self.0 == other
+    }
+}
+
+impl PartialEq<UserHash> for String {
+    fn eq(&self, other: &UserHash) -> bool {
+        *self == other.0
+    }
+}
+
+/// Autonomous system number, 32-bit and non-zero
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type,
+)]
+#[serde(into = "i64", try_from = "i64")]
+#[sqlx(transparent)]
+pub struct Asn(i64);
+
+impl Asn {
+    pub fn get(self) -> i64 {
+        self.0
+    }
+}
+
+impl TryFrom<i64> for Asn {
+    type Error = String;
+
+    fn try_from(asn: i64) -> Result<Self, Self::Error> {
+        if !(1..=u32::MAX as i64).contains(&asn) {
+            return Err(format!("Invalid ASN {} (expected 1 to {})", asn, u32::MAX));
+        }
+        Ok(Self(asn))
+    }
+}
+
+impl From<Asn> for i64 {
+    fn from(asn: Asn) -> Self {
+        asn.0
+    }
+}
+
+impl fmt::Display for Asn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// IPv6 prefix in canonical form (host bits cleared)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Prefix(Ipv6Net);
+
+impl Prefix {
+    pub fn net(&self) -> Ipv6Net {
+        self.0
+    }
+}
+
+impl From<Ipv6Net> for Prefix {
+    fn from(net: Ipv6Net) -> Self {
+        Self(net.trunc())
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ipv6Net::from_str(s)
+            .map(Self::from)
+            .map_err(|_| format!("Invalid IPv6 prefix '{}'", s))
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_hash() {
+        let hash = crate::hash_user_identifier("user@example.com");
+        assert_eq!(hash.parse::<UserHash>().unwrap(), hash);
+        assert!("abc".parse::<UserHash>().is_err());
+        assert!(hash.to_uppercase().parse::<UserHash>().is_err());
+    }
+
+    #[test]
+    fn test_asn() {
+        assert_eq!(Asn::try_from(65000).unwrap().get(), 65000);
+        assert!(Asn::try_from(4_294_967_295).is_ok());
+        assert!(Asn::try_from(0).is_err());
+        assert!(Asn::try_from(4_294_967_296).is_err());
+    }
+
+    #[test]
+    fn test_prefix_is_canonical() {
+        let prefix: Prefix = "2001:db8:1000::1/48".parse().unwrap();
+        assert_eq!(prefix.to_string(), "2001:db8:1000::/48");
+        assert!("10.0.0.0/8".parse::<Prefix>().is_err());
+    }
+}
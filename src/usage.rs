@@ -0,0 +1,366 @@
+//! API usage metering.
+//!
+//! Calls to the client and service APIs are counted in memory per user or
+//! agent, route and hour, then added to the `api_usage` table every minute.
+//! Admins use the totals to spot misbehaving clients and to size quotas.
+
+use axum::{
+    Json,
+    extract::{MatchedPath, Path, Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+use crate::AppState;
+use crate::agent::AgentInfo;
+use crate::database::ApiUsage;
+use crate::jwt::AuthInfo;
+
+/// Calls authenticated as a user of the client API
+pub const KIND_USER: &str = "user";
+/// Calls authenticated as an agent of the service API
+pub const KIND_AGENT: &str = "agent";
+
+/// How often counts are written to the database
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Days of usage kept by the scheduler
+pub const RETENTION_DAYS: i64 = 90;
+
+/// Period reported when none is given
+const DEFAULT_PERIOD: TimeDelta = TimeDelta::hours(24);
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct UsageKey {
+    hour: DateTime<Utc>,
+    subject_kind: String,
+    subject: String,
+    route: String,
+}
+
+/// Call counts not written to the database yet
+#[derive(Debug, Clone, Default)]
+pub struct UsageMeter {
+    counts: Arc<Mutex<HashMap<UsageKey, i64>>>,
+}
+
+/// Start of the hour of a point in time
+fn hour_of(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.duration_trunc(TimeDelta::hours(1)).unwrap_or(time)
+}
+
+impl UsageMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a call of a user or agent on a route
+    pub fn record(&self, subject_kind: &str, subject: &str, route: &str) {
+        self.add(hour_of(Utc::now()), subject_kind, subject, route, 1);
+    }
+
+    fn add(&self, hour: DateTime<Utc>, subject_kind: &str, subject: &str, route: &str, calls: i64) {
+        let key = UsageKey {
+            hour,
+            subject_kind: subject_kind.to_string(),
+            subject: subject.to_string(),
+            route: route.to_string(),
+        };
+        *self.counts.lock().unwrap().entry(key).or_default() += calls;
+    }
+
+    /// Take the pending counts, leaving none
+    pub fn take(&self) -> Vec<ApiUsage> {
+        let counts = std::mem::take(&mut *self.counts.lock().unwrap());
+        counts
+            .into_iter()
+            .map(|(key, calls)| ApiUsage {
+                hour: key.hour,
+                subject_kind: key.subject_kind,
+                subject: key.subject,
+                route: key.route,
+                calls,
+            })
+            .collect()
+    }
+
+    /// Put back counts that couldn't be written
+    pub fn restore(&self, usage: Vec<ApiUsage>) {
+        for u in usage {
+            self.add(u.hour, &u.subject_kind, &u.subject, &u.route, u.calls);
+        }
+    }
+}
+
+/// Count the request against the authenticated user or agent.
+///
+/// Layered inside the authentication middleware, which provides the caller.
+pub async fn meter(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| format!("{} {}", request.method(), path.as_str()));
+    let subject = if let Some(auth_info) = request.extensions().get::<AuthInfo>() {
+        Some((
+            KIND_USER,
+            state.identity.user_hash(&auth_info.identity).into(),
+        ))
+    } else {
+        request
+            .extensions()
+            .get::<AgentInfo>()
+            .map(|agent| (KIND_AGENT, agent.id.clone()))
+    };
+    if let (Some(route), Some((kind, subject))) = (route, subject) {
+        state.usage.record(kind, &subject, &route);
+    }
+    next.run(request).await
+}
+
+/// Write pending counts to the database, returning how many rows were written
+pub async fn flush(state: &AppState) -> Result<usize, sqlx::Error> {
+    let usage = state.usage.take();
+    match state.database.record_api_usage(&usage).await {
+        Ok(()) => Ok(usage.len()),
+        Err(err) => {
+            // Keep the counts for the next flush
+            state.usage.restore(usage);
+            Err(err)
+        }
+    }
+}
+
+/// Spawn the task writing counts to the database
+pub fn spawn(state: AppState) {
+    info!("Metering API usage (flushed every {:?})", FLUSH_INTERVAL);
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match flush(&state).await {
+                Ok(0) => {}
+                Ok(rows) => debug!("Recorded {} API usage rows", rows),
+                Err(err) => error!("Failed to record API usage: {}", err),
+            }
+        }
+    });
+}
+
+// Request/Response types
+
+#[derive(Deserialize)]
+pub struct UsageQuery {
+    kind: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct UsageTotalResponse {
+    kind: String,
+    subject: String,
+    calls: i64,
+}
+
+#[derive(Serialize)]
+pub struct TopUsageResponse {
+    since: String,
+    until: String,
+    subjects: Vec<UsageTotalResponse>,
+}
+
+#[derive(Serialize)]
+pub struct HourlyUsageResponse {
+    hour: String,
+    route: String,
+    calls: i64,
+}
+
+#[derive(Serialize)]
+pub struct SubjectUsageResponse {
+    kind: String,
+    subject: String,
+    since: String,
+    until: String,
+    calls: i64,
+    hours: Vec<HourlyUsageResponse>,
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.into()
+        })),
+    )
+}
+
+fn internal_error(err: sqlx::Error) -> ApiError {
+    error!("Failed to load API usage: {}", err);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to load API usage",
+    )
+}
+
+fn validate_kind(kind: &str) -> Result<(), String> {
+    match kind {
+        KIND_USER | KIND_AGENT => Ok(()),
+        other => Err(format!("Unknown kind '{}' (expected user or agent)", other)),
+    }
+}
+
+/// Period of a query, from the start of the hour of `since` (default 24 hours
+/// ago) to `until` (default now)
+fn period(
+    query: &UsageQuery,
+    now: DateTime<Utc>,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), String> {
+    let until = query.until.unwrap_or(now);
+    let since = hour_of(query.since.unwrap_or(until - DEFAULT_PERIOD));
+    if since >= until {
+        return Err("since must be before until".to_string());
+    }
+    Ok((since, until))
+}
+
+// Handlers
+
+/// Users and agents making the most calls over a period
+pub async fn get_top_usage(
+    State(state): State<AppState>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<TopUsageResponse>, ApiError> {
+    if let Some(kind) = &query.kind {
+        validate_kind(kind).map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("limit must be between 1 and {}", MAX_LIMIT),
+        ));
+    }
+    let (since, until) =
+        period(&query, Utc::now()).map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+
+    let totals = state
+        .database
+        .get_top_api_usage(query.kind.as_deref(), since, until, limit)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(TopUsageResponse {
+        since: since.to_rfc3339(),
+        until: until.to_rfc3339(),
+        subjects: totals
+            .into_iter()
+            .map(|t| UsageTotalResponse {
+                kind: t.subject_kind,
+                subject: t.subject,
+                calls: t.calls,
+            })
+            .collect(),
+    }))
+}
+
+/// Hourly calls of a user or agent per route over a period
+pub async fn get_subject_usage(
+    State(state): State<AppState>,
+    Path((kind, subject)): Path<(String, String)>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<SubjectUsageResponse>, ApiError> {
+    validate_kind(&kind).map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+    let (since, until) =
+        period(&query, Utc::now()).map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+
+    let usage = state
+        .database
+        .get_api_usage(&kind, &subject, since, until)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(SubjectUsageResponse {
+        kind,
+        subject,
+        since: since.to_rfc3339(),
+        until: until.to_rfc3339(),
+        calls: usage.iter().map(|u| u.calls).sum(),
+        hours: usage
+            .into_iter()
+            .map(|u| HourlyUsageResponse {
+                hour: u.hour.to_rfc3339(),
+                route: u.route,
+                calls: u.calls,
+            })
+            .collect(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_meter_rolls_up_calls() {
+        let meter = UsageMeter::new();
+        let hour = Utc.with_ymd_and_hms(2025, 1, 1, 10, 0, 0).unwrap();
+        meter.add(hour, KIND_USER, "abc", "POST /api/user/prefix", 1);
+        meter.add(hour, KIND_USER, "abc", "POST /api/user/prefix", 1);
+        meter.add(hour, KIND_AGENT, "ams-1", "GET /service/mappings", 1);
+
+        let mut usage = meter.take();
+        usage.sort_by(|a, b| a.subject.cmp(&b.subject));
+        assert_eq!(usage.len(), 2);
+        assert_eq!((usage[0].subject.as_str(), usage[0].calls), ("abc", 2));
+        assert_eq!((usage[1].subject.as_str(), usage[1].calls), ("ams-1", 1));
+        assert!(meter.take().is_empty());
+
+        // Counts that failed to flush are merged with newer ones
+        meter.add(hour, KIND_USER, "abc", "POST /api/user/prefix", 1);
+        meter.restore(usage);
+        let usage = meter.take();
+        assert_eq!(usage.len(), 2);
+        assert!(usage.iter().any(|u| u.subject == "abc" && u.calls == 3));
+    }
+
+    #[test]
+    fn test_period() {
+        let now = Utc.with_ymd_and_hms(2025, 1, 2, 10, 30, 0).unwrap();
+        let query = |since, until| UsageQuery {
+            kind: None,
+            since,
+            until,
+            limit: None,
+        };
+
+        assert_eq!(
+            period(&query(None, None), now),
+            Ok((Utc.with_ymd_and_hms(2025, 1, 1, 10, 0, 0).unwrap(), now))
+        );
+        // A since within the hour of until still covers that hour
+        assert!(period(&query(Some(now), Some(now)), now).is_ok());
+        assert!(period(&query(Some(now), Some(now - TimeDelta::hours(1))), now).is_err());
+        assert_eq!(
+            hour_of(now),
+            Utc.with_ymd_and_hms(2025, 1, 2, 10, 0, 0).unwrap()
+        );
+        assert!(validate_kind("user").is_ok());
+        assert!(validate_kind("tenant").is_err());
+    }
+}
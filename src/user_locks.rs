@@ -0,0 +1,114 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// Per-user async locks serializing mutating requests for the same user hash.
+///
+/// Locks are created on demand and dropped once no request holds or waits for
+/// them, so the map only grows with the number of concurrently active users.
+#[derive(Debug, Clone, Default)]
+pub struct UserLocks {
+    locks: Arc<Mutex<HashMap<String, Weak<AsyncMutex<()>>>>>,
+}
+
+impl UserLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait for exclusive access to a user, released when the guard is dropped
+    pub async fn lock(&self, user_hash: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            locks.retain(|_, lock| lock.strong_count() > 0);
+            match locks.get(user_hash).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    let lock = Arc::new(AsyncMutex::new(()));
+                    locks.insert(user_hash.to_string(), Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+        lock.lock_owned().await
+    }
+
+    /// Wait for exclusive access to two different users, always locked in the
+    /// same order so requests locking the same pair can't deadlock
+    pub async fn lock_pair(&self, a: &str, b: &str) -> (OwnedMutexGuard<()>, OwnedMutexGuard<()>) {
+        let (first, second) = if a <= b { (a, b) } else { (b, a) };
+        let first = self.lock(first).await;
+        (first, self.lock(second).await)
+    }
+
+    /// Number of users currently locked or waited on
+    pub fn len(&self) -> usize {
+        let locks = self.locks.lock().unwrap();
+        locks
+            .values()
+            .filter(|lock| lock.strong_count() > 0)
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_same_user_is_serialized() {
+        let locks = UserLocks::new();
+        let guard = locks.lock("alice").await;
+
+        // Another user isn't blocked
+        let _bob = locks.lock("bob").await;
+
+        let waiter = {
+            let locks = locks.clone();
+            tokio::spawn(async move {
+                let _guard = locks.lock("alice").await;
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        drop(guard);
+        waiter.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pairs_lock_in_order() {
+        let locks = UserLocks::new();
+        let guards = locks.lock_pair("bob", "alice").await;
+        assert_eq!(locks.len(), 2);
+
+        let waiter = {
+            let locks = locks.clone();
+            tokio::spawn(async move {
+                let _guards = locks.lock_pair("alice", "bob").await;
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        drop(guards);
+        waiter.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_locks_are_released() {
+        let locks = UserLocks::new();
+        {
+            let _guard = locks.lock("alice").await;
+            assert_eq!(locks.len(), 1);
+        }
+        assert!(locks.is_empty());
+    }
+}
@@ -0,0 +1,649 @@
+use axum::{
+    Json,
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+use crate::database::{Database, Webhook, WebhookDelivery};
+use crate::types::UserHash;
+use crate::{AppState, jwt, outbound};
+
+/// Maximum number of webhooks a single user can register
+pub const MAX_WEBHOOKS_PER_USER: usize = 10;
+
+/// Event types users can subscribe to
+pub const EVENT_TYPES: [&str; 7] = [
+    "asn.assigned",
+    "asn.released",
+    "asn.transferred",
+    "prefix.leased",
+    "prefix.renewed",
+    "prefix.transferred",
+    "test",
+];
+
+/// Delivery is in flight or waiting for a retry
+pub const DELIVERY_PENDING: &str = "pending";
+/// Receiver acknowledged the delivery with a 2xx response
+pub const DELIVERY_SUCCEEDED: &str = "succeeded";
+/// All attempts failed; only a manual redelivery will retry it
+pub const DELIVERY_DEAD_LETTER: &str = "dead_letter";
+
+/// Delay before the first retry, doubled after every failed attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+
+/// An event delivered to webhook endpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub created_at: String,
+    pub data: Value,
+}
+
+impl WebhookEvent {
+    pub fn new(event_type: &str, data: Value) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            event_type: event_type.to_string(),
+            created_at: Utc::now().to_rfc3339(),
+            data,
+        }
+    }
+}
+
+/// Result of a single delivery attempt
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryOutcome {
+    pub status_code: Option<u16>,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+impl DeliveryOutcome {
+    pub fn is_success(&self) -> bool {
+        self.status_code.is_some_and(|s| (200..300).contains(&s))
+    }
+}
+
+/// Generate a new random signing secret
+pub fn generate_secret() -> String {
+    format!(
+        "whsec_{}{}",
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    )
+}
+
+/// Compute the `X-Peerlab-Signature` value for a payload.
+///
+/// The signature is an HMAC-SHA256 over `"{timestamp}.{body}"` so receivers
+/// can reject replayed deliveries by checking the timestamp.
+pub fn sign_payload(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Send a serialized event to a webhook endpoint (a single attempt)
+pub async fn deliver(
+    webhook: &Webhook,
+    event_id: Uuid,
+    event_type: &str,
+    body: &str,
+) -> DeliveryOutcome {
+    let timestamp = Utc::now().timestamp();
+    let signature = sign_payload(&webhook.secret, timestamp, body);
+
+    // Checked again for webhooks registered before URLs were checked; the
+    // addresses of host names are checked by the client's resolver
+    if let Err(err) = outbound::validate_url(&webhook.url) {
+        warn!("Refused delivery to webhook {}: {}", webhook.id, err);
+        return DeliveryOutcome {
+            status_code: None,
+            latency_ms: 0,
+            error: Some(err),
+        };
+    }
+    let client = outbound::client(Duration::from_secs(10));
+
+    let start = Instant::now();
+    let result = client
+        .post(&webhook.url)
+        .header("Content-Type", "application/json")
+        .header("X-Peerlab-Event", event_type)
+        .header("X-Peerlab-Delivery", event_id.to_string())
+        .header("X-Peerlab-Timestamp", timestamp.to_string())
+        .header("X-Peerlab-Signature", signature)
+        .body(body.to_string())
+        .send()
+        .await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+
+    match result {
+        Ok(response) => {
+            let status = response.status();
+            debug!(
+                "Delivered {} to webhook {}: {}",
+                event_type, webhook.id, status
+            );
+            DeliveryOutcome {
+                status_code: Some(status.as_u16()),
+                latency_ms,
+                error: (!status.is_success()).then(|| format!("Receiver returned {}", status)),
+            }
+        }
+        Err(e) => {
+            // The error text can describe the receiver's network, so the
+            // user only gets its kind
+            warn!("Failed to deliver to webhook {}: {}", webhook.id, e);
+            DeliveryOutcome {
+                status_code: None,
+                latency_ms,
+                error: Some(outbound::describe_error(&e).to_string()),
+            }
+        }
+    }
+}
+
+/// Attempt a logged delivery, retrying with exponential backoff up to
+/// `max_attempts` in total when `retry` is set. The delivery ends up either
+/// succeeded or dead-lettered.
+pub async fn run_delivery(
+    database: &Database,
+    webhook: &Webhook,
+    mut delivery: WebhookDelivery,
+    max_attempts: u32,
+    retry: bool,
+) -> Result<WebhookDelivery, sqlx::Error> {
+    let mut delay = RETRY_BASE_DELAY;
+
+    loop {
+        let outcome = deliver(
+            webhook,
+            delivery.event_id,
+            &delivery.event_type,
+            &delivery.payload,
+        )
+        .await;
+
+        let attempts = delivery.attempts as u32 + 1;
+        let status = if outcome.is_success() {
+            DELIVERY_SUCCEEDED
+        } else if !retry || attempts >= max_attempts {
+            DELIVERY_DEAD_LETTER
+        } else {
+            DELIVERY_PENDING
+        };
+
+        delivery = database
+            .record_webhook_attempt(
+                delivery.id,
+                status,
+                outcome.status_code.map(|c| c as i32),
+                outcome.latency_ms.min(i32::MAX as u64) as i32,
+                outcome.error.as_deref(),
+            )
+            .await?;
+
+        if status != DELIVERY_PENDING {
+            if status == DELIVERY_DEAD_LETTER {
+                warn!(
+                    "Webhook delivery {} dead-lettered after {} attempts",
+                    delivery.id, delivery.attempts
+                );
+            }
+            return Ok(delivery);
+        }
+
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+/// Log an event delivery for a webhook
+async fn log_delivery(
+    database: &Database,
+    webhook: &Webhook,
+    event: &WebhookEvent,
+) -> Result<WebhookDelivery, sqlx::Error> {
+    let payload = serde_json::to_string(event).unwrap_or_default();
+    database
+        .create_webhook_delivery(webhook.id, event.id, &event.event_type, &payload)
+        .await
+}
+
+/// Notify a user's subscribed webhooks about an event, in the background
+pub fn dispatch(state: &AppState, user_hash: &UserHash, event_type: &str, data: Value) {
+    let database = state.database.clone();
+    let max_attempts = state.webhook_max_attempts;
+    let user_hash = user_hash.clone();
+    let event = WebhookEvent::new(event_type, data);
+
+    tokio::spawn(async move {
+        let webhooks = match database
+            .get_webhooks_for_event(&user_hash, &event.event_type)
+            .await
+        {
+            Ok(webhooks) => webhooks,
+            Err(err) => {
+                error!("Failed to load webhooks for user {}: {}", user_hash, err);
+                return;
+            }
+        };
+
+        for webhook in webhooks {
+            let database = database.clone();
+            let event = event.clone();
+            tokio::spawn(async move {
+                let result = match log_delivery(&database, &webhook, &event).await {
+                    Ok(delivery) => {
+                        run_delivery(&database, &webhook, delivery, max_attempts, true).await
+                    }
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    error!("Failed to log delivery for webhook {}: {}", webhook.id, err);
+                }
+            });
+        }
+    });
+}
+
+/// Validate requested event types
+fn validate_event_types(event_types: &[String]) -> Result<(), String> {
+    match event_types
+        .iter()
+        .find(|t| !EVENT_TYPES.contains(&t.as_str()))
+    {
+        Some(unknown) => Err(format!(
+            "Unknown event type '{}' (expected one of: {})",
+            unknown,
+            EVENT_TYPES.join(", ")
+        )),
+        None => Ok(()),
+    }
+}
+
+// Request/Response types
+
+#[derive(Deserialize)]
+pub struct CreateWebhookRequest {
+    url: String,
+    #[serde(default)]
+    event_types: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct WebhookResponse {
+    id: Uuid,
+    url: String,
+    event_types: Vec<String>,
+    created_at: String,
+    updated_at: String,
+    /// Only returned when the webhook is created or its secret rotated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+impl WebhookResponse {
+    fn new(webhook: Webhook, include_secret: bool) -> Self {
+        Self {
+            id: webhook.id,
+            url: webhook.url,
+            event_types: webhook.event_types,
+            created_at: webhook.created_at.to_rfc3339(),
+            updated_at: webhook.updated_at.to_rfc3339(),
+            secret: include_secret.then_some(webhook.secret),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct WebhookListResponse {
+    webhooks: Vec<WebhookResponse>,
+}
+
+#[derive(Deserialize)]
+pub struct DeliveryListQuery {
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct DeliveryResponse {
+    id: Uuid,
+    event_id: Uuid,
+    event_type: String,
+    status: String,
+    attempts: i32,
+    last_status_code: Option<i32>,
+    last_latency_ms: Option<i32>,
+    last_error: Option<String>,
+    created_at: String,
+    updated_at: String,
+}
+
+impl From<WebhookDelivery> for DeliveryResponse {
+    fn from(delivery: WebhookDelivery) -> Self {
+        Self {
+            id: delivery.id,
+            event_id: delivery.event_id,
+            event_type: delivery.event_type,
+            status: delivery.status,
+            attempts: delivery.attempts,
+            last_status_code: delivery.last_status_code,
+            last_latency_ms: delivery.last_latency_ms,
+            last_error: delivery.last_error,
+            created_at: delivery.created_at.to_rfc3339(),
+            updated_at: delivery.updated_at.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct DeliveryListResponse {
+    deliveries: Vec<DeliveryResponse>,
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.into()
+        })),
+    )
+}
+
+fn not_found() -> ApiError {
+    api_error(StatusCode::NOT_FOUND, "Webhook not found")
+}
+
+// Handler implementations
+
+/// List the caller's webhooks
+pub async fn list_webhooks(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+) -> Result<Json<WebhookListResponse>, ApiError> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+
+    match state.database.get_user_webhooks(&user_hash).await {
+        Ok(webhooks) => Ok(Json(WebhookListResponse {
+            webhooks: webhooks
+                .into_iter()
+                .map(|w| WebhookResponse::new(w, false))
+                .collect(),
+        })),
+        Err(err) => {
+            error!("Failed to list webhooks: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to list webhooks",
+            ))
+        }
+    }
+}
+
+/// Register a new webhook for the caller
+pub async fn create_webhook(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Json(request): Json<CreateWebhookRequest>,
+) -> Result<(StatusCode, Json<WebhookResponse>), ApiError> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+
+    outbound::validate_url(&request.url).map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+    validate_event_types(&request.event_types)
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+
+    // Hold the user's lock so concurrent requests can't exceed the limit
+    let _guard = state.user_locks.lock(&user_hash).await;
+    let existing = state
+        .database
+        .get_user_webhooks(&user_hash)
+        .await
+        .map_err(|err| {
+            error!("Failed to count webhooks: {}", err);
+            api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to create webhook",
+            )
+        })?;
+    if existing.len() >= MAX_WEBHOOKS_PER_USER {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!("Webhook limit reached ({} per user)", MAX_WEBHOOKS_PER_USER),
+        ));
+    }
+
+    match state
+        .database
+        .create_webhook(
+            &user_hash,
+            &request.url,
+            &request.event_types,
+            &generate_secret(),
+        )
+        .await
+    {
+        Ok(webhook) => Ok((
+            StatusCode::CREATED,
+            Json(WebhookResponse::new(webhook, true)),
+        )),
+        Err(err) => {
+            error!("Failed to create webhook: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to create webhook",
+            ))
+        }
+    }
+}
+
+/// Delete one of the caller's webhooks
+pub async fn delete_webhook(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+
+    match state.database.delete_webhook(&user_hash, id).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(not_found()),
+        Err(err) => {
+            error!("Failed to delete webhook: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to delete webhook",
+            ))
+        }
+    }
+}
+
+/// Replace the signing secret of one of the caller's webhooks
+pub async fn rotate_webhook_secret(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<WebhookResponse>, ApiError> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+
+    match state
+        .database
+        .rotate_webhook_secret(&user_hash, id, &generate_secret())
+        .await
+    {
+        Ok(Some(webhook)) => Ok(Json(WebhookResponse::new(webhook, true))),
+        Ok(None) => Err(not_found()),
+        Err(err) => {
+            error!("Failed to rotate webhook secret: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to rotate webhook secret",
+            ))
+        }
+    }
+}
+
+/// Look up one of the caller's webhooks
+async fn find_user_webhook(
+    state: &AppState,
+    user_hash: &UserHash,
+    id: Uuid,
+) -> Result<Webhook, ApiError> {
+    match state.database.get_user_webhook(user_hash, id).await {
+        Ok(Some(webhook)) => Ok(webhook),
+        Ok(None) => Err(not_found()),
+        Err(err) => {
+            error!("Failed to get webhook: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to retrieve webhook",
+            ))
+        }
+    }
+}
+
+/// Send a test event to one of the caller's webhooks and report the outcome
+pub async fn test_webhook(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<DeliveryResponse>, ApiError> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    let webhook = find_user_webhook(&state, &user_hash, id).await?;
+
+    let event = WebhookEvent::new(
+        "test",
+        json!({ "message": "This is a test event from peerlab-gateway" }),
+    );
+
+    let result = match log_delivery(&state.database, &webhook, &event).await {
+        Ok(delivery) => run_delivery(&state.database, &webhook, delivery, 1, false).await,
+        Err(err) => Err(err),
+    };
+
+    result.map(|d| Json(d.into())).map_err(|err| {
+        error!("Failed to send test event: {}", err);
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to send test event",
+        )
+    })
+}
+
+/// List the most recent deliveries of one of the caller's webhooks
+pub async fn list_deliveries(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<DeliveryListQuery>,
+) -> Result<Json<DeliveryListResponse>, ApiError> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    let webhook = find_user_webhook(&state, &user_hash, id).await?;
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+
+    match state
+        .database
+        .get_webhook_deliveries(webhook.id, limit)
+        .await
+    {
+        Ok(deliveries) => Ok(Json(DeliveryListResponse {
+            deliveries: deliveries.into_iter().map(|d| d.into()).collect(),
+        })),
+        Err(err) => {
+            error!("Failed to list webhook deliveries: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to list deliveries",
+            ))
+        }
+    }
+}
+
+/// Manually redeliver a logged event (a single attempt, typically for dead-lettered deliveries)
+pub async fn redeliver(
+    Extension(auth_info): Extension<jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Path((id, delivery_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<DeliveryResponse>, ApiError> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    let webhook = find_user_webhook(&state, &user_hash, id).await?;
+
+    let delivery = match state
+        .database
+        .get_webhook_delivery(webhook.id, delivery_id)
+        .await
+    {
+        Ok(Some(delivery)) => delivery,
+        Ok(None) => return Err(api_error(StatusCode::NOT_FOUND, "Delivery not found")),
+        Err(err) => {
+            error!("Failed to get webhook delivery: {}", err);
+            return Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to retrieve delivery",
+            ));
+        }
+    };
+
+    match run_delivery(&state.database, &webhook, delivery, 1, false).await {
+        Ok(delivery) => Ok(Json(delivery.into())),
+        Err(err) => {
+            error!("Failed to redeliver webhook event: {}", err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to redeliver event",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_payload() {
+        // Reference value: printf '1700000000.{}' | openssl dgst -sha256 -hmac secret
+        assert_eq!(
+            sign_payload("secret", 1700000000, "{}"),
+            "sha256=b8569b78799ff9e3cbff0fc2d63a33a2b57f3282abd07c37ae5e8e7d79a5f163"
+        );
+    }
+
+    #[test]
+    fn test_validate_webhook_input() {
+        assert!(outbound::validate_url("https://example.com/hook").is_ok());
+        assert!(outbound::validate_url("ftp://example.com/hook").is_err());
+        assert!(outbound::validate_url("not a url").is_err());
+        assert!(outbound::validate_url("http://10.0.0.1/hook").is_err());
+
+        assert!(validate_event_types(&["prefix.leased".to_string()]).is_ok());
+        assert!(validate_event_types(&[]).is_ok());
+        assert!(validate_event_types(&["prefix.deleted".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_generated_secrets_are_unique() {
+        let a = generate_secret();
+        assert!(a.starts_with("whsec_"));
+        assert_eq!(a.len(), 70);
+        assert_ne!(a, generate_secret());
+    }
+}
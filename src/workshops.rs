@@ -0,0 +1,721 @@
+//! Workshop slots pre-provisioned ahead of a course.
+//!
+//! On the first day of a workshop, every student requests an ASN and a
+//! prefix within minutes. `POST /admin/workshops` sets the resources aside
+//! beforehand instead: each slot gets an ASN and leases scheduled for the
+//! course, held by a placeholder user and bound to an invite code. A student
+//! redeeming their code with `POST /api/user/redeem` takes over the slot's
+//! ASN and leases, like an admin transfer.
+
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, Duration, SubsecRound, Utc};
+use ipnet::Ipv6Net;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::str::FromStr;
+use tracing::{Span, debug, error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::database::{self, InviteCode, LeaseOptions, PrefixLease, WorkshopSlot};
+use crate::events::{
+    AgentEvent, EVENT_ASN_ASSIGNED, EVENT_INVALIDATE, EVENT_LEASE_CREATED, EventPriority,
+};
+use crate::messages;
+use crate::pool_asns::AsnPool;
+use crate::pool_prefixes::{PrefixClass, PrefixPool};
+use crate::types::{Asn, Prefix, UserHash};
+use crate::{AppState, MAX_LEASE_ATTEMPTS, MAX_PREFIX_COUNT, MAX_RESERVATION_DAYS};
+
+/// Most slots a workshop provisions at once
+const MAX_SLOTS: u32 = 1_000;
+
+/// Longest workshop name, in characters
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Characters of invite codes (Crockford base32, no look-alikes)
+const CODE_ALPHABET: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Characters of an invite code, in groups of four
+const CODE_LENGTH: usize = 12;
+
+#[derive(Deserialize)]
+pub struct CreateWorkshopRequest {
+    /// Name of the workshop, e.g. `ripe-ws-2026`
+    name: String,
+    /// Number of slots (students)
+    slots: u32,
+    /// When the leases start (now when omitted)
+    #[serde(default)]
+    start_time: Option<DateTime<Utc>>,
+    /// How long the leases last
+    duration_minutes: i64,
+    /// Prefixes leased to each slot
+    #[serde(default = "default_leases_per_slot")]
+    leases_per_slot: usize,
+    /// Routability class of the leased prefixes (any class when omitted)
+    #[serde(default)]
+    class: Option<PrefixClass>,
+    /// Sites where the prefixes may be announced (every site when omitted)
+    #[serde(default)]
+    sites: Option<Vec<String>>,
+    /// When unredeemed codes expire (the end of the leases when omitted)
+    #[serde(default)]
+    codes_expire_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct RedeemRequest {
+    /// Invite code handed out for a workshop slot
+    code: String,
+}
+
+fn default_leases_per_slot() -> usize {
+    1
+}
+
+/// A checked workshop request
+#[derive(Debug)]
+struct Plan {
+    name: String,
+    slots: u32,
+    start_time: DateTime<Utc>,
+    duration: Duration,
+    leases_per_slot: usize,
+    class: Option<PrefixClass>,
+    sites: Option<Vec<String>>,
+    codes_expire_at: DateTime<Utc>,
+}
+
+impl Plan {
+    fn end_time(&self) -> DateTime<Utc> {
+        self.start_time + self.duration
+    }
+}
+
+type ApiError = (StatusCode, Json<Value>);
+
+fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(json!({
+            "error": status.as_u16(),
+            "message": message.into()
+        })),
+    )
+}
+
+/// Generate a new invite code, e.g. `7KQ2-M9XD-4HTB`
+pub fn generate_code() -> String {
+    // Hashed so the version bits of the UUID don't show through
+    let random = Sha256::digest(Uuid::new_v4().as_bytes());
+    let mut code = String::with_capacity(CODE_LENGTH + CODE_LENGTH / 4);
+    for (i, byte) in random[..CODE_LENGTH].iter().enumerate() {
+        if i > 0 && i % 4 == 0 {
+            code.push('-');
+        }
+        code.push(CODE_ALPHABET[(byte % 32) as usize] as char);
+    }
+    code
+}
+
+/// Codes are stored hashed, and match whatever their case and grouping
+pub(crate) fn hash_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+/// Placeholder user holding the resources of a slot until it is redeemed
+fn slot_user_hash() -> UserHash {
+    let digest = Sha256::digest(format!("workshop-slot:{}", Uuid::new_v4()).as_bytes());
+    UserHash::from_digest(hex::encode(digest))
+}
+
+/// Check a workshop request against the limits clients are held to
+fn plan(state: &AppState, request: CreateWorkshopRequest) -> Result<Plan, String> {
+    let name = request.name.trim();
+    if name.is_empty()
+        || name.chars().count() > MAX_NAME_LENGTH
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(format!(
+            "name must be 1 to {} letters, digits, '-', '_' or '.'",
+            MAX_NAME_LENGTH
+        ));
+    }
+    if !(1..=MAX_SLOTS).contains(&request.slots) {
+        return Err(format!("slots must be between 1 and {}", MAX_SLOTS));
+    }
+    let duration = Duration::try_minutes(request.duration_minutes)
+        .filter(|d| *d >= state.lease_min_duration && *d <= state.lease_max_duration)
+        .ok_or_else(|| {
+            format!(
+                "duration_minutes must be between {} and {}",
+                state.lease_min_duration.num_minutes(),
+                state.lease_max_duration.num_minutes()
+            )
+        })?;
+    if !(1..=MAX_PREFIX_COUNT).contains(&request.leases_per_slot) {
+        return Err(format!(
+            "leases_per_slot must be between 1 and {}",
+            MAX_PREFIX_COUNT
+        ));
+    }
+    if let Some(max_leases) = state.max_leases_per_user
+        && request.leases_per_slot > max_leases as usize
+    {
+        return Err(format!(
+            "leases_per_slot exceeds the lease quota ({} per user)",
+            max_leases
+        ));
+    }
+
+    let now = Utc::now();
+    let start_time = match request.start_time {
+        Some(start) if start > now + Duration::days(MAX_RESERVATION_DAYS) => {
+            return Err(format!(
+                "start_time must be within {} days",
+                MAX_RESERVATION_DAYS
+            ));
+        }
+        Some(start) => start.max(now).trunc_subsecs(6),
+        None => now,
+    };
+    let sites = match request.sites {
+        Some(sites) => Some(crate::validate_sites(state, sites)?),
+        None => None,
+    };
+    let codes_expire_at = request.codes_expire_at.unwrap_or(start_time + duration);
+    if codes_expire_at <= now {
+        return Err("codes_expire_at must be in the future".to_string());
+    }
+
+    Ok(Plan {
+        name: name.to_string(),
+        slots: request.slots,
+        start_time,
+        duration,
+        leases_per_slot: request.leases_per_slot,
+        class: request.class,
+        sites,
+        codes_expire_at,
+    })
+}
+
+/// Pick the ASN and prefixes of each slot the way the allocators would, in
+/// pool order and skipping assigned ASNs and leased prefixes. Fails with the
+/// number of slots the pools have room for when they run out.
+pub fn allocate(
+    slots: u32,
+    leases_per_slot: usize,
+    class: Option<PrefixClass>,
+    asn_pool: &AsnPool,
+    prefix_pool: &PrefixPool,
+    assigned_asns: &HashSet<i64>,
+    mut leased: Vec<Ipv6Net>,
+) -> Result<Vec<(Asn, Vec<Ipv6Net>)>, usize> {
+    let mut free_asns = asn_pool
+        .ranges()
+        .iter()
+        .flat_map(|&(start, end)| start..=end)
+        .filter(|asn| !assigned_asns.contains(asn))
+        .filter_map(|asn| Asn::try_from(asn).ok());
+
+    let mut allocated = Vec::with_capacity(slots as usize);
+    for _ in 0..slots {
+        let Some(asn) = free_asns.next() else {
+            return Err(allocated.len());
+        };
+        let mut prefixes = Vec::with_capacity(leases_per_slot);
+        while prefixes.len() < leases_per_slot {
+            let Some(prefix) = prefix_pool.find_available_prefix(&leased, class, None) else {
+                return Err(allocated.len());
+            };
+            leased.push(prefix);
+            prefixes.push(prefix);
+        }
+        allocated.push((asn, prefixes));
+    }
+    Ok(allocated)
+}
+
+fn lease_json(lease: &PrefixLease) -> Value {
+    json!({
+        "id": lease.id,
+        "prefix": lease.prefix,
+        "start_time": lease.start_time.to_rfc3339(),
+        "end_time": lease.end_time.to_rfc3339(),
+        "sites": lease.sites,
+    })
+}
+
+fn code_json(code: &InviteCode) -> Value {
+    json!({
+        "id": code.id,
+        "asn": code.asn,
+        "slot_user_hash": code.slot_user_hash,
+        "expires_at": code.expires_at.to_rfc3339(),
+        "redeemed_by": code.redeemed_by,
+        "redeemed_at": code.redeemed_at.map(|at| at.to_rfc3339()),
+        "created_at": code.created_at.to_rfc3339(),
+    })
+}
+
+/// Tell agents about the ASN and leases set aside for a slot
+fn publish_slot(state: &AppState, code: &InviteCode, leases: &[PrefixLease]) {
+    state.agent_events.publish(AgentEvent::new(
+        EVENT_ASN_ASSIGNED,
+        EventPriority::Normal,
+        json!({ "user_hash": code.slot_user_hash, "asn": code.asn }),
+    ));
+    for lease in leases {
+        state.agent_events.publish(
+            AgentEvent::new(
+                EVENT_LEASE_CREATED,
+                EventPriority::Normal,
+                json!({
+                    "id": lease.id,
+                    "user_hash": lease.user_hash,
+                    "prefix": lease.prefix,
+                    "start_time": lease.start_time.to_rfc3339(),
+                    "end_time": lease.end_time.to_rfc3339(),
+                }),
+            )
+            .at_sites(lease.sites.clone()),
+        );
+    }
+}
+
+// Handlers
+
+/// Provision the slots of a workshop, all or none of them, returning their
+/// invite codes (admin API). Codes are only shown here.
+#[instrument(name = "handler", skip_all, fields(operation = "create_workshop"))]
+pub async fn create_workshop(
+    State(state): State<AppState>,
+    Json(request): Json<CreateWorkshopRequest>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let plan = plan(&state, request).map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+    let internal_error = || {
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to provision workshop",
+        )
+    };
+
+    // Prefixes leased during the workshop, or whose grace period reaches into it
+    let grace = state.lease_grace_period;
+    let options = LeaseOptions {
+        sites: plan.sites.clone(),
+        auto_renew: false,
+        label: Some(plan.name.clone()),
+        purpose: None,
+    };
+    let mut attempt = 1;
+    let (created, codes) = loop {
+        let (mappings, leases) = tokio::try_join!(
+            state.database.get_all_asn_mappings(),
+            state
+                .database
+                .get_leases_during(plan.start_time - grace, plan.end_time() + grace),
+        )
+        .map_err(|err| {
+            error!(
+                "Failed to load allocations for workshop {}: {}",
+                plan.name, err
+            );
+            internal_error()
+        })?;
+        let assigned: HashSet<i64> = mappings.iter().map(|mapping| mapping.asn).collect();
+        let leased = leases
+            .iter()
+            .filter_map(|lease| Ipv6Net::from_str(&lease.prefix).ok())
+            .collect();
+
+        let allocated = allocate(
+            plan.slots,
+            plan.leases_per_slot,
+            plan.class,
+            &state.asn_pool.load(),
+            &state.prefix_pool.load(),
+            &assigned,
+            leased,
+        )
+        .map_err(|room| {
+            api_error(
+                StatusCode::CONFLICT,
+                format!(
+                    "The pools only have room for {} of the {} slots",
+                    room, plan.slots
+                ),
+            )
+        })?;
+
+        let codes: Vec<String> = allocated.iter().map(|_| generate_code()).collect();
+        let slots: Vec<WorkshopSlot> = allocated
+            .into_iter()
+            .zip(&codes)
+            .map(|((asn, prefixes), code)| WorkshopSlot {
+                user_hash: slot_user_hash(),
+                code_hash: hash_code(code),
+                asn,
+                prefixes: prefixes.into_iter().map(Prefix::from).collect(),
+            })
+            .collect();
+        let result = state
+            .database
+            .create_workshop_slots(
+                &plan.name,
+                &slots,
+                plan.start_time,
+                plan.duration,
+                &options,
+                plan.codes_expire_at,
+            )
+            .await;
+        match result {
+            Err(err)
+                if (database::is_lease_conflict(&err) || database::is_asn_conflict(&err))
+                    && attempt < MAX_LEASE_ATTEMPTS =>
+            {
+                warn!("A selected ASN or prefix was taken concurrently, selecting again");
+                attempt += 1;
+            }
+            Ok(created) => break (created, codes),
+            Err(err) => {
+                error!("Failed to provision workshop {}: {}", plan.name, err);
+                return Err(internal_error());
+            }
+        }
+    };
+
+    info!(
+        "Provisioned {} slots of workshop {} from {} to {}",
+        created.len(),
+        plan.name,
+        plan.start_time,
+        plan.end_time()
+    );
+    let mut slots = Vec::with_capacity(created.len());
+    for ((code, leases), plaintext) in created.iter().zip(codes) {
+        publish_slot(&state, code, leases);
+        let mut slot = code_json(code);
+        slot["code"] = json!(plaintext);
+        slot["leases"] = json!(leases.iter().map(lease_json).collect::<Vec<_>>());
+        slots.push(slot);
+    }
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "workshop": plan.name,
+            "start_time": plan.start_time.to_rfc3339(),
+            "end_time": plan.end_time().to_rfc3339(),
+            "codes_expire_at": plan.codes_expire_at.to_rfc3339(),
+            "slots": slots,
+        })),
+    ))
+}
+
+/// List the slots of a workshop and whether they were redeemed (admin API)
+pub async fn get_workshop(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    match state.database.get_workshop_invite_codes(&name).await {
+        Ok(codes) if codes.is_empty() => {
+            Err(api_error(StatusCode::NOT_FOUND, "Workshop not found"))
+        }
+        Ok(codes) => Ok(Json(json!({
+            "workshop": name,
+            "redeemed": codes.iter().filter(|code| code.redeemed_at.is_some()).count(),
+            "slots": codes.iter().map(code_json).collect::<Vec<_>>(),
+        }))),
+        Err(err) => {
+            error!("Failed to get workshop {}: {}", name, err);
+            Err(api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to get workshop",
+            ))
+        }
+    }
+}
+
+/// Give the unredeemed slots of a workshop back to the pools, e.g. after the
+/// course (admin API). Redeemed slots belong to their students and are kept.
+#[instrument(name = "handler", skip_all, fields(operation = "delete_workshop"))]
+pub async fn delete_workshop(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let (codes, leases) = state
+        .database
+        .delete_workshop_slots(&name)
+        .await
+        .map_err(|err| {
+            error!("Failed to release workshop {}: {}", name, err);
+            api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to release workshop",
+            )
+        })?;
+    if codes.is_empty() {
+        return Err(api_error(
+            StatusCode::NOT_FOUND,
+            "Workshop not found or every slot redeemed",
+        ));
+    }
+
+    info!(
+        "Released {} unredeemed slots of workshop {} ({} leases ended)",
+        codes.len(),
+        name,
+        leases.len()
+    );
+    for code in &codes {
+        state.agent_events.publish(AgentEvent::new(
+            EVENT_INVALIDATE,
+            EventPriority::High,
+            json!({
+                "resource": "asn",
+                "user_hash": code.slot_user_hash,
+                "asn": code.asn,
+                "reason": "workshop slot released",
+            }),
+        ));
+    }
+    for lease in &leases {
+        state.agent_events.publish(
+            AgentEvent::new(
+                EVENT_INVALIDATE,
+                EventPriority::High,
+                json!({
+                    "resource": "prefix",
+                    "id": lease.id,
+                    "user_hash": lease.user_hash,
+                    "prefix": lease.prefix,
+                    "reason": "workshop slot released",
+                }),
+            )
+            .at_sites(lease.sites.clone()),
+        );
+    }
+    Ok(Json(json!({
+        "workshop": name,
+        "released_slots": codes.len(),
+        "ended_leases": leases.len(),
+    })))
+}
+
+/// Take over the ASN and leases of the workshop slot an invite code is
+/// bound to. Each code is redeemed once, before it expires.
+#[instrument(name = "handler", skip_all, fields(operation = "redeem_invite_code", asn = tracing::field::Empty))]
+pub async fn redeem_invite_code(
+    Extension(auth_info): Extension<crate::jwt::AuthInfo>,
+    State(state): State<AppState>,
+    Json(request): Json<RedeemRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let user_hash = state.identity.user_hash(&auth_info.identity);
+    crate::verify_account(&state, &auth_info).await?;
+    let internal_error = || {
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            messages::INVITE_REDEEM_FAILED.text(&[]),
+        )
+    };
+    let already_redeemed = || {
+        api_error(
+            StatusCode::CONFLICT,
+            messages::INVITE_ALREADY_REDEEMED.text(&[]),
+        )
+    };
+
+    let code = match state
+        .database
+        .get_invite_code(&hash_code(&request.code))
+        .await
+    {
+        Ok(Some(code)) => code,
+        Ok(None) => {
+            return Err(api_error(
+                StatusCode::NOT_FOUND,
+                messages::INVITE_INVALID.text(&[]),
+            ));
+        }
+        Err(err) => {
+            error!("Failed to get invite code: {}", err);
+            return Err(internal_error());
+        }
+    };
+    match &code.redeemed_by {
+        // A retried redemption gets the slot it already took over
+        Some(redeemed_by) if *redeemed_by == user_hash => {
+            debug!(
+                "User {} already redeemed invite code {}",
+                user_hash, code.id
+            );
+            return Ok(Json(json!({
+                "workshop": code.workshop,
+                "asn": code.asn,
+                "message": messages::INVITE_ALREADY_REDEEMED.text(&[]),
+            })));
+        }
+        Some(_) => return Err(already_redeemed()),
+        None if code.expires_at <= Utc::now() => {
+            return Err(api_error(
+                StatusCode::GONE,
+                messages::INVITE_EXPIRED.text(&[]),
+            ));
+        }
+        None => {}
+    }
+
+    // Serialize with the user's ASN requests, so the quota holds
+    let _guard = state.user_locks.lock(&user_hash).await;
+    let held = state
+        .database
+        .get_user_asns(&user_hash)
+        .await
+        .map_err(|err| {
+            error!("Failed to get ASNs of user {}: {}", user_hash, err);
+            internal_error()
+        })?;
+    if held.len() >= state.max_asns_per_user as usize {
+        let held = held.iter().map(|mapping| mapping.asn).collect();
+        return Err(crate::asn_quota_exceeded(&state, held));
+    }
+
+    let (code, mapping, leases) = match state
+        .database
+        .redeem_invite_code(code.id, &user_hash, Some(&auth_info.sub))
+        .await
+    {
+        Ok(Some(redeemed)) => redeemed,
+        Ok(None) => return Err(already_redeemed()),
+        Err(err) => {
+            error!("Failed to redeem invite code {}: {}", code.id, err);
+            return Err(internal_error());
+        }
+    };
+
+    Span::current().record("asn", mapping.asn);
+    info!(
+        "User {} redeemed invite code {} of workshop {}: ASN {} and {} leases",
+        user_hash,
+        code.id,
+        code.workshop,
+        mapping.asn,
+        leases.len()
+    );
+    let reason = format!("invite code of workshop {} redeemed", code.workshop);
+    state.agent_events.publish(AgentEvent::new(
+        EVENT_INVALIDATE,
+        EventPriority::High,
+        json!({
+            "resource": "asn",
+            "user_hash": code.slot_user_hash,
+            "asn": mapping.asn,
+            "reason": reason,
+        }),
+    ));
+    state.agent_events.publish(AgentEvent::new(
+        EVENT_ASN_ASSIGNED,
+        EventPriority::Normal,
+        json!({ "user_hash": user_hash, "asn": mapping.asn }),
+    ));
+    for lease in &leases {
+        crate::transfers::notify_lease_transfer(&state, lease, &code.slot_user_hash, &reason);
+    }
+
+    Ok(Json(json!({
+        "workshop": code.workshop,
+        "asn": mapping.asn,
+        "leases": leases.iter().map(lease_json).collect::<Vec<_>>(),
+        "message": messages::INVITE_REDEEMED.text(&[]),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes() {
+        let code = generate_code();
+        assert_eq!(code.len(), 14);
+        assert_eq!(code.matches('-').count(), 2);
+        assert_ne!(code, generate_code());
+
+        // Case and grouping don't matter
+        assert_eq!(hash_code("7KQ2-M9XD-4HTB"), hash_code("7kq2m9xd4htb"));
+        assert_eq!(hash_code("7KQ2-M9XD-4HTB"), hash_code(" 7KQ2 M9XD 4HTB "));
+        assert_ne!(hash_code("7KQ2-M9XD-4HTB"), hash_code("7KQ2-M9XD-4HTC"));
+    }
+
+    #[test]
+    fn test_allocate() {
+        let asn_pool = AsnPool::new(65000, 65003);
+        let prefix_pool = PrefixPool::new(
+            (1..=6)
+                .map(|i| format!("2001:db8:{}::/48", i).parse().unwrap())
+                .collect(),
+        );
+        let assigned = HashSet::from([65001]);
+        let leased = vec!["2001:db8:1::/48".parse().unwrap()];
+
+        let allocated = allocate(
+            2,
+            2,
+            None,
+            &asn_pool,
+            &prefix_pool,
+            &assigned,
+            leased.clone(),
+        )
+        .unwrap();
+        let asns: Vec<i64> = allocated.iter().map(|(asn, _)| asn.get()).collect();
+        assert_eq!(asns, [65000, 65002]);
+        let prefixes: Vec<String> = allocated
+            .iter()
+            .flat_map(|(_, prefixes)| prefixes.iter().map(|p| p.to_string()))
+            .collect();
+        assert_eq!(
+            prefixes,
+            [
+                "2001:db8:2::/48",
+                "2001:db8:3::/48",
+                "2001:db8:4::/48",
+                "2001:db8:5::/48"
+            ]
+        );
+
+        // 3 free ASNs, but prefixes for only 2 slots of 2 leases
+        assert_eq!(
+            allocate(
+                3,
+                2,
+                None,
+                &asn_pool,
+                &prefix_pool,
+                &assigned,
+                leased.clone()
+            ),
+            Err(2)
+        );
+        // 3 free ASNs for 4 slots
+        assert_eq!(
+            allocate(4, 1, None, &asn_pool, &prefix_pool, &assigned, leased),
+            Err(3)
+        );
+    }
+}
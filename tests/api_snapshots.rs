@@ -0,0 +1,1195 @@
+//! Golden-file snapshots of API responses.
+//!
+//! The full router runs with the in-memory agent store and a database handle
+//! pointing at a closed port, so every endpoint can be exercised without
+//! PostgreSQL: validation and auth errors come from the handlers themselves and
+//! database-backed paths produce their error responses. Router-facing exports
+//! are snapshotted from fixed leases, and the success bodies of the user and
+//! mapping endpoints from a seeded in-memory store.
+//!
+//! Review changes with `cargo insta review` (or `INSTA_UPDATE=always cargo test`).
+
+use axum_test::{TestResponse, TestServer};
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use insta::{assert_json_snapshot, assert_snapshot};
+use serde_json::{Value, json};
+use std::{io::Write, sync::Arc, time::Duration};
+use uuid::Uuid;
+
+use peerlab_gateway::{
+    AppMode, AppState,
+    agent::{AgentInfo, AgentKeys},
+    create_app, create_app_for_mode, create_client_app,
+    database::{
+        AnnouncementPermission, Database, DatabaseConfig, MappingAnnotation,
+        PERMISSION_SOURCE_LEASE, PrefixLease, UserAsnMapping,
+    },
+    export, hash_user_identifier,
+    identity::IdentityMapping,
+    jwt::{self, AuthErrorReason, AuthInfo, AuthorizationError, Claims, TokenValidator},
+    messages,
+    pool_asns::AsnPool,
+    pool_prefixes::PrefixPool,
+    problem::{ErrorFormat, PROBLEM_JSON},
+    public_stats::PublicStats,
+    reload::Reloadable,
+    store::MemoryStore,
+    token_cache::TokenCache,
+    types::UserHash,
+};
+
+const AGENT_KEY: &str = "test-agent-key";
+const ADMIN_KEY: &str = "test-admin-key";
+
+// RSA public key from RFC 7515 appendix A.2
+const TEST_JWKS: &str = r#"{"keys": [{"kid": "test", "kty": "RSA", "e": "AQAB", "n": "ofgWCuLjybRlzo0tZWJjNiuSfb4p4fAkd_wWJcyQoTbji9k0l8W26mPddxHmfHQp-Vaw-4qPCJrcS2mJPMEzP1Pt0Bm4d4QlL-yRT-SFd2lZS-pCgNMsD1W_YpRPEwOWvG6b32690r2jZ47soMZo9wGzjb_7OMg0LOL-bSf63kpaSHSXndS5z5rexMdbBYUsLA9e-KXBdQOS-UTo7WTBEMa2R2CapHg665xsmtdVMTBQY4uDZlxvb3qCo5ZwKh9kG4LT6_I5IhlJH7aGhyxXFvUK-DWNmoudF8NAco9_h9iaGNj8q2ethFkMLs91kzk2PAcDTW9gb54h4FRWyuXpoQ"}]}"#;
+
+fn test_state(bypass_jwt: bool, admin_key: Option<&str>) -> AppState {
+    let mut pool_file = tempfile::NamedTempFile::new().unwrap();
+    writeln!(pool_file, "2001:db8:1000::/48\n2001:db8:1001::/48").unwrap();
+
+    // Nothing listens on port 1: database calls fail with a connection error
+    let config = DatabaseConfig::new("postgresql://127.0.0.1:1/none".into())
+        .with_acquire_timeout(Duration::from_millis(100));
+    let database = Database::connect_lazy(&config).unwrap();
+
+    let mut builder = AppState::builder()
+        .database(database)
+        .agent_key(AGENT_KEY)
+        .asn_pool(AsnPool::new(65000, 65009))
+        .prefix_pool(PrefixPool::from_file(pool_file.path()).unwrap())
+        .bypass_jwt_validation(bypass_jwt)
+        .token_cache(TokenCache::new(0))
+        .webhook_max_attempts(1);
+    if let Some(admin_key) = admin_key {
+        builder = builder.admin_key(admin_key);
+    }
+    builder.build().unwrap()
+}
+
+fn server(bypass_jwt: bool) -> TestServer {
+    TestServer::new(create_app(test_state(bypass_jwt, Some(ADMIN_KEY)))).unwrap()
+}
+
+/// Status code, auth challenge and JSON body of a response
+fn snapshot(response: TestResponse) -> Value {
+    let challenge = response
+        .maybe_header("www-authenticate")
+        .map(|h| h.to_str().unwrap().to_string());
+    json!({
+        "status": response.status_code().as_u16(),
+        "www_authenticate": challenge,
+        "body": response.json::<Value>(),
+    })
+}
+
+#[tokio::test]
+async fn client_api_auth_errors() {
+    let mut jwks_file = tempfile::NamedTempFile::new().unwrap();
+    write!(jwks_file, "{}", TEST_JWKS).unwrap();
+
+    let mut state = test_state(false, Some(ADMIN_KEY));
+    state.jwks_file = Some(jwks_file.path().to_string_lossy().into_owned());
+    let with_jwks = TestServer::new(create_app(state)).unwrap();
+
+    assert_json_snapshot!(
+        "client_missing_token",
+        snapshot(with_jwks.get("/api/user/info").await)
+    );
+    assert_json_snapshot!(
+        "client_malformed_token",
+        snapshot(
+            with_jwks
+                .get("/api/user/info")
+                .authorization_bearer("not-a-jwt")
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "client_impersonation_read_only",
+        snapshot(
+            with_jwks
+                .post("/api/user/asn")
+                .add_header("x-impersonation-token", "imp_test")
+                .await
+        )
+    );
+}
+
+#[cfg(feature = "abuse")]
+#[tokio::test]
+async fn abuse_reports() {
+    let mut state = test_state(false, None);
+    state.abuse_reports = peerlab_gateway::abuse::AbuseReports::new(1);
+    let server = TestServer::new(create_app(state)).unwrap();
+
+    // Public, but rate limited per client address
+    assert_json_snapshot!(
+        "abuse_report_invalid_prefix",
+        snapshot(
+            server
+                .post("/api/abuse-report")
+                .json(&json!({ "prefix": "192.0.2.0/24", "description": "Hijack" }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "abuse_report_rate_limited",
+        snapshot(
+            server
+                .post("/api/abuse-report")
+                .json(&json!({ "prefix": "2001:db8:1::/48", "description": "Hijack" }))
+                .await
+        )
+    );
+}
+
+#[tokio::test]
+async fn public_stats() {
+    let mut state = test_state(false, None);
+    state.public_stats = PublicStats::new(1, Duration::from_secs(60));
+    let server = TestServer::new(create_app(state)).unwrap();
+
+    // Public, but rate limited per client address
+    assert_json_snapshot!(
+        "public_stats_database_error",
+        snapshot(server.get("/api/public/stats").await)
+    );
+    assert_json_snapshot!(
+        "public_stats_rate_limited",
+        snapshot(server.get("/api/public/stats").await)
+    );
+}
+
+#[tokio::test]
+async fn deprecations() {
+    // Public, like the stats
+    let server = server(false);
+    assert_json_snapshot!(
+        "deprecations",
+        snapshot(server.get("/api/deprecations").await)
+    );
+}
+
+#[tokio::test]
+async fn client_api_responses() {
+    let server = server(true);
+
+    assert_json_snapshot!(
+        "user_info_database_error",
+        snapshot(server.get("/api/user/info").await)
+    );
+    assert_json_snapshot!(
+        "user_asn_database_error",
+        snapshot(server.post("/api/user/asn").await)
+    );
+    assert_json_snapshot!(
+        "user_asn_release_database_error",
+        snapshot(server.delete("/api/user/asn").await)
+    );
+    assert_json_snapshot!(
+        "user_asns_database_error",
+        snapshot(server.get("/api/user/asns").await)
+    );
+    assert_json_snapshot!(
+        "user_redeem_database_error",
+        snapshot(
+            server
+                .post("/api/user/redeem")
+                .json(&json!({ "code": "7KQ2-M9XD-4HTB" }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_asn_update_database_error",
+        snapshot(
+            server
+                .patch("/api/user/asn")
+                .json(&json!({ "description": "Route leak experiment" }))
+                .await
+        )
+    );
+    // Validated before the database is queried
+    assert_json_snapshot!(
+        "user_asn_update_invalid_abuse_contact",
+        snapshot(
+            server
+                .patch("/api/user/asn")
+                .json(&json!({ "abuse_contact": "noc at example.com" }))
+                .await
+        )
+    );
+    // Checked against the pool before the database is queried
+    assert_json_snapshot!(
+        "user_asn_not_in_pool",
+        snapshot(
+            server
+                .post("/api/user/asn")
+                .json(&json!({ "asn": 64512 }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_asn_invalid_idempotency_key",
+        snapshot(
+            server
+                .post("/api/user/asn")
+                .add_header("idempotency-key", "not a key")
+                .await
+        )
+    );
+    // Keys are claimed in the database before the request is processed
+    assert_json_snapshot!(
+        "user_asn_idempotency_database_error",
+        snapshot(
+            server
+                .post("/api/user/asn")
+                .add_header("idempotency-key", "retry-1")
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_lease_history_invalid_limit",
+        snapshot(server.get("/api/user/leases/history?limit=0").await)
+    );
+    assert_json_snapshot!(
+        "user_prefix_invalid_duration",
+        snapshot(
+            server
+                .post("/api/user/prefix")
+                .json(&json!({ "duration_hours": 48 }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_ambiguous_duration",
+        snapshot(
+            server
+                .post("/api/user/prefix")
+                .json(&json!({ "duration_hours": 1, "duration": "PT30M" }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_invalid_iso_duration",
+        snapshot(
+            server
+                .post("/api/user/prefix")
+                .json(&json!({ "duration": "P1M" }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_label_too_long",
+        snapshot(
+            server
+                .post("/api/user/prefix")
+                .json(&json!({ "duration_hours": 2, "label": "x".repeat(65) }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_invalid_prefix_len",
+        snapshot(
+            server
+                .post("/api/user/prefix")
+                .json(&json!({ "duration_hours": 2, "prefix_len": 60 }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_prefix_len_mismatch",
+        snapshot(
+            server
+                .post("/api/user/prefix")
+                .json(&json!({
+                    "duration_hours": 2,
+                    "prefix": "2001:db8:1000::/56",
+                    "prefix_len": 64
+                }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_permanent_with_duration",
+        snapshot(
+            server
+                .post("/api/user/prefix")
+                .json(&json!({ "duration_hours": 2, "permanent": true }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_roa_set_by_admins",
+        snapshot(
+            server
+                .put("/api/user/prefix/2001:db8:1000::%2F48/roa")
+                .json(&json!({ "max_length": 56 }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_invalid_site",
+        snapshot(
+            server
+                .post("/api/user/prefix")
+                .json(&json!({ "duration_hours": 2, "sites": ["AMS 1"] }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_database_error",
+        snapshot(
+            server
+                .post("/api/user/prefix")
+                .json(&json!({ "duration_hours": 2 }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_invalid_count",
+        snapshot(
+            server
+                .post("/api/user/prefix")
+                .json(&json!({ "duration_hours": 2, "count": 17 }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_start_in_past",
+        snapshot(
+            server
+                .post("/api/user/prefix")
+                .json(&json!({ "duration_hours": 2, "start_time": "2020-01-01T00:00:00Z" }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_outside_pool",
+        snapshot(
+            server
+                .post("/api/user/prefix")
+                .json(&json!({ "duration_hours": 2, "prefix": "2001:db8:2000::/48" }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_renew_invalid_duration",
+        snapshot(
+            server
+                .post("/api/user/prefix/2001:db8:1000::%2F48/renew")
+                .json(&json!({ "duration_hours": 0 }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "user_prefix_renew_invalid_prefix",
+        snapshot(
+            server
+                .post("/api/user/prefix/10.0.0.0%2F8/renew")
+                .json(&json!({ "duration_hours": 2 }))
+                .await
+        )
+    );
+    #[cfg(feature = "webhooks")]
+    assert_json_snapshot!(
+        "webhook_invalid_url",
+        snapshot(
+            server
+                .post("/api/user/webhooks")
+                .json(&json!({ "url": "ftp://example.com/hook" }))
+                .await
+        )
+    );
+    #[cfg(feature = "webhooks")]
+    assert_json_snapshot!(
+        "webhook_unknown_event",
+        snapshot(
+            server
+                .post("/api/user/webhooks")
+                .json(&json!({
+                    "url": "https://example.com/hook",
+                    "event_types": ["asn.revoked"]
+                }))
+                .await
+        )
+    );
+    #[cfg(feature = "webhooks")]
+    assert_json_snapshot!(
+        "webhook_list_database_error",
+        snapshot(server.get("/api/user/webhooks").await)
+    );
+}
+
+/// Every message of the client ASN, prefix, history and invite endpoints
+/// has a key: with a language translating all of them, none is left in English
+#[tokio::test]
+async fn client_messages_have_keys() {
+    let translations: serde_json::Map<String, Value> = messages::MESSAGES
+        .iter()
+        .map(|message| {
+            let placeholders = " {}".repeat(message.template.matches("{}").count());
+            (
+                message.key.to_string(),
+                json!(format!("[{}]{}", message.key, placeholders)),
+            )
+        })
+        .collect();
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    write!(file, "{}", json!({ "xx": translations })).unwrap();
+    let state = AppState {
+        messages: Reloadable::new(messages::Catalog::from_file(file.path()).unwrap()),
+        ..test_state(true, None)
+    };
+    // Without the failover layer, which answers for the handlers while the
+    // database is down
+    let app = axum::Router::new().nest("/api", create_client_app(state));
+    let server = TestServer::new(app).unwrap();
+
+    let prefix = |body: Value| server.post("/api/user/prefix").json(&body);
+    let requests = [
+        server.get("/api/user/info"),
+        server.get("/api/user/asns"),
+        server.get("/api/user/leases/history?limit=0"),
+        server.get(&format!("/api/user/leases/history?cursor={}", Uuid::nil())),
+        server.post("/api/user/asn"),
+        server.post("/api/user/asn").json(&json!({ "asn": 64512 })),
+        server
+            .post("/api/user/asn")
+            .add_header("idempotency-key", "not a key"),
+        server.delete("/api/user/asn"),
+        server
+            .patch("/api/user/asn")
+            .json(&json!({ "description": "Route leak experiment" })),
+        server
+            .patch("/api/user/asn")
+            .json(&json!({ "abuse_contact": "noc at example.com" })),
+        server
+            .post("/api/user/redeem")
+            .json(&json!({ "code": "7KQ2-M9XD-4HTB" })),
+        prefix(json!({ "duration_hours": 2 })),
+        prefix(json!({ "duration_hours": 48 })),
+        prefix(json!({ "duration_hours": 1, "duration": "PT30M" })),
+        prefix(json!({ "duration": "P1M" })),
+        prefix(json!({ "duration": "PT90S" })),
+        prefix(json!({ "duration_hours": 2, "permanent": true })),
+        prefix(json!({ "permanent": true, "auto_renew": true })),
+        prefix(json!({ "duration_hours": 2, "count": 17 })),
+        prefix(json!({ "duration_hours": 2, "start_time": "2020-01-01T00:00:00Z" })),
+        prefix(json!({ "duration_hours": 2, "start_time": "2999-01-01T00:00:00Z" })),
+        prefix(json!({ "duration_hours": 2, "label": "x".repeat(65) })),
+        prefix(json!({ "duration_hours": 2, "purpose": "a\u{7}b" })),
+        prefix(json!({ "duration_hours": 2, "sites": [] })),
+        prefix(json!({ "duration_hours": 2, "sites": ["AMS 1"] })),
+        prefix(json!({ "duration_hours": 2, "prefix_len": 60 })),
+        prefix(json!({ "duration_hours": 2, "prefix": "not a prefix" })),
+        prefix(json!({ "duration_hours": 2, "prefix": "2001:db8:1000::/40" })),
+        prefix(json!({ "duration_hours": 2, "prefix": "2001:db8:2000::/48" })),
+        prefix(json!({ "duration_hours": 2, "prefix": "2001:db8:1000::/48", "count": 2 })),
+        prefix(json!({ "duration_hours": 2, "prefix": "2001:db8:1000::/48", "class": "ula" })),
+        prefix(json!({
+            "duration_hours": 2,
+            "prefix": "2001:db8:1000::/56",
+            "prefix_len": 64
+        })),
+        server
+            .post("/api/user/prefix/10.0.0.0%2F8/renew")
+            .json(&json!({ "duration_hours": 2 })),
+        server
+            .post("/api/user/prefix/2001:db8:1000::%2F48/renew")
+            .json(&json!({ "duration_hours": 2 })),
+        server
+            .put("/api/user/prefix/2001:db8:1000::%2F48/roa")
+            .json(&json!({ "max_length": 56 })),
+    ];
+    for request in requests {
+        let response = request.add_header("accept-language", "xx").await;
+        let body = response.json::<Value>();
+        let message = body["message"].as_str().unwrap_or_default();
+        assert!(message.starts_with('['), "untranslated: {}", body);
+    }
+}
+
+#[tokio::test]
+async fn service_api_responses() {
+    let server = server(false);
+
+    assert_json_snapshot!(
+        "service_missing_key",
+        snapshot(server.get("/service/mappings").await)
+    );
+    assert_json_snapshot!(
+        "service_invalid_key",
+        snapshot(
+            server
+                .get("/service/mappings")
+                .authorization_bearer("wrong-key")
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "service_mappings_database_error",
+        snapshot(
+            server
+                .get("/service/mappings")
+                .authorization_bearer(AGENT_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "service_prefix_index_database_error",
+        snapshot(
+            server
+                .get("/service/mappings/index/by-prefix")
+                .authorization_bearer(AGENT_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "service_mappings_invalid_sort",
+        snapshot(
+            server
+                .get("/service/mappings")
+                .add_query_param("sort", "email")
+                .authorization_bearer(AGENT_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "service_unknown_filter_format",
+        snapshot(
+            server
+                .get("/service/filters/cisco")
+                .authorization_bearer(AGENT_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "service_unknown_policy_format",
+        snapshot(
+            server
+                .get("/service/policies/bird")
+                .authorization_bearer(AGENT_KEY)
+                .await
+        )
+    );
+}
+
+/// IdP accepting `<name>-token` as the token of user `auth0|<name>`
+struct MockIdp;
+
+impl TokenValidator for MockIdp {
+    fn validate<'a>(
+        &'a self,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<AuthInfo, AuthorizationError>> {
+        Box::pin(async move {
+            let Some(name) = token.strip_suffix("-token") else {
+                return Err(AuthorizationError::new(
+                    AuthErrorReason::InvalidToken,
+                    "Unknown token",
+                ));
+            };
+            let claims = Claims {
+                sub: Some(format!("auth0|{}", name)),
+                scope: vec!["api:read".to_string(), "api:write".to_string()],
+                ..Claims::default()
+            };
+            jwt::auth_info_from_claims(claims, &IdentityMapping::default())
+        })
+    }
+}
+
+fn at(timestamp: &str) -> DateTime<Utc> {
+    timestamp.parse().unwrap()
+}
+
+/// Alice holds 65001 with an annotated mapping and a labelled lease, bob
+/// holds 65002 and nothing else
+fn seeded_store() -> MemoryStore {
+    let alice = UserAsnMapping {
+        id: Uuid::from_u128(1),
+        user_hash: user_hash("auth0|alice"),
+        user_id: Some("auth0|alice".to_string()),
+        asn: 65001,
+        created_at: at("2025-01-01T00:00:00Z"),
+        updated_at: at("2025-01-02T00:00:00Z"),
+        description: Some("Anycast experiments".to_string()),
+        abuse_contact: Some("noc@alice.example".to_string()),
+    };
+    let bob = UserAsnMapping {
+        id: Uuid::from_u128(2),
+        user_hash: user_hash("auth0|bob"),
+        user_id: Some("auth0|bob".to_string()),
+        asn: 65002,
+        created_at: at("2025-02-01T00:00:00Z"),
+        updated_at: at("2025-02-01T00:00:00Z"),
+        description: None,
+        abuse_contact: None,
+    };
+    let lease = PrefixLease {
+        id: Uuid::from_u128(3),
+        start_time: at("2025-01-03T00:00:00Z"),
+        end_time: at("2099-01-01T00:00:00Z"),
+        created_at: at("2025-01-03T00:00:00Z"),
+        updated_at: at("2025-01-04T00:00:00Z"),
+        label: Some("anycast".to_string()),
+        purpose: Some("Anycast DNS testbed".to_string()),
+        ..lease("auth0|alice", "2001:db8:1000::/48")
+    };
+    let annotation = MappingAnnotation {
+        mapping_id: alice.id,
+        key: "ticket".to_string(),
+        value: "OPS-42".to_string(),
+        updated_by: "billing".to_string(),
+        updated_at: at("2025-01-05T00:00:00Z"),
+    };
+    MemoryStore::new()
+        .with_mapping(alice)
+        .with_mapping(bob)
+        .with_lease(lease)
+        .with_annotation(annotation)
+}
+
+/// Replace the values of fields depending on the current time
+fn redact_ages(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                if key == "age_seconds" {
+                    *value = json!("[age]");
+                } else {
+                    redact_ages(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_ages),
+        _ => {}
+    }
+}
+
+#[tokio::test]
+async fn store_backed_responses() {
+    let state = AppState {
+        store: Arc::new(seeded_store()),
+        token_validator: Some(Arc::new(MockIdp)),
+        ..test_state(false, None)
+    };
+    let server = TestServer::new(create_app(state)).unwrap();
+
+    assert_json_snapshot!(
+        "user_info",
+        snapshot(
+            server
+                .get("/api/user/info")
+                .authorization_bearer("alice-token")
+                .await
+        )
+    );
+
+    let mut mappings = snapshot(
+        server
+            .get("/service/mappings")
+            .authorization_bearer(AGENT_KEY)
+            .await,
+    );
+    redact_ages(&mut mappings);
+    assert_json_snapshot!("service_mappings", mappings);
+
+    let mut mapping = snapshot(
+        server
+            .get(&format!("/service/mappings/{}", user_hash("auth0|alice")))
+            .authorization_bearer(AGENT_KEY)
+            .await,
+    );
+    redact_ages(&mut mapping);
+    assert_json_snapshot!("service_user_mapping", mapping);
+
+    // The first free ASN of the pool. Assigned last, as the new mapping is
+    // stamped with the current time.
+    assert_json_snapshot!(
+        "user_asn_assigned",
+        snapshot(
+            server
+                .post("/api/user/asn")
+                .authorization_bearer("carol-token")
+                .await
+        )
+    );
+}
+
+#[tokio::test]
+async fn service_api_agent_scopes() {
+    let state = test_state(false, Some(ADMIN_KEY));
+    state.agent_keys.replace(AgentKeys::new([
+        (
+            "rs-ams-key".to_string(),
+            AgentInfo {
+                id: "rs-ams".to_string(),
+                name: None,
+                site: Some("ams".to_string()),
+                scopes: vec!["filters".to_string()],
+            },
+        ),
+        (
+            "rs-fra-key".to_string(),
+            AgentInfo {
+                id: "rs-fra".to_string(),
+                name: None,
+                site: Some("fra".to_string()),
+                scopes: vec!["mappings".to_string()],
+            },
+        ),
+    ]));
+    let server = TestServer::new(create_app(state)).unwrap();
+
+    assert_json_snapshot!(
+        "service_agent_missing_scope",
+        snapshot(
+            server
+                .get("/service/mappings")
+                .authorization_bearer("rs-ams-key")
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "service_agent_other_site",
+        snapshot(
+            server
+                .get("/service/mappings")
+                .add_query_param("site", "ams")
+                .authorization_bearer("rs-fra-key")
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "service_agent_missing_write_scope",
+        snapshot(
+            server
+                .put(&format!(
+                    "/service/mappings/{}/annotations/portal_id",
+                    "a".repeat(64)
+                ))
+                .json(&json!({ "value": "42" }))
+                .authorization_bearer("rs-fra-key")
+                .await
+        )
+    );
+    server
+        .get("/service/filters/cisco")
+        .authorization_bearer("rs-ams-key")
+        .expect_failure()
+        .await
+        .assert_status_not_found();
+}
+
+#[tokio::test]
+async fn admin_api_responses() {
+    let disabled = TestServer::new(create_app(test_state(false, None))).unwrap();
+    assert_json_snapshot!(
+        "admin_disabled",
+        snapshot(
+            disabled
+                .get("/admin/stats/forecast")
+                .authorization_bearer(ADMIN_KEY)
+                .await
+        )
+    );
+
+    let server = server(false);
+    assert_json_snapshot!(
+        "admin_invalid_key",
+        snapshot(
+            server
+                .get("/admin/stats/forecast")
+                .authorization_bearer(AGENT_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_forecast_database_error",
+        snapshot(
+            server
+                .get("/admin/stats/forecast")
+                .authorization_bearer(ADMIN_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_simulate_invalid_duration",
+        snapshot(
+            server
+                .post("/admin/simulate")
+                .authorization_bearer(ADMIN_KEY)
+                .json(&json!({ "users": 200, "lease_minutes": [5] }))
+                .await
+        )
+    );
+    #[cfg(feature = "alerts")]
+    assert_json_snapshot!(
+        "admin_alert_invalid_pool",
+        snapshot(
+            server
+                .post("/admin/alerts")
+                .authorization_bearer(ADMIN_KEY)
+                .json(&json!({
+                    "pool": "vlan",
+                    "threshold": 0.8,
+                    "webhook_url": "https://example.com/alerts"
+                }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_transfer_asn_to_same_user",
+        snapshot(
+            server
+                .post(&format!("/admin/users/{}/asn/transfer", "a".repeat(64)))
+                .authorization_bearer(ADMIN_KEY)
+                .json(&json!({ "user_hash": "a".repeat(64), "reason": "demo handoff" }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_workshop_invalid_name",
+        snapshot(
+            server
+                .post("/admin/workshops")
+                .authorization_bearer(ADMIN_KEY)
+                .json(&json!({ "name": "ripe ws", "slots": 30, "duration_minutes": 480 }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_workshop_database_error",
+        snapshot(
+            server
+                .get("/admin/workshops/ripe-ws-2026")
+                .authorization_bearer(ADMIN_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_pool_prefix_not_48",
+        snapshot(
+            server
+                .put("/admin/pools/prefixes/2001:db8:1::%2F56")
+                .authorization_bearer(ADMIN_KEY)
+                .json(&json!({ "status": "disabled" }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_pool_prefixes_database_error",
+        snapshot(
+            server
+                .get("/admin/pools/prefixes")
+                .authorization_bearer(ADMIN_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_config_not_recorded",
+        snapshot(
+            server
+                .get("/admin/config")
+                .authorization_bearer(ADMIN_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_asn_pool",
+        snapshot(
+            server
+                .get("/admin/stats/asn-pool")
+                .authorization_bearer(ADMIN_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_revoke_lease_database_error",
+        snapshot(
+            server
+                .delete(&format!("/admin/leases/{}", Uuid::nil()))
+                .add_query_param("reason", "abuse report")
+                .authorization_bearer(ADMIN_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_revoke_leases_without_filter",
+        snapshot(
+            server
+                .post("/admin/leases/revoke")
+                .authorization_bearer(ADMIN_KEY)
+                .json(&json!({ "reason": "cleanup", "dry_run": true }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_update_lease_in_the_past",
+        snapshot(
+            server
+                .patch(&format!("/admin/leases/{}", Uuid::nil()))
+                .authorization_bearer(ADMIN_KEY)
+                .json(&json!({
+                    "end_time": "2020-01-01T00:00:00Z",
+                    "reason": "experiment finished"
+                }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_open_incident_without_target",
+        snapshot(
+            server
+                .post("/admin/incidents")
+                .authorization_bearer(ADMIN_KEY)
+                .json(&json!({ "description": "Spam sourced from the lab" }))
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_list_incidents_unknown_status",
+        snapshot(
+            server
+                .get("/admin/incidents?status=pending")
+                .authorization_bearer(ADMIN_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_user_invalid_hash",
+        snapshot(
+            server
+                .get("/admin/users/alice")
+                .authorization_bearer(ADMIN_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_usage_unknown_kind",
+        snapshot(
+            server
+                .get("/admin/usage/tenant/abc")
+                .authorization_bearer(ADMIN_KEY)
+                .await
+        )
+    );
+    assert_json_snapshot!(
+        "admin_impersonation_too_long",
+        snapshot(
+            server
+                .post("/admin/impersonations")
+                .authorization_bearer(ADMIN_KEY)
+                .json(&json!({
+                    "identity": "auth0|alice",
+                    "reason": "ticket 42",
+                    "duration_minutes": 240
+                }))
+                .await
+        )
+    );
+}
+
+#[tokio::test]
+async fn error_formats() {
+    let response = server(false).get("/service/mappings").await;
+    assert_eq!(response.header("content-type"), PROBLEM_JSON);
+
+    let mut state = test_state(false, Some(ADMIN_KEY));
+    state.error_format = ErrorFormat::Legacy;
+    let legacy = TestServer::new(create_app(state)).unwrap();
+    assert_json_snapshot!(
+        "legacy_error_format",
+        snapshot(legacy.get("/service/mappings").await)
+    );
+}
+
+#[cfg(feature = "sessions")]
+#[tokio::test]
+async fn browser_sessions() {
+    use peerlab_gateway::sessions::SessionConfig;
+
+    server(false)
+        .get("/api/auth/login")
+        .expect_failure()
+        .await
+        .assert_status_not_found();
+
+    let mut state = test_state(false, Some(ADMIN_KEY));
+    state.sessions = Some(
+        SessionConfig::new(
+            &"s".repeat(32),
+            "client".to_string(),
+            "secret".to_string(),
+            "https://idp.example.com/authorize".to_string(),
+            "https://idp.example.com/oauth/token".to_string(),
+            "https://gateway.example.com/api/auth/callback".to_string(),
+        )
+        .unwrap(),
+    );
+    let server = TestServer::new(create_app(state)).unwrap();
+
+    let response = server.get("/api/auth/login").await;
+    assert_eq!(response.status_code().as_u16(), 303);
+    let location = response.header("location");
+    let location = location.to_str().unwrap();
+    assert!(location.starts_with("https://idp.example.com/authorize?"));
+    assert!(location.contains("code_challenge_method=S256"));
+    assert!(location.contains("client_id=client"));
+    let login_cookie = response.header("set-cookie");
+    assert!(login_cookie.to_str().unwrap().starts_with("peerlab_login="));
+
+    assert_json_snapshot!(
+        "session_callback_without_login",
+        snapshot(
+            server
+                .get("/api/auth/callback")
+                .add_query_param("code", "abc")
+                .add_query_param("state", "xyz")
+                .expect_failure()
+                .await
+        )
+    );
+
+    // Cookies that don't decrypt are ignored like a missing token
+    server
+        .get("/api/user/info")
+        .add_header("cookie", "peerlab_session=forged")
+        .expect_failure()
+        .await
+        .assert_status_unauthorized();
+}
+
+#[tokio::test]
+async fn split_app_modes() {
+    let client = TestServer::new(create_app_for_mode(
+        test_state(true, Some(ADMIN_KEY)),
+        AppMode::Client,
+    ))
+    .unwrap();
+    client
+        .post("/api/user/prefix")
+        .json(&json!({ "duration_hours": 48 }))
+        .expect_failure()
+        .await
+        .assert_status_bad_request();
+    client
+        .get("/service/mappings")
+        .expect_failure()
+        .await
+        .assert_status_not_found();
+    client
+        .get("/admin/stats/forecast")
+        .expect_failure()
+        .await
+        .assert_status_not_found();
+    client.get("/health").await.assert_status_ok();
+
+    let service = TestServer::new(create_app_for_mode(
+        test_state(true, Some(ADMIN_KEY)),
+        AppMode::Service,
+    ))
+    .unwrap();
+    service
+        .get("/api/user/info")
+        .expect_failure()
+        .await
+        .assert_status_not_found();
+    service
+        .get("/service/mappings")
+        .expect_failure()
+        .await
+        .assert_status_unauthorized();
+
+    assert_eq!("client".parse(), Ok(AppMode::Client));
+    assert_eq!("Combined".parse(), Ok(AppMode::Combined));
+    assert!("public".parse::<AppMode>().is_err());
+}
+
+/// Hash standing for a user named in a test
+fn user_hash(name: &str) -> UserHash {
+    hash_user_identifier(name).parse().unwrap()
+}
+
+fn mapping(name: &str, asn: i64) -> UserAsnMapping {
+    UserAsnMapping {
+        id: Uuid::nil(),
+        user_hash: user_hash(name),
+        user_id: None,
+        asn,
+        created_at: Utc::now(),
+        updated_at: Utc::now(),
+        description: None,
+        abuse_contact: None,
+    }
+}
+
+fn lease(name: &str, prefix: &str) -> PrefixLease {
+    PrefixLease {
+        id: Uuid::nil(),
+        user_hash: user_hash(name),
+        prefix: prefix.to_string(),
+        start_time: Utc::now(),
+        end_time: Utc::now(),
+        created_at: Utc::now(),
+        updated_at: Utc::now(),
+        sites: None,
+        renew_minutes: None,
+        label: None,
+        purpose: None,
+        roa_max_length: None,
+    }
+}
+
+fn permission(name: &str, prefix: &str, asn: Option<i64>) -> AnnouncementPermission {
+    AnnouncementPermission {
+        prefix: prefix.to_string(),
+        max_length: 48,
+        origin_asn: asn,
+        asn_assigned_at: asn.map(|_| Utc::now()),
+        user_hash: user_hash(name),
+        source: PERMISSION_SOURCE_LEASE.to_string(),
+        source_id: Uuid::nil(),
+        sites: None,
+        rpki_state: None,
+        valid_from: Utc::now(),
+        valid_to: Some(Utc::now()),
+    }
+}
+
+#[test]
+fn router_exports() {
+    let mappings = vec![mapping("alice", 65001), mapping("bob", 65002)];
+    let leases = vec![
+        lease("alice", "2001:db8:1000::/48"),
+        lease("alice", "2001:db8:1001::/48"),
+        lease("bob", "2001:db8:2000::/48"),
+        lease("carol", "2001:db8:3000::/48"),
+    ];
+    let permissions = vec![
+        permission("alice", "2001:db8:1000::/48", Some(65001)),
+        permission("alice", "2001:db8:1001::/48", Some(65001)),
+        permission("bob", "2001:db8:2000::/48", Some(65002)),
+        permission("carol", "2001:db8:3000::/48", None),
+    ];
+    let groups = export::group_leases_by_user(&mappings, &leases);
+    assert_eq!(export::group_permissions_by_user(&permissions), groups);
+    let by_asn = export::prefixes_by_asn(&groups);
+
+    for format in export::FilterFormat::ALL {
+        assert_snapshot!(
+            format!("filters_{}", format.name()),
+            export::render_filters(format, &by_asn)
+        );
+    }
+    for format in [export::PolicyFormat::GoBgp, export::PolicyFormat::ExaBgp] {
+        assert_json_snapshot!(
+            format!("policy_{}", format.name()),
+            export::render_policy(format, &by_asn)
+        );
+    }
+    assert_json_snapshot!("roas", export::render_roas(&export::roas(&permissions), 0));
+}
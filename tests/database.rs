@@ -0,0 +1,183 @@
+//! Queries run against PostgreSQL.
+//!
+//! Set `TEST_DATABASE_URL` to a scratch database to run them, e.g.
+//! `TEST_DATABASE_URL=postgresql://postgres@localhost/peerlab_test cargo test`;
+//! migrations are applied first. Without it the tests are skipped. Each test
+//! works in a /48 and with a user of its own and ends its leases after.
+
+use chrono::{Duration, Utc};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use peerlab_gateway::{
+    database::{Database, DatabaseConfig, LeaseOptions, MappingFilter, MappingSort, SortOrder},
+    types::{Asn, Prefix, UserHash},
+};
+
+async fn database() -> Option<Database> {
+    let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL is not set, skipping");
+        return None;
+    };
+    let database = Database::new(&DatabaseConfig::new(url)).await.unwrap();
+    database.initialize().await.unwrap();
+    Some(database)
+}
+
+/// A fresh user and a documentation /48 unlikely to be used by another run
+fn scratch() -> (UserHash, u16) {
+    let id = Uuid::new_v4();
+    let user_hash = hex::encode(Sha256::digest(id.as_bytes())).parse().unwrap();
+    (
+        user_hash,
+        u16::from_be_bytes([id.as_bytes()[0], id.as_bytes()[1]]),
+    )
+}
+
+fn prefix(block: u16, suffix: &str) -> Prefix {
+    format!("2001:db8:{:x}:{}", block, suffix).parse().unwrap()
+}
+
+async fn revoke(database: &Database, ids: &[Uuid]) {
+    for id in ids {
+        database.revoke_prefix_lease(*id).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_renew_around_nested_reservations() {
+    let Some(database) = database().await else {
+        return;
+    };
+    let (user_hash, block) = scratch();
+    let auto_renew = LeaseOptions {
+        auto_renew: true,
+        ..Default::default()
+    };
+    let start = Utc::now() - Duration::minutes(10);
+
+    // Two auto-renewed /64s in different /56s, ending in 20 minutes
+    let leases = database
+        .create_prefix_leases(
+            &user_hash,
+            &[prefix(block, "100::/64"), prefix(block, "200::/64")],
+            start,
+            Duration::minutes(30),
+            &auto_renew,
+        )
+        .await
+        .unwrap();
+    // The /56 covering the first one is reserved right after it
+    let reservation = database
+        .create_prefix_leases(
+            &user_hash,
+            &[prefix(block, "100::/56")],
+            leases[0].end_time,
+            Duration::hours(1),
+            &LeaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    let renewed = database
+        .renew_expiring_leases(Utc::now() + Duration::hours(1), 24)
+        .await;
+    revoke(&database, &[leases[0].id, leases[1].id, reservation[0].id]).await;
+
+    // The first lease is left to end, the other one is still renewed
+    let renewed: Vec<_> = renewed
+        .unwrap()
+        .into_iter()
+        .filter(|lease| lease.user_hash == user_hash)
+        .collect();
+    assert_eq!(renewed.len(), 1);
+    assert_eq!(renewed[0].id, leases[1].id);
+    assert_eq!(
+        renewed[0].end_time,
+        leases[1].end_time + Duration::minutes(30)
+    );
+}
+
+#[tokio::test]
+async fn test_mapping_permissions_filters() {
+    let Some(database) = database().await else {
+        return;
+    };
+    let (first, block) = scratch();
+    let (second, _) = scratch();
+    // Private 32-bit ASNs from the block, the lowest one held by the first user
+    let base = 4_200_000_000 + i64::from(block) * 3;
+    let asn = |offset: i64| Asn::try_from(base + offset).unwrap();
+    database
+        .assign_user_asn(&first, None, asn(0), 2)
+        .await
+        .unwrap();
+    for offset in [1, 2] {
+        database
+            .assign_user_asn(&second, None, asn(offset), 2)
+            .await
+            .unwrap();
+    }
+    let leases = database
+        .create_prefix_leases(
+            &first,
+            &[prefix(block, ":/48")],
+            Utc::now() - Duration::minutes(10),
+            Duration::hours(1),
+            &LeaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    let users = |filter: MappingFilter| {
+        let database = &database;
+        let (first, second) = (&first, &second);
+        async move {
+            let rows = database
+                .get_mapping_permissions(None, &filter)
+                .await
+                .unwrap()
+                .into_iter()
+                .filter(|row| &row.user_hash == first || &row.user_hash == second)
+                .collect();
+            MappingFilter::group(rows)
+                .into_iter()
+                .map(|user| {
+                    let asns: Vec<_> = user.asns.iter().filter_map(|m| m.origin_asn).collect();
+                    (asns, user.leases.len())
+                })
+                .collect::<Vec<_>>()
+        }
+    };
+    let ascending = users(MappingFilter {
+        sort: MappingSort::Asn,
+        order: SortOrder::Asc,
+        ..Default::default()
+    })
+    .await;
+    let descending = users(MappingFilter {
+        sort: MappingSort::Asn,
+        ..Default::default()
+    })
+    .await;
+    let holding = users(MappingFilter {
+        asn: Some(base + 2),
+        ..Default::default()
+    })
+    .await;
+    let active = users(MappingFilter {
+        active_only: true,
+        ..Default::default()
+    })
+    .await;
+
+    revoke(&database, &[leases[0].id]).await;
+    database.delete_user_asns(&first).await.unwrap();
+    database.delete_user_asns(&second).await.unwrap();
+
+    // Users by the ASN of theirs sorting first, their ASNs first assigned first
+    assert_eq!(ascending, [(vec![base], 1), (vec![base + 1, base + 2], 0)]);
+    assert_eq!(descending, [(vec![base + 1, base + 2], 0), (vec![base], 1)]);
+    assert_eq!(holding, [(vec![base + 1, base + 2], 0)]);
+    assert_eq!(active, [(vec![base], 1)]);
+}